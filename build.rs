@@ -0,0 +1,21 @@
+//! With the `embed-packages` feature, this archives the built-in package
+//! definitions so that `src/embedded.rs` can include them in the executable.
+
+use std::path::PathBuf;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if std::env::var_os("CARGO_FEATURE_EMBED_PACKAGES").is_none() {
+        return;
+    }
+    println!("cargo:rerun-if-changed=packages");
+
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").expect("OUT_DIR not set"));
+    let file =
+        std::fs::File::create(out_dir.join("packages.tar")).expect("failed to create packages.tar");
+    let mut builder = tar::Builder::new(file);
+    builder
+        .append_dir_all(".", "packages")
+        .expect("failed to archive packages directory");
+    builder.finish().expect("failed to write packages.tar");
+}
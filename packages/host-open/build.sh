@@ -0,0 +1,7 @@
+#!/bin/sh
+set -eu
+
+cp -a bin/xdg-open ~/bin/
+ln -fs xdg-open ~/bin/open
+
+tar -c -C ~ --verbatim-files-from --files-from ~/w/provides.txt -f ~/provides.tar
@@ -0,0 +1,4 @@
+#!/bin/sh
+set -eux
+
+sccache --version
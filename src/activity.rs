@@ -0,0 +1,202 @@
+//! Tracks when environments were last used, for the `auto_stop` setting.
+//!
+//! Each environment has a small file holding the time it was last entered or
+//! used with `exec`. While a session is running, it holds a shared lock on
+//! that file, so that idle checks can tell the environment is in use. While
+//! `cub gc` stops an idle environment, it holds an exclusive lock on the
+//! file, so that new sessions wait for it to finish.
+
+use rustix::fs::{flock, FlockOperation};
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{EnvironmentName, HostPath};
+use crate::somehow::{somehow as anyhow, Context, LowLevelResult, Result};
+
+pub struct ActivityTracker {
+    dir: HostPath,
+}
+
+/// The state of an environment, as far as idle checks are concerned.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Activity {
+    /// At least one session is currently running in the environment.
+    InUse,
+    /// No session is running. The environment was last used at this time.
+    LastUsed(SystemTime),
+    /// No session is running, and there's no record of past use.
+    Unknown,
+}
+
+/// Marks an environment as in use until dropped.
+pub struct Session {
+    file: File,
+}
+
+/// Keeps sessions from starting in an idle environment until dropped.
+pub struct IdleLock {
+    _file: File,
+}
+
+impl ActivityTracker {
+    pub fn new(dir: HostPath) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, name: &EnvironmentName) -> HostPath {
+        self.dir.join(name.as_filename())
+    }
+
+    fn open(&self, name: &EnvironmentName) -> Result<File> {
+        std::fs::create_dir_all(self.dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {}", self.dir))?;
+        let path = self.path(name);
+        File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.as_host_raw())
+            .with_context(|| format!("failed to open activity file {path}"))
+    }
+
+    /// Records that the environment is being used now, until the returned
+    /// session is dropped.
+    pub fn begin_session(&self, name: &EnvironmentName) -> Result<Session> {
+        let path = self.path(name);
+        let file = self.open(name)?;
+        flock(&file, FlockOperation::LockShared)
+            .with_context(|| format!("failed to lock activity file {path}"))?;
+        write_time(&file, SystemTime::now())
+            .enough_context()
+            .with_context(|| format!("failed to write activity file {path}"))?;
+        Ok(Session { file })
+    }
+
+    /// Returns whether the environment is in use or when it was last used.
+    ///
+    /// If there's no record of the environment's past use, this starts one
+    /// now, so that a later check can find the environment idle.
+    pub fn activity(&self, name: &EnvironmentName) -> Result<Activity> {
+        self.lock_if_idle(name).map(|(activity, _lock)| activity)
+    }
+
+    /// Like [`Self::activity`], but if no session is running, this also
+    /// returns a lock that makes new sessions wait until it's dropped. This
+    /// lets the caller stop an idle environment without a session starting
+    /// in the meantime.
+    pub fn lock_if_idle(&self, name: &EnvironmentName) -> Result<(Activity, Option<IdleLock>)> {
+        let path = self.path(name);
+        let file = self.open(name)?;
+        match flock(&file, FlockOperation::NonBlockingLockExclusive) {
+            Ok(()) => {}
+            Err(e) if e == rustix::io::Errno::WOULDBLOCK => return Ok((Activity::InUse, None)),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to lock activity file {path}"))
+            }
+        }
+        let read = || -> LowLevelResult<Option<SystemTime>> {
+            match read_time(&file)? {
+                Some(time) => Ok(Some(time)),
+                None => {
+                    write_time(&file, SystemTime::now())?;
+                    Ok(None)
+                }
+            }
+        };
+        let activity = match read()
+            .enough_context()
+            .with_context(|| format!("failed to access activity file {path}"))?
+        {
+            Some(time) => Activity::LastUsed(time),
+            None => Activity::Unknown,
+        };
+        Ok((activity, Some(IdleLock { _file: file })))
+    }
+
+    /// Removes the record of the environment's use, if any.
+    pub fn forget(&self, name: &EnvironmentName) -> Result<()> {
+        let path = self.path(name);
+        match std::fs::remove_file(path.as_host_raw()) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("failed to remove activity file {path}")),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // The environment is idle starting from the end of the session. This
+        // is best-effort: the start time was already recorded.
+        let _ = write_time(&self.file, SystemTime::now());
+    }
+}
+
+fn write_time(mut file: &File, time: SystemTime) -> io::Result<()> {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{secs}")
+}
+
+fn read_time(mut file: &File) -> LowLevelResult<Option<SystemTime>> {
+    let mut buf = String::new();
+    file.rewind()?;
+    file.read_to_string(&mut buf)?;
+    let buf = buf.trim();
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    let secs = u64::from_str(buf).map_err(|_| anyhow!("invalid timestamp: {buf:?}"))?;
+    Ok(Some(UNIX_EPOCH + Duration::from_secs(secs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn activity() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir_path = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        let tracker = ActivityTracker::new(tmpdir_path.join("activity"));
+        let name = EnvironmentName::from_str("foo").unwrap();
+
+        assert_eq!(Activity::Unknown, tracker.activity(&name).unwrap());
+        assert!(matches!(
+            tracker.activity(&name).unwrap(),
+            Activity::LastUsed(_)
+        ));
+
+        let session = tracker.begin_session(&name).unwrap();
+        assert_eq!(Activity::InUse, tracker.activity(&name).unwrap());
+        drop(session);
+        assert!(matches!(
+            tracker.activity(&name).unwrap(),
+            Activity::LastUsed(_)
+        ));
+
+        // While the lock is held, the environment looks busy to others.
+        let (activity, lock) = tracker.lock_if_idle(&name).unwrap();
+        assert!(matches!(activity, Activity::LastUsed(_)));
+        assert!(lock.is_some());
+        assert_eq!(Activity::InUse, tracker.activity(&name).unwrap());
+        drop(lock);
+
+        let session = tracker.begin_session(&name).unwrap();
+        let (activity, lock) = tracker.lock_if_idle(&name).unwrap();
+        assert_eq!(Activity::InUse, activity);
+        assert!(lock.is_none());
+        drop(session);
+
+        tracker.forget(&name).unwrap();
+        tracker.forget(&name).unwrap();
+        assert_eq!(Activity::Unknown, tracker.activity(&name).unwrap());
+    }
+}
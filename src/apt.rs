@@ -1,4 +1,5 @@
 use regex::{Regex, RegexBuilder};
+use std::collections::BTreeSet;
 use std::str::FromStr;
 use std::sync::OnceLock;
 
@@ -80,6 +81,102 @@ pub fn simulate_satisfy(deps: &[&str]) -> Result<Summary> {
     }
 }
 
+/// Returns the names of all the packages that the host's apt knows about.
+pub fn available_packages() -> Result<BTreeSet<String>> {
+    let output = Command::new("apt-cache").arg("pkgnames").output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`apt-cache pkgnames` exited with {} and stderr: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse_pkgnames(&output.stdout)
+}
+
+/// Parses the output of `apt-cache pkgnames`.
+pub fn parse_pkgnames(stdout: &[u8]) -> Result<BTreeSet<String>> {
+    let stdout =
+        std::str::from_utf8(stdout).context("failed to read `apt-cache pkgnames` output")?;
+    Ok(stdout
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Returns an error listing the requested packages that aren't available,
+/// with suggestions for similarly named packages.
+pub fn check_available<'a, I>(requested: I, available: &BTreeSet<String>) -> Result<()>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut missing = Vec::new();
+    for package in requested {
+        // Strip any architecture qualifier, like ":amd64".
+        let name = package.split_once(':').map_or(package, |(name, _)| name);
+        if available.contains(name) {
+            continue;
+        }
+        let suggestions = suggest(name, available);
+        missing.push(if suggestions.is_empty() {
+            format!("{package:?}")
+        } else {
+            format!(
+                "{package:?} (did you mean {}?)",
+                suggestions
+                    .iter()
+                    .map(|s| format!("{s:?}"))
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            )
+        });
+    }
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Debian package(s) not found: {}",
+            missing.join(", ")
+        ))
+    }
+}
+
+/// Returns up to 3 available package names that are close to `name`.
+fn suggest<'a>(name: &str, available: &'a BTreeSet<String>) -> Vec<&'a str> {
+    let max_distance = (name.chars().count() / 3).clamp(1, 3);
+    let mut candidates = available
+        .iter()
+        .filter_map(|candidate| {
+            let distance = edit_distance(name, candidate);
+            (distance <= max_distance).then_some((distance, candidate.as_str()))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_unstable();
+    candidates
+        .into_iter()
+        .take(3)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// Returns the Levenshtein distance between the two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != *cb);
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
 pub fn check_satisfied(deps: &[&str]) {
     match simulate_satisfy(deps) {
         Ok(summary) => {
@@ -92,3 +189,29 @@ pub fn check_satisfied(deps: &[&str]) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distance() {
+        assert_eq!(0, super::edit_distance("vim", "vim"));
+        assert_eq!(1, super::edit_distance("vim", "vi"));
+        assert_eq!(2, super::edit_distance("pyhton3", "python3"));
+        assert_eq!(3, super::edit_distance("", "gcc"));
+    }
+
+    #[test]
+    fn check_available() {
+        let available = parse_pkgnames(b"curl\ngcc\ngcc-12\npython3\npython3-pip\nvim\n").unwrap();
+        assert!(super::check_available(["curl", "gcc:amd64", "vim"], &available).is_ok());
+        assert_eq!(
+            "Debian package(s) not found: \"pyhton3\" (did you mean \"python3\"?), \
+            \"gc\" (did you mean \"gcc\"?), \"zzzzzz\"",
+            super::check_available(["pyhton3", "gc", "zzzzzz"], &available)
+                .unwrap_err()
+                .to_string()
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! Picks a runner for `runner = "auto"`.
+//!
+//! The first time Cubicle runs with this setting, it probes the host for
+//! runners it can use and records its choice in the data directory. Later
+//! runs use the recorded runner without probing again, so that existing
+//! environments don't seem to vanish just because, for example, the Docker
+//! daemon wasn't running yet. `cub doctor` explains the choice.
+
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use super::command_ext::Command;
+use super::config::Config;
+use super::docker::docker_command;
+use super::{HostPath, RunnerKind};
+use crate::somehow::{somehow as anyhow, Context, ErrorKind, Result};
+
+/// The runners that `runner = "auto"` may pick, from most to least
+/// preferred. Bubblewrap is lighter-weight than Docker where both work. The
+/// User runner is never picked, since it creates accounts on the host.
+const PREFERENCE: [RunnerKind; 2] = [RunnerKind::Bubblewrap, RunnerKind::Docker];
+
+/// How the runner in use was chosen.
+///
+/// This is returned by [`Cubicle::runner_choice`](crate::Cubicle::runner_choice).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RunnerChoice {
+    /// The runner in use.
+    pub runner: RunnerKind,
+    /// Why this runner is in use.
+    pub source: RunnerSource,
+}
+
+/// Why a runner is in use. See [`RunnerChoice`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum RunnerSource {
+    /// The configuration file names the runner.
+    Config,
+    /// With `runner = "auto"`, this process probed the host, picked the
+    /// runner, and recorded it in the given file.
+    Probed(PathBuf),
+    /// With `runner = "auto"`, an earlier process picked the runner and
+    /// recorded it in the given file.
+    Recorded(PathBuf),
+}
+
+/// Whether a runner can be used on this host. See [`probe`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RunnerProbe {
+    /// The runner.
+    pub runner: RunnerKind,
+    /// True if `runner = "auto"` could pick this runner.
+    pub usable: bool,
+    /// A short explanation, like "the Docker daemon is reachable".
+    pub reason: String,
+}
+
+/// Returns the file where the choice for `runner = "auto"` is recorded.
+pub(super) fn record_path(data: &HostPath) -> HostPath {
+    data.join("runner")
+}
+
+/// Returns the recorded runner for `runner = "auto"`, or probes the host to
+/// pick one and records it (except in a dry run).
+pub(super) fn resolve(config: &Config, data: &HostPath, dry_run: bool) -> Result<RunnerChoice> {
+    let path = record_path(data);
+    match std::fs::read_to_string(path.as_host_raw()) {
+        Ok(recorded) => {
+            let runner = parse_recorded(&recorded).with_context(|| {
+                format!(
+                    "invalid runner recorded for `runner = \"auto\"` in {path} \
+                    (remove the file to choose again)"
+                )
+            })?;
+            return Ok(RunnerChoice {
+                runner,
+                source: RunnerSource::Recorded(path.as_host_raw().to_owned()),
+            });
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("failed to read {path}")),
+    }
+
+    let probes = probe(config);
+    let Some(runner) = choose(&probes) else {
+        return Err(anyhow!(
+            "`runner = \"auto\"` found no usable runner:\n{}\n\
+            Set `runner` in the configuration file to choose one explicitly.",
+            probes
+                .iter()
+                .map(|probe| format!("- {}: {}", probe.runner.as_str(), probe.reason))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ));
+    };
+    if !dry_run {
+        std::fs::create_dir_all(data.as_host_raw())
+            .with_context(|| format!("failed to create directory {data}"))?;
+        std::fs::write(path.as_host_raw(), format!("{}\n", runner.as_str()))
+            .with_context(|| format!("failed to write {path}"))?;
+    }
+    Ok(RunnerChoice {
+        runner,
+        source: RunnerSource::Probed(path.as_host_raw().to_owned()),
+    })
+}
+
+fn parse_recorded(recorded: &str) -> Result<RunnerKind> {
+    let runner: RunnerKind = toml::Value::String(recorded.trim().to_owned())
+        .try_into()
+        .map_err(|_| anyhow!("unknown runner {:?}", recorded.trim()))?;
+    if runner == RunnerKind::Auto {
+        return Err(anyhow!("the recorded runner must not be \"auto\""));
+    }
+    Ok(runner)
+}
+
+/// Returns the most preferred usable runner, if any.
+fn choose(probes: &[RunnerProbe]) -> Option<RunnerKind> {
+    PREFERENCE.into_iter().find(|runner| {
+        probes
+            .iter()
+            .any(|probe| probe.runner == *runner && probe.usable)
+    })
+}
+
+/// Checks which runners can be used on this host, and why or why not.
+pub fn probe(config: &Config) -> Vec<RunnerProbe> {
+    let probe = |runner, (usable, reason): (bool, String)| RunnerProbe {
+        runner,
+        usable,
+        reason,
+    };
+    vec![
+        probe(RunnerKind::Bubblewrap, probe_bubblewrap(config)),
+        probe(RunnerKind::Docker, probe_docker(config)),
+        probe(
+            RunnerKind::User,
+            (
+                false,
+                String::from("never picked automatically, since it creates user accounts"),
+            ),
+        ),
+    ]
+}
+
+fn probe_bubblewrap(config: &Config) -> (bool, String) {
+    if !cfg!(target_os = "linux") {
+        return (false, String::from("only available on Linux"));
+    }
+    // Unprivileged user namespaces may be disabled, so this checks that bwrap
+    // can actually create a sandbox.
+    let output = Command::new("bwrap")
+        .args(["--ro-bind", "/", "/", "--", "true"])
+        .stdout(Stdio::null())
+        .output();
+    match output {
+        Err(e) if e.kind() == Some(ErrorKind::ProgramNotFound) => {
+            (false, String::from("`bwrap` is not installed"))
+        }
+        Err(e) => (false, format!("failed to run `bwrap`: {e}")),
+        Ok(output) if !output.status.success() => (
+            false,
+            format!(
+                "`bwrap` can't create a sandbox: {}",
+                first_line(&output.stderr, &output.status.to_string())
+            ),
+        ),
+        Ok(_) if config.bubblewrap.is_none() => (
+            false,
+            String::from(
+                "`bwrap` works, but the configuration file has no `[bubblewrap]` settings \
+                (see docs/Bubblewrap.md)",
+            ),
+        ),
+        Ok(_) => (true, String::from("`bwrap` can create sandboxes")),
+    }
+}
+
+fn probe_docker(config: &Config) -> (bool, String) {
+    let output = docker_command(&config.docker)
+        .args(["version", "--format", "{{ .Server.Version }}"])
+        .output();
+    match output {
+        Err(e) if e.kind() == Some(ErrorKind::ProgramNotFound) => {
+            (false, String::from("`docker` is not installed"))
+        }
+        Err(e) => (false, format!("failed to run `docker`: {e}")),
+        Ok(output) if !output.status.success() => (
+            false,
+            format!(
+                "the Docker daemon is not reachable: {}",
+                first_line(&output.stderr, &output.status.to_string())
+            ),
+        ),
+        Ok(output) => (
+            true,
+            format!(
+                "the Docker daemon (version {}) is reachable",
+                first_line(&output.stdout, "unknown")
+            ),
+        ),
+    }
+}
+
+/// Returns the first non-empty line of a command's output, or `default`.
+fn first_line(output: &[u8], default: &str) -> String {
+    String::from_utf8_lossy(output)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or(default)
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_recorded() {
+        assert_eq!(
+            RunnerKind::Docker,
+            super::parse_recorded("docker\n").unwrap()
+        );
+        assert_eq!(
+            RunnerKind::Bubblewrap,
+            super::parse_recorded("bwrap").unwrap()
+        );
+        assert!(super::parse_recorded("auto").is_err());
+        assert!(super::parse_recorded("podman").is_err());
+    }
+
+    #[test]
+    fn choose() {
+        let probe = |runner, usable| RunnerProbe {
+            runner,
+            usable,
+            reason: String::new(),
+        };
+        assert_eq!(
+            Some(RunnerKind::Bubblewrap),
+            super::choose(&[
+                probe(RunnerKind::Docker, true),
+                probe(RunnerKind::Bubblewrap, true),
+            ])
+        );
+        assert_eq!(
+            Some(RunnerKind::Docker),
+            super::choose(&[
+                probe(RunnerKind::Bubblewrap, false),
+                probe(RunnerKind::Docker, true),
+                probe(RunnerKind::User, true),
+            ])
+        );
+        assert_eq!(None, super::choose(&[probe(RunnerKind::User, true)]));
+    }
+}
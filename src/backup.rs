@@ -0,0 +1,314 @@
+//! Backs up and restores environments, for `cub backup` and `cub restore`.
+//!
+//! A backup is a directory, or a tar archive of one. It holds a manifest
+//! listing each environment and its packages, plus a tar archive of each
+//! environment's home directory, including its work directory. Restoring an
+//! environment creates it with the same packages, built as needed on the
+//! new machine, and seeds its home directory from the archive. The package
+//! seeds are extracted afterwards, so the packages' files come from the new
+//! builds.
+//!
+//! `cub migrate` uses the same steps to move one environment to another
+//! runner.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Component, Path};
+
+use super::fs_util::{try_exists, try_iterdir};
+use super::{
+    CreateMode, Cubicle, EnvironmentExists, EnvironmentName, Event, FullPackageName, HostPath,
+    KeepPartial, Runner,
+};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+/// The name of the manifest within a backup.
+const MANIFEST: &str = "cubicle-backup.json";
+
+#[derive(Deserialize, Serialize)]
+struct Manifest {
+    environments: Vec<ManifestEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ManifestEntry {
+    name: EnvironmentName,
+    packages: BTreeSet<FullPackageName>,
+    /// The filename of the home directory archive within the backup.
+    home: String,
+}
+
+/// Where a backup is written.
+enum Destination {
+    Dir(HostPath),
+    Archive(tar::Builder<File>),
+}
+
+impl Destination {
+    /// Opens a new backup at `path`, which is an archive if it ends in
+    /// `.tar` and a directory otherwise.
+    fn create(path: &HostPath) -> Result<Self> {
+        if path
+            .as_host_raw()
+            .extension()
+            .is_some_and(|ext| ext == "tar")
+        {
+            let file = File::options()
+                .write(true)
+                .create_new(true)
+                .open(path.as_host_raw())
+                .with_context(|| format!("failed to create backup archive {path}"))?;
+            Ok(Self::Archive(tar::Builder::new(file)))
+        } else {
+            if !try_iterdir(path)?.is_empty() {
+                return Err(anyhow!("backup directory {path} exists and is not empty"));
+            }
+            std::fs::create_dir_all(path.as_host_raw())
+                .with_context(|| format!("failed to create backup directory {path}"))?;
+            Ok(Self::Dir(path.clone()))
+        }
+    }
+
+    /// Adds a file to the backup with the contents written by `write`.
+    fn add<F>(&mut self, filename: &str, write: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn Write) -> Result<()>,
+    {
+        match self {
+            Self::Dir(dir) => {
+                let path = dir.join(filename);
+                let mut file = io::BufWriter::new(
+                    File::create(path.as_host_raw())
+                        .with_context(|| format!("failed to create {path}"))?,
+                );
+                write(&mut file)?;
+                file.flush()
+                    .with_context(|| format!("failed to write {path}"))
+            }
+            Self::Archive(builder) => {
+                // Tar headers need the size up front, so this writes the file
+                // somewhere else first.
+                let mut temp = tempfile::NamedTempFile::new()
+                    .context("failed to create temporary file for backup")?;
+                {
+                    let mut writer = io::BufWriter::new(temp.as_file_mut());
+                    write(&mut writer)?;
+                    writer
+                        .flush()
+                        .context("failed to write temporary file for backup")?;
+                }
+                builder
+                    .append_path_with_name(temp.path(), filename)
+                    .with_context(|| format!("failed to add {filename:?} to backup archive"))
+            }
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Dir(_) => Ok(()),
+            Self::Archive(builder) => builder
+                .into_inner()
+                .and_then(|file| file.sync_all())
+                .context("failed to finish backup archive"),
+        }
+    }
+}
+
+impl Cubicle {
+    /// Corresponds to `cub backup`.
+    ///
+    /// Writes the home and work directories and package lists of all the
+    /// environments into a new backup at `path`. Instances of golden
+    /// environments and environments for git worktrees are skipped with a
+    /// warning, since they depend on files outside the environment.
+    pub fn backup_environments(&self, path: &Path) -> Result<()> {
+        self.shared.check_not_dry_run("back up environments")?;
+        let path = HostPath::try_from(
+            std::env::current_dir()
+                .context("failed to get current directory")?
+                .join(path),
+        )?;
+        let mut dest = Destination::create(&path)?;
+        let mut manifest = Manifest {
+            environments: Vec::new(),
+        };
+        for name in self.get_environment_names()? {
+            let _lock = self.shared.locks.lock_environment(&name)?;
+            if let Some(reason) = self.backup_skip_reason(&name)? {
+                warn(anyhow!("not backing up environment {name}, since {reason}"));
+                continue;
+            }
+            manifest
+                .environments
+                .push(self.backup_environment(&name, &mut dest)?);
+        }
+
+        let buf = serde_json::to_vec_pretty(&manifest).context("failed to serialize manifest")?;
+        dest.add(MANIFEST, |w| {
+            w.write_all(&buf).context("failed to write manifest")
+        })?;
+        dest.finish()
+    }
+
+    /// Returns why the environment can't be backed up, if it can't.
+    ///
+    /// The caller should hold the environment's lock.
+    fn backup_skip_reason(&self, name: &EnvironmentName) -> Result<Option<String>> {
+        if self.runner.exists(name)? != EnvironmentExists::FullyExists {
+            return Ok(Some(String::from("it's in a broken state")));
+        }
+        if let Some(golden) = self.shared.golden.golden(name)? {
+            return Ok(Some(format!(
+                "it's an instance of golden environment {golden}"
+            )));
+        }
+        if self.shared.worktrees.get(name)?.is_some() {
+            return Ok(Some(String::from("its work directory is a git worktree")));
+        }
+        Ok(None)
+    }
+
+    /// Writes the environment's home directory archive into `dest` and
+    /// returns its manifest entry.
+    ///
+    /// The caller should hold the environment's lock.
+    fn backup_environment(
+        &self,
+        name: &EnvironmentName,
+        dest: &mut Destination,
+    ) -> Result<ManifestEntry> {
+        self.shared.event(Event::BackingUpEnvironment(name.clone()));
+        let packages = self
+            .read_package_list_from_env(name)
+            .with_context(|| format!("failed to parse `packages.txt` from {name}"))?;
+        let home = format!("{}.tar", name.as_filename());
+        dest.add(&home, |w| self.runner.backup(name, w))?;
+        Ok(ManifestEntry {
+            name: name.clone(),
+            packages,
+            home,
+        })
+    }
+
+    /// Corresponds to `cub restore`.
+    ///
+    /// Recreates the environments from the backup at `path`, which is a
+    /// directory or a tar archive written by
+    /// [`Cubicle::backup_environments`]. Environments that already exist
+    /// are skipped with a warning.
+    pub fn restore_environments(&self, path: &Path) -> Result<()> {
+        let path = HostPath::try_from(
+            std::env::current_dir()
+                .context("failed to get current directory")?
+                .join(path),
+        )?;
+        let extracted;
+        let dir = if path.as_host_raw().is_dir() {
+            path.clone()
+        } else {
+            extracted = tempfile::tempdir().context("failed to create temporary directory")?;
+            let file = File::open(path.as_host_raw())
+                .with_context(|| format!("failed to open backup archive {path}"))?;
+            tar::Archive::new(file)
+                .unpack(extracted.path())
+                .with_context(|| format!("failed to extract backup archive {path}"))?;
+            HostPath::try_from(extracted.path().to_owned())?
+        };
+
+        let manifest_path = dir.join(MANIFEST);
+        if !try_exists(&manifest_path).todo_context()? {
+            return Err(anyhow!(
+                "{path} is not a Cubicle backup (missing {MANIFEST})"
+            ));
+        }
+        let manifest: Manifest = serde_json::from_slice(
+            &std::fs::read(manifest_path.as_host_raw())
+                .with_context(|| format!("failed to read {manifest_path}"))?,
+        )
+        .with_context(|| format!("failed to parse {manifest_path}"))?;
+
+        for entry in manifest.environments {
+            let name = &entry.name;
+            if self.runner.exists(name)? != EnvironmentExists::NoEnvironment {
+                warn(anyhow!(
+                    "not restoring environment {name}, since it already exists"
+                ));
+                continue;
+            }
+            self.restore_environment(&dir, entry)?;
+        }
+        Ok(())
+    }
+
+    /// Creates the environment from its manifest entry, using the home
+    /// directory archive in `dir`.
+    fn restore_environment(&self, dir: &HostPath, entry: ManifestEntry) -> Result<()> {
+        let name = &entry.name;
+        let mut components = Path::new(&entry.home).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(anyhow!(
+                "invalid home directory archive name for {name}: {:?}",
+                entry.home
+            ));
+        }
+        self.shared.event(Event::RestoringEnvironment(name.clone()));
+        self.new_environment_(
+            name,
+            Some(entry.packages),
+            Some(&dir.join(&entry.home)),
+            KeepPartial(false),
+            CreateMode::Default,
+        )
+        .with_context(|| format!("failed to restore environment {name}"))
+    }
+
+    /// Corresponds to `cub migrate`.
+    ///
+    /// Recreates the environment under the runner of `to`, which must use a
+    /// different runner than this instance. Like `cub backup` followed by
+    /// `cub restore`, this copies the environment's home and work
+    /// directories into a new environment with the same name and packages.
+    /// The original environment is left in place.
+    pub fn migrate_environment(&self, name: &EnvironmentName, to: &Cubicle) -> Result<()> {
+        self.shared.check_not_dry_run("migrate an environment")?;
+        let from_runner = self.shared.config.runner;
+        let to_runner = to.shared.config.runner;
+        if from_runner == to_runner {
+            return Err(anyhow!(
+                "environment {name} already uses the {} runner",
+                to_runner.as_str()
+            ));
+        }
+        if to.runner.exists(name)? != EnvironmentExists::NoEnvironment {
+            return Err(anyhow!(
+                "environment {name} already exists under the {} runner",
+                to_runner.as_str()
+            ));
+        }
+
+        let tmp = tempfile::tempdir().context("failed to create temporary directory")?;
+        let dir = HostPath::try_from(tmp.path().to_owned())?;
+        let entry = {
+            // Both instances share the same locks, so this must be released
+            // before `to` creates the environment.
+            let _lock = self.shared.locks.lock_environment(name)?;
+            if let Some(reason) = self.backup_skip_reason(name)? {
+                return Err(anyhow!("can't migrate environment {name}, since {reason}"));
+            }
+            self.backup_environment(name, &mut Destination::Dir(dir.clone()))?
+        };
+        to.restore_environment(&dir, entry).with_context(|| {
+            format!(
+                "failed to migrate environment {name} from the {} runner to the {} runner",
+                from_runner.as_str(),
+                to_runner.as_str()
+            )
+        })
+    }
+}
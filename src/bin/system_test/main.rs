@@ -11,100 +11,46 @@
 use clap::Parser;
 use cubicle::config::Config;
 use cubicle::somehow::{somehow as anyhow, Context, Result};
-use cubicle::{
-    Cubicle, EnvironmentName, FullPackageName, ListFormat, ListPackagesFormat, Quiet,
-    ShouldPackageUpdate, UpdatePackagesConditions,
-};
-use expect_test::expect;
-use std::collections::BTreeSet;
-use std::path::{Path, PathBuf};
+use cubicle::{Cubicle, EnvironmentName, Event};
+use std::path::PathBuf;
 use std::str::FromStr;
 
+mod scenarios;
+use scenarios::{TestContext, SCENARIOS};
+
 #[derive(Parser)]
 struct Args {
     /// Path to configuration file.
-    #[arg(short, long, required(true), value_hint(clap::ValueHint::FilePath))]
-    config: PathBuf,
-}
-
-/// Read a file's contents and write it back to disk.
-///
-/// The purpose of this function is to update the file modified time. It's not
-/// trivial to update that metadata in a cross-platform way, so this just
-/// rewrites the file instead.
-fn rewrite<P: AsRef<Path>>(path: P) -> Result<()> {
-    let rewrite_ = |path: &Path| -> std::io::Result<()> {
-        let contents = std::fs::read(path)?;
-        std::fs::write(path, contents)?;
-        Ok(())
-    };
-    let path = path.as_ref();
-    rewrite_(path).with_context(|| format!("Failed to rewrite {path:?}"))
+    #[arg(
+        short,
+        long,
+        required_unless_present("list"),
+        value_hint(clap::ValueHint::FilePath)
+    )]
+    config: Option<PathBuf>,
+
+    /// Run only the named scenario (may be given more than once).
+    #[arg(long, value_name = "SCENARIO", value_parser = scenario_names())]
+    only: Vec<String>,
+
+    /// Print the available scenarios and exit.
+    #[arg(long)]
+    list: bool,
 }
 
-fn test_package_not_found_errors(cub: &Cubicle, test_env: &EnvironmentName) -> Result<()> {
-    cub.purge_environment(test_env, Quiet(false))?;
-
-    let not_exist = BTreeSet::from([FullPackageName::from_str("does-not-exist")?]);
-
-    // cub new --packages=does-not-exist
-    let err = cub
-        .new_environment(test_env, Some(not_exist.clone()))
-        .expect_err("should not be able to use does-not-exist package in `cub new`");
-    expect![[r#"could not find package definition for "does-not-exist""#]]
-        .assert_eq(&err.debug_without_backtrace());
-
-    let envs = cub.get_environment_names()?;
-    assert!(
-        !envs.contains(test_env),
-        "{test_env} environment should not exist"
-    );
-
-    // cub tmp --packages=does-not-exist
-    let err = cub
-        .create_enter_tmp_environment(Some(not_exist.clone()))
-        .expect_err("should not be able to use does-not-exist package in `cub tmp`");
-    expect![[r#"could not find package definition for "does-not-exist""#]]
-        .assert_eq(&err.debug_without_backtrace());
-    let new_envs = cub
-        .get_environment_names()?
-        .difference(&envs)
-        .cloned()
-        .collect::<Vec<_>>();
-    assert!(
-        new_envs.is_empty(),
-        "new tmp environment should not exist, found {new_envs:?}"
-    );
-
-    // cub reset --packages=does-not-exist
-    cub.new_environment(test_env, Some(BTreeSet::new()))?;
-    cub.exec_environment(test_env, &[String::from("touch"), String::from("../foo")])?;
-    let err = cub
-        .reset_environment(test_env, Some(not_exist.clone()))
-        .expect_err("should not be able to use does-not-exist package in `cub reset`");
-    expect![[r#"could not find package definition for "does-not-exist""#]]
-        .assert_eq(&err.debug_without_backtrace());
-    cub.exec_environment(test_env, &[String::from("cat"), String::from("../foo")])
-        .context("file `../foo` should still exist")?;
-
-    // cub package update does-not-exist
-    let err = cub
-        .update_packages(
-            &not_exist,
-            &cub.scan_packages()?,
-            &UpdatePackagesConditions {
-                dependencies: ShouldPackageUpdate::Always,
-                named: ShouldPackageUpdate::Always,
-            },
-        )
-        .expect_err("should not be able to use does-not-exist package in `cub tmp`");
-    expect![[r#"could not find package definition for "does-not-exist""#]]
-        .assert_eq(&err.debug_without_backtrace());
-
-    Ok(())
+fn scenario_names() -> clap::builder::PossibleValuesParser {
+    clap::builder::PossibleValuesParser::new(SCENARIOS.iter().map(|scenario| scenario.name))
 }
 
 fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.list {
+        for scenario in SCENARIOS {
+            println!("{:<12} {}", scenario.name, scenario.about);
+        }
+        return Ok(());
+    }
+
     let exe = std::env::current_exe().todo_context()?;
     let project_root = match exe.ancestors().nth(3) {
         Some(path) => path.to_owned(),
@@ -116,44 +62,30 @@ fn main() -> Result<()> {
         }
     };
 
-    let args = Args::parse();
-    let config = Config::read_from_file(&args.config)?;
-    let cub = Cubicle::new(config)?;
-
-    let test_env = EnvironmentName::from_str("system_test")?;
-    let configs_pkg = FullPackageName::from_str("configs-interactive")?;
-
-    cub.list_environments(ListFormat::Default)?;
-
-    test_package_not_found_errors(&cub, &test_env)?;
-
-    cub.purge_environment(&test_env, Quiet(false))?;
-    cub.new_environment(&test_env, Some(BTreeSet::new()))?;
-    cub.exec_environment(&test_env, &["ls", "-l", ".."].map(String::from))?;
-    cub.reset_environment(&test_env, None)?;
-
-    cub.purge_environment(&test_env, Quiet(false))?;
-    cub.new_environment(&test_env, Some(BTreeSet::from([configs_pkg])))?;
-    cub.exec_environment(&test_env, &["ls", "-al", ".."].map(String::from))?;
-    // This should cause the configs-interactive package to be rebuilt.
-    rewrite(project_root.join("packages/configs-interactive/build.sh"))?;
-    cub.reset_environment(&test_env, None)?;
-    cub.exec_environment(&test_env, &["ls", "-al", ".."].map(String::from))?;
+    let config_path = args.config.expect("clap requires --config without --list");
+    let config = Config::read_from_file(&config_path)?;
+    let runner = config.runner;
+    let cub = Cubicle::new(config, |event: &Event| println!("{event}"))?;
+    let ctx = TestContext {
+        cub,
+        runner,
+        project_root,
+        test_env: EnvironmentName::from_str("system_test")?,
+    };
 
-    cub.list_environments(ListFormat::Default)?;
-    cub.purge_environment(&test_env, Quiet(false))?;
+    println!("Environments: {:?}", ctx.cub.get_environment_names()?);
 
-    cub.list_packages(ListPackagesFormat::Default)?;
-    let packages = BTreeSet::from([FullPackageName::from_str("no-op")?]);
-    cub.update_packages(
-        &packages,
-        &cub.scan_packages()?,
-        &UpdatePackagesConditions {
-            dependencies: ShouldPackageUpdate::Always,
-            named: ShouldPackageUpdate::Always,
-        },
-    )?;
-    cub.list_packages(ListPackagesFormat::Default)?;
+    for scenario in SCENARIOS {
+        if !args.only.is_empty() && !args.only.iter().any(|name| name == scenario.name) {
+            continue;
+        }
+        if !scenario.applies_to(ctx.runner) {
+            println!("Skipping scenario {} for this runner", scenario.name);
+            continue;
+        }
+        println!("Running scenario {}", scenario.name);
+        (scenario.run)(&ctx).with_context(|| format!("scenario {} failed", scenario.name))?;
+    }
 
     Ok(())
 }
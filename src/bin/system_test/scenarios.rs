@@ -0,0 +1,277 @@
+//! Scenarios that the system test runs against a real runner.
+//!
+//! Each scenario exercises one area of Cubicle end to end and cleans up the
+//! environments it uses. Together, they're a conformance suite for runners:
+//! a new runner should pass all of them. To cover a new runner, add a
+//! configuration file for it and run `system_test --config` with that file.
+//!
+//! A scenario that only makes sense for some runners lists them in
+//! [`Scenario::runners`]; it's skipped for the others.
+
+use cubicle::somehow::{somehow as anyhow, Context, ErrorKind, Result};
+use cubicle::{
+    Cubicle, EnvironmentName, FullPackageName, KeepPartial, Quiet, RunnerKind, ShouldPackageUpdate,
+    UpdatePackagesConditions,
+};
+use expect_test::expect;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// State shared by all the scenarios.
+pub struct TestContext {
+    pub cub: Cubicle,
+    /// The runner the configuration file selected.
+    pub runner: RunnerKind,
+    /// The root of the source tree, which holds the built-in packages.
+    pub project_root: PathBuf,
+    /// The name of the environment that scenarios create and purge.
+    pub test_env: EnvironmentName,
+}
+
+/// A named group of checks.
+pub struct Scenario {
+    /// Used to select the scenario with `--only`.
+    pub name: &'static str,
+    /// A one-line description, shown by `--list`.
+    pub about: &'static str,
+    /// The runners this scenario applies to, or empty for all of them.
+    pub runners: &'static [RunnerKind],
+    pub run: fn(&TestContext) -> Result<()>,
+}
+
+impl Scenario {
+    /// Returns true if the scenario should run with the given runner.
+    pub fn applies_to(&self, runner: RunnerKind) -> bool {
+        self.runners.is_empty() || self.runners.contains(&runner)
+    }
+}
+
+/// All the scenarios, in the order they run.
+pub const SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "not-found",
+        about: "commands fail cleanly when given a package that doesn't exist",
+        runners: &[],
+        run: package_not_found_errors,
+    },
+    Scenario {
+        name: "lifecycle",
+        about: "create, run commands in, reset, and purge an environment",
+        runners: &[],
+        run: environment_lifecycle,
+    },
+    Scenario {
+        name: "packages",
+        about: "build packages, rebuild them when edited, and update them",
+        runners: &[],
+        run: package_lifecycle,
+    },
+    Scenario {
+        name: "failures",
+        about: "failing commands and package builds are reported and leave no mess",
+        runners: &[],
+        run: failure_injection,
+    },
+];
+
+/// Returns all the updates for the named packages and their dependencies.
+fn update_always() -> UpdatePackagesConditions {
+    UpdatePackagesConditions {
+        dependencies: ShouldPackageUpdate::Always,
+        named: ShouldPackageUpdate::Always,
+        excluded: BTreeSet::new(),
+    }
+}
+
+/// Read a file's contents and write it back to disk.
+///
+/// The purpose of this function is to update the file modified time. It's not
+/// trivial to update that metadata in a cross-platform way, so this just
+/// rewrites the file instead.
+fn rewrite<P: AsRef<Path>>(path: P) -> Result<()> {
+    let rewrite_ = |path: &Path| -> std::io::Result<()> {
+        let contents = std::fs::read(path)?;
+        std::fs::write(path, contents)?;
+        Ok(())
+    };
+    let path = path.as_ref();
+    rewrite_(path).with_context(|| format!("Failed to rewrite {path:?}"))
+}
+
+fn package_not_found_errors(ctx: &TestContext) -> Result<()> {
+    let TestContext { cub, test_env, .. } = ctx;
+    cub.purge_environment(test_env, Quiet(false))?;
+
+    let not_exist = BTreeSet::from([FullPackageName::from_str("does-not-exist")?]);
+
+    // cub new --packages=does-not-exist
+    let err = cub
+        .new_environment(test_env, Some(not_exist.clone()), KeepPartial(false))
+        .expect_err("should not be able to use does-not-exist package in `cub new`");
+    expect![[r#"could not find package definition for "does-not-exist""#]]
+        .assert_eq(&err.debug_without_backtrace());
+
+    let envs = cub.get_environment_names()?;
+    assert!(
+        !envs.contains(test_env),
+        "{test_env} environment should not exist"
+    );
+
+    // cub tmp --packages=does-not-exist
+    let err = cub
+        .create_enter_tmp_environment(Some(not_exist.clone()), None)
+        .expect_err("should not be able to use does-not-exist package in `cub tmp`");
+    expect![[r#"could not find package definition for "does-not-exist""#]]
+        .assert_eq(&err.debug_without_backtrace());
+    let new_envs = cub
+        .get_environment_names()?
+        .difference(&envs)
+        .cloned()
+        .collect::<Vec<_>>();
+    assert!(
+        new_envs.is_empty(),
+        "new tmp environment should not exist, found {new_envs:?}"
+    );
+
+    // cub reset --packages=does-not-exist
+    cub.new_environment(test_env, Some(BTreeSet::new()), KeepPartial(false))?;
+    cub.exec_environment(test_env, &[String::from("touch"), String::from("../foo")])?;
+    let err = cub
+        .reset_environment(test_env, Some(not_exist.clone()))
+        .expect_err("should not be able to use does-not-exist package in `cub reset`");
+    expect![[r#"could not find package definition for "does-not-exist""#]]
+        .assert_eq(&err.debug_without_backtrace());
+    cub.exec_environment(test_env, &[String::from("cat"), String::from("../foo")])
+        .context("file `../foo` should still exist")?;
+
+    // cub package update does-not-exist
+    let err = cub
+        .update_packages(&not_exist, &cub.scan_packages()?, &update_always())
+        .expect_err("should not be able to use does-not-exist package in `cub tmp`");
+    expect![[r#"could not find package definition for "does-not-exist""#]]
+        .assert_eq(&err.debug_without_backtrace());
+
+    cub.purge_environment(test_env, Quiet(false))
+}
+
+fn environment_lifecycle(ctx: &TestContext) -> Result<()> {
+    let TestContext { cub, test_env, .. } = ctx;
+    cub.purge_environment(test_env, Quiet(false))?;
+    cub.new_environment(test_env, Some(BTreeSet::new()), KeepPartial(false))?;
+    cub.exec_environment(test_env, &["ls", "-l", ".."].map(String::from))?;
+    cub.reset_environment(test_env, None)?;
+
+    assert!(cub.get_environment_names()?.contains(test_env));
+    println!("{:#?}", cub.get_environment(test_env)?);
+    cub.purge_environment(test_env, Quiet(false))?;
+    assert!(!cub.get_environment_names()?.contains(test_env));
+    Ok(())
+}
+
+fn package_lifecycle(ctx: &TestContext) -> Result<()> {
+    let TestContext {
+        cub,
+        project_root,
+        test_env,
+        ..
+    } = ctx;
+    let configs_pkg = FullPackageName::from_str("configs-interactive")?;
+
+    cub.purge_environment(test_env, Quiet(false))?;
+    cub.new_environment(
+        test_env,
+        Some(BTreeSet::from([configs_pkg])),
+        KeepPartial(false),
+    )?;
+    cub.exec_environment(test_env, &["ls", "-al", ".."].map(String::from))?;
+    // This should cause the configs-interactive package to be rebuilt.
+    rewrite(project_root.join("packages/configs-interactive/build.sh"))?;
+    cub.reset_environment(test_env, None)?;
+    cub.exec_environment(test_env, &["ls", "-al", ".."].map(String::from))?;
+    cub.purge_environment(test_env, Quiet(false))?;
+
+    let no_op = FullPackageName::from_str("no-op")?;
+    let packages = BTreeSet::from([no_op.clone()]);
+    cub.update_packages(&packages, &cub.scan_packages()?, &update_always())?;
+    let details = cub
+        .get_packages()?
+        .remove(&no_op)
+        .ok_or_else(|| anyhow!("package {no_op} missing after update"))?;
+    assert!(details.built.is_some());
+    assert!(!details.last_build_failed);
+    Ok(())
+}
+
+/// A built-in package whose build always fails. It's removed when dropped.
+struct FailingPackage {
+    dir: PathBuf,
+}
+
+impl FailingPackage {
+    const NAME: &'static str = "system-test-fails";
+
+    fn new(project_root: &Path) -> Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = project_root.join("packages").join(Self::NAME);
+        let write = || -> std::io::Result<()> {
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(dir.join("package.toml"), "")?;
+            let build = dir.join("build.sh");
+            std::fs::write(&build, "#!/bin/sh\necho 'failing on purpose' >&2\nexit 1\n")?;
+            std::fs::set_permissions(&build, std::fs::Permissions::from_mode(0o755))
+        };
+        write().with_context(|| format!("failed to write package {dir:?}"))?;
+        Ok(Self { dir })
+    }
+}
+
+impl Drop for FailingPackage {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            println!("WARNING: failed to remove {:?}: {e}", self.dir);
+        }
+    }
+}
+
+fn failure_injection(ctx: &TestContext) -> Result<()> {
+    let TestContext {
+        cub,
+        project_root,
+        test_env,
+        ..
+    } = ctx;
+
+    // A failing command is reported and leaves the environment alone.
+    cub.purge_environment(test_env, Quiet(false))?;
+    cub.new_environment(test_env, Some(BTreeSet::new()), KeepPartial(false))?;
+    cub.exec_environment(test_env, &["sh", "-c", "exit 3"].map(String::from))
+        .expect_err("`exit 3` should fail");
+    assert!(cub.get_environment_names()?.contains(test_env));
+    cub.purge_environment(test_env, Quiet(false))?;
+
+    // A failing package build is reported, recorded, and doesn't create the
+    // environment that needs the package.
+    let _package = FailingPackage::new(project_root)?;
+    let failing = FullPackageName::from_str(FailingPackage::NAME)?;
+    let packages = BTreeSet::from([failing.clone()]);
+    let err = cub
+        .update_packages(&packages, &cub.scan_packages()?, &update_always())
+        .expect_err("failing package should not build");
+    assert_eq!(Some(ErrorKind::PackageBuildFailed), err.kind(), "{err:?}");
+    let details = cub
+        .get_packages()?
+        .remove(&failing)
+        .ok_or_else(|| anyhow!("package {failing} missing after failed update"))?;
+    assert!(details.built.is_none());
+    assert!(details.last_build_failed);
+
+    cub.new_environment(test_env, Some(packages), KeepPartial(false))
+        .expect_err("environment should not be created with a failing package");
+    assert!(!cub.get_environment_names()?.contains(test_env));
+
+    cub.purge_environment(
+        &EnvironmentName::for_builder_package(&failing),
+        Quiet(false),
+    )
+}
@@ -1,66 +1,151 @@
 use std::collections::BTreeSet;
 use std::io::{self, Write};
+use std::os::fd::OwnedFd;
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{ChildStdout, Stdio};
-use std::rc::Rc;
+use std::sync::Arc;
 use tempfile::NamedTempFile;
 
 use super::apt;
+use super::caches;
 use super::command_ext::Command;
-use super::fs_util::{rmtree, summarize_dir, try_exists, try_iterdir_dirs, DirSummary};
+use super::config::{BubblewrapNetwork, PathOrDisabled};
+use super::fs_util::{
+    create_home_tar, create_tar_of_paths, rmtree, summarize_dir, try_exists, try_iterdir_dirs,
+    DirSummary,
+};
+use super::host_open;
 use super::paths::EnvPath;
 use super::runner::{
-    EnvFilesSummary, EnvironmentExists, Init, Runner, RunnerCommand, Target,
-    LOCALE_ENVIRONMENT_VARIABLES,
+    interactive_shell_command, Capability, CodeTarget, EnvDir, EnvFilesSummary, EnvNetwork,
+    EnvPiece, EnvironmentExists, Init, Runner, RunnerCommand, Target, LOCALE_ENVIRONMENT_VARIABLES,
 };
-use super::{CubicleShared, EnvironmentName, ExitStatusError, HostPath};
-use crate::somehow::{Context, Result};
+use super::seccomp::check_bubblewrap_policy;
+use super::x11;
+use super::{CubicleShared, EnvironmentName, Event, ExitStatusError, HostPath, Phase};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+mod dbus;
+mod gocryptfs;
+mod layers;
+mod slirp;
 
 pub struct Bubblewrap {
-    pub(super) program: Rc<CubicleShared>,
+    pub(super) program: Arc<CubicleShared>,
     home_dirs: HostPath,
     work_dirs: HostPath,
+    overlay_dirs: HostPath,
+    encrypted_dirs: HostPath,
+    decrypted_dirs: HostPath,
+    layers: layers::Layers,
 }
 
 struct Dirs {
     host_home: HostPath,
     host_work: HostPath,
+    /// The overlayfs work directory, used only for instances of golden
+    /// environments and environments with seed layers.
+    host_overlay: HostPath,
+    /// The gocryptfs cipher directory, used only for encrypted
+    /// environments.
+    host_cipher: HostPath,
+    /// Where the decrypted view of `host_cipher` is mounted. For encrypted
+    /// environments, `host_home` and `host_work` are within it.
+    host_decrypted: HostPath,
+    /// True if `host_cipher` exists.
+    encrypted: bool,
 }
 
 struct BwrapArgs<'a> {
     bind: &'a [(&'a HostPath, &'a EnvPath)],
     run: &'a RunnerCommand<'a>,
     stdin: Option<ChildStdout>,
+    /// If true, the home directory is mounted through an overlay with a
+    /// temporary upper layer, so changes to it are discarded.
+    tmp_home: bool,
 }
 
 impl Bubblewrap {
-    pub(super) fn new(program: Rc<CubicleShared>) -> Result<Self> {
-        let xdg_cache_home = match std::env::var("XDG_CACHE_HOME") {
-            Ok(path) => HostPath::try_from(path)?,
-            Err(_) => program.home.join(".cache"),
-        };
-        let home_dirs = xdg_cache_home.join("cubicle").join("home");
+    pub(super) fn new(program: Arc<CubicleShared>) -> Result<Self> {
+        let home_dirs = program.paths.home_dirs.clone();
+        let overlay_dirs = program.paths.cache.join("overlay");
+        let work_dirs = program.paths.work_dirs.clone();
+        let encrypted_dirs = program.paths.data.join("encrypted");
+        let decrypted_dirs = program.paths.cache.join("decrypted");
+        let layers = layers::Layers::new(
+            program.paths.cache.join("layers"),
+            program.paths.cache.join("layer-lists"),
+        );
 
-        let xdg_data_home = match std::env::var("XDG_DATA_HOME") {
-            Ok(path) => HostPath::try_from(path)?,
-            Err(_) => program.home.join(".local").join("share"),
-        };
-        let work_dirs = xdg_data_home.join("cubicle").join("work");
+        let config = program
+            .config
+            .bubblewrap
+            .as_ref()
+            .expect("Bubblewrap config needed");
+        if let PathOrDisabled::Path(path) = &config.seccomp {
+            // Better give an early error message if this isn't configured right.
+            check_bubblewrap_policy(path, &program.exe_name)?;
+        }
 
         Ok(Self {
             program,
             home_dirs,
             work_dirs,
+            overlay_dirs,
+            encrypted_dirs,
+            decrypted_dirs,
+            layers,
         })
     }
 
-    fn dirs(&self, name: &EnvironmentName) -> Dirs {
+    fn dirs(&self, name: &EnvironmentName) -> Result<Dirs> {
         let encoded = name.as_filename();
-        Dirs {
-            host_home: self.home_dirs.join(&encoded),
-            host_work: self.work_dirs.join(&encoded),
+        let host_cipher = self.encrypted_dirs.join(&encoded);
+        let host_decrypted = self.decrypted_dirs.join(&encoded);
+        let encrypted = try_exists(&host_cipher).todo_context()?;
+        let (host_home, host_work) = if encrypted {
+            (host_decrypted.join("home"), host_decrypted.join("work"))
+        } else {
+            (self.home_dirs.join(&encoded), self.work_dirs.join(&encoded))
+        };
+        Ok(Dirs {
+            host_home,
+            host_work,
+            host_overlay: self.overlay_dirs.join(&encoded),
+            host_cipher,
+            host_decrypted,
+            encrypted,
+        })
+    }
+
+    /// Mounts the decrypted files of an encrypted environment, if they're
+    /// not already mounted. They stay mounted until the environment is
+    /// stopped or purged. This does nothing for other environments.
+    fn unlock(&self, name: &EnvironmentName, dirs: &Dirs) -> Result<()> {
+        if !dirs.encrypted {
+            return Ok(());
         }
+        let _lock = self.program.locks.lock_decrypted(name)?;
+        gocryptfs::mount(
+            &dirs.host_cipher,
+            &dirs.host_decrypted,
+            self.config().encryption_password_command.as_deref(),
+        )
+        .context("failed to mount encrypted environment files")
+    }
+
+    /// Unmounts the decrypted files of an encrypted environment, if they're
+    /// mounted.
+    fn unmount_decrypted(&self, name: &EnvironmentName, dirs: &Dirs) -> Result<()> {
+        if !dirs.encrypted {
+            return Ok(());
+        }
+        let _lock = self.program.locks.lock_decrypted(name)?;
+        if gocryptfs::is_mounted(&dirs.host_decrypted)? {
+            gocryptfs::unmount(&dirs.host_decrypted)?;
+        }
+        Ok(())
     }
 
     fn config(&self) -> &super::config::Bubblewrap {
@@ -71,6 +156,92 @@ impl Bubblewrap {
             .expect("Bubblewrap config needed")
     }
 
+    /// Returns the read-only layers under the environment's home directory,
+    /// from lowest to highest. The home directory is an overlay with its
+    /// changes on top of these, or a plain directory if there are none.
+    fn lower_layers(&self, name: &EnvironmentName) -> Result<Vec<HostPath>> {
+        match self.program.golden.golden(name)? {
+            Some(golden) => {
+                let mut layers = self.layers.get(&golden)?;
+                layers.push(self.dirs(&golden)?.host_home);
+                Ok(layers)
+            }
+            None => self.layers.get(name),
+        }
+    }
+
+    /// Returns true if the environment needs an overlayfs work directory.
+    fn has_overlay(&self, name: &EnvironmentName) -> Result<bool> {
+        Ok(!self.lower_layers(name)?.is_empty())
+    }
+
+    /// Returns true if the environment's seeds should be extracted into
+    /// shared layers, rather than into its home directory.
+    ///
+    /// Golden environments' instances already share the golden
+    /// environment's layers, and encrypted environments keep all their
+    /// files within the encrypted directory.
+    fn uses_seed_layers(&self, name: &EnvironmentName, dirs: &Dirs) -> Result<bool> {
+        Ok(self.config().overlay_home
+            && !dirs.encrypted
+            && self.program.golden.golden(name)?.is_none())
+    }
+
+    /// Makes the seed tarballs the layers under the environment's home
+    /// directory, extracting them as needed.
+    fn set_seed_layers(&self, name: &EnvironmentName, seeds: &[HostPath]) -> Result<()> {
+        self.program.event(Event::CopyingSeeds(name.clone()));
+        self.program
+            .timings
+            .time(Phase::CopySeeds(name.clone()), || -> Result<()> {
+                let _lock = self.program.locks.lock_seed_layers()?;
+                self.layers.set(name, seeds)?;
+                self.layers.remove_unused()
+            })?;
+        let host_overlay = self.dirs(name)?.host_overlay;
+        std::fs::create_dir_all(host_overlay.as_host_raw()).todo_context()
+    }
+
+    /// Forgets the environment's seed layers, if any.
+    fn forget_seed_layers(&self, name: &EnvironmentName) -> Result<()> {
+        if self.layers.get(name)?.is_empty() {
+            return Ok(());
+        }
+        let _lock = self.program.locks.lock_seed_layers()?;
+        self.layers.forget(name)?;
+        self.layers.remove_unused()
+    }
+
+    /// Extracts the seed tarballs into the environment's home directory.
+    fn copy_seeds(&self, name: &EnvironmentName, seeds: &[HostPath]) -> Result<()> {
+        if seeds.is_empty() {
+            return Ok(());
+        }
+        self.program.event(Event::CopyingSeeds(name.clone()));
+        self.program
+            .timings
+            .time(Phase::CopySeeds(name.clone()), || -> Result<()> {
+                let mut child = Command::new("pv")
+                    .args(["--interval", "0.1"])
+                    .args(seeds.iter().map(|s| s.as_host_raw()))
+                    .stdout(Stdio::piped())
+                    .scoped_spawn()?;
+                self.bwrap(
+                    name,
+                    BwrapArgs {
+                        bind: &[],
+                        run: &RunnerCommand::Exec {
+                            command: &["tar", "--ignore-zero", "--directory", "..", "--extract"]
+                                .map(|s| s.to_owned()),
+                            env_vars: &[],
+                        },
+                        stdin: child.stdout().take(),
+                        tmp_home: false,
+                    },
+                )
+            })
+    }
+
     fn init(
         &self,
         name: &EnvironmentName,
@@ -87,26 +258,12 @@ impl Bubblewrap {
                 .collect::<Vec<&str>>(),
         );
 
-        if !seeds.is_empty() {
-            println!("Copying/extracting seed tarball");
-            let mut child = Command::new("pv")
-                .args(["--interval", "0.1"])
-                .args(seeds.iter().map(|s| s.as_host_raw()))
-                .stdout(Stdio::piped())
-                .scoped_spawn()?;
-            self.bwrap(
-                name,
-                BwrapArgs {
-                    bind: &[],
-                    run: &RunnerCommand::Exec {
-                        command: &["tar", "--ignore-zero", "--directory", "..", "--extract"]
-                            .map(|s| s.to_owned()),
-                        env_vars: &[],
-                    },
-                    stdin: child.stdout().take(),
-                },
-            )?;
-        };
+        if self.uses_seed_layers(name, &self.dirs(name)?)? {
+            self.set_seed_layers(name, seeds)?;
+        } else {
+            self.forget_seed_layers(name)?;
+            self.copy_seeds(name, seeds)?;
+        }
 
         let host_script_temp = {
             let file = NamedTempFile::new()
@@ -142,6 +299,7 @@ impl Bubblewrap {
                     env_vars,
                 },
                 stdin: None,
+                tmp_home: false,
             },
         )
     }
@@ -149,12 +307,28 @@ impl Bubblewrap {
     fn bwrap(
         &self,
         name: &EnvironmentName,
-        BwrapArgs { bind, run, stdin }: BwrapArgs,
+        BwrapArgs {
+            bind,
+            run,
+            stdin,
+            tmp_home,
+        }: BwrapArgs,
     ) -> Result<()> {
         let Dirs {
             host_home,
             host_work,
-        } = self.dirs(name);
+            host_overlay,
+            host_decrypted,
+            encrypted,
+            ..
+        } = self.dirs(name)?;
+        if encrypted && !gocryptfs::is_mounted(&host_decrypted)? {
+            // Otherwise, the sandbox would write its files unencrypted.
+            return Err(anyhow!(
+                "refusing to run encrypted environment {name} because its decrypted \
+                files aren't mounted at {host_decrypted}"
+            ));
+        }
 
         let seccomp: Option<std::fs::File> = {
             use super::config::PathOrDisabled::*;
@@ -191,8 +365,8 @@ impl Bubblewrap {
             }
         }
         match run {
-            RunnerCommand::Interactive => {}
-            RunnerCommand::Exec { env_vars, .. } => {
+            RunnerCommand::Pipe { .. } => {}
+            RunnerCommand::Interactive { env_vars, .. } | RunnerCommand::Exec { env_vars, .. } => {
                 for (var, value) in *env_vars {
                     command.env(var, value);
                 }
@@ -204,6 +378,31 @@ impl Bubblewrap {
         command.arg("--unshare-ipc");
         command.arg("--unshare-pid");
         command.arg("--unshare-uts");
+        if let Some(userns) = &self.config().user_namespace {
+            command.arg("--unshare-user");
+            if let Some(uid) = userns.uid {
+                command.arg("--uid").arg(uid.to_string());
+            }
+            if let Some(gid) = userns.gid {
+                command.arg("--gid").arg(gid.to_string());
+            }
+        }
+        let dbus = dbus::Bus::connect(self.config().dbus, &self.config().dbus_talk)
+            .context("failed to set up D-Bus access")?;
+        if dbus.is_some() {
+            command.env("DBUS_SESSION_BUS_ADDRESS", dbus::ENV_ADDRESS);
+        }
+        let slirp = match self.config().network {
+            BubblewrapNetwork::Host => None,
+            BubblewrapNetwork::None => {
+                command.arg("--unshare-net");
+                None
+            }
+            BubblewrapNetwork::Slirp => {
+                command.arg("--unshare-net");
+                Some(slirp::Setup::new()?)
+            }
+        };
 
         command.args(["--symlink", "/usr/bin", "/bin"]);
         command.args(["--dev", "/dev"]);
@@ -216,14 +415,88 @@ impl Bubblewrap {
         }
 
         command.args(ro_bind_try("/etc"));
-        command
-            .arg("--bind")
-            .arg(host_home.as_host_raw())
-            .arg(env_home.as_env_raw());
-        command
-            .arg("--bind")
-            .arg(host_work.as_host_raw())
-            .arg(env_home.join("w").as_env_raw());
+        if let Some(slirp) = &slirp {
+            command.args(slirp.bwrap_args()?);
+        }
+        let lower_layers = self.lower_layers(name)?;
+        for layer in &lower_layers {
+            command.arg("--overlay-src").arg(layer.as_host_raw());
+        }
+        if tmp_home {
+            // The home directory is the read-only top lower layer, and the
+            // changes go in a tmpfs that's discarded when bwrap exits.
+            command
+                .arg("--overlay-src")
+                .arg(host_home.as_host_raw())
+                .arg("--tmp-overlay")
+                .arg(env_home.as_env_raw());
+        } else if lower_layers.is_empty() {
+            command
+                .arg("--bind")
+                .arg(host_home.as_host_raw())
+                .arg(env_home.as_env_raw());
+        } else {
+            // The golden environment's home directory or the seed layers
+            // are the read-only lower layers, and this environment's home
+            // directory holds its changes.
+            command
+                .arg("--overlay")
+                .arg(host_home.as_host_raw())
+                .arg(host_overlay.as_host_raw())
+                .arg(env_home.as_env_raw());
+        }
+        match self.program.worktrees.get(name)? {
+            None => {
+                command
+                    .arg("--bind")
+                    .arg(host_work.as_host_raw())
+                    .arg(env_home.join("w").as_env_raw());
+            }
+            Some(worktree) => {
+                // The worktree's `.git` file refers to the repository's git
+                // directory by its host path.
+                command
+                    .arg("--bind")
+                    .arg(worktree.path.as_host_raw())
+                    .arg(env_home.join("w").as_env_raw());
+                for mount in worktree.prepare_git_mounts()? {
+                    command
+                        .arg(if mount.writable {
+                            "--bind"
+                        } else {
+                            "--ro-bind"
+                        })
+                        .arg(mount.path.as_host_raw())
+                        .arg(mount.path.as_host_raw());
+                }
+            }
+        }
+        let x11_dir = x11::host_dir(&self.program.paths, name);
+        match x11::write_xauthority(&x11_dir) {
+            Ok(true) => {
+                command.env("XAUTHORITY", x11::ENV_XAUTHORITY);
+                command
+                    .arg("--ro-bind")
+                    .arg(x11_dir.as_host_raw())
+                    .arg(x11::ENV_DIR);
+            }
+            Ok(false) => {}
+            Err(e) => warn(e.context("failed to set up X11 authorization")),
+        }
+        if let Some(dbus) = &dbus {
+            command.args(dbus.bwrap_args());
+        }
+        if self.program.config.host_open.enabled {
+            let dir = host_open::host_dir(&self.program.paths, name);
+            host_open::create_pipe(&dir)?;
+            command
+                .arg("--bind")
+                .arg(dir.as_host_raw())
+                .arg(host_open::ENV_DIR);
+        }
+        for (dir, target) in caches::mounts(&self.program)? {
+            command.arg("--bind").arg(dir.as_host_raw()).arg(target);
+        }
         command.args(["--symlink", "/usr/lib", "/lib"]);
         command.args(["--symlink", "/usr/lib64", "/lib64"]);
         command.args(ro_bind_try("/opt"));
@@ -233,6 +506,14 @@ impl Bubblewrap {
         command.args(ro_bind_try("/usr"));
         command.args(ro_bind_try("/var/lib/apt/lists"));
         command.args(ro_bind_try("/var/lib/dpkg"));
+        // These come after the built-in binds so that they can be nested
+        // within them, like a directory under `/usr`.
+        for path in &self.config().ro_binds {
+            command.arg("--ro-bind-try").arg(path).arg(path);
+        }
+        for path in &self.config().rw_binds {
+            command.arg("--bind-try").arg(path).arg(path);
+        }
         if let Some(seccomp) = &seccomp {
             command
                 .arg("--seccomp")
@@ -242,30 +523,29 @@ impl Bubblewrap {
         }
         command.arg("--chdir").arg(env_home.join("w").as_env_raw());
         command.arg("--");
-        command.arg(&self.program.shell);
-        command.arg("-l");
-
         match run {
-            RunnerCommand::Interactive => {}
-            RunnerCommand::Exec { command: exec, .. } => {
+            RunnerCommand::Interactive { shell, .. } => {
+                command.args(interactive_shell_command(*shell, &self.program.shell));
+            }
+            RunnerCommand::Exec { command: exec, .. } | RunnerCommand::Pipe { command: exec } => {
+                command.arg(&self.program.shell);
+                command.arg("-l");
                 command.arg("-c");
                 command.arg(shlex::try_join(exec.iter().map(|a| a.as_str())).expect("TODO"));
             }
         }
 
-        let status = match stdin {
-            None => command.status(),
-            Some(mut reader) => {
-                command.stdin(Stdio::piped());
-                let mut child = command.scoped_spawn()?;
-                {
-                    let mut writer = child.stdin().take().unwrap();
-                    io::copy(&mut reader, &mut writer).todo_context()?;
-                    // drop writer to close stdin
-                }
-                child.wait()
-            }
-        }?;
+        if stdin.is_some() {
+            command.stdin(Stdio::piped());
+        }
+        let mut child = command.scoped_spawn()?;
+        let _slirp = slirp.map(slirp::Setup::start).transpose()?;
+        if let Some(mut reader) = stdin {
+            let mut writer = child.stdin().take().unwrap();
+            io::copy(&mut reader, &mut writer).todo_context()?;
+            // drop writer to close stdin
+        }
+        let status = child.wait()?;
 
         if status.success() {
             Ok(())
@@ -286,6 +566,12 @@ where
     Ok(file.as_raw_fd().to_string())
 }
 
+/// Creates a pipe whose ends are closed on exec, unless
+/// [`get_fd_for_child`] is called on them.
+fn pipe() -> std::io::Result<(OwnedFd, OwnedFd)> {
+    Ok(rustix::pipe::pipe_with(rustix::pipe::PipeFlags::CLOEXEC)?)
+}
+
 fn ro_bind_try(path: &str) -> [&str; 3] {
     ["--ro-bind-try", path, path]
 }
@@ -297,9 +583,10 @@ impl Runner for Bubblewrap {
         path: &Path,
         w: &mut dyn io::Write,
     ) -> Result<()> {
-        let Dirs { host_home, .. } = self.dirs(name);
+        let dirs = self.dirs(name)?;
+        self.unlock(name, &dirs)?;
         let home_dir = cap_std::fs::Dir::open_ambient_dir(
-            host_home.as_host_raw(),
+            dirs.host_home.as_host_raw(),
             cap_std::ambient_authority(),
         )
         .todo_context()?;
@@ -314,9 +601,10 @@ impl Runner for Bubblewrap {
         path: &Path,
         w: &mut dyn io::Write,
     ) -> Result<()> {
-        let Dirs { host_work, .. } = self.dirs(name);
+        let dirs = self.dirs(name)?;
+        self.unlock(name, &dirs)?;
         let work_dir = cap_std::fs::Dir::open_ambient_dir(
-            host_work.as_host_raw(),
+            dirs.host_work.as_host_raw(),
             cap_std::ambient_authority(),
         )
         .todo_context()?;
@@ -325,25 +613,75 @@ impl Runner for Bubblewrap {
         Ok(())
     }
 
+    fn backup(&self, name: &EnvironmentName, w: &mut dyn io::Write) -> Result<()> {
+        let dirs = self.dirs(name)?;
+        self.unlock(name, &dirs)?;
+        create_home_tar(&dirs.host_home, &dirs.host_work, w)
+    }
+
+    fn save_home_paths(
+        &self,
+        name: &EnvironmentName,
+        paths: &[PathBuf],
+        w: &mut dyn io::Write,
+    ) -> Result<()> {
+        let dirs = self.dirs(name)?;
+        self.unlock(name, &dirs)?;
+        create_tar_of_paths(&dirs.host_home, paths, w)
+    }
+
     fn create(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        let dirs = self.dirs(name)?;
+        self.unlock(name, &dirs)?;
         let Dirs {
             host_home,
             host_work,
-        } = self.dirs(name);
+            host_overlay,
+            ..
+        } = dirs;
         std::fs::create_dir_all(host_home.as_host_raw()).todo_context()?;
         std::fs::create_dir_all(host_work.as_host_raw()).todo_context()?;
+        if self.program.golden.golden(name)?.is_some() {
+            std::fs::create_dir_all(host_overlay.as_host_raw()).todo_context()?;
+        }
         self.init(name, init)
     }
 
+    fn create_encrypted(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        let Dirs { host_cipher, .. } = self.dirs(name)?;
+        std::fs::create_dir_all(host_cipher.as_host_raw()).todo_context()?;
+        gocryptfs::init(
+            &host_cipher,
+            self.config().encryption_password_command.as_deref(),
+        )?;
+        self.create(name, init)
+    }
+
+    fn is_encrypted(&self, name: &EnvironmentName) -> Result<bool> {
+        Ok(self.dirs(name)?.encrypted)
+    }
+
     fn exists(&self, name: &EnvironmentName) -> Result<EnvironmentExists> {
+        use EnvironmentExists::*;
         let Dirs {
             host_home,
             host_work,
-        } = self.dirs(name);
+            host_cipher,
+            encrypted,
+            ..
+        } = self.dirs(name)?;
+        if encrypted {
+            // The home and work directories can't be checked without the
+            // password.
+            let initialized = try_exists(&host_cipher.join(gocryptfs::CONF_FILE)).todo_context()?;
+            return Ok(if initialized {
+                FullyExists
+            } else {
+                PartiallyExists
+            });
+        }
         let has_home_dir = try_exists(&host_home).todo_context()?;
         let has_work_dir = try_exists(&host_work).todo_context()?;
-
-        use EnvironmentExists::*;
         Ok(if has_home_dir && has_work_dir {
             FullyExists
         } else if has_home_dir || has_work_dir {
@@ -353,32 +691,63 @@ impl Runner for Bubblewrap {
         })
     }
 
-    fn stop(&self, _name: &EnvironmentName) -> Result<()> {
+    fn networks(&self, _name: &EnvironmentName) -> Result<Vec<EnvNetwork>> {
+        // shares the host's network namespace
+        Ok(Vec::new())
+    }
+
+    fn stop(&self, name: &EnvironmentName) -> Result<()> {
         // don't know how to enumerate such processes, so don't bother
-        Ok(())
+
+        // An encrypted environment's files stay mounted until it's stopped.
+        self.unmount_decrypted(name, &self.dirs(name)?)
+    }
+
+    fn restarts_after_stop(&self) -> bool {
+        true
+    }
+
+    fn supports_golden(&self) -> bool {
+        true
+    }
+
+    fn supports_encryption(&self) -> bool {
+        true
+    }
+
+    fn supports_read_only_home(&self) -> bool {
+        true
+    }
+
+    fn supports_worktrees(&self) -> bool {
+        true
+    }
+
+    fn supports_host_open(&self) -> bool {
+        true
+    }
+
+    fn supports_caches(&self) -> bool {
+        true
+    }
+
+    fn available_debian_packages(&self) -> Result<Option<BTreeSet<String>>> {
+        apt::available_packages().map(Some)
     }
 
     fn list(&self) -> Result<Vec<EnvironmentName>> {
         let mut envs = BTreeSet::new();
 
-        for name in try_iterdir_dirs(&self.home_dirs)? {
-            let env = EnvironmentName::from_filename(&name).with_context(|| {
-                format!(
-                    "error parsing environment name from path {}",
-                    self.home_dirs.join(&name)
-                )
-            })?;
-            envs.insert(env);
-        }
-
-        for name in try_iterdir_dirs(&self.work_dirs)? {
-            let env = EnvironmentName::from_filename(&name).with_context(|| {
-                format!(
-                    "error parsing environment name from path {}",
-                    self.work_dirs.join(&name)
-                )
-            })?;
-            envs.insert(env);
+        for dirs in [&self.home_dirs, &self.work_dirs, &self.encrypted_dirs] {
+            for name in try_iterdir_dirs(dirs)? {
+                let env = EnvironmentName::from_filename(&name).with_context(|| {
+                    format!(
+                        "error parsing environment name from path {}",
+                        dirs.join(&name)
+                    )
+                })?;
+                envs.insert(env);
+            }
         }
 
         Ok(Vec::from_iter(envs))
@@ -388,7 +757,19 @@ impl Runner for Bubblewrap {
         let Dirs {
             host_home: home_dir,
             host_work: work_dir,
-        } = self.dirs(name);
+            host_decrypted,
+            encrypted,
+            ..
+        } = self.dirs(name)?;
+        if encrypted && !gocryptfs::is_mounted(&host_decrypted)? {
+            // Summarizing the files would need the password.
+            return Ok(EnvFilesSummary {
+                home_dir_path: None,
+                home_dir: DirSummary::new_with_errors(),
+                work_dir_path: None,
+                work_dir: DirSummary::new_with_errors(),
+            });
+        }
 
         let home_dir_exists = try_exists(&home_dir).todo_context()?;
         let home_dir_summary = if home_dir_exists {
@@ -412,39 +793,157 @@ impl Runner for Bubblewrap {
         })
     }
 
+    fn code_target(&self, name: &EnvironmentName) -> Result<CodeTarget> {
+        let dirs = self.dirs(name)?;
+        if dirs.encrypted {
+            return Err(anyhow!(
+                "can't open encrypted environment {name} in VS Code, since its \
+                files are only mounted while Cubicle uses them"
+            ));
+        }
+        Ok(CodeTarget::HostDir(dirs.host_work))
+    }
+
+    fn username(&self, _name: &EnvironmentName) -> Result<String> {
+        // Bubblewrap runs commands as the same user.
+        std::env::var("USER").context("Invalid $USER")
+    }
+
     fn reset(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        let dirs = self.dirs(name)?;
+        self.unlock(name, &dirs)?;
         let Dirs {
             host_home,
             host_work,
-        } = self.dirs(name);
+            host_overlay,
+            ..
+        } = dirs;
         rmtree(&host_home)?;
+        rmtree(&host_overlay)?;
         std::fs::create_dir_all(host_home.as_host_raw()).todo_context()?;
         std::fs::create_dir_all(host_work.as_host_raw()).todo_context()?;
+        if self.program.golden.golden(name)?.is_some() {
+            std::fs::create_dir_all(host_overlay.as_host_raw()).todo_context()?;
+        }
         self.init(name, init)
     }
 
+    fn reseed(&self, name: &EnvironmentName, seeds: &[HostPath]) -> Result<()> {
+        self.unlock(name, &self.dirs(name)?)?;
+        self.copy_seeds(name, seeds)
+    }
+
+    fn inspect(&self, name: &EnvironmentName) -> Result<Vec<EnvPiece>> {
+        let Dirs {
+            host_home,
+            host_work,
+            host_overlay,
+            host_cipher,
+            encrypted,
+            ..
+        } = self.dirs(name)?;
+        let mut dirs = if encrypted {
+            vec![("encrypted directory", host_cipher)]
+        } else {
+            vec![("home directory", host_home), ("work directory", host_work)]
+        };
+        if self.has_overlay(name)? {
+            dirs.push(("overlay directory", host_overlay));
+        }
+        dirs.into_iter()
+            .map(|(kind, path)| {
+                Ok(EnvPiece {
+                    kind,
+                    exists: try_exists(&path).todo_context()?,
+                    location: path.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    fn repair(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        let dirs = self.dirs(name)?;
+        self.unlock(name, &dirs)?;
+        let Dirs {
+            host_home,
+            host_work,
+            host_overlay,
+            ..
+        } = dirs;
+        let home_missing = !try_exists(&host_home).todo_context()?;
+        std::fs::create_dir_all(host_home.as_host_raw()).todo_context()?;
+        std::fs::create_dir_all(host_work.as_host_raw()).todo_context()?;
+        if self.has_overlay(name)? {
+            std::fs::create_dir_all(host_overlay.as_host_raw()).todo_context()?;
+        }
+        if home_missing {
+            self.init(name, init)
+        } else {
+            Ok(())
+        }
+    }
+
     fn purge(&self, name: &EnvironmentName) -> Result<()> {
+        let dirs = self.dirs(name)?;
+        self.unmount_decrypted(name, &dirs)?;
         let Dirs {
             host_home,
             host_work,
-        } = self.dirs(name);
+            host_overlay,
+            host_cipher,
+            host_decrypted,
+            encrypted,
+        } = dirs;
+        self.forget_seed_layers(name)?;
+        if encrypted {
+            rmtree(&host_cipher)?;
+            rmtree(&host_decrypted)?;
+            return rmtree(&host_overlay);
+        }
         rmtree(&host_home)?;
+        rmtree(&host_overlay)?;
         rmtree(&host_work)
     }
 
+    fn purge_dir(&self, name: &EnvironmentName, dir: EnvDir) -> Result<()> {
+        let Dirs {
+            host_home,
+            host_work,
+            host_overlay,
+            encrypted,
+            ..
+        } = self.dirs(name)?;
+        if encrypted {
+            return Err(anyhow!(
+                "can't purge part of an encrypted environment, since its home and work \
+                directories are encrypted together"
+            ));
+        }
+        match dir {
+            EnvDir::Home => {
+                self.forget_seed_layers(name)?;
+                rmtree(&host_home)?;
+                rmtree(&host_overlay)
+            }
+            EnvDir::Work => rmtree(&host_work),
+        }
+    }
+
     fn run(&self, name: &EnvironmentName, run: &RunnerCommand) -> Result<()> {
+        self.unlock(name, &self.dirs(name)?)?;
         self.bwrap(
             name,
             BwrapArgs {
                 bind: &[],
                 run,
                 stdin: None,
+                tmp_home: self.program.read_only.is_read_only(name)?,
             },
         )
     }
 
     fn supports_any(&self, targets: &[Target]) -> Result<bool> {
-        Ok(targets.iter().any(|Target { arch, os }| {
+        Ok(targets.iter().any(|Target { arch, os, .. }| {
             (match arch {
                 None => true,
                 Some(arch) => arch == std::env::consts::ARCH,
@@ -454,4 +953,30 @@ impl Runner for Bubblewrap {
             })
         }))
     }
+
+    fn capabilities(&self) -> BTreeSet<Capability> {
+        let config = self.config();
+        let mut capabilities = BTreeSet::new();
+        if config.network != BubblewrapNetwork::None {
+            capabilities.insert(Capability::Network);
+        }
+        // X11 programs reach the host's display through its abstract socket,
+        // which is only shared with the host's network namespace.
+        if config.network == BubblewrapNetwork::Host {
+            capabilities.insert(Capability::Gui);
+        }
+        // bwrap's minimal `/dev` only has `/dev/kvm` if it's bound in.
+        if config
+            .rw_binds
+            .iter()
+            .any(|path| path == Path::new("/dev/kvm"))
+        {
+            capabilities.insert(Capability::Kvm);
+        }
+        capabilities
+    }
+
+    fn storage_dirs(&self) -> Result<Vec<HostPath>> {
+        Ok(vec![self.home_dirs.clone(), self.work_dirs.clone()])
+    }
 }
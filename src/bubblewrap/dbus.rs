@@ -0,0 +1,196 @@
+//! Gives Bubblewrap environments access to the host's D-Bus session bus, for
+//! the `dbus` setting.
+//!
+//! With `dbus = "proxy"`, each bwrap process gets its own
+//! [xdg-dbus-proxy](https://github.com/flatpak/xdg-dbus-proxy), which only
+//! forwards messages to the XDG desktop portals and the names listed in
+//! `dbus_talk`. With `dbus = "host"`, the session bus socket is bound into the
+//! environment directly.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tempfile::TempDir;
+
+use super::{get_fd_for_child, pipe};
+use crate::command_ext::{Command, ScopedChild};
+use crate::config::BubblewrapDbus;
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// Where the bus socket appears inside the environments.
+const ENV_SOCKET: &str = "/run/cubicle-dbus/bus";
+
+/// The value for `$DBUS_SESSION_BUS_ADDRESS` inside the environments.
+pub const ENV_ADDRESS: &str = "unix:path=/run/cubicle-dbus/bus";
+
+/// Names that the proxy always lets environments talk to.
+const PORTAL_NAMES: &str = "org.freedesktop.portal.*";
+
+/// A session bus socket to bind into an environment. Any proxy is stopped
+/// when dropped, so this should be kept until bwrap exits.
+pub struct Bus {
+    socket: PathBuf,
+    _proxy: Option<Proxy>,
+}
+
+struct Proxy {
+    _child: ScopedChild,
+    /// xdg-dbus-proxy exits when this is closed.
+    _ready: File,
+    /// Holds the proxy's socket. This is removed when dropped.
+    _dir: TempDir,
+}
+
+impl Bus {
+    /// Finds the host's session bus and, for [`BubblewrapDbus::Proxy`],
+    /// starts a proxy for it. Returns `None` for [`BubblewrapDbus::None`].
+    pub fn connect(mode: BubblewrapDbus, talk: &[String]) -> Result<Option<Self>> {
+        let host_socket = match mode {
+            BubblewrapDbus::None => return Ok(None),
+            BubblewrapDbus::Proxy | BubblewrapDbus::Host => host_socket()?,
+        };
+        if mode == BubblewrapDbus::Host {
+            return Ok(Some(Self {
+                socket: host_socket,
+                _proxy: None,
+            }));
+        }
+
+        let dir = tempfile::Builder::new()
+            .prefix("cubicle-dbus-")
+            .tempdir()
+            .context("failed to create temp directory for D-Bus proxy")?;
+        let socket = dir.path().join("bus");
+        let (ready_read, ready_write) =
+            pipe().context("failed to create pipe for xdg-dbus-proxy readiness")?;
+        let mut address = OsString::from("unix:path=");
+        address.push(&host_socket);
+        let child = Command::new("xdg-dbus-proxy")
+            .arg(format!(
+                "--fd={}",
+                get_fd_for_child(&ready_write).context(
+                    "failed to set up ready file descriptor to be inherited by xdg-dbus-proxy"
+                )?
+            ))
+            .arg(address)
+            .arg(&socket)
+            .arg("--filter")
+            .arg(format!("--talk={PORTAL_NAMES}"))
+            .args(talk.iter().map(|name| format!("--talk={name}")))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .scoped_spawn()?;
+        drop(ready_write);
+
+        let mut ready = File::from(ready_read);
+        let mut buf = [0; 1];
+        let n = ready
+            .read(&mut buf)
+            .context("failed to wait for xdg-dbus-proxy")?;
+        if n == 0 {
+            return Err(anyhow!("xdg-dbus-proxy exited before creating its socket"));
+        }
+        Ok(Some(Self {
+            socket,
+            _proxy: Some(Proxy {
+                _child: child,
+                _ready: ready,
+                _dir: dir,
+            }),
+        }))
+    }
+
+    /// Returns the arguments that bind the bus socket into the environment.
+    pub fn bwrap_args(&self) -> [OsString; 3] {
+        [
+            OsString::from("--bind"),
+            OsString::from(&self.socket),
+            OsString::from(ENV_SOCKET),
+        ]
+    }
+}
+
+/// Returns the path of the host's session bus socket.
+fn host_socket() -> Result<PathBuf> {
+    match std::env::var("DBUS_SESSION_BUS_ADDRESS") {
+        Ok(address) => socket_from_address(&address).ok_or_else(|| {
+            anyhow!(
+                "found no Unix socket path in $DBUS_SESSION_BUS_ADDRESS ({address:?}) \
+                to share with the environment"
+            )
+        }),
+        Err(_) => {
+            let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").ok_or_else(|| {
+                anyhow!("can't find the D-Bus session bus: neither $DBUS_SESSION_BUS_ADDRESS nor $XDG_RUNTIME_DIR is set")
+            })?;
+            let socket = Path::new(&runtime_dir).join("bus");
+            if !socket.exists() {
+                return Err(anyhow!(
+                    "can't find the D-Bus session bus: $DBUS_SESSION_BUS_ADDRESS is not set \
+                    and {socket:?} does not exist"
+                ));
+            }
+            Ok(socket)
+        }
+    }
+}
+
+/// Returns the socket path from the first `unix:path=...` entry of a D-Bus
+/// address list, like `unix:path=/run/user/1000/bus,guid=...`.
+///
+/// Abstract sockets (`unix:abstract=...`) have no path to bind, so they're
+/// skipped.
+fn socket_from_address(address: &str) -> Option<PathBuf> {
+    address.split(';').find_map(|entry| {
+        entry
+            .strip_prefix("unix:")?
+            .split(',')
+            .find_map(|pair| pair.strip_prefix("path="))
+            .map(|path| PathBuf::from(unescape(path)))
+    })
+}
+
+/// Undoes the percent-encoding used in D-Bus address values.
+fn unescape(value: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next(), iter.next()];
+            if let [Some(high), Some(low)] = hex {
+                if let Ok(decoded) = u8::from_str_radix(&String::from_utf8_lossy(&[high, low]), 16)
+                {
+                    bytes.push(decoded);
+                    continue;
+                }
+            }
+            bytes.push(byte);
+            bytes.extend(hex.into_iter().flatten());
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_from_address() {
+        let parse = |address| super::socket_from_address(address);
+        assert_eq!(
+            Some(PathBuf::from("/run/user/1000/bus")),
+            parse("unix:path=/run/user/1000/bus")
+        );
+        assert_eq!(
+            Some(PathBuf::from("/tmp/my bus")),
+            parse("unix:abstract=/tmp/dbus-x,guid=1234;unix:guid=5678,path=/tmp/my%20bus")
+        );
+        assert_eq!(None, parse("unix:abstract=/tmp/dbus-x,guid=1234"));
+        assert_eq!(None, parse("tcp:host=localhost,port=1234"));
+    }
+}
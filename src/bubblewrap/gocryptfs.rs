@@ -0,0 +1,144 @@
+//! Encrypts Bubblewrap environments' files at rest with
+//! [gocryptfs](https://nuetzlich.net/gocryptfs/).
+//!
+//! An encrypted environment keeps its home and work directories in a single
+//! gocryptfs cipher directory. Cubicle mounts the decrypted view of it on
+//! the host with FUSE when it first needs the files, and the mount stays
+//! until the environment is stopped or purged. This way, every session of
+//! the environment shares one gocryptfs process: gocryptfs doesn't support
+//! two processes serving the same cipher directory at once.
+
+use std::os::unix::fs::MetadataExt;
+
+use crate::command_ext::Command;
+use crate::somehow::{somehow as anyhow, Context, ErrorKind, Result};
+use crate::HostPath;
+
+/// The name of gocryptfs's configuration file within a cipher directory,
+/// which holds the encrypted master key.
+pub const CONF_FILE: &str = "gocryptfs.conf";
+
+/// Creates a new gocryptfs file system in the empty directory `cipher`.
+///
+/// gocryptfs asks for the new password on the terminal, unless
+/// `password_command` is given.
+pub fn init(cipher: &HostPath, password_command: Option<&[String]>) -> Result<()> {
+    let status = Command::new("gocryptfs")
+        .args(["-init", "-q"])
+        .args(extpass_args(password_command))
+        .arg("--")
+        .arg(cipher.as_host_raw())
+        .status()
+        .context("failed to run `gocryptfs -init`")?;
+    if !status.success() {
+        return Err(anyhow!("`gocryptfs -init` exited with {status}"));
+    }
+    Ok(())
+}
+
+/// Mounts the decrypted view of `cipher` at `plain`, unless it's already
+/// mounted there. The caller should hold a lock so that concurrent `cub`
+/// processes don't both mount it.
+///
+/// gocryptfs asks for the password on the terminal, unless
+/// `password_command` is given.
+pub fn mount(
+    cipher: &HostPath,
+    plain: &HostPath,
+    password_command: Option<&[String]>,
+) -> Result<()> {
+    if is_mounted(plain)? {
+        return Ok(());
+    }
+    std::fs::create_dir_all(plain.as_host_raw())
+        .with_context(|| format!("failed to create directory {plain}"))?;
+    let status = Command::new("gocryptfs")
+        .arg("-q")
+        .args(extpass_args(password_command))
+        .arg("--")
+        .arg(cipher.as_host_raw())
+        .arg(plain.as_host_raw())
+        .status()
+        .context("failed to run `gocryptfs`")?;
+    if !status.success() {
+        return Err(anyhow!(
+            "`gocryptfs` exited with {status} while mounting {cipher}"
+        ));
+    }
+    Ok(())
+}
+
+/// Unmounts the decrypted view at `plain`.
+pub fn unmount(plain: &HostPath) -> Result<()> {
+    let mut status = Command::new("fusermount")
+        .arg("-u")
+        .arg(plain.as_host_raw())
+        .status();
+    if matches!(&status, Err(e) if e.kind() == Some(ErrorKind::ProgramNotFound)) {
+        status = Command::new("fusermount3")
+            .arg("-u")
+            .arg(plain.as_host_raw())
+            .status();
+    }
+    let status = status.with_context(|| format!("failed to unmount {plain}"))?;
+    if !status.success() {
+        return Err(anyhow!("`fusermount -u {plain}` exited with {status}"));
+    }
+    Ok(())
+}
+
+/// Returns true if something is mounted at `dir`, meaning it's on a
+/// different device than its parent.
+pub fn is_mounted(dir: &HostPath) -> Result<bool> {
+    let path = dir.as_host_raw();
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("failed to stat {dir}")),
+    };
+    let Some(parent) = path.parent() else {
+        return Ok(false);
+    };
+    let parent_metadata =
+        std::fs::metadata(parent).with_context(|| format!("failed to stat {parent:?}"))?;
+    Ok(metadata.dev() != parent_metadata.dev())
+}
+
+/// Returns gocryptfs's arguments to run `password_command` for the
+/// password. gocryptfs takes each word of the command as a separate
+/// `-extpass` argument.
+fn extpass_args(password_command: Option<&[String]>) -> Vec<&str> {
+    password_command
+        .unwrap_or_default()
+        .iter()
+        .flat_map(|arg| ["-extpass", arg.as_str()])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extpass_args() {
+        assert!(super::extpass_args(None).is_empty());
+        let command = [
+            String::from("pass"),
+            String::from("show"),
+            String::from("x"),
+        ];
+        assert_eq!(
+            vec!["-extpass", "pass", "-extpass", "show", "-extpass", "x"],
+            super::extpass_args(Some(&command))
+        );
+    }
+
+    #[test]
+    fn is_mounted() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dir = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        assert!(!super::is_mounted(&dir.join("missing")).unwrap());
+        std::fs::create_dir(dir.join("plain").as_host_raw()).unwrap();
+        assert!(!super::is_mounted(&dir.join("plain")).unwrap());
+    }
+}
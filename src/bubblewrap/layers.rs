@@ -0,0 +1,195 @@
+//! Shares seeded home directory contents between Bubblewrap environments,
+//! for the `overlay_home` setting.
+//!
+//! Each seed tarball is extracted once into a layer directory, named by a
+//! hash of the tarball's contents. An environment's home directory is then
+//! an overlay of its layers, with the environment's own home directory as
+//! the upper layer holding its changes. Resetting the environment only
+//! needs to clear the upper layer. Each layered environment has a file
+//! listing its layers, and layers that no environment lists are removed.
+
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io;
+
+use crate::fs_util::{rmtree, try_exists, try_iterdir};
+use crate::somehow::{Context, Result};
+use crate::{EnvironmentName, HostPath};
+
+pub struct Layers {
+    /// Holds the extracted layers.
+    dir: HostPath,
+    /// Holds a file per layered environment, listing its layers.
+    lists: HostPath,
+}
+
+impl Layers {
+    pub fn new(dir: HostPath, lists: HostPath) -> Self {
+        Self { dir, lists }
+    }
+
+    fn list_path(&self, name: &EnvironmentName) -> HostPath {
+        self.lists.join(name.as_filename())
+    }
+
+    /// Returns the environment's layers, from lowest to highest, or an empty
+    /// list if its home directory isn't layered.
+    pub fn get(&self, name: &EnvironmentName) -> Result<Vec<HostPath>> {
+        Ok(self
+            .read_list(&self.list_path(name))?
+            .into_iter()
+            .map(|key| self.dir.join(key))
+            .collect())
+    }
+
+    fn read_list(&self, path: &HostPath) -> Result<Vec<String>> {
+        match std::fs::read_to_string(path.as_host_raw()) {
+            Ok(list) => Ok(list.lines().map(String::from).collect()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e).with_context(|| format!("failed to read {path}")),
+        }
+    }
+
+    /// Extracts the seed tarballs into layers, unless they already exist, and
+    /// records them as the environment's layers, in the same order.
+    ///
+    /// The caller should hold the seed layers lock.
+    pub fn set(&self, name: &EnvironmentName, seeds: &[HostPath]) -> Result<()> {
+        let keys = seeds
+            .iter()
+            .map(hash_file)
+            .collect::<Result<Vec<String>>>()?;
+        for (seed, key) in seeds.iter().zip(&keys) {
+            self.extract(seed, key)?;
+        }
+        std::fs::create_dir_all(self.lists.as_host_raw())
+            .with_context(|| format!("failed to create directory {}", self.lists))?;
+        let path = self.list_path(name);
+        let list: String = keys.iter().map(|key| format!("{key}\n")).collect();
+        std::fs::write(path.as_host_raw(), list).with_context(|| format!("failed to write {path}"))
+    }
+
+    fn extract(&self, seed: &HostPath, key: &str) -> Result<()> {
+        let layer = self.dir.join(key);
+        if try_exists(&layer).todo_context()? {
+            return Ok(());
+        }
+        std::fs::create_dir_all(self.dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {}", self.dir))?;
+        // Extract into a temporary directory first, so that a partially
+        // extracted layer is never used.
+        let tmp = tempfile::Builder::new()
+            .prefix(".tmp-")
+            .tempdir_in(self.dir.as_host_raw())
+            .with_context(|| format!("failed to create temp directory in {}", self.dir))?;
+        let file =
+            File::open(seed.as_host_raw()).with_context(|| format!("failed to open {seed}"))?;
+        let mut archive = tar::Archive::new(file);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        archive
+            .unpack(tmp.path())
+            .with_context(|| format!("failed to extract {seed} into a seed layer"))?;
+        std::fs::rename(tmp.path(), layer.as_host_raw())
+            .with_context(|| format!("failed to rename {:?} to {layer}", tmp.path()))
+    }
+
+    /// Forgets the environment's layers, so that its home directory is no
+    /// longer layered.
+    ///
+    /// The caller should hold the seed layers lock.
+    pub fn forget(&self, name: &EnvironmentName) -> Result<()> {
+        let path = self.list_path(name);
+        match std::fs::remove_file(path.as_host_raw()) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("failed to remove {path}")),
+        }
+    }
+
+    /// Removes the layers that no environment uses, along with any
+    /// temporary directories left behind by interrupted extractions.
+    ///
+    /// The caller should hold the seed layers lock.
+    pub fn remove_unused(&self) -> Result<()> {
+        let mut used = BTreeSet::new();
+        for list in try_iterdir(&self.lists)? {
+            used.extend(self.read_list(&self.lists.join(list))?);
+        }
+        for layer in try_iterdir(&self.dir)? {
+            let unused = match layer.to_str() {
+                Some(key) => !used.contains(key),
+                None => true,
+            };
+            if unused {
+                rmtree(&self.dir.join(layer))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the hex-encoded SHA-256 hash of the file's contents.
+fn hash_file(path: &HostPath) -> Result<String> {
+    let mut file =
+        File::open(path.as_host_raw()).with_context(|| format!("failed to open {path}"))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("failed to read {path}"))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn write_tar(path: &HostPath, file_name: &str, contents: &str) {
+        let mut builder = tar::Builder::new(File::create(path.as_host_raw()).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, file_name, contents.as_bytes())
+            .unwrap();
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn layers() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dir = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        let layers = Layers::new(dir.join("layers"), dir.join("lists"));
+        let a = EnvironmentName::from_str("a").unwrap();
+        let b = EnvironmentName::from_str("b").unwrap();
+        let one = dir.join("one.tar");
+        let two = dir.join("two.tar");
+        write_tar(&one, "one.txt", "1");
+        write_tar(&two, "two.txt", "2");
+
+        assert!(layers.get(&a).unwrap().is_empty());
+        layers.set(&a, &[one.clone(), two]).unwrap();
+        layers.set(&b, &[one]).unwrap();
+        let a_layers = layers.get(&a).unwrap();
+        assert_eq!(2, a_layers.len());
+        let b_layers = layers.get(&b).unwrap();
+        assert_eq!(1, b_layers.len());
+        assert_eq!(a_layers[0].as_host_raw(), b_layers[0].as_host_raw());
+        assert_eq!(
+            "2",
+            std::fs::read_to_string(a_layers[1].join("two.txt").as_host_raw()).unwrap()
+        );
+
+        layers.forget(&a).unwrap();
+        layers.forget(&a).unwrap();
+        layers.remove_unused().unwrap();
+        assert!(layers.get(&a).unwrap().is_empty());
+        assert!(try_exists(&a_layers[0]).unwrap());
+        assert!(!try_exists(&a_layers[1]).unwrap());
+    }
+}
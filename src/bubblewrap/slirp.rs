@@ -0,0 +1,158 @@
+//! Gives Bubblewrap environments outbound-only networking with
+//! [slirp4netns](https://github.com/rootless-containers/slirp4netns).
+//!
+//! bwrap creates the environment's network namespace, reports the sandbox's
+//! process ID, and then waits before running anything inside it. Meanwhile,
+//! Cubicle starts slirp4netns to add a virtual network interface to the
+//! namespace, and it lets bwrap continue once the interface is configured.
+
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::OwnedFd;
+use std::process::Stdio;
+use tempfile::NamedTempFile;
+
+use super::{get_fd_for_child, pipe};
+use crate::command_ext::{Command, ScopedChild};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// The address of slirp4netns's built-in DNS forwarder, as seen from inside
+/// the network namespace.
+const DNS_SERVER: &str = "10.0.2.3";
+
+/// Pipes and files shared with a bwrap process, which are created before
+/// it starts.
+pub struct Setup {
+    /// bwrap writes JSON with the sandbox's process ID here.
+    info_read: File,
+    info_write: OwnedFd,
+    /// bwrap waits for a byte on this before running anything in the
+    /// sandbox.
+    block_read: OwnedFd,
+    block_write: File,
+    /// A `resolv.conf` that points to slirp4netns's DNS forwarder. This is
+    /// removed when dropped.
+    resolv_conf: NamedTempFile,
+}
+
+impl Setup {
+    pub fn new() -> Result<Self> {
+        let (info_read, info_write) = pipe().context("failed to create pipe for bwrap info")?;
+        let (block_read, block_write) = pipe().context("failed to create pipe to block bwrap")?;
+        let mut resolv_conf =
+            NamedTempFile::new().context("failed to create temp file for resolv.conf")?;
+        writeln!(resolv_conf, "nameserver {DNS_SERVER}").with_context(|| {
+            format!(
+                "failed to write temp file for resolv.conf: {:?}",
+                resolv_conf.path()
+            )
+        })?;
+        Ok(Self {
+            info_read: File::from(info_read),
+            info_write,
+            block_read,
+            block_write: File::from(block_write),
+            resolv_conf,
+        })
+    }
+
+    /// Returns the arguments that bwrap needs to coordinate with
+    /// slirp4netns and use its DNS forwarder.
+    ///
+    /// These must come after `/etc` is bound into the sandbox.
+    pub fn bwrap_args(&self) -> Result<Vec<String>> {
+        // The host's `/etc/resolv.conf` is often a symlink, such as into
+        // `/run/systemd/resolve`, so this binds over its target instead.
+        let resolv_conf = std::fs::canonicalize("/etc/resolv.conf")
+            .ok()
+            .and_then(|path| path.to_str().map(String::from))
+            .unwrap_or_else(|| String::from("/etc/resolv.conf"));
+        Ok(vec![
+            String::from("--info-fd"),
+            get_fd_for_child(&self.info_write)
+                .context("failed to set up info file descriptor to be inherited by bwrap")?,
+            String::from("--block-fd"),
+            get_fd_for_child(&self.block_read)
+                .context("failed to set up block file descriptor to be inherited by bwrap")?,
+            String::from("--ro-bind"),
+            self.resolv_conf
+                .path()
+                .to_str()
+                .ok_or_else(|| anyhow!("temp file path is not valid UTF-8"))?
+                .to_owned(),
+            resolv_conf,
+        ])
+    }
+
+    /// Starts slirp4netns for a bwrap process that was just spawned with
+    /// [`Setup::bwrap_args`], then lets the sandbox continue.
+    ///
+    /// The returned value should be kept until bwrap exits.
+    pub fn start(self) -> Result<Running> {
+        let Self {
+            mut info_read,
+            info_write,
+            block_read,
+            mut block_write,
+            resolv_conf,
+        } = self;
+        // Close this process's copies of bwrap's ends, so that reading from
+        // the pipes fails instead of blocking if bwrap exits early.
+        drop(info_write);
+        drop(block_read);
+
+        #[derive(Deserialize)]
+        struct Info {
+            #[serde(rename = "child-pid")]
+            child_pid: u32,
+        }
+        let info = serde_json::Deserializer::from_reader(&mut info_read)
+            .into_iter::<Info>()
+            .next()
+            .ok_or_else(|| anyhow!("bwrap exited before reporting the sandbox's process ID"))?
+            .context("failed to parse info from bwrap")?;
+
+        let (ready_read, ready_write) =
+            pipe().context("failed to create pipe for slirp4netns readiness")?;
+        let slirp = Command::new("slirp4netns")
+            .arg("--configure")
+            .arg("--mtu=65520")
+            .arg("--disable-host-loopback")
+            .arg(format!(
+                "--ready-fd={}",
+                get_fd_for_child(&ready_write).context(
+                    "failed to set up ready file descriptor to be inherited by slirp4netns"
+                )?
+            ))
+            .arg(info.child_pid.to_string())
+            .arg("tap0")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .scoped_spawn()?;
+        drop(ready_write);
+
+        let mut ready = [0; 1];
+        let n = File::from(ready_read)
+            .read(&mut ready)
+            .context("failed to wait for slirp4netns")?;
+        if n == 0 {
+            return Err(anyhow!("slirp4netns exited before configuring the network"));
+        }
+
+        block_write
+            .write_all(b"1")
+            .context("failed to unblock bwrap")?;
+        Ok(Running {
+            _slirp: slirp,
+            _resolv_conf: resolv_conf,
+        })
+    }
+}
+
+/// Stops slirp4netns and removes the temporary `resolv.conf` when dropped.
+#[must_use]
+pub struct Running {
+    _slirp: ScopedChild,
+    _resolv_conf: NamedTempFile,
+}
@@ -0,0 +1,501 @@
+//! Configures and creates a [`Cubicle`] instance.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::activity::ActivityTracker;
+use super::auto_runner::{self, RunnerChoice, RunnerSource};
+#[cfg(target_os = "linux")]
+use super::bubblewrap::Bubblewrap;
+use super::config::Config;
+use super::docker::Docker;
+#[cfg(feature = "embed-packages")]
+use super::embedded;
+use super::fs_util::try_exists;
+use super::golden::GoldenRegistry;
+use super::history::HistoryLog;
+use super::lock::Locks;
+use super::os_util::host_home_dir;
+use super::packages;
+use super::paths::{xdg_cache_home, xdg_data_home, Paths};
+use super::randname::RandomNameGenerator;
+use super::read_only::ReadOnlyRegistry;
+use super::runner::{fault, CheckedRunner, Runner};
+use super::shells::ShellRegistry;
+use super::summary_cache::SummaryCache;
+use super::timings::Timings;
+use super::user::User;
+use super::worktree::WorktreeRegistry;
+use super::{Cubicle, CubicleShared, Event, EventSink, HostPath, RunnerKind};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// Creates a [`Cubicle`] instance with explicit settings.
+///
+/// By default, [`CubicleBuilder::build`] finds its directories, shell, and
+/// built-in packages the same way as the command-line program: using
+/// `$HOME`, `$SHELL`, `$XDG_CACHE_HOME`, `$XDG_DATA_HOME`, and the path of
+/// the current executable. Each of these can be set explicitly instead, which
+/// is useful for embedding Cubicle in other programs and for hermetic tests.
+///
+/// # Example
+///
+/// ```no_run
+/// # use cubicle::{config::Config, CubicleBuilder, Event};
+/// # fn main() -> cubicle::Result<()> {
+/// let config = Config::read_from_file("/etc/cubicle.toml".as_ref())?;
+/// let cubicle = CubicleBuilder::new(config)
+///     .home_dir("/srv/cubicle".into())
+///     .cache_dir("/srv/cubicle/cache".into())
+///     .data_dir("/srv/cubicle/data".into())
+///     .events(|event: &Event| eprintln!("{event}"))
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct CubicleBuilder {
+    config: Config,
+    events: Arc<dyn EventSink>,
+    home_dir: Option<PathBuf>,
+    shell: Option<String>,
+    cache_dir: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    exe_name: Option<String>,
+    builtin_package_dir: Option<PathBuf>,
+    user_package_dir: Option<PathBuf>,
+    package_cache_dir: Option<PathBuf>,
+    runner: Option<Box<dyn Runner>>,
+    dry_run: bool,
+}
+
+impl CubicleBuilder {
+    /// Starts building a [`Cubicle`] instance with the given configuration.
+    ///
+    /// Events are ignored unless [`CubicleBuilder::events`] is called.
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            events: Arc::new(|_: &Event| {}),
+            home_dir: None,
+            shell: None,
+            cache_dir: None,
+            data_dir: None,
+            exe_name: None,
+            builtin_package_dir: None,
+            user_package_dir: None,
+            package_cache_dir: None,
+            runner: None,
+            dry_run: false,
+        }
+    }
+
+    /// Reports progress updates from long-running operations to `events`.
+    pub fn events(mut self, events: impl EventSink + 'static) -> Self {
+        self.events = Arc::new(events);
+        self
+    }
+
+    /// Sets the user's home directory on the host.
+    ///
+    /// Default: `$HOME`.
+    pub fn home_dir(mut self, path: PathBuf) -> Self {
+        self.home_dir = Some(path);
+        self
+    }
+
+    /// Sets the shell to run in environments.
+    ///
+    /// Default: `$SHELL`, or `/bin/sh` if unset.
+    pub fn shell(mut self, shell: String) -> Self {
+        self.shell = Some(shell);
+        self
+    }
+
+    /// Sets the base directory for cached files. Cubicle keeps its files in a
+    /// `cubicle` subdirectory.
+    ///
+    /// Default: `$XDG_CACHE_HOME`, or `.cache` in the home directory if
+    /// unset.
+    pub fn cache_dir(mut self, path: PathBuf) -> Self {
+        self.cache_dir = Some(path);
+        self
+    }
+
+    /// Sets the base directory for data files. Cubicle keeps its files in a
+    /// `cubicle` subdirectory.
+    ///
+    /// Default: `$XDG_DATA_HOME`, or `.local/share` in the home directory if
+    /// unset.
+    pub fn data_dir(mut self, path: PathBuf) -> Self {
+        self.data_dir = Some(path);
+        self
+    }
+
+    /// Sets the name of the command-line program, as used in hints within
+    /// error messages.
+    ///
+    /// Default: the file name of the current executable.
+    pub fn exe_name(mut self, name: String) -> Self {
+        self.exe_name = Some(name);
+        self
+    }
+
+    /// Sets where to find the built-in package definitions. This takes
+    /// precedence over the `builtin_package_dir` configuration setting.
+    ///
+    /// Default: see [`Config::builtin_package_dir`].
+    pub fn builtin_package_dir(mut self, path: PathBuf) -> Self {
+        self.builtin_package_dir = Some(path);
+        self
+    }
+
+    /// Sets where to find the user's own package definitions.
+    ///
+    /// Default: `cubicle/packages` in the data directory.
+    pub fn user_package_dir(mut self, path: PathBuf) -> Self {
+        self.user_package_dir = Some(path);
+        self
+    }
+
+    /// Sets where to store built packages. This takes precedence over the
+    /// `dirs.package_cache` configuration setting.
+    ///
+    /// Default: see [`Dirs::package_cache`](crate::config::Dirs::package_cache).
+    pub fn package_cache_dir(mut self, path: PathBuf) -> Self {
+        self.package_cache_dir = Some(path);
+        self
+    }
+
+    /// Uses the given runner instead of one of the built-in runners.
+    ///
+    /// This allows other crates to provide their own isolation mechanisms.
+    /// The `runner` field of the configuration is ignored, except that hooks
+    /// and plugins still see it as `CUBICLE_RUNNER`.
+    pub fn runner(mut self, runner: Box<dyn Runner>) -> Self {
+        self.runner = Some(runner);
+        self
+    }
+
+    /// Reports the actions that operations would take as
+    /// [`Event::Planned`](crate::Event::Planned) instead of taking them.
+    ///
+    /// Only some operations support dry runs: creating, resetting, and
+    /// purging environments, and updating packages. The others return an
+    /// error.
+    ///
+    /// Default: false.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Creates the [`Cubicle`] instance.
+    ///
+    /// # Errors
+    ///
+    /// - An invalid configuration (see [`Config::validate`]).
+    /// - Reading and parsing environment variables.
+    /// - Relative paths given to this builder.
+    /// - Loading and initializing filesystem structures.
+    /// - Creating a runner.
+    pub fn build(mut self) -> Result<Cubicle> {
+        self.config.validate()?;
+        let home = match self.home_dir {
+            Some(path) => HostPath::try_from(path)?,
+            None => host_home_dir().clone(),
+        };
+        let shell = match self.shell {
+            Some(shell) => shell,
+            None => std::env::var("SHELL").unwrap_or_else(|_| String::from("/bin/sh")),
+        };
+
+        let cache_home = match self.cache_dir {
+            Some(path) => HostPath::try_from(path)?,
+            None => xdg_cache_home(&home)?,
+        };
+        let data_home = match self.data_dir {
+            Some(path) => HostPath::try_from(path)?,
+            None => xdg_data_home(&home)?,
+        };
+        let mut paths = Paths::new(&cache_home, &data_home, &self.config.dirs)
+            .context("invalid `dirs` setting")?;
+        if let Some(path) = self.package_cache_dir {
+            paths.package_cache = HostPath::try_from(path)?;
+        }
+
+        let runner_choice = if self.config.runner == RunnerKind::Auto && self.runner.is_none() {
+            let choice = auto_runner::resolve(&self.config, &paths.data, self.dry_run)?;
+            if let RunnerSource::Probed(_) = &choice.source {
+                self.events.event(&Event::ChoseRunner(choice.runner));
+            }
+            self.config.runner = choice.runner;
+            choice
+        } else {
+            RunnerChoice {
+                runner: self.config.runner,
+                source: RunnerSource::Config,
+            }
+        };
+
+        let exe =
+            || std::env::current_exe().context("error getting the path of the current executable");
+        let exe_name = match self.exe_name {
+            Some(name) => name,
+            None => {
+                let exe = exe()?;
+                match exe.file_name() {
+                    Some(path) => path.to_string_lossy().into_owned(),
+                    None => {
+                        return Err(anyhow!(
+                            "could not get executable name from current_exe: {:?}",
+                            exe
+                        ));
+                    }
+                }
+            }
+        };
+
+        fn code_package_dir_ok(dir: &HostPath) -> bool {
+            let key_packages = [
+                packages::special::AUTO_BATCH,
+                packages::special::AUTO_INTERACTIVE,
+                packages::special::CONFIGS_CORE,
+                packages::special::DEFAULT,
+            ];
+            key_packages
+                .iter()
+                .all(|p| try_exists(&dir.join(p).join("package.toml")).unwrap_or(false))
+        }
+
+        let code_package_dir = match self
+            .builtin_package_dir
+            .as_ref()
+            .or(self.config.builtin_package_dir.as_ref())
+        {
+            Some(dir) => {
+                let dir = HostPath::try_from(dir.clone())?;
+                if !code_package_dir_ok(&dir) {
+                    return Err(anyhow!(
+                        "missing expected contents in configured `builtin_package_dir`: {dir}"
+                    ));
+                }
+                dir
+            }
+
+            #[cfg(feature = "embed-packages")]
+            None => embedded::builtin_package_dir(&paths.cache)?,
+
+            #[cfg(not(feature = "embed-packages"))]
+            None => {
+                let exe = exe()?;
+                let exe = std::fs::canonicalize(&exe).with_context(|| {
+                    format!("failed to canonicalize path of current executable: {exe:?}")
+                })?;
+
+                let mut candidates = Vec::new();
+                let mut ancestors = exe.ancestors();
+                ancestors.next(); // skip self
+                if let Some(dir) = ancestors.next() {
+                    candidates.push(HostPath::try_from(dir.to_owned())?.join("packages"));
+                }
+                if let (Some(parent), Some(dir)) = (ancestors.next(), ancestors.next()) {
+                    if parent.ends_with("target") {
+                        candidates.push(HostPath::try_from(dir.to_owned())?.join("packages"));
+                    }
+                }
+
+                match candidates.iter().find(|dir| code_package_dir_ok(dir)) {
+                    Some(dir) => dir.clone(),
+                    None => {
+                        return Err(anyhow!(
+                            "Could not find built-in package definitions \
+                            (looked in {:?} based on executable location). \
+                            Hint: set `builtin_package_dir` in `cubicle.toml`.",
+                            candidates
+                                .iter()
+                                .map(|p| p.as_host_raw())
+                                .collect::<Vec<_>>()
+                        ))
+                    }
+                }
+            }
+        };
+
+        let user_package_dir = match self.user_package_dir {
+            Some(path) => HostPath::try_from(path)?,
+            None => paths.data.join("packages"),
+        };
+
+        let random_name_gen =
+            RandomNameGenerator::new(paths.word_list.clone(), self.config.tmp.word_list.clone());
+
+        let activity = ActivityTracker::new(paths.cache.join("activity"));
+        let summaries = SummaryCache::new(paths.cache.join("summaries"));
+        let golden = GoldenRegistry::new(paths.data.join("golden"));
+        let worktrees = WorktreeRegistry::new(paths.data.join("worktrees"));
+        let read_only = ReadOnlyRegistry::new(paths.data.join("read-only"));
+        let shells = ShellRegistry::new(paths.data.join("shells"));
+        let history = HistoryLog::new(paths.data.join("history"));
+        let locks = Locks::new(
+            paths.cache.join("locks"),
+            self.config.lock_timeout,
+            self.events.clone(),
+        );
+
+        let shared = Arc::new(CubicleShared {
+            config: self.config,
+            runner_choice,
+            shell,
+            exe_name,
+            home,
+            paths,
+            code_package_dir,
+            user_package_dir,
+            random_name_gen,
+            activity,
+            summaries,
+            golden,
+            worktrees,
+            read_only,
+            shells,
+            history,
+            locks,
+            events: self.events,
+            dry_run: self.dry_run,
+            timings: Timings::default(),
+            env_init_script: std::include_bytes!("env-init.sh"),
+        });
+
+        let runner: Box<dyn Runner> = match self.runner {
+            Some(runner) => runner,
+            None => match shared.config.runner {
+                RunnerKind::Bubblewrap => {
+                    #[cfg(not(target_os = "linux"))]
+                    return Err(anyhow!("The Bubblewrap runner is only available on Linux"));
+                    #[cfg(target_os = "linux")]
+                    {
+                        // `cub migrate` may pick this runner after the
+                        // configuration file was checked.
+                        if shared.config.bubblewrap.is_none() {
+                            return Err(anyhow!(
+                                "Bubblewrap settings are required for that runner. \
+                                See `docs/Bubblewrap.md`."
+                            ));
+                        }
+                        Box::new(Bubblewrap::new(shared.clone())?)
+                    }
+                }
+                RunnerKind::Docker => Box::new(Docker::new(shared.clone())?),
+                RunnerKind::User => Box::new(User::new(shared.clone())?),
+                RunnerKind::Auto => unreachable!("`runner = \"auto\"` is resolved above"),
+            },
+        };
+
+        Ok(Cubicle {
+            shared,
+            runner: Arc::new(CheckedRunner::new(fault::wrap_from_env(runner)?)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockRunner;
+    use crate::{EnvironmentName, FullPackageName, KeepPartial, Quiet};
+    use expect_test::expect;
+    use std::collections::BTreeSet;
+    use std::path::Path;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    #[test]
+    fn build_hermetic() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir_path = tmpdir.path().canonicalize().unwrap();
+        let config = Config::from_str("runner = 'user'")
+            .enough_context()
+            .unwrap();
+        let cubicle = CubicleBuilder::new(config)
+            .home_dir(tmpdir_path.join("home"))
+            .shell(String::from("/bin/sh"))
+            .cache_dir(tmpdir_path.join("cache"))
+            .data_dir(tmpdir_path.join("data"))
+            .exe_name(String::from("cub"))
+            .builtin_package_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("packages"))
+            .runner(Box::new(MockRunner::new()))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            tmpdir_path.join("cache/cubicle/packages"),
+            cubicle.shared.paths.package_cache.as_host_raw()
+        );
+        assert_eq!(
+            tmpdir_path.join("data/cubicle/packages"),
+            cubicle.shared.user_package_dir.as_host_raw()
+        );
+        std::thread::scope(|scope| {
+            scope.spawn(|| assert!(cubicle.get_environment_names().unwrap().is_empty()));
+        });
+        assert!(cubicle
+            .get_package_names()
+            .unwrap()
+            .contains(&FullPackageName::from_str("default").unwrap()));
+
+        let error = CubicleBuilder::new(
+            Config::from_str("runner = 'user'")
+                .enough_context()
+                .unwrap(),
+        )
+        .home_dir(PathBuf::from("relative"))
+        .build()
+        .err()
+        .unwrap()
+        .to_string();
+        assert_eq!("HostPath must be an absolute path, got \"relative\"", error);
+    }
+
+    #[test]
+    fn dry_run() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir_path = tmpdir.path().canonicalize().unwrap();
+        let config = Config::from_str("runner = 'user'")
+            .enough_context()
+            .unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let cubicle = CubicleBuilder::new(config)
+            .events({
+                let events = events.clone();
+                move |event: &Event| events.lock().unwrap().push(event.to_string())
+            })
+            .home_dir(tmpdir_path.join("home"))
+            .cache_dir(tmpdir_path.join("cache"))
+            .data_dir(tmpdir_path.join("data"))
+            .builtin_package_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("packages"))
+            .runner(Box::new(MockRunner::new()))
+            .dry_run(true)
+            .build()
+            .unwrap();
+
+        let name = EnvironmentName::from_str("foo").unwrap();
+        cubicle
+            .new_environment(
+                &name,
+                Some(BTreeSet::from([
+                    FullPackageName::from_str("configs-core").unwrap()
+                ])),
+                KeepPartial(false),
+            )
+            .unwrap();
+        cubicle.purge_environment(&name, Quiet(true)).unwrap();
+        expect![[r#"
+            Would build "configs-core" package (never built)
+            Would build "configs-interactive" package (never built)
+            Would build "apt-binary" package (never built)
+            Would create environment "foo", seeded from packages apt-binary, configs-core, configs-interactive
+            Would delete environment "foo""#]].assert_eq(&events.lock().unwrap().join("\n"));
+
+        let error = cubicle.enter_environment(&name).unwrap_err().to_string();
+        assert_eq!("cannot enter an environment in a dry run", error);
+    }
+}
@@ -0,0 +1,201 @@
+//! Caches shared by all environments, for the `caches` configuration option.
+//!
+//! Each cache is a directory on the host, which the runners make available at
+//! `ENV_DIR/<name>` inside every environment. The initialization script
+//! symlinks the configured path in the home directory to it, so the home
+//! directory itself needs no extra mounts.
+//!
+//! The `compiler_cache` option adds one more cache, named after the tool.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use super::config::Config;
+use super::fs_util::{rmtree, summarize_dir, try_exists, try_iterdir, try_iterdir_dirs};
+use super::paths::Paths;
+use super::runner::Runner;
+use super::{Cubicle, HostPath};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+/// Where the caches appear inside environments.
+pub const ENV_DIR: &str = "/run/cubicle-caches";
+
+/// Returns the host directory holding the caches.
+fn host_dir(paths: &Paths) -> HostPath {
+    paths.cache.join("caches")
+}
+
+/// Returns the configured caches, keyed by name, with their paths relative
+/// to the home directory. This includes any compiler cache.
+fn configured(config: &Config) -> BTreeMap<String, PathBuf> {
+    let mut caches = config.caches.clone();
+    if let Some(compiler_cache) = &config.compiler_cache {
+        let name = compiler_cache.tool.as_str();
+        caches
+            .entry(name.to_owned())
+            .or_insert_with(|| PathBuf::from(".cache").join(name));
+    }
+    caches
+}
+
+/// Returns each configured cache's host directory, creating it if needed,
+/// and where it should be mounted inside environments.
+pub fn mounts(program: &super::CubicleShared) -> Result<Vec<(HostPath, String)>> {
+    let dir = host_dir(&program.paths);
+    configured(&program.config)
+        .into_keys()
+        .map(|name| {
+            let path = dir.join(&name);
+            std::fs::create_dir_all(path.as_host_raw())
+                .with_context(|| format!("failed to create cache directory {path}"))?;
+            Ok((path, format!("{ENV_DIR}/{name}")))
+        })
+        .collect()
+}
+
+/// Description of a shared cache, as returned by [`Cubicle::list_caches`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct CacheDetails {
+    /// The cache's name.
+    pub name: String,
+    /// Where the cache appears inside environments' home directories, or
+    /// `None` if it's no longer configured.
+    pub env_path: Option<PathBuf>,
+    /// The cache's directory on the host, if it exists.
+    pub host_path: Option<PathBuf>,
+    /// The total size in bytes of the cache's files.
+    pub size: u64,
+}
+
+impl Cubicle {
+    /// Corresponds to `cub cache list`.
+    ///
+    /// Returns the configured caches, along with any left over from
+    /// previous configurations.
+    pub fn list_caches(&self) -> Result<Vec<CacheDetails>> {
+        let dir = host_dir(&self.shared.paths);
+        let caches = configured(&self.shared.config);
+        let mut names: Vec<String> = caches.keys().cloned().collect();
+        for name in try_iterdir_dirs(&dir)? {
+            if let Some(name) = name.to_str() {
+                if !caches.contains_key(name) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort();
+
+        names
+            .into_iter()
+            .map(|name| {
+                let path = dir.join(&name);
+                let exists = try_exists(&path).todo_context()?;
+                let size = if exists {
+                    summarize_dir(&path)?.total_size
+                } else {
+                    0
+                };
+                Ok(CacheDetails {
+                    env_path: caches.get(&name).cloned(),
+                    host_path: exists.then(|| path.as_host_raw().to_owned()),
+                    size,
+                    name,
+                })
+            })
+            .collect()
+    }
+
+    /// Corresponds to `cub cache clear`.
+    ///
+    /// Removes the contents of the named caches. Caches that are no longer
+    /// configured are removed entirely.
+    pub fn clear_caches(&self, names: &[String]) -> Result<()> {
+        self.shared.check_not_dry_run("clear caches")?;
+        let dir = host_dir(&self.shared.paths);
+        let caches = configured(&self.shared.config);
+        for name in names {
+            let path = dir.join(name);
+            if name.contains('/') || !try_exists(&path).todo_context()? {
+                warn(anyhow!("cache {name:?} does not exist (nothing to clear)"));
+                continue;
+            }
+            if !caches.contains_key(name) {
+                rmtree(&path)?;
+                continue;
+            }
+            // Running environments have the directory itself mounted, so
+            // only its contents are removed.
+            for entry in try_iterdir(&path)? {
+                let entry = path.join(entry);
+                if entry.as_host_raw().is_dir() && !entry.as_host_raw().is_symlink() {
+                    rmtree(&entry)?;
+                } else {
+                    std::fs::remove_file(entry.as_host_raw())
+                        .with_context(|| format!("failed to remove {entry}"))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the environment variables that tell the initialization
+    /// script which caches to link into the home directory and which
+    /// compiler cache to set up, if any.
+    pub(super) fn caches_env_vars(&self) -> Vec<(String, String)> {
+        let caches = configured(&self.shared.config);
+        if caches.is_empty() {
+            return Vec::new();
+        }
+        if !self.runner.supports_caches() {
+            warn(anyhow!(
+                "the {} runner doesn't support shared caches (ignoring `caches`)",
+                self.shared.config.runner.as_str()
+            ));
+            return Vec::new();
+        }
+        let lines: Vec<String> = caches
+            .iter()
+            .map(|(name, path)| format!("{name} {ENV_DIR}/{name} {}", path.display()))
+            .collect();
+        let mut env_vars = vec![(String::from("CUBICLE_CACHES"), lines.join("\n"))];
+        if let Some(compiler_cache) = &self.shared.config.compiler_cache {
+            env_vars.push((
+                String::from("CUBICLE_COMPILER_CACHE"),
+                compiler_cache.tool.as_str().to_owned(),
+            ));
+            if let Some(max_size) = &compiler_cache.max_size {
+                env_vars.push((
+                    String::from("CUBICLE_COMPILER_CACHE_MAX_SIZE"),
+                    max_size.clone(),
+                ));
+            }
+        }
+        env_vars
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configured() {
+        let config = Config::from_str(
+            "runner = 'user'\n\
+            [caches]\n\
+            pip = '.cache/pip'\n\
+            [compiler_cache]\n\
+            tool = 'sccache'\n",
+        )
+        .enough_context()
+        .unwrap();
+        assert_eq!(
+            BTreeMap::from([
+                (String::from("pip"), PathBuf::from(".cache/pip")),
+                (String::from("sccache"), PathBuf::from(".cache/sccache")),
+            ]),
+            super::configured(&config)
+        );
+    }
+}
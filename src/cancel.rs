@@ -0,0 +1,58 @@
+//! Handles Ctrl-C (SIGINT) and SIGTERM by cancelling the current operation.
+//!
+//! When the terminal sends SIGINT, it goes to the whole foreground process
+//! group, so child processes like `docker exec`, `pv`, and `tar` exit on
+//! their own. Rather than dying immediately, `cub` records the cancellation
+//! and returns an error from the next [`check`]. That unwinds normally, so
+//! destructors kill any remaining child processes and remove temporary
+//! files, and callers can clean up (for example, by marking a package build
+//! as failed).
+//!
+//! A second signal exits immediately, in case cleanup gets stuck.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::somehow::{somehow as anyhow, Error, ErrorKind, Result};
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_signal: libc::c_int) {
+    if CANCELLED.swap(true, Ordering::SeqCst) {
+        // SAFETY: `_exit` is async-signal-safe.
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Installs handlers for SIGINT and SIGTERM that request cancellation.
+///
+/// This should only be called by the command-line program, since it changes
+/// how the whole process handles these signals.
+pub fn install_handler() -> Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: The handler only touches an atomic and calls `_exit`, both
+        // of which are async-signal-safe.
+        let previous =
+            unsafe { libc::signal(signal, handle_signal as *const () as libc::sighandler_t) };
+        if previous == libc::SIG_ERR {
+            return Err(anyhow!(
+                "failed to install signal handler: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if the user has asked to cancel the current operation.
+pub(crate) fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Returns an error if the user has asked to cancel the current operation.
+pub(crate) fn check() -> Result<()> {
+    if is_cancelled() {
+        Err(Error::with_kind(ErrorKind::Interrupted, "interrupted"))
+    } else {
+        Ok(())
+    }
+}
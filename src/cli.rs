@@ -4,22 +4,39 @@
 //! the usage for the command-line program and should be read from that
 //! perspective.
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use clap_complete::{generate, shells::Shell};
 use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
 use std::fmt::{self, Debug, Display};
-use std::io;
+use std::io::{self, IsTerminal, Write};
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
 use std::str::FromStr;
+use std::time::Duration;
 use wildmatch::WildMatch;
 
+use cubicle::config::{Config, TimeFormat};
+use cubicle::daemon;
 use cubicle::hidden::host_home_dir;
-use cubicle::somehow::{somehow as anyhow, warn, Context, Error, Result};
+use cubicle::somehow::{somehow as anyhow, warn, Context, Error, ErrorKind, Result};
 use cubicle::{
-    Cubicle, EnvironmentName, FullPackageName, ListFormat, ListPackagesFormat, Quiet,
-    ShouldPackageUpdate, UpdatePackagesConditions,
+    install_seccomp_policy, Cubicle, CubicleBuilder, EnvDir, EnvironmentName, FullPackageName,
+    KeepPartial, Quiet, Refresh, RunnerKind, ShouldPackageUpdate, SyncDirection,
+    UpdatePackagesConditions, Watch,
 };
 
+mod autoupdate;
+mod direnv;
+
+mod output;
+mod style;
+mod systemd;
+pub use output::{eprint_event, print_event, print_timings};
+use output::{ListFormat, ListPackagesFormat, ShowFormat};
+use style::{ColorChoice, Style};
+
 /// Manage sandboxed development environments.
 #[derive(Debug, Parser)]
 pub struct Args {
@@ -32,10 +49,98 @@ pub struct Args {
     )]
     config: PathWithVarExpansion,
 
+    /// Profile from the configuration file to merge over its other settings
+    /// [default: $CUBICLE_PROFILE, if set].
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Fail instead of waiting if another process is using an environment or
+    /// package.
+    #[arg(long)]
+    no_wait: bool,
+
+    /// Print what `new`, `reset`, `purge`, and `package update` would do,
+    /// without doing it.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// After the command finishes, print how long its slow parts took (such
+    /// as building packages and copying them into environments).
+    #[arg(long)]
+    timings: bool,
+
+    /// Don't ask for confirmation before `purge` or `reset` acts on several
+    /// environments matched by wildcards.
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Color the tables printed by `list` and `package list`. `auto` uses
+    /// color when writing to a terminal, unless `NO_COLOR` is set.
+    #[arg(long, value_enum, default_value_t)]
+    color: ColorChoice,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// A time format named on the command line. See [`TimeFormat`].
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum TimeFormatArg {
+    Relative,
+    Iso,
+    Unix,
+}
+
+impl From<TimeFormatArg> for TimeFormat {
+    fn from(format: TimeFormatArg) -> Self {
+        match format {
+            TimeFormatArg::Relative => Self::Relative,
+            TimeFormatArg::Iso => Self::Iso,
+            TimeFormatArg::Unix => Self::Unix,
+        }
+    }
+}
+
+/// When `cub package update` re-builds dependencies.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DepsArg {
+    /// Only build dependencies that have never been built successfully.
+    Never,
+    /// Re-build dependencies that are stale.
+    Stale,
+    /// Always re-build dependencies.
+    Always,
+}
+
+impl From<DepsArg> for ShouldPackageUpdate {
+    fn from(deps: DepsArg) -> Self {
+        match deps {
+            DepsArg::Never => Self::IfRequired,
+            DepsArg::Stale => Self::IfStale,
+            DepsArg::Always => Self::Always,
+        }
+    }
+}
+
+/// A runner named on the command line.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum RunnerArg {
+    #[value(name = "bwrap", alias = "bubblewrap")]
+    Bubblewrap,
+    Docker,
+    User,
+}
+
+impl From<RunnerArg> for RunnerKind {
+    fn from(runner: RunnerArg) -> Self {
+        match runner {
+            RunnerArg::Bubblewrap => Self::Bubblewrap,
+            RunnerArg::Docker => Self::Docker,
+            RunnerArg::User => Self::User,
+        }
+    }
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Generate tab-completions for your shell.
@@ -57,6 +162,103 @@ enum Commands {
     #[command(arg_required_else_help(true))]
     Completions { shell: Shell },
 
+    /// Save all environments, to move them to another machine.
+    ///
+    /// This saves each environment's home directory, work directory, and
+    /// package list. Use `cub restore` to recreate the environments from the
+    /// backup. Instances of golden environments and environments for git
+    /// worktrees are skipped.
+    #[command(arg_required_else_help(true))]
+    Backup {
+        /// Where to write the backup: a new tar archive if it ends in `.tar`,
+        /// or a new or empty directory otherwise.
+        path: PathBuf,
+    },
+
+    /// View and clear the caches shared by all environments.
+    #[command(subcommand)]
+    Cache(CacheCommands),
+
+    /// Open an existing environment in Visual Studio Code.
+    ///
+    /// With the Docker runner, this attaches VS Code to the environment's
+    /// container, starting it if needed. This needs VS Code's Dev Containers
+    /// extension. With other runners, this opens the environment's work
+    /// directory, through the remote set by `code.remote` in the
+    /// configuration file, if any.
+    #[command(arg_required_else_help(true))]
+    Code {
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+    },
+
+    /// Describe the configuration file.
+    #[command(subcommand)]
+    Config(ConfigCommands),
+
+    /// Answer queries from a background process, to speed up other commands.
+    ///
+    /// While this runs, `list`, `show`, and `package list` get their results
+    /// from it instead of computing them, and it keeps the disk usage of
+    /// environments up to date. It stops when the configuration file changes.
+    Daemon {
+        /// Also serve metrics over HTTP at `/metrics` on this address, like
+        /// `127.0.0.1:9466`, for Prometheus to scrape.
+        ///
+        /// A port number alone, like `9466`, listens on `127.0.0.1`. The
+        /// endpoint has no authentication, and the metrics include the names
+        /// of environments and packages, so only listen on other addresses
+        /// if the network is trusted.
+        ///
+        /// See `cub metrics` for details.
+        #[arg(long, value_name = "ADDRESS")]
+        metrics_address: Option<MetricsAddress>,
+    },
+
+    /// Compare the packages of two environments.
+    ///
+    /// This lists the packages that only one environment was created or
+    /// last reset with, marking those only in the first with `-` and those
+    /// only in the second with `+`.
+    #[command(arg_required_else_help(true))]
+    Diff {
+        /// Also compare the environments' Debian packages.
+        ///
+        /// This uses the installed Debian packages when the runner knows
+        /// them (see `cub show --debian-packages`), or the requested ones
+        /// otherwise.
+        #[arg(long)]
+        debian_packages: bool,
+        /// Also compare the sizes of the environments' home directories.
+        #[arg(long)]
+        sizes: bool,
+        /// First environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name1: EnvironmentPattern,
+        /// Second environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name2: EnvironmentPattern,
+    },
+
+    /// Integrate with direnv.
+    #[command(subcommand)]
+    Direnv(DirenvCommands),
+
+    /// Explain how Cubicle is set up on this host.
+    ///
+    /// This shows which runner is in use and why. With `runner = "auto"` in
+    /// the configuration file, that's the runner picked the first time
+    /// Cubicle ran. This also probes the host to show which runners could be
+    /// picked now.
+    Doctor,
+
     /// Run a shell in an existing environment.
     #[command(arg_required_else_help(true))]
     Enter {
@@ -67,6 +269,29 @@ enum Commands {
         name: EnvironmentPattern,
     },
 
+    /// Recreate an environment under another runner.
+    ///
+    /// This copies the environment's home and work directories into a new
+    /// environment with the same name and packages under the given runner,
+    /// like `cub backup` followed by `cub restore`. The packages are built
+    /// with the new runner as needed. Afterwards, set `runner` in the
+    /// configuration file to keep using the new environment.
+    #[command(arg_required_else_help(true))]
+    Migrate {
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+        /// The runner to move the environment to.
+        #[arg(long)]
+        to: RunnerArg,
+        /// Purge the environment from the current runner once it's been
+        /// recreated under the new one.
+        #[arg(long)]
+        purge: bool,
+    },
+
     /// Run a command in an existing environment.
     #[command(arg_required_else_help(true))]
     Exec {
@@ -80,13 +305,66 @@ enum Commands {
         command: Vec<String>,
     },
 
+    /// Generate files to integrate with other tools.
+    #[command(subcommand)]
+    Generate(GenerateCommands),
+
+    /// Stop environments that have been idle for too long.
+    ///
+    /// This stops environments that haven't been entered or used with `exec`
+    /// for longer than the `auto_stop` duration in the configuration file.
+    /// Stopped environments start again automatically when they're next used.
+    ///
+    /// This may be useful to run periodically, such as from a cron job or
+    /// systemd timer.
+    Gc,
+
+    /// Show a log of operations on environments and packages.
+    ///
+    /// This lists when environments were created, reset, and purged, with
+    /// the packages and package versions they got, and when packages were
+    /// built. Package versions are short prefixes of the SHA-256 digests of
+    /// the packages' build outputs.
+    History {
+        /// Environment name.
+        ///
+        /// If given, only show the operations on this environment, which
+        /// need not exist anymore. Otherwise, show the operations on all
+        /// environments and packages.
+        name: Option<EnvironmentName>,
+    },
+
     /// Show existing environments.
     List {
         /// Set output format.
         #[arg(long, value_enum, default_value_t)]
         format: ListFormat,
+        /// Set how times are shown in the table [default: `time_format` from
+        /// the configuration file].
+        #[arg(long, value_enum)]
+        time_format: Option<TimeFormatArg>,
+        /// Show long names in full, even if the table doesn't fit in the
+        /// terminal.
+        #[arg(long)]
+        wide: bool,
+        /// Recompute the disk usage of every environment now.
+        ///
+        /// Otherwise, disk usage computed within the last minute is reused,
+        /// and older values are shown (marked with `~`) while they're updated
+        /// in the background.
+        #[arg(long)]
+        refresh: bool,
     },
 
+    /// Print metrics about environments and package builds.
+    ///
+    /// The output uses the Prometheus text format. It includes each
+    /// environment's disk usage, whether it's in use, and when it was last
+    /// used, plus the number of builds, failed builds, and duration of the
+    /// last build for each package that has been built. `cub daemon
+    /// --metrics-address` serves the same metrics over HTTP.
+    Metrics,
+
     /// View and manage packages.
     #[command(subcommand)]
     Package(PackageCommands),
@@ -105,6 +383,62 @@ enum Commands {
         /// matches zero or more characters.
         #[arg(long, value_delimiter = ',')]
         packages: Option<Vec<String>>,
+        /// Create a lightweight instance of this existing environment.
+        ///
+        /// The instance's home directory starts as a copy-on-write overlay of
+        /// the golden environment's home directory, and the instance gets its
+        /// own empty work directory.
+        #[arg(long, conflicts_with = "packages")]
+        golden: Option<EnvironmentName>,
+        /// Use the same packages as this existing environment.
+        ///
+        /// The packages come from the `packages.txt` file in the existing
+        /// environment's work directory. Unlike `--golden`, the new
+        /// environment is independent and gets its own copy of the packages;
+        /// no other files are copied.
+        #[arg(long, conflicts_with_all = ["packages", "golden"])]
+        from: Option<EnvironmentName>,
+        /// Give the environment a read-only home directory.
+        ///
+        /// Each `cub enter` or `cub exec` starts with the home directory as
+        /// it was seeded, and changes to it are discarded on exit. The work
+        /// directory is kept as usual.
+        #[arg(long, conflicts_with = "golden")]
+        read_only_home: bool,
+        /// Encrypt the environment's files at rest.
+        ///
+        /// The files are decrypted only while they're in use, such as during
+        /// `cub enter`. This is only supported with the Bubblewrap runner,
+        /// which uses gocryptfs.
+        #[arg(long, conflicts_with_all = ["golden", "read_only_home"])]
+        encrypt: bool,
+        /// Let the members of this Unix group use the environment too.
+        ///
+        /// Members can enter the environment and run commands in it as its
+        /// user. This is only supported with the User runner.
+        #[arg(long, conflicts_with_all = ["golden", "read_only_home", "encrypt"])]
+        group: Option<String>,
+        /// Bind-mount the environment's home and work directories from the
+        /// host, instead of using volumes.
+        ///
+        /// This overrides `docker.bind_mounts` for this environment. It's only
+        /// supported with the Docker runner.
+        #[arg(
+            long,
+            conflicts_with_all = ["golden", "read_only_home", "encrypt", "group"]
+        )]
+        bind_mounts: bool,
+        /// Start this shell for interactive sessions in the environment, like
+        /// `zsh` or `/usr/bin/fish`.
+        ///
+        /// This overrides the `shell` configuration setting for this
+        /// environment.
+        #[arg(long)]
+        shell: Option<String>,
+        /// Leave the environment in place if creating it fails partway, for
+        /// debugging. Otherwise, it's removed.
+        #[arg(long)]
+        keep_partial: bool,
         /// New environment name.
         name: EnvironmentName,
     },
@@ -112,6 +446,32 @@ enum Commands {
     /// Delete environment(s) and their work directories.
     #[command(arg_required_else_help(true))]
     Purge {
+        /// Delete only the home directory, keeping the work directory.
+        ///
+        /// This is like `reset` without recreating the home directory, to
+        /// reclaim its space. Use `reset` or `repair` to recreate it later.
+        #[arg(long, conflicts_with = "work_only")]
+        home_only: bool,
+        /// Delete only the work directory, keeping the home directory.
+        ///
+        /// Use `repair` to recreate an empty work directory later.
+        #[arg(long)]
+        work_only: bool,
+        /// Environment name(s).
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        #[arg(required(true))]
+        names: Vec<EnvironmentPattern>,
+    },
+
+    /// Recreate the missing pieces of partially created environment(s).
+    ///
+    /// This lists which pieces of each environment exist (such as its
+    /// container, home directory, and work directory), then recreates any
+    /// missing pieces, keeping the ones that exist.
+    #[command(arg_required_else_help(true))]
+    Repair {
         /// Environment name(s).
         ///
         /// Wildcards are allowed: `?` matches a single character and `*`
@@ -133,6 +493,14 @@ enum Commands {
         /// matches zero or more characters.
         #[arg(long, value_delimiter = ',')]
         packages: Option<Vec<String>>,
+        /// Keep this file or directory from the old home directory, like
+        /// `~/.cache/cargo` (may be given more than once).
+        ///
+        /// The files are saved before the reset and extracted over the new
+        /// home directory, so they win over files from packages. This is
+        /// useful for expensive caches and credentials.
+        #[arg(long, value_name = "PATH")]
+        keep: Vec<HomeRelativePath>,
         /// Environment name(s).
         ///
         /// Wildcards are allowed: `?` matches a single character and `*`
@@ -141,8 +509,189 @@ enum Commands {
         names: Vec<EnvironmentPattern>,
     },
 
+    /// Recreate environments from a backup made by `cub backup`.
+    ///
+    /// The packages for each environment are built as needed. Environments
+    /// that already exist are skipped.
+    #[command(arg_required_else_help(true))]
+    Restore {
+        /// The backup directory or tar archive.
+        path: PathBuf,
+    },
+
+    /// Check environments for known vulnerabilities.
+    ///
+    /// This runs Trivy (`trivy`) or Grype (`grype`), whichever is installed,
+    /// and prints its report of known CVEs. With the Docker runner, it scans
+    /// each environment's container image, which includes the base image and
+    /// the environment's Debian packages. With other runners, it scans the
+    /// host's versions of the Debian packages that the environment's
+    /// packages depend on.
+    #[command(arg_required_else_help(true))]
+    Scan {
+        /// Scan the base images that environments are created from, instead
+        /// of environments.
+        #[arg(long, conflicts_with = "names")]
+        images: bool,
+        /// Environment name(s).
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        #[arg(required_unless_present = "images")]
+        names: Vec<EnvironmentPattern>,
+    },
+
+    /// Manage seccomp policies for the configured runner.
+    #[command(subcommand)]
+    Seccomp(SeccompCommands),
+
+    /// Show details about an existing environment.
+    #[command(arg_required_else_help(true))]
+    Show {
+        /// Set output format.
+        #[arg(long, value_enum, default_value_t)]
+        format: ShowFormat,
+        /// Show the environment's Debian packages instead.
+        ///
+        /// This compares the Debian packages that the environment's packages
+        /// depend on with the ones installed for it, not counting the ones
+        /// Cubicle always installs. They differ with the Docker runner's
+        /// `strict_debian_packages = false`, which installs the Debian
+        /// dependencies of every package definition, or when package
+        /// definitions changed since the environment was created or reset.
+        /// Other runners use the host's Debian packages, so only the
+        /// requested ones are known.
+        #[arg(long)]
+        debian_packages: bool,
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+    },
+
+    /// Connect to an existing environment over SSH.
+    ///
+    /// This needs `enabled = true` in the `[ssh]` section of the
+    /// configuration file. It runs the SSH client with the same options that
+    /// `ssh-config` generates.
+    #[command(arg_required_else_help(true))]
+    Ssh {
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+        /// Command and arguments to run (default: an interactive shell).
+        #[arg(last = true)]
+        command: Vec<String>,
+    },
+
+    /// Print SSH client configuration for all environments.
+    ///
+    /// This lets other programs that use SSH, like `scp`, `rsync`, and
+    /// remote development tools, connect to an environment named `foo` as
+    /// the host `foo.cubicle`. For example:
+    ///
+    ///   $ cub ssh-config > ~/.ssh/cubicle.conf
+    ///
+    /// Then add `Include cubicle.conf` near the top of `~/.ssh/config`. Run
+    /// this again after creating environments.
+    SshConfig,
+
+    /// Run an SSH server in an environment over standard input and output.
+    ///
+    /// The SSH client runs this as its `ProxyCommand`.
+    #[command(hide = true)]
+    SshProxy {
+        /// Environment name.
+        name: EnvironmentName,
+    },
+
+    /// Start environment(s) ahead of their next use.
+    ///
+    /// Runners that don't keep environments running, like Bubblewrap, have
+    /// nothing to start, so this only checks that the environments exist.
+    #[command(arg_required_else_help(true))]
+    Start {
+        /// Environment name(s).
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        #[arg(required(true))]
+        names: Vec<EnvironmentPattern>,
+    },
+
+    /// Summarize Cubicle's state for shell prompts.
+    ///
+    /// This shows the current environment, taken from `CUBICLE_ENV` (as set
+    /// by `use cubicle` in direnv) or `CUBICLE` (as set inside
+    /// environments), and counts the packages that will be rebuilt when
+    /// they're next used: stale packages have changed since they were built,
+    /// and packages due for update have passed their `auto_update` period.
+    Status {
+        /// Print one line, `STALE PENDING [ENV]`, in a format that won't
+        /// change across versions.
+        #[arg(long)]
+        porcelain: bool,
+    },
+
+    /// Stop environment(s) and any processes running in them.
+    ///
+    /// This keeps the environments' home and work directories. With the
+    /// Docker runner, stopped environments start again when they're next
+    /// used.
+    #[command(arg_required_else_help(true))]
+    Stop {
+        /// Environment name(s).
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        #[arg(required(true))]
+        names: Vec<EnvironmentPattern>,
+    },
+
+    /// Mirror an environment's work directory with a remote location.
+    ///
+    /// This runs rsync to make the remote location a copy of the work
+    /// directory, deleting remote files that aren't in the work directory.
+    /// It supports editing locally while building in an environment on
+    /// another machine. The work directory must be on the host's filesystem,
+    /// which needs bind mounts with the Docker runner (see
+    /// `docker.bind_mounts` and `cub new --bind-mounts`).
+    #[command(arg_required_else_help(true))]
+    Sync {
+        /// Copy from the remote location into the work directory instead,
+        /// deleting files that aren't at the remote location.
+        #[arg(long, conflicts_with = "watch")]
+        pull: bool,
+        /// Keep running, and sync again whenever the work directory changes.
+        #[arg(long)]
+        watch: bool,
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+        /// Remote location, like `user@host:src/project`.
+        ///
+        /// The path must name a directory other than the remote home
+        /// directory or `/`.
+        remote: String,
+    },
+
     /// Create and enter a new temporary environment.
+    ///
+    /// The environment is named with a prefix and random words, as set by
+    /// `tmp` in the configuration file.
     Tmp {
+        /// Text to put before the random words, instead of `tmp.name_prefix`
+        /// from the configuration file.
+        ///
+        /// `{date}` is replaced with the current date and `{project}` with
+        /// the name of the current directory.
+        #[arg(long)]
+        name_prefix: Option<String>,
         /// Comma-separated names of packages to inject into home directory.
         ///
         /// If omitted, uses the "default" package.
@@ -152,6 +701,33 @@ enum Commands {
         #[arg(long, value_delimiter = ',')]
         packages: Option<Vec<String>>,
     },
+
+    /// Report changes to files in an environment's work directory.
+    ///
+    /// This prints a JSON object per line for each file that's created,
+    /// modified, or removed, with the path relative to the work directory,
+    /// until interrupted. Changes are found by listing the directory about
+    /// once a second. With Docker volumes, the listing runs in a separate
+    /// container.
+    #[command(arg_required_else_help(true))]
+    Watch {
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+        /// Only report changes within this directory, relative to the work
+        /// directory.
+        path: Option<PathBuf>,
+    },
+
+    /// Manage environments for git branches.
+    #[command(subcommand)]
+    Worktree(WorktreeCommands),
+
+    /// Run `cub-<name>` from `$PATH` for any other subcommand.
+    #[command(external_subcommand)]
+    External(Vec<OsString>),
 }
 
 /// View and manage packages.
@@ -162,6 +738,14 @@ enum PackageCommands {
         /// Set output format.
         #[arg(long, value_enum, default_value_t)]
         format: ListPackagesFormat,
+        /// Set how times are shown in the table [default: `time_format` from
+        /// the configuration file].
+        #[arg(long, value_enum)]
+        time_format: Option<TimeFormatArg>,
+        /// Show long names and origins in full, even if the table doesn't
+        /// fit in the terminal.
+        #[arg(long)]
+        wide: bool,
     },
 
     /// (Re-)build one or more packages.
@@ -173,13 +757,36 @@ enum PackageCommands {
         /// dependencies.
         #[arg(long)]
         clean: bool,
-        /// Build dependencies only if required.
+        /// When to re-build the packages' transitive dependencies.
+        #[arg(long, value_enum, default_value_t = DepsArg::Stale)]
+        deps: DepsArg,
+        /// Build dependencies only if required. Same as `--deps never`.
+        #[arg(long, conflicts_with = "deps")]
+        skip_deps: bool,
+        /// Build dependencies matching this pattern only if required, even
+        /// when others are re-built.
+        ///
+        /// This defers re-building a slow dependency. It may be given more
+        /// than once. Wildcards are allowed, as for PACKAGES. It doesn't
+        /// apply to the named PACKAGES themselves.
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+        /// Keep running, and update the packages again whenever their
+        /// sources or their dependencies' sources change.
         ///
-        /// By default, this command will re-build dependencies if they are
-        /// stale. With this flag, it will only build dependencies if they are
-        /// strictly needed because have never been built successfully before.
+        /// Packages with a test script are tested after each build. Each
+        /// round prints PASS or FAIL, and a failure doesn't stop the
+        /// watching.
         #[arg(long)]
-        skip_deps: bool,
+        watch: bool,
+        /// After updating, extract the packages into the existing
+        /// environments that use them.
+        ///
+        /// This applies new builds without resetting the environments. Files
+        /// that a previous build included and the new build doesn't are left
+        /// in place until the environment is reset.
+        #[arg(long, conflicts_with = "watch")]
+        reseed: bool,
         /// Package name(s).
         ///
         /// Wildcards are allowed: `?` matches a single character and `*`
@@ -187,6 +794,174 @@ enum PackageCommands {
         #[arg(required(true))]
         packages: Vec<String>,
     },
+
+    /// Re-build stale packages that have been built before.
+    ///
+    /// Running this periodically keeps packages fresh, so that `cub new`
+    /// and `cub reset` rarely wait on builds. A package that fails to build
+    /// doesn't stop the others from updating.
+    Autoupdate {
+        /// Instead of updating packages now, install a systemd user timer
+        /// (or a launchd agent on macOS) that runs this command hourly.
+        ///
+        /// The job uses this configuration file and profile. Running this
+        /// again replaces it.
+        #[arg(long)]
+        install_timer: bool,
+    },
+
+    /// Create a local package from an existing Dockerfile.
+    ///
+    /// This builds the Dockerfile on the host with Docker, using its
+    /// directory as the build context. Then, it copies the given directories
+    /// out of the image into the package, which places them under
+    /// `~/opt/<package>` and puts symlinks to their `bin` executables in
+    /// `~/bin`. Running this again with the same package name replaces the
+    /// package's files but keeps any edits to its `package.toml`.
+    ///
+    /// The copied programs must be able to run in Cubicle's environments,
+    /// so this works best with Dockerfiles based on the same Debian release.
+    #[command(arg_required_else_help(true))]
+    ImportDockerfile {
+        /// Package name. Defaults to the name of the Dockerfile's directory.
+        #[arg(long)]
+        name: Option<String>,
+        /// Absolute path of a directory in the image to include (may be
+        /// repeated).
+        #[arg(long = "path", value_name = "PATH", default_value = "/usr/local")]
+        paths: Vec<PathBuf>,
+        /// Path to the Dockerfile.
+        dockerfile: PathBuf,
+    },
+}
+
+/// View and clear the caches shared by all environments.
+#[derive(Debug, Subcommand)]
+enum CacheCommands {
+    /// Show the caches and their sizes on the host.
+    ///
+    /// This includes caches left over from earlier configurations, which can
+    /// be removed with `cub cache clear`.
+    List,
+
+    /// Remove the contents of one or more caches.
+    ///
+    /// Caches that are no longer configured are removed entirely.
+    #[command(arg_required_else_help(true))]
+    Clear {
+        /// Cache name(s), as in the `caches` configuration.
+        #[arg(required(true))]
+        names: Vec<String>,
+    },
+}
+
+/// Manage environments for git branches.
+#[derive(Debug, Subcommand)]
+enum WorktreeCommands {
+    /// Create a new environment for a branch of the current git repository.
+    ///
+    /// This checks out the branch in a new git worktree on the host and uses
+    /// that as the environment's work directory. The environment is named
+    /// after the repository's directory and the branch, like `cubicle-fix-bug`
+    /// for the branch `fix/bug`. The repository's `.git` directory is also
+    /// made available in the environment, so that git works there. Only its
+    /// objects, refs, and the worktree's own state are writable.
+    ///
+    /// Purging the environment also removes the worktree, discarding any
+    /// changes in it.
+    #[command(arg_required_else_help(true))]
+    New {
+        /// Run a shell in new environment.
+        #[arg(long)]
+        enter: bool,
+        /// Comma-separated names of packages to inject into home directory.
+        ///
+        /// If omitted, uses the "default" package.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        #[arg(long, value_delimiter = ',')]
+        packages: Option<Vec<String>>,
+        /// Leave the environment in place if creating it fails partway, for
+        /// debugging. Otherwise, it's removed.
+        #[arg(long)]
+        keep_partial: bool,
+        /// Branch to check out.
+        branch: String,
+    },
+
+    /// Purge the environments of deleted branches.
+    ///
+    /// This purges environments created with `worktree new` whose branch has
+    /// been deleted from the remote it tracks (as of the last `git fetch
+    /// --prune`). Branches without an upstream are never pruned. This may be
+    /// useful to run periodically, such as from a cron job or a git hook.
+    Prune,
+}
+
+/// Describe the configuration file.
+#[derive(Debug, Subcommand)]
+enum ConfigCommands {
+    /// Print a JSON Schema for the configuration file.
+    ///
+    /// Editors that support JSON Schema for TOML files can use this to check
+    /// `cubicle.toml` and complete its settings. For example, with Taplo
+    /// (used by the "Even Better TOML" extension for VS Code), save the
+    /// schema and add this line to the top of `cubicle.toml`:
+    ///
+    ///   #:schema ./cubicle.schema.json
+    Schema,
+}
+
+/// Integrate with direnv.
+#[derive(Debug, Subcommand)]
+enum DirenvCommands {
+    /// Print a direnv library that adds `use cubicle` for `.envrc` files.
+    ///
+    /// Load it from `~/.config/direnv/direnvrc`:
+    ///
+    ///   eval "$(cub direnv hook)"
+    ///
+    /// Then, in a project's `.envrc`, `use cubicle ENVIRONMENT [COMMAND...]`
+    /// sets `CUBICLE_ENV` to the environment name and puts a wrapper for
+    /// each COMMAND on the `PATH` that runs it inside the environment with
+    /// `cub exec`.
+    Hook,
+}
+
+/// Generate files to integrate with other tools.
+#[derive(Debug, Subcommand)]
+enum GenerateCommands {
+    /// Print a systemd user unit that keeps an environment running.
+    ///
+    /// The unit starts the environment at login and stops it at shutdown,
+    /// so that a long-lived environment is ready without a manual `cub
+    /// enter`. Save it into `~/.config/systemd/user/`, then enable it with
+    /// `systemctl --user enable --now`. The unit's comments include the
+    /// exact commands.
+    ///
+    /// To start the environment at boot rather than at login, also run
+    /// `loginctl enable-linger`.
+    #[command(arg_required_else_help(true))]
+    Systemd {
+        /// Environment name.
+        ///
+        /// Wildcards are allowed: `?` matches a single character and `*`
+        /// matches zero or more characters.
+        name: EnvironmentPattern,
+    },
+}
+
+/// Manage seccomp policies for the configured runner.
+#[derive(Debug, Subcommand)]
+enum SeccompCommands {
+    /// Download and install the recommended seccomp policy.
+    ///
+    /// This saves the policy for the configured runner (Bubblewrap or Docker)
+    /// into `${XDG_DATA_HOME:-~/.local/share}/cubicle/seccomp/` and updates
+    /// the runner's `seccomp` setting in the configuration file to point to
+    /// it.
+    Install,
 }
 
 /// Parses the command-line arguments given to this executable.
@@ -203,6 +978,74 @@ impl Args {
     pub fn config_path(&self) -> &Path {
         self.config.as_ref()
     }
+
+    /// Returns the name of the configuration profile to use, if any.
+    pub fn profile(&self) -> Option<String> {
+        self.profile.clone().or_else(|| {
+            std::env::var("CUBICLE_PROFILE")
+                .ok()
+                .filter(|profile| !profile.is_empty())
+        })
+    }
+
+    /// Returns true if actions should be printed rather than taken.
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Returns true if standard output is reserved for data, so events must
+    /// be printed elsewhere.
+    pub fn stdout_is_data(&self) -> bool {
+        matches!(self.command, Commands::SshProxy { .. })
+    }
+
+    /// Returns the name of the command, like `"package update"`, if it may
+    /// run long enough to be reported when it finishes (see
+    /// [`Cubicle::notify_finished`]).
+    pub fn long_running_command(&self) -> Option<&'static str> {
+        use Commands::*;
+        match &self.command {
+            Migrate { .. } => Some("migrate"),
+            New { enter: false, .. } => Some("new"),
+            Package(PackageCommands::Autoupdate {
+                install_timer: false,
+            }) => Some("package autoupdate"),
+            Package(PackageCommands::Update { watch: false, .. }) => Some("package update"),
+            Purge { .. } => Some("purge"),
+            Reset { .. } => Some("reset"),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the command only queries state, so that a
+    /// `cub daemon` can answer it.
+    fn is_query(&self) -> bool {
+        use Commands::*;
+        matches!(
+            self.command,
+            List { .. }
+                | Metrics
+                | Show { .. }
+                | Status { .. }
+                | Package(PackageCommands::List { .. })
+        )
+    }
+
+    /// Returns true if a breakdown of the time spent should be printed.
+    pub fn timings(&self) -> bool {
+        self.timings
+    }
+
+    /// Applies command-line flags that override settings in the
+    /// configuration file.
+    pub fn override_config(&self, config: &mut Config) {
+        if self.no_wait {
+            config.lock_timeout = Some(Duration::ZERO);
+        }
+        if self.yes {
+            config.confirm_wildcards = false;
+        }
+    }
 }
 
 /// This type wrapper stores a normal path but understands "$HOME".
@@ -271,6 +1114,60 @@ impl FromStr for PathWithVarExpansion {
     }
 }
 
+/// A path within an environment's home directory, like `~/.cache/cargo`.
+///
+/// This is stored relative to the home directory. Since the shell expands an
+/// unquoted `~` to the host's home directory, paths within the host's home
+/// directory are taken to mean the same paths in the environment.
+#[derive(Clone, Debug)]
+struct HomeRelativePath(PathBuf);
+
+impl HomeRelativePath {
+    /// Helper for `from_str`. Split out for unit testing.
+    fn parse(s: &str, home: &Path) -> Result<Self> {
+        let path = Path::new(s);
+        let rel = if let Some(rest) = s.strip_prefix("~/") {
+            Path::new(rest)
+        } else if let Ok(rest) = path.strip_prefix(home) {
+            rest
+        } else if path.is_absolute() {
+            return Err(anyhow!(
+                "expected a path within the home directory, like `~/.cache`, found {s:?}"
+            ));
+        } else {
+            path
+        };
+        Ok(Self(rel.to_owned()))
+    }
+}
+
+impl FromStr for HomeRelativePath {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s, host_home_dir())
+    }
+}
+
+/// The address for `cub daemon` to serve metrics on.
+///
+/// A port number alone is taken to mean that port on `127.0.0.1`.
+#[derive(Clone, Copy, Debug)]
+struct MetricsAddress(SocketAddr);
+
+impl FromStr for MetricsAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(port) = s.parse::<u16>() {
+            return Ok(Self(SocketAddr::from(([127, 0, 0, 1], port))));
+        }
+        s.parse()
+            .map(Self)
+            .with_context(|| format!("invalid address {s:?}: expected HOST:PORT or PORT"))
+    }
+}
+
 fn default_config_path() -> PathWithVarExpansion {
     let xdg_config_home = if let Ok(path) = std::env::var("XDG_CONFIG_HOME") {
         PathBuf::from(path)
@@ -327,7 +1224,7 @@ fn write_completions<W: io::Write>(shell: Shell, out: &mut W) -> Result<()> {
         let mut buf: Vec<u8> = Vec::new();
         generate(shell, cmd, "cub", &mut buf);
         let buf = String::from_utf8(buf).context("error reading clap shell completion output")?;
-        let mut counts = [0; 5];
+        let mut counts = [0; 6];
         let mut write = || -> std::io::Result<()> {
             for line in buf.lines() {
                 match line {
@@ -351,6 +1248,14 @@ fn write_completions<W: io::Write>(shell: Shell, out: &mut W) -> Result<()> {
                             r#"'*--packages=[Comma-separated names of packages to inject into home directory]:PACKAGES:_cub_pkgs_comma' \"#
                         )?;
                     }
+                    r#"'(--packages)--golden=[Create a lightweight instance of this existing environment]:GOLDEN:_default' \"# =>
+                    {
+                        counts[5] += 1;
+                        writeln!(
+                            out,
+                            r#"'(--packages)--golden=[Create a lightweight instance of this existing environment]:GOLDEN:_cub_envs' \"#
+                        )?;
+                    }
                     r#"if [ "$funcstack[1]" = "_cub" ]; then"# => {
                         counts[4] += 1;
                         #[allow(clippy::write_literal)]
@@ -381,7 +1286,7 @@ _cub_pkgs_comma() {
         write().context("failed to write zsh completions")?;
         debug_assert_eq!(
             counts,
-            [2, 2, 1, 3, 1],
+            [10, 6, 1, 4, 1, 1],
             "zsh completions not patched as expected"
         );
     } else {
@@ -390,11 +1295,224 @@ _cub_pkgs_comma() {
     Ok(())
 }
 
+/// Execute the subcommand requested on the command line if it doesn't need a
+/// [`Cubicle`] instance.
+///
+/// Returns `None` for other subcommands, which should be passed to [`run`].
+pub fn run_without_program(args: &Args) -> Option<Result<()>> {
+    match &args.command {
+        Commands::Direnv(DirenvCommands::Hook) => Some((|| {
+            let exe = std::env::current_exe()
+                .context("error getting the path of the current executable")?;
+            direnv::write_hook(
+                &mut io::stdout(),
+                &exe,
+                args.config_path(),
+                args.profile().as_deref(),
+            )
+        })()),
+        Commands::Config(ConfigCommands::Schema) => Some((|| {
+            let schema = serde_json::to_string_pretty(&Config::json_schema())
+                .context("failed to serialize JSON Schema")?;
+            println!("{schema}");
+            Ok(())
+        })()),
+        Commands::Seccomp(SeccompCommands::Install) => Some((|| {
+            let config_path = args.config_path();
+            println!("Downloading the recommended seccomp policy");
+            let path = install_seccomp_policy(config_path)?;
+            println!("Installed seccomp policy at {path}");
+            println!("Updated `seccomp` setting in {config_path:?}");
+            Ok(())
+        })()),
+        _ => None,
+    }
+}
+
+/// Execute the subcommand requested on the command line using a running
+/// `cub daemon`, if the subcommand only queries state and a daemon is
+/// available.
+///
+/// Returns `None` otherwise, and the subcommand should be passed to [`run`].
+pub fn run_with_daemon(args: &Args) -> Option<Result<()>> {
+    use Commands::*;
+    if !args.is_query() || args.dry_run || args.timings {
+        return None;
+    }
+    let client = daemon::Client::connect(
+        daemon::socket_path(args.config_path(), args.profile().as_deref()).ok()?,
+    )?;
+    // Without a flag, the time format comes from the configuration file,
+    // which the daemon doesn't share.
+    let time_format = |arg: Option<TimeFormatArg>| -> Result<TimeFormat> {
+        match arg {
+            Some(arg) => Ok(TimeFormat::from(arg)),
+            None => Ok(cubicle::config::Config::read_from_file_with_profile(
+                args.config_path(),
+                args.profile().as_deref(),
+            )?
+            .time_format),
+        }
+    };
+    Some(match &args.command {
+        List {
+            format,
+            refresh,
+            time_format: arg,
+            wide,
+        } => time_format(*arg).and_then(|time_format| {
+            output::list_environments(
+                &client,
+                *format,
+                Refresh(*refresh),
+                Style::new(args.color).wide(*wide),
+                time_format,
+            )
+            .map(|_| ())
+        }),
+        Metrics => output::print_metrics(&client),
+        Show {
+            name,
+            format,
+            debian_packages,
+        } => client.get_environment_names().and_then(|names| {
+            let name = name.matching_environment(names)?;
+            if *debian_packages {
+                output::show_debian_packages(&client, &name, *format)
+            } else {
+                output::show_environment(&client, &name, *format)
+            }
+        }),
+        Status { porcelain } => {
+            output::print_status(&client, current_environment().as_ref(), *porcelain)
+        }
+        Package(PackageCommands::List {
+            format,
+            time_format: arg,
+            wide,
+        }) => time_format(*arg).and_then(|time_format| {
+            output::list_packages(
+                &client,
+                *format,
+                Style::new(args.color).wide(*wide),
+                time_format,
+            )
+        }),
+        _ => unreachable!(),
+    })
+}
+
+/// Returns a client for the running `cub daemon`, if any, whose cached
+/// answers the subcommand may make out of date.
+///
+/// After running the subcommand, pass this to [`invalidate_daemon`].
+pub fn daemon_to_invalidate(args: &Args) -> Option<daemon::Client> {
+    if args.is_query() || args.dry_run || matches!(args.command, Commands::Daemon { .. }) {
+        return None;
+    }
+    daemon::Client::connect(
+        daemon::socket_path(args.config_path(), args.profile().as_deref()).ok()?,
+    )
+}
+
+/// Tells the `cub daemon` to forget its cached answers.
+pub fn invalidate_daemon(client: daemon::Client) {
+    if let Err(e) = client.invalidate() {
+        warn(e);
+    }
+}
+
+/// Returned when a plugin (an external subcommand) exits unsuccessfully.
+#[derive(Debug)]
+struct PluginFailed(ExitStatus);
+
+impl PluginFailed {
+    /// Returns the status for `cub` to exit with, using the shell's
+    /// convention of 128 plus the signal number for a plugin killed by a
+    /// signal.
+    fn exit_code(&self) -> i32 {
+        use std::os::unix::process::ExitStatusExt;
+        match (self.0.code(), self.0.signal()) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1,
+        }
+    }
+}
+
+impl Display for PluginFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "plugin failed with {}", self.0)
+    }
+}
+
+impl std::error::Error for PluginFailed {}
+
+/// Returns the status that `cub` should exit with if `error` came from a
+/// failed plugin, which has already reported its own error.
+pub fn plugin_exit_code(error: &Error) -> Option<i32> {
+    error
+        .downcast_ref::<PluginFailed>()
+        .map(PluginFailed::exit_code)
+}
+
+/// Starts `cub list --refresh` in the background to update the cached disk
+/// usage of every environment, without waiting for it.
+fn refresh_environments_in_background(config_path: &Path, profile: Option<&str>) -> Result<()> {
+    let exe =
+        std::env::current_exe().context("error getting the path of the current executable")?;
+    let mut args = vec![OsStr::new("--config"), config_path.as_os_str()];
+    if let Some(profile) = profile {
+        args.extend([OsStr::new("--profile"), OsStr::new(profile)]);
+    }
+    args.extend(["list", "--refresh", "--format", "json"].map(OsStr::new));
+    cubicle::hidden::spawn_detached(&exe, &args)
+}
+
+/// Returns the environment that the shell is associated with, from
+/// `CUBICLE_ENV` or `CUBICLE`.
+fn current_environment() -> Option<EnvironmentName> {
+    ["CUBICLE_ENV", "CUBICLE"].into_iter().find_map(|var| {
+        std::env::var(var)
+            .ok()
+            .and_then(|name| EnvironmentName::from_str(&name).ok())
+    })
+}
+
 /// Execute the subcommand requested on the command line.
 pub fn run(args: Args, program: &Cubicle) -> Result<()> {
     use Commands::*;
+    // `cub migrate` needs a second instance, using the other runner.
+    let migrate_config = match &args.command {
+        Migrate { to, .. } => {
+            let mut config = cubicle::config::Config::read_from_file_with_profile(
+                args.config_path(),
+                args.profile().as_deref(),
+            )?;
+            args.override_config(&mut config);
+            config.runner = RunnerKind::from(*to);
+            Some(config)
+        }
+        _ => None,
+    };
+    let profile = args.profile();
+    let dry_run = args.dry_run;
+    let style = Style::new(args.color);
+    let config_path = args.config.0;
     match args.command {
         Completions { shell } => write_completions(shell, &mut io::stdout()),
+        Backup { path } => program.backup_environments(&path),
+        Cache(command) => run_cache_command(command, program),
+        Code { name } => {
+            program.open_in_code(&name.matching_environment(program.get_environment_names()?)?)
+        }
+        Daemon { metrics_address } => daemon::serve(
+            program,
+            &daemon::socket_path(&config_path, profile.as_deref())?,
+            &config_path,
+            metrics_address.map(|address| address.0),
+        ),
+        Doctor => output::print_doctor(program, &config_path),
         Enter { name } => {
             program.enter_environment(&name.matching_environment(program.get_environment_names()?)?)
         }
@@ -402,59 +1520,358 @@ pub fn run(args: Args, program: &Cubicle) -> Result<()> {
             &name.matching_environment(program.get_environment_names()?)?,
             &command,
         ),
-        List { format } => program.list_environments(format),
+        Diff {
+            debian_packages,
+            sizes,
+            name1,
+            name2,
+        } => {
+            let names = program.get_environment_names()?;
+            output::print_environment_diff(
+                program,
+                [
+                    &name1.matching_environment(names.clone())?,
+                    &name2.matching_environment(names)?,
+                ],
+                debian_packages,
+                sizes,
+            )
+        }
+        Gc => program.stop_idle_environments(),
+        History { name } => {
+            for entry in program.history(name.as_ref())? {
+                println!("{entry}");
+            }
+            Ok(())
+        }
+        Metrics => output::print_metrics(program),
+        Migrate { name, to: _, purge } => {
+            let name = name.matching_environment(program.get_environment_names()?)?;
+            let to = CubicleBuilder::new(migrate_config.unwrap())
+                .events(print_event)
+                .build()?;
+            program.migrate_environment(&name, &to)?;
+            if purge {
+                program.purge_environment(&name, Quiet(false))?;
+            }
+            Ok(())
+        }
+        Generate(GenerateCommands::Systemd { name }) => {
+            let exe = std::env::current_exe()
+                .context("error getting the path of the current executable")?;
+            systemd::write_unit(
+                &mut io::stdout(),
+                &exe,
+                &config_path,
+                profile.as_deref(),
+                &name.matching_environment(program.get_environment_names()?)?,
+            )
+        }
+        List {
+            format,
+            refresh,
+            time_format,
+            wide,
+        } => {
+            let stale = output::list_environments(
+                program,
+                format,
+                Refresh(refresh),
+                style.wide(wide),
+                time_format.map_or_else(|| program.time_format(), TimeFormat::from),
+            )?;
+            if stale && !args.dry_run {
+                refresh_environments_in_background(&config_path, profile.as_deref())?;
+            }
+            program.warn_if_low_on_space();
+            Ok(())
+        }
         New {
             name,
             enter,
             packages,
+            golden,
+            from,
+            read_only_home,
+            encrypt,
+            group,
+            bind_mounts,
+            shell,
+            keep_partial,
         } => {
-            let packages = packages
-                .map(|packages| package_set_from_patterns(&packages, program.get_package_names()?))
-                .transpose()?;
-            program.new_environment(&name, packages)?;
+            if shell.as_deref() == Some("") {
+                return Err(anyhow!("`--shell` may not be empty"));
+            }
+            let keep_partial = KeepPartial(keep_partial);
+            if let Some(golden) = golden {
+                program.new_instance(&name, &golden, keep_partial)?;
+            } else {
+                let packages = match from {
+                    Some(from) => Some(program.get_environment_packages(&from)?),
+                    None => packages
+                        .map(|packages| {
+                            package_set_from_patterns(&packages, program.get_package_names()?)
+                        })
+                        .transpose()?,
+                };
+                if read_only_home {
+                    program.new_read_only_environment(&name, packages, keep_partial)?;
+                } else if encrypt {
+                    program.new_encrypted_environment(&name, packages, keep_partial)?;
+                } else if let Some(group) = group {
+                    program.new_shared_environment(&name, packages, keep_partial, &group)?;
+                } else if bind_mounts {
+                    program.new_bind_mounted_environment(&name, packages, keep_partial)?;
+                } else {
+                    program.new_environment(&name, packages, keep_partial)?;
+                }
+            }
+            if let Some(shell) = shell {
+                program.set_environment_shell(&name, &shell)?;
+            }
             if enter {
                 program.enter_environment(&name)?;
             }
             Ok(())
         }
-        Package(command) => run_package_command(command, program),
-        Purge { names } => {
+        Package(PackageCommands::Autoupdate {
+            install_timer: true,
+        }) => {
+            if dry_run {
+                return Err(anyhow!("cannot install a timer in a dry run"));
+            }
+            let exe = std::env::current_exe()
+                .context("error getting the path of the current executable")?;
+            autoupdate::install_timer(&exe, &config_path, profile.as_deref())
+        }
+        Package(command) => run_package_command(command, program, style),
+        Purge {
+            home_only,
+            work_only,
+            names,
+        } => {
+            let matched = matching_environments(&names, program.get_environment_names()?)?;
+            if !confirm_wildcards(program, "purge", &names, &matched, dry_run)? {
+                return Ok(());
+            }
+            let dir = if home_only {
+                Some(EnvDir::Home)
+            } else if work_only {
+                Some(EnvDir::Work)
+            } else {
+                None
+            };
+            for_each_environment("purge", matched, jobs(program), |name| match dir {
+                Some(dir) => program.purge_environment_dir(name, dir),
+                None => program.purge_environment(name, Quiet(false)),
+            })
+        }
+        Repair { names } => {
             for name in matching_environments(&names, program.get_environment_names()?)? {
-                program.purge_environment(&name, Quiet(false))?;
+                output::print_pieces(&name, &program.inspect_environment(&name)?);
+                if !program.repair_environment(&name)? {
+                    println!("Environment {name} is not broken (nothing to repair)");
+                }
             }
             Ok(())
         }
         // TODO: rename
-        Reset { names, packages } => {
+        Reset {
+            names,
+            packages,
+            keep,
+        } => {
             let packages = packages
                 .map(|packages| package_set_from_patterns(&packages, program.get_package_names()?))
                 .transpose()?;
+            let keep: Vec<PathBuf> = keep.into_iter().map(|path| path.0).collect();
+            let matched = matching_environments(&names, program.get_environment_names()?)?;
+            if !confirm_wildcards(program, "reset", &names, &matched, dry_run)? {
+                return Ok(());
+            }
+            for_each_environment("reset", matched, jobs(program), |name| {
+                program.reset_environment_keeping(name, packages.clone(), &keep)
+            })
+        }
+        Restore { path } => program.restore_environments(&path),
+        Scan { images: true, .. } => program.scan_images(),
+        Scan {
+            images: false,
+            names,
+        } => {
+            // The scanners' reports would be interleaved if these ran in
+            // parallel.
             for name in matching_environments(&names, program.get_environment_names()?)? {
-                program.reset_environment(&name, packages.clone())?;
+                program.scan_environment(&name)?;
+            }
+            Ok(())
+        }
+        Commands::Config(_) | Direnv(_) | Seccomp(_) => {
+            unreachable!("handled in `run_without_program`")
+        }
+        Show {
+            name,
+            format,
+            debian_packages,
+        } => {
+            let name = name.matching_environment(program.get_environment_names()?)?;
+            if debian_packages {
+                output::show_debian_packages(program, &name, format)
+            } else {
+                output::show_environment(program, &name, format)
             }
+        }
+        Start { names } => for_each_environment(
+            "start",
+            matching_environments(&names, program.get_environment_names()?)?,
+            jobs(program),
+            |name| program.start_environment(name),
+        ),
+        Status { porcelain } => {
+            output::print_status(program, current_environment().as_ref(), porcelain)
+        }
+        Sync {
+            pull,
+            watch,
+            name,
+            remote,
+        } => program.sync_work_dir(
+            &name.matching_environment(program.get_environment_names()?)?,
+            &remote,
+            if pull {
+                SyncDirection::Pull
+            } else {
+                SyncDirection::Push
+            },
+            Watch(watch),
+        ),
+        Stop { names } => for_each_environment(
+            "stop",
+            matching_environments(&names, program.get_environment_names()?)?,
+            jobs(program),
+            |name| program.stop_environment(name),
+        ),
+        Ssh { name, command } => program.ssh(
+            &name.matching_environment(program.get_environment_names()?)?,
+            &command,
+            &config_path,
+        ),
+        SshConfig => {
+            print!("{}", program.ssh_config(&config_path)?);
             Ok(())
         }
-        Tmp { packages } => {
+        SshProxy { name } => program.ssh_proxy(&name),
+        External(args) => {
+            let (name, args) = args
+                .split_first()
+                .expect("clap requires a name for external subcommands");
+            let status = program.run_plugin(name, args, &config_path)?;
+            if status.success() {
+                Ok(())
+            } else {
+                Err(anyhow!(PluginFailed(status)))
+            }
+        }
+        Tmp {
+            name_prefix,
+            packages,
+        } => {
+            let packages = packages
+                .map(|packages| package_set_from_patterns(&packages, program.get_package_names()?))
+                .transpose()?;
+            program.create_enter_tmp_environment(packages, name_prefix.as_deref())
+        }
+        Watch { name, path } => output::print_file_events(
+            program,
+            &name.matching_environment(program.get_environment_names()?)?,
+            path.as_deref(),
+        ),
+        Worktree(command) => run_worktree_command(command, program),
+    }
+}
+
+fn run_cache_command(command: CacheCommands, program: &Cubicle) -> Result<()> {
+    use CacheCommands::*;
+    match command {
+        List => output::list_caches(program),
+        Clear { names } => program.clear_caches(&names),
+    }
+}
+
+fn run_worktree_command(command: WorktreeCommands, program: &Cubicle) -> Result<()> {
+    use WorktreeCommands::*;
+    match command {
+        New {
+            enter,
+            packages,
+            keep_partial,
+            branch,
+        } => {
             let packages = packages
                 .map(|packages| package_set_from_patterns(&packages, program.get_package_names()?))
                 .transpose()?;
-            program.create_enter_tmp_environment(packages)
+            let dir = std::env::current_dir().context("failed to get current directory")?;
+            let name = program.new_worktree_environment(
+                &dir,
+                &branch,
+                packages,
+                KeepPartial(keep_partial),
+            )?;
+            println!("Created environment {name} for branch {branch:?}");
+            if enter {
+                program.enter_environment(&name)?;
+            }
+            Ok(())
+        }
+        Prune => {
+            for name in program.prune_worktree_environments()? {
+                println!("Purged environment {name}");
+            }
+            Ok(())
         }
     }
 }
 
-fn run_package_command(command: PackageCommands, program: &Cubicle) -> Result<()> {
+fn run_package_command(command: PackageCommands, program: &Cubicle, style: Style) -> Result<()> {
     use PackageCommands::*;
     match command {
-        List { format } => program.list_packages(format),
+        // `--install-timer` is handled in `run`.
+        Autoupdate { .. } => program.auto_update_packages(),
+
+        List {
+            format,
+            time_format,
+            wide,
+        } => output::list_packages(
+            program,
+            format,
+            style.wide(wide),
+            time_format.map_or_else(|| program.time_format(), TimeFormat::from),
+        ),
+
+        ImportDockerfile {
+            name,
+            paths,
+            dockerfile,
+        } => {
+            let name = program.import_dockerfile(&dockerfile, name.as_deref(), &paths)?;
+            println!("Imported package {name}");
+            Ok(())
+        }
 
         Update {
             clean,
+            deps,
             skip_deps,
+            exclude,
+            watch,
+            reseed,
             packages,
         } => {
             use ShouldPackageUpdate::*;
-            let packages = package_set_from_patterns(&packages, program.get_package_names()?)?;
+            let names = program.get_package_names()?;
+            let packages = package_set_from_patterns(&packages, names.clone())?;
+            let excluded = package_set_from_patterns(&exclude, names)?;
             if clean {
                 for package in &packages {
                     program.purge_environment(
@@ -463,15 +1880,23 @@ fn run_package_command(command: PackageCommands, program: &Cubicle) -> Result<()
                     )?;
                 }
             }
+            let conditions = UpdatePackagesConditions {
+                dependencies: if skip_deps { IfRequired } else { deps.into() },
+                named: Always,
+                excluded,
+            };
+            if watch {
+                return program.watch_packages(&packages, &conditions);
+            }
             let specs = program.scan_packages()?;
-            program.update_packages(
-                &packages,
-                &specs,
-                &UpdatePackagesConditions {
-                    dependencies: if skip_deps { IfRequired } else { IfStale },
-                    named: Always,
-                },
-            )
+            program.update_packages(&packages, &specs, &conditions)?;
+            if reseed {
+                // Each re-seeded environment is reported as an event.
+                if program.reseed_environments(&packages)?.is_empty() {
+                    println!("No environments use these packages");
+                }
+            }
+            Ok(())
         }
     }
 }
@@ -529,7 +1954,10 @@ impl EnvironmentPattern {
                 if self.0.is_pattern() {
                     Err(anyhow!("pattern {self} matched no environment names"))
                 } else {
-                    Err(anyhow!("environment {self} not found"))
+                    Err(Error::with_kind(
+                        ErrorKind::EnvironmentNotFound,
+                        format!("environment {self} not found"),
+                    ))
                 }
             }
             (Some(name), None) => Ok(name),
@@ -553,13 +1981,137 @@ fn matching_environments(
                     "pattern {pattern} did not match any environment names"
                 ));
             } else {
-                return Err(anyhow!("environment {pattern} not found"));
+                return Err(Error::with_kind(
+                    ErrorKind::EnvironmentNotFound,
+                    format!("environment {pattern} not found"),
+                ));
             }
         }
     }
     Ok(matched)
 }
 
+/// Asks whether to go ahead with `purge` or `reset` when wildcards matched
+/// more than one environment, as set by `confirm_wildcards` in the
+/// configuration file. Returns false if the user declines.
+fn confirm_wildcards(
+    program: &Cubicle,
+    verb: &str,
+    patterns: &[EnvironmentPattern],
+    matched: &[EnvironmentName],
+    dry_run: bool,
+) -> Result<bool> {
+    if dry_run
+        || !program.confirm_wildcards()
+        || matched.len() < 2
+        || !patterns.iter().any(|pattern| pattern.0.is_pattern())
+    {
+        return Ok(true);
+    }
+    output::print_environment_sizes(program, matched)?;
+    if !io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "refusing to {verb} {} environments without confirmation \
+            (pass `--yes` to skip this)",
+            matched.len()
+        ));
+    }
+    print!(
+        "{} {} environments? [y/N] ",
+        capitalize(verb),
+        matched.len()
+    );
+    io::stdout().flush().context("failed to flush stdout")?;
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("failed to read answer from stdin")?;
+    let confirmed = matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+    if !confirmed {
+        println!("Canceled");
+    }
+    Ok(confirmed)
+}
+
+/// Returns the string with its first letter in uppercase.
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// How many environments `purge`, `reset`, `start`, and `stop` work on at
+/// once.
+const PARALLEL_ENVIRONMENTS: usize = 4;
+
+/// Returns how many environments to work on at once with the configured
+/// runner.
+///
+/// The user runner uses `sudo`, so it works on one environment at a time to
+/// keep password prompts from interleaving.
+fn jobs(program: &Cubicle) -> usize {
+    match program.runner_choice().runner {
+        RunnerKind::User => 1,
+        _ => PARALLEL_ENVIRONMENTS,
+    }
+}
+
+/// Calls `f` on each environment, running up to `jobs` at once.
+///
+/// This keeps going after failures. If only one environment fails, it
+/// returns that error. If several fail, it prints each error and returns a
+/// summary.
+fn for_each_environment<F>(verb: &str, names: Vec<EnvironmentName>, jobs: usize, f: F) -> Result<()>
+where
+    F: Fn(&EnvironmentName) -> Result<()> + Sync,
+{
+    let total = names.len();
+    let queue = std::sync::Mutex::new(names.into_iter());
+    let failures = std::sync::Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(total) {
+            scope.spawn(|| loop {
+                let Some(name) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                if let Err(e) = f(&name) {
+                    failures.lock().unwrap().push((name, e));
+                }
+            });
+        }
+    });
+
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort_by(|(a, _), (b, _)| a.cmp(b));
+    if failures.len() <= 1 {
+        return match failures.pop() {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        };
+    }
+    // Keep the exit status of an interrupted run.
+    if let Some(i) = failures
+        .iter()
+        .position(|(_, e)| e.kind() == Some(ErrorKind::Interrupted))
+    {
+        return Err(failures.swap_remove(i).1);
+    }
+    let failed = failures.len();
+    let names = failures
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    for (_, e) in failures.drain(..) {
+        warn(e);
+    }
+    Err(anyhow!(
+        "failed to {verb} {failed} of {total} environments: {names}"
+    ))
+}
+
 // `BTreeSet::drain_filter` isn't stable yet. See <https://github.com/rust-lang/rust/issues/70530>.
 fn drain_filter<T, F>(set: &mut BTreeSet<T>, mut pred: F) -> Vec<T>
 where
@@ -592,6 +2144,44 @@ mod tests {
         assert_eq!("/home/foo/bar", p.sub_home_prefix(Path::new("/home/fo")));
     }
 
+    #[test]
+    fn home_relative_path() {
+        let parse = |s| {
+            HomeRelativePath::parse(s, Path::new("/home/foo"))
+                .map(|path| path.0.display().to_string())
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(Ok(String::from(".cache/cargo")), parse("~/.cache/cargo"));
+        assert_eq!(
+            Ok(String::from(".cache/cargo")),
+            parse("/home/foo/.cache/cargo")
+        );
+        assert_eq!(Ok(String::from(".config")), parse(".config"));
+        assert!(parse("/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn metrics_address() {
+        let parse = |s: &str| {
+            MetricsAddress::from_str(s)
+                .map(|address| address.0.to_string())
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(Ok(String::from("127.0.0.1:9466")), parse("9466"));
+        assert_eq!(Ok(String::from("0.0.0.0:9466")), parse("0.0.0.0:9466"));
+        assert_eq!(Ok(String::from("[::1]:9466")), parse("[::1]:9466"));
+        assert!(parse("localhost").is_err());
+    }
+
+    #[test]
+    fn plugin_exit_code() {
+        use std::os::unix::process::ExitStatusExt;
+        let failed = |raw| anyhow!(PluginFailed(ExitStatus::from_raw(raw)));
+        assert_eq!(Some(3), super::plugin_exit_code(&failed(3 << 8)));
+        assert_eq!(Some(137), super::plugin_exit_code(&failed(9)));
+        assert_eq!(None, super::plugin_exit_code(&anyhow!("oops")));
+    }
+
     #[test]
     fn expand_home_prefix() {
         assert_eq!(
@@ -714,17 +2304,50 @@ mod tests {
     fn usage() {
         for cmd in [
             "",
+            "backup",
+            "cache",
+            "cache clear",
+            "cache list",
+            "code",
             "completions",
+            "config",
+            "config schema",
+            "diff",
+            "direnv",
+            "direnv hook",
             "enter",
             "exec",
+            "gc",
+            "history",
+            "generate",
+            "generate systemd",
             "list",
+            "metrics",
             "new",
             "package",
+            "package autoupdate",
+            "package import-dockerfile",
             "package list",
             "package update",
             "purge",
+            "repair",
             "reset",
+            "restore",
+            "scan",
+            "seccomp",
+            "seccomp install",
+            "show",
+            "ssh",
+            "ssh-config",
+            "start",
+            "status",
+            "stop",
+            "sync",
             "tmp",
+            "watch",
+            "worktree",
+            "worktree new",
+            "worktree prune",
         ] {
             let split_cmd = shlex::split(&format!("cub {cmd} --help")).unwrap();
             let err = Args::command()
@@ -868,4 +2491,50 @@ mod tests {
             "environment \"baz\" not found"
         );
     }
+
+    #[test]
+    fn for_each_environment() {
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| EnvironmentName::from_str(name).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let seen = std::sync::Mutex::new(Vec::new());
+        let all = names(&["a", "b", "c", "d", "e", "f"]);
+        let record = |name: &EnvironmentName| {
+            seen.lock().unwrap().push(name.clone());
+            Ok(())
+        };
+        super::for_each_environment("purge", all.clone(), PARALLEL_ENVIRONMENTS, record).unwrap();
+        assert_eq!(
+            all.iter().collect::<BTreeSet<_>>(),
+            seen.lock().unwrap().iter().collect::<BTreeSet<_>>()
+        );
+
+        // One job runs them in order.
+        seen.lock().unwrap().clear();
+        super::for_each_environment("purge", all.clone(), 1, record).unwrap();
+        assert_eq!(all, *seen.lock().unwrap());
+
+        let fail_some = |name: &EnvironmentName| match name.as_str() {
+            "b" | "e" => Err(anyhow!("environment {name} is broken")),
+            _ => Ok(()),
+        };
+        expect![[r#"environment "b" is broken"#]].assert_eq(
+            &super::for_each_environment("reset", names(&["a", "b", "c"]), 1, fail_some)
+                .unwrap_err()
+                .to_string(),
+        );
+        expect![[r#"failed to reset 2 of 6 environments: "b", "e""#]].assert_eq(
+            &super::for_each_environment(
+                "reset",
+                names(&["a", "b", "c", "d", "e", "f"]),
+                PARALLEL_ENVIRONMENTS,
+                fail_some,
+            )
+            .unwrap_err()
+            .to_string(),
+        );
+    }
 }
@@ -0,0 +1,197 @@
+//! Installs a job that runs `cub package autoupdate` periodically, for `cub
+//! package autoupdate --install-timer`.
+//!
+//! On macOS, this is a launchd agent. Elsewhere, it's a systemd user timer.
+
+use std::path::{Path, PathBuf};
+
+use super::systemd;
+use cubicle::hidden::{host_home_dir, run_command};
+use cubicle::somehow::{somehow as anyhow, Context, Result};
+
+/// How often launchd runs the job, in seconds. The systemd timer runs
+/// hourly too.
+const LAUNCHD_INTERVAL: u32 = 60 * 60;
+
+/// Installs and starts the job, replacing any earlier one for the same
+/// profile.
+pub fn install_timer(exe: &Path, config_path: &Path, profile: Option<&str>) -> Result<()> {
+    let args = systemd::cub_args(exe, config_path, profile)?;
+    let name = job_name(profile);
+    if cfg!(target_os = "macos") {
+        install_launchd(&name, &args)
+    } else {
+        install_systemd(&name, &args)
+    }
+}
+
+/// Returns the base name for the job's files. Each profile gets its own job,
+/// since profiles may use different packages.
+fn job_name(profile: Option<&str>) -> String {
+    let mut name = String::from("cubicle-package-autoupdate");
+    if let Some(profile) = profile {
+        name.push('-');
+        name.extend(profile.chars().map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        }));
+    }
+    name
+}
+
+fn install_systemd(name: &str, args: &[String]) -> Result<()> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(config) if !config.is_empty() => PathBuf::from(config),
+        _ => host_home_dir().join(".config"),
+    }
+    .join("systemd")
+    .join("user");
+    std::fs::create_dir_all(&dir).with_context(|| format!("failed to create directory {dir:?}"))?;
+    let (service, timer) = systemd::autoupdate_units(args);
+    let timer_name = format!("{name}.timer");
+    write_file(&dir.join(format!("{name}.service")), &service)?;
+    write_file(&dir.join(&timer_name), &timer)?;
+    run_command("systemctl", &["--user", "daemon-reload"])?;
+    run_command("systemctl", &["--user", "enable", "--now", &timer_name])?;
+    println!(
+        "Installed systemd user timer {timer_name} in {dir:?}. It runs \
+        `cub package autoupdate` hourly. Check it with `systemctl --user status {timer_name}`."
+    );
+    Ok(())
+}
+
+fn install_launchd(name: &str, args: &[String]) -> Result<()> {
+    let home = host_home_dir();
+    let dir = home.join("Library").join("LaunchAgents");
+    std::fs::create_dir_all(&dir).with_context(|| format!("failed to create directory {dir:?}"))?;
+    let log = home
+        .join("Library")
+        .join("Logs")
+        .join(format!("{name}.log"));
+    let path = dir.join(format!("{name}.plist"));
+    write_file(&path, &launchd_agent(name, args, &log)?)?;
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| anyhow!("path not valid UTF-8: {path:?}"))?;
+    // launchd refuses to load a job that's already loaded, so any earlier
+    // version is unloaded first. That fails harmlessly if there is none.
+    let _ = run_command("launchctl", &["unload", path_str]);
+    run_command("launchctl", &["load", "-w", path_str])?;
+    println!(
+        "Installed launchd agent {path:?}. It runs `cub package autoupdate` hourly \
+        and logs to {log:?}."
+    );
+    Ok(())
+}
+
+/// Returns a launchd property list for an agent that runs `cub package
+/// autoupdate`, with `cub` given by `args` (see [`systemd::cub_args`]).
+fn launchd_agent(label: &str, args: &[String], log: &Path) -> Result<String> {
+    let log = log
+        .to_str()
+        .ok_or_else(|| anyhow!("path not valid UTF-8: {log:?}"))?;
+    let args = args
+        .iter()
+        .map(String::as_str)
+        .chain(["package", "autoupdate"])
+        .map(|arg| format!("        <string>{}</string>\n", xml_escape(arg)))
+        .collect::<String>();
+    Ok(format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Installed by `cub package autoupdate --install-timer`. -->
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+{args}    </array>
+    <key>StartInterval</key>
+    <integer>{LAUNCHD_INTERVAL}</integer>
+    <key>ProcessType</key>
+    <string>Background</string>
+    <key>StandardOutPath</key>
+    <string>{log}</string>
+    <key>StandardErrorPath</key>
+    <string>{log}</string>
+</dict>
+</plist>
+"#,
+        label = xml_escape(label),
+        log = xml_escape(log),
+    ))
+}
+
+/// Escapes text for an XML element.
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<()> {
+    std::fs::write(path, contents).with_context(|| format!("failed to write {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+
+    #[test]
+    fn job_name() {
+        assert_eq!("cubicle-package-autoupdate", super::job_name(None));
+        assert_eq!(
+            "cubicle-package-autoupdate-my_work",
+            super::job_name(Some("my work"))
+        );
+    }
+
+    #[test]
+    fn launchd_agent() {
+        let args = [
+            String::from("/opt/cub"),
+            String::from("--config"),
+            String::from("a&b.toml"),
+        ];
+        expect![[r#"
+            <?xml version="1.0" encoding="UTF-8"?>
+            <!-- Installed by `cub package autoupdate --install-timer`. -->
+            <!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+            <plist version="1.0">
+            <dict>
+                <key>Label</key>
+                <string>cubicle-package-autoupdate</string>
+                <key>ProgramArguments</key>
+                <array>
+                    <string>/opt/cub</string>
+                    <string>--config</string>
+                    <string>a&amp;b.toml</string>
+                    <string>package</string>
+                    <string>autoupdate</string>
+                </array>
+                <key>StartInterval</key>
+                <integer>3600</integer>
+                <key>ProcessType</key>
+                <string>Background</string>
+                <key>StandardOutPath</key>
+                <string>/tmp/cub.log</string>
+                <key>StandardErrorPath</key>
+                <string>/tmp/cub.log</string>
+            </dict>
+            </plist>
+        "#]]
+        .assert_eq(
+            &super::launchd_agent(
+                "cubicle-package-autoupdate",
+                &args,
+                Path::new("/tmp/cub.log"),
+            )
+            .unwrap(),
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! Integration with direnv, for `cub direnv hook`.
+
+use std::io;
+use std::path::Path;
+
+use cubicle::somehow::{somehow as anyhow, Context, Result};
+
+/// Writes a direnv library that defines `use cubicle` for `.envrc` files.
+///
+/// The generated wrappers run `exe` with the given configuration file and
+/// profile, so they keep working when `cub` isn't on the `PATH` or uses a
+/// non-default configuration.
+pub fn write_hook<W: io::Write>(
+    w: &mut W,
+    exe: &Path,
+    config_path: &Path,
+    profile: Option<&str>,
+) -> Result<()> {
+    let utf8 = |path: &Path| -> Result<String> {
+        path.to_str()
+            .map(String::from)
+            .ok_or_else(|| anyhow!("path not valid UTF-8: {path:?}"))
+    };
+    let (exe, config_path) = (utf8(exe)?, utf8(config_path)?);
+    let mut args = vec![exe.as_str(), "--config", config_path.as_str()];
+    if let Some(profile) = profile {
+        args.extend(["--profile", profile]);
+    }
+    let cub = shlex::try_join(args).context("invalid path to run cub")?;
+    let cub = shlex::try_quote(&cub).context("invalid path to run cub")?;
+    write!(
+        w,
+        r#"# Cubicle library for direnv, generated by `cub direnv hook`.
+#
+# Load this from `~/.config/direnv/direnvrc`:
+#
+#   eval "$(cub direnv hook)"
+#
+# Then, in a project's `.envrc`:
+#
+#   use cubicle ENVIRONMENT [COMMAND...]
+#
+# This sets `CUBICLE_ENV` to the environment name and puts a wrapper for each
+# COMMAND on the `PATH` that runs the command inside the environment.
+use_cubicle() {{
+    local env=${{1:-}}
+    if [[ -z $env ]]; then
+        log_error "use cubicle: missing environment name"
+        return 1
+    fi
+    shift
+    local cub={cub}
+    local dir
+    dir="$(direnv_layout_dir)/cubicle"
+    rm -rf "$dir"
+    mkdir -p "$dir"
+    local command
+    for command in "$@"; do
+        if [[ $command == */* ]]; then
+            log_error "use cubicle: command name cannot contain '/': $command"
+            return 1
+        fi
+        printf '#!/usr/bin/env bash\nexec %s exec %q -- %q "$@"\n' \
+            "$cub" "$env" "$command" > "$dir/$command"
+        chmod +x "$dir/$command"
+    done
+    PATH_add "$dir"
+    export CUBICLE_ENV=$env
+}}
+"#
+    )
+    .context("failed to write direnv hook")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect_file;
+
+    #[test]
+    fn write_hook() {
+        let mut buf = Vec::new();
+        super::write_hook(
+            &mut buf,
+            Path::new("/opt/cub"),
+            Path::new("/home/me/my config.toml"),
+            None,
+        )
+        .unwrap();
+        expect_file!["../snapshots/cub__cli__direnv__tests__write_hook.snap"]
+            .assert_eq(&String::from_utf8(buf).unwrap());
+    }
+}
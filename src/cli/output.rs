@@ -0,0 +1,788 @@
+//! Renders the data returned by the library for the command-line program.
+
+use clap::ValueEnum;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::style::{elide, Color, Style};
+use cubicle::config::TimeFormat;
+use cubicle::daemon::Client;
+use cubicle::json::{
+    format_rfc3339, EnvironmentDebianPackages, EnvironmentList, EnvironmentShow, PackageList,
+    WatchEvent,
+};
+use cubicle::somehow::{Context, Result};
+use cubicle::{
+    Bytes, CacheDetails, Cubicle, DebianPackages, EnvPiece, EnvironmentDetails, EnvironmentName,
+    Event, FullPackageName, PackageDetails, PackageStatus, Phase, Refresh, RunnerSource, Timing,
+};
+
+/// Allowed formats for `cub list`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ListFormat {
+    /// Human-formatted table.
+    #[default]
+    Default,
+    /// Detailed JSON output for machine consumption.
+    Json,
+    /// Newline-delimited list of environment names only.
+    Names,
+}
+
+/// Allowed formats for `cub show`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ShowFormat {
+    /// Human-formatted text.
+    #[default]
+    Default,
+    /// Detailed JSON output for machine consumption.
+    Json,
+}
+
+/// Allowed formats for `cub package list`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ListPackagesFormat {
+    /// Human-formatted table.
+    #[default]
+    Default,
+    /// Detailed JSON output for machine consumption.
+    Json,
+    /// Newline-delimited list of package names only.
+    Names,
+}
+
+/// Prints progress updates from the library.
+pub fn print_event(event: &Event) {
+    println!("{event}");
+}
+
+/// Like [`print_event`] but prints to stderr.
+pub fn eprint_event(event: &Event) {
+    eprintln!("{event}");
+}
+
+/// Where `list`, `metrics`, `show`, `status`, and `package list` get their data: either a
+/// [`Cubicle`] instance or a running `cub daemon`.
+pub trait Source {
+    fn get_environment_names(&self) -> Result<BTreeSet<EnvironmentName>>;
+    fn get_environments(
+        &self,
+        refresh: Refresh,
+    ) -> Result<BTreeMap<EnvironmentName, EnvironmentDetails>>;
+    fn get_environment(&self, name: &EnvironmentName) -> Result<EnvironmentDetails>;
+    fn get_debian_packages(&self, name: &EnvironmentName) -> Result<DebianPackages>;
+    fn get_package_names(&self) -> Result<BTreeSet<FullPackageName>>;
+    fn get_packages(&self) -> Result<BTreeMap<FullPackageName, PackageDetails>>;
+    fn get_package_status(&self) -> Result<PackageStatus>;
+    fn metrics(&self) -> Result<String>;
+}
+
+macro_rules! impl_source {
+    ($type:ty) => {
+        impl Source for $type {
+            fn get_environment_names(&self) -> Result<BTreeSet<EnvironmentName>> {
+                <$type>::get_environment_names(self)
+            }
+            fn get_environments(
+                &self,
+                refresh: Refresh,
+            ) -> Result<BTreeMap<EnvironmentName, EnvironmentDetails>> {
+                <$type>::get_environments(self, refresh)
+            }
+            fn get_environment(&self, name: &EnvironmentName) -> Result<EnvironmentDetails> {
+                <$type>::get_environment(self, name)
+            }
+            fn get_debian_packages(&self, name: &EnvironmentName) -> Result<DebianPackages> {
+                <$type>::get_debian_packages(self, name)
+            }
+            fn get_package_names(&self) -> Result<BTreeSet<FullPackageName>> {
+                <$type>::get_package_names(self)
+            }
+            fn get_packages(&self) -> Result<BTreeMap<FullPackageName, PackageDetails>> {
+                <$type>::get_packages(self)
+            }
+            fn get_package_status(&self) -> Result<PackageStatus> {
+                <$type>::get_package_status(self)
+            }
+            fn metrics(&self) -> Result<String> {
+                <$type>::metrics(self)
+            }
+        }
+    };
+}
+
+impl_source!(Cubicle);
+impl_source!(Client);
+
+/// Prints the time spent in each phase, slowest first, to stderr for
+/// `--timings`.
+pub fn print_timings(timings: &[Timing], total: Duration) {
+    let mut phases: BTreeMap<&Phase, (Duration, usize)> = BTreeMap::new();
+    for timing in timings {
+        let (elapsed, count) = phases.entry(&timing.phase).or_default();
+        *elapsed += timing.elapsed;
+        *count += 1;
+    }
+    let mut phases = Vec::from_iter(phases);
+    phases.sort_by(|(_, (a, _)), (_, (b, _))| b.cmp(a));
+
+    eprintln!("Timings:");
+    for (phase, (elapsed, count)) in phases {
+        if count == 1 {
+            eprintln!("{:>10.2}s  {phase}", elapsed.as_secs_f64());
+        } else {
+            eprintln!("{:>10.2}s  {phase} ({count} times)", elapsed.as_secs_f64());
+        }
+    }
+    eprintln!("{:>10.2}s  total", total.as_secs_f64());
+}
+
+/// Corresponds to `cub list`.
+///
+/// Returns true if any of the disk usage shown was stale.
+pub fn list_environments(
+    program: &impl Source,
+    format: ListFormat,
+    refresh: Refresh,
+    style: Style,
+    time_format: TimeFormat,
+) -> Result<bool> {
+    let mut stale = false;
+    match format {
+        ListFormat::Names => {
+            for name in program.get_environment_names()? {
+                println!("{}", name.as_str());
+            }
+        }
+
+        ListFormat::Json => {
+            let envs = EnvironmentList::new(program.get_environments(refresh)?);
+            stale = envs.environments.values().any(|env| env.summary_stale);
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&envs)
+                    .context("failed to serialize JSON while listing environments")?
+            );
+        }
+
+        ListFormat::Default => {
+            let envs = program.get_environments(refresh)?;
+            stale = envs.values().any(|env| env.summary_stale);
+            let now = SystemTime::now();
+            let tw = time_width(time_format);
+            let dw = 11 + tw;
+            let quota = envs.values().any(|env| env.disk_quota.is_some());
+            // Long names are shortened to fit the rest of the table in the
+            // terminal. The quota column's width varies, so this assumes a
+            // typical one.
+            let nw = style.fit(
+                envs.keys()
+                    .map(|name| name.as_str().chars().count())
+                    .max()
+                    .unwrap_or(10),
+                6 + 2 * dw + if quota { 20 } else { 0 },
+                10,
+            );
+            println!(
+                "{:<nw$} | {:^dw$} | {:^dw$}{}",
+                "",
+                "home directory",
+                "work directory",
+                if quota { " |" } else { "" },
+            );
+            println!(
+                "{:<nw$} | {:>10} {:>tw$} | {:>10} {:>tw$}{}",
+                "name",
+                "size",
+                "modified",
+                "size",
+                "modified",
+                if quota { " | size/limit" } else { "" },
+            );
+            println!(
+                "{0:-<nw$} + {0:-<10} {0:-<tw$} + {0:-<10} {0:-<tw$}{1}",
+                "",
+                if quota { " + ----------" } else { "" },
+            );
+
+            for (name, env) in envs {
+                // Stale sizes are marked as approximate.
+                let size = |bytes| {
+                    format!(
+                        "{}{}",
+                        if env.summary_stale { "~" } else { "" },
+                        Bytes(bytes)
+                    )
+                };
+                let quota = match env.disk_quota {
+                    Some(limit) => format!(
+                        " | {}{}/{}",
+                        Bytes(env.home_dir_size + env.work_dir_size),
+                        if env.home_dir_du_error || env.work_dir_du_error {
+                            "+"
+                        } else {
+                            ""
+                        },
+                        Bytes(limit),
+                    ),
+                    None => String::new(),
+                };
+                let row = format!(
+                    "{:<nw$} | {:>9}{} {:>tw$} | {:>9}{} {:>tw$}{}",
+                    elide(name.as_str(), nw),
+                    size(env.home_dir_size),
+                    if env.home_dir_du_error { '+' } else { ' ' },
+                    table_time(env.home_dir_mtime, now, time_format),
+                    size(env.work_dir_size),
+                    if env.work_dir_du_error { '+' } else { ' ' },
+                    table_time(env.work_dir_mtime, now, time_format),
+                    quota,
+                );
+                // Running environments are shown in green.
+                println!("{}", style.paint(env.running.then_some(Color::Green), row));
+            }
+            if stale {
+                println!();
+                println!("Sizes marked with ~ are out of date and are being refreshed.");
+            }
+        }
+    }
+    Ok(stale)
+}
+
+/// Prints the given environments with the combined size of their home and
+/// work directories, before asking to confirm a destructive operation.
+pub fn print_environment_sizes(program: &Cubicle, names: &[EnvironmentName]) -> Result<()> {
+    let envs = program.get_environments(Refresh(false))?;
+    let nw = names
+        .iter()
+        .map(|name| name.as_str().len())
+        .max()
+        .unwrap_or(4);
+    for name in names {
+        let size = match envs.get(name) {
+            Some(env) => format!(
+                "{}{}{}",
+                if env.summary_stale { "~" } else { "" },
+                Bytes(env.home_dir_size + env.work_dir_size),
+                if env.home_dir_du_error || env.work_dir_du_error {
+                    "+"
+                } else {
+                    ""
+                },
+            ),
+            None => String::from("N/A"),
+        };
+        println!("  {:<nw$}  {:>11}", name.as_str(), size);
+    }
+    Ok(())
+}
+
+/// Corresponds to `cub show`.
+pub fn show_environment(
+    program: &impl Source,
+    name: &EnvironmentName,
+    format: ShowFormat,
+) -> Result<()> {
+    let env = program.get_environment(name)?;
+    match format {
+        ShowFormat::Json => {
+            let env = EnvironmentShow::new(name.clone(), env);
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&env)
+                    .context("failed to serialize JSON while showing environment")?
+            );
+        }
+
+        ShowFormat::Default => {
+            let now = SystemTime::now();
+            let dir = |path: &Option<PathBuf>, size, du_error, mtime: Option<SystemTime>| {
+                format!(
+                    "{} ({}{}, modified {})",
+                    match path {
+                        Some(path) => path.display().to_string(),
+                        None => String::from("N/A"),
+                    },
+                    Bytes(size),
+                    if du_error { "+" } else { "" },
+                    match mtime {
+                        Some(mtime) => format!("{} ago", rel_time(now.duration_since(mtime).ok())),
+                        None => String::from("N/A"),
+                    },
+                )
+            };
+            println!("name: {name}");
+            if let Some(golden) = &env.golden {
+                println!("golden environment: {golden}");
+            }
+            if let Some(shared) = &env.shared {
+                println!("owner: {}", shared.owner);
+                println!("shared with group: {}", shared.group);
+            }
+            println!(
+                "home directory: {}",
+                dir(
+                    &env.home_dir,
+                    env.home_dir_size,
+                    env.home_dir_du_error,
+                    env.home_dir_mtime
+                )
+            );
+            println!(
+                "work directory: {}",
+                dir(
+                    &env.work_dir,
+                    env.work_dir_size,
+                    env.work_dir_du_error,
+                    env.work_dir_mtime
+                )
+            );
+            match &env.networks {
+                Some(networks) if !networks.is_empty() => {
+                    println!("networks:");
+                    for (network, details) in networks {
+                        println!(
+                            "  {network}: {}",
+                            details.ip_address.as_deref().unwrap_or("N/A")
+                        );
+                    }
+                }
+                _ => println!("networks: N/A"),
+            }
+            if let Some(limit) = env.disk_quota {
+                println!(
+                    "disk quota: {} of {}",
+                    Bytes(env.home_dir_size + env.work_dir_size),
+                    Bytes(limit)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints the pieces of an environment, as part of `cub repair`.
+pub fn print_pieces(name: &EnvironmentName, pieces: &[EnvPiece]) {
+    println!("Environment {name}:");
+    for piece in pieces {
+        println!(
+            "  {} {}: {}",
+            piece.kind,
+            piece.location,
+            if piece.exists { "exists" } else { "missing" }
+        );
+    }
+}
+
+/// Corresponds to `cub show --debian-packages`.
+pub fn show_debian_packages(
+    program: &impl Source,
+    name: &EnvironmentName,
+    format: ShowFormat,
+) -> Result<()> {
+    let packages = program.get_debian_packages(name)?;
+    match format {
+        ShowFormat::Json => {
+            let packages = EnvironmentDebianPackages::new(name.clone(), packages);
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&packages)
+                    .context("failed to serialize JSON while showing Debian packages")?
+            );
+        }
+
+        ShowFormat::Default => {
+            let list = |packages: &BTreeSet<String>| {
+                if packages.is_empty() {
+                    String::from("none")
+                } else {
+                    packages
+                        .iter()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }
+            };
+            println!("requested: {}", list(&packages.requested));
+            match &packages.installed {
+                Some(installed) => {
+                    println!("installed: {}", list(installed));
+                    println!("installed but not requested: {}", list(&packages.extra));
+                    println!("requested but not installed: {}", list(&packages.missing));
+                }
+                None => println!("installed: unknown"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Corresponds to `cub diff`.
+pub fn print_environment_diff(
+    program: &Cubicle,
+    names: [&EnvironmentName; 2],
+    debian_packages: bool,
+    sizes: bool,
+) -> Result<()> {
+    let [a, b] = names;
+    println!("--- {a}");
+    println!("+++ {b}");
+
+    let packages = |name| -> Result<BTreeSet<String>> {
+        Ok(program
+            .get_environment_packages(name)?
+            .iter()
+            .map(|p| p.unquoted())
+            .collect())
+    };
+    print_set_diff("packages", &packages(a)?, &packages(b)?);
+
+    if debian_packages {
+        let (a, b) = (
+            program.get_debian_packages(a)?,
+            program.get_debian_packages(b)?,
+        );
+        match (&a.installed, &b.installed) {
+            (Some(a), Some(b)) => print_set_diff("installed Debian packages", a, b),
+            _ => print_set_diff("requested Debian packages", &a.requested, &b.requested),
+        }
+    }
+
+    if sizes {
+        let size = |name| -> Result<String> {
+            let env = program.get_environment(name)?;
+            Ok(format!(
+                "{}{}",
+                Bytes(env.home_dir_size),
+                if env.home_dir_du_error { "+" } else { "" }
+            ))
+        };
+        println!(
+            "home directory size: {} in {a}, {} in {b}",
+            size(a)?,
+            size(b)?
+        );
+    }
+    Ok(())
+}
+
+/// Prints a heading with the number of items in common, then the items only
+/// in `a` marked with `-` and the items only in `b` marked with `+`.
+fn print_set_diff(label: &str, a: &BTreeSet<String>, b: &BTreeSet<String>) {
+    println!("{label}: {} in common", a.intersection(b).count());
+    for item in a.difference(b) {
+        println!("- {item}");
+    }
+    for item in b.difference(a) {
+        println!("+ {item}");
+    }
+}
+
+/// Prints the output of `cub metrics`.
+pub fn print_metrics(program: &impl Source) -> Result<()> {
+    print!("{}", program.metrics()?);
+    Ok(())
+}
+
+/// Corresponds to `cub watch`.
+///
+/// Prints each change as a line of JSON, flushing after each so that other
+/// programs can react to it right away.
+pub fn print_file_events(
+    program: &Cubicle,
+    name: &EnvironmentName,
+    path: Option<&Path>,
+) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    program.watch_work_dir(name, path, &mut |event| {
+        let line = serde_json::to_string(&WatchEvent::new(event))
+            .context("failed to serialize JSON while watching files")?;
+        writeln!(stdout, "{line}")
+            .and_then(|()| stdout.flush())
+            .context("failed to write to stdout")
+    })
+}
+
+/// Prints the summary for `cub status`.
+///
+/// `env` is the environment that the shell is in or associated with, if
+/// any. The porcelain format is a single line, `STALE PENDING [ENV]`, so
+/// that shell prompts can parse it with `read -r stale pending env`.
+pub fn print_status(
+    program: &impl Source,
+    env: Option<&EnvironmentName>,
+    porcelain: bool,
+) -> Result<()> {
+    let status = program.get_package_status()?;
+    print!("{}", format_status(&status, env, porcelain));
+    Ok(())
+}
+
+fn format_status(status: &PackageStatus, env: Option<&EnvironmentName>, porcelain: bool) -> String {
+    if porcelain {
+        format!(
+            "{} {} {}\n",
+            status.stale,
+            status.pending,
+            env.map(|env| env.as_str()).unwrap_or_default()
+        )
+    } else {
+        format!(
+            "environment: {}\nstale packages: {}\npackages due for update: {}\n",
+            match env {
+                Some(env) => env.to_string(),
+                None => String::from("none"),
+            },
+            status.stale,
+            status.pending,
+        )
+    }
+}
+
+/// Corresponds to `cub doctor`.
+pub fn print_doctor(program: &Cubicle, config_path: &Path) -> Result<()> {
+    println!("configuration file: {config_path:?}");
+    let choice = program.runner_choice();
+    let how = match &choice.source {
+        RunnerSource::Config => String::from("set in the configuration file"),
+        RunnerSource::Probed(path) => {
+            format!("picked by `runner = \"auto\"` just now, recorded in {path:?}")
+        }
+        RunnerSource::Recorded(path) => format!(
+            "picked by `runner = \"auto\"` earlier, recorded in {path:?}; \
+            remove that file to pick again"
+        ),
+        _ => String::from("unknown"),
+    };
+    println!("runner: {} ({how})", choice.runner.as_str());
+    println!();
+    println!("runners on this host:");
+    for probe in program.probe_runners() {
+        println!(
+            "  {:<12} {:<4} {}",
+            probe.runner.as_str(),
+            if probe.usable { "yes" } else { "no" },
+            probe.reason
+        );
+    }
+    Ok(())
+}
+
+/// Corresponds to `cub cache list`.
+pub fn list_caches(program: &Cubicle) -> Result<()> {
+    let caches: Vec<CacheDetails> = program.list_caches()?;
+    let nw = caches
+        .iter()
+        .map(|c| c.name.len())
+        .max()
+        .unwrap_or(4)
+        .max(4);
+    let pw = caches
+        .iter()
+        .map(|c| {
+            c.env_path
+                .as_ref()
+                .map_or(3, |path| path.display().to_string().len())
+        })
+        .max()
+        .unwrap_or(4)
+        .max(4);
+    println!("{:<nw$}  {:<pw$}  {:>10}", "name", "path", "size");
+    println!("{0:-<nw$}  {0:-<pw$}  {0:-<10}", "");
+    for cache in caches {
+        println!(
+            "{:<nw$}  {:<pw$}  {:>10}",
+            cache.name,
+            match &cache.env_path {
+                Some(path) => path.display().to_string(),
+                None => String::from("N/A"),
+            },
+            match cache.host_path {
+                Some(_) => Bytes(cache.size).to_string(),
+                None => String::from("N/A"),
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Corresponds to `cub package list`.
+///
+/// Packages whose last build failed are shown in red, and packages edited
+/// since they were last built are shown in yellow.
+pub fn list_packages(
+    program: &impl Source,
+    format: ListPackagesFormat,
+    style: Style,
+    time_format: TimeFormat,
+) -> Result<()> {
+    use ListPackagesFormat::*;
+    match format {
+        Names => {
+            for name in program.get_package_names()? {
+                println!("{}", name.unquoted());
+            }
+        }
+
+        Json => {
+            let packages = PackageList::new(program.get_packages()?);
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&packages)
+                    .context("failed to serialize JSON while listing packages")?
+            );
+        }
+
+        Default => {
+            let packages = program.get_packages()?;
+            let names: Vec<String> = packages
+                .iter()
+                .map(|(full_name, details)| {
+                    if details.package_manager {
+                        format!("{}.*", full_name.unquoted())
+                    } else {
+                        full_name.unquoted()
+                    }
+                })
+                .collect();
+            let now = SystemTime::now();
+            let tw = time_width(time_format);
+            // Long names, then long origins, are shortened to fit the table
+            // in the terminal.
+            let rest = 2 + 10 + 2 + tw + 2 + tw + 2 + 8;
+            let ow = packages
+                .values()
+                .map(|p| p.origin.chars().count())
+                .max()
+                .unwrap_or(8);
+            let nw = style.fit(
+                names.iter().map(|s| s.chars().count()).max().unwrap_or(10),
+                2 + ow + rest,
+                10,
+            );
+            let ow = style.fit(ow, nw + 2 + rest, 8);
+            println!(
+                "{:<nw$}  {:<ow$}  {:>10}  {:>tw$}  {:>tw$}  {:>8}",
+                "name", "origin", "size", "built", "edited", "status"
+            );
+            println!(
+                "{0:-<nw$}  {0:-<ow$}  {0:-<10}  {0:-<tw$}  {0:-<tw$}  {0:-<8}",
+                ""
+            );
+            for (name, package) in names.iter().zip(packages.values()) {
+                let stale = matches!(
+                    (package.built, package.edited),
+                    (Some(built), Some(edited)) if edited > built
+                );
+                let color = if package.last_build_failed {
+                    Some(Color::Red)
+                } else if stale {
+                    Some(Color::Yellow)
+                } else {
+                    None
+                };
+                let row = format!(
+                    "{:<nw$}  {:<ow$}  {:>10}  {:>tw$}  {:>tw$}  {:>8}",
+                    elide(name, nw),
+                    elide(&package.origin, ow),
+                    match package.size {
+                        Some(size) => Bytes(size).to_string(),
+                        None => String::from("N/A"),
+                    },
+                    table_time(package.built, now, time_format),
+                    table_time(package.edited, now, time_format),
+                    if package.last_build_failed {
+                        "failed"
+                    } else {
+                        "ok"
+                    },
+                );
+                println!("{}", style.paint(color, row));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Formats a time for a table, or returns "N/A" if it's unknown.
+fn table_time(time: Option<SystemTime>, now: SystemTime, format: TimeFormat) -> String {
+    let Some(time) = time else {
+        return String::from("N/A");
+    };
+    match format {
+        TimeFormat::Relative => rel_time(now.duration_since(time).ok()),
+        TimeFormat::Iso => match time.duration_since(UNIX_EPOCH) {
+            // Fractional seconds would only widen the column.
+            Ok(since) => format_rfc3339(UNIX_EPOCH + Duration::from_secs(since.as_secs())),
+            Err(_) => format_rfc3339(time),
+        },
+        TimeFormat::Unix => match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs().to_string(),
+            Err(e) => format!("-{}", e.duration().as_secs()),
+        },
+    }
+}
+
+/// Returns the width of a table column holding times from [`table_time`].
+fn time_width(format: TimeFormat) -> usize {
+    match format {
+        TimeFormat::Relative => 13,
+        TimeFormat::Iso => 20,
+        TimeFormat::Unix => 10,
+    }
+}
+
+fn rel_time(duration: Option<Duration>) -> String {
+    let mut duration = match duration {
+        Some(duration) => duration.as_secs_f64(),
+        None => return String::from("N/A"),
+    };
+    duration /= 60.0;
+    if duration < 59.5 {
+        return format!("{duration:.0} minutes");
+    }
+    duration /= 60.0;
+    if duration < 23.5 {
+        return format!("{duration:.0} hours");
+    }
+    duration /= 24.0;
+    format!("{duration:.0} days")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn table_time() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_715_000_000);
+        let time = Some(now - Duration::from_millis(3 * 60 * 60 * 1000 + 500));
+        let format = |format| super::table_time(time, now, format);
+        assert_eq!("3 hours", format(TimeFormat::Relative));
+        assert_eq!("2024-05-06T09:53:19Z", format(TimeFormat::Iso));
+        assert_eq!("1714989199", format(TimeFormat::Unix));
+        assert_eq!("N/A", super::table_time(None, now, TimeFormat::Iso));
+    }
+
+    #[test]
+    fn format_status() {
+        let mut status = PackageStatus::default();
+        status.stale = 2;
+        status.pending = 1;
+        let env = EnvironmentName::from_str("foo").unwrap();
+        assert_eq!("2 1 foo\n", super::format_status(&status, Some(&env), true));
+        assert_eq!("2 1 \n", super::format_status(&status, None, true));
+        assert_eq!(
+            "environment: \"foo\"\nstale packages: 2\npackages due for update: 1\n",
+            super::format_status(&status, Some(&env), false)
+        );
+        assert_eq!(
+            "environment: none\nstale packages: 2\npackages due for update: 1\n",
+            super::format_status(&status, None, false)
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Adds color to the tables printed by the command-line program and fits
+//! them to the terminal's width.
+//!
+//! Cells are padded before they're colored, so the escape codes don't count
+//! toward the column widths.
+
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::io::{self, IsTerminal};
+
+/// Allowed values for `--color`.
+///
+/// The variants aren't documented with doc comments, which would make clap
+/// switch the top-level usage to its long form. `--color` explains them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// A color for a table row.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Color {
+    Green,
+    Yellow,
+    Red,
+}
+
+impl Color {
+    /// Returns the ANSI SGR code for the foreground color.
+    fn code(self) -> u8 {
+        match self {
+            Self::Red => 31,
+            Self::Green => 32,
+            Self::Yellow => 33,
+        }
+    }
+}
+
+/// Decides whether and how to color output, and how wide tables may be.
+#[derive(Clone, Copy, Debug)]
+pub struct Style {
+    enabled: bool,
+    /// The terminal's width in columns, if stdout is a terminal.
+    width: Option<usize>,
+}
+
+impl Style {
+    pub fn new(choice: ColorChoice) -> Self {
+        let enabled = match choice {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                io::stdout().is_terminal() && !no_color(std::env::var_os("NO_COLOR"))
+            }
+        };
+        Self {
+            enabled,
+            width: terminal_width(),
+        }
+    }
+
+    /// Lets tables grow past the terminal's width, for `--wide`.
+    pub fn wide(self, wide: bool) -> Self {
+        Self {
+            width: if wide { None } else { self.width },
+            ..self
+        }
+    }
+
+    /// Returns how wide a column that would like to be `wanted` characters
+    /// may be, so that the table fits in the terminal. `rest` is the width
+    /// of the table's other columns and separators. The column is never
+    /// narrowed below `min` characters.
+    pub fn fit(&self, wanted: usize, rest: usize, min: usize) -> usize {
+        match self.width {
+            Some(width) if wanted + rest > width => width.saturating_sub(rest).max(min).min(wanted),
+            _ => wanted,
+        }
+    }
+
+    /// Returns `text`, wrapped in escape codes for `color` if color is
+    /// enabled and `color` is set.
+    pub fn paint(&self, color: Option<Color>, text: impl Display) -> String {
+        match color {
+            Some(color) if self.enabled => format!("\x1b[{}m{text}\x1b[0m", color.code()),
+            _ => text.to_string(),
+        }
+    }
+}
+
+/// Returns the width of the terminal in columns, or `None` if stdout isn't a
+/// terminal.
+fn terminal_width() -> Option<usize> {
+    let stdout = io::stdout();
+    if !stdout.is_terminal() {
+        return None;
+    }
+    let size = rustix::termios::tcgetwinsize(stdout).ok()?;
+    (size.ws_col > 0).then_some(usize::from(size.ws_col))
+}
+
+/// Shortens `text` to at most `width` characters, ending it with `…` if
+/// anything was cut.
+pub fn elide(text: &str, width: usize) -> Cow<'_, str> {
+    if text.chars().count() <= width {
+        return Cow::Borrowed(text);
+    }
+    let mut short: String = text.chars().take(width.saturating_sub(1)).collect();
+    short.push('…');
+    Cow::Owned(short)
+}
+
+/// Returns true if the value of `NO_COLOR` asks for no color, meaning it's
+/// set and not empty. See <https://no-color.org/>.
+fn no_color(value: Option<std::ffi::OsString>) -> bool {
+    value.is_some_and(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+
+    #[test]
+    fn paint() {
+        let on = Style {
+            enabled: true,
+            width: None,
+        };
+        let off = Style {
+            enabled: false,
+            width: None,
+        };
+        assert_eq!(
+            "\x1b[31mfailed\x1b[0m",
+            on.paint(Some(Color::Red), "failed")
+        );
+        assert_eq!("ok", on.paint(None, "ok"));
+        assert_eq!("failed", off.paint(Some(Color::Red), "failed"));
+        assert_eq!(
+            "ok",
+            Style::new(ColorChoice::Never).paint(Some(Color::Green), "ok")
+        );
+        assert_eq!(
+            "\x1b[32mok\x1b[0m",
+            Style::new(ColorChoice::Always).paint(Some(Color::Green), "ok")
+        );
+    }
+
+    #[test]
+    fn fit() {
+        let style = Style {
+            enabled: false,
+            width: Some(80),
+        };
+        assert_eq!(20, style.fit(20, 50, 10));
+        assert_eq!(30, style.fit(40, 50, 10));
+        assert_eq!(10, style.fit(40, 75, 10));
+        assert_eq!(5, style.fit(5, 90, 10));
+        assert_eq!(40, style.wide(true).fit(40, 75, 10));
+    }
+
+    #[test]
+    fn elide() {
+        assert_eq!("short", super::elide("short", 5));
+        assert_eq!("long…", super::elide("longer", 5));
+        assert_eq!("né…", super::elide("néant", 3));
+    }
+
+    #[test]
+    fn no_color() {
+        assert!(!super::no_color(None));
+        assert!(!super::no_color(Some(OsString::new())));
+        assert!(super::no_color(Some(OsString::from("1"))));
+    }
+}
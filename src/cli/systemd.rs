@@ -0,0 +1,206 @@
+//! systemd user units, for `cub generate systemd` and `cub package
+//! autoupdate --install-timer`.
+
+use std::io;
+use std::path::Path;
+
+use cubicle::somehow::{somehow as anyhow, Context, Result};
+use cubicle::EnvironmentName;
+
+/// Writes a systemd user service that starts the environment at login and
+/// stops it at shutdown.
+///
+/// The unit runs `exe` with the given configuration file and profile, so it
+/// keeps working when `cub` isn't on systemd's `PATH` or uses a non-default
+/// configuration.
+pub fn write_unit<W: io::Write>(
+    w: &mut W,
+    exe: &Path,
+    config_path: &Path,
+    profile: Option<&str>,
+    name: &EnvironmentName,
+) -> Result<()> {
+    let cub = exec_line(&cub_args(exe, config_path, profile)?);
+    let name_arg = quote(name.as_str());
+    let shell_name = shlex::try_quote(name.as_str()).context("invalid environment name")?;
+    write!(
+        w,
+        r#"# systemd user unit for Cubicle environment {name},
+# generated by `cub generate systemd`.
+#
+# Install it with:
+#
+#   cub generate systemd {shell_name} > ~/.config/systemd/user/{unit}
+#   systemctl --user daemon-reload
+#   systemctl --user enable --now {unit}
+[Unit]
+Description=Cubicle environment {description}
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart={cub} start -- {name_arg}
+ExecStop={cub} stop -- {name_arg}
+
+[Install]
+WantedBy=default.target
+"#,
+        unit = unit_name(name),
+        description = escape_specifiers(name.as_str()),
+    )
+    .context("failed to write systemd unit")
+}
+
+/// Returns the arguments that run `exe` with the given configuration file
+/// and profile.
+pub fn cub_args(exe: &Path, config_path: &Path, profile: Option<&str>) -> Result<Vec<String>> {
+    let utf8 = |path: &Path| -> Result<String> {
+        path.to_str()
+            .map(String::from)
+            .ok_or_else(|| anyhow!("path not valid UTF-8: {path:?}"))
+    };
+    let mut args = vec![utf8(exe)?, String::from("--config"), utf8(config_path)?];
+    if let Some(profile) = profile {
+        args.extend([String::from("--profile"), profile.to_owned()]);
+    }
+    Ok(args)
+}
+
+/// Returns the service and timer units that run `cub package autoupdate`
+/// hourly, with `cub` given by `args` (see [`cub_args`]).
+pub fn autoupdate_units(args: &[String]) -> (String, String) {
+    let cub = exec_line(args);
+    let service = format!(
+        r#"# systemd user service that rebuilds stale Cubicle packages,
+# installed by `cub package autoupdate --install-timer`.
+[Unit]
+Description=Rebuild stale Cubicle packages
+
+[Service]
+Type=oneshot
+Nice=10
+ExecStart={cub} package autoupdate
+"#
+    );
+    let timer = String::from(
+        r#"# systemd user timer that rebuilds stale Cubicle packages,
+# installed by `cub package autoupdate --install-timer`.
+[Unit]
+Description=Rebuild stale Cubicle packages hourly
+
+[Timer]
+OnCalendar=hourly
+RandomizedDelaySec=10min
+Persistent=true
+
+[Install]
+WantedBy=timers.target
+"#,
+    );
+    (service, timer)
+}
+
+/// Returns the suggested filename for the environment's unit.
+pub fn unit_name(name: &EnvironmentName) -> String {
+    let escaped = name
+        .as_str()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!("cubicle-{escaped}.service")
+}
+
+/// Escapes `%` so that systemd doesn't expand it as a specifier.
+fn escape_specifiers(s: &str) -> String {
+    s.replace('%', "%%")
+}
+
+/// Quotes and joins words for a systemd `Exec*=` line.
+fn exec_line(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Quotes a word for a systemd `Exec*=` line.
+///
+/// systemd splits these lines on whitespace, understands C-style escapes
+/// within double quotes, and expands `%` specifiers and `$` variables.
+fn quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/' | ':' | '+'));
+    if plain {
+        return word.to_owned();
+    }
+    let mut quoted = String::from("\"");
+    for c in word.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '%' => quoted.push_str("%%"),
+            '$' => quoted.push_str("$$"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::{expect, expect_file};
+    use std::str::FromStr;
+
+    #[test]
+    fn write_unit() {
+        let mut buf = Vec::new();
+        super::write_unit(
+            &mut buf,
+            Path::new("/opt/cub"),
+            Path::new("/home/me/my config.toml"),
+            None,
+            &EnvironmentName::from_str("dev").unwrap(),
+        )
+        .unwrap();
+        expect_file!["../snapshots/cub__cli__systemd__tests__write_unit.snap"]
+            .assert_eq(&String::from_utf8(buf).unwrap());
+    }
+
+    #[test]
+    fn autoupdate_units() {
+        let args = cub_args(
+            Path::new("/opt/cub"),
+            Path::new("/home/me/cubicle.toml"),
+            Some("work"),
+        )
+        .unwrap();
+        let (service, timer) = super::autoupdate_units(&args);
+        assert!(service.contains(
+            "\nExecStart=/opt/cub --config /home/me/cubicle.toml --profile work package autoupdate\n"
+        ));
+        assert!(timer.contains("\nOnCalendar=hourly\n"));
+    }
+
+    #[test]
+    fn quote() {
+        expect![[r#"/opt/cub "my 100%% \"$$HOME\"""#]].assert_eq(&format!(
+            "{} {}",
+            super::quote("/opt/cub"),
+            super::quote(r#"my 100% "$HOME""#)
+        ));
+        assert_eq!(
+            "cubicle-a_b-c.service",
+            unit_name(&EnvironmentName::from_str("a b-c").unwrap())
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! Opens environments in Visual Studio Code, for `cub code`.
+
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+
+use super::command_ext::Command;
+use super::config::Code;
+use super::runner::CodeTarget;
+use super::HostPath;
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// Runs VS Code to open the target, without waiting for VS Code to exit.
+pub fn open(config: &Code, home: &HostPath, target: &CodeTarget) -> Result<()> {
+    let mut command = Command::new(&config.command);
+    match target {
+        CodeTarget::Container {
+            name,
+            user,
+            work_dir,
+        } => {
+            if let Some(dir) = name_configs_dir(&config.command, home) {
+                save_container_config(&dir, name, user, work_dir)?;
+            }
+            command
+                .arg("--folder-uri")
+                .arg(container_uri(name, work_dir)?);
+        }
+        CodeTarget::HostDir(dir) => match &config.remote {
+            Some(remote) => {
+                let dir = dir
+                    .as_host_raw()
+                    .to_str()
+                    .ok_or_else(|| anyhow!("path not valid UTF-8: {dir}"))?;
+                command
+                    .arg("--folder-uri")
+                    .arg(format!("vscode-remote://{remote}{dir}"));
+            }
+            None => {
+                command.arg(dir.as_host_raw());
+            }
+        },
+    }
+    command
+        .spawn_detached()
+        .with_context(|| format!("failed to run {:?}", config.command))
+}
+
+/// Returns the URI that VS Code's Dev Containers extension uses to attach
+/// to a running container and open a folder in it.
+fn container_uri(name: &str, work_dir: &Path) -> Result<String> {
+    let work_dir = work_dir
+        .to_str()
+        .ok_or_else(|| anyhow!("path not valid UTF-8: {work_dir:?}"))?;
+    let container = serde_json::json!({ "containerName": format!("/{name}") }).to_string();
+    let hex = container
+        .bytes()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    Ok(format!(
+        "vscode-remote://attached-container+{hex}{work_dir}"
+    ))
+}
+
+/// Returns the directory where the Dev Containers extension keeps its
+/// per-container settings, or `None` for unknown editors.
+fn name_configs_dir(command: &str, home: &HostPath) -> Option<HostPath> {
+    let product = match Path::new(command).file_name()?.to_str()? {
+        "code" => "Code",
+        "code-insiders" => "Code - Insiders",
+        _ => return None,
+    };
+    let config_dir = if cfg!(target_os = "macos") {
+        home.join("Library").join("Application Support")
+    } else {
+        match std::env::var("XDG_CONFIG_HOME") {
+            Ok(path) => HostPath::try_from(path).ok()?,
+            Err(_) => home.join(".config"),
+        }
+    };
+    Some(
+        config_dir
+            .join(product)
+            .join("User")
+            .join("globalStorage")
+            .join("ms-vscode-remote.remote-containers")
+            .join("nameConfigs"),
+    )
+}
+
+/// Records which folder to open and which user to run as in the
+/// container's settings.
+///
+/// The Dev Containers extension looks these up by container name, so they
+/// still apply after Cubicle starts a new container for the environment,
+/// including when it's reattached from VS Code's list of recent folders.
+/// Other settings in the file are kept.
+fn save_container_config(dir: &HostPath, name: &str, user: &str, work_dir: &Path) -> Result<()> {
+    let path = dir.join(format!("{name}.json"));
+    let mut settings = match std::fs::read(path.as_host_raw()) {
+        Ok(buf) => serde_json::from_slice::<Map<String, Value>>(&buf)
+            .with_context(|| format!("failed to parse {path}"))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Map::new(),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {path}")),
+    };
+    settings.insert(
+        String::from("workspaceFolder"),
+        Value::from(PathBuf::from(work_dir).to_string_lossy()),
+    );
+    settings.insert(String::from("remoteUser"), Value::from(user));
+    std::fs::create_dir_all(dir.as_host_raw())
+        .with_context(|| format!("failed to create directory {dir}"))?;
+    let buf = serde_json::to_vec_pretty(&settings)
+        .with_context(|| format!("failed to serialize {path}"))?;
+    std::fs::write(path.as_host_raw(), buf).with_context(|| format!("failed to write {path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+
+    #[test]
+    fn container_uri() {
+        expect!["vscode-remote://attached-container+7b22636f6e7461696e65724e616d65223a222f666f6f227d/home/w"]
+            .assert_eq(&super::container_uri("foo", Path::new("/home/w")).unwrap());
+    }
+
+    #[test]
+    fn save_container_config() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dir = HostPath::try_from(tmpdir.path().to_owned()).unwrap();
+        let path = tmpdir.path().join("foo.json");
+        std::fs::write(&path, r#"{"remoteUser": "root", "extensions": ["x"]}"#).unwrap();
+        super::save_container_config(&dir, "foo", "me", Path::new("/home/w")).unwrap();
+        expect![[r#"
+            {
+              "extensions": [
+                "x"
+              ],
+              "remoteUser": "me",
+              "workspaceFolder": "/home/w"
+            }"#]]
+        .assert_eq(&std::fs::read_to_string(path).unwrap());
+    }
+}
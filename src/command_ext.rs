@@ -1,9 +1,14 @@
 #![allow(clippy::disallowed_types)]
 use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Write;
 use std::process::{Child, Command as StdCommand};
 pub use std::process::{ChildStderr, ChildStdin, ChildStdout, ExitStatus, Output, Stdio};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
 
-use crate::somehow::{Context, Result};
+use super::cancel;
+use crate::somehow::{somehow as anyhow, warn_brief, Context, Error, ErrorKind, Result};
 
 #[must_use]
 pub struct ScopedChild {
@@ -38,6 +43,11 @@ impl ScopedChild {
             .with_context(|| format!("error waiting on child process {:?}", self.name))
     }
 
+    /// Lets the child process keep running after this is dropped.
+    fn detach(mut self) {
+        self.inner = None;
+    }
+
     pub fn wait_with_output(mut self) -> Result<Output> {
         self.inner
             .take()
@@ -58,6 +68,60 @@ impl Drop for ScopedChild {
     }
 }
 
+/// Retries failed operations with exponential backoff.
+///
+/// This should only wrap operations that are safe to repeat, such as
+/// commands that only query state.
+#[derive(Clone, Copy, Debug)]
+pub struct Retry {
+    /// How many times to retry after the first attempt fails.
+    pub retries: u32,
+    /// How long to wait before the first retry. This doubles after each
+    /// retry.
+    pub initial_delay: Duration,
+}
+
+impl Retry {
+    /// Calls `f` until it succeeds or the retries are exhausted, returning
+    /// the last error in the latter case. Errors that retrying won't fix are
+    /// returned right away; see [`is_transient`].
+    pub fn run<T>(&self, what: &str, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut delay = self.initial_delay;
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.retries && is_transient(&e) && !cancel::is_cancelled() => {
+                    attempt += 1;
+                    warn_brief(format!(
+                        "{what} failed, retrying in {delay:?} ({attempt} of {}): {e}",
+                        self.retries
+                    ));
+                    std::thread::sleep(delay);
+                    cancel::check()?;
+                    delay *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Returns false for errors that retrying won't fix, such as a missing
+/// program or an invalid argument.
+fn is_transient(e: &Error) -> bool {
+    if matches!(
+        e.kind(),
+        Some(ErrorKind::ProgramNotFound | ErrorKind::Interrupted | ErrorKind::InvalidConfig)
+    ) {
+        return false;
+    }
+    !matches!(
+        e.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+        Some(std::io::ErrorKind::InvalidInput | std::io::ErrorKind::PermissionDenied)
+    )
+}
+
 #[derive(Debug)]
 pub struct Command {
     inner: StdCommand,
@@ -77,16 +141,26 @@ impl Command {
     }
 
     pub fn scoped_spawn(&mut self) -> Result<ScopedChild> {
-        let child = self.inner.spawn().with_context(|| {
+        let message = |program: &OsStr| {
             format!(
-                "failed to spawn {:?} process ($PATH is {:?})",
-                self.inner.get_program(),
+                "failed to spawn {program:?} process ($PATH is {:?})",
                 match std::env::var_os("PATH") {
                     Some(path) => path,
                     None => OsString::from("not set"),
                 }
             )
-        })?;
+        };
+        let replaced = transcribe(&self.inner)?;
+        let child = match replaced.map_or_else(|| self.inner.spawn(), |mut cmd| cmd.spawn()) {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let message = message(self.inner.get_program());
+                return Err(e)
+                    .enough_context()
+                    .map_err(|e| e.kind_context(ErrorKind::ProgramNotFound, message));
+            }
+            Err(e) => return Err(e).with_context(|| message(self.inner.get_program())),
+        };
         Ok(ScopedChild::new(child, self.inner.get_program()))
     }
 
@@ -102,6 +176,16 @@ impl Command {
         child.wait_with_output()
     }
 
+    /// Starts the process with null stdio and doesn't wait for it to exit.
+    pub fn spawn_detached(&mut self) -> Result<()> {
+        self.inner
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        self.scoped_spawn()?.detach();
+        Ok(())
+    }
+
     pub fn status(&mut self) -> Result<ExitStatus> {
         let mut child = self.scoped_spawn()?;
         child.wait()
@@ -153,3 +237,205 @@ impl Command {
         self
     }
 }
+
+/// The environment variable naming a file to append a line to for each
+/// external command, before it runs. This makes changes to how commands like
+/// `docker` and `bwrap` are constructed reviewable. The lines include the
+/// names of the environment variables set for each command, but not their
+/// values, since those may be secrets.
+const RECORD_VAR: &str = "CUBICLE_RECORD_COMMANDS";
+
+/// The environment variable naming a file written with [`RECORD_VAR`].
+/// Instead of running external commands, Cubicle checks that each one
+/// matches the next line in the file and runs a stand-in that consumes its
+/// input and succeeds with no output. This lets tests check the commands
+/// without the backends that would run them.
+const REPLAY_VAR: &str = "CUBICLE_REPLAY_COMMANDS";
+
+enum Transcript {
+    Record(File),
+    Replay {
+        path: OsString,
+        lines: Vec<String>,
+        next: usize,
+    },
+}
+
+/// Returns the transcript from the environment variables, or an error
+/// message if it couldn't be opened.
+fn transcript() -> &'static std::result::Result<Option<Mutex<Transcript>>, String> {
+    static TRANSCRIPT: OnceLock<std::result::Result<Option<Mutex<Transcript>>, String>> =
+        OnceLock::new();
+    TRANSCRIPT.get_or_init(|| {
+        if let Some(path) = std::env::var_os(REPLAY_VAR) {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read {REPLAY_VAR} file {path:?}: {e}"))?;
+            let lines = contents.lines().map(String::from).collect();
+            return Ok(Some(Mutex::new(Transcript::Replay {
+                path,
+                lines,
+                next: 0,
+            })));
+        }
+        if let Some(path) = std::env::var_os(RECORD_VAR) {
+            let file = File::options()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| format!("failed to open {RECORD_VAR} file {path:?}: {e}"))?;
+            return Ok(Some(Mutex::new(Transcript::Record(file))));
+        }
+        Ok(None)
+    })
+}
+
+#[cfg(test)]
+thread_local! {
+    static CAPTURED: std::cell::RefCell<Option<Vec<String>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Runs `f`, replacing the external commands it runs on this thread with
+/// stand-ins as in replay mode. Returns the lines that would have been
+/// recorded for them.
+#[cfg(test)]
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let lines = CAPTURED.with(|captured| captured.borrow_mut().take().unwrap());
+    (result, lines)
+}
+
+/// Records or checks the command about to run, according to the transcript.
+/// Returns a stand-in to run instead, if any.
+fn transcribe(command: &StdCommand) -> Result<Option<StdCommand>> {
+    #[cfg(test)]
+    if let Some(()) = CAPTURED.with(|captured| {
+        captured
+            .borrow_mut()
+            .as_mut()
+            .map(|lines| lines.push(describe(command)))
+    }) {
+        return Ok(Some(stand_in()));
+    }
+
+    let transcript = match transcript() {
+        Ok(Some(transcript)) => transcript,
+        Ok(None) => return Ok(None),
+        Err(message) => return Err(anyhow!("{message}")),
+    };
+    let line = describe(command);
+    let mut transcript = transcript.lock().unwrap_or_else(|e| e.into_inner());
+    match &mut *transcript {
+        Transcript::Record(file) => {
+            writeln!(file, "{line}")
+                .with_context(|| format!("failed to write to {RECORD_VAR} file"))?;
+            Ok(None)
+        }
+        Transcript::Replay { path, lines, next } => {
+            let number = *next + 1;
+            match lines.get(*next) {
+                Some(expected) if *expected == line => {
+                    *next += 1;
+                    Ok(Some(stand_in()))
+                }
+                Some(expected) => Err(anyhow!(
+                    "command differs from line {number} of {path:?}:\n  \
+                    expected: {expected}\n  \
+                    actual:   {line}"
+                )),
+                None => Err(anyhow!(
+                    "command not in {path:?}, which has only {} lines: {line}",
+                    lines.len()
+                )),
+            }
+        }
+    }
+}
+
+/// Returns a command that consumes its input and exits successfully with no
+/// output, to run in place of a replayed command.
+fn stand_in() -> StdCommand {
+    let mut command = StdCommand::new("sh");
+    command
+        .args(["-c", "cat >/dev/null"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    command
+}
+
+/// Formats a command as a shell-like line, starting with the environment
+/// variables it sets. Their values are left out, since commands get secrets
+/// through their environment to keep them out of their arguments.
+fn describe(command: &StdCommand) -> String {
+    let quote = |s: &OsStr| {
+        let s = s.to_string_lossy();
+        match shlex::try_quote(&s) {
+            Ok(quoted) => quoted.into_owned(),
+            Err(_) => format!("{s:?}"),
+        }
+    };
+    let envs = command
+        .get_envs()
+        .filter(|(_, value)| value.is_some())
+        .map(|(key, _)| format!("{}=...", key.to_string_lossy()));
+    let words = std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(quote);
+    envs.chain(words).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture() {
+        let (status, lines) = super::capture(|| {
+            Command::new("docker")
+                .arg("exec")
+                .arg("my env")
+                .env("CUBICLE", "1")
+                .env("API_KEY", "hunter2")
+                .status()
+        });
+        assert!(status.unwrap().success());
+        assert_eq!(vec!["API_KEY=... CUBICLE=... docker exec 'my env'"], lines);
+
+        let (output, lines) = super::capture(|| Command::new("false").output());
+        let output = output.unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+        assert_eq!(vec!["false"], lines);
+    }
+
+    #[test]
+    fn retry() {
+        let retry = Retry {
+            retries: 2,
+            initial_delay: Duration::ZERO,
+        };
+        let attempts = |error: fn() -> Error| {
+            let mut attempts = 0;
+            let result = retry.run("test", || -> Result<()> {
+                attempts += 1;
+                Err(error())
+            });
+            assert!(result.is_err());
+            attempts
+        };
+        assert_eq!(3, attempts(|| anyhow!("connection reset")));
+        assert_eq!(
+            1,
+            attempts(|| Error::with_kind(ErrorKind::ProgramNotFound, "no docker"))
+        );
+        assert_eq!(
+            1,
+            attempts(|| {
+                Err::<(), _>(std::io::Error::from(std::io::ErrorKind::InvalidInput))
+                    .context("bad argument")
+                    .unwrap_err()
+            })
+        );
+    }
+}
@@ -1,7 +1,9 @@
 //! Main Cubicle program configuration.
 
 use regex::{Regex, RegexBuilder};
-use serde::{Deserialize, Deserializer};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::OnceLock;
@@ -10,14 +12,30 @@ use std::time::Duration;
 use super::os_util::host_home_dir;
 use super::HostPath;
 use super::RunnerKind;
-use crate::somehow::{somehow as anyhow, Context, LowLevelResult, Result};
+use crate::somehow::{somehow as anyhow, Context, Error, ErrorKind, LowLevelResult, Result};
 
 /// Main Cubicle program configuration, normally read from a `cubicle.toml`
 /// file.
-#[derive(Debug, Deserialize, Eq, PartialEq)]
+///
+/// String values in the file, including paths, may refer to environment
+/// variables as `${VAR}`, or as `${VAR:-default}` to use `default` when the
+/// variable is unset or empty. This lets a shared configuration file refer
+/// to each user's own directories:
+///
+/// ```toml
+/// [dirs]
+/// package_cache = '${COMPANY_CACHE:-/var/cache}/cubicle/${USER}/packages'
+/// ```
+///
+/// Write `$${` for a literal `${`. Referring to a variable that's unset,
+/// without a default, is an error. `${HOME}` always refers to the same home
+/// directory as a leading `~` in paths.
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
-    /// Which runner to use.
+    /// Which runner to use, or `"auto"` to pick one the first time Cubicle
+    /// runs. See `cub doctor` for how it was picked.
+    #[schemars(schema_with = "runner_schema")]
     pub runner: RunnerKind,
 
     /// Packages will be re-built when accessed if they haven't been built for
@@ -28,17 +46,183 @@ pub struct Config {
     /// Default: 12 hours.
     #[serde(
         default = "twelve_hours",
-        deserialize_with = "deserialize_opt_duration"
+        deserialize_with = "deserialize_opt_duration",
+        serialize_with = "serialize_opt_duration"
     )]
+    #[schemars(with = "String")]
     pub auto_update: Option<Duration>,
 
+    /// `cub gc` will stop environments that haven't been used for this
+    /// amount of time.
+    ///
+    /// Set to `"never"` in TOML or `None` in code to disable.
+    ///
+    /// Default: never.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_opt_duration",
+        serialize_with = "serialize_opt_duration"
+    )]
+    #[schemars(with = "String")]
+    pub auto_stop: Option<Duration>,
+
+    /// How long to wait for another `cub` process to finish with an
+    /// environment or package before giving up. Commands that create, reset,
+    /// or purge an environment, or that build a package, lock it first.
+    ///
+    /// Set to `"never"` in TOML or `None` in code to wait indefinitely. The
+    /// `--no-wait` command-line flag sets this to zero.
+    ///
+    /// Default: 10 minutes.
+    #[serde(
+        default = "ten_minutes",
+        deserialize_with = "deserialize_opt_duration",
+        serialize_with = "serialize_opt_duration"
+    )]
+    #[schemars(with = "String")]
+    pub lock_timeout: Option<Duration>,
+
+    /// Whether `cub purge` and `cub reset` ask for confirmation before
+    /// acting on more than one environment matched by wildcards. They list
+    /// the environments and their sizes first. If the standard input isn't a
+    /// terminal, they fail instead of asking. The `--yes` command-line flag
+    /// sets this to false.
+    ///
+    /// Default: true.
+    #[serde(default = "default_confirm_wildcards")]
+    pub confirm_wildcards: bool,
+
+    /// How `cub list` and `cub package list` show times in their tables.
+    /// The `--time-format` command-line flag overrides this.
+    ///
+    /// Default: `"relative"`.
+    #[serde(default)]
+    pub time_format: TimeFormat,
+
+    /// Limits how much disk space each environment's home and work
+    /// directories may use, combined.
+    ///
+    /// Cubicle checks this when listing environments and before re-seeding
+    /// an environment's home directory in `cub reset`. The Docker runner can
+    /// also pass the limit on to Docker; see `storage_opt_quota`.
+    ///
+    /// Default: no limit.
+    #[serde(default)]
+    pub disk_quota: Option<DiskQuota>,
+
+    /// Warns when less than this much space is free on a filesystem where
+    /// Cubicle keeps environments or packages. In TOML, this is a string
+    /// like `"5 GB"`; set it to `"0 B"` to turn off the warnings.
+    ///
+    /// Cubicle checks this in `cub list` and before creating an environment,
+    /// since running out of space while extracting packages into a home
+    /// directory can fail in confusing ways. For the Docker runner, this
+    /// includes Docker's data directory, if it's on this host.
+    ///
+    /// Default: 2 GB.
+    #[serde(
+        default = "default_min_free_space",
+        deserialize_with = "deserialize_size",
+        serialize_with = "serialize_size"
+    )]
+    #[schemars(with = "String")]
+    pub min_free_space: u64,
+
     /// Where to look for built-in package definitions.
     ///
     /// Default: use the current executable path to find the package directory
-    /// automatically.
+    /// automatically, or extract the package definitions embedded in the
+    /// executable if built with the `embed-packages` feature.
     #[serde(default, deserialize_with = "deserialize_opt_path")]
     pub builtin_package_dir: Option<PathBuf>,
 
+    /// Where to keep Cubicle's files on the host, overriding the locations
+    /// based on `$XDG_CACHE_HOME` and `$XDG_DATA_HOME`.
+    #[serde(default)]
+    pub dirs: Dirs,
+
+    /// Host-side scripts to run at points in an environment's lifecycle.
+    ///
+    /// Default: no hooks.
+    #[serde(default)]
+    pub hooks: Hooks,
+
+    /// Reports when long-running commands finish, so they can be left
+    /// unattended.
+    ///
+    /// Default: no reports.
+    #[serde(default)]
+    pub notify: Option<Notify>,
+
+    /// How `cub code` opens environments in Visual Studio Code.
+    #[serde(default)]
+    pub code: Code,
+
+    /// SSH access to environments, for `cub ssh` and `cub ssh-config`.
+    #[serde(default)]
+    pub ssh: Ssh,
+
+    /// Opening URLs and files on the host from inside environments.
+    #[serde(default)]
+    pub host_open: HostOpen,
+
+    /// How `cub tmp` names temporary environments.
+    #[serde(default)]
+    pub tmp: Tmp,
+
+    /// A dotfiles repository to seed new environments' home directories.
+    ///
+    /// Default: none.
+    #[serde(default)]
+    pub dotfiles: Option<Dotfiles>,
+
+    /// The shell to start for interactive sessions, like `"zsh"` or
+    /// `"/usr/bin/fish"`. A name without a slash is looked up in the
+    /// environment's `PATH`. `cub new --shell` overrides this for a single
+    /// environment. Commands given to `cub exec` still run through the
+    /// host's `$SHELL`.
+    ///
+    /// Default: the login shell from the environment user's passwd entry,
+    /// falling back to the host's `$SHELL` if that's missing or isn't
+    /// executable in the environment.
+    #[serde(default)]
+    pub shell: Option<String>,
+
+    /// Secrets to set as environment variables in interactive sessions and
+    /// commands, keyed by the variable names. See [`Secret`].
+    ///
+    /// Default: none.
+    #[serde(default)]
+    pub secrets: BTreeMap<String, Secret>,
+
+    /// Caches shared by all environments, keyed by name, with the path
+    /// inside each environment's home directory where the cache appears.
+    ///
+    /// Each cache is a single directory on the host, under Cubicle's cache
+    /// directory, so that package managers' downloads and build caches
+    /// aren't duplicated in every environment. New environments get a
+    /// symlink from the given path to the shared cache. Use `cub cache list`
+    /// and `cub cache clear` to manage the caches. The user runner doesn't
+    /// support caches.
+    ///
+    /// ```toml
+    /// [caches]
+    /// cargo-registry = '.cargo/registry'
+    /// pip = '.cache/pip'
+    /// npm = '.npm'
+    /// ```
+    ///
+    /// Default: none.
+    #[serde(default, deserialize_with = "deserialize_caches")]
+    pub caches: BTreeMap<String, PathBuf>,
+
+    /// A compiler cache shared by all environments, to speed up repeated
+    /// C, C++, and Rust builds. See [`CompilerCache`].
+    ///
+    /// Default: none.
+    #[serde(default)]
+    pub compiler_cache: Option<CompilerCache>,
+
     /// Configuration specific to the Bubblewrap runner. Set to `None` for
     /// other runners.
     #[serde(default)]
@@ -48,6 +232,43 @@ pub struct Config {
     /// for other runners.
     #[serde(default)]
     pub docker: Docker,
+
+    /// Configuration specific to the User runner.
+    #[serde(default)]
+    pub user: User,
+
+    /// The names of the profiles defined in the file.
+    ///
+    /// A profile is a table under `[profiles.NAME]` with any of the settings
+    /// above. When a profile is selected, its settings are merged over the
+    /// rest of the file: tables are merged key by key, and other values
+    /// (including arrays) replace the base values.
+    ///
+    /// ```toml
+    /// runner = 'bubblewrap'
+    ///
+    /// [bubblewrap]
+    /// seccomp = 'dangerously-disabled'
+    ///
+    /// [profiles.work]
+    /// runner = 'docker'
+    /// docker.network = 'corp-proxy'
+    /// ```
+    ///
+    /// Default: none.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_profile_names",
+        skip_serializing
+    )]
+    #[schemars(with = "BTreeMap<String, serde_json::Map<String, serde_json::Value>>")]
+    pub profiles: BTreeSet<String>,
+
+    /// The name of the profile that was merged into this configuration, if
+    /// any. This is set by [`Config::read_from_file_with_profile`], not read
+    /// from the file.
+    #[serde(skip)]
+    pub profile: Option<String>,
 }
 
 /// Configuration specific to the Bubblewrap runner.
@@ -55,17 +276,616 @@ pub struct Config {
 /// See the [Configuration](#configuration) section below for details.
 /// This documentation is included from `docs/Bubblewrap.md`.
 #[doc = include_str!("../docs/Bubblewrap.md")]
-#[derive(Debug, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 #[allow(missing_docs)]
+#[schemars(description = "Configuration specific to the Bubblewrap runner. \
+    See `docs/Bubblewrap.md` for details.")]
 pub struct Bubblewrap {
+    #[schemars(with = "String")]
     pub seccomp: PathOrDisabled,
+    #[serde(default)]
+    pub encryption_password_command: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "deserialize_bind_paths")]
+    pub ro_binds: Vec<PathBuf>,
+    #[serde(default, deserialize_with = "deserialize_bind_paths")]
+    pub rw_binds: Vec<PathBuf>,
+    #[serde(default)]
+    pub network: BubblewrapNetwork,
+    #[serde(default)]
+    pub user_namespace: Option<UserNamespace>,
+    #[serde(default)]
+    pub overlay_home: bool,
+    #[serde(default)]
+    pub dbus: BubblewrapDbus,
+    #[serde(default)]
+    pub dbus_talk: Vec<String>,
+}
+
+/// Runs Bubblewrap environments in their own user namespace, where they can
+/// see a different user identity than the host's.
+///
+/// See the [`Bubblewrap`] documentation for details.
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserNamespace {
+    /// The user ID that the environment sees, which maps to the host user's
+    /// ID. Defaults to the host user's ID.
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// The group ID that the environment sees, which maps to the host
+    /// user's primary group ID. Defaults to the host user's group ID.
+    #[serde(default)]
+    pub gid: Option<u32>,
+}
+
+/// What network access Bubblewrap environments have.
+///
+/// See the [`Bubblewrap`] documentation for details.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub enum BubblewrapNetwork {
+    /// Share the host's network namespace.
+    #[default]
+    #[serde(rename = "host")]
+    Host,
+    /// Give each environment an empty network namespace with only a loopback
+    /// interface.
+    #[serde(rename = "none")]
+    None,
+    /// Give each environment its own network namespace, connected to the
+    /// outside through slirp4netns for outbound connections only.
+    #[serde(rename = "slirp")]
+    Slirp,
+}
+
+/// Whether Bubblewrap environments can use the host's D-Bus session bus.
+///
+/// See the [`Bubblewrap`] documentation for details.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub enum BubblewrapDbus {
+    /// Don't give environments access to the session bus.
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    /// Give environments filtered access to the session bus through
+    /// xdg-dbus-proxy, allowing only the XDG desktop portals and the names
+    /// in `dbus_talk`.
+    #[serde(rename = "proxy")]
+    Proxy,
+    /// Give environments the host's session bus socket, unfiltered.
+    #[serde(rename = "host")]
+    Host,
+}
+
+/// Where to keep Cubicle's files on the host.
+///
+/// Each of these can be set independently, for example to keep work
+/// directories on a large data disk and caches on a fast one.
+///
+/// ```toml
+/// [dirs]
+/// package_cache = '/nvme/cubicle/packages'
+/// work_dirs = '/data/cubicle/work'
+/// ```
+#[derive(Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dirs {
+    /// Where to store built packages.
+    ///
+    /// Default: `$XDG_CACHE_HOME/cubicle/packages`.
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub package_cache: Option<PathBuf>,
+
+    /// Where the Bubblewrap runner, and the Docker runner with bind mounts,
+    /// keep environments' home directories.
+    ///
+    /// Default: `$XDG_CACHE_HOME/cubicle/home`.
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub home_dirs: Option<PathBuf>,
+
+    /// Where the Bubblewrap runner, and the Docker runner with bind mounts,
+    /// keep environments' work directories. The User runner keeps
+    /// temporary archives of work directories here while resetting
+    /// environments.
+    ///
+    /// Default: `$XDG_DATA_HOME/cubicle/work`.
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub work_dirs: Option<PathBuf>,
+
+    /// Where to keep the word list used to generate random environment
+    /// names.
+    ///
+    /// Default: `$XDG_CACHE_HOME/cubicle`.
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub word_list: Option<PathBuf>,
+}
+
+/// Host-side scripts that Cubicle runs at points in an environment's
+/// lifecycle.
+///
+/// Each hook is the path to an executable, which Cubicle runs on the host
+/// (outside the environment) with no arguments. It passes information about
+/// the environment in these environment variables:
+///
+/// - `CUBICLE_HOOK`: The name of the hook, like `on_create`.
+/// - `CUBICLE_ENV`: The name of the environment.
+/// - `CUBICLE_RUNNER`: The runner, as one of `bubblewrap`, `docker`, or
+///   `user`.
+/// - `CUBICLE_GOLDEN`: For instances of a golden environment, the name of the
+///   golden environment. This is unset for ordinary environments.
+///
+/// If a hook exits with a nonzero status, the `cub` command fails. Hooks only
+/// run for the commands listed here, not for package builder environments
+/// that Cubicle manages internally.
+#[derive(Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    /// Runs after `cub new` or `cub tmp` creates an environment.
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub on_create: Option<PathBuf>,
+
+    /// Runs before `cub enter` or `cub tmp` starts an interactive shell in
+    /// an environment.
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub on_enter: Option<PathBuf>,
+
+    /// Runs after `cub reset` resets an environment.
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub on_reset: Option<PathBuf>,
+
+    /// Runs after `cub purge` removes an environment.
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub on_purge: Option<PathBuf>,
+}
+
+/// Settings for reporting when long-running commands finish.
+///
+/// This applies to `cub new` (without `--enter`), `cub reset`, `cub purge`, `cub migrate`, `cub
+/// package update` (without `--watch`), and `cub package autoupdate`, when
+/// they take at least `min_duration`. For example:
+///
+/// ```toml
+/// [notify]
+/// desktop = true
+/// min_duration = "2m"
+/// ```
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Notify {
+    /// An executable to run on the host when a command finishes. Cubicle
+    /// runs it with no arguments and these environment variables:
+    ///
+    /// - `CUBICLE_COMMAND`: The command, like `package update`.
+    /// - `CUBICLE_STATUS`: `success` or `failure`.
+    /// - `CUBICLE_DURATION`: How long the command took, in whole seconds.
+    /// - `CUBICLE_ERROR`: The error message, if the command failed.
+    ///
+    /// Default: none.
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub command: Option<PathBuf>,
+
+    /// Whether to show a desktop notification, using `notify-send` on Linux
+    /// or `osascript` on macOS.
+    ///
+    /// Default: false.
+    #[serde(default)]
+    pub desktop: bool,
+
+    /// Commands that finish faster than this aren't reported.
+    ///
+    /// Default: 1 minute.
+    #[serde(
+        default = "one_minute",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    #[schemars(with = "String")]
+    pub min_duration: Duration,
+}
+
+fn one_minute() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// How `cub code` opens environments in Visual Studio Code.
+///
+/// With the Docker runner, `cub code` attaches VS Code to the environment's
+/// container (this needs the Dev Containers extension). With other runners,
+/// it opens the environment's work directory, either directly on the host or
+/// through `remote`.
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Code {
+    /// The VS Code executable to run.
+    ///
+    /// Default: `"code"`.
+    #[serde(default = "default_code_command")]
+    pub command: String,
+
+    /// A VS Code remote authority, like `"ssh-remote+localhost"`, through
+    /// which to open the work directory with the Bubblewrap and User runners.
+    /// This is ignored with the Docker runner.
+    ///
+    /// Default: open the work directory on the host directly.
+    #[serde(default)]
+    pub remote: Option<String>,
+}
+
+impl Default for Code {
+    fn default() -> Self {
+        Self {
+            command: default_code_command(),
+            remote: None,
+        }
+    }
+}
+
+fn default_code_command() -> String {
+    String::from("code")
+}
+
+/// SSH access to environments, for `cub ssh` and `cub ssh-config`.
+///
+/// Cubicle doesn't keep SSH servers running or listening on network ports.
+/// Instead, each SSH connection runs a new server inside the environment
+/// that talks to the client over a pipe, through the `ProxyCommand` option.
+/// The client authenticates with a key pair that Cubicle generates the first
+/// time it's needed.
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ssh {
+    /// Allows SSH connections to environments.
+    ///
+    /// With the Docker runner, this also installs the OpenSSH server in the
+    /// base image, so existing environments need a `cub reset` to use it.
+    /// With other runners, the OpenSSH server must be installed on the host.
+    ///
+    /// Default: false.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// The path to the OpenSSH server executable within environments.
+    ///
+    /// Default: `"/usr/sbin/sshd"`.
+    #[serde(default = "default_sshd")]
+    pub sshd: PathBuf,
+}
+
+impl Default for Ssh {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sshd: default_sshd(),
+        }
+    }
+}
+
+fn default_sshd() -> PathBuf {
+    PathBuf::from("/usr/sbin/sshd")
+}
+
+/// Opening URLs and files on the host from inside environments.
+///
+/// When enabled, the Bubblewrap and Docker runners make a named pipe
+/// available inside environments, and the `xdg-open` and `open` commands
+/// from the `host-open` package send requests through it. While `cub enter`,
+/// `cub exec`, or an SSH connection is running for an environment, Cubicle
+/// opens the requested `http` and `https` URLs on the host. For files within
+/// the environment's home directory, it opens a copy of the file on the
+/// host, kept until the environment is purged. Other URLs are refused.
+///
+/// With the Docker runner, existing environments' containers need to be
+/// restarted (for example, with `cub stop`) to get the pipe.
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostOpen {
+    /// Allows environments to open URLs and files on the host.
+    ///
+    /// Default: false.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// The program on the host that opens URLs and files.
+    ///
+    /// Default: `"open"` on macOS, `"xdg-open"` otherwise.
+    #[serde(default = "default_host_open_command")]
+    pub command: String,
+}
+
+impl Default for HostOpen {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            command: default_host_open_command(),
+        }
+    }
+}
+
+fn default_host_open_command() -> String {
+    if cfg!(target_os = "macos") {
+        String::from("open")
+    } else {
+        String::from("xdg-open")
+    }
+}
+
+/// How `cub tmp` names temporary environments.
+///
+/// Each name is the prefix followed by one or more random words joined with
+/// hyphens, like `tmp-acorn` or `tmp-acorn-ember`. For example:
+///
+/// ```toml
+/// [tmp]
+/// name_prefix = "{project}-{date}-"
+/// words = 2
+/// ```
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tmp {
+    /// The text before the random words. `{date}` is replaced with the
+    /// current date in UTC, like `2024-05-06`, and `{project}` is replaced
+    /// with the name of the current directory. `cub tmp --name-prefix`
+    /// overrides this.
+    ///
+    /// Default: `"tmp-"`.
+    #[serde(default = "default_tmp_name_prefix")]
+    pub name_prefix: String,
+
+    /// How many random words to use.
+    ///
+    /// Default: 1.
+    #[serde(
+        default = "default_tmp_words",
+        deserialize_with = "deserialize_tmp_words"
+    )]
+    pub words: u8,
+
+    /// A file of words to pick from, separated by whitespace or newlines.
+    /// Numbers (like dice rolls in a diceware list) are skipped.
+    ///
+    /// Default: the EFF short word list, which is downloaded into the
+    /// `dirs.word_list` directory when first needed.
+    #[serde(default, deserialize_with = "deserialize_opt_path")]
+    pub word_list: Option<PathBuf>,
+}
+
+impl Default for Tmp {
+    fn default() -> Self {
+        Self {
+            name_prefix: default_tmp_name_prefix(),
+            words: default_tmp_words(),
+            word_list: None,
+        }
+    }
+}
+
+fn default_tmp_name_prefix() -> String {
+    String::from("tmp-")
+}
+
+fn default_tmp_words() -> u8 {
+    1
+}
+
+fn deserialize_tmp_words<'de, D>(deserializer: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let words = u8::deserialize(deserializer)?;
+    if !(1..=8).contains(&words) {
+        return Err(serde::de::Error::custom(format!(
+            "invalid number of words {words}: expected 1 to 8"
+        )));
+    }
+    Ok(words)
+}
+
+/// A dotfiles repository to seed new environments' home directories.
+///
+/// When creating or resetting an environment, its initialization script
+/// clones the repository and applies it after unpacking the environment's
+/// packages, so the dotfiles take precedence over files from packages like
+/// `configs-core`. This runs inside the environment, so the repository must
+/// be reachable from there, and the environment needs `git` (and `stow` or
+/// `chezmoi` for those methods). If applying the dotfiles fails, Cubicle
+/// prints a warning and keeps the environment.
+///
+/// Package builder and test environments don't use the dotfiles, and
+/// instances of golden environments get them from the golden environment.
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dotfiles {
+    /// The repository's URL, as given to `git clone`.
+    pub repo: String,
+
+    /// The branch to check out.
+    ///
+    /// Default: the repository's default branch.
+    #[serde(default)]
+    pub branch: Option<String>,
+
+    /// How to apply the dotfiles to the home directory.
+    ///
+    /// Default: `"git"`.
+    #[serde(default)]
+    pub method: DotfilesMethod,
+}
+
+/// How to apply a dotfiles repository to a home directory.
+///
+/// See the [`Dotfiles`] documentation for details.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub enum DotfilesMethod {
+    /// Check out the repository directly into the home directory, keeping
+    /// the git directory in `~/.dotfiles`.
+    #[default]
+    #[serde(rename = "git")]
+    Git,
+    /// Clone the repository into `~/.dotfiles` and link each of its
+    /// top-level directories into the home directory with GNU Stow.
+    #[serde(rename = "stow")]
+    Stow,
+    /// Run `chezmoi init --apply` with the repository.
+    #[serde(rename = "chezmoi")]
+    Chezmoi,
+}
+
+impl DotfilesMethod {
+    /// Returns the name of the method as used in the configuration file.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Git => "git",
+            Self::Stow => "stow",
+            Self::Chezmoi => "chezmoi",
+        }
+    }
+}
+
+/// Settings for a compiler cache shared by all environments.
+///
+/// This adds a shared cache (see `caches`) named after the tool, which
+/// appears at the tool's default location, `~/.cache/ccache` or
+/// `~/.cache/sccache`. The initialization script also writes
+/// `~/.config/profile.d/50-compiler-cache.sh`, which sets up shells to build
+/// through the tool if it's installed. The `ccache` and `sccache` packages
+/// install the tools. For example:
+///
+/// ```toml
+/// [compiler_cache]
+/// tool = "sccache"
+/// max_size = "20G"
+/// ```
+///
+/// With ccache, C and C++ compilers are found through `/usr/lib/ccache`. With
+/// sccache, Cargo uses it as `RUSTC_WRAPPER`, and CMake uses it as the C and
+/// C++ compiler launcher.
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompilerCache {
+    /// Which compiler cache to use.
+    pub tool: CompilerCacheTool,
+
+    /// The most space the cache may use, like `"20G"`, as understood by the
+    /// tool.
+    ///
+    /// Default: the tool's own default.
+    #[serde(default)]
+    pub max_size: Option<String>,
+}
+
+/// A compiler cache tool. See [`CompilerCache`].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub enum CompilerCacheTool {
+    /// [ccache](https://ccache.dev/), for C and C++.
+    #[serde(rename = "ccache")]
+    Ccache,
+    /// [sccache](https://github.com/mozilla/sccache), for C, C++, and Rust.
+    #[serde(rename = "sccache")]
+    Sccache,
+}
+
+impl CompilerCacheTool {
+    /// Returns the name of the tool as used in the configuration file, which
+    /// is also the name of its shared cache.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ccache => "ccache",
+            Self::Sccache => "sccache",
+        }
+    }
+}
+
+/// How times are shown in tables. See [`Config::time_format`].
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub enum TimeFormat {
+    /// How long ago, rounded to minutes, hours, or days, like `3 hours`.
+    #[default]
+    #[serde(rename = "relative")]
+    Relative,
+    /// An RFC 3339 timestamp in UTC, like `2024-05-06T07:08:09Z`.
+    #[serde(rename = "iso")]
+    Iso,
+    /// Seconds since the Unix epoch.
+    #[serde(rename = "unix")]
+    Unix,
+}
+
+/// Where to get the value of a secret environment variable.
+///
+/// Cubicle reads secrets on the host each time `cub enter`, `cub exec`, or
+/// `cub tmp` starts a session, and passes them only to that session. They're
+/// never written into package seeds, home directories, or Docker images, and
+/// they aren't set for package builds, initialization scripts, or SSH
+/// connections. A single trailing newline is removed from the value.
+///
+/// For example:
+///
+/// ```toml
+/// [secrets]
+/// GITHUB_TOKEN = { command = ["pass", "show", "github-token"] }
+/// OPENAI_API_KEY = { file = "~/.config/openai/key" }
+/// ```
+///
+/// If a secret can't be read, the session doesn't start.
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub enum Secret {
+    /// Runs a program on the host with the given arguments and uses its
+    /// standard output.
+    #[serde(rename = "command")]
+    Command(Vec<String>),
+    /// Reads a file on the host. A leading `~` in the path refers to the
+    /// home directory.
+    #[serde(rename = "file", deserialize_with = "deserialize_path")]
+    File(PathBuf),
+}
+
+/// Per-environment disk quota.
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiskQuota {
+    /// The maximum size in bytes. In TOML, this is a string like `"500 MB"`
+    /// or `"20GiB"`.
+    #[serde(
+        deserialize_with = "deserialize_size",
+        serialize_with = "serialize_size"
+    )]
+    #[schemars(with = "String")]
+    pub size: u64,
+
+    /// If false, `cub reset` only warns when an environment is over quota.
+    /// If true, it refuses to re-seed such environments.
+    ///
+    /// Default: false.
+    #[serde(default)]
+    pub enforce: bool,
+}
+
+fn default_min_free_space() -> u64 {
+    2_000_000_000
 }
 
 fn twelve_hours() -> Option<Duration> {
     Some(Duration::from_secs(60 * 60 * 12))
 }
 
+fn default_confirm_wildcards() -> bool {
+    true
+}
+
+fn ten_minutes() -> Option<Duration> {
+    Some(Duration::from_secs(60 * 10))
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+    deserialize_opt_duration(deserializer)?
+        .ok_or_else(|| D::Error::custom("expected a duration, not `never`"))
+}
+
 fn deserialize_opt_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
 where
     D: Deserializer<'de>,
@@ -123,6 +943,98 @@ where
     }
 }
 
+fn serialize_duration<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&format!("{}s", duration.as_secs_f64()))
+}
+
+fn serialize_opt_duration<S>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match duration {
+        Some(duration) => serialize_duration(duration, serializer),
+        None => serializer.serialize_str("never"),
+    }
+}
+
+fn deserialize_size<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let s = String::deserialize(deserializer)?;
+
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        RegexBuilder::new(
+            r#"^(?x)
+            # integer or decimal
+            (?P<value>
+                [0-9]+
+                ( \. [0-9]+ )?
+            )
+            # optional space
+            \ ?
+            # required unit
+            (?P<unit>
+                B |
+                kB | KB | MB | GB | TB |
+                KiB | MiB | GiB | TiB
+            )
+            $"#,
+        )
+        .build()
+        .unwrap()
+    });
+
+    match re.captures(&s) {
+        Some(caps) => {
+            let value = caps.name("value").unwrap().as_str();
+            let value = f64::from_str(value).unwrap();
+            let unit = caps.name("unit").unwrap().as_str();
+            let multiple: f64 = match unit {
+                "B" => 1.0,
+                "kB" | "KB" => 1e3,
+                "MB" => 1e6,
+                "GB" => 1e9,
+                "TB" => 1e12,
+                "KiB" => 1024.0,
+                "MiB" => 1024.0 * 1024.0,
+                "GiB" => 1024.0 * 1024.0 * 1024.0,
+                "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+                _ => unreachable!(),
+            };
+            Ok((value * multiple).round() as u64)
+        }
+
+        None => Err(D::Error::custom(format!(
+            "could not parse {s:?}, expected size like `500 MB`, `20GiB`, `1.5 TB`"
+        ))),
+    }
+}
+
+/// Serializes a size in bytes as a string that [`deserialize_size`] reads
+/// back exactly, using the largest binary unit that divides it evenly.
+fn serialize_size<S>(size: &u64, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let (value, unit) = [
+        ("TiB", 1 << 40),
+        ("GiB", 1 << 30),
+        ("MiB", 1 << 20),
+        ("KiB", 1 << 10),
+    ]
+    .into_iter()
+    .find(|(_, multiple)| *size > 0 && size % multiple == 0)
+    .map_or((*size, "B"), |(unit, multiple)| (size / multiple, unit));
+    serializer.serialize_str(&format!("{value} {unit}"))
+}
+
 /// Like an `Option<PathBuf>` but more opinionated about recommending a path be
 /// set.
 #[derive(Debug, Deserialize, Eq, PartialEq)]
@@ -135,6 +1047,18 @@ pub enum PathOrDisabled {
     Path(PathBuf),
 }
 
+impl Serialize for PathOrDisabled {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Self::DangerouslyDisabled => serializer.serialize_str("dangerously-disabled"),
+            Self::Path(path) => path.serialize(serializer),
+        }
+    }
+}
+
 impl std::convert::From<String> for PathOrDisabled {
     fn from(s: String) -> Self {
         if s == "dangerously-disabled" {
@@ -145,18 +1069,78 @@ impl std::convert::From<String> for PathOrDisabled {
     }
 }
 
+/// Configuration specific to the User runner.
+///
+/// See the [Configuration](#configuration) section below for details.
+/// This documentation is included from `docs/User.md`.
+#[doc = include_str!("../docs/User.md")]
+#[derive(Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+#[allow(missing_docs)]
+#[schemars(description = "Configuration specific to the User runner. \
+    See `docs/User.md` for details.")]
+pub struct User {
+    #[serde(default)]
+    pub accounts: Option<UserAccounts>,
+    #[serde(default)]
+    pub isolation: UserIsolation,
+}
+
+/// How the User runner isolates sessions, beyond running them as the
+/// environment's user.
+///
+/// See the [`User`] documentation for details.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub enum UserIsolation {
+    /// Switch to the environment's user with `sudo` only.
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    /// Run each session as a transient systemd service with sandboxing
+    /// settings applied.
+    #[serde(rename = "systemd-run")]
+    SystemdRun,
+}
+
+/// Which tools the User runner uses to create and delete user accounts.
+///
+/// See the [`User`] documentation for details.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub enum UserAccounts {
+    /// Debian's `adduser` and `deluser`.
+    #[serde(rename = "adduser")]
+    Adduser,
+    /// `useradd` and `userdel`, as on Fedora, RHEL, and Arch Linux.
+    #[serde(rename = "useradd")]
+    Useradd,
+    /// `sysadminctl` and `dscl` on macOS.
+    #[serde(rename = "macos")]
+    Macos,
+}
+
 /// Configuration specific to the Docker runner.
 ///
 /// See the [Configuration](#configuration) section below for details.
 /// This documentation is included from `docs/Docker.md`.
 #[doc = include_str!("../docs/Docker.md")]
-#[derive(Debug, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 #[allow(missing_docs)]
+#[schemars(description = "Configuration specific to the Docker runner. \
+    See `docs/Docker.md` for details.")]
 pub struct Docker {
+    #[serde(default)]
+    pub apparmor: Option<String>,
+
     #[serde(default)]
     pub bind_mounts: bool,
 
+    #[serde(default)]
+    pub context: Option<String>,
+
+    #[serde(default)]
+    pub host: Option<String>,
+
     #[serde(default, deserialize_with = "deserialize_opt_path")]
     pub seccomp: Option<PathBuf>,
 
@@ -168,30 +1152,235 @@ pub struct Docker {
 
     #[serde(default)]
     pub locales: Vec<String>,
+
+    #[serde(default)]
+    pub network: Option<String>,
+
+    #[serde(default)]
+    pub environments: BTreeMap<String, DockerEnvironment>,
+
+    #[serde(default)]
+    #[schemars(with = "KeepAliveSetting")]
+    pub keep_alive: KeepAlive,
+
+    #[serde(default)]
+    pub selinux_relabel: Option<SelinuxRelabel>,
+
+    #[serde(default)]
+    pub storage_opt_quota: bool,
+
+    #[serde(default = "two")]
+    pub retries: u32,
+
+    #[serde(default)]
+    pub retry_builds: bool,
 }
 
 impl Default for Docker {
     fn default() -> Self {
         Self {
+            apparmor: None,
             bind_mounts: Default::default(),
+            context: None,
+            host: None,
             seccomp: None,
             strict_debian_packages: false,
             prefix: cub_dash(),
             locales: Vec::new(),
+            network: None,
+            environments: BTreeMap::new(),
+            keep_alive: KeepAlive::default(),
+            selinux_relabel: None,
+            storage_opt_quota: false,
+            retries: two(),
+            retry_builds: false,
+        }
+    }
+}
+
+/// What the Docker runner uses as the main process of each container, which
+/// keeps the container running.
+///
+/// See the [`Docker`] documentation for details.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "KeepAliveSetting")]
+pub enum KeepAlive {
+    /// Run `sleep infinity` under Docker's init process (`"sleep"`).
+    #[default]
+    Sleep,
+    /// Run `sleep infinity` under `tini` from the base image (`"tini"`).
+    Tini,
+    /// Run this command under Docker's init process.
+    Command(Vec<String>),
+}
+
+impl Serialize for KeepAlive {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Self::Sleep => serializer.serialize_str("sleep"),
+            Self::Tini => serializer.serialize_str("tini"),
+            Self::Command(command) => command.serialize(serializer),
+        }
+    }
+}
+
+/// Helper to deserialize [`KeepAlive`] from either a string or an array.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum KeepAliveSetting {
+    Name(String),
+    Command(Vec<String>),
+}
+
+impl TryFrom<KeepAliveSetting> for KeepAlive {
+    type Error = String;
+
+    fn try_from(setting: KeepAliveSetting) -> Result<Self, Self::Error> {
+        match setting {
+            KeepAliveSetting::Name(name) => match name.as_str() {
+                "sleep" => Ok(Self::Sleep),
+                "tini" => Ok(Self::Tini),
+                _ => Err(format!(
+                    "unknown keep-alive {name:?}, expected `sleep`, `tini`, or \
+                    an array of command arguments"
+                )),
+            },
+            KeepAliveSetting::Command(command) if command.is_empty() => {
+                Err(String::from("keep-alive command cannot be empty"))
+            }
+            KeepAliveSetting::Command(command) => Ok(Self::Command(command)),
         }
     }
 }
 
-fn cub_dash() -> String {
-    String::from("cub-")
+/// How the Docker runner asks Docker to relabel bind mounts for SELinux.
+///
+/// See the [`Docker`] documentation for details.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+pub enum SelinuxRelabel {
+    /// Label the content so that any container may use it (`:z`).
+    #[serde(rename = "shared")]
+    Shared,
+    /// Label the content so that only this container may use it (`:Z`).
+    #[serde(rename = "private")]
+    Private,
+}
+
+/// Docker runner settings that override the global [`Docker`] settings for a
+/// single environment.
+///
+/// See the [`Docker`] documentation for details.
+#[derive(Debug, Default, Deserialize, Eq, JsonSchema, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+#[allow(missing_docs)]
+pub struct DockerEnvironment {
+    #[serde(default)]
+    pub bind_mounts: Option<bool>,
+    #[serde(default)]
+    pub network: Option<String>,
+}
+
+/// Describes the names accepted for [`RunnerKind`], including its aliases.
+fn runner_schema(_gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+    schemars::schema::SchemaObject {
+        instance_type: Some(schemars::schema::InstanceType::String.into()),
+        enum_values: Some(
+            ["auto", "bubblewrap", "bwrap", "docker", "user", "users"]
+                .map(serde_json::Value::from)
+                .to_vec(),
+        ),
+        ..Default::default()
+    }
+    .into()
+}
+
+fn two() -> u32 {
+    2
+}
+
+fn cub_dash() -> String {
+    String::from("cub-")
+}
+
+fn deserialize_opt_path<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<PathBuf>::deserialize(deserializer)?
+        .map(|path| tilde_expand(path, host_home_dir())))
+}
+
+fn deserialize_path<'de, D>(deserializer: D) -> Result<PathBuf, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(tilde_expand(
+        PathBuf::deserialize(deserializer)?,
+        host_home_dir(),
+    ))
+}
+
+/// Deserializes a list of host paths to bind into environments at the same
+/// paths, which must be absolute.
+fn deserialize_bind_paths<'de, D>(deserializer: D) -> Result<Vec<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let paths = Vec::<PathBuf>::deserialize(deserializer)?
+        .into_iter()
+        .map(|path| tilde_expand(path, host_home_dir()))
+        .collect::<Vec<_>>();
+    check_bind_paths(&paths).map_err(serde::de::Error::custom)?;
+    Ok(paths)
 }
 
-fn deserialize_opt_path<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+/// Checks that the paths to bind into environments are absolute.
+fn check_bind_paths(paths: &[PathBuf]) -> Result<(), String> {
+    match paths.iter().find(|path| !path.is_absolute()) {
+        Some(path) => Err(format!("bind paths must be absolute, found {path:?}")),
+        None => Ok(()),
+    }
+}
+
+/// Deserializes the `caches` table. See [`check_caches`].
+fn deserialize_caches<'de, D>(deserializer: D) -> Result<BTreeMap<String, PathBuf>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    Ok(Option::<PathBuf>::deserialize(deserializer)?
-        .map(|path| tilde_expand(path, host_home_dir())))
+    let caches = BTreeMap::<String, PathBuf>::deserialize(deserializer)?;
+    check_caches(&caches).map_err(serde::de::Error::custom)?;
+    Ok(caches)
+}
+
+/// Checks that each cache name is usable as a filename and each path is
+/// relative to the home directory.
+fn check_caches(caches: &BTreeMap<String, PathBuf>) -> Result<(), String> {
+    for (name, path) in caches {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "invalid cache name {name:?}: expected ASCII letters, digits, \
+                hyphens, and underscores"
+            ));
+        }
+        let confined = path.components().count() > 0
+            && path
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)));
+        if !confined {
+            return Err(format!(
+                "invalid path for cache {name:?}: expected a path relative to the \
+                home directory, found {path:?}"
+            ));
+        }
+    }
+    Ok(())
 }
 
 fn tilde_expand(path: PathBuf, home: &HostPath) -> PathBuf {
@@ -202,34 +1391,323 @@ fn tilde_expand(path: PathBuf, home: &HostPath) -> PathBuf {
     }
 }
 
+fn deserialize_profile_names<'de, D>(deserializer: D) -> Result<BTreeSet<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        BTreeMap::<String, BTreeMap<String, serde::de::IgnoredAny>>::deserialize(deserializer)?
+            .into_keys()
+            .collect(),
+    )
+}
+
+/// Merges the values in `overlay` into `base`, recursively for tables.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Looks up an environment variable for [`interpolate_document`]. `HOME`
+/// comes from [`host_home_dir`], so that it agrees with [`tilde_expand`].
+fn lookup_env_var(name: &str) -> Option<String> {
+    if name == "HOME" {
+        return host_home_dir()
+            .as_host_raw()
+            .to_str()
+            .map(ToOwned::to_owned);
+    }
+    std::env::var(name).ok()
+}
+
+/// Expands environment variable references in every string value of a
+/// TOML document, keeping the rest of the document as written so that later
+/// parse errors point to the right lines.
+fn interpolate_document(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    fn interpolate_item(
+        item: &mut toml_edit::Item,
+        lookup: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<()> {
+        match item {
+            toml_edit::Item::None => Ok(()),
+            toml_edit::Item::Value(v) => interpolate_value(v, lookup),
+            toml_edit::Item::Table(table) => table
+                .iter_mut()
+                .try_for_each(|(_, v)| interpolate_item(v, lookup)),
+            toml_edit::Item::ArrayOfTables(tables) => tables.iter_mut().try_for_each(|table| {
+                table
+                    .iter_mut()
+                    .try_for_each(|(_, v)| interpolate_item(v, lookup))
+            }),
+        }
+    }
+
+    fn interpolate_value(
+        v: &mut toml_edit::Value,
+        lookup: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<()> {
+        match v {
+            toml_edit::Value::String(s) => {
+                let expanded = interpolate(s.value(), lookup)?;
+                if expanded != *s.value() {
+                    let decor = s.decor().clone();
+                    *s = toml_edit::Formatted::new(expanded);
+                    *s.decor_mut() = decor;
+                }
+                Ok(())
+            }
+            toml_edit::Value::Array(array) => array
+                .iter_mut()
+                .try_for_each(|v| interpolate_value(v, lookup)),
+            toml_edit::Value::InlineTable(table) => table
+                .iter_mut()
+                .try_for_each(|(_, v)| interpolate_value(v, lookup)),
+            _ => Ok(()),
+        }
+    }
+
+    // Only rewrite documents that might need it, so that the common case
+    // leaves the input untouched.
+    if !s.contains("${") {
+        return Ok(s.to_owned());
+    }
+    let mut doc = s
+        .parse::<toml_edit::DocumentMut>()
+        .context("failed to parse TOML")?;
+    interpolate_item(doc.as_item_mut(), lookup)?;
+    Ok(doc.to_string())
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` in `s`. See [`Config`].
+fn interpolate(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(after) = rest.strip_prefix("${") else {
+            expanded.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let Some(end) = after.find('}') else {
+            return Err(anyhow!("unterminated `${{` in {s:?}"));
+        };
+        let (name, default) = match after[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&after[..end], None),
+        };
+        if !is_env_var_name(name) {
+            return Err(anyhow!(
+                "invalid environment variable name {name:?} in {s:?}"
+            ));
+        }
+        let value = match default {
+            Some(default) => lookup(name)
+                .filter(|value| !value.is_empty())
+                .unwrap_or_else(|| default.to_owned()),
+            None => lookup(name).ok_or_else(|| {
+                anyhow!("environment variable ${name} is not set (used in {s:?})")
+            })?,
+        };
+        expanded.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Returns true if `name` can be used as an environment variable name in
+/// shells.
+fn is_env_var_name(name: &str) -> bool {
+    name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+}
+
 impl Config {
-    /// Parses and validates a TOML-formatted string into a Config.
-    fn from_str(s: &str) -> LowLevelResult<Self> {
-        let config: Self = toml::from_str(s)?;
+    /// Returns a configuration that uses the given runner, with every other
+    /// setting at the default it has when omitted from the configuration
+    /// file.
+    ///
+    /// Callers may change the public fields afterwards, then should call
+    /// [`Config::validate`]. For example, the Bubblewrap runner requires
+    /// [`Config::bubblewrap`] to be set.
+    pub fn new(runner: RunnerKind) -> Self {
+        let mut table = toml::Table::new();
+        table.insert(
+            String::from("runner"),
+            toml::Value::String(runner.as_str().to_owned()),
+        );
+        toml::Value::Table(table)
+            .try_into()
+            .expect("the default configuration should deserialize")
+    }
 
-        match config.runner {
+    /// Checks the rules that a configuration file must follow beyond its
+    /// syntax, such as the Bubblewrap runner requiring its settings.
+    ///
+    /// Configurations read from a file are already checked. This is for
+    /// configurations built or changed in code.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: String| Error::with_kind(ErrorKind::InvalidConfig, message);
+
+        match self.runner {
             RunnerKind::Bubblewrap => {
-                if config.bubblewrap.is_none() {
-                    return Err(anyhow!(
+                if self.bubblewrap.is_none() {
+                    return Err(invalid(String::from(
                         "Bubblewrap settings are required for that runner. \
-                        See `docs/Bubblewrap.md`."
-                    )
-                    .into());
+                        See `docs/Bubblewrap.md`.",
+                    )));
                 }
             }
             RunnerKind::Docker => {}
             RunnerKind::User => {}
+            RunnerKind::Auto => {}
+        }
+
+        if let Some(bubblewrap) = &self.bubblewrap {
+            check_bind_paths(&bubblewrap.ro_binds).map_err(invalid)?;
+            check_bind_paths(&bubblewrap.rw_binds).map_err(invalid)?;
+        }
+        check_caches(&self.caches).map_err(invalid)?;
+
+        for name in self.secrets.keys() {
+            if !is_env_var_name(name) {
+                return Err(invalid(format!(
+                    "invalid secret name {name:?}: expected an environment variable \
+                    name made of ASCII letters, digits, and underscores"
+                )));
+            }
+        }
+
+        if self.shell.as_deref() == Some("") {
+            return Err(invalid(String::from("`shell` may not be empty")));
+        }
+
+        Ok(())
+    }
+
+    /// Formats the configuration as the contents of a `cubicle.toml` file,
+    /// which reads back as an equal configuration.
+    ///
+    /// Every setting is written out, including defaults. Profiles aren't
+    /// written, since only their names are kept.
+    pub fn to_toml(&self) -> Result<String> {
+        /// Escapes `${` in string values so that reading the file doesn't
+        /// expand them as environment variables.
+        fn escape(value: &mut toml::Value) {
+            match value {
+                toml::Value::String(s) if s.contains("${") => *s = s.replace("${", "$${"),
+                toml::Value::Array(array) => array.iter_mut().for_each(escape),
+                toml::Value::Table(table) => table.iter_mut().for_each(|(_, value)| escape(value)),
+                _ => {}
+            }
         }
 
+        let mut value = toml::Value::try_from(self).context("failed to serialize configuration")?;
+        escape(&mut value);
+        toml::to_string(&value).context("failed to format configuration as TOML")
+    }
+
+    /// Parses and validates a TOML-formatted string into a Config.
+    #[cfg(test)]
+    pub(crate) fn from_str(s: &str) -> LowLevelResult<Self> {
+        Self::from_str_with_profile(s, None)
+    }
+
+    /// Parses a TOML-formatted string, merges the named profile over it if
+    /// given, and validates the result into a Config.
+    pub(crate) fn from_str_with_profile(s: &str, profile: Option<&str>) -> LowLevelResult<Self> {
+        let s = &interpolate_document(s, &lookup_env_var)?;
+        let config: Self = match profile {
+            // Parsing directly gives better error messages, with the
+            // locations of problems in the file.
+            None => toml::from_str(s)?,
+            Some(name) => {
+                let mut table: toml::Table = toml::from_str(s)?;
+                let mut profiles = match table.remove("profiles") {
+                    Some(toml::Value::Table(profiles)) => profiles,
+                    Some(_) => return Err(anyhow!("`profiles` must be a table").into()),
+                    None => toml::Table::new(),
+                };
+                let names: BTreeSet<String> = profiles.keys().cloned().collect();
+                let Some(overlay) = profiles.remove(name) else {
+                    return Err(anyhow!(
+                        "unknown profile {name:?} (defined profiles: {})",
+                        if names.is_empty() {
+                            String::from("none")
+                        } else {
+                            names.into_iter().collect::<Vec<_>>().join(", ")
+                        }
+                    )
+                    .into());
+                };
+                let toml::Value::Table(overlay) = overlay else {
+                    return Err(anyhow!("profile {name:?} must be a table").into());
+                };
+                merge_tables(&mut table, overlay);
+                let mut config: Self = toml::Value::Table(table).try_into()?;
+                config.profiles = names;
+                config.profile = Some(name.to_owned());
+                config
+            }
+        };
+
+        config.validate()?;
         Ok(config)
     }
 
+    /// Returns a JSON Schema describing the configuration file, for `cub
+    /// config schema`.
+    ///
+    /// Editors that support JSON Schema for TOML files can use this to check
+    /// `cubicle.toml` and complete its settings.
+    pub fn json_schema() -> serde_json::Value {
+        let mut schema = schemars::schema_for!(Config);
+        if let Some(metadata) = &mut schema.schema.metadata {
+            metadata.title = Some(String::from("Cubicle configuration (cubicle.toml)"));
+        }
+        serde_json::to_value(schema).expect("JSON Schema should serialize")
+    }
+
     /// Parses a TOML-formatted config file.
     pub fn read_from_file(path: &Path) -> Result<Self> {
+        Self::read_from_file_with_profile(path, None)
+    }
+
+    /// Parses a TOML-formatted config file, with the settings of the named
+    /// profile merged over the rest of the file if given. See
+    /// [`Config::profiles`].
+    pub fn read_from_file_with_profile(path: &Path, profile: Option<&str>) -> Result<Self> {
         let buf = std::fs::read_to_string(path)
-            .with_context(|| format!("Failed to read config file: {path:?}"))?;
-        Self::from_str(&buf)
-            .with_context(|| format!("Failed to parse/validate config file: {path:?}"))
+            .enough_context()
+            .map_err(|e| {
+                e.kind_context(
+                    ErrorKind::InvalidConfig,
+                    format!("Failed to read config file: {path:?}"),
+                )
+            })?;
+        Self::from_str_with_profile(&buf, profile)
+            .enough_context()
+            .map_err(|e| {
+                e.kind_context(
+                    ErrorKind::InvalidConfig,
+                    format!("Failed to parse/validate config file: {path:?}"),
+                )
+            })
     }
 }
 
@@ -271,6 +1749,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn deserialize_size() {
+        #[derive(Debug, Deserialize, PartialEq, Eq)]
+        struct Test {
+            #[serde(deserialize_with = "super::deserialize_size")]
+            value: u64,
+        }
+
+        for (input, expected) in [
+            ("10B", 10),
+            ("1.5 kB", 1_500),
+            ("500 MB", 500_000_000),
+            ("20GB", 20_000_000_000),
+            ("2 KiB", 2048),
+            ("1.5GiB", 1_610_612_736),
+        ] {
+            assert_eq!(
+                toml::from_str(&format!("value = '{input}'")),
+                Ok(Test { value: expected }),
+                "deserialize_size (left is actual, right is expected)"
+            );
+        }
+
+        assert!(toml::from_str::<Test>("value = '10 parsecs'").is_err());
+    }
+
     #[test]
     fn tilde_expand() {
         let home = HostPath::try_from(PathBuf::from("/home/foo")).unwrap();
@@ -284,6 +1788,15 @@ mod tests {
         assert_eq!(PathBuf::from("/~/~/baz"), expand("/~/~/baz"));
     }
 
+    #[test]
+    fn home_var_matches_tilde() {
+        let tilde = super::tilde_expand(PathBuf::from("~"), host_home_dir());
+        assert_eq!(
+            Some(tilde),
+            super::lookup_env_var("HOME").map(PathBuf::from)
+        );
+    }
+
     #[test]
     fn config_from_str_bad_runner() {
         assert_eq!(
@@ -312,7 +1825,7 @@ mod tests {
                   |          ^^^
                 unknown variant `q`, expected one of `Bubblewrap`, \
                 `bubblewrap`, `bwrap`, `Docker`, `docker`, `User`, `Users`, \
-                `user`, `users`
+                `user`, `users`, `Auto`, `auto`
             "},
             Config::from_str("runner = 'q'")
                 .enough_context()
@@ -342,14 +1855,69 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn config_from_str_bad_secret_name() {
+        assert_eq!(
+            "invalid secret name \"API-KEY\": expected an environment variable \
+            name made of ASCII letters, digits, and underscores",
+            Config::from_str(
+                "
+                runner = 'user'
+                [secrets]
+                API-KEY = { file = 'key' }
+                "
+            )
+            .enough_context()
+            .unwrap_err()
+            .to_string()
+        );
+        assert!(is_env_var_name("_A1"));
+        assert!(!is_env_var_name("1A"));
+        assert!(!is_env_var_name(""));
+    }
+
+    #[test]
+    fn config_from_str_bad_caches() {
+        let error = |caches: &str| {
+            Config::from_str(&format!("runner = 'user'\n[caches]\n{caches}"))
+                .enough_context()
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error("'a/b' = '.cache/ab'").contains("invalid cache name \"a/b\""));
+        assert!(error("pip = '/root/.cache/pip'").contains("invalid path for cache \"pip\""));
+        assert!(error("pip = '../.cache/pip'").contains("invalid path for cache \"pip\""));
+    }
+
     #[test]
     fn config_from_str_ok() {
         let expected = Config {
             runner: RunnerKind::Docker,
             auto_update: twelve_hours(),
+            auto_stop: None,
+            lock_timeout: ten_minutes(),
+            confirm_wildcards: true,
+            time_format: TimeFormat::Relative,
+            disk_quota: None,
+            min_free_space: 2_000_000_000,
             builtin_package_dir: None,
+            dirs: Dirs::default(),
+            hooks: Hooks::default(),
+            notify: None,
+            code: Code::default(),
+            ssh: Ssh::default(),
+            host_open: HostOpen::default(),
+            tmp: Tmp::default(),
+            dotfiles: None,
+            shell: None,
+            secrets: BTreeMap::new(),
+            caches: BTreeMap::new(),
+            compiler_cache: None,
             bubblewrap: None,
             docker: Docker::default(),
+            user: User::default(),
+            profiles: BTreeSet::new(),
+            profile: None,
         };
         assert_eq!(
             expected,
@@ -368,6 +1936,92 @@ mod tests {
             .enough_context()
             .unwrap()
         );
+        assert_eq!(expected, Config::new(RunnerKind::Docker));
+    }
+
+    #[test]
+    fn validate() {
+        let error = Config::new(RunnerKind::Bubblewrap).validate().unwrap_err();
+        assert_eq!(Some(ErrorKind::InvalidConfig), error.kind());
+        assert_eq!(
+            "Bubblewrap settings are required for that runner. See `docs/Bubblewrap.md`.",
+            error.to_string()
+        );
+
+        let mut config = Config::new(RunnerKind::User);
+        config.validate().unwrap();
+        config
+            .caches
+            .insert(String::from("npm"), PathBuf::from("/root/.npm"));
+        assert_eq!(
+            "invalid path for cache \"npm\": expected a path relative to the home \
+            directory, found \"/root/.npm\"",
+            config.validate().unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn to_toml() {
+        let config = Config::from_str(
+            "
+            runner = 'bubblewrap'
+            auto_update = '1.5m'
+            lock_timeout = 'never'
+            min_free_space = '5 GB'
+
+            [disk_quota]
+            size = '20GiB'
+
+            [notify]
+            min_duration = '2h'
+
+            [tmp]
+            name_prefix = '$${HOME}-'
+
+            [secrets]
+            API_KEY = { file = '/keys/api' }
+            GITHUB_TOKEN = { command = ['pass', 'show', 'github'] }
+
+            [caches]
+            pip = '.cache/pip'
+
+            [bubblewrap]
+            seccomp = 'dangerously-disabled'
+            ro_binds = ['/nix']
+
+            [docker]
+            keep_alive = ['my-init', '--forever']
+
+            [docker.environments.db-client]
+            network = 'dbnet'
+            ",
+        )
+        .enough_context()
+        .unwrap();
+        assert_eq!("${HOME}-", config.tmp.name_prefix);
+
+        let toml = config.to_toml().unwrap();
+        for line in [
+            "runner = \"bubblewrap\"",
+            "auto_update = \"90s\"",
+            "lock_timeout = \"never\"",
+            "min_free_space = \"5000000000 B\"",
+            "size = \"20 GiB\"",
+            "name_prefix = \"$${HOME}-\"",
+            "seccomp = \"dangerously-disabled\"",
+            "keep_alive = [\"my-init\", \"--forever\"]",
+        ] {
+            assert!(toml.lines().any(|l| l == line), "{line:?} not in:\n{toml}");
+        }
+        assert_eq!(config, Config::from_str(&toml).enough_context().unwrap());
+
+        let config = Config::new(RunnerKind::Docker);
+        assert_eq!(
+            config,
+            Config::from_str(&config.to_toml().unwrap())
+                .enough_context()
+                .unwrap()
+        );
     }
 
     #[test]
@@ -376,33 +2030,232 @@ mod tests {
             Config {
                 runner: RunnerKind::Docker,
                 auto_update: Some(Duration::from_secs(60 * 60 * 24 * 10)),
+                auto_stop: Some(Duration::from_secs(60 * 60 * 2)),
+                lock_timeout: None,
+                confirm_wildcards: false,
+                time_format: TimeFormat::Iso,
+                disk_quota: Some(DiskQuota {
+                    size: 20_000_000_000,
+                    enforce: true,
+                }),
+                min_free_space: 5_000_000_000,
                 builtin_package_dir: Some(PathBuf::from("/usr/local/share/cubicle/packages")),
+                dirs: Dirs {
+                    package_cache: Some(PathBuf::from("/nvme/cubicle/packages")),
+                    home_dirs: None,
+                    work_dirs: Some(host_home_dir().as_host_raw().join("data/work")),
+                    word_list: None,
+                },
+                hooks: Hooks {
+                    on_create: Some(PathBuf::from("/usr/local/bin/cub-created")),
+                    on_enter: Some(PathBuf::from("/usr/local/bin/vpn-up")),
+                    on_reset: None,
+                    on_purge: Some(PathBuf::from("/usr/local/bin/cub-purged")),
+                },
+                notify: Some(Notify {
+                    command: Some(PathBuf::from("/usr/local/bin/cub-done")),
+                    desktop: true,
+                    min_duration: Duration::from_secs(30),
+                }),
+                code: Code {
+                    command: String::from("codium"),
+                    remote: Some(String::from("ssh-remote+localhost")),
+                },
+                ssh: Ssh {
+                    enabled: true,
+                    sshd: PathBuf::from("/usr/local/sbin/sshd"),
+                },
+                host_open: HostOpen {
+                    enabled: true,
+                    command: String::from("firefox"),
+                },
+                tmp: Tmp {
+                    name_prefix: String::from("{project}-"),
+                    words: 2,
+                    word_list: Some(PathBuf::from("/usr/share/dict/words")),
+                },
+                dotfiles: Some(Dotfiles {
+                    repo: String::from("https://example.org/me/dotfiles.git"),
+                    branch: Some(String::from("cubicle")),
+                    method: DotfilesMethod::Stow,
+                }),
+                shell: Some(String::from("zsh")),
+                secrets: BTreeMap::from([
+                    (
+                        String::from("API_KEY"),
+                        Secret::File(host_home_dir().as_host_raw().join("api-key")),
+                    ),
+                    (
+                        String::from("GITHUB_TOKEN"),
+                        Secret::Command(vec![
+                            String::from("pass"),
+                            String::from("show"),
+                            String::from("github"),
+                        ]),
+                    ),
+                ]),
+                caches: BTreeMap::from([
+                    (
+                        String::from("cargo-registry"),
+                        PathBuf::from(".cargo/registry")
+                    ),
+                    (String::from("pip"), PathBuf::from(".cache/pip")),
+                ]),
+                compiler_cache: Some(CompilerCache {
+                    tool: CompilerCacheTool::Sccache,
+                    max_size: Some(String::from("20G")),
+                }),
                 bubblewrap: Some(Bubblewrap {
                     seccomp: PathOrDisabled::Path(PathBuf::from("/tmp/seccomp.bpf")),
+                    encryption_password_command: Some(vec![
+                        String::from("pass"),
+                        String::from("show"),
+                        String::from("cubicle"),
+                    ]),
+                    ro_binds: vec![
+                        PathBuf::from("/nix"),
+                        host_home_dir().as_host_raw().join(".local/toolchains"),
+                    ],
+                    rw_binds: vec![PathBuf::from("/var/cache/ccache")],
+                    network: BubblewrapNetwork::Slirp,
+                    user_namespace: Some(UserNamespace {
+                        uid: Some(1000),
+                        gid: Some(100),
+                    }),
+                    overlay_home: true,
+                    dbus: BubblewrapDbus::Proxy,
+                    dbus_talk: vec![String::from("org.freedesktop.Notifications")],
                 }),
                 docker: Docker {
+                    apparmor: Some(String::from("cubicle-profile")),
                     bind_mounts: true,
+                    context: Some(String::from("colima")),
+                    host: None,
                     locales: vec![String::from("eo"), String::from("tg_TJ.UTF-8")],
                     prefix: String::from("p"),
                     seccomp: Some(PathBuf::from("/etc/seccomp.json")),
                     strict_debian_packages: true,
+                    network: Some(String::from("devnet")),
+                    environments: BTreeMap::from([(
+                        String::from("db-client"),
+                        DockerEnvironment {
+                            bind_mounts: Some(false),
+                            network: Some(String::from("dbnet")),
+                        },
+                    )]),
+                    keep_alive: KeepAlive::Command(vec![
+                        String::from("my-init"),
+                        String::from("--forever"),
+                    ]),
+                    selinux_relabel: Some(SelinuxRelabel::Private),
+                    storage_opt_quota: true,
+                    retries: 5,
+                    retry_builds: true,
                 },
+                user: User {
+                    accounts: Some(UserAccounts::Useradd),
+                    isolation: UserIsolation::SystemdRun,
+                },
+                profiles: BTreeSet::new(),
+                profile: None,
             },
             Config::from_str(
                 "
                 runner = 'docker'
                 auto_update = '10d'
+                auto_stop = '2h'
+                lock_timeout = 'never'
+                confirm_wildcards = false
+                time_format = 'iso'
+                min_free_space = '5 GB'
                 builtin_package_dir = '/usr/local/share/cubicle/packages'
+                shell = 'zsh'
+
+                [disk_quota]
+                size = '20 GB'
+                enforce = true
+
+                [dirs]
+                package_cache = '/nvme/cubicle/packages'
+                work_dirs = '~/data/work'
+
+                [hooks]
+                on_create = '/usr/local/bin/cub-created'
+                on_enter = '/usr/local/bin/vpn-up'
+                on_purge = '/usr/local/bin/cub-purged'
+
+                [notify]
+                command = '/usr/local/bin/cub-done'
+                desktop = true
+                min_duration = '30s'
+
+                [code]
+                command = 'codium'
+                remote = 'ssh-remote+localhost'
+
+                [ssh]
+                enabled = true
+                sshd = '/usr/local/sbin/sshd'
+
+                [host_open]
+                enabled = true
+                command = 'firefox'
+
+                [tmp]
+                name_prefix = '{project}-'
+                words = 2
+                word_list = '/usr/share/dict/words'
+
+                [dotfiles]
+                repo = 'https://example.org/me/dotfiles.git'
+                branch = 'cubicle'
+                method = 'stow'
+
+                [secrets]
+                API_KEY = { file = '~/api-key' }
+                GITHUB_TOKEN = { command = ['pass', 'show', 'github'] }
+
+                [caches]
+                cargo-registry = '.cargo/registry'
+                pip = '.cache/pip'
+
+                [compiler_cache]
+                tool = 'sccache'
+                max_size = '20G'
 
                 [bubblewrap]
                 seccomp = '/tmp/seccomp.bpf'
+                encryption_password_command = ['pass', 'show', 'cubicle']
+                ro_binds = ['/nix', '~/.local/toolchains']
+                rw_binds = ['/var/cache/ccache']
+                network = 'slirp'
+                user_namespace = { uid = 1000, gid = 100 }
+                overlay_home = true
+                dbus = 'proxy'
+                dbus_talk = ['org.freedesktop.Notifications']
 
                 [docker]
+                apparmor = 'cubicle-profile'
                 bind_mounts = true
+                context = 'colima'
                 locales = ['eo', 'tg_TJ.UTF-8']
                 prefix = 'p'
                 seccomp = '/etc/seccomp.json'
                 strict_debian_packages = true
+                keep_alive = ['my-init', '--forever']
+                network = 'devnet'
+                selinux_relabel = 'private'
+                storage_opt_quota = true
+                retries = 5
+                retry_builds = true
+
+                [docker.environments.db-client]
+                bind_mounts = false
+                network = 'dbnet'
+
+                [user]
+                accounts = 'useradd'
+                isolation = 'systemd-run'
                 "
             )
             .enough_context()
@@ -410,6 +2263,162 @@ mod tests {
         );
     }
 
+    #[test]
+    fn config_from_str_keep_alive() {
+        let keep_alive = |value: &str| {
+            Config::from_str(&format!(
+                "
+                runner = 'docker'
+                [docker]
+                keep_alive = {value}
+                "
+            ))
+            .enough_context()
+            .map(|config| config.docker.keep_alive)
+        };
+        assert_eq!(KeepAlive::Sleep, keep_alive("'sleep'").unwrap());
+        assert_eq!(KeepAlive::Tini, keep_alive("'tini'").unwrap());
+        assert!(keep_alive("'forever'")
+            .unwrap_err()
+            .to_string()
+            .contains("unknown keep-alive \"forever\""));
+        assert!(keep_alive("[]")
+            .unwrap_err()
+            .to_string()
+            .contains("keep-alive command cannot be empty"));
+    }
+
+    #[test]
+    fn interpolate() {
+        let lookup = |name: &str| match name {
+            "USER" => Some(String::from("me")),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let interpolate = |s| super::interpolate(s, &lookup).map_err(|e| e.to_string());
+        assert_eq!(
+            Ok(String::from("/home/me/x")),
+            interpolate("/home/${USER}/x")
+        );
+        assert_eq!(
+            Ok(String::from("me-/tmp")),
+            interpolate("${USER}-${CACHE:-/tmp}")
+        );
+        assert_eq!(Ok(String::from("a")), interpolate("${EMPTY:-a}"));
+        assert_eq!(Ok(String::new()), interpolate("${EMPTY}"));
+        assert_eq!(
+            Ok(String::from("$USER ${USER}")),
+            interpolate("$USER $${USER}")
+        );
+        assert_eq!(
+            Err(String::from(
+                "environment variable $CACHE is not set (used in \"${CACHE}/x\")"
+            )),
+            interpolate("${CACHE}/x")
+        );
+        assert_eq!(
+            Err(String::from("unterminated `${` in \"${USER\"")),
+            interpolate("${USER")
+        );
+        assert_eq!(
+            Err(String::from(
+                "invalid environment variable name \"1\" in \"${1}\""
+            )),
+            interpolate("${1}")
+        );
+    }
+
+    #[test]
+    fn interpolate_document() {
+        let lookup = |name: &str| (name == "USER").then(|| String::from("me"));
+        expect_test::expect![[r#"
+            # Comments stay.
+            runner = 'docker' # Here too.
+            [dirs]
+            work_dirs = "/data/me/work"
+            [docker]
+            locales = ["${LANG}", "me"]
+            keep_alive = { command = ["/home/me/bin/init"] }
+        "#]]
+        .assert_eq(
+            &super::interpolate_document(
+                indoc! {"
+                    # Comments stay.
+                    runner = 'docker' # Here too.
+                    [dirs]
+                    work_dirs = '/data/${USER}/work'
+                    [docker]
+                    locales = ['$${LANG}', '${USER}']
+                    keep_alive = { command = ['/home/${USER}/bin/init'] }
+                "},
+                &lookup,
+            )
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    fn config_from_str_profiles() {
+        let s = "
+            runner = 'bubblewrap'
+            auto_stop = '2h'
+
+            [bubblewrap]
+            seccomp = 'dangerously-disabled'
+
+            [docker]
+            locales = ['eo']
+            prefix = 'p'
+
+            [profiles.work]
+            runner = 'docker'
+            docker.network = 'corp-proxy'
+            docker.locales = ['tg_TJ.UTF-8']
+
+            [profiles.personal]
+            auto_stop = 'never'
+            ";
+
+        let config = Config::from_str(s).enough_context().unwrap();
+        assert_eq!(RunnerKind::Bubblewrap, config.runner);
+        assert_eq!(
+            BTreeSet::from([String::from("personal"), String::from("work")]),
+            config.profiles
+        );
+        assert_eq!(None, config.profile);
+
+        let config = Config::from_str_with_profile(s, Some("work"))
+            .enough_context()
+            .unwrap();
+        assert_eq!(RunnerKind::Docker, config.runner);
+        assert_eq!(Some(Duration::from_secs(60 * 60 * 2)), config.auto_stop);
+        assert_eq!(Some(String::from("corp-proxy")), config.docker.network);
+        assert_eq!(vec![String::from("tg_TJ.UTF-8")], config.docker.locales);
+        assert_eq!("p", config.docker.prefix);
+        assert_eq!(Some(String::from("work")), config.profile);
+
+        let config = Config::from_str_with_profile(s, Some("personal"))
+            .enough_context()
+            .unwrap();
+        assert_eq!(RunnerKind::Bubblewrap, config.runner);
+        assert_eq!(None, config.auto_stop);
+
+        assert_eq!(
+            "unknown profile \"home\" (defined profiles: personal, work)",
+            Config::from_str_with_profile(s, Some("home"))
+                .enough_context()
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "unknown profile \"work\" (defined profiles: none)",
+            Config::from_str_with_profile("runner = 'user'", Some("work"))
+                .enough_context()
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
     #[test]
     fn config_from_str_full_seccomp_disabled() {
         assert_eq!(
@@ -428,4 +2437,39 @@ mod tests {
             .seccomp
         );
     }
+
+    #[test]
+    fn config_from_str_relative_bind() {
+        let error = Config::from_str(
+            "
+            runner = 'bubblewrap'
+            [bubblewrap]
+            seccomp = 'dangerously-disabled'
+            ro_binds = ['nix']
+            ",
+        )
+        .enough_context()
+        .unwrap_err()
+        .to_string();
+        assert!(
+            error.contains("bind paths must be absolute, found \"nix\""),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn json_schema() {
+        let schema = Config::json_schema();
+        assert_eq!(
+            Some("Cubicle configuration (cubicle.toml)"),
+            schema["title"].as_str()
+        );
+        assert_eq!(Some(false), schema["additionalProperties"].as_bool());
+        let properties = schema["properties"].as_object().unwrap();
+        for key in ["runner", "auto_update", "bubblewrap", "docker", "profiles"] {
+            assert!(properties.contains_key(key), "missing {key}");
+        }
+        let runners = schema["properties"]["runner"]["enum"].as_array().unwrap();
+        assert!(runners.contains(&serde_json::Value::from("docker")));
+    }
 }
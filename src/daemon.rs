@@ -0,0 +1,596 @@
+//! Serves read-only queries from a long-running `cub daemon` process.
+//!
+//! Creating a [`Cubicle`] instance reads the configuration, scans for
+//! packages, and probes the runner (for example, by running `docker info`),
+//! which can take a noticeable fraction of a second. A daemon does that once
+//! and then answers queries over a Unix socket, so `cub list`, `cub show`, and
+//! `cub package list` can respond right away. IDEs and other tools can use
+//! [`Client`] the same way.
+//!
+//! The daemon also keeps its answers in memory for a few seconds, so repeated
+//! queries don't scan the packages and environments on disk again. `cub`
+//! commands that may change that state tell the daemon to forget its answers
+//! (see [`Client::invalidate`]).
+//!
+//! With `--metrics-address`, the daemon also serves [`Cubicle::metrics`]
+//! over HTTP at `/metrics`, for Prometheus to scrape. That endpoint has no
+//! authentication, so the CLI listens on `127.0.0.1` unless given a host.
+//!
+//! The protocol is one JSON request line and one JSON response line per
+//! connection. The daemon only answers clients from the same version of
+//! Cubicle, and it stops when its configuration file changes, so clients
+//! fall back to running queries themselves in those cases.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::json::{EnvironmentDebianPackages, EnvironmentList, EnvironmentShow, PackageList};
+use super::os_util::host_home_dir;
+use super::paths::xdg_cache_home;
+use super::{
+    cancel, Cubicle, DebianPackages, EnvironmentDetails, EnvironmentName, Event, FullPackageName,
+    HostPath, PackageDetails, PackageStatus, Refresh,
+};
+use crate::somehow::{somehow as anyhow, warn, Context, Error, ErrorKind, Result};
+
+/// Requests are rejected unless they come from the same version of Cubicle,
+/// since the two sides share the JSON documents' Rust types.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// How often the daemon checks for Ctrl-C while waiting for connections.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the daemon waits for a client to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the daemon reuses an answer, in case something other than `cub`
+/// changed the state on disk.
+const CACHE_FOR: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Serialize)]
+struct Envelope {
+    version: String,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Request {
+    Ping,
+    EnvironmentNames,
+    Environments { refresh: bool },
+    Environment { name: EnvironmentName },
+    DebianPackages { name: EnvironmentName },
+    PackageNames,
+    Packages,
+    PackageStatus,
+    Metrics,
+    Invalidate,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Response {
+    Ok(serde_json::Value),
+    Error {
+        message: String,
+        kind: Option<ErrorKind>,
+    },
+    /// The daemon can't answer, so the client should do the work itself.
+    Unavailable(String),
+}
+
+/// Returns the path of the socket for a daemon using the given configuration
+/// file and profile.
+///
+/// This is in `$XDG_RUNTIME_DIR` if set, or in `$XDG_CACHE_HOME` otherwise.
+/// The name includes a hash of the configuration file's path and the
+/// profile, so that daemons for different configurations don't conflict.
+pub fn socket_path(config_path: &Path, profile: Option<&str>) -> Result<HostPath> {
+    let dir = match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(path) => HostPath::try_from(path)?.join("cubicle"),
+        Err(_) => xdg_cache_home(host_home_dir())?
+            .join("cubicle")
+            .join("daemon"),
+    };
+    let mut key = std::fs::canonicalize(config_path)
+        .unwrap_or_else(|_| config_path.to_owned())
+        .into_os_string();
+    if let Some(profile) = profile {
+        key.push("\0");
+        key.push(profile);
+    }
+    use sha2::{Digest, Sha256};
+    let hash = Sha256::digest(key.as_encoded_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    Ok(dir.join(format!("{hash}.sock")))
+}
+
+/// Recent answers to requests, keyed by the serialized request.
+#[derive(Default)]
+struct Cache(Mutex<CacheState>);
+
+#[derive(Default)]
+struct CacheState {
+    /// Incremented by [`Cache::clear`], so that answers computed before
+    /// clearing the cache aren't added to it afterwards.
+    generation: u64,
+    entries: HashMap<String, (Instant, serde_json::Value)>,
+}
+
+impl Cache {
+    /// Returns the cached answer for `key` if it's recent enough, or calls
+    /// `compute` otherwise. Only successful answers are cached.
+    fn get_or_compute<F>(&self, key: String, compute: F) -> Result<serde_json::Value>
+    where
+        F: FnOnce() -> Result<serde_json::Value>,
+    {
+        let generation = {
+            let state = self.0.lock().unwrap();
+            if let Some((computed, value)) = state.entries.get(&key) {
+                if computed.elapsed() < CACHE_FOR {
+                    return Ok(value.clone());
+                }
+            }
+            state.generation
+        };
+        let computed = Instant::now();
+        let value = compute()?;
+        let mut state = self.0.lock().unwrap();
+        if state.generation == generation {
+            state.entries.insert(key, (computed, value.clone()));
+        }
+        Ok(value)
+    }
+
+    fn clear(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.generation += 1;
+        state.entries.clear();
+    }
+}
+
+/// State shared by the threads handling the daemon's connections.
+#[derive(Default)]
+struct Shared {
+    /// Set while a thread is refreshing disk usage in the background.
+    refreshing: AtomicBool,
+    cache: Cache,
+}
+
+/// Removes the socket file when the daemon stops.
+struct SocketFile<'a>(&'a HostPath);
+
+impl Drop for SocketFile<'_> {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(self.0.as_host_raw());
+    }
+}
+
+/// Answers queries on the socket until interrupted or until the
+/// configuration file at `config_path` changes.
+///
+/// If `metrics_address` is given, this also serves metrics over HTTP on it.
+///
+/// Fails if another daemon is already listening on the socket.
+pub fn serve(
+    program: &Cubicle,
+    socket: &HostPath,
+    config_path: &Path,
+    metrics_address: Option<SocketAddr>,
+) -> Result<()> {
+    let config_mtime = || {
+        std::fs::metadata(config_path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    let started_config_mtime = config_mtime();
+
+    if UnixStream::connect(socket.as_host_raw()).is_ok() {
+        return Err(anyhow!("a Cubicle daemon is already listening on {socket}"));
+    }
+    if let Some(dir) = socket.as_host_raw().parent() {
+        // Only this user should be able to query the daemon.
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create directory {dir:?}"))?;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))
+            .with_context(|| format!("failed to set permissions on {dir:?}"))?;
+    }
+    // The socket file may be left over from a daemon that was killed.
+    match std::fs::remove_file(socket.as_host_raw()) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("failed to remove {socket}")),
+    }
+    let listener = UnixListener::bind(socket.as_host_raw())
+        .with_context(|| format!("failed to listen on {socket}"))?;
+    let _socket_file = SocketFile(socket);
+    listener
+        .set_nonblocking(true)
+        .with_context(|| format!("failed to configure {socket}"))?;
+    program
+        .shared
+        .event(Event::DaemonListening(socket.as_host_raw().to_owned()));
+
+    let metrics_listener = match metrics_address {
+        Some(address) => {
+            let listener = TcpListener::bind(address)
+                .with_context(|| format!("failed to listen on {address}"))?;
+            listener
+                .set_nonblocking(true)
+                .with_context(|| format!("failed to configure {address}"))?;
+            program.shared.event(Event::ServingMetrics(address));
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let shared = Shared::default();
+    std::thread::scope(|scope| loop {
+        cancel::check()?;
+        if let Some(metrics_listener) = &metrics_listener {
+            match metrics_listener.accept() {
+                Ok((stream, _)) => {
+                    scope.spawn(move || {
+                        if let Err(e) = serve_metrics(program, stream) {
+                            warn(e.context("failed to handle metrics request"));
+                        }
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // Keep answering queries even if metrics are broken.
+                Err(e) => warn(anyhow!("failed to accept metrics connection: {e}")),
+            }
+        }
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to accept connection on {socket}"))
+            }
+        };
+        if config_mtime() != started_config_mtime {
+            let _ = reply(
+                &stream,
+                &Response::Unavailable(String::from("configuration file changed")),
+            );
+            program
+                .shared
+                .event(Event::ConfigChanged(config_path.to_owned()));
+            return Ok(());
+        }
+        let shared = &shared;
+        scope.spawn(move || {
+            if let Err(e) = handle(program, &stream, scope, shared) {
+                warn(e.context("failed to handle daemon request"));
+            }
+        });
+    })
+}
+
+fn handle<'scope>(
+    program: &'scope Cubicle,
+    stream: &UnixStream,
+    scope: &'scope std::thread::Scope<'scope, '_>,
+    shared: &'scope Shared,
+) -> Result<()> {
+    stream
+        .set_nonblocking(false)
+        .and_then(|()| stream.set_read_timeout(Some(READ_TIMEOUT)))
+        .context("failed to configure connection")?;
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .context("failed to read request")?;
+    let envelope: Envelope = serde_json::from_str(&line).context("failed to parse request")?;
+    if envelope.version != VERSION {
+        return reply(
+            stream,
+            &Response::Unavailable(format!(
+                "daemon is version {VERSION} but client is version {}",
+                envelope.version
+            )),
+        );
+    }
+
+    fn ok<T: Serialize>(value: Result<T>) -> Result<serde_json::Value> {
+        value.and_then(|value| {
+            serde_json::to_value(value).context("failed to serialize daemon response")
+        })
+    }
+    let answer = || match &envelope.request {
+        Request::Ping | Request::Invalidate => Ok(serde_json::Value::Null),
+        Request::EnvironmentNames => ok(program.get_environment_names()),
+        Request::Environments { refresh } => {
+            let envs = program.get_environments(Refresh(*refresh));
+            if let Ok(envs) = &envs {
+                // Refresh stale disk usage here, rather than have the client
+                // start a process to do it.
+                if envs.values().any(|env| env.summary_stale)
+                    && !shared.refreshing.swap(true, Ordering::SeqCst)
+                {
+                    scope.spawn(move || {
+                        match program.get_environments(Refresh(true)) {
+                            Ok(_) => shared.cache.clear(),
+                            Err(e) => warn(e.context("failed to refresh disk usage")),
+                        }
+                        shared.refreshing.store(false, Ordering::SeqCst);
+                    });
+                }
+            }
+            ok(envs.map(EnvironmentList::new))
+        }
+        Request::Environment { name } => ok(program
+            .get_environment(name)
+            .map(|env| EnvironmentShow::new(name.clone(), env))),
+        Request::DebianPackages { name } => ok(program
+            .get_debian_packages(name)
+            .map(|packages| EnvironmentDebianPackages::new(name.clone(), packages))),
+        Request::PackageNames => ok(program.get_package_names()),
+        Request::Packages => ok(program.get_packages().map(PackageList::new)),
+        Request::PackageStatus => ok(program.get_package_status()),
+        Request::Metrics => ok(program.metrics()),
+    };
+    let result = match &envelope.request {
+        Request::Ping | Request::Metrics => answer(),
+        Request::Invalidate => {
+            shared.cache.clear();
+            answer()
+        }
+        Request::Environments { refresh: true } => {
+            let result = answer();
+            // Later requests should see the refreshed disk usage.
+            shared.cache.clear();
+            result
+        }
+        request => match serde_json::to_string(request) {
+            Ok(key) => shared.cache.get_or_compute(key, answer),
+            Err(e) => Err(e).context("failed to serialize daemon request"),
+        },
+    };
+    reply(
+        stream,
+        &match result {
+            Ok(value) => Response::Ok(value),
+            Err(e) => Response::Error {
+                message: format!("{e:#}"),
+                kind: e.kind(),
+            },
+        },
+    )
+}
+
+/// Answers one HTTP request for metrics.
+fn serve_metrics(program: &Cubicle, mut stream: TcpStream) -> Result<()> {
+    stream
+        .set_nonblocking(false)
+        .and_then(|()| stream.set_read_timeout(Some(READ_TIMEOUT)))
+        .context("failed to configure connection")?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader
+        .read_line(&mut request_line)
+        .context("failed to read request")?;
+    // Skip the headers.
+    loop {
+        let mut header = String::new();
+        let n = reader
+            .read_line(&mut header)
+            .context("failed to read request")?;
+        if n == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut words = request_line.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => match program.metrics() {
+            Ok(metrics) => ("200 OK", metrics),
+            Err(e) => ("500 Internal Server Error", format!("{e:#}\n")),
+        },
+        _ => ("404 Not Found", String::from("Not found\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.0 {status}\r\n\
+        Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {body}",
+        body.len()
+    )
+    .context("failed to write metrics response")
+}
+
+fn reply(mut stream: &UnixStream, response: &Response) -> Result<()> {
+    let mut buf = serde_json::to_vec(response).context("failed to serialize daemon response")?;
+    buf.push(b'\n');
+    stream
+        .write_all(&buf)
+        .context("failed to write daemon response")
+}
+
+/// Sends queries to a running `cub daemon`.
+///
+/// The methods correspond to the same-named methods on [`Cubicle`].
+pub struct Client {
+    socket: HostPath,
+}
+
+impl Client {
+    /// Returns a client if a compatible daemon is listening on the socket.
+    pub fn connect(socket: HostPath) -> Option<Self> {
+        let client = Self { socket };
+        match client.send(Request::Ping) {
+            Ok(Response::Ok(_)) => Some(client),
+            _ => None,
+        }
+    }
+
+    fn send(&self, request: Request) -> Result<Response> {
+        let send = || -> Result<Response> {
+            let mut stream =
+                UnixStream::connect(self.socket.as_host_raw()).context("failed to connect")?;
+            let mut buf = serde_json::to_vec(&Envelope {
+                version: String::from(VERSION),
+                request,
+            })
+            .context("failed to serialize request")?;
+            buf.push(b'\n');
+            stream.write_all(&buf).context("failed to send request")?;
+            let mut line = String::new();
+            BufReader::new(stream)
+                .read_line(&mut line)
+                .context("failed to read response")?;
+            serde_json::from_str(&line).context("failed to parse response")
+        };
+        send().with_context(|| format!("failed to query Cubicle daemon at {}", self.socket))
+    }
+
+    fn query<T: for<'de> Deserialize<'de>>(&self, request: Request) -> Result<T> {
+        match self.send(request)? {
+            Response::Ok(value) => serde_json::from_value(value)
+                .with_context(|| format!("failed to parse response from {}", self.socket)),
+            Response::Error {
+                message,
+                kind: Some(kind),
+            } => Err(Error::with_kind(kind, message)),
+            Response::Error {
+                message,
+                kind: None,
+            } => Err(anyhow!(message)),
+            Response::Unavailable(reason) => Err(anyhow!(
+                "Cubicle daemon at {} stopped answering queries: {reason}",
+                self.socket
+            )),
+        }
+    }
+
+    /// See [`Cubicle::get_environment_names`].
+    pub fn get_environment_names(&self) -> Result<BTreeSet<EnvironmentName>> {
+        self.query(Request::EnvironmentNames)
+    }
+
+    /// See [`Cubicle::get_environments`].
+    ///
+    /// The daemon refreshes stale disk usage itself, so callers don't need to
+    /// start `cub list --refresh` in the background.
+    pub fn get_environments(
+        &self,
+        refresh: Refresh,
+    ) -> Result<BTreeMap<EnvironmentName, EnvironmentDetails>> {
+        let list: EnvironmentList = self.query(Request::Environments { refresh: refresh.0 })?;
+        Ok(list.environments)
+    }
+
+    /// See [`Cubicle::get_environment`].
+    pub fn get_environment(&self, name: &EnvironmentName) -> Result<EnvironmentDetails> {
+        let show: EnvironmentShow = self.query(Request::Environment { name: name.clone() })?;
+        Ok(show.environment)
+    }
+
+    /// See [`Cubicle::get_debian_packages`].
+    pub fn get_debian_packages(&self, name: &EnvironmentName) -> Result<DebianPackages> {
+        let packages: EnvironmentDebianPackages =
+            self.query(Request::DebianPackages { name: name.clone() })?;
+        Ok(packages.debian_packages)
+    }
+
+    /// See [`Cubicle::get_package_names`].
+    pub fn get_package_names(&self) -> Result<BTreeSet<FullPackageName>> {
+        self.query(Request::PackageNames)
+    }
+
+    /// See [`Cubicle::get_packages`].
+    pub fn get_packages(&self) -> Result<BTreeMap<FullPackageName, PackageDetails>> {
+        let list: PackageList = self.query(Request::Packages)?;
+        Ok(list.packages)
+    }
+
+    /// See [`Cubicle::metrics`].
+    pub fn metrics(&self) -> Result<String> {
+        self.query(Request::Metrics)
+    }
+
+    /// See [`Cubicle::get_package_status`].
+    pub fn get_package_status(&self) -> Result<PackageStatus> {
+        self.query(Request::PackageStatus)
+    }
+
+    /// Tells the daemon to forget its cached answers, after changing state
+    /// that they may depend on.
+    pub fn invalidate(&self) -> Result<()> {
+        self.query(Request::Invalidate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+    use std::str::FromStr;
+
+    #[test]
+    fn wire_format() {
+        let request = Envelope {
+            version: String::from("1.2.3"),
+            request: Request::Environment {
+                name: EnvironmentName::from_str("foo").unwrap(),
+            },
+        };
+        expect![[r#"{"version":"1.2.3","method":"environment","name":"foo"}"#]]
+            .assert_eq(&serde_json::to_string(&request).unwrap());
+
+        let response = Response::Error {
+            message: String::from("environment \"foo\" not found"),
+            kind: Some(ErrorKind::EnvironmentNotFound),
+        };
+        expect![[
+            r#"{"error":{"message":"environment \"foo\" not found","kind":"EnvironmentNotFound"}}"#
+        ]]
+        .assert_eq(&serde_json::to_string(&response).unwrap());
+    }
+
+    #[test]
+    fn cache() {
+        let cache = Cache::default();
+        let key = || String::from("packages");
+        let compute = |n: u64| move || Ok(serde_json::Value::from(n));
+        assert_eq!(cache.get_or_compute(key(), compute(1)).unwrap(), 1);
+        assert_eq!(cache.get_or_compute(key(), compute(2)).unwrap(), 1);
+        cache.clear();
+        assert_eq!(cache.get_or_compute(key(), compute(3)).unwrap(), 3);
+
+        // Errors aren't cached.
+        cache.clear();
+        assert!(cache
+            .get_or_compute(key(), || Err(anyhow!("oops")))
+            .is_err());
+        assert_eq!(cache.get_or_compute(key(), compute(4)).unwrap(), 4);
+
+        // An answer computed while the cache is cleared isn't kept.
+        cache.clear();
+        let value = cache.get_or_compute(key(), || {
+            cache.clear();
+            Ok(serde_json::Value::from(5))
+        });
+        assert_eq!(value.unwrap(), 5);
+        assert_eq!(cache.get_or_compute(key(), compute(6)).unwrap(), 6);
+    }
+}
@@ -1,60 +1,98 @@
 use regex::{Regex, RegexBuilder};
-use std::collections::BTreeSet;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::{OsStr, OsString};
 use std::io::{self, BufRead, IsTerminal, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use std::rc::Rc;
 use std::str::FromStr;
-use std::sync::OnceLock;
-use std::time::{Duration, UNIX_EPOCH};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use super::command_ext::Command;
+use super::caches;
+use super::command_ext::{Command, Retry};
+use super::config::{self, KeepAlive, SelinuxRelabel};
+use super::file_watch::{parse_find_listing, poll_host_dir};
 use super::fs_util::{rmtree, summarize_dir, try_exists, try_iterdir_dirs, DirSummary};
+use super::host_open;
+use super::lock::Lock;
 use super::os_util::{get_timezone, get_uids, Uids};
 use super::paths::EnvPath;
 use super::runner::{
-    EnvFilesSummary, EnvironmentExists, Init, Runner, RunnerCommand, Target,
+    interactive_shell_command, Capability, CodeTarget, EnvDir, EnvFilesSummary, EnvNetwork,
+    EnvPiece, EnvironmentExists, FileListing, Init, Runner, RunnerCommand, Target,
     LOCALE_ENVIRONMENT_VARIABLES,
 };
-use super::{CubicleShared, EnvironmentName, ExitStatusError, HostPath};
+use super::seccomp::check_docker_policy;
+use super::x11;
+use super::{apt, CubicleShared, EnvironmentName, Event, ExitStatusError, HostPath, Phase};
 use crate::somehow::{somehow as anyhow, warn, Context, LowLevelResult, Result};
 
+mod api;
 mod names;
 use names::{ContainerName, ImageName, VolumeName};
 
 pub struct Docker {
-    pub(super) program: Rc<CubicleShared>,
+    pub(super) program: Arc<CubicleShared>,
     user: String,
     uids: Uids,
     timezone: String,
     locales: BTreeSet<String>,
     mounts: Mounts,
+    /// Used for read-only queries instead of the `docker` CLI, if the
+    /// daemon's socket was found.
+    api: Option<api::Client>,
     base_image: ImageName,
+    /// Held while building the base image and starting a container from it,
+    /// since threads resetting environments in parallel share the image.
+    base_image_lock: Mutex<()>,
     container_home: EnvPath,
+    user_namespace: OnceLock<UserNamespace>,
 }
 
-enum Mounts {
-    BindMounts {
-        home_dirs: HostPath,
-        work_dirs: HostPath,
-    },
-    Volumes,
+/// How user IDs in the containers relate to user IDs on the host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum UserNamespace {
+    /// The daemon runs as root, so container UIDs are host UIDs.
+    Host,
+    /// Rootless Docker maps the container's root user to the host user and
+    /// other container UIDs into the host user's subordinate UID range (see
+    /// `/etc/subuid`).
+    RootlessDocker,
+    /// Rootless Podman maps UIDs like rootless Docker by default, but it
+    /// supports `--userns=keep-id` to map the host user to the same UID in the
+    /// container.
+    RootlessPodman,
+}
+
+/// Where environments' bind-mounted directories are kept on the host, for
+/// the environments that use bind mounts instead of volumes.
+struct Mounts {
+    home_dirs: HostPath,
+    work_dirs: HostPath,
+    overlay_dirs: HostPath,
 }
 
+/// Where an environment's files are stored on the host.
+///
+/// The overlay directory or volume is the overlayfs work directory, used only
+/// for instances of golden environments.
 enum EnvMounts {
     BindMounts {
         host_home: HostPath,
         host_work: HostPath,
+        host_overlay: HostPath,
     },
     Volumes {
         home_volume: VolumeName,
         work_volume: VolumeName,
+        overlay_volume: VolumeName,
     },
 }
 
 impl Docker {
-    pub(super) fn new(program: Rc<CubicleShared>) -> Result<Self> {
+    pub(super) fn new(program: Arc<CubicleShared>) -> Result<Self> {
         let host_user = std::env::var("USER").context("Invalid $USER")?;
         let (user, uids) = if host_user == "root" {
             (
@@ -74,24 +112,10 @@ impl Docker {
             .chain(program.config.docker.locales.iter().cloned())
             .collect();
 
-        let mounts = if program.config.docker.bind_mounts {
-            let xdg_cache_home = match std::env::var("XDG_CACHE_HOME") {
-                Ok(path) => HostPath::try_from(path)?,
-                Err(_) => program.home.join(".cache"),
-            };
-            let home_dirs = xdg_cache_home.join("cubicle").join("home");
-
-            let xdg_data_home = match std::env::var("XDG_DATA_HOME") {
-                Ok(path) => HostPath::try_from(path)?,
-                Err(_) => program.home.join(".local").join("share"),
-            };
-            let work_dirs = xdg_data_home.join("cubicle").join("work");
-            Mounts::BindMounts {
-                home_dirs,
-                work_dirs,
-            }
-        } else {
-            Mounts::Volumes
+        let mounts = Mounts {
+            home_dirs: program.paths.home_dirs.clone(),
+            work_dirs: program.paths.work_dirs.clone(),
+            overlay_dirs: program.paths.cache.join("overlay"),
         };
 
         let base_image = ImageName::new(format!("{}cubicle-base", program.config.docker.prefix));
@@ -100,12 +124,15 @@ impl Docker {
             .unwrap()
             .join(&user);
 
+        check_endpoint(&program.config.docker)?;
+
         if let Some(path) = &program.config.docker.seccomp {
             // Better give an early error message if this isn't configured right.
-            std::fs::metadata(path)
-                .with_context(|| format!("could not read Docker seccomp policy: {path:?}"))?;
+            check_docker_policy(path, &program.exe_name)?;
         };
 
+        let api = api::Client::detect(&program.config.docker);
+
         Ok(Self {
             program,
             user,
@@ -113,11 +140,192 @@ impl Docker {
             timezone,
             locales,
             mounts,
+            api,
             base_image,
+            base_image_lock: Mutex::new(()),
             container_home,
+            user_namespace: OnceLock::new(),
+        })
+    }
+
+    /// Returns a `docker` command that talks to the configured daemon.
+    fn docker(&self) -> Command {
+        docker_command(&self.program.config.docker)
+    }
+
+    /// Returns how the Docker daemon maps user IDs, detecting it the first
+    /// time this is called.
+    fn user_namespace(&self) -> UserNamespace {
+        *self.user_namespace.get_or_init(|| {
+            self.retry()
+                .run("`docker info`", || {
+                    detect_user_namespace(&self.program.config.docker)
+                        .context("failed to query Docker daemon")
+                })
+                .context("failed to detect whether Docker daemon is rootless (assuming not)")
+                .unwrap_or_else(|e| {
+                    warn(e);
+                    UserNamespace::Host
+                })
         })
     }
 
+    /// Returns the UID and GID for the user account in the containers.
+    fn container_uids(&self) -> Uids {
+        match self.user_namespace() {
+            // The host UID can't be used to make bind mounts owned by the
+            // host user, so any UID will do.
+            UserNamespace::RootlessDocker => Uids {
+                real_user: 1000,
+                group: 1000,
+            },
+            UserNamespace::Host | UserNamespace::RootlessPodman => Uids {
+                real_user: self.uids.real_user,
+                group: self.uids.group,
+            },
+        }
+    }
+
+    /// Makes a newly created bind mount directory owned by the container's
+    /// user account.
+    ///
+    /// This is only needed with rootless Docker, where the host user appears
+    /// as root inside the containers.
+    fn prepare_bind_dir(&self, dir: &HostPath) -> Result<()> {
+        if self.user_namespace() != UserNamespace::RootlessDocker {
+            return Ok(());
+        }
+        let Uids {
+            real_user: uid,
+            group: gid,
+        } = self.container_uids();
+        self.run_as_container_root(dir, &["chown", &format!("{uid}:{gid}"), "/v"])
+            .with_context(|| format!("failed to change owner of {dir} for rootless Docker"))
+    }
+
+    /// Removes the contents of a bind mount directory.
+    ///
+    /// With rootless Docker, the files are owned by UIDs in the host user's
+    /// subordinate range, so the host user may not be able to remove them
+    /// directly. This removes them from a container instead.
+    fn clear_bind_dir(&self, dir: &HostPath) -> Result<()> {
+        if self.user_namespace() == UserNamespace::RootlessDocker
+            && try_exists(dir).todo_context()?
+        {
+            self.run_as_container_root(dir, &["find", "/v", "-mindepth", "1", "-delete"])
+                .with_context(|| format!("failed to clear {dir} for rootless Docker"))?;
+        }
+        rmtree(dir)
+    }
+
+    /// Removes an overlayfs work directory.
+    ///
+    /// The kernel creates the contents of this directory as the Docker
+    /// daemon's user (usually root), so they're removed from a container.
+    fn clear_overlay_dir(&self, dir: &HostPath) -> Result<()> {
+        if try_exists(dir).todo_context()? {
+            self.run_as_container_root(dir, &["find", "/v", "-mindepth", "1", "-delete"])
+                .with_context(|| format!("failed to clear overlay directory {dir}"))?;
+        }
+        rmtree(dir)
+    }
+
+    /// Runs a command as root in a temporary container from the base image,
+    /// with `dir` mounted at `/v`.
+    ///
+    /// This builds the base image first if it doesn't exist yet, without any
+    /// of the packages' Debian packages, which the next environment to start
+    /// adds.
+    fn run_as_container_root(&self, dir: &HostPath, command: &[&str]) -> LowLevelResult<()> {
+        {
+            let _lock = self.lock_base_image()?;
+            if !self.image_exists(&self.base_image, "latest")? {
+                self.build_base(&[])?;
+            }
+        }
+        let mut args = self.bind_mount_args(dir, "/v")?.to_vec();
+        args.extend(["--rm", "--user", "0:0"].map(String::from));
+        args.push(self.base_image.encoded());
+        args.extend(command.iter().map(|arg| String::from(*arg)));
+        let status = self
+            .docker()
+            .arg("run")
+            .args(args)
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(anyhow!("`docker run ... {}` exited with {status}", command[0]).into());
+        }
+        Ok(())
+    }
+
+    /// Creates the environment's home and work directories as bind mounts
+    /// or volumes, then initializes it.
+    fn create_(&self, env_name: &EnvironmentName, init: &Init, bind_mounts: bool) -> Result<()> {
+        let container_name = self.container_from_environment(env_name);
+        if self.is_container(&container_name)? {
+            return Err(anyhow!("Docker container {container_name} already exists"));
+        }
+        let is_instance = self.program.golden.golden(env_name)?.is_some();
+        match &self.mounts_of_kind(env_name, bind_mounts) {
+            EnvMounts::BindMounts {
+                host_home,
+                host_work,
+                host_overlay,
+            } => {
+                std::fs::create_dir_all(host_home.as_host_raw()).todo_context()?;
+                std::fs::create_dir_all(host_work.as_host_raw()).todo_context()?;
+                self.prepare_bind_dir(host_home)?;
+                self.prepare_bind_dir(host_work)?;
+                if is_instance {
+                    std::fs::create_dir_all(host_overlay.as_host_raw()).todo_context()?;
+                }
+            }
+
+            EnvMounts::Volumes {
+                home_volume,
+                work_volume,
+                overlay_volume,
+            } => {
+                self.ensure_volume_exists(env_name, home_volume)?;
+                self.ensure_volume_exists(env_name, work_volume)?;
+                if is_instance {
+                    self.ensure_volume_exists(env_name, overlay_volume)?;
+                }
+            }
+        }
+
+        self.init(env_name, init)
+    }
+
+    /// Returns the label and value marking objects created with this
+    /// configuration's `docker.prefix`, as `KEY=VALUE`.
+    fn prefix_label(&self) -> String {
+        format!("{PREFIX_LABEL}={}", self.program.config.docker.prefix)
+    }
+
+    /// Returns `--label` arguments for a container, volume, or image created
+    /// by the runner, for the given environment or for no environment in
+    /// particular.
+    fn label_args(&self, env: Option<&EnvironmentName>) -> Vec<String> {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut labels = vec![
+            self.prefix_label(),
+            format!("{VERSION_LABEL}={}", env!("CARGO_PKG_VERSION")),
+            format!("{CREATED_LABEL}={created}"),
+        ];
+        if let Some(env) = env {
+            labels.push(format!("{ENVIRONMENT_LABEL}={}", env.as_str()));
+        }
+        labels
+            .into_iter()
+            .flat_map(|label| [String::from("--label"), label])
+            .collect()
+    }
+
     fn container_from_environment(&self, env: &EnvironmentName) -> ContainerName {
         ContainerName::new(format!(
             "{}{}",
@@ -126,20 +334,64 @@ impl Docker {
         ))
     }
 
+    /// Returns the name of the Docker network that the environment's
+    /// container should join, if configured.
+    fn network(&self, env: &EnvironmentName) -> Option<&str> {
+        let config = &self.program.config.docker;
+        config
+            .environments
+            .get(env.as_str())
+            .and_then(|env_config| env_config.network.as_deref())
+            .or(config.network.as_deref())
+    }
+
+    /// Returns true if the environment's home and work directories are bind
+    /// mounts, or false if they're Docker volumes.
+    ///
+    /// The environment's own `bind_mounts` setting takes precedence. Next, an
+    /// environment whose bind-mounted directories exist keeps using them,
+    /// such as one created with `cub new --bind-mounts`. Otherwise, this
+    /// follows the global setting.
+    fn uses_bind_mounts(&self, env: &EnvironmentName) -> bool {
+        let config = &self.program.config.docker;
+        if let Some(bind_mounts) = config
+            .environments
+            .get(env.as_str())
+            .and_then(|env_config| env_config.bind_mounts)
+        {
+            return bind_mounts;
+        }
+        let encoded = env.as_filename();
+        config.bind_mounts
+            || self.mounts.home_dirs.join(&encoded).as_host_raw().exists()
+            || self.mounts.work_dirs.join(&encoded).as_host_raw().exists()
+    }
+
+    /// Returns true if the volumes need to be listed to find all the
+    /// environments, because some may not use bind mounts.
+    fn may_use_volumes(&self) -> bool {
+        let config = &self.program.config.docker;
+        !config.bind_mounts
+            || config
+                .environments
+                .values()
+                .any(|env_config| env_config.bind_mounts == Some(false))
+    }
+
     fn mounts(&self, env: &EnvironmentName) -> EnvMounts {
-        match &self.mounts {
-            Mounts::BindMounts {
-                home_dirs,
-                work_dirs,
-            } => {
-                let encoded = env.as_filename();
-                EnvMounts::BindMounts {
-                    host_home: home_dirs.join(&encoded),
-                    host_work: work_dirs.join(&encoded),
-                }
-            }
+        self.mounts_of_kind(env, self.uses_bind_mounts(env))
+    }
 
-            Mounts::Volumes => EnvMounts::Volumes {
+    fn mounts_of_kind(&self, env: &EnvironmentName, bind_mounts: bool) -> EnvMounts {
+        if bind_mounts {
+            let encoded = env.as_filename();
+            EnvMounts::BindMounts {
+                host_home: self.mounts.home_dirs.join(&encoded),
+                host_work: self.mounts.work_dirs.join(&encoded),
+                host_overlay: self.mounts.overlay_dirs.join(&encoded),
+            }
+        } else {
+            EnvMounts::Volumes {
                 home_volume: VolumeName::new(format!(
                     "{}{}-home",
                     self.program.config.docker.prefix,
@@ -150,17 +402,70 @@ impl Docker {
                     self.program.config.docker.prefix,
                     env.as_str()
                 )),
-            },
+                overlay_volume: VolumeName::new(format!(
+                    "{}{}-overlay",
+                    self.program.config.docker.prefix,
+                    env.as_str()
+                )),
+            }
+        }
+    }
+
+    /// Returns how to retry failed `docker` commands that are safe to repeat.
+    fn retry(&self) -> Retry {
+        Retry {
+            retries: self.program.config.docker.retries,
+            initial_delay: Duration::from_secs(1),
+        }
+    }
+
+    fn image_exists(&self, name: &ImageName, tag: &str) -> Result<bool> {
+        if let Some(api) = &self.api {
+            return self.retry().run("Docker API image inspect", || {
+                let path = format!(
+                    "/images/{}/json",
+                    api::encode(&format!("{}:{tag}", name.encoded()))
+                );
+                Ok(api
+                    .get::<serde_json::Value>(&path)
+                    .with_context(|| {
+                        format!("failed to check if Docker image {name}:{tag} exists")
+                    })?
+                    .is_some())
+            });
         }
+        self.retry().run("`docker image inspect`", || {
+            let status = self
+                .docker()
+                .args(["image", "inspect"])
+                .args(["--format", "{{ .Id }}"])
+                .arg(format!("{}:{tag}", name.encoded()))
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()?;
+            match status.code() {
+                Some(0) => Ok(true),
+                Some(1) => Ok(false),
+                _ => Err(anyhow!("`docker image inspect ...` exited with {status}")),
+            }
+            .with_context(|| format!("failed to check if Docker image {name}:{tag} exists"))
+        })
     }
 
     fn is_container(&self, name: &ContainerName) -> Result<bool> {
-        self.is_container_(name)
-            .with_context(|| format!("failed to check if {name} is an existing container"))
+        self.retry().run("`docker inspect`", || {
+            self.is_container_(name)
+                .with_context(|| format!("failed to check if {name} is an existing container"))
+        })
     }
 
     fn is_container_(&self, name: &ContainerName) -> Result<bool> {
-        let status = Command::new("docker")
+        if let Some(api) = &self.api {
+            let path = format!("/containers/{}/json", api::encode(&name.encoded()));
+            return Ok(api.get::<serde_json::Value>(&path)?.is_some());
+        }
+        let status = self
+            .docker()
             .arg("inspect")
             .args(["--type", "container"])
             .args(["--format", "{{ .Name }}"])
@@ -175,13 +480,147 @@ impl Docker {
         }
     }
 
+    fn container_networks(&self, name: &ContainerName) -> Result<Vec<EnvNetwork>> {
+        self.retry().run("`docker inspect`", || {
+            self.container_networks_(name)
+                .with_context(|| format!("failed to inspect networks of Docker container {name}"))
+        })
+    }
+
+    fn container_networks_(&self, name: &ContainerName) -> LowLevelResult<Vec<EnvNetwork>> {
+        let output = self
+            .docker()
+            .arg("inspect")
+            .args(["--type", "container"])
+            .args(["--format", "{{ json .NetworkSettings.Networks }}"])
+            .arg(name.encoded())
+            .output()?;
+        let status = output.status;
+        if !status.success() {
+            return Err(anyhow!(
+                "`docker inspect` exited with {} and stderr: {}",
+                status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+
+        #[derive(Deserialize)]
+        struct InspectNetwork {
+            #[serde(rename = "IPAddress")]
+            ip_address: String,
+        }
+        let networks: Option<BTreeMap<String, InspectNetwork>> =
+            serde_json::from_slice(&output.stdout)
+                .context("failed to parse `docker inspect` output")?;
+        Ok(networks
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, network)| EnvNetwork {
+                name,
+                ip_address: (!network.ip_address.is_empty()).then_some(network.ip_address),
+            })
+            .collect())
+    }
+
+    /// Returns Docker's data directory, as the daemon reports it.
+    fn root_dir(&self) -> Result<Option<String>> {
+        self.root_dir_()
+            .context("failed to find Docker's data directory")
+    }
+
+    fn root_dir_(&self) -> LowLevelResult<Option<String>> {
+        if let Some(api) = &self.api {
+            let info: Option<api::Info> = api.get("/info")?;
+            return Ok(info
+                .map(|info| info.docker_root_dir)
+                .filter(|dir| !dir.is_empty()));
+        }
+        let output = self
+            .docker()
+            .args(["info", "--format", "{{ .DockerRootDir }}"])
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "`docker info` exited with {} and stderr: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        let dir = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+        Ok((!dir.is_empty()).then_some(dir))
+    }
+
+    /// Returns an error unless the Docker daemon runs on this host, judging
+    /// by its host name. Otherwise, paths on this host may not exist for the
+    /// daemon, or they may refer to different files.
+    fn check_local_daemon(&self) -> Result<()> {
+        let daemon = self
+            .daemon_host_name()
+            .context("failed to find the Docker daemon's host name")?;
+        let local = rustix::system::uname();
+        let local = local.nodename().to_string_lossy();
+        match daemon {
+            Some(daemon) if daemon == local => Ok(()),
+            daemon => Err(anyhow!(
+                "the Docker daemon must run on this host ({local:?}), but it runs on {}",
+                match daemon {
+                    Some(daemon) => format!("{daemon:?}"),
+                    None => String::from("an unknown host"),
+                }
+            )),
+        }
+    }
+
+    fn daemon_host_name(&self) -> LowLevelResult<Option<String>> {
+        if let Some(api) = &self.api {
+            let info: Option<api::Info> = api.get("/info")?;
+            return Ok(info.map(|info| info.name).filter(|name| !name.is_empty()));
+        }
+        let output = self
+            .docker()
+            .args(["info", "--format", "{{ .Name }}"])
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "`docker info` exited with {} and stderr: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        let name = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+        Ok((!name.is_empty()).then_some(name))
+    }
+
     fn ps(&self) -> Result<Vec<EnvironmentName>> {
-        self.ps_().context("failed to list Docker containers")
+        self.retry().run("`docker ps`", || {
+            self.ps_().context("failed to list Docker containers")
+        })
     }
 
     fn ps_(&self) -> LowLevelResult<Vec<EnvironmentName>> {
-        let output = Command::new("docker")
-            .args(["ps", "--all", "--format", "{{ .Names }}"])
+        if let Some(api) = &self.api {
+            let filters = serde_json::json!({ "label": [self.prefix_label()] }).to_string();
+            let path = api::path("/containers/json", &[("all", "1"), ("filters", &filters)]);
+            let containers: Vec<api::Container> = api.get(&path)?.unwrap_or_default();
+            return Ok(containers
+                .into_iter()
+                .filter_map(|container| {
+                    let env = container.labels?.remove(ENVIRONMENT_LABEL)?;
+                    EnvironmentName::from_str(&env).ok()
+                })
+                .collect());
+        }
+        let output = self
+            .docker()
+            .args(["ps", "--all"])
+            .args(["--filter", &format!("label={}", self.prefix_label())])
+            .args([
+                "--format",
+                &format!("{{{{ .Label {ENVIRONMENT_LABEL:?} }}}}"),
+            ])
             .output()?;
         let status = output.status;
         if !status.success() {
@@ -196,60 +635,160 @@ impl Docker {
         let mut envs = Vec::new();
         for line in output.stdout.lines() {
             let line = line.context("could not read `docker ps` output")?;
-            if let Some(container_name) = ContainerName::decode(&line) {
-                if let Some(name) = container_name
-                    .decoded()
-                    .strip_prefix(&self.program.config.docker.prefix)
-                {
-                    if let Ok(env) = EnvironmentName::from_str(name) {
-                        envs.push(env);
-                    }
-                }
+            if let Ok(env) = EnvironmentName::from_str(&line) {
+                envs.push(env);
             }
         }
         Ok(envs)
     }
 
+    /// Returns the Debian packages that the base image always includes.
+    fn base_packages(&self) -> BTreeSet<&'static str> {
+        let mut packages: BTreeSet<&str> = BASE_PACKAGES.iter().copied().collect();
+        if self.program.config.docker.keep_alive == KeepAlive::Tini {
+            packages.insert("tini");
+        }
+        if self.program.config.ssh.enabled {
+            packages.insert("openssh-server");
+        }
+        packages
+    }
+
+    /// Keeps other threads and processes from building the base image until
+    /// the returned guards are dropped.
+    ///
+    /// Builds retag the image as `latest`, so callers should hold this from
+    /// building the image until they've started a container from it.
+    fn lock_base_image(&self) -> Result<(MutexGuard<'_, ()>, Lock)> {
+        let guard = self
+            .base_image_lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let lock = self.program.locks.lock_base_image()?;
+        Ok((guard, lock))
+    }
+
     fn build_base(&self, debian_packages: &[String]) -> LowLevelResult<()> {
-        let mut child = Command::new("docker")
-            .args(["build", "--tag", &self.base_image.encoded(), "-"])
+        let mut packages = self.base_packages();
+        packages.extend(debian_packages.iter().map(String::as_str));
+        let mut dockerfile = Vec::new();
+        write_dockerfile(
+            &mut dockerfile,
+            DockerfileArgs {
+                packages: &packages,
+                timezone: &self.timezone,
+                locales: &self.locales,
+                user: &self.user,
+                uids: &self.container_uids(),
+            },
+        )
+        .context("failed to generate Dockerfile for base image")?;
+
+        // The Dockerfile captures all the inputs to the image, so an image
+        // tagged with its hash can be reused without running `docker build`,
+        // which would otherwise re-check the apt metadata every time.
+        let key = base_image_key(&dockerfile);
+        let keyed = format!("{}:{key}", self.base_image.encoded());
+        if self.image_exists(&self.base_image, &key)? {
+            let status = self
+                .docker()
+                .args(["tag", &keyed, &self.base_image.encoded()])
+                .status()?;
+            if !status.success() {
+                return Err(anyhow!("`docker tag` exited with {status}").into());
+            }
+            if let Err(e) = self.remove_old_base_image_tags(&key) {
+                warn(e);
+            }
+            return Ok(());
+        }
+
+        let mut child = self
+            .docker()
+            .args(["build", "--tag", &self.base_image.encoded()])
+            .args(self.label_args(None))
+            .args([
+                "--label",
+                &format!("{PACKAGES_HASH_LABEL}={}", packages_hash(&packages)),
+            ])
+            .args(["--tag", &keyed, "-"])
             .stdin(Stdio::piped())
             .scoped_spawn()?;
 
         {
             let mut stdin = child.stdin().take().unwrap();
-            let mut packages: BTreeSet<&str> = BASE_PACKAGES.iter().copied().collect();
-            packages.extend(debian_packages.iter().map(String::as_str));
-            write_dockerfile(
-                &mut stdin,
-                DockerfileArgs {
-                    packages: &packages,
-                    timezone: &self.timezone,
-                    locales: &self.locales,
-                    user: &self.user,
-                    uids: &self.uids,
-                },
-            )
-            .and_then(|_| stdin.flush())
-            .context("failed to write Dockerfile for base image")?;
+            stdin
+                .write_all(&dockerfile)
+                .and_then(|_| stdin.flush())
+                .context("failed to write Dockerfile for base image")?;
         }
 
         let status = child.wait()?;
         if !status.success() {
             return Err(anyhow!("`docker build` exited with {status}").into());
         }
+        if let Err(e) = self.remove_old_base_image_tags(&key) {
+            warn(e);
+        }
+        Ok(())
+    }
+
+    /// Removes the base image's tags from earlier Dockerfiles (see
+    /// [`base_image_key`]), other than `keep`, so that their images can be
+    /// garbage collected.
+    ///
+    /// Docker refuses to remove images that containers still use. Those are
+    /// left for a later build to clean up.
+    fn remove_old_base_image_tags(&self, keep: &str) -> Result<()> {
+        let output = self
+            .docker()
+            .args(["image", "ls", "--format", "{{ .Tag }}"])
+            .arg(self.base_image.encoded())
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "`docker image ls` exited with {} and stderr: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let old = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|tag| tag.starts_with("inputs-") && *tag != keep)
+            .map(|tag| format!("{}:{tag}", self.base_image.encoded()))
+            .collect::<Vec<_>>();
+        if old.is_empty() {
+            return Ok(());
+        }
+        self.docker()
+            .args(["image", "rm"])
+            .args(&old)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
         Ok(())
     }
 
     fn spawn(&self, env_name: &EnvironmentName) -> LowLevelResult<()> {
         let container_name = self.container_from_environment(env_name);
 
-        let mut command = Command::new("docker");
+        let mut command = self.docker();
         command.arg("run");
         command.arg("--detach");
         command.args(["--env", &format!("CUBICLE={}", env_name.as_str())]);
-        command.arg("--init");
+        let keep_alive = &self.program.config.docker.keep_alive;
+        if keep_alive != &KeepAlive::Tini {
+            command.arg("--init");
+        }
         command.args(["--name", &container_name.encoded()]);
+        command.args(self.label_args(Some(env_name)));
+        if self.user_namespace() == UserNamespace::RootlessPodman {
+            command.arg("--userns=keep-id");
+        }
+        if let Some(network) = self.network(env_name) {
+            command.args(["--network", network]);
+        }
         command.arg("--rm");
         if let Some(seccomp_json) = &self.program.config.docker.seccomp {
             command.args([
@@ -257,6 +796,14 @@ impl Docker {
                 &format!("seccomp={}", seccomp_json.display()),
             ]);
         }
+        if let Some(profile) = &self.program.config.docker.apparmor {
+            command.args(["--security-opt", &format!("apparmor={profile}")]);
+        }
+        if self.program.config.docker.storage_opt_quota {
+            if let Some(quota) = &self.program.config.disk_quota {
+                command.args(["--storage-opt", &format!("size={}", quota.size)]);
+            }
+        }
         // The default `/dev/shm` is limited to only 64 MiB under
         // Docker (v20.10.5), which causes many crashes in Chromium
         // and Electron-based programs. See
@@ -278,61 +825,98 @@ impl Docker {
             .to_str()
             .ok_or_else(|| anyhow!("path not valid UTF-8: {:#?}", container_work))?;
 
+        let golden = self.program.golden.golden(env_name)?;
+        let worktree = self.program.worktrees.get(env_name)?;
         match &self.mounts(env_name) {
             EnvMounts::BindMounts {
                 host_home,
                 host_work,
+                ..
             } => {
-                command.args([
-                    "--mount",
-                    &format!(
-                        r#""type=bind","source={}","target={}""#,
-                        host_home
-                            .as_host_raw()
-                            .to_str()
-                            .ok_or_else(|| anyhow!("path not valid UTF-8: {:#?}", host_home))?,
+                match &golden {
+                    None => command.args(self.bind_mount_args(host_home, container_home_str)?),
+                    Some(golden) => command.args(self.overlay_mount_args(
+                        env_name,
+                        golden,
                         container_home_str,
-                    ),
-                ]);
-                command.args([
-                    "--mount",
-                    &format!(
-                        r#""type=bind","source={}","target={}""#,
-                        host_work
-                            .as_host_raw()
-                            .to_str()
-                            .ok_or_else(|| anyhow!("path not valid UTF-8: {:#?}", host_work))?,
-                        container_work_str,
-                    ),
-                ]);
+                    )?),
+                };
+                if worktree.is_none() {
+                    command.args(self.bind_mount_args(host_work, container_work_str)?);
+                }
             }
 
             EnvMounts::Volumes {
                 home_volume,
                 work_volume,
+                ..
             } => {
-                command.args([
-                    "--mount",
-                    &format!(
-                        r#""type=volume","source={}","target={}""#,
-                        home_volume.encoded(),
+                match &golden {
+                    None => command.args([
+                        "--mount",
+                        &format!(
+                            r#""type=volume","source={}","target={}""#,
+                            home_volume.encoded(),
+                            container_home_str,
+                        ),
+                    ]),
+                    Some(golden) => command.args(self.overlay_mount_args(
+                        env_name,
+                        golden,
                         container_home_str,
-                    ),
-                ]);
-                command.args([
-                    "--mount",
-                    &format!(
-                        r#""type=volume","source={}","target={}""#,
-                        work_volume.encoded(),
-                        container_work_str,
-                    ),
-                ]);
+                    )?),
+                };
+                if worktree.is_none() {
+                    command.args([
+                        "--mount",
+                        &format!(
+                            r#""type=volume","source={}","target={}""#,
+                            work_volume.encoded(),
+                            container_work_str,
+                        ),
+                    ]);
+                }
+            }
+        }
+        if let Some(worktree) = &worktree {
+            // The worktree's `.git` file refers to the repository's git
+            // directory by its host path.
+            command.args(self.bind_mount_args(&worktree.path, container_work_str)?);
+            for mount in worktree.prepare_git_mounts()? {
+                let path_str = mount
+                    .path
+                    .as_host_raw()
+                    .to_str()
+                    .ok_or_else(|| anyhow!("path not valid UTF-8: {:#?}", mount.path))?;
+                if mount.writable {
+                    command.args(self.bind_mount_args(&mount.path, path_str)?);
+                } else {
+                    command.args(self.ro_bind_mount_args(&mount.path, path_str)?);
+                }
             }
         }
+        // `run_` keeps the X11 authorization file in this directory up to
+        // date.
+        let x11_dir = x11::host_dir(&self.program.paths, env_name);
+        std::fs::create_dir_all(x11_dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {x11_dir}"))?;
+        command.args(self.bind_mount_args(&x11_dir, x11::ENV_DIR)?);
+        if self.program.config.host_open.enabled {
+            let dir = host_open::host_dir(&self.program.paths, env_name);
+            host_open::create_pipe(&dir)?;
+            command.args(self.bind_mount_args(&dir, host_open::ENV_DIR)?);
+        }
+        for (dir, target) in caches::mounts(&self.program)? {
+            command.args(self.bind_mount_args(&dir, &target)?);
+        }
 
         command.arg("--workdir").arg(container_work.as_env_raw());
         command.arg(self.base_image.encoded());
-        command.args(["sleep", "90d"]);
+        match keep_alive {
+            KeepAlive::Sleep => command.args(["sleep", "infinity"]),
+            KeepAlive::Tini => command.args(["tini", "--", "sleep", "infinity"]),
+            KeepAlive::Command(keep_alive) => command.args(keep_alive),
+        };
         command.stdout(Stdio::null());
         let status = command.status()?;
         if status.success() {
@@ -342,6 +926,141 @@ impl Docker {
         }
     }
 
+    /// Returns the `docker run` arguments to bind mount `source` from the host
+    /// at `target` in the container.
+    fn bind_mount_args(&self, source: &HostPath, target: &str) -> LowLevelResult<[String; 2]> {
+        self.bind_mount_args_(source, target, false)
+    }
+
+    /// Like [`Self::bind_mount_args`], but the mount is read-only.
+    fn ro_bind_mount_args(&self, source: &HostPath, target: &str) -> LowLevelResult<[String; 2]> {
+        self.bind_mount_args_(source, target, true)
+    }
+
+    fn bind_mount_args_(
+        &self,
+        source: &HostPath,
+        target: &str,
+        readonly: bool,
+    ) -> LowLevelResult<[String; 2]> {
+        let source_str = source
+            .as_host_raw()
+            .to_str()
+            .ok_or_else(|| anyhow!("path not valid UTF-8: {:#?}", source))?;
+        match self.program.config.docker.selinux_relabel {
+            None => Ok([
+                String::from("--mount"),
+                format!(
+                    r#""type=bind","source={source_str}","target={target}"{}"#,
+                    if readonly { r#","readonly""# } else { "" }
+                ),
+            ]),
+            // `--mount` has no way to request relabeling, so this uses the
+            // older `--volume` syntax.
+            Some(relabel) => {
+                if source_str.contains(':') || target.contains(':') {
+                    return Err(anyhow!(
+                        "cannot bind mount {source_str:?} at {target:?} with SELinux \
+                        relabeling because one of the paths contains a colon"
+                    )
+                    .into());
+                }
+                let option = match relabel {
+                    SelinuxRelabel::Shared => "z",
+                    SelinuxRelabel::Private => "Z",
+                };
+                Ok([
+                    String::from("--volume"),
+                    format!(
+                        "{source_str}:{target}:{}{option}",
+                        if readonly { "ro," } else { "" }
+                    ),
+                ])
+            }
+        }
+    }
+
+    /// Returns the arguments to `docker run` to mount an overlay at `target`,
+    /// with the golden environment's home directory as the read-only lower
+    /// layer and the instance's home directory as the upper layer.
+    ///
+    /// The Docker daemon mounts the overlay using its local volume driver, so
+    /// the layers are given as paths on the daemon's host. For bind mounts,
+    /// those are paths on this host, so the daemon must run here too.
+    fn overlay_mount_args(
+        &self,
+        env_name: &EnvironmentName,
+        golden: &EnvironmentName,
+        target: &str,
+    ) -> LowLevelResult<[String; 2]> {
+        let mountpoint = |volume: &VolumeName, path: Option<String>| -> Result<String> {
+            path.ok_or_else(|| anyhow!("Docker volume {volume} does not exist"))
+        };
+        let host_path = |path: HostPath| -> Result<String> {
+            path.as_host_raw()
+                .to_str()
+                .map(String::from)
+                .ok_or_else(|| anyhow!("path not valid UTF-8: {path}"))
+        };
+        let (lower, upper, work) = match (self.mounts(golden), self.mounts(env_name)) {
+            (
+                EnvMounts::BindMounts {
+                    host_home: lower, ..
+                },
+                EnvMounts::BindMounts {
+                    host_home: upper,
+                    host_overlay: work,
+                    ..
+                },
+            ) => {
+                self.check_local_daemon().with_context(|| {
+                    format!("cannot create overlay for instance {env_name} of {golden}")
+                })?;
+                (host_path(lower)?, host_path(upper)?, host_path(work)?)
+            }
+            (
+                EnvMounts::Volumes {
+                    home_volume: lower, ..
+                },
+                EnvMounts::Volumes {
+                    home_volume: upper,
+                    overlay_volume: work,
+                    ..
+                },
+            ) => {
+                let [lower_path, upper_path, work_path] =
+                    self.volume_mountpoints([&lower, &upper, &work])?;
+                (
+                    mountpoint(&lower, lower_path)?,
+                    mountpoint(&upper, upper_path)?,
+                    mountpoint(&work, work_path)?,
+                )
+            }
+            _ => {
+                return Err(anyhow!(
+                    "environment {env_name} and its golden environment {golden} must both \
+                    use bind mounts or both use volumes"
+                )
+                .into())
+            }
+        };
+        for path in [&lower, &upper, &work] {
+            if path.contains([',', ':', '"']) {
+                return Err(anyhow!(
+                    "cannot use {path:?} as an overlay layer because it contains a \
+                    comma, colon, or quote"
+                )
+                .into());
+            }
+        }
+        Ok([
+            String::from("--mount"),
+            format!(
+                r#""type=volume","target={target}","volume-driver=local","volume-opt=type=overlay","volume-opt=device=overlay","volume-opt=o=lowerdir={lower},upperdir={upper},workdir={work}""#
+            ),
+        ])
+    }
+
     fn init(
         &self,
         env_name: &EnvironmentName,
@@ -352,15 +1071,27 @@ impl Docker {
         }: &Init,
     ) -> Result<()> {
         let container_name = self.container_from_environment(env_name);
-        self.build_base(debian_packages)
-            .with_context(|| format!("failed to build {} Docker image", self.base_image))?;
+        let build = || {
+            self.build_base(debian_packages)
+                .with_context(|| format!("failed to build {} Docker image", self.base_image))
+        };
+        let base_image_lock = self.lock_base_image()?;
+        self.program.timings.time(Phase::BuildBaseImage, || {
+            if self.program.config.docker.retry_builds {
+                self.retry().run("`docker build`", build)
+            } else {
+                build()
+            }
+        })?;
         self.spawn(env_name)
             .with_context(|| format!("failed to start Docker container {container_name}"))?;
+        drop(base_image_lock);
 
         let script_path = "../.cubicle-init";
 
         let copy_init = || -> Result<()> {
-            let mut child = Command::new("docker")
+            let mut child = self
+                .docker()
                 .arg("exec")
                 .arg("--interactive")
                 .arg(container_name.encoded())
@@ -389,9 +1120,10 @@ impl Docker {
             format!("failed to copy init script into Docker container {container_name}")
         })?;
 
-        self.copy_seeds(&container_name, seeds).with_context(|| {
-            format!("failed to copy package seeds into Docker container {container_name}")
-        })?;
+        self.copy_seeds(env_name, &container_name, seeds)
+            .with_context(|| {
+                format!("failed to copy package seeds into Docker container {container_name}")
+            })?;
 
         self.run_(
             env_name,
@@ -402,14 +1134,41 @@ impl Docker {
         )
     }
 
-    fn list_volumes(&self) -> Result<Vec<VolumeName>> {
-        self.list_volumes_()
-            .context("failed to list Docker volumes")
+    /// Returns the names of the environments that have Docker volumes.
+    fn volume_environments(&self) -> Result<BTreeSet<EnvironmentName>> {
+        self.retry().run("`docker volume ls`", || {
+            self.volume_environments_()
+                .context("failed to list Docker volumes")
+        })
     }
 
-    fn list_volumes_(&self) -> LowLevelResult<Vec<VolumeName>> {
-        let output = Command::new("docker")
-            .args(["volume", "ls", "--format", "{{ .Name }}"])
+    fn volume_environments_(&self) -> LowLevelResult<BTreeSet<EnvironmentName>> {
+        let prefix = &self.program.config.docker.prefix;
+        if let Some(api) = &self.api {
+            let list: api::VolumeList = api
+                .get("/volumes")?
+                .ok_or_else(|| anyhow!("Docker API returned 404 for GET /volumes"))?;
+            return Ok(list
+                .volumes
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|volume| {
+                    let labels = volume.labels.unwrap_or_default();
+                    let label = |key| labels.get(key).map(String::as_str).unwrap_or_default();
+                    volume_environment(
+                        &volume.name,
+                        label(PREFIX_LABEL),
+                        label(ENVIRONMENT_LABEL),
+                        prefix,
+                    )
+                })
+                .collect());
+        }
+        let output = self.docker()
+            .args(["volume", "ls", "--format"])
+            .arg(format!(
+                "{{{{ .Name }}}}\t{{{{ .Label {PREFIX_LABEL:?} }}}}\t{{{{ .Label {ENVIRONMENT_LABEL:?} }}}}"
+            ))
             .output()?;
         let status = output.status;
         if !status.success() {
@@ -421,80 +1180,106 @@ impl Docker {
             .into());
         }
 
-        output
-            .stdout
-            .lines()
-            .filter_map(|line| {
-                line.map(|name| VolumeName::decode(&name))
-                    .context("failed to read `docker volume ls` output")
-                    .map_err(|e| e.into())
-                    .transpose()
-            })
-            .collect()
-    }
-
-    fn volume_exists(&self, name: &VolumeName) -> Result<bool> {
-        self.volume_mountpoint(name).map(|o| o.is_some())
+        let stdout =
+            String::from_utf8(output.stdout).context("failed to read `docker volume ls` output")?;
+        Ok(parse_volume_environments(&stdout, prefix))
     }
 
-    fn volume_mountpoint(&self, name: &VolumeName) -> Result<Option<HostPath>> {
-        self.volume_mountpoint_(name)
-            .with_context(|| format!("failed to get mountpoint of Docker volume {name}"))
+    /// Returns the mountpoint of each volume, or `None` for volumes that
+    /// don't exist.
+    ///
+    /// The mountpoints are paths on the Docker daemon's host, which isn't
+    /// this host when Docker runs in a VM, as with Docker Desktop.
+    ///
+    /// This runs a single `docker volume ls`, since each `docker` invocation
+    /// can be slow (especially on macOS).
+    fn volume_mountpoints<const N: usize>(
+        &self,
+        names: [&VolumeName; N],
+    ) -> Result<[Option<String>; N]> {
+        self.retry().run("`docker volume ls`", || {
+            self.volume_mountpoints_(names).with_context(|| {
+                format!(
+                    "failed to get mountpoints of Docker volumes {}",
+                    names.map(|name| name.to_string()).join(", ")
+                )
+            })
+        })
     }
 
-    fn volume_mountpoint_(&self, name: &VolumeName) -> LowLevelResult<Option<HostPath>> {
-        let output = Command::new("docker")
-            .arg("volume")
-            .arg("inspect")
-            .args(["--format", "{{ .Mountpoint }}"])
-            .arg(name.encoded())
+    fn volume_mountpoints_<const N: usize>(
+        &self,
+        names: [&VolumeName; N],
+    ) -> LowLevelResult<[Option<String>; N]> {
+        let encoded = names.map(|name| name.encoded());
+        if let Some(api) = &self.api {
+            let mut mountpoints = [(); N].map(|_| None);
+            for (mountpoint, name) in mountpoints.iter_mut().zip(&encoded) {
+                let volume: Option<api::Volume> =
+                    api.get(&format!("/volumes/{}", api::encode(name)))?;
+                *mountpoint = volume.map(|volume| volume.mountpoint);
+            }
+            return Ok(mountpoints);
+        }
+        let mut command = self.docker();
+        command.args(["volume", "ls"]);
+        // The name filter also matches substrings, so the output is checked
+        // for exact matches below.
+        for name in &encoded {
+            command.args(["--filter", &format!("name={name}")]);
+        }
+        let output = command
+            .args(["--format", "{{ .Name }}\t{{ .Mountpoint }}"])
             .output()?;
         let status = output.status;
         if !status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
-            // Errors for missing volume on Debian 12 with docker.io
-            // 20.10.24+dfsg1 are like:
-            //
-            // ```
-            // [blank line]
-            // Error: No such volume: [name]
-            // ```
-            //
-            // However, on ubuntu-20.04 and macos-12 in CI, they're like:
-            //
-            // ```
-            // Error response from daemon: get NAME: no such volume
-            // ```
-            if status.code() == Some(1)
-                && (stderr.starts_with("Error: No such volume")
-                    || stderr.ends_with(": no such volume"))
-            {
-                return Ok(None);
-            }
             return Err(anyhow!(
-                "`docker volume inspect` exited with {status} and stderr: {stderr}"
+                "`docker volume ls` exited with {status} and stderr: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
             )
             .into());
         }
-        let stdout = String::from_utf8(output.stdout)
-            .context("failed to read `docker volume inspect` output")?
-            .trim()
-            .to_owned();
-        Ok(Some(HostPath::try_from(stdout)?))
+
+        let stdout =
+            String::from_utf8(output.stdout).context("failed to read `docker volume ls` output")?;
+        let mut mountpoints = [(); N].map(|_| None);
+        for line in stdout.lines() {
+            let Some((name, mountpoint)) = line.split_once('\t') else {
+                return Err(anyhow!("unexpected output from `docker volume ls`: {line:?}").into());
+            };
+            for (i, _) in encoded.iter().enumerate().filter(|(_, e)| *e == name) {
+                mountpoints[i] = Some(mountpoint.to_owned());
+            }
+        }
+        Ok(mountpoints)
     }
 
-    fn volume_du(&self, name: &VolumeName) -> Result<DirSummary> {
-        self.volume_du_(name)
-            .with_context(|| format!("failed to summarize disk usage of Docker volume {name}"))
+    /// Summarizes the disk usage of each volume.
+    ///
+    /// This runs `du` in a single container with all the volumes mounted,
+    /// since starting a container is slow.
+    fn volume_du<const N: usize>(&self, names: [&VolumeName; N]) -> Result<[DirSummary; N]> {
+        self.volume_du_(names).with_context(|| {
+            format!(
+                "failed to summarize disk usage of Docker volumes {}",
+                names.map(|name| name.to_string()).join(", ")
+            )
+        })
     }
-    fn volume_du_(&self, name: &VolumeName) -> LowLevelResult<DirSummary> {
-        let output = Command::new("docker")
-            .arg("run")
-            .arg("--mount")
-            .arg(format!(
-                r#""type=volume","source={}","target=/v""#,
+
+    fn volume_du_<const N: usize>(
+        &self,
+        names: [&VolumeName; N],
+    ) -> LowLevelResult<[DirSummary; N]> {
+        let mut command = self.docker();
+        command.arg("run");
+        for (i, name) in names.iter().enumerate() {
+            command.arg("--mount").arg(format!(
+                r#""type=volume","source={}","target=/v/{i}""#,
                 name.encoded()
-            ))
+            ));
+        }
+        let output = command
             .arg("--rm")
             .arg("debian:12")
             .arg("du")
@@ -502,12 +1287,9 @@ impl Docker {
             .arg("--summarize")
             .arg("--time")
             .arg("--time-style=+%s")
-            .arg("/v")
+            .args((0..N).map(|i| format!("/v/{i}")))
             .output()?;
 
-        // ignore permissions errors
-        let errors = !&output.stderr.is_empty();
-
         let status = output.status;
         if !status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
@@ -519,42 +1301,27 @@ impl Docker {
 
         let stdout = String::from_utf8(output.stdout)
             .context("failed to read `docker run ... -- du ...` output")?;
-
-        static RE: OnceLock<Regex> = OnceLock::new();
-        let re = RE.get_or_init(|| {
-            RegexBuilder::new(r#"^(?P<size>[0-9]+)\t(?P<mtime>[0-9]+)\t/v$"#)
-                .build()
-                .unwrap()
-        });
-
-        match re.captures(stdout.trim_end()) {
-            Some(caps) => {
-                let size = caps.name("size").unwrap().as_str();
-                let size = u64::from_str(size).unwrap();
-                let mtime = caps.name("mtime").unwrap().as_str();
-                let mtime = u64::from_str(mtime).unwrap();
-                let mtime = UNIX_EPOCH + Duration::from_secs(mtime);
-                Ok(DirSummary {
-                    errors,
-                    total_size: size,
-                    last_modified: mtime,
-                })
-            }
-            None => {
-                Err(anyhow!("unexpected output from `docker run ... -- du ...`: {stdout:?}").into())
-            }
-        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Ok(parse_volume_du::<N>(&stdout, &stderr).ok_or_else(|| {
+            anyhow!("unexpected output from `docker run ... -- du ...`: {stdout:?}")
+        })?)
     }
 
-    fn ensure_volume_exists(&self, name: &VolumeName) -> Result<()> {
-        self.ensure_volume_exists_(name)
+    fn ensure_volume_exists(&self, env_name: &EnvironmentName, name: &VolumeName) -> Result<()> {
+        self.ensure_volume_exists_(env_name, name)
             .with_context(|| format!("failed to create Docker volume {name}"))
     }
 
-    fn ensure_volume_exists_(&self, name: &VolumeName) -> LowLevelResult<()> {
-        let status = Command::new("docker")
+    fn ensure_volume_exists_(
+        &self,
+        env_name: &EnvironmentName,
+        name: &VolumeName,
+    ) -> LowLevelResult<()> {
+        let status = self
+            .docker()
             .arg("volume")
             .arg("create")
+            .args(self.label_args(Some(env_name)))
             .arg(name.encoded())
             .stdout(Stdio::null())
             .status()?;
@@ -570,7 +1337,8 @@ impl Docker {
     }
 
     fn ensure_no_volume_(&self, name: &VolumeName) -> LowLevelResult<()> {
-        let status = Command::new("docker")
+        let status = self
+            .docker()
             .arg("volume")
             .arg("rm")
             .arg("--force")
@@ -592,7 +1360,8 @@ impl Docker {
         // Note: This used to use `docker cp`. That's a bit annoying because (1) it
         // requires a container to exist, and (2) Docker creates a tarfile when
         // using stdout.
-        let mut child = Command::new("docker")
+        let mut child = self
+            .docker()
             .arg("run")
             .arg("--mount")
             .arg(format!(
@@ -619,14 +1388,26 @@ impl Docker {
 
     fn copy_seeds(
         &self,
+        env_name: &EnvironmentName,
         container_name: &ContainerName,
         seeds: &Vec<HostPath>,
     ) -> LowLevelResult<()> {
         if seeds.is_empty() {
             return Ok(());
         }
-        println!("Copying/extracting seed tarball");
+        self.program.event(Event::CopyingSeeds(env_name.clone()));
+        self.program
+            .timings
+            .time(Phase::CopySeeds(env_name.clone()), || {
+                self.copy_seeds_(container_name, seeds)
+            })
+    }
 
+    fn copy_seeds_(
+        &self,
+        container_name: &ContainerName,
+        seeds: &Vec<HostPath>,
+    ) -> LowLevelResult<()> {
         // Use pv from inside the container since it may not be
         // installed on the host. Since it's reading from a stream, it
         // needs to know the total size to display a good progress bar.
@@ -643,7 +1424,8 @@ impl Docker {
             size
         });
 
-        let mut child = Command::new("docker")
+        let mut child = self
+            .docker()
             .arg("exec")
             .arg("--interactive")
             .arg(container_name.encoded())
@@ -677,11 +1459,36 @@ impl Docker {
         Ok(())
     }
 
+    /// Returns true if a missing container can be started again without
+    /// re-initializing the environment.
+    ///
+    /// This is not possible with `strict_debian_packages`, since the Debian
+    /// packages were installed in the old container.
+    fn can_respawn(&self) -> bool {
+        !self.program.config.docker.strict_debian_packages
+    }
+
+    /// Starts a new container for the environment if its previous container
+    /// exited.
+    fn ensure_running(&self, env_name: &EnvironmentName) -> Result<()> {
+        let container_name = self.container_from_environment(env_name);
+        if !self.is_container(&container_name)? {
+            // The container exits (and is removed) if its keep-alive process
+            // exits, and `exists` reports such environments as existing.
+            assert!(self.can_respawn());
+            self.program
+                .event(Event::RestartingContainer(env_name.clone()));
+            self.spawn(env_name)
+                .with_context(|| format!("failed to restart Docker container {container_name}"))?;
+        }
+        Ok(())
+    }
+
     fn run_(&self, env_name: &EnvironmentName, run_command: &RunnerCommand) -> Result<()> {
         let container_name = self.container_from_environment(env_name);
-        assert!(self.is_container(&container_name)?);
+        self.ensure_running(env_name)?;
 
-        let mut command = Command::new("docker");
+        let mut command = self.docker();
         command.arg("exec");
 
         command
@@ -694,12 +1501,24 @@ impl Docker {
         {
             command.args(["--env", var]);
         }
+        let x11_dir = x11::host_dir(&self.program.paths, env_name);
+        match x11::write_xauthority(&x11_dir) {
+            Ok(true) => {
+                command
+                    .arg("--env")
+                    .arg(format!("XAUTHORITY={}", x11::ENV_XAUTHORITY));
+            }
+            Ok(false) => {}
+            Err(e) => warn(e.context("failed to set up X11 authorization")),
+        }
 
         match run_command {
-            RunnerCommand::Interactive => {}
-            RunnerCommand::Exec { env_vars, .. } => {
+            RunnerCommand::Pipe { .. } => {}
+            RunnerCommand::Interactive { env_vars, .. } | RunnerCommand::Exec { env_vars, .. } => {
+                // Pass the values through the environment of the `docker`
+                // process, so they don't show up in its arguments.
                 for (var, value) in *env_vars {
-                    command.arg("--env").arg(format!("{}={}", var, value));
+                    command.env(var, value).arg("--env").arg(var);
                 }
             }
         }
@@ -708,16 +1527,22 @@ impl Docker {
 
         // If we really don't have a TTY, Docker will exit with status 1 when
         // we request one.
-        if io::stdin().is_terminal() || io::stdout().is_terminal() || io::stderr().is_terminal() {
+        let pipe = matches!(run_command, RunnerCommand::Pipe { .. });
+        if !pipe
+            && (io::stdin().is_terminal()
+                || io::stdout().is_terminal()
+                || io::stderr().is_terminal())
+        {
             command.arg("--tty");
         }
 
         command.arg(container_name.encoded());
-        command.args([&self.program.shell, "-l"]);
         match run_command {
-            RunnerCommand::Interactive => {}
-            RunnerCommand::Exec { command: exec, .. } => {
-                command.arg("-c");
+            RunnerCommand::Interactive { shell, .. } => {
+                command.args(interactive_shell_command(*shell, &self.program.shell));
+            }
+            RunnerCommand::Exec { command: exec, .. } | RunnerCommand::Pipe { command: exec } => {
+                command.args([&self.program.shell, "-l", "-c"]);
                 command.arg(shlex::try_join(exec.iter().map(|a| a.as_str())).expect("TODO"));
             }
         }
@@ -784,30 +1609,87 @@ impl Runner for Docker {
         }
     }
 
-    fn create(&self, env_name: &EnvironmentName, init: &Init) -> Result<()> {
+    fn backup(&self, env_name: &EnvironmentName, w: &mut dyn io::Write) -> Result<()> {
+        // This runs tar in the container, since the home directory may be a
+        // volume and its files may not be readable from the host.
+        self.ensure_running(env_name)?;
         let container_name = self.container_from_environment(env_name);
-        if self.is_container(&container_name)? {
-            return Err(anyhow!("Docker container {container_name} already exists"));
+        let mut child = self
+            .docker()
+            .arg("exec")
+            .arg(container_name.encoded())
+            .args(["sh", "-c", "tar --create --directory ~ --file - ."])
+            .stdout(Stdio::piped())
+            .scoped_spawn()?;
+        let mut stdout = child.stdout().take().unwrap();
+        io::copy(&mut stdout, w).context("error reading/writing data")?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!(
+                "`docker exec ... tar --create` exited with {status}"
+            ));
         }
-        match &self.mounts(env_name) {
-            EnvMounts::BindMounts {
-                host_home,
-                host_work,
-            } => {
-                std::fs::create_dir_all(host_home.as_host_raw()).todo_context()?;
-                std::fs::create_dir_all(host_work.as_host_raw()).todo_context()?;
-            }
+        Ok(())
+    }
 
-            EnvMounts::Volumes {
-                home_volume,
-                work_volume,
-            } => {
-                self.ensure_volume_exists(home_volume)?;
-                self.ensure_volume_exists(work_volume)?;
-            }
+    fn save_home_paths(
+        &self,
+        env_name: &EnvironmentName,
+        paths: &[PathBuf],
+        w: &mut dyn io::Write,
+    ) -> Result<()> {
+        // Like `backup`, this runs tar in the container. Missing paths only
+        // produce warnings with `--ignore-failed-read`.
+        self.ensure_running(env_name)?;
+        let container_name = self.container_from_environment(env_name);
+        let mut child = self
+            .docker()
+            .arg("exec")
+            .arg(container_name.encoded())
+            .args([
+                "sh",
+                "-c",
+                r#"cd ~ && tar --create --ignore-failed-read --file - -- "$@""#,
+                "sh",
+            ])
+            .args(paths)
+            .stdout(Stdio::piped())
+            .scoped_spawn()?;
+        let mut stdout = child.stdout().take().unwrap();
+        io::copy(&mut stdout, w).context("error reading/writing data")?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!(
+                "`docker exec ... tar --create` exited with {status}"
+            ));
         }
+        Ok(())
+    }
 
-        self.init(env_name, init)
+    fn create(&self, env_name: &EnvironmentName, init: &Init) -> Result<()> {
+        // Instances use the same kind of mounts as their golden environment,
+        // which their home directory overlays.
+        let bind_mounts = match self.program.golden.golden(env_name)? {
+            Some(golden) => self.uses_bind_mounts(&golden),
+            None => self.uses_bind_mounts(env_name),
+        };
+        self.create_(env_name, init, bind_mounts)
+    }
+
+    fn create_with_bind_mounts(&self, env_name: &EnvironmentName, init: &Init) -> Result<()> {
+        let env_config = self
+            .program
+            .config
+            .docker
+            .environments
+            .get(env_name.as_str());
+        if env_config.and_then(|env_config| env_config.bind_mounts) == Some(false) {
+            return Err(anyhow!(
+                "environment {env_name} is configured with `bind_mounts = false` in \
+                `[docker.environments.{env_name}]`"
+            ));
+        }
+        self.create_(env_name, init, true)
     }
 
     fn exists(&self, env_name: &EnvironmentName) -> Result<EnvironmentExists> {
@@ -820,6 +1702,7 @@ impl Runner for Docker {
             EnvMounts::BindMounts {
                 host_home,
                 host_work,
+                ..
             } => {
                 has_home_dir = try_exists(host_home).todo_context()?;
                 has_work_dir = try_exists(host_work).todo_context()?;
@@ -828,26 +1711,31 @@ impl Runner for Docker {
             EnvMounts::Volumes {
                 home_volume,
                 work_volume,
+                ..
             } => {
-                has_home_dir = self.volume_exists(home_volume)?;
-                has_work_dir = self.volume_exists(work_volume)?;
+                let [home, work] = self.volume_mountpoints([home_volume, work_volume])?;
+                has_home_dir = home.is_some();
+                has_work_dir = work.is_some();
             }
         }
 
         use EnvironmentExists::*;
-        Ok(if is_container && has_home_dir && has_work_dir {
-            FullyExists
-        } else if is_container || has_home_dir || has_work_dir {
-            PartiallyExists
-        } else {
-            NoEnvironment
-        })
+        Ok(
+            if (is_container || self.can_respawn()) && has_home_dir && has_work_dir {
+                FullyExists
+            } else if is_container || has_home_dir || has_work_dir {
+                PartiallyExists
+            } else {
+                NoEnvironment
+            },
+        )
     }
 
     fn stop(&self, env_name: &EnvironmentName) -> Result<()> {
         let container_name = self.container_from_environment(env_name);
         let do_stop = || {
-            let status = Command::new("docker")
+            let status = self
+                .docker()
                 .args(["rm", "--force", &container_name.encoded()])
                 .stdout(Stdio::null())
                 .status()?;
@@ -859,52 +1747,172 @@ impl Runner for Docker {
         do_stop().with_context(|| format!("failed to remove Docker container {container_name}"))
     }
 
-    fn list(&self) -> Result<Vec<EnvironmentName>> {
-        let mut envs = BTreeSet::from_iter(self.ps()?);
+    fn restarts_after_stop(&self) -> bool {
+        self.can_respawn()
+    }
 
-        match &self.mounts {
-            Mounts::BindMounts {
-                home_dirs,
-                work_dirs,
-            } => {
-                for name in try_iterdir_dirs(home_dirs)? {
-                    let env = EnvironmentName::from_filename(&name).with_context(|| {
-                        format!(
-                            "error parsing environment name from path {}",
-                            home_dirs.join(&name)
-                        )
-                    })?;
-                    envs.insert(env);
-                }
+    fn start(&self, env_name: &EnvironmentName) -> Result<()> {
+        self.ensure_running(env_name)
+    }
 
-                for name in try_iterdir_dirs(work_dirs)? {
-                    let env = EnvironmentName::from_filename(&name).with_context(|| {
-                        format!(
-                            "error parsing environment name from path {}",
-                            work_dirs.join(&name)
-                        )
-                    })?;
-                    envs.insert(env);
-                }
+    fn supports_golden(&self) -> bool {
+        true
+    }
+
+    fn supports_bind_mounts(&self) -> bool {
+        true
+    }
+
+    fn supports_worktrees(&self) -> bool {
+        true
+    }
+
+    fn supports_host_open(&self) -> bool {
+        true
+    }
+
+    fn supports_caches(&self) -> bool {
+        true
+    }
+
+    fn available_debian_packages(&self) -> Result<Option<BTreeSet<String>>> {
+        // The base image has the apt package lists, but building it just to
+        // check the package names would take too long.
+        if !self.image_exists(&self.base_image, "latest")? {
+            return Ok(None);
+        }
+        let list = || -> LowLevelResult<BTreeSet<String>> {
+            let output = self
+                .docker()
+                .args(["run", "--rm"])
+                .arg(self.base_image.encoded())
+                .args(["apt-cache", "pkgnames"])
+                .output()?;
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "`docker run ... apt-cache pkgnames` exited with {} and stderr: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )
+                .into());
+            }
+            Ok(apt::parse_pkgnames(&output.stdout)?)
+        };
+        list()
+            .with_context(|| {
+                format!(
+                    "failed to list Debian packages available in {} Docker image",
+                    self.base_image
+                )
+            })
+            .map(Some)
+    }
+
+    fn base_images(&self) -> Result<Vec<String>> {
+        if !self.image_exists(&self.base_image, "latest")? {
+            return Ok(Vec::new());
+        }
+        Ok(vec![self.base_image.encoded()])
+    }
+
+    fn base_debian_packages(&self) -> BTreeSet<String> {
+        self.base_packages().into_iter().map(String::from).collect()
+    }
+
+    fn debian_packages(&self, env_name: &EnvironmentName) -> Result<Option<BTreeSet<String>>> {
+        // A stopped environment gets the latest base image when it restarts.
+        let container_name = self.container_from_environment(env_name);
+        let object = if self.is_container(&container_name)? {
+            container_name.encoded()
+        } else if self.image_exists(&self.base_image, "latest")? {
+            self.base_image.encoded()
+        } else {
+            return Ok(None);
+        };
+        let output = self
+            .docker()
+            .arg("inspect")
+            .args(["--format", "{{ json .Config.Labels }}"])
+            .arg(&object)
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "`docker inspect` exited with {} and stderr: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let labels: Option<BTreeMap<String, String>> = serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("failed to parse labels of {object:?}"))?;
+        // Images built by older versions don't have the label.
+        let Some(label) = labels.unwrap_or_default().remove(DEBIAN_PACKAGES_LABEL) else {
+            return Ok(None);
+        };
+        let base = self.base_packages();
+        Ok(Some(
+            label
+                .split_whitespace()
+                .filter(|package| !base.contains(package))
+                .map(String::from)
+                .collect(),
+        ))
+    }
+
+    fn image(&self, env_name: &EnvironmentName) -> Result<Option<String>> {
+        // Each environment's container may come from a different build of the
+        // base image, with different Debian packages. A stopped environment
+        // gets the latest base image when it restarts.
+        let container_name = self.container_from_environment(env_name);
+        if !self.is_container(&container_name)? {
+            if !self.image_exists(&self.base_image, "latest")? {
+                return Err(anyhow!(
+                    "environment {env_name} has no container and the base image \
+                    {} does not exist",
+                    self.base_image
+                ));
             }
+            return Ok(Some(self.base_image.encoded()));
+        }
+        let output = self
+            .docker()
+            .arg("inspect")
+            .args(["--type", "container"])
+            .args(["--format", "{{ .Image }}"])
+            .arg(container_name.encoded())
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "`docker inspect` exited with {} and stderr: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let image =
+            String::from_utf8(output.stdout).context("`docker inspect` output not valid UTF-8")?;
+        Ok(Some(image.trim().to_owned()))
+    }
 
-            Mounts::Volumes => {
-                for name in self.list_volumes()? {
-                    if let Some(name) = name
-                        .decoded()
-                        .strip_prefix(&self.program.config.docker.prefix)
-                    {
-                        if let Some(env) = name.strip_suffix("-home") {
-                            envs.insert(EnvironmentName::from_str(env)?);
-                        }
-                        if let Some(env) = name.strip_suffix("-work") {
-                            envs.insert(EnvironmentName::from_str(env)?);
-                        }
-                    }
-                }
+    fn list(&self) -> Result<Vec<EnvironmentName>> {
+        let mut envs = BTreeSet::from_iter(self.ps()?);
+
+        // Environments may use bind mounts or volumes, so this looks for
+        // both.
+        for dir in [&self.mounts.home_dirs, &self.mounts.work_dirs] {
+            for name in try_iterdir_dirs(dir)? {
+                let env = EnvironmentName::from_filename(&name).with_context(|| {
+                    format!(
+                        "error parsing environment name from path {}",
+                        dir.join(&name)
+                    )
+                })?;
+                envs.insert(env);
             }
         }
 
+        if self.may_use_volumes() {
+            envs.extend(self.volume_environments()?);
+        }
+
         Ok(Vec::from_iter(envs))
     }
 
@@ -913,6 +1921,7 @@ impl Runner for Docker {
             EnvMounts::BindMounts {
                 host_home: home_dir,
                 host_work: work_dir,
+                ..
             } => {
                 let home_dir_exists = try_exists(&home_dir).todo_context()?;
                 let home_dir_summary = if home_dir_exists {
@@ -939,46 +1948,337 @@ impl Runner for Docker {
             EnvMounts::Volumes {
                 home_volume,
                 work_volume,
-            } => Ok(EnvFilesSummary {
-                home_dir_path: self.volume_mountpoint(&home_volume)?,
-                home_dir: self.volume_du(&home_volume)?,
-                work_dir_path: self.volume_mountpoint(&work_volume)?,
-                work_dir: self.volume_du(&work_volume)?,
-            }),
+                ..
+            } => {
+                let [home_dir_path, work_dir_path] =
+                    self.volume_mountpoints([&home_volume, &work_volume])?;
+                // Mounting a missing volume would create it, so only the
+                // existing ones are summarized.
+                let (home_dir, work_dir) = match (&home_dir_path, &work_dir_path) {
+                    (Some(_), Some(_)) => {
+                        let [home, work] = self.volume_du([&home_volume, &work_volume])?;
+                        (home, work)
+                    }
+                    (Some(_), None) => {
+                        let [home] = self.volume_du([&home_volume])?;
+                        (home, DirSummary::new_with_errors())
+                    }
+                    (None, Some(_)) => {
+                        let [work] = self.volume_du([&work_volume])?;
+                        (DirSummary::new_with_errors(), work)
+                    }
+                    (None, None) => (DirSummary::new_with_errors(), DirSummary::new_with_errors()),
+                };
+                // The mountpoints are on the Docker daemon's host, so they're
+                // only shown if they're valid paths here.
+                Ok(EnvFilesSummary {
+                    home_dir_path: home_dir_path.and_then(|p| HostPath::try_from(p).ok()),
+                    home_dir,
+                    work_dir_path: work_dir_path.and_then(|p| HostPath::try_from(p).ok()),
+                    work_dir,
+                })
+            }
+        }
+    }
+
+    fn watch_work_dir(
+        &self,
+        env_name: &EnvironmentName,
+        path: &Path,
+        interval: Duration,
+        f: &mut dyn FnMut(FileListing) -> Result<()>,
+    ) -> Result<()> {
+        match &self.mounts(env_name) {
+            EnvMounts::BindMounts { host_work, .. } => {
+                poll_host_dir(&host_work.join(path), interval, f)
+            }
+
+            // The volume's files may not be readable from the host, so this
+            // lists them from a container. One container prints a listing
+            // every interval, each ending with an empty record, to avoid
+            // starting a container for every listing.
+            EnvMounts::Volumes { work_volume, .. } => {
+                let mut child = self
+                    .docker()
+                    .arg("run")
+                    .arg("--mount")
+                    .arg(format!(
+                        r#""type=volume","source={}","target=/v","readonly""#,
+                        work_volume.encoded()
+                    ))
+                    .arg("--rm")
+                    .args(["--workdir", "/v"])
+                    .arg("debian:12")
+                    .args([
+                        "sh",
+                        "-c",
+                        r#"while true; do
+                            find "./$1" -printf '%y\t%s\t%T@\t%P\0' || exit 1
+                            printf '\0'
+                            sleep "$2"
+                        done"#,
+                        "sh",
+                    ])
+                    .arg(path)
+                    .arg(interval.as_secs_f64().to_string())
+                    .stdout(Stdio::piped())
+                    .scoped_spawn()?;
+                let mut stdout = io::BufReader::new(child.stdout().take().unwrap());
+                let mut listing = Vec::new();
+                loop {
+                    let mut record = Vec::new();
+                    stdout
+                        .read_until(b'\0', &mut record)
+                        .context("failed to read file listing from Docker container")?;
+                    if record.is_empty() {
+                        let status = child.wait()?;
+                        return Err(anyhow!("`docker run ... find` exited with {status}"));
+                    }
+                    if record == b"\0" {
+                        f(parse_find_listing(&listing))?;
+                        listing.clear();
+                    } else {
+                        listing.extend(record);
+                    }
+                }
+            }
+        }
+    }
+
+    fn networks(&self, env_name: &EnvironmentName) -> Result<Vec<EnvNetwork>> {
+        let container_name = self.container_from_environment(env_name);
+        if !self.is_container(&container_name)? {
+            return Ok(Vec::new());
         }
+        self.container_networks(&container_name)
+    }
+
+    fn code_target(&self, name: &EnvironmentName) -> Result<CodeTarget> {
+        self.ensure_running(name)?;
+        Ok(CodeTarget::Container {
+            name: self.container_from_environment(name).encoded(),
+            user: self.user.clone(),
+            work_dir: self.container_home.join("w").as_env_raw().to_owned(),
+        })
+    }
+
+    fn username(&self, _name: &EnvironmentName) -> Result<String> {
+        Ok(self.user.clone())
     }
 
     fn reset(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
         self.stop(name)?;
+        let is_instance = self.program.golden.golden(name)?.is_some();
         match &self.mounts(name) {
-            EnvMounts::BindMounts { host_home, .. } => {
-                rmtree(host_home)?;
+            EnvMounts::BindMounts {
+                host_home,
+                host_overlay,
+                ..
+            } => {
+                self.clear_bind_dir(host_home)?;
+                self.clear_overlay_dir(host_overlay)?;
                 std::fs::create_dir(host_home.as_host_raw()).todo_context()?;
+                self.prepare_bind_dir(host_home)?;
+                if is_instance {
+                    std::fs::create_dir_all(host_overlay.as_host_raw()).todo_context()?;
+                }
             }
-            EnvMounts::Volumes { home_volume, .. } => {
+            EnvMounts::Volumes {
+                home_volume,
+                overlay_volume,
+                ..
+            } => {
                 self.ensure_no_volume(home_volume)?;
-                self.ensure_volume_exists(home_volume)?;
+                self.ensure_no_volume(overlay_volume)?;
+                self.ensure_volume_exists(name, home_volume)?;
+                if is_instance {
+                    self.ensure_volume_exists(name, overlay_volume)?;
+                }
             }
         }
         self.init(name, init)
     }
 
+    fn reseed(&self, name: &EnvironmentName, seeds: &[HostPath]) -> Result<()> {
+        self.ensure_running(name)?;
+        let container_name = self.container_from_environment(name);
+        self.copy_seeds(name, &container_name, &seeds.to_vec())
+            .with_context(|| {
+                format!("failed to copy package seeds into Docker container {container_name}")
+            })
+    }
+
+    fn inspect(&self, name: &EnvironmentName) -> Result<Vec<EnvPiece>> {
+        let container_name = self.container_from_environment(name);
+        let mut pieces = vec![EnvPiece {
+            kind: "container",
+            exists: self.is_container(&container_name)?,
+            location: container_name.to_string(),
+        }];
+        let is_instance = self.program.golden.golden(name)?.is_some();
+        match self.mounts(name) {
+            EnvMounts::BindMounts {
+                host_home,
+                host_work,
+                host_overlay,
+            } => {
+                let mut dirs = vec![("home directory", host_home), ("work directory", host_work)];
+                if is_instance {
+                    dirs.push(("overlay directory", host_overlay));
+                }
+                for (kind, path) in dirs {
+                    pieces.push(EnvPiece {
+                        kind,
+                        exists: try_exists(&path).todo_context()?,
+                        location: path.to_string(),
+                    });
+                }
+            }
+
+            EnvMounts::Volumes {
+                home_volume,
+                work_volume,
+                overlay_volume,
+            } => {
+                let [home, work, overlay] =
+                    self.volume_mountpoints([&home_volume, &work_volume, &overlay_volume])?;
+                let mut volumes = vec![
+                    ("home volume", home_volume, home),
+                    ("work volume", work_volume, work),
+                ];
+                if is_instance {
+                    volumes.push(("overlay volume", overlay_volume, overlay));
+                }
+                for (kind, volume, mountpoint) in volumes {
+                    pieces.push(EnvPiece {
+                        kind,
+                        exists: mountpoint.is_some(),
+                        location: volume.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(pieces)
+    }
+
+    fn repair(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        let container_name = self.container_from_environment(name);
+        let is_instance = self.program.golden.golden(name)?.is_some();
+        let home_missing;
+        let work_missing;
+        match &self.mounts(name) {
+            EnvMounts::BindMounts {
+                host_home,
+                host_work,
+                host_overlay,
+            } => {
+                home_missing = !try_exists(host_home).todo_context()?;
+                work_missing = !try_exists(host_work).todo_context()?;
+                for (dir, missing) in [(host_home, home_missing), (host_work, work_missing)] {
+                    if missing {
+                        std::fs::create_dir_all(dir.as_host_raw()).todo_context()?;
+                        self.prepare_bind_dir(dir)?;
+                    }
+                }
+                if is_instance {
+                    std::fs::create_dir_all(host_overlay.as_host_raw()).todo_context()?;
+                }
+            }
+
+            EnvMounts::Volumes {
+                home_volume,
+                work_volume,
+                overlay_volume,
+            } => {
+                let [home, work] = self.volume_mountpoints([home_volume, work_volume])?;
+                home_missing = home.is_none();
+                work_missing = work.is_none();
+                self.ensure_volume_exists(name, home_volume)?;
+                self.ensure_volume_exists(name, work_volume)?;
+                if is_instance {
+                    self.ensure_volume_exists(name, overlay_volume)?;
+                }
+            }
+        }
+
+        if home_missing || work_missing {
+            // The container's mounts refer to the missing directories or
+            // volumes, so it needs to be replaced.
+            self.stop(name)?;
+        }
+        if home_missing {
+            self.init(name, init)
+        } else if !self.can_respawn() && !self.is_container(&container_name)? {
+            // Keep the existing home directory, but start a new container
+            // with the Debian packages installed.
+            self.init(
+                name,
+                &Init {
+                    debian_packages: init.debian_packages.clone(),
+                    env_vars: init.env_vars.clone(),
+                    seeds: Vec::new(),
+                },
+            )
+        } else {
+            Ok(())
+        }
+    }
+
     fn purge(&self, name: &EnvironmentName) -> Result<()> {
         self.stop(name)?;
         match &self.mounts(name) {
             EnvMounts::BindMounts {
                 host_home,
                 host_work,
+                host_overlay,
             } => {
-                rmtree(host_home)?;
-                rmtree(host_work)
+                self.clear_bind_dir(host_home)?;
+                self.clear_overlay_dir(host_overlay)?;
+                self.clear_bind_dir(host_work)
             }
 
             EnvMounts::Volumes {
                 home_volume,
                 work_volume,
+                overlay_volume,
             } => {
                 self.ensure_no_volume(home_volume)?;
+                self.ensure_no_volume(overlay_volume)?;
+                self.ensure_no_volume(work_volume)
+            }
+        }
+    }
+
+    fn purge_dir(&self, name: &EnvironmentName, dir: EnvDir) -> Result<()> {
+        // The container's mounts refer to the directory or volume.
+        self.stop(name)?;
+        match (&self.mounts(name), dir) {
+            (
+                EnvMounts::BindMounts {
+                    host_home,
+                    host_overlay,
+                    ..
+                },
+                EnvDir::Home,
+            ) => {
+                self.clear_bind_dir(host_home)?;
+                self.clear_overlay_dir(host_overlay)
+            }
+            (EnvMounts::BindMounts { host_work, .. }, EnvDir::Work) => {
+                self.clear_bind_dir(host_work)
+            }
+            (
+                EnvMounts::Volumes {
+                    home_volume,
+                    overlay_volume,
+                    ..
+                },
+                EnvDir::Home,
+            ) => {
+                self.ensure_no_volume(home_volume)?;
+                self.ensure_no_volume(overlay_volume)
+            }
+            (EnvMounts::Volumes { work_volume, .. }, EnvDir::Work) => {
                 self.ensure_no_volume(work_volume)
             }
         }
@@ -989,7 +2289,7 @@ impl Runner for Docker {
     }
 
     fn supports_any(&self, targets: &[Target]) -> Result<bool> {
-        Ok(targets.iter().any(|Target { arch, os }| {
+        Ok(targets.iter().any(|Target { arch, os, .. }| {
             (match arch {
                 None => true,
                 Some(arch) => arch == std::env::consts::ARCH,
@@ -999,6 +2299,113 @@ impl Runner for Docker {
             })
         }))
     }
+
+    fn capabilities(&self) -> BTreeSet<Capability> {
+        // The X11 socket is always bound into the containers, but they don't
+        // get `/dev/kvm` or run systemd.
+        let mut capabilities = BTreeSet::from([Capability::Gui]);
+        if self.program.config.docker.network.as_deref() != Some("none") {
+            capabilities.insert(Capability::Network);
+        }
+        capabilities
+    }
+
+    fn storage_dirs(&self) -> Result<Vec<HostPath>> {
+        let mut dirs = vec![self.mounts.home_dirs.clone(), self.mounts.work_dirs.clone()];
+        // Volumes and images live in Docker's data directory, which is only
+        // on this host if the daemon is.
+        if let Some(root) = self.root_dir()? {
+            if let Ok(root) = HostPath::try_from(PathBuf::from(root)) {
+                if try_exists(&root).unwrap_or(false) {
+                    dirs.push(root);
+                }
+            }
+        }
+        Ok(dirs)
+    }
+}
+
+/// Returns a `docker` command that talks to the daemon chosen by the
+/// `docker.context` or `docker.host` setting, if either is set.
+///
+/// The command-line options take precedence over `$DOCKER_HOST`,
+/// `$DOCKER_CONTEXT`, and the current context (`docker context use`).
+pub(super) fn docker_command(config: &config::Docker) -> Command {
+    let mut command = Command::new("docker");
+    if let Some(context) = &config.context {
+        command.args(["--context", context]);
+    }
+    if let Some(host) = &config.host {
+        command.args(["--host", host]);
+    }
+    command
+}
+
+/// Checks the `docker.context` and `docker.host` settings.
+fn check_endpoint(config: &config::Docker) -> Result<()> {
+    match (&config.context, &config.host) {
+        (Some(_), Some(_)) => Err(anyhow!(
+            "only one of `docker.context` and `docker.host` may be set"
+        )),
+        (Some(context), None) if context.is_empty() => {
+            Err(anyhow!("`docker.context` must not be empty"))
+        }
+        (None, Some(host)) => {
+            const SCHEMES: [&str; 5] = ["fd", "npipe", "ssh", "tcp", "unix"];
+            match host.split_once("://") {
+                Some((scheme, rest)) if SCHEMES.contains(&scheme) && !rest.is_empty() => Ok(()),
+                _ => Err(anyhow!(
+                    "`docker.host` must be a URL like 'unix:///var/run/docker.sock' \
+                    or 'ssh://user@host' (supported schemes: {}), found {host:?}",
+                    SCHEMES.join(", ")
+                )),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+fn detect_user_namespace(config: &config::Docker) -> LowLevelResult<UserNamespace> {
+    let output = docker_command(config)
+        .args(["info", "--format", "{{ json . }}"])
+        .output()?;
+    let status = output.status;
+    if !status.success() {
+        return Err(anyhow!(
+            "`docker info` exited with {} and stderr: {}",
+            status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    Ok(user_namespace_from_info(&info))
+}
+
+/// Helper for `detect_user_namespace`. Split out for unit testing.
+fn user_namespace_from_info(info: &serde_json::Value) -> UserNamespace {
+    // Podman's `info` has a different structure than Docker's.
+    if info
+        .pointer("/host/security/rootless")
+        .and_then(|rootless| rootless.as_bool())
+        == Some(true)
+    {
+        return UserNamespace::RootlessPodman;
+    }
+    let docker_rootless = info
+        .get("SecurityOptions")
+        .and_then(|options| options.as_array())
+        .is_some_and(|options| {
+            options
+                .iter()
+                .filter_map(|option| option.as_str())
+                .any(|option| option.split(',').any(|part| part == "name=rootless"))
+        });
+    if docker_rootless {
+        UserNamespace::RootlessDocker
+    } else {
+        UserNamespace::Host
+    }
 }
 
 fn fallback_path(container_home: &EnvPath) -> OsString {
@@ -1035,6 +2442,28 @@ fn get_host_locales() -> impl Iterator<Item = String> {
     })
 }
 
+/// The label on base images listing the Debian packages installed in them.
+const DEBIAN_PACKAGES_LABEL: &str = "org.cubicle.debian-packages";
+
+/// The label on containers, volumes, and images holding the `docker.prefix`
+/// setting they were created with.
+const PREFIX_LABEL: &str = "org.cubicle.prefix";
+
+/// The label on containers and volumes holding the environment's name.
+const ENVIRONMENT_LABEL: &str = "org.cubicle.environment";
+
+/// The label on containers, volumes, and images holding the version of
+/// Cubicle that created them.
+const VERSION_LABEL: &str = "org.cubicle.version";
+
+/// The label on containers, volumes, and images holding when they were
+/// created, in seconds since the Unix epoch.
+const CREATED_LABEL: &str = "org.cubicle.created";
+
+/// The label on base images holding a hash of the Debian packages installed
+/// in them.
+const PACKAGES_HASH_LABEL: &str = "org.cubicle.packages-hash";
+
 /// Debian packages that many packages might depend on for basic functionality.
 /// They are installed in the CI system.
 const BASE_PACKAGES: &[&str] = &[
@@ -1065,6 +2494,101 @@ struct DockerfileArgs<'a> {
     uids: &'a Uids,
 }
 
+/// Parses the output of `du` run on the volumes mounted at `/v/0`, `/v/1`,
+/// etc, in that order.
+fn parse_volume_du<const N: usize>(stdout: &str, stderr: &str) -> Option<[DirSummary; N]> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        RegexBuilder::new(r#"^(?P<size>[0-9]+)\t(?P<mtime>[0-9]+)\t/v/(?P<index>[0-9]+)$"#)
+            .build()
+            .unwrap()
+    });
+
+    let mut summaries = [(); N].map(|_| None);
+    for line in stdout.lines() {
+        let caps = re.captures(line)?;
+        let index = usize::from_str(caps.name("index").unwrap().as_str()).ok()?;
+        let size = u64::from_str(caps.name("size").unwrap().as_str()).ok()?;
+        let mtime = u64::from_str(caps.name("mtime").unwrap().as_str()).ok()?;
+        // Ignore permissions errors, but note which volumes had them.
+        let errors = stderr.lines().any(|line| {
+            line.contains(&format!("'/v/{index}/")) || line.contains(&format!("'/v/{index}'"))
+        });
+        *summaries.get_mut(index)? = Some(DirSummary {
+            errors,
+            total_size: size,
+            last_modified: UNIX_EPOCH + Duration::from_secs(mtime),
+        });
+    }
+    let summaries: Vec<DirSummary> = summaries.into_iter().collect::<Option<_>>()?;
+    summaries.try_into().ok()
+}
+
+/// Finds the environments that have volumes in the output of `docker volume
+/// ls`, formatted as the volume name, the prefix label, and the environment
+/// label, separated by tabs.
+///
+/// Volumes created by older versions of Cubicle have no labels, so they're
+/// recognized by their names instead.
+fn parse_volume_environments(stdout: &str, prefix: &str) -> BTreeSet<EnvironmentName> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let (name, label_prefix, label_env) = (fields.next()?, fields.next()?, fields.next()?);
+            volume_environment(name, label_prefix, label_env, prefix)
+        })
+        .collect()
+}
+
+/// Returns the environment that a volume belongs to, given its name and its
+/// prefix and environment labels (which are empty if missing).
+fn volume_environment(
+    name: &str,
+    label_prefix: &str,
+    label_env: &str,
+    prefix: &str,
+) -> Option<EnvironmentName> {
+    let env = if label_env.is_empty() {
+        let name = VolumeName::decode(name)?;
+        let name = name.decoded().strip_prefix(prefix)?;
+        name.strip_suffix("-home")
+            .or_else(|| name.strip_suffix("-work"))?
+            .to_owned()
+    } else if label_prefix == prefix {
+        label_env.to_owned()
+    } else {
+        return None;
+    };
+    EnvironmentName::from_str(&env).ok()
+}
+
+/// Returns a short hash of the given Debian package names.
+fn packages_hash(packages: &BTreeSet<&str>) -> String {
+    let mut hasher = Sha256::new();
+    for package in packages {
+        hasher.update(package.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher
+        .finalize()
+        .iter()
+        .take(8)
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Returns a Docker tag identifying the base image built from the given
+/// Dockerfile.
+fn base_image_key(dockerfile: &[u8]) -> String {
+    let hash = Sha256::digest(dockerfile)
+        .iter()
+        .take(8)
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    format!("inputs-{hash}")
+}
+
 fn write_dockerfile<W: io::Write>(w: &mut W, args: DockerfileArgs) -> std::io::Result<()> {
     // Quote all the Strings that go into the file.
     let packages: Vec<String> = args
@@ -1099,6 +2623,16 @@ fn write_dockerfile<W: io::Write>(w: &mut W, args: DockerfileArgs) -> std::io::R
     };
     let timezone = shlex::try_quote(args.timezone).expect("TODO");
     let user = shlex::try_quote(args.user).expect("TODO");
+    let packages_label = args
+        .packages
+        .iter()
+        .map(|p| {
+            p.replace('\\', r"\\")
+                .replace('"', r#"\""#)
+                .replace('$', r"\$")
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
     let has_apt_file = args.packages.contains("apt-file");
     let has_sudo = args.packages.contains("sudo");
     let uid = args.uids.real_user;
@@ -1161,6 +2695,9 @@ fn write_dockerfile<W: io::Write>(w: &mut W, args: DockerfileArgs) -> std::io::R
         }
         writeln!(w, "    {last}")?;
     }
+    // Record the packages so that `cub show --debian-packages` can find them
+    // from a container.
+    writeln!(w, r#"LABEL {DEBIAN_PACKAGES_LABEL}="{packages_label}""#)?;
 
     // Update lists of package contents (after 'apt-file' is installed).
     if has_apt_file {
@@ -1207,6 +2744,116 @@ mod tests {
         );
     }
 
+    #[test]
+    fn user_namespace_from_info() {
+        use serde_json::json;
+        use UserNamespace::*;
+        for (expected, info) in [
+            (Host, json!({})),
+            (
+                Host,
+                json!({"SecurityOptions": ["name=apparmor", "name=seccomp,profile=builtin"]}),
+            ),
+            (
+                RootlessDocker,
+                json!({"SecurityOptions": ["name=seccomp,profile=builtin", "name=rootless"]}),
+            ),
+            (Host, json!({"host": {"security": {"rootless": false}}})),
+            (
+                RootlessPodman,
+                json!({"host": {"security": {"rootless": true}}}),
+            ),
+        ] {
+            assert_eq!(expected, super::user_namespace_from_info(&info), "{info}");
+        }
+    }
+
+    #[test]
+    fn parse_volume_du() {
+        let stdout = "1234\t1700000000\t/v/0\n5678\t1700000001\t/v/1\n";
+        let stderr = "du: cannot read directory '/v/1/secret': Permission denied\n";
+        let [home, work] = super::parse_volume_du::<2>(stdout, stderr).unwrap();
+        assert!(!home.errors);
+        assert_eq!(1234, home.total_size);
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            home.last_modified
+        );
+        assert!(work.errors);
+        assert_eq!(5678, work.total_size);
+
+        assert!(super::parse_volume_du::<2>("1234\t1700000000\t/v/0\n", "").is_none());
+        assert!(super::parse_volume_du::<1>("1234\t1700000000\t/v/1\n", "").is_none());
+        assert!(super::parse_volume_du::<1>("1234 /v/0\n", "").is_none());
+    }
+
+    #[test]
+    fn check_endpoint() {
+        let check = |context: Option<&str>, host: Option<&str>| {
+            super::check_endpoint(&config::Docker {
+                context: context.map(String::from),
+                host: host.map(String::from),
+                ..config::Docker::default()
+            })
+            .map_err(|e| e.to_string())
+        };
+        assert_eq!(Ok(()), check(None, None));
+        assert_eq!(Ok(()), check(Some("colima"), None));
+        assert_eq!(Ok(()), check(None, Some("unix:///var/run/docker.sock")));
+        assert_eq!(Ok(()), check(None, Some("ssh://me@build-box")));
+        expect![[r#"
+            Err(
+                "only one of `docker.context` and `docker.host` may be set",
+            )
+        "#]]
+        .assert_debug_eq(&check(Some("colima"), Some("tcp://localhost:2375")));
+        expect![[r#"
+            Err(
+                "`docker.context` must not be empty",
+            )
+        "#]]
+        .assert_debug_eq(&check(Some(""), None));
+        expect![[r#"
+            Err(
+                "`docker.host` must be a URL like 'unix:///var/run/docker.sock' or 'ssh://user@host' (supported schemes: fd, npipe, ssh, tcp, unix), found \"/var/run/docker.sock\"",
+            )
+        "#]]
+        .assert_debug_eq(&check(None, Some("/var/run/docker.sock")));
+    }
+
+    #[test]
+    fn parse_volume_environments() {
+        let stdout = [
+            "cub-a-home\tcub-\ta",
+            "cub-a-work\tcub-\ta",
+            "renamed\tcub-\tb",
+            "cub-c-home\tother-\tc",
+            "cub-old-work\t\t",
+            "cub-old-overlay\t\t",
+            "unrelated\t\t",
+            "",
+        ]
+        .join("\n");
+        let envs = super::parse_volume_environments(&stdout, "cub-");
+        expect![[r#"
+            [
+                "a",
+                "b",
+                "old",
+            ]
+        "#]]
+        .assert_debug_eq(&envs.iter().map(|env| env.as_str()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn base_image_key() {
+        expect!["inputs-2c26b46b68ffc68f"].assert_eq(&super::base_image_key(b"foo"));
+        assert_ne!(
+            super::base_image_key(b"foo"),
+            super::base_image_key(b"foo\n")
+        );
+    }
+
     #[test]
     fn write_dockerfile() {
         let mut buf: Vec<u8> = Vec::new();
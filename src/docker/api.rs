@@ -0,0 +1,289 @@
+//! A minimal client for the Docker Engine API, used instead of the `docker`
+//! CLI for the frequent read-only queries, like listing containers and
+//! volumes.
+//!
+//! Each `docker` invocation can be slow (especially on macOS), so talking to
+//! the daemon's Unix socket directly speeds up commands like `cub list`. The
+//! client is only used when the socket can be found without the CLI's help.
+//! Otherwise, such as with a Docker context or a remote daemon, Cubicle runs
+//! the `docker` CLI as usual. Commands that stream data or attach a
+//! terminal, like `docker exec`, always use the CLI.
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config;
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// How long to wait for the daemon to respond before giving up.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct Client {
+    socket: PathBuf,
+}
+
+/// A container, as listed by `GET /containers/json`.
+#[derive(Debug, Deserialize)]
+pub struct Container {
+    #[serde(rename = "Labels", default)]
+    pub labels: Option<BTreeMap<String, String>>,
+}
+
+/// System-wide information, as returned by `GET /info`.
+#[derive(Debug, Deserialize)]
+pub struct Info {
+    #[serde(rename = "DockerRootDir", default)]
+    pub docker_root_dir: String,
+    /// The host name of the daemon's host.
+    #[serde(rename = "Name", default)]
+    pub name: String,
+}
+
+/// The response to `GET /volumes`.
+#[derive(Debug, Deserialize)]
+pub struct VolumeList {
+    #[serde(rename = "Volumes", default)]
+    pub volumes: Option<Vec<Volume>>,
+}
+
+/// A volume, as returned by `GET /volumes` and `GET /volumes/{name}`.
+#[derive(Debug, Deserialize)]
+pub struct Volume {
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Labels", default)]
+    pub labels: Option<BTreeMap<String, String>>,
+    #[serde(rename = "Mountpoint", default)]
+    pub mountpoint: String,
+}
+
+impl Client {
+    /// Returns a client for the daemon that the `docker` CLI would use, if
+    /// it's reachable over a local Unix socket.
+    ///
+    /// This follows the CLI's order of precedence: `docker.context`,
+    /// `docker.host`, `$DOCKER_HOST`, `$DOCKER_CONTEXT`, then the current
+    /// context. Contexts other than "default" are left to the CLI.
+    pub fn detect(config: &config::Docker) -> Option<Self> {
+        if !cfg!(unix) || config.context.is_some() {
+            return None;
+        }
+        let host = match &config.host {
+            Some(host) => Some(host.clone()),
+            None => std::env::var("DOCKER_HOST")
+                .ok()
+                .filter(|host| !host.is_empty()),
+        };
+        let socket = match host {
+            Some(host) => PathBuf::from(host.strip_prefix("unix://")?),
+            None => {
+                if std::env::var("DOCKER_CONTEXT")
+                    .is_ok_and(|context| !context.is_empty() && context != "default")
+                    || uses_current_context()
+                {
+                    return None;
+                }
+                PathBuf::from("/var/run/docker.sock")
+            }
+        };
+        socket.exists().then_some(Self { socket })
+    }
+
+    /// Sends a GET request and parses the JSON response. Returns `None` if
+    /// the daemon responds with "404 Not Found".
+    pub fn get<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>> {
+        let (status, body) = self
+            .request(path)
+            .with_context(|| format!("failed to query Docker API at {:?}", self.socket))?;
+        match status {
+            200..=299 => serde_json::from_slice(&body)
+                .map(Some)
+                .with_context(|| format!("failed to parse Docker API response to GET {path}")),
+            404 => Ok(None),
+            _ => {
+                #[derive(Deserialize)]
+                struct ErrorResponse {
+                    message: String,
+                }
+                let message = match serde_json::from_slice::<ErrorResponse>(&body) {
+                    Ok(error) => error.message,
+                    Err(_) => String::from_utf8_lossy(&body).trim().to_owned(),
+                };
+                Err(anyhow!(
+                    "Docker API request GET {path} failed with status {status}: {message}"
+                ))
+            }
+        }
+    }
+
+    fn request(&self, path: &str) -> io::Result<(u16, Vec<u8>)> {
+        let mut stream = self.connect()?;
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: docker\r\nConnection: close\r\n\r\n"
+        )?;
+        stream.flush()?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        parse_response(&response)
+    }
+
+    fn connect(&self) -> io::Result<std::os::unix::net::UnixStream> {
+        let stream = std::os::unix::net::UnixStream::connect(&self.socket)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(stream)
+    }
+}
+
+/// Returns true if the CLI's configuration file selects a context other
+/// than "default".
+fn uses_current_context() -> bool {
+    let dir = match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => PathBuf::from(dir),
+        None => match std::env::var_os("HOME") {
+            Some(home) => Path::new(&home).join(".docker"),
+            None => return false,
+        },
+    };
+    #[derive(Deserialize)]
+    struct CliConfig {
+        #[serde(rename = "currentContext", default)]
+        current_context: String,
+    }
+    std::fs::read(dir.join("config.json"))
+        .ok()
+        .and_then(|json| serde_json::from_slice::<CliConfig>(&json).ok())
+        .is_some_and(|config| {
+            !config.current_context.is_empty() && config.current_context != "default"
+        })
+}
+
+/// Returns a request path with the given query parameters, which are
+/// percent-encoded.
+pub fn path(path: &str, query: &[(&str, &str)]) -> String {
+    let mut buf = String::from(path);
+    for (i, (key, value)) in query.iter().enumerate() {
+        buf.push(if i == 0 { '?' } else { '&' });
+        buf.push_str(&encode(key));
+        buf.push('=');
+        buf.push_str(&encode(value));
+    }
+    buf
+}
+
+/// Percent-encodes a path segment or query parameter.
+pub fn encode(s: &str) -> String {
+    let mut buf = String::new();
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            buf.push(char::from(byte));
+        } else {
+            buf.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    buf
+}
+
+/// Parses an HTTP/1.1 response that was read until the connection closed,
+/// returning its status code and body.
+fn parse_response(response: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| invalid("incomplete HTTP response headers"))?;
+    let head = std::str::from_utf8(&response[..header_end])
+        .map_err(|_| invalid("HTTP response headers not valid UTF-8"))?;
+    let body = &response[header_end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| invalid("invalid HTTP status line"))?;
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    if !chunked {
+        return Ok((status, body.to_vec()));
+    }
+
+    let mut decoded = Vec::new();
+    let mut rest = body;
+    loop {
+        let line_end = rest
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(|| invalid("incomplete HTTP chunk"))?;
+        let size = std::str::from_utf8(&rest[..line_end])
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+            .ok_or_else(|| invalid("invalid HTTP chunk size"))?;
+        if size == 0 {
+            return Ok((status, decoded));
+        }
+        let chunk = rest
+            .get(line_end + 2..line_end + 2 + size)
+            .ok_or_else(|| invalid("incomplete HTTP chunk"))?;
+        decoded.extend_from_slice(chunk);
+        rest = rest
+            .get(line_end + 2 + size + 2..)
+            .ok_or_else(|| invalid("incomplete HTTP chunk"))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_response() {
+        let (status, body) =
+            super::parse_response(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n[]\n")
+                .unwrap();
+        assert_eq!(200, status);
+        assert_eq!(b"[]\n", body.as_slice());
+
+        let (status, body) = super::parse_response(
+            b"HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\n{\"mes\r\n11\r\nsage\":\"no such\"}\n\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(404, status);
+        assert_eq!(b"{\"message\":\"no such\"}\n", body.as_slice());
+
+        assert!(super::parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+        assert!(super::parse_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn path() {
+        assert_eq!("/volumes", super::path("/volumes", &[]));
+        assert_eq!(
+            "/containers/json?all=1&filters=%7B%22label%22%3A%5B%22org.cubicle.prefix%3Dcub-%22%5D%7D",
+            super::path(
+                "/containers/json",
+                &[
+                    ("all", "1"),
+                    ("filters", r#"{"label":["org.cubicle.prefix=cub-"]}"#)
+                ]
+            )
+        );
+        assert_eq!(
+            "cub-cubicle-base%3Alatest",
+            encode("cub-cubicle-base:latest")
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! Built-in package definitions embedded in the executable.
+//!
+//! With the `embed-packages` feature, `build.rs` archives the `packages/`
+//! directory into the executable. This extracts the archive into the cache
+//! directory on first use, so the executable works without the source tree
+//! (for example, when installed with `cargo install`).
+
+use sha2::{Digest, Sha256};
+use std::io;
+
+use super::fs_util::try_exists;
+use super::HostPath;
+use crate::somehow::{Context, Result};
+
+static PACKAGES_TAR: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/packages.tar"));
+
+/// Returns the directory containing the built-in package definitions,
+/// extracting them into `cache_dir` if needed.
+///
+/// Each version of the package definitions is extracted into a directory
+/// named after its hash, so upgrading Cubicle doesn't reuse stale files.
+pub fn builtin_package_dir(cache_dir: &HostPath) -> Result<HostPath> {
+    let hash = Sha256::digest(PACKAGES_TAR)
+        .iter()
+        .take(8)
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    let dir = cache_dir.join(format!("builtin-packages-{hash}"));
+    if try_exists(&dir).todo_context()? {
+        return Ok(dir);
+    }
+
+    // Extract into a temporary directory first, so that an interrupted
+    // extraction doesn't leave a partial directory in place.
+    std::fs::create_dir_all(cache_dir.as_host_raw())
+        .with_context(|| format!("failed to create directory {cache_dir}"))?;
+    let tmp = tempfile::Builder::new()
+        .prefix(".builtin-packages-")
+        .tempdir_in(cache_dir.as_host_raw())
+        .with_context(|| format!("failed to create temporary directory in {cache_dir}"))?;
+    tar::Archive::new(PACKAGES_TAR)
+        .unpack(tmp.path())
+        .with_context(|| format!("failed to extract built-in packages into {:?}", tmp.path()))?;
+    match std::fs::rename(tmp.path(), dir.as_host_raw()) {
+        Ok(()) => {}
+        // Another process may have extracted the same packages concurrently.
+        Err(_) if try_exists(&dir).unwrap_or(false) => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to move built-in packages to {dir}"))
+        }
+    }
+    // The temporary directory was renamed, so there's usually nothing left to
+    // remove.
+    if let Err(e) = tmp.close() {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e).context("failed to remove temporary directory");
+        }
+    }
+    Ok(dir)
+}
@@ -1,9 +1,113 @@
 #!/bin/sh
 set -eu
 
+# Applies the dotfiles repository from Cubicle's configuration, given by the
+# `CUBICLE_DOTFILES_*` environment variables.
+apply_dotfiles() {
+    case "$CUBICLE_DOTFILES_METHOD" in
+        git)
+            rm -rf .dotfiles &&
+                git clone --quiet --bare \
+                    ${CUBICLE_DOTFILES_BRANCH:+"--branch=$CUBICLE_DOTFILES_BRANCH"} \
+                    -- "$CUBICLE_DOTFILES_REPO" .dotfiles &&
+                git --git-dir=.dotfiles config status.showUntrackedFiles no &&
+                git --git-dir=.dotfiles --work-tree=. checkout --force
+            ;;
+        stow)
+            # Stow won't replace files from packages, so this adopts them into
+            # the clone and then restores the repository's versions.
+            rm -rf .dotfiles &&
+                git clone --quiet \
+                    ${CUBICLE_DOTFILES_BRANCH:+"--branch=$CUBICLE_DOTFILES_BRANCH"} \
+                    -- "$CUBICLE_DOTFILES_REPO" .dotfiles &&
+                (
+                    cd .dotfiles &&
+                        for dir in */; do
+                            stow --adopt --target="$HOME" -- "${dir%/}" || exit
+                        done &&
+                        git checkout --quiet -- .
+                )
+            ;;
+        chezmoi)
+            chezmoi init --apply --force \
+                ${CUBICLE_DOTFILES_BRANCH:+"--branch=$CUBICLE_DOTFILES_BRANCH"} \
+                -- "$CUBICLE_DOTFILES_REPO"
+            ;;
+        *)
+            echo "Unknown dotfiles method: $CUBICLE_DOTFILES_METHOD" >&2
+            return 1
+            ;;
+    esac
+}
+
+# Links the shared caches from Cubicle's configuration into the home
+# directory. `CUBICLE_CACHES` has a line per cache: its name, where it's
+# mounted, and its path relative to the home directory. Any files that
+# packages already put at that path are moved into the cache.
+link_caches() {
+    printf '%s\n' "$CUBICLE_CACHES" | while read -r name target path; do
+        if [ -d "$path" ] && [ ! -L "$path" ]; then
+            cp -a "$path/." "$target/" && rm -rf "$path" ||
+                echo "WARNING: moving ~/$path into cache $name failed with status $?"
+        fi
+        mkdir -p "$(dirname "$path")" && ln -sfn "$target" "$path" ||
+            echo "WARNING: linking cache $name at ~/$path failed with status $?"
+    done
+}
+
+# Writes a profile script that sets up shells to build through the compiler
+# cache from Cubicle's configuration, given by `CUBICLE_COMPILER_CACHE` and
+# `CUBICLE_COMPILER_CACHE_MAX_SIZE`. The script checks that the tool is
+# installed, since builds would fail without it.
+write_compiler_cache_profile() {
+    case "$CUBICLE_COMPILER_CACHE" in
+        ccache)
+            cat << EOF
+if command -v ccache > /dev/null; then
+    export CCACHE_DIR="\$HOME/.cache/ccache"
+    ${CUBICLE_COMPILER_CACHE_MAX_SIZE:+export CCACHE_MAXSIZE='$CUBICLE_COMPILER_CACHE_MAX_SIZE'}
+    export PATH="/usr/lib/ccache:\$PATH"
+fi
+EOF
+            ;;
+        sccache)
+            cat << EOF
+if command -v sccache > /dev/null; then
+    export SCCACHE_DIR="\$HOME/.cache/sccache"
+    ${CUBICLE_COMPILER_CACHE_MAX_SIZE:+export SCCACHE_CACHE_SIZE='$CUBICLE_COMPILER_CACHE_MAX_SIZE'}
+    export RUSTC_WRAPPER=sccache
+    export CMAKE_C_COMPILER_LAUNCHER=sccache
+    export CMAKE_CXX_COMPILER_LAUNCHER=sccache
+fi
+EOF
+            ;;
+        *)
+            echo "Unknown compiler cache: $CUBICLE_COMPILER_CACHE" >&2
+            return 1
+            ;;
+    esac
+}
+
 cd
 mkdir -p .dev-init bin opt tmp w
 
+if [ -n "${CUBICLE_CACHES:-}" ]; then
+    link_caches
+fi
+
+if [ -n "${CUBICLE_COMPILER_CACHE:-}" ]; then
+    mkdir -p .config/profile.d
+    write_compiler_cache_profile > .config/profile.d/50-compiler-cache.sh ||
+        echo "WARNING: setting up compiler cache failed with status $?"
+else
+    rm -f .config/profile.d/50-compiler-cache.sh
+fi
+
+if [ -n "${CUBICLE_DOTFILES_REPO:-}" ]; then
+    echo "Applying dotfiles from $CUBICLE_DOTFILES_REPO"
+    apply_dotfiles || echo "WARNING: applying dotfiles in $CUBICLE failed with status $?"
+fi
+
 if [ -f ./.profile ]; then
     set +u
     # shellcheck source=/dev/null
@@ -0,0 +1,406 @@
+//! Progress notifications from long-running operations.
+//!
+//! The library doesn't print its progress. Instead, it reports [`Event`]s to
+//! the [`EventSink`] given to [`Cubicle::new`](crate::Cubicle::new), and the
+//! frontend decides how (or whether) to display them.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::{EnvDir, EnvPiece, EnvironmentName, FullPackageName, RunnerKind};
+
+/// Something that happened during an operation that a user may want to know
+/// about.
+///
+/// The [`fmt::Display`] impl renders each event as a short, human-readable
+/// line of text.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum Event {
+    /// Another process holds a lock, so this one is waiting for it. `what`
+    /// describes the locked object, like `environment "foo"`.
+    WaitingForLock {
+        /// The locked object.
+        what: String,
+    },
+    /// Stopping an environment that hasn't been used for longer than the
+    /// configured `auto_stop` duration.
+    StoppingIdleEnvironment(EnvironmentName),
+    /// Removing an environment that failed partway through its creation.
+    RemovingPartialEnvironment(EnvironmentName),
+    /// Recreating the missing pieces of a broken environment.
+    RepairingEnvironment(EnvironmentName),
+    /// Writing an environment's files into a backup.
+    BackingUpEnvironment(EnvironmentName),
+    /// Recreating an environment from a backup.
+    RestoringEnvironment(EnvironmentName),
+    /// Starting a new Docker container for an existing environment, since its
+    /// previous container exited.
+    RestartingContainer(EnvironmentName),
+    /// Copying the package and other seed tarballs into an environment.
+    CopyingSeeds(EnvironmentName),
+    /// Saving files from an environment's home directory to restore after
+    /// resetting it.
+    SavingHomeFiles {
+        /// The environment.
+        name: EnvironmentName,
+        /// The files and directories, relative to the home directory.
+        paths: Vec<PathBuf>,
+    },
+    /// Saving an environment's work directory before resetting it.
+    SavingWorkDir {
+        /// The environment being reset.
+        name: EnvironmentName,
+        /// The path on the host of the archive of the work directory.
+        path: PathBuf,
+    },
+    /// Restoring an environment's work directory after resetting it.
+    RestoringWorkDir {
+        /// The environment being reset.
+        name: EnvironmentName,
+        /// The path on the host of the archive of the work directory.
+        path: PathBuf,
+    },
+    /// Running rsync to mirror an environment's work directory with a
+    /// remote location.
+    SyncingWorkDir {
+        /// The environment.
+        name: EnvironmentName,
+        /// The remote location, in rsync's `[USER@]HOST:PATH` syntax.
+        remote: String,
+    },
+    /// Watching the sources of packages, to update them when they change.
+    WatchingPackages(Vec<FullPackageName>),
+    /// Finished updating watched packages after their sources changed.
+    WatchedPackagesUpdated {
+        /// How long the update took.
+        duration: Duration,
+        /// The error message, if the update failed.
+        error: Option<String>,
+    },
+    /// Extracting newly built packages over an existing environment's home
+    /// directory.
+    ReseedingEnvironment {
+        /// The environment.
+        name: EnvironmentName,
+        /// The packages being extracted.
+        packages: Vec<FullPackageName>,
+    },
+    /// Watching an environment's work directory, to report changes to its
+    /// files.
+    WatchingWorkDir(EnvironmentName),
+    /// Running a vulnerability scanner on an environment.
+    ScanningEnvironment(EnvironmentName),
+    /// Running a vulnerability scanner on a container image.
+    ScanningImage(String),
+    /// Forcibly killing a user's processes that didn't exit on their own.
+    KillingProcesses {
+        /// The system user account.
+        username: String,
+        /// How long the processes were given to exit.
+        grace_period: Duration,
+    },
+    /// Rebuilding a package because its cached build output is corrupt.
+    RebuildingCorruptPackage(FullPackageName),
+    /// Building a package.
+    UpdatingPackage(FullPackageName),
+    /// Running a package's tests after building it.
+    TestingPackage(FullPackageName),
+    /// Picked a runner for `runner = "auto"` by probing the host. This
+    /// happens once; later runs use the same runner.
+    ChoseRunner(RunnerKind),
+    /// `cub daemon` is listening for queries on this Unix socket.
+    DaemonListening(PathBuf),
+    /// `cub daemon` is serving metrics over HTTP at this address.
+    ServingMetrics(SocketAddr),
+    /// `cub daemon` is stopping because its configuration file changed.
+    ConfigChanged(PathBuf),
+    /// An action that a dry run skipped.
+    Planned(PlannedAction),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Event::*;
+        match self {
+            WaitingForLock { what } => {
+                write!(f, "Waiting for another process to finish with {what}")
+            }
+            StoppingIdleEnvironment(name) => write!(f, "Stopping idle environment {name}"),
+            RemovingPartialEnvironment(name) => {
+                write!(f, "Removing partially created environment {name}")
+            }
+            RepairingEnvironment(name) => write!(f, "Repairing environment {name}"),
+            BackingUpEnvironment(name) => write!(f, "Backing up environment {name}"),
+            RestoringEnvironment(name) => write!(f, "Restoring environment {name}"),
+            RestartingContainer(name) => {
+                write!(f, "Restarting Docker container for environment {name}")
+            }
+            CopyingSeeds(_) => write!(f, "Copying/extracting seed tarball"),
+            SavingHomeFiles { paths, .. } => {
+                write!(f, "Saving files to keep:")?;
+                write_home_paths(f, paths)
+            }
+            SavingWorkDir { path, .. } => write!(f, "Saving work directory to {path:?}"),
+            RestoringWorkDir { path, .. } => write!(f, "Restoring work directory from {path:?}"),
+            SyncingWorkDir { name, remote } => {
+                write!(f, "Syncing work directory of {name} with {remote:?}")
+            }
+            WatchingPackages(packages) => write!(
+                f,
+                "Watching {} for changes (press Ctrl-C to stop)",
+                packages
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            WatchedPackagesUpdated {
+                duration,
+                error: None,
+            } => write!(f, "PASS: updated in {:.1}s", duration.as_secs_f64()),
+            WatchedPackagesUpdated {
+                duration,
+                error: Some(error),
+            } => write!(f, "FAIL after {:.1}s: {error}", duration.as_secs_f64()),
+            ReseedingEnvironment { name, packages } => {
+                write!(f, "Re-seeding environment {name} from packages")?;
+                for (i, package) in packages.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { "," };
+                    write!(f, "{sep} {}", package.unquoted())?;
+                }
+                Ok(())
+            }
+            WatchingWorkDir(name) => write!(
+                f,
+                "Watching work directory of environment {name} for changes (press Ctrl-C to stop)"
+            ),
+            ScanningEnvironment(name) => write!(f, "Scanning environment {name}"),
+            ScanningImage(image) => write!(f, "Scanning image {image:?}"),
+            KillingProcesses {
+                username,
+                grace_period,
+            } => write!(
+                f,
+                "Processes for user {username:?} didn't exit within {} seconds, killing them",
+                grace_period.as_secs()
+            ),
+            RebuildingCorruptPackage(name) => write!(
+                f,
+                "Cached {name} package is missing its checksum or is corrupt, so rebuilding it"
+            ),
+            UpdatingPackage(name) => write!(f, "Updating {name} package"),
+            TestingPackage(name) => write!(f, "Testing {name} package"),
+            ChoseRunner(runner) => write!(
+                f,
+                "Using the {} runner for `runner = \"auto\"` (run `cub doctor` for details)",
+                runner.as_str()
+            ),
+            DaemonListening(path) => write!(f, "Listening on {path:?}"),
+            ServingMetrics(address) => write!(f, "Serving metrics at http://{address}/metrics"),
+            ConfigChanged(path) => write!(f, "Stopping because {path:?} changed"),
+            Planned(action) => write!(f, "Would {action}"),
+        }
+    }
+}
+
+/// Something that an operation would have done, if not for a dry run.
+///
+/// The [`fmt::Display`] impl renders each action as a phrase that follows
+/// "would", like `build "rust" package (source files changed)`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum PlannedAction {
+    /// Build a package and run its tests.
+    BuildPackage {
+        /// The package to build.
+        name: FullPackageName,
+        /// Why the package needs to be built.
+        reason: BuildReason,
+    },
+    /// Create a new environment.
+    CreateEnvironment {
+        /// The environment to create.
+        name: EnvironmentName,
+        /// The pieces of the environment that the runner would create, if
+        /// the runner can describe them.
+        pieces: Vec<EnvPiece>,
+        /// The packages whose build outputs would seed the home directory.
+        packages: Vec<FullPackageName>,
+    },
+    /// Replace an environment's home directory, keeping its work directory.
+    ResetEnvironment {
+        /// The environment to reset.
+        name: EnvironmentName,
+        /// The packages whose build outputs would seed the home directory.
+        packages: Vec<FullPackageName>,
+        /// Files and directories that would be kept, relative to the home
+        /// directory.
+        keep: Vec<PathBuf>,
+    },
+    /// Extract packages' build outputs over an environment's home
+    /// directory, keeping its other files.
+    ReseedEnvironment {
+        /// The environment to re-seed.
+        name: EnvironmentName,
+        /// The packages whose build outputs would be extracted.
+        packages: Vec<FullPackageName>,
+    },
+    /// Delete an environment, including its work directory.
+    PurgeEnvironment {
+        /// The environment to delete.
+        name: EnvironmentName,
+        /// The existing pieces of the environment that the runner would
+        /// delete, if the runner can describe them.
+        pieces: Vec<EnvPiece>,
+    },
+    /// Delete an environment's home directory or work directory, leaving
+    /// the rest of the environment.
+    PurgeEnvironmentDir {
+        /// The environment.
+        name: EnvironmentName,
+        /// The directory to delete.
+        dir: EnvDir,
+    },
+    /// Run a hook script from the configuration.
+    RunHook {
+        /// The hook's setting name, like `on_create`.
+        hook: &'static str,
+        /// The script.
+        path: PathBuf,
+        /// The environment that the hook is for.
+        name: EnvironmentName,
+    },
+}
+
+impl fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use PlannedAction::*;
+        match self {
+            BuildPackage { name, reason } => write!(f, "build {name} package ({reason})"),
+            CreateEnvironment {
+                name,
+                pieces,
+                packages,
+            } => {
+                write!(f, "create environment {name}")?;
+                write_pieces(f, pieces)?;
+                write_packages(f, packages)
+            }
+            ResetEnvironment {
+                name,
+                packages,
+                keep,
+            } => {
+                write!(f, "reset the home directory of environment {name}")?;
+                write_packages(f, packages)?;
+                if !keep.is_empty() {
+                    write!(f, ", keeping")?;
+                    write_home_paths(f, keep)?;
+                }
+                Ok(())
+            }
+            ReseedEnvironment { name, packages } => {
+                write!(f, "re-seed the home directory of environment {name}")?;
+                write_packages(f, packages)
+            }
+            PurgeEnvironment { name, pieces } => {
+                write!(f, "delete environment {name}")?;
+                write_pieces(f, pieces)
+            }
+            PurgeEnvironmentDir { name, dir } => {
+                write!(f, "delete the {} of environment {name}", dir.as_str())
+            }
+            RunHook { hook, path, name } => {
+                write!(f, "run `{hook}` hook {path:?} for environment {name}")
+            }
+        }
+    }
+}
+
+fn write_pieces(f: &mut fmt::Formatter<'_>, pieces: &[EnvPiece]) -> fmt::Result {
+    for (i, piece) in pieces.iter().enumerate() {
+        let sep = if i == 0 { " with" } else { "," };
+        write!(f, "{sep} {} {}", piece.kind, piece.location)?;
+    }
+    Ok(())
+}
+
+fn write_home_paths(f: &mut fmt::Formatter<'_>, paths: &[PathBuf]) -> fmt::Result {
+    for (i, path) in paths.iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        write!(f, "{sep} ~/{}", path.display())?;
+    }
+    Ok(())
+}
+
+fn write_packages(f: &mut fmt::Formatter<'_>, packages: &[FullPackageName]) -> fmt::Result {
+    for (i, package) in packages.iter().enumerate() {
+        let sep = if i == 0 {
+            ", seeded from packages"
+        } else {
+            ","
+        };
+        write!(f, "{sep} {}", package.unquoted())?;
+    }
+    Ok(())
+}
+
+/// Why a package needs to be built, as part of [`PlannedAction::BuildPackage`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum BuildReason {
+    /// The caller asked for the package to be built.
+    Requested,
+    /// The package has never been built successfully.
+    NeverBuilt,
+    /// The package was last built longer ago than the `auto_update`
+    /// setting.
+    Expired,
+    /// The package's source files have changed since it was last built.
+    SourcesChanged,
+    /// A dependency was built more recently than the package, or would be
+    /// built first.
+    DependencyChanged(FullPackageName),
+    /// The package's cached build output is missing its checksum or is
+    /// corrupt.
+    Corrupt,
+}
+
+impl fmt::Display for BuildReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use BuildReason::*;
+        match self {
+            Requested => write!(f, "requested"),
+            NeverBuilt => write!(f, "never built"),
+            Expired => write!(f, "last built longer ago than `auto_update`"),
+            SourcesChanged => write!(f, "source files changed"),
+            DependencyChanged(name) => write!(f, "dependency {name} changed"),
+            Corrupt => write!(f, "cached build is corrupt"),
+        }
+    }
+}
+
+/// Receives [`Event`]s as they happen.
+///
+/// This is implemented for closures, so `|event: &Event| println!("{event}")`
+/// is a sink that prints every event, and `|_: &Event| {}` ignores them all.
+///
+/// Sinks must be `Send + Sync` so that a [`Cubicle`](crate::Cubicle) can be
+/// shared across threads. Events may be reported from any thread that's using
+/// it.
+pub trait EventSink: Send + Sync {
+    /// Called once for each event, in order.
+    fn event(&self, event: &Event);
+}
+
+impl<F> EventSink for F
+where
+    F: Fn(&Event) + Send + Sync,
+{
+    fn event(&self, event: &Event) {
+        self(event)
+    }
+}
@@ -0,0 +1,362 @@
+//! Reports changes to the files in an environment's work directory, for `cub
+//! watch`.
+//!
+//! Like `cub sync --watch`, this polls for changes rather than relying on a
+//! platform's file notifications, which wouldn't see changes made inside a
+//! Docker volume. When the work directory is on the host's filesystem, this
+//! lists it directly. Otherwise, the runner lists it from inside Docker and
+//! streams the listings back.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::runner::Runner;
+use super::{cancel, Cubicle, EnvironmentName, Event, HostPath};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// How often to list the work directory.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The files and directories found under a directory, keyed by their paths
+/// relative to it.
+pub type FileListing = BTreeMap<PathBuf, FileState>;
+
+/// What was found at a path in a [`FileListing`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileState {
+    /// The type of file.
+    pub kind: FileKind,
+    /// The file's size in bytes.
+    pub size: u64,
+    /// When the file was last modified.
+    pub modified: SystemTime,
+}
+
+/// A type of file, without following symlinks.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileKind {
+    /// A regular file.
+    File,
+    /// A directory.
+    Dir,
+    /// A symbolic link.
+    Symlink,
+    /// Anything else, like a socket or named pipe.
+    Other,
+}
+
+/// How a path changed between two listings.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileChange {
+    /// The path appeared.
+    Created,
+    /// The file's size or modification time changed. This isn't reported
+    /// for directories, whose modification times change whenever their
+    /// entries do.
+    Modified,
+    /// The path disappeared.
+    Removed,
+}
+
+/// A change to a path in the work directory, reported by
+/// [`Cubicle::watch_work_dir`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileEvent {
+    /// How the path changed.
+    pub change: FileChange,
+    /// The path, relative to the work directory.
+    pub path: PathBuf,
+    /// The type of file at the path, or that was at the path if it was
+    /// removed.
+    pub kind: FileKind,
+}
+
+impl Cubicle {
+    /// Corresponds to `cub watch`.
+    ///
+    /// Calls `f` for each change to the files under `path` in the
+    /// environment's work directory (or the whole work directory if `path`
+    /// is `None`), until interrupted or until `f` returns an error. Changes
+    /// are found by comparing listings of the directory about once a
+    /// second, so a file that's created and removed in between isn't
+    /// reported.
+    pub fn watch_work_dir(
+        &self,
+        name: &EnvironmentName,
+        path: Option<&Path>,
+        f: &mut dyn FnMut(FileEvent) -> Result<()>,
+    ) -> Result<()> {
+        self.check_fully_exists(name)?;
+        let path = path.unwrap_or(Path::new(""));
+        if !path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(anyhow!(
+                "expected a relative path within the work directory, found {path:?}"
+            ));
+        }
+        self.shared.event(Event::WatchingWorkDir(name.clone()));
+
+        let mut last: Option<FileListing> = None;
+        let mut on_listing = |listing: FileListing| -> Result<()> {
+            if let Some(last) = &last {
+                for mut event in diff(last, &listing) {
+                    event.path = path.join(event.path);
+                    f(event)?;
+                }
+            }
+            last = Some(listing);
+            cancel::check()
+        };
+        match self.shared.worktrees.get(name)? {
+            Some(worktree) => {
+                poll_host_dir(&worktree.path.join(path), WATCH_INTERVAL, &mut on_listing)
+            }
+            None => self
+                .runner
+                .watch_work_dir(name, path, WATCH_INTERVAL, &mut on_listing),
+        }
+    }
+}
+
+/// Calls `f` with a listing of the directory every `interval`, until `f`
+/// returns an error.
+///
+/// This is how runners whose work directories are on the host's filesystem
+/// implement [`Runner::watch_work_dir`].
+pub(crate) fn poll_host_dir(
+    dir: &HostPath,
+    interval: Duration,
+    f: &mut dyn FnMut(FileListing) -> Result<()>,
+) -> Result<()> {
+    loop {
+        let mut listing = FileListing::new();
+        list_host_dir(dir.as_host_raw(), Path::new(""), &mut listing)
+            .with_context(|| format!("failed to list files in {dir}"))?;
+        f(listing)?;
+        std::thread::sleep(interval);
+    }
+}
+
+/// Adds the contents of `root.join(rel)` to the listing, recursively.
+fn list_host_dir(root: &Path, rel: &Path, listing: &mut FileListing) -> io::Result<()> {
+    let entries = match std::fs::read_dir(root.join(rel)) {
+        Ok(entries) => entries,
+        // Files may be removed while they're being listed.
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !rel.as_os_str().is_empty() => {
+            return Ok(())
+        }
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_file() {
+            FileKind::File
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else {
+            FileKind::Other
+        };
+        let path = rel.join(entry.file_name());
+        if kind == FileKind::Dir {
+            list_host_dir(root, &path, listing)?;
+        }
+        listing.insert(
+            path,
+            FileState {
+                kind,
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Parses a listing printed by `find -printf '%y\t%s\t%T@\t%P\0'`.
+///
+/// This is how runners that list files from inside the environment
+/// implement [`Runner::watch_work_dir`].
+pub(crate) fn parse_find_listing(output: &[u8]) -> FileListing {
+    let mut listing = FileListing::new();
+    for record in output.split(|b| *b == b'\0') {
+        let Ok(record) = std::str::from_utf8(record) else {
+            continue;
+        };
+        let mut fields = record.splitn(4, '\t');
+        let (Some(kind), Some(size), Some(modified), Some(path)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        if path.is_empty() {
+            continue;
+        }
+        let kind = match kind {
+            "f" => FileKind::File,
+            "d" => FileKind::Dir,
+            "l" => FileKind::Symlink,
+            _ => FileKind::Other,
+        };
+        let modified = match modified.split_once('.') {
+            Some((secs, frac)) => {
+                let nanos = format!("{frac:0<9}");
+                secs.parse().ok().zip(nanos[..9].parse().ok())
+            }
+            None => modified.parse().ok().map(|secs| (secs, 0)),
+        }
+        .map(|(secs, nanos)| UNIX_EPOCH + Duration::new(secs, nanos))
+        .unwrap_or(UNIX_EPOCH);
+        listing.insert(
+            PathBuf::from(path),
+            FileState {
+                kind,
+                size: size.parse().unwrap_or(0),
+                modified,
+            },
+        );
+    }
+    listing
+}
+
+/// Returns the changes from one listing to the next, in path order.
+fn diff(old: &FileListing, new: &FileListing) -> Vec<FileEvent> {
+    let mut events = Vec::new();
+    let event = |change, path: &PathBuf, state: &FileState| FileEvent {
+        change,
+        path: path.clone(),
+        kind: state.kind,
+    };
+    for (path, state) in new {
+        match old.get(path) {
+            None => events.push(event(FileChange::Created, path, state)),
+            Some(old_state) if old_state.kind != state.kind => {
+                events.push(event(FileChange::Removed, path, old_state));
+                events.push(event(FileChange::Created, path, state));
+            }
+            Some(old_state) if state.kind != FileKind::Dir && old_state != state => {
+                events.push(event(FileChange::Modified, path, state));
+            }
+            Some(_) => {}
+        }
+    }
+    for (path, state) in old {
+        if !new.contains_key(path) {
+            events.push(event(FileChange::Removed, path, state));
+        }
+    }
+    events.sort_by(|a, b| a.path.cmp(&b.path));
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+
+    #[test]
+    fn parse_find_listing() {
+        let listing = super::parse_find_listing(
+            b"d\t4096\t1700000000.5\t\0\
+              d\t4096\t1700000000.5\tsrc\0\
+              f\t12\t1700000001.123456789\tsrc/main.rs\0\
+              l\t7\t1700000002\tlink\0",
+        );
+        expect![[r#"
+            {
+                "link": FileState {
+                    kind: Symlink,
+                    size: 7,
+                    modified: SystemTime {
+                        tv_sec: 1700000002,
+                        tv_nsec: 0,
+                    },
+                },
+                "src": FileState {
+                    kind: Dir,
+                    size: 4096,
+                    modified: SystemTime {
+                        tv_sec: 1700000000,
+                        tv_nsec: 500000000,
+                    },
+                },
+                "src/main.rs": FileState {
+                    kind: File,
+                    size: 12,
+                    modified: SystemTime {
+                        tv_sec: 1700000001,
+                        tv_nsec: 123456789,
+                    },
+                },
+            }
+        "#]]
+        .assert_debug_eq(&listing);
+    }
+
+    #[test]
+    fn diff() {
+        let state = |kind, size, secs| FileState {
+            kind,
+            size,
+            modified: UNIX_EPOCH + Duration::from_secs(secs),
+        };
+        let old = FileListing::from([
+            (PathBuf::from("a"), state(FileKind::File, 1, 1)),
+            (PathBuf::from("b"), state(FileKind::File, 1, 1)),
+            (PathBuf::from("c"), state(FileKind::File, 1, 1)),
+            (PathBuf::from("d"), state(FileKind::Dir, 4096, 1)),
+        ]);
+        let new = FileListing::from([
+            (PathBuf::from("a"), state(FileKind::File, 1, 1)),
+            (PathBuf::from("b"), state(FileKind::File, 2, 2)),
+            (PathBuf::from("c"), state(FileKind::Dir, 4096, 2)),
+            (PathBuf::from("d"), state(FileKind::Dir, 4096, 2)),
+            (PathBuf::from("d/e"), state(FileKind::File, 0, 2)),
+        ]);
+        let events = super::diff(&old, &new)
+            .iter()
+            .map(|e| format!("{:?} {:?} {:?}", e.change, e.path, e.kind))
+            .collect::<Vec<_>>()
+            .join("\n");
+        expect![[r#"
+            Modified "b" File
+            Removed "c" File
+            Created "c" Dir
+            Created "d/e" File"#]]
+        .assert_eq(&events);
+    }
+
+    #[test]
+    fn list_host_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+        let mut listing = FileListing::new();
+        super::list_host_dir(dir.path(), Path::new(""), &mut listing).unwrap();
+        assert_eq!(
+            vec![
+                (&PathBuf::from("src"), FileKind::Dir),
+                (&PathBuf::from("src/main.rs"), FileKind::File)
+            ],
+            listing
+                .iter()
+                .map(|(path, state)| (path, state.kind))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(12, listing[Path::new("src/main.rs")].size);
+    }
+}
@@ -0,0 +1,105 @@
+//! Warns when a filesystem holding environments or packages is nearly full,
+//! for the `min_free_space` configuration option.
+//!
+//! Running out of space while extracting packages into a home directory
+//! fails partway with errors that don't mention the disk, so Cubicle checks
+//! ahead of time.
+
+use std::collections::BTreeSet;
+
+use super::fs_util::try_exists;
+use super::runner::Runner;
+use super::{Bytes, Cubicle, HostPath};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+/// A filesystem with less free space than configured.
+#[derive(Debug)]
+struct LowSpace {
+    /// The first directory checked on the filesystem.
+    dir: HostPath,
+    /// The free space available to unprivileged users, in bytes.
+    free: u64,
+}
+
+impl Cubicle {
+    /// Used by `cub list` and `cub new`.
+    ///
+    /// Prints a warning for each filesystem holding environments or
+    /// packages that has less than `min_free_space` free.
+    pub fn warn_if_low_on_space(&self) {
+        let min = self.shared.config.min_free_space;
+        if min == 0 {
+            return;
+        }
+        let dirs = match self.runner.storage_dirs() {
+            Ok(mut dirs) => {
+                dirs.push(self.shared.paths.package_cache.clone());
+                dirs
+            }
+            Err(e) => {
+                warn(e.context("failed to check free disk space"));
+                return;
+            }
+        };
+        match low_space(&dirs, min) {
+            Ok(low) => {
+                for LowSpace { dir, free } in low {
+                    warn(anyhow!(
+                        "only {} is free on the filesystem holding {dir} \
+                        (`min_free_space` is {}). Creating or resetting environments \
+                        may fail partway.",
+                        Bytes(free),
+                        Bytes(min),
+                    ));
+                }
+            }
+            Err(e) => warn(e.context("failed to check free disk space")),
+        }
+    }
+}
+
+/// Returns the filesystems among `dirs` with less than `min` bytes free.
+/// Directories that don't exist are skipped, and each filesystem is only
+/// reported once.
+fn low_space(dirs: &[HostPath], min: u64) -> Result<Vec<LowSpace>> {
+    let mut seen = BTreeSet::new();
+    let mut low = Vec::new();
+    for dir in dirs {
+        if !try_exists(dir).todo_context()? {
+            continue;
+        }
+        let stat = rustix::fs::statvfs(dir.as_host_raw())
+            .with_context(|| format!("failed to get filesystem statistics for {dir}"))?;
+        #[allow(clippy::useless_conversion)] // field types vary by platform
+        let fsid = u64::from(stat.f_fsid);
+        if !seen.insert(fsid) {
+            continue;
+        }
+        let free = stat.f_bavail.saturating_mul(stat.f_frsize);
+        if free < min {
+            low.push(LowSpace {
+                dir: dir.clone(),
+                free,
+            });
+        }
+    }
+    Ok(low)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn low_space() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = HostPath::try_from(tmp.path().to_owned()).unwrap();
+        let missing = dir.join("missing");
+        assert!(super::low_space(&[missing.clone(), dir.clone()], 1)
+            .unwrap()
+            .is_empty());
+        let low = super::low_space(&[dir.clone(), missing, dir.clone()], u64::MAX).unwrap();
+        assert_eq!(1, low.len());
+        assert_eq!(dir.as_host_raw(), low[0].dir.as_host_raw());
+    }
+}
@@ -1,6 +1,8 @@
+use serde::Serialize;
+use std::collections::HashSet;
 use std::ffi::OsString;
-use std::io;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -102,14 +104,22 @@ pub fn file_size_cap(metadata: &cap_std::fs::Metadata) -> Option<u64> {
     None
 }
 
+/// The size and modification time of a directory tree, as returned by
+/// [`summarize_dir`].
 #[derive(Debug)]
 pub struct DirSummary {
+    /// If true, at least one error was encountered while walking the
+    /// directory, so the other fields may be incomplete.
     pub errors: bool,
+    /// The total size in bytes of the files in the directory.
     pub total_size: u64,
+    /// The most recent time that the directory or anything within it was
+    /// modified, or `UNIX_EPOCH` if unknown.
     pub last_modified: SystemTime,
 }
 
 impl DirSummary {
+    /// Returns a summary for a directory that couldn't be read at all.
     pub fn new_with_errors() -> Self {
         Self {
             errors: true,
@@ -119,6 +129,8 @@ impl DirSummary {
     }
 }
 
+/// Walks the directory tree to calculate its size and last modification
+/// time.
 pub fn summarize_dir(path: &HostPath) -> Result<DirSummary> {
     fn handle_entry(summary: &mut DirSummary, entry: Result<WalkDirEntry>) {
         match entry {
@@ -296,6 +308,80 @@ pub struct TarOptions {
 
 pub fn create_tar_from_dir<W: io::Write>(dir: &HostPath, w: W, opts: &TarOptions) -> Result<()> {
     let mut builder = tar::Builder::new(w);
+    append_dir_to_tar(&mut builder, dir, opts)?;
+    builder
+        .into_inner()
+        .and_then(|mut f| f.flush())
+        .todo_context()?;
+    Ok(())
+}
+
+/// Creates a tar archive of an environment's home directory, with its
+/// separate work directory at `w/`.
+pub fn create_home_tar<W: io::Write>(home: &HostPath, work: &HostPath, w: W) -> Result<()> {
+    let mut builder = tar::Builder::new(w);
+    append_dir_to_tar(
+        &mut builder,
+        home,
+        &TarOptions {
+            prefix: None,
+            exclude: vec![PathBuf::from("w")],
+        },
+    )?;
+    append_dir_to_tar(
+        &mut builder,
+        work,
+        &TarOptions {
+            prefix: Some(PathBuf::from("w")),
+            exclude: vec![],
+        },
+    )?;
+    builder
+        .into_inner()
+        .and_then(|mut f| f.flush())
+        .todo_context()?;
+    Ok(())
+}
+
+/// Creates a tar archive of the given files and directories within `dir`,
+/// with their paths relative to `dir`. Paths that don't exist are skipped.
+pub fn create_tar_of_paths<W: io::Write>(dir: &HostPath, paths: &[PathBuf], w: W) -> Result<()> {
+    let mut builder = tar::Builder::new(w);
+    builder.follow_symlinks(false);
+    for path in paths {
+        let full = dir.join(path);
+        let metadata = match std::fs::symlink_metadata(full.as_host_raw()) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("failed to read metadata of {full}")),
+        };
+        if metadata.is_dir() {
+            append_dir_to_tar(
+                &mut builder,
+                &full,
+                &TarOptions {
+                    prefix: Some(path.clone()),
+                    exclude: vec![],
+                },
+            )?;
+        } else {
+            builder
+                .append_path_with_name(full.as_host_raw(), path)
+                .with_context(|| format!("Failed to add {full} to tar archive"))?;
+        }
+    }
+    builder
+        .into_inner()
+        .and_then(|mut f| f.flush())
+        .todo_context()?;
+    Ok(())
+}
+
+fn append_dir_to_tar<W: io::Write>(
+    builder: &mut tar::Builder<W>,
+    dir: &HostPath,
+    opts: &TarOptions,
+) -> Result<()> {
     for entry in WalkDir::new(dir)? {
         let WalkDirEntry {
             parent,
@@ -304,7 +390,7 @@ pub fn create_tar_from_dir<W: io::Write>(dir: &HostPath, w: W, opts: &TarOptions
             file_type,
         } = entry?;
         let mut add = || {
-            if opts.exclude.contains(&path) {
+            if opts.exclude.iter().any(|exclude| path.starts_with(exclude)) {
                 return Ok(());
             }
             let append_path = match &opts.prefix {
@@ -323,6 +409,7 @@ pub fn create_tar_from_dir<W: io::Write>(dir: &HostPath, w: W, opts: &TarOptions
                 use cap_std::fs::MetadataExt;
                 let metadata = entry.metadata().todo_context()?;
                 let mut header = tar::Header::new_gnu();
+                header.set_size(0);
                 header.set_mtime(metadata.mtime() as u64);
                 header.set_uid(u64::from(metadata.uid()));
                 header.set_gid(u64::from(metadata.gid()));
@@ -346,13 +433,81 @@ pub fn create_tar_from_dir<W: io::Write>(dir: &HostPath, w: W, opts: &TarOptions
         };
         add().with_context(|| format!("Failed to add {:#?} to tar archive", dir.join(path)))?;
     }
-    builder
-        .into_inner()
-        .and_then(|mut f| f.flush())
-        .todo_context()?;
     Ok(())
 }
 
+/// Checks that extracting the given tar archives, in order, into a directory
+/// would only write files within that directory.
+///
+/// This rejects entries with absolute paths or `..` components, hard links to
+/// such paths, entries beneath symlinks from earlier entries (possibly in
+/// earlier archives), and special files like devices. Symlinks themselves may
+/// point anywhere.
+pub fn check_tars_confined(paths: &[HostPath]) -> Result<()> {
+    let mut symlinks = HashSet::new();
+    for path in paths {
+        check_tar_confined(path, &mut symlinks)
+            .with_context(|| format!("refusing to extract tar archive {path}"))?;
+    }
+    Ok(())
+}
+
+fn check_tar_confined(path: &HostPath, symlinks: &mut HashSet<PathBuf>) -> Result<()> {
+    let file = std::fs::File::open(path.as_host_raw()).todo_context()?;
+    let mut archive = tar::Archive::new(file);
+    for entry in archive.entries_with_seek().todo_context()? {
+        let entry = entry.todo_context()?;
+        let raw_path = entry.path().todo_context()?;
+        let entry_type = entry.header().entry_type();
+        let entry_path = confined_path(&raw_path)
+            .filter(|path| !path.as_os_str().is_empty() || entry_type.is_dir())
+            .ok_or_else(|| anyhow!("entry {raw_path:?} is outside the target directory"))?;
+        if let Some(symlink) = entry_path
+            .ancestors()
+            .skip(1)
+            .find(|a| symlinks.contains(*a))
+        {
+            return Err(anyhow!(
+                "entry {raw_path:?} is beneath symlink {symlink:?} from an earlier entry"
+            ));
+        }
+        if entry_type.is_hard_link() {
+            let target = entry.link_name().todo_context()?.unwrap_or_default();
+            if confined_path(&target).map_or(true, |t| t.as_os_str().is_empty()) {
+                return Err(anyhow!(
+                    "hard link {raw_path:?} points outside the target directory to {target:?}"
+                ));
+            }
+        } else if entry_type.is_symlink() {
+            symlinks.insert(entry_path);
+        } else if !(entry_type.is_file()
+            || entry_type.is_contiguous()
+            || entry_type.is_gnu_sparse()
+            || entry_type.is_dir()
+            || entry_type.is_pax_global_extensions())
+        {
+            return Err(anyhow!(
+                "entry {raw_path:?} has unsupported type {entry_type:?}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the path without `.` components, or `None` if the path is absolute
+/// or has `..` components.
+fn confined_path(path: &Path) -> Option<PathBuf> {
+    let mut confined = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => confined.push(c),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(confined)
+}
+
 pub fn try_exists(path: &HostPath) -> io::Result<bool> {
     // Adapted from rust `library/std/src/sys_common/fs.rs`
     // since `std::fs::try_exists` is unstable
@@ -363,3 +518,187 @@ pub fn try_exists(path: &HostPath) -> io::Result<bool> {
         Err(error) => Err(error),
     }
 }
+
+/// Writes `value` as JSON to `path`, which must be in `dir`, creating `dir`
+/// if needed.
+pub fn write_json_atomically(
+    dir: &HostPath,
+    path: &HostPath,
+    value: &impl Serialize,
+) -> Result<()> {
+    std::fs::create_dir_all(dir.as_host_raw())
+        .with_context(|| format!("failed to create directory {dir}"))?;
+    // Write to a temporary file first so that concurrent readers never see a
+    // partial file.
+    let mut file = tempfile::NamedTempFile::new_in(dir.as_host_raw())
+        .with_context(|| format!("failed to create temporary file in {dir}"))?;
+    serde_json::to_writer(&mut file, value)
+        .with_context(|| format!("failed to serialize JSON for {path}"))?;
+    file.flush()
+        .with_context(|| format!("failed to write {path}"))?;
+    file.persist(path.as_host_raw())
+        .with_context(|| format!("failed to write {path}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a tar file with the given entries, bypassing the `tar` crate's
+    /// own path checks.
+    fn write_tar(path: &HostPath, entries: &[(&str, tar::EntryType, &str)]) {
+        let mut builder = tar::Builder::new(std::fs::File::create(path.as_host_raw()).unwrap());
+        for (name, entry_type, link) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o644);
+            header.set_size(0);
+            if !link.is_empty() {
+                header.set_link_name(link).unwrap();
+            }
+            header.set_cksum();
+            builder.append(&header, io::empty()).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn check_tars_confined() {
+        use tar::EntryType::{Block, Directory, Link, Regular, Symlink};
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir_path = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        let check = |tars: &[&[(&str, tar::EntryType, &str)]]| {
+            let paths = tars
+                .iter()
+                .enumerate()
+                .map(|(i, entries)| {
+                    let path = tmpdir_path.join(format!("{i}.tar"));
+                    write_tar(&path, entries);
+                    path
+                })
+                .collect::<Vec<_>>();
+            super::check_tars_confined(&paths).map_err(|e| format!("{e:#}"))
+        };
+
+        assert_eq!(
+            Ok(()),
+            check(&[
+                &[
+                    ("./", Directory, ""),
+                    ("./bin/", Directory, ""),
+                    ("./bin/x", Regular, ""),
+                    ("./bin/y", Symlink, "/usr/bin/y"),
+                    ("./bin/z", Link, "./bin/x"),
+                ],
+                &[("bin/w", Symlink, "../../etc/passwd")],
+            ])
+        );
+
+        let err = |tars| check(tars).unwrap_err();
+        assert!(err(&[&[("/etc/passwd", Regular, "")]]).contains("outside the target directory"));
+        assert!(err(&[&[("a/../../b", Regular, "")]]).contains("outside the target directory"));
+        assert!(err(&[&[("", Regular, "")]]).contains("outside the target directory"));
+        assert!(err(&[&[("a", Link, "/etc/passwd")]]).contains("hard link"));
+        assert!(err(&[&[("a", Link, "../b")]]).contains("hard link"));
+        assert!(err(&[&[("a", Block, "")]]).contains("unsupported type"));
+        assert!(
+            err(&[&[("a", Symlink, "/etc")], &[("./a/passwd", Regular, "")]])
+                .contains("beneath symlink")
+        );
+    }
+
+    #[test]
+    fn create_home_tar() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir_path = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        let home = tmpdir_path.join("home");
+        let work = tmpdir_path.join("work");
+        std::fs::create_dir_all(home.join(".config").as_host_raw()).unwrap();
+        std::fs::write(home.join(".config").join("a").as_host_raw(), "a").unwrap();
+        std::fs::create_dir_all(home.join("w").as_host_raw()).unwrap();
+        std::fs::write(home.join("w").join("hidden").as_host_raw(), "").unwrap();
+        std::fs::create_dir_all(work.join("src").as_host_raw()).unwrap();
+        std::fs::write(work.join("src").join("b").as_host_raw(), "bb").unwrap();
+
+        let mut buf = Vec::new();
+        super::create_home_tar(&home, &work, &mut buf).unwrap();
+        let mut entries = tar::Archive::new(buf.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                format!(
+                    "{} {}",
+                    entry.path().unwrap().display(),
+                    entry.header().size().unwrap()
+                )
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        expect_test::expect![[r#"
+            [
+                ".config 0",
+                ".config/a 1",
+                "w/src 0",
+                "w/src/b 2",
+            ]
+        "#]]
+        .assert_debug_eq(&entries);
+    }
+
+    #[test]
+    fn create_tar_of_paths() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let home = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        std::fs::create_dir_all(home.join(".cache/cargo/registry").as_host_raw()).unwrap();
+        std::fs::write(home.join(".cache/cargo/registry/a").as_host_raw(), "a").unwrap();
+        std::fs::write(home.join(".cache/other").as_host_raw(), "").unwrap();
+        std::fs::write(home.join(".token").as_host_raw(), "tok").unwrap();
+
+        let mut buf = Vec::new();
+        super::create_tar_of_paths(
+            &home,
+            &[
+                PathBuf::from(".cache/cargo"),
+                PathBuf::from(".token"),
+                PathBuf::from("missing"),
+            ],
+            &mut buf,
+        )
+        .unwrap();
+        let mut entries = tar::Archive::new(buf.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                format!(
+                    "{} {}",
+                    entry.path().unwrap().display(),
+                    entry.header().size().unwrap()
+                )
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        expect_test::expect![[r#"
+            [
+                ".cache/cargo/registry 0",
+                ".cache/cargo/registry/a 1",
+                ".token 3",
+            ]
+        "#]]
+        .assert_debug_eq(&entries);
+    }
+
+    #[test]
+    fn write_json_atomically() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = HostPath::try_from(tmp.path().to_owned()).unwrap().join("a");
+        let path = dir.join("b.json");
+        super::write_json_atomically(&dir, &path, &[1, 2]).unwrap();
+        super::write_json_atomically(&dir, &path, &[3]).unwrap();
+        assert_eq!("[3]", std::fs::read_to_string(path.as_host_raw()).unwrap());
+        assert_eq!(vec![OsString::from("b.json")], try_iterdir(&dir).unwrap());
+    }
+}
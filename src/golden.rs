@@ -0,0 +1,108 @@
+//! Tracks which environments are instances of "golden" environments.
+//!
+//! An instance's home directory is an overlay: the golden environment's home
+//! directory is the read-only lower layer, and the instance only stores its
+//! own changes in an upper layer. Each instance has a small file naming its
+//! golden environment.
+
+use std::io;
+use std::str::FromStr;
+
+use super::fs_util::try_iterdir;
+use super::{EnvironmentName, HostPath};
+use crate::somehow::{Context, Result};
+
+pub struct GoldenRegistry {
+    dir: HostPath,
+}
+
+impl GoldenRegistry {
+    pub fn new(dir: HostPath) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, name: &EnvironmentName) -> HostPath {
+        self.dir.join(name.as_filename())
+    }
+
+    /// Returns the golden environment that `name` is an instance of, or
+    /// `None` if `name` is an ordinary environment.
+    pub fn golden(&self, name: &EnvironmentName) -> Result<Option<EnvironmentName>> {
+        let path = self.path(name);
+        match std::fs::read_to_string(path.as_host_raw()) {
+            Ok(golden) => EnvironmentName::from_str(golden.trim())
+                .map(Some)
+                .with_context(|| format!("invalid environment name in {path}")),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to read {path}")),
+        }
+    }
+
+    /// Returns the instances of the given golden environment.
+    pub fn instances(&self, golden: &EnvironmentName) -> Result<Vec<EnvironmentName>> {
+        let mut instances = Vec::new();
+        for filename in try_iterdir(&self.dir)? {
+            let name = EnvironmentName::from_filename(&filename).with_context(|| {
+                format!(
+                    "error parsing environment name from path {}",
+                    self.dir.join(&filename)
+                )
+            })?;
+            if self.golden(&name)?.as_ref() == Some(golden) {
+                instances.push(name);
+            }
+        }
+        Ok(instances)
+    }
+
+    /// Records that `name` is an instance of `golden`.
+    pub fn record(&self, name: &EnvironmentName, golden: &EnvironmentName) -> Result<()> {
+        std::fs::create_dir_all(self.dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {}", self.dir))?;
+        let path = self.path(name);
+        std::fs::write(path.as_host_raw(), format!("{}\n", golden.as_str()))
+            .with_context(|| format!("failed to write {path}"))
+    }
+
+    /// Removes the record of `name` being an instance, if any.
+    pub fn forget(&self, name: &EnvironmentName) -> Result<()> {
+        let path = self.path(name);
+        match std::fs::remove_file(path.as_host_raw()) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("failed to remove {path}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir_path = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        let registry = GoldenRegistry::new(tmpdir_path.join("golden"));
+        let golden = EnvironmentName::from_str("base").unwrap();
+        let a = EnvironmentName::from_str("a").unwrap();
+        let b = EnvironmentName::from_str("b/c").unwrap();
+
+        assert_eq!(None, registry.golden(&a).unwrap());
+        assert!(registry.instances(&golden).unwrap().is_empty());
+
+        registry.record(&a, &golden).unwrap();
+        registry.record(&b, &golden).unwrap();
+        assert_eq!(Some(golden.clone()), registry.golden(&a).unwrap());
+        assert_eq!(
+            vec![a.clone(), b.clone()],
+            registry.instances(&golden).unwrap()
+        );
+        assert!(registry.instances(&a).unwrap().is_empty());
+
+        registry.forget(&a).unwrap();
+        registry.forget(&a).unwrap();
+        assert_eq!(None, registry.golden(&a).unwrap());
+        assert_eq!(vec![b], registry.instances(&golden).unwrap());
+    }
+}
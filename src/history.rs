@@ -0,0 +1,374 @@
+//! Records lifecycle operations in append-only logs, for `cub history`.
+//!
+//! Each operation on an environment is appended to that environment's log
+//! and to a global log. Package builds only go in the global log, since
+//! they're shared between environments. The logs are JSON Lines files kept
+//! with Cubicle's data rather than its cache, and they outlive the
+//! environments, so that `cub history` can still show what happened to a
+//! purged environment.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::json::{deserialize_time_opt, format_rfc3339, serialize_time_opt};
+use super::{Cubicle, EnvironmentName, FullPackageName, HostPath};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+pub struct HistoryLog {
+    dir: HostPath,
+}
+
+/// One operation recorded by `cub history`.
+///
+/// The [`fmt::Display`] impl renders each entry as a line of text starting
+/// with its timestamp.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct HistoryEntry {
+    /// When the operation finished, to the second.
+    #[serde(
+        serialize_with = "serialize_time",
+        deserialize_with = "deserialize_time"
+    )]
+    pub time: SystemTime,
+    /// What happened.
+    pub operation: Operation,
+}
+
+/// A lifecycle operation in a [`HistoryEntry`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+#[non_exhaustive]
+pub enum Operation {
+    /// Created an environment, including with `cub restore`.
+    Create {
+        /// The environment.
+        name: EnvironmentName,
+        /// The environment's packages. This is empty for instances of golden
+        /// environments, which get their packages from the golden
+        /// environment.
+        packages: Vec<PackageVersion>,
+        /// The golden environment, if this is an instance of one.
+        golden: Option<EnvironmentName>,
+    },
+    /// Reset an environment's home directory.
+    Reset {
+        /// The environment.
+        name: EnvironmentName,
+        /// The environment's packages, or empty for instances of golden
+        /// environments.
+        packages: Vec<PackageVersion>,
+        /// The golden environment, if this is an instance of one.
+        golden: Option<EnvironmentName>,
+    },
+    /// Extracted newly built packages over an environment's home directory,
+    /// with `cub package update --reseed`.
+    Reseed {
+        /// The environment.
+        name: EnvironmentName,
+        /// The packages that were extracted.
+        packages: Vec<PackageVersion>,
+    },
+    /// Purged an environment.
+    Purge {
+        /// The environment.
+        name: EnvironmentName,
+    },
+    /// Built a package, successfully or not.
+    UpdatePackage {
+        /// The package and, if the build succeeded, its new version.
+        package: PackageVersion,
+        /// True if the build failed.
+        failed: bool,
+    },
+}
+
+/// A package and the build of it that was used.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct PackageVersion {
+    /// The package.
+    pub name: FullPackageName,
+    /// A short prefix of the SHA-256 digest of the package's build output,
+    /// or `None` for packages that aren't built by Cubicle, like Debian
+    /// packages.
+    pub version: Option<String>,
+}
+
+impl PackageVersion {
+    pub(crate) fn new(name: FullPackageName, version: Option<String>) -> Self {
+        Self { name, version }
+    }
+}
+
+impl HistoryLog {
+    pub fn new(dir: HostPath) -> Self {
+        Self { dir }
+    }
+
+    fn global_path(&self) -> HostPath {
+        self.dir.join("all.jsonl")
+    }
+
+    fn environment_path(&self, name: &EnvironmentName) -> HostPath {
+        self.dir
+            .join("environments")
+            .join(format!("{}.jsonl", name.as_filename()))
+    }
+
+    /// Appends an entry for the operation to the global log and, if it
+    /// concerns an environment, to that environment's log.
+    ///
+    /// The history is informational, so this only warns on errors, rather
+    /// than failing an operation that already happened.
+    pub fn record(&self, operation: Operation) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| UNIX_EPOCH + Duration::from_secs(d.as_secs()))
+            .unwrap_or(UNIX_EPOCH);
+        let entry = HistoryEntry { time, operation };
+        let environment = match &entry.operation {
+            Operation::Create { name, .. }
+            | Operation::Reset { name, .. }
+            | Operation::Reseed { name, .. }
+            | Operation::Purge { name } => Some(name),
+            Operation::UpdatePackage { .. } => None,
+        };
+        let result = (|| -> Result<()> {
+            let mut line =
+                serde_json::to_vec(&entry).context("failed to serialize history entry")?;
+            line.push(b'\n');
+            let dir = self.dir.join("environments");
+            std::fs::create_dir_all(dir.as_host_raw())
+                .with_context(|| format!("failed to create directory {dir}"))?;
+            append(&self.global_path(), &line)?;
+            if let Some(name) = environment {
+                append(&self.environment_path(name), &line)?;
+            }
+            Ok(())
+        })();
+        if let Err(e) = result {
+            warn(e.context("failed to record operation in history"));
+        }
+    }
+
+    /// Returns the entries in the environment's log, or in the global log if
+    /// `name` is `None`, oldest first.
+    pub fn entries(&self, name: Option<&EnvironmentName>) -> Result<Vec<HistoryEntry>> {
+        let path = match name {
+            Some(name) => self.environment_path(name),
+            None => self.global_path(),
+        };
+        let file = match File::open(path.as_host_raw()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("failed to open {path}")),
+        };
+        let mut entries = Vec::new();
+        for (i, line) in io::BufReader::new(file).lines().enumerate() {
+            let line = line.with_context(|| format!("failed to read {path}"))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // A line may be cut short if `cub` was killed while appending
+            // it. That shouldn't hide the rest of the history.
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => warn(anyhow!(e).context(format!(
+                    "skipping invalid entry on line {} of {path}",
+                    i + 1
+                ))),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Cubicle {
+    /// Corresponds to `cub history`.
+    ///
+    /// Returns the operations recorded for the environment, or for all
+    /// environments and packages if `name` is `None`, oldest first. This
+    /// works for environments that no longer exist.
+    pub fn history(&self, name: Option<&EnvironmentName>) -> Result<Vec<HistoryEntry>> {
+        self.shared.history.entries(name)
+    }
+
+    /// Returns the packages with their current versions, for recording in
+    /// the history.
+    pub(super) fn package_versions(
+        &self,
+        packages: &BTreeSet<FullPackageName>,
+    ) -> Vec<PackageVersion> {
+        packages
+            .iter()
+            .map(|name| {
+                let version = self.package_version(name).unwrap_or_else(|e| {
+                    warn(e);
+                    None
+                });
+                PackageVersion::new(name.clone(), version)
+            })
+            .collect()
+    }
+}
+
+/// Appends `buf` to the file, creating it if needed.
+fn append(path: &HostPath, buf: &[u8]) -> Result<()> {
+    // A single write to a file opened for appending lands at the end, even
+    // if another process is appending to it too.
+    File::options()
+        .append(true)
+        .create(true)
+        .open(path.as_host_raw())
+        .and_then(|mut file| file.write_all(buf))
+        .with_context(|| format!("failed to append to {path}"))
+}
+
+fn serialize_time<S>(time: &SystemTime, ser: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serialize_time_opt(&Some(*time), ser)
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> std::result::Result<SystemTime, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    deserialize_time_opt(deserializer)?.ok_or_else(|| D::Error::custom("missing timestamp"))
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}  ", format_rfc3339(self.time))?;
+        let packages = |f: &mut fmt::Formatter<'_>,
+                        packages: &[PackageVersion],
+                        golden: &Option<EnvironmentName>| {
+            match golden {
+                Some(golden) => write!(f, " as an instance of {golden}"),
+                None => {
+                    write!(f, " with packages:")?;
+                    for (i, package) in packages.iter().enumerate() {
+                        let sep = if i == 0 { "" } else { "," };
+                        match &package.version {
+                            Some(version) => write!(f, "{sep} {} ({version})", package.name)?,
+                            None => write!(f, "{sep} {}", package.name)?,
+                        }
+                    }
+                    Ok(())
+                }
+            }
+        };
+        match &self.operation {
+            Operation::Create {
+                name,
+                packages: p,
+                golden,
+            } => {
+                write!(f, "Created environment {name}")?;
+                packages(f, p, golden)
+            }
+            Operation::Reset {
+                name,
+                packages: p,
+                golden,
+            } => {
+                write!(f, "Reset environment {name}")?;
+                packages(f, p, golden)
+            }
+            Operation::Reseed { name, packages: p } => {
+                write!(f, "Re-seeded environment {name}")?;
+                packages(f, p, &None)
+            }
+            Operation::Purge { name } => write!(f, "Purged environment {name}"),
+            Operation::UpdatePackage {
+                package,
+                failed: true,
+            } => write!(f, "Failed to update package {}", package.name),
+            Operation::UpdatePackage {
+                package,
+                failed: false,
+            } => match &package.version {
+                Some(version) => write!(f, "Updated package {} ({version})", package.name),
+                None => write!(f, "Updated package {}", package.name),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+    use std::str::FromStr;
+
+    #[test]
+    fn record_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = HistoryLog::new(HostPath::try_from(dir.path().to_owned()).unwrap());
+        let name = EnvironmentName::from_str("dev").unwrap();
+        let other = EnvironmentName::from_str("other").unwrap();
+        log.record(Operation::Create {
+            name: name.clone(),
+            packages: vec![
+                PackageVersion::new(
+                    FullPackageName::from_str("default").unwrap(),
+                    Some(String::from("0123456789ab")),
+                ),
+                PackageVersion::new(FullPackageName::from_str("debian.git").unwrap(), None),
+            ],
+            golden: None,
+        });
+        log.record(Operation::Reset {
+            name: other.clone(),
+            packages: Vec::new(),
+            golden: Some(name.clone()),
+        });
+        log.record(Operation::Reseed {
+            name: name.clone(),
+            packages: vec![PackageVersion::new(
+                FullPackageName::from_str("rust").unwrap(),
+                Some(String::from("fedcba987654")),
+            )],
+        });
+        log.record(Operation::UpdatePackage {
+            package: PackageVersion::new(FullPackageName::from_str("rust").unwrap(), None),
+            failed: true,
+        });
+        log.record(Operation::Purge { name: name.clone() });
+
+        let show = |entries: Vec<HistoryEntry>| {
+            entries
+                .iter()
+                .map(|entry| {
+                    let line = entry.to_string();
+                    // Skip the timestamp.
+                    line.split_once("  ").unwrap().1.to_owned()
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        expect![[r#"
+            Created environment "dev" with packages: "default" (0123456789ab), "debian.git"
+            Reset environment "other" as an instance of "dev"
+            Re-seeded environment "dev" with packages: "rust" (fedcba987654)
+            Failed to update package "rust"
+            Purged environment "dev""#]]
+        .assert_eq(&show(log.entries(None).unwrap()));
+        expect![[r#"
+            Created environment "dev" with packages: "default" (0123456789ab), "debian.git"
+            Re-seeded environment "dev" with packages: "rust" (fedcba987654)
+            Purged environment "dev""#]]
+        .assert_eq(&show(log.entries(Some(&name)).unwrap()));
+        assert!(log
+            .entries(Some(&EnvironmentName::from_str("nope").unwrap()))
+            .unwrap()
+            .is_empty());
+    }
+}
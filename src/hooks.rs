@@ -0,0 +1,130 @@
+//! Runs the host-side lifecycle scripts configured in [`Hooks`].
+
+use std::path::Path;
+
+use super::command_ext::Command;
+use super::config::Hooks;
+use super::{EnvironmentName, PlannedAction, RunnerKind};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// A point in an environment's lifecycle where a hook may run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Hook {
+    Create,
+    Enter,
+    Reset,
+    Purge,
+}
+
+impl Hook {
+    /// Returns the name of the hook's setting, which is also passed to the
+    /// script as `CUBICLE_HOOK`.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Create => "on_create",
+            Self::Enter => "on_enter",
+            Self::Reset => "on_reset",
+            Self::Purge => "on_purge",
+        }
+    }
+
+    fn path(self, hooks: &Hooks) -> Option<&Path> {
+        match self {
+            Self::Create => hooks.on_create.as_deref(),
+            Self::Enter => hooks.on_enter.as_deref(),
+            Self::Reset => hooks.on_reset.as_deref(),
+            Self::Purge => hooks.on_purge.as_deref(),
+        }
+    }
+}
+
+/// Describes what [`run`] would do, for a dry run. Returns `None` if no
+/// script is configured for `hook`.
+pub fn plan(hooks: &Hooks, hook: Hook, name: &EnvironmentName) -> Option<PlannedAction> {
+    hook.path(hooks).map(|path| PlannedAction::RunHook {
+        hook: hook.name(),
+        path: path.to_owned(),
+        name: name.clone(),
+    })
+}
+
+/// Runs the configured script for `hook`, if any, and waits for it to exit.
+pub fn run(
+    hooks: &Hooks,
+    hook: Hook,
+    runner: RunnerKind,
+    name: &EnvironmentName,
+    golden: Option<&EnvironmentName>,
+) -> Result<()> {
+    let Some(path) = hook.path(hooks) else {
+        return Ok(());
+    };
+    let mut command = Command::new(path);
+    command
+        .env("CUBICLE_HOOK", hook.name())
+        .env("CUBICLE_ENV", name.as_str())
+        .env("CUBICLE_RUNNER", runner.as_str());
+    if let Some(golden) = golden {
+        command.env("CUBICLE_GOLDEN", golden.as_str());
+    }
+    let status = command
+        .status()
+        .with_context(|| format!("failed to run `{}` hook {path:?}", hook.name()))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "`{}` hook {path:?} for environment {name} exited with {status}",
+            hook.name()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn run_hooks() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let out = tmpdir.path().join("out");
+        let script = tmpdir.path().join("hook.sh");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\n\
+                echo \"$CUBICLE_HOOK $CUBICLE_ENV $CUBICLE_RUNNER ${{CUBICLE_GOLDEN-none}}\" >> {out:?}\n\
+                [ \"$CUBICLE_HOOK\" != on_purge ]\n"
+            ),
+        )
+        .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let hooks = Hooks {
+            on_create: Some(script.clone()),
+            on_enter: None,
+            on_reset: Some(script.clone()),
+            on_purge: Some(script),
+        };
+        let name = EnvironmentName::from_str("foo").unwrap();
+        let golden = EnvironmentName::from_str("base").unwrap();
+        run(&hooks, Hook::Create, RunnerKind::Docker, &name, None).unwrap();
+        run(&hooks, Hook::Enter, RunnerKind::Docker, &name, None).unwrap();
+        run(&hooks, Hook::Reset, RunnerKind::User, &name, Some(&golden)).unwrap();
+        let error = run(&hooks, Hook::Purge, RunnerKind::User, &name, None)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("`on_purge` hook"), "{error}");
+        assert_eq!(
+            "on_create foo docker none\n\
+            on_reset foo user base\n\
+            on_purge foo user none\n",
+            std::fs::read_to_string(out).unwrap()
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Forwards `xdg-open` requests from environments to the host, for the
+//! `host_open` configuration option.
+//!
+//! Each environment gets a named pipe in a directory on the host, which the
+//! runners make available at [`ENV_DIR`] inside the environment. The
+//! `xdg-open` wrapper from the `host-open` package writes one request per
+//! line to the pipe. While `cub enter`, `cub exec`, or an SSH connection is
+//! running for the environment, Cubicle reads the requests and opens them
+//! on the host. Unlike a Unix socket, writing to a named pipe doesn't need
+//! any extra tools inside the environment.
+//!
+//! Environments are less trusted than the host, so Cubicle only opens
+//! `http` and `https` URLs as given. For files, it copies the file out of
+//! the environment's home directory and opens the copy. The copies are
+//! removed after a day, or when the environment is purged.
+
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader};
+use std::path::{Component, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::command_ext::Command;
+use super::fs_util::{rmtree, try_exists, try_iterdir_dirs};
+use super::paths::Paths;
+use super::{EnvironmentName, HostPath};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// Where the directory holding the pipe appears inside environments.
+pub const ENV_DIR: &str = "/run/cubicle-host-open";
+
+/// The name of the pipe within the directory.
+const PIPE: &str = "requests";
+
+/// A request from an environment to open something on the host.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Request {
+    /// An `http` or `https` URL.
+    Url(String),
+    /// A file, relative to the environment's home directory.
+    File(PathBuf),
+}
+
+impl Request {
+    /// Parses a line written to the pipe, like `url https://example.org/`
+    /// or `file w/README.html`.
+    pub fn parse(line: &str) -> Result<Self> {
+        match line.split_once(' ') {
+            Some(("url", url)) => {
+                if url.starts_with("http://") || url.starts_with("https://") {
+                    Ok(Self::Url(url.to_owned()))
+                } else {
+                    Err(anyhow!("only http and https URLs may be opened: {url:?}"))
+                }
+            }
+            Some(("file", path)) => {
+                let path = PathBuf::from(path);
+                let confined = path.components().count() > 0
+                    && path
+                        .components()
+                        .all(|component| matches!(component, Component::Normal(_)));
+                if confined {
+                    Ok(Self::File(path))
+                } else {
+                    Err(anyhow!(
+                        "file paths must be relative to the home directory: {path:?}"
+                    ))
+                }
+            }
+            _ => Err(anyhow!("invalid request: {line:?}")),
+        }
+    }
+}
+
+/// How long to keep copies of files. The program opening a copy may read it
+/// some time after it's started, so the copies can't be removed right away.
+const COPY_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Removes the directories in `files` holding copies of files that were
+/// opened longer than [`COPY_LIFETIME`] before `now`.
+pub fn remove_old_copies(files: &HostPath, now: SystemTime) -> Result<()> {
+    for dir in try_iterdir_dirs(files)? {
+        let dir = files.join(dir);
+        let modified = std::fs::metadata(dir.as_host_raw())
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("failed to get modification time of {dir}"))?;
+        if now
+            .duration_since(modified)
+            .is_ok_and(|age| age > COPY_LIFETIME)
+        {
+            rmtree(&dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the host directory holding the environment's pipe.
+pub fn host_dir(paths: &Paths, name: &EnvironmentName) -> HostPath {
+    paths.cache.join("host-open").join(name.as_filename())
+}
+
+/// Creates the directory holding the pipe and the pipe itself, if needed.
+///
+/// Runners call this before making the directory available inside the
+/// environment.
+pub fn create_pipe(dir: &HostPath) -> Result<()> {
+    let pipe = dir.join(PIPE);
+    if try_exists(&pipe).todo_context()? {
+        return Ok(());
+    }
+    std::fs::create_dir_all(dir.as_host_raw())
+        .with_context(|| format!("failed to create directory {dir}"))?;
+    let status = Command::new("mkfifo")
+        .args(["-m", "600"])
+        .arg(pipe.as_host_raw())
+        .status()
+        .context("failed to run mkfifo")?;
+    if !status.success() {
+        return Err(anyhow!("mkfifo {pipe} exited with {status}"));
+    }
+    Ok(())
+}
+
+/// Reads requests from the pipe in `dir` and passes each one to `handle`.
+///
+/// This doesn't return unless reading the pipe fails. Errors from `handle`
+/// and invalid requests are passed to `report`.
+pub fn serve<H, R>(dir: &HostPath, handle: H, report: R) -> Result<()>
+where
+    H: Fn(Request) -> Result<()>,
+    R: Fn(crate::somehow::Error),
+{
+    let pipe = dir.join(PIPE);
+    // Opening the pipe for writing too means that this never sees the end
+    // of the file, even when no environment process has it open.
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(pipe.as_host_raw())
+        .with_context(|| format!("failed to open {pipe}"))?;
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("failed to read {pipe}"))?;
+        if let Err(e) = Request::parse(&line).and_then(&handle) {
+            report(e);
+        }
+    }
+    Ok(())
+}
+
+/// Opens the URL or file on the host with the given command, without
+/// waiting for it to exit.
+pub fn open(command: &str, target: &OsStr) -> Result<()> {
+    Command::new(command)
+        .arg(target)
+        .spawn_detached()
+        .with_context(|| format!("failed to run {command:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_parse() {
+        let parse = |line| Request::parse(line).map_err(|e| e.to_string());
+        assert_eq!(
+            Ok(Request::Url(String::from("https://example.org/a b"))),
+            parse("url https://example.org/a b")
+        );
+        assert_eq!(
+            Ok(Request::File(PathBuf::from("w/docs/index.html"))),
+            parse("file w/docs/index.html")
+        );
+        assert!(parse("url file:///etc/passwd")
+            .unwrap_err()
+            .contains("only http and https"));
+        assert!(parse("url javascript:alert(1)").is_err());
+        assert!(parse("file /etc/passwd")
+            .unwrap_err()
+            .contains("relative to the home directory"));
+        assert!(parse("file w/../../etc/passwd").is_err());
+        assert!(parse("file ").is_err());
+        assert!(parse("exec rm -rf /").is_err());
+    }
+
+    #[test]
+    fn remove_old_copies() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let files = HostPath::try_from(tmpdir.path().to_owned()).unwrap();
+        std::fs::create_dir(files.join("a").as_host_raw()).unwrap();
+        std::fs::write(files.join("a/index.html").as_host_raw(), "hi").unwrap();
+
+        super::remove_old_copies(&files, SystemTime::now()).unwrap();
+        assert!(try_exists(&files.join("a/index.html")).unwrap());
+
+        let later = SystemTime::now() + COPY_LIFETIME + Duration::from_secs(60);
+        std::fs::create_dir(files.join("b").as_host_raw()).unwrap();
+        super::remove_old_copies(&files, later).unwrap();
+        assert!(!try_exists(&files.join("a")).unwrap());
+        assert!(!try_exists(&files.join("b")).unwrap());
+    }
+}
@@ -0,0 +1,370 @@
+//! The JSON documents that `cub` prints for machine consumption.
+//!
+//! `cub list --format json` prints an [`EnvironmentList`], `cub show --format
+//! json` prints an [`EnvironmentShow`], `cub show --debian-packages --format
+//! json` prints an [`EnvironmentDebianPackages`], `cub package list
+//! --format json` prints a [`PackageList`], and `cub watch` prints a
+//! [`WatchEvent`] per line. Each document is an object with a `version`
+//! field set to [`SCHEMA_VERSION`].
+//!
+//! New fields may appear in any release without changing the version, so
+//! readers should ignore fields they don't recognize. Removing a field or
+//! changing its meaning or type increments the version.
+//!
+//! Timestamps are RFC 3339 strings in UTC, like `"2024-05-06T07:08:09.5Z"`,
+//! or `null` when unavailable.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::runner::FileKind;
+use super::{
+    DebianPackages, EnvironmentDetails, EnvironmentName, FileChange, FileEvent, FullPackageName,
+    PackageDetails,
+};
+
+/// The version of the documents in this module.
+///
+/// History:
+/// - Version 1 added the `version` field and changed timestamps from
+///   floating-point seconds since the Unix epoch to RFC 3339 strings.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Printed by `cub list --format json`.
+#[derive(Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct EnvironmentList {
+    /// See [`SCHEMA_VERSION`].
+    pub version: u32,
+    /// Details about every environment, keyed by name.
+    pub environments: BTreeMap<EnvironmentName, EnvironmentDetails>,
+}
+
+impl EnvironmentList {
+    /// Wraps the result of [`Cubicle::get_environments`](crate::Cubicle::get_environments).
+    pub fn new(environments: BTreeMap<EnvironmentName, EnvironmentDetails>) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            environments,
+        }
+    }
+}
+
+/// Printed by `cub show --format json`.
+#[derive(Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct EnvironmentShow {
+    /// See [`SCHEMA_VERSION`].
+    pub version: u32,
+    /// The environment's name.
+    pub name: EnvironmentName,
+    /// Details about the environment.
+    pub environment: EnvironmentDetails,
+}
+
+impl EnvironmentShow {
+    /// Wraps the result of [`Cubicle::get_environment`](crate::Cubicle::get_environment).
+    pub fn new(name: EnvironmentName, environment: EnvironmentDetails) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            name,
+            environment,
+        }
+    }
+}
+
+/// Printed by `cub show --debian-packages --format json`.
+#[derive(Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct EnvironmentDebianPackages {
+    /// See [`SCHEMA_VERSION`].
+    pub version: u32,
+    /// The environment's name.
+    pub name: EnvironmentName,
+    /// The environment's requested and installed Debian packages.
+    pub debian_packages: DebianPackages,
+}
+
+impl EnvironmentDebianPackages {
+    /// Wraps the result of
+    /// [`Cubicle::get_debian_packages`](crate::Cubicle::get_debian_packages).
+    pub fn new(name: EnvironmentName, debian_packages: DebianPackages) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            name,
+            debian_packages,
+        }
+    }
+}
+
+/// Printed by `cub package list --format json`.
+#[derive(Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct PackageList {
+    /// See [`SCHEMA_VERSION`].
+    pub version: u32,
+    /// Details about every package, keyed by name.
+    pub packages: BTreeMap<FullPackageName, PackageDetails>,
+}
+
+impl PackageList {
+    /// Wraps the result of [`Cubicle::get_packages`](crate::Cubicle::get_packages).
+    pub fn new(packages: BTreeMap<FullPackageName, PackageDetails>) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            packages,
+        }
+    }
+}
+
+/// Printed by `cub watch`, one per line.
+#[derive(Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct WatchEvent {
+    /// See [`SCHEMA_VERSION`].
+    pub version: u32,
+    /// When the change was noticed.
+    #[serde(
+        serialize_with = "serialize_time_opt",
+        deserialize_with = "deserialize_time_opt"
+    )]
+    pub time: Option<SystemTime>,
+    /// How the path changed.
+    pub change: FileChange,
+    /// The path, relative to the environment's work directory.
+    pub path: PathBuf,
+    /// The type of file at the path, or that was at the path if it was
+    /// removed.
+    pub kind: FileKind,
+}
+
+impl WatchEvent {
+    /// Wraps an event from
+    /// [`Cubicle::watch_work_dir`](crate::Cubicle::watch_work_dir), noticed
+    /// now.
+    pub fn new(event: FileEvent) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            time: Some(SystemTime::now()),
+            change: event.change,
+            path: event.path,
+            kind: event.kind,
+        }
+    }
+}
+
+/// Serializes an optional timestamp as an RFC 3339 string or null.
+pub(crate) fn serialize_time_opt<S>(
+    time: &Option<SystemTime>,
+    ser: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match time {
+        Some(time) => ser.serialize_some(&format_rfc3339(*time)),
+        None => ser.serialize_none(),
+    }
+}
+
+/// Deserializes an optional timestamp from an RFC 3339 string or null.
+pub(crate) fn deserialize_time_opt<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<SystemTime>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => parse_rfc3339(&s)
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("invalid RFC 3339 timestamp: {s:?}"))),
+        None => Ok(None),
+    }
+}
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Formats a time in UTC, with as many fractional digits as needed.
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let (secs, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            let d = e.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                nanos => (-(d.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        }
+    };
+    let (year, month, day) = civil_from_days(secs.div_euclid(SECS_PER_DAY));
+    let secs = secs.rem_euclid(SECS_PER_DAY);
+    let mut s = format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    if nanos != 0 {
+        s.push('.');
+        s.push_str(format!("{nanos:09}").trim_end_matches('0'));
+    }
+    s.push('Z');
+    s
+}
+
+/// Parses a full RFC 3339 date-time, with any UTC offset.
+fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    let b = s.as_bytes();
+    let num = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = b.get(range)?;
+        if !digits.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        std::str::from_utf8(digits).ok()?.parse().ok()
+    };
+    let sep = |i: usize, allowed: &[u8]| b.get(i).is_some_and(|c| allowed.contains(c));
+    if !(sep(4, b"-") && sep(7, b"-") && sep(10, b"Tt ") && sep(13, b":") && sep(16, b":")) {
+        return None;
+    }
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, minute, second) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let mut i = 19;
+    let mut nanos: u32 = 0;
+    if sep(i, b".") {
+        i += 1;
+        let start = i;
+        while b.get(i).is_some_and(u8::is_ascii_digit) {
+            i += 1;
+        }
+        if i == start {
+            return None;
+        }
+        let digits = &s[start..i.min(start + 9)];
+        nanos = format!("{digits:0<9}").parse().ok()?;
+    }
+
+    let offset = match b.get(i)? {
+        b'Z' | b'z' if i + 1 == b.len() => 0,
+        sign @ (b'+' | b'-') if i + 6 == b.len() && sep(i + 3, b":") => {
+            let (h, m) = (num(i + 1..i + 3)?, num(i + 4..i + 6)?);
+            if h > 23 || m > 59 {
+                return None;
+            }
+            let offset = h * 3600 + m * 60;
+            if *sign == b'+' {
+                offset
+            } else {
+                -offset
+            }
+        }
+        _ => return None,
+    };
+
+    // Leap seconds are folded into the following second.
+    let secs =
+        days_from_civil(year, month, day) * SECS_PER_DAY + hour * 3600 + minute * 60 + second
+            - offset;
+    let subsec = Duration::from_nanos(u64::from(nanos));
+    if secs >= 0 {
+        Some(UNIX_EPOCH + Duration::from_secs(secs as u64) + subsec)
+    } else {
+        Some(UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + subsec)
+    }
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) in the
+/// proleptic Gregorian calendar.
+///
+/// This is Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// The inverse of [`civil_from_days`].
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc3339() {
+        let cases = [
+            (0_i64, 0, "1970-01-01T00:00:00Z"),
+            (951_782_400, 0, "2000-02-29T00:00:00Z"),
+            (1_715_000_000, 500_000_000, "2024-05-06T12:53:20.5Z"),
+            (4_102_444_799, 1, "2099-12-31T23:59:59.000000001Z"),
+            (-1, 0, "1969-12-31T23:59:59Z"),
+        ];
+        for (secs, nanos, expected) in cases {
+            let time = if secs >= 0 {
+                UNIX_EPOCH + Duration::new(secs as u64, nanos)
+            } else {
+                UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+                    + Duration::from_nanos(nanos.into())
+            };
+            assert_eq!(expected, format_rfc3339(time), "{secs}.{nanos:09}");
+            assert_eq!(Some(time), parse_rfc3339(expected), "{expected}");
+        }
+
+        assert_eq!(
+            parse_rfc3339("2024-05-06T12:53:20.5Z"),
+            parse_rfc3339("2024-05-06T14:53:20.500+02:00")
+        );
+        assert_eq!(
+            parse_rfc3339("1970-01-01T00:00:00Z"),
+            parse_rfc3339("1969-12-31t19:00:00-05:00")
+        );
+        for bad in [
+            "",
+            "2024-05-06",
+            "2024-05-06T12:53:20",
+            "2024-05-06T12:53:20.Z",
+            "2024-02-30T00:00:00Z",
+            "2024-05-06T24:00:00Z",
+            "2024-05-06T12:53:20+0200",
+            "2024-05-06T12:53:20Zjunk",
+            "+024-05-06T12:53:20Z",
+        ] {
+            assert_eq!(None, parse_rfc3339(bad), "{bad:?}");
+        }
+    }
+}
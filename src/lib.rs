@@ -10,24 +10,26 @@
 //! program. Skip below to learn about the the library API.
 #![doc = include_str!("../README.md")]
 
-use clap::ValueEnum;
+use rustix::process::Signal;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::{BTreeMap, BTreeSet};
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fmt::{self, Debug, Display};
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
 use std::process::ExitStatus;
-use std::rc::Rc;
 use std::str::FromStr;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
 
 pub mod somehow;
 pub use somehow::Result;
-use somehow::{somehow as anyhow, warn, warn_brief, Context, Error};
+use somehow::{somehow as anyhow, warn, warn_brief, Context, Error, ErrorKind};
 
 mod paths;
-use paths::HostPath;
+use paths::{HostPath, Paths};
 
 pub mod config;
 use config::Config;
@@ -35,407 +37,1328 @@ use config::Config;
 mod randname;
 use randname::RandomNameGenerator;
 
-mod runner;
-use runner::{CheckedRunner, EnvFilesSummary, EnvironmentExists, Init, Runner, RunnerCommand};
+pub mod runner;
+use runner::{
+    CheckedRunner, EnvFilesSummary, EnvironmentExists, Init, Runner, RunnerCommand, Sharing,
+};
+pub use runner::{EnvDir, EnvPiece};
+
+mod activity;
+use activity::{Activity, ActivityTracker};
+
+mod auto_runner;
+pub use auto_runner::{RunnerChoice, RunnerProbe, RunnerSource};
+
+mod backup;
 
 mod bytes;
-use bytes::Bytes;
+pub use bytes::Bytes;
+
+mod golden;
+use golden::GoldenRegistry;
+
+mod summary_cache;
+use summary_cache::SummaryCache;
+
+mod worktree;
+use worktree::WorktreeRegistry;
+
+mod read_only;
+use read_only::ReadOnlyRegistry;
+
+mod shells;
+use shells::ShellRegistry;
+
+mod hooks;
+use hooks::Hook;
+
+mod secrets;
+
+mod history;
+use history::HistoryLog;
+pub use history::{HistoryEntry, Operation, PackageVersion};
+
+mod code;
+
+mod ssh;
+
+mod host_open;
+
+mod caches;
+pub use caches::CacheDetails;
+
+mod free_space;
+
+mod notify;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+mod x11;
+
+mod sync;
+pub use sync::{SyncDirection, Watch};
+
+mod file_watch;
+pub use file_watch::{FileChange, FileEvent};
+
+mod scan;
+
+mod lock;
+use lock::Locks;
+
+mod metrics;
+
+mod timings;
+use timings::Timings;
+pub use timings::{Phase, Timing};
+
+mod builder;
+pub use builder::CubicleBuilder;
+
+pub mod cancel;
+
+pub mod json;
+
+pub mod daemon;
+
+mod events;
+pub use events::{BuildReason, Event, EventSink, PlannedAction};
 
 mod encoding;
 use encoding::FilenameEncoder;
 
+#[cfg(feature = "embed-packages")]
+mod embedded;
+
 mod fs_util;
-use fs_util::{try_exists, DirSummary};
+use fs_util::DirSummary;
 
 mod os_util;
 use os_util::host_home_dir;
 
 mod packages;
-use packages::{write_package_list_tar, Target};
+use packages::write_package_list_tar;
 pub use packages::{
-    FullPackageName, ListPackagesFormat, PackageDetails, PackageName, PackageNamespace,
-    PackageSpec, PackageSpecs, ShouldPackageUpdate, UpdatePackagesConditions,
+    DebianPackages, FullPackageName, PackageDetails, PackageName, PackageNamespace, PackageSpec,
+    PackageSpecs, PackageStatus, ShouldPackageUpdate, UpdatePackagesConditions,
 };
 
+pub mod manifest {
+    //! Types for packages' `package.toml` files.
+    //!
+    //! External tools, like linters and package registries, can use
+    //! [`Manifest::read`] to parse these files consistently with Cubicle.
+
+    pub use crate::packages::{Capability, Dependency, Manifest, Target};
+}
+
 mod command_ext;
+use command_ext::Command;
 
 #[cfg(target_os = "linux")]
 mod bubblewrap;
-#[cfg(target_os = "linux")]
-use bubblewrap::Bubblewrap;
 
 mod docker;
-use docker::Docker;
 
 mod user;
-use user::User;
 
 mod apt;
 
+mod seccomp;
+pub use seccomp::install_seccomp_policy;
+
 /// The main Cubicle program functionality.
 ///
 // This struct is split in two so that the runner may also keep a reference to
 // `shared`.
 pub struct Cubicle {
-    shared: Rc<CubicleShared>,
-    runner: CheckedRunner,
+    shared: Arc<CubicleShared>,
+    runner: Arc<CheckedRunner>,
 }
 
 struct CubicleShared {
     config: Config,
+    runner_choice: RunnerChoice,
     shell: String,
     exe_name: String,
     home: HostPath,
-    package_cache: HostPath,
+    paths: Paths,
     code_package_dir: HostPath,
     user_package_dir: HostPath,
     random_name_gen: RandomNameGenerator,
+    activity: ActivityTracker,
+    summaries: SummaryCache,
+    golden: GoldenRegistry,
+    worktrees: WorktreeRegistry,
+    read_only: ReadOnlyRegistry,
+    shells: ShellRegistry,
+    history: HistoryLog,
+    locks: Locks,
+    events: Arc<dyn EventSink>,
+    dry_run: bool,
+    timings: Timings,
     env_init_script: &'static [u8],
 }
 
+impl CubicleShared {
+    fn event(&self, event: Event) {
+        self.events.event(&event);
+    }
+
+    /// Reports an action that a dry run skipped.
+    fn plan(&self, action: PlannedAction) {
+        self.event(Event::Planned(action));
+    }
+
+    /// Returns an error in a dry run, for operations that don't support
+    /// them.
+    fn check_not_dry_run(&self, what: &str) -> Result<()> {
+        if self.dry_run {
+            Err(anyhow!("cannot {what} in a dry run"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
 /// Named boolean flag for [`Cubicle::purge_environment`].
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct Quiet(pub bool);
 
+/// Named boolean flag for [`Cubicle::new_environment`] and
+/// [`Cubicle::new_instance`].
+///
+/// If true, an environment that fails partway through creation is left in
+/// place for debugging, rather than removed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KeepPartial(pub bool);
+
+/// How [`Cubicle::new_environment_`] asks the runner to create an
+/// environment.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CreateMode<'a> {
+    /// An ordinary environment.
+    Default,
+    /// Encrypt the environment's files at rest.
+    Encrypted,
+    /// Let the members of this Unix group use the environment too.
+    Shared { group: &'a str },
+    /// Bind-mount the home and work directories from the host.
+    BindMounts,
+}
+
+/// Named boolean flag for [`Cubicle::get_environments`].
+///
+/// If true, the environments' disk usage is recomputed. Otherwise, recent
+/// cached values may be used instead.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Refresh(pub bool);
+
 impl Cubicle {
-    /// Creates a new instance.
+    /// Creates a new instance, using the process environment to find
+    /// directories. See [`CubicleBuilder`] for more control.
     ///
-    /// Note that this function and the rest of this library may read from
-    /// stdin and write to stdout and stderr. These effects are not currently
-    /// modeled through the type system.
+    /// Progress updates from long-running operations are reported to
+    /// `events`. Note that this function and the rest of this library may
+    /// still read from stdin and write to stdout and stderr (for example,
+    /// when running commands in an environment). These effects are not
+    /// currently modeled through the type system.
     ///
     /// # Errors
     ///
     /// - Reading and parsing environment variables.
     /// - Loading and initializing filesystem structures.
     /// - Creating a runner.
-    pub fn new(config: Config) -> Result<Self> {
-        let home = host_home_dir().clone();
-        let shell = std::env::var("SHELL").unwrap_or_else(|_| String::from("/bin/sh"));
+    pub fn new(config: Config, events: impl EventSink + 'static) -> Result<Self> {
+        CubicleBuilder::new(config).events(events).build()
+    }
 
-        let xdg_cache_home = match std::env::var("XDG_CACHE_HOME") {
-            Ok(path) => HostPath::try_from(path)?,
-            Err(_) => home.join(".cache"),
-        };
+    /// Creates a new instance that uses the given runner instead of one of
+    /// the built-in runners.
+    ///
+    /// This allows other crates to provide their own isolation mechanisms.
+    /// The `runner` field of `config` is ignored, except that hooks and
+    /// plugins still see it as `CUBICLE_RUNNER`.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Cubicle::new`], except for creating a runner.
+    pub fn new_with_runner(
+        config: Config,
+        events: impl EventSink + 'static,
+        runner: Box<dyn Runner>,
+    ) -> Result<Self> {
+        CubicleBuilder::new(config)
+            .events(events)
+            .runner(runner)
+            .build()
+    }
 
-        let xdg_data_home = match std::env::var("XDG_DATA_HOME") {
-            Ok(path) => HostPath::try_from(path)?,
-            Err(_) => home.join(".local").join("share"),
-        };
+    /// Returns an async interface to this instance's runner.
+    ///
+    /// Calls through this interface run on Tokio's blocking thread pool, so
+    /// they must be made from within a Tokio runtime. Like the methods on
+    /// `Cubicle`, they check the runner's preconditions and postconditions,
+    /// but they don't take any locks.
+    #[cfg(feature = "tokio")]
+    pub fn async_runner(&self) -> runner::Blocking {
+        runner::Blocking::new(self.runner.clone())
+    }
 
-        let exe =
-            std::env::current_exe().context("error getting the path of the current executable")?;
-        let exe_name = match exe.file_name() {
-            Some(path) => path.to_string_lossy().into_owned(),
-            None => {
-                return Err(anyhow!(
-                    "could not get executable name from current_exe: {:?}",
-                    exe
-                ));
+    /// Corresponds to `cub enter`.
+    pub fn enter_environment(&self, name: &EnvironmentName) -> Result<()> {
+        self.shared.check_not_dry_run("enter an environment")?;
+        use EnvironmentExists::*;
+        match self.runner.exists(name)? {
+            NoEnvironment => Err(Error::with_kind(
+                ErrorKind::EnvironmentNotFound,
+                format!("Environment {name} does not exist"),
+            )),
+            PartiallyExists => Err(Error::with_kind(
+                ErrorKind::EnvironmentBroken,
+                format!(
+                    "Environment {name} in broken state (try '{} reset')",
+                    self.shared.exe_name
+                ),
+            )),
+            FullyExists => {
+                self.check_no_instances(name, "enter")?;
+                self.run_hook(Hook::Enter, name)?;
+                let env_vars = secrets::read(&self.shared.config.secrets)?;
+                let shell = self.environment_shell(name)?;
+                let _session = self.shared.activity.begin_session(name)?;
+                self.forward_host_open(name);
+                lenient_exit(
+                    name,
+                    self.runner.run(
+                        name,
+                        &RunnerCommand::Interactive {
+                            env_vars: &env_vars,
+                            shell: shell.as_deref(),
+                        },
+                    ),
+                )
             }
-        };
+        }
+    }
 
-        fn code_package_dir_ok(dir: &HostPath) -> bool {
-            let key_packages = [
-                packages::special::AUTO_BATCH,
-                packages::special::AUTO_INTERACTIVE,
-                packages::special::CONFIGS_CORE,
-                packages::special::DEFAULT,
-            ];
-            key_packages
-                .iter()
-                .all(|p| try_exists(&dir.join(p).join("package.toml")).unwrap_or(false))
+    /// Corresponds to `cub exec`.
+    pub fn exec_environment(&self, name: &EnvironmentName, command: &[String]) -> Result<()> {
+        self.shared
+            .check_not_dry_run("run a command in an environment")?;
+        use EnvironmentExists::*;
+        match self.runner.exists(name)? {
+            NoEnvironment => Err(Error::with_kind(
+                ErrorKind::EnvironmentNotFound,
+                format!("Environment {name} does not exist"),
+            )),
+            PartiallyExists => Err(Error::with_kind(
+                ErrorKind::EnvironmentBroken,
+                format!(
+                    "Environment {name} in broken state (try '{} reset')",
+                    self.shared.exe_name
+                ),
+            )),
+            FullyExists => {
+                self.check_no_instances(name, "run a command in")?;
+                let env_vars = secrets::read(&self.shared.config.secrets)?;
+                let _session = self.shared.activity.begin_session(name)?;
+                self.forward_host_open(name);
+                self.runner.run(
+                    name,
+                    &RunnerCommand::Exec {
+                        command,
+                        env_vars: &env_vars,
+                    },
+                )
+            }
         }
+    }
 
-        let code_package_dir = match &config.builtin_package_dir {
-            Some(dir) => {
-                let dir = HostPath::try_from(dir.clone())?;
-                if !code_package_dir_ok(&dir) {
-                    return Err(anyhow!(
-                        "missing expected contents in configured `builtin_package_dir`: {dir}"
+    /// Corresponds to `cub code`.
+    ///
+    /// Opens the environment's work directory in VS Code, starting the
+    /// environment first if needed.
+    pub fn open_in_code(&self, name: &EnvironmentName) -> Result<()> {
+        self.shared
+            .check_not_dry_run("open an environment in VS Code")?;
+        self.check_fully_exists(name)?;
+        self.check_no_instances(name, "open")?;
+        let target = self.runner.code_target(name)?;
+        code::open(&self.shared.config.code, &self.shared.home, &target)
+    }
+
+    /// Corresponds to `cub ssh`.
+    ///
+    /// Connects to the environment with the SSH client and runs the given
+    /// command, or an interactive shell if it's empty. `config_path` is
+    /// passed along to `cub ssh-proxy`.
+    pub fn ssh(
+        &self,
+        name: &EnvironmentName,
+        command: &[String],
+        config_path: &Path,
+    ) -> Result<()> {
+        self.shared.check_not_dry_run("connect to an environment")?;
+        self.check_ssh_enabled()?;
+        self.check_fully_exists(name)?;
+        let host = ssh::host_alias(name).unwrap_or_else(|| String::from("cubicle"));
+        let mut ssh = Command::new("ssh");
+        for option in self.ssh_options(name, config_path)? {
+            ssh.arg("-o").arg(option);
+        }
+        let status = ssh
+            .arg(host)
+            .args(command)
+            .status()
+            .context("failed to run ssh")?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(anyhow!(ExitStatusError::new(status, "ssh")))
+        }
+    }
+
+    /// Corresponds to `cub ssh-config`.
+    ///
+    /// Returns SSH client configuration, suitable for an `Include` from
+    /// `~/.ssh/config`, with an entry named `<environment>.cubicle` for each
+    /// existing environment. `config_path` is passed along to `cub
+    /// ssh-proxy`.
+    pub fn ssh_config(&self, config_path: &Path) -> Result<String> {
+        self.shared
+            .check_not_dry_run("generate the SSH client configuration")?;
+        self.check_ssh_enabled()?;
+        let mut config = format!(
+            "# Generated by `{} ssh-config`. Run it again after creating environments.\n",
+            self.shared.exe_name
+        );
+        for name in self.get_environment_names()? {
+            match ssh::host_alias(&name) {
+                Some(host) => {
+                    config.push_str(&format!("\nHost {host}\n"));
+                    for option in self.ssh_options(&name, config_path)? {
+                        config.push_str(&format!("    {option}\n"));
+                    }
+                }
+                None => {
+                    config.push_str(&format!(
+                        "\n# Skipped environment {name}: not usable in a host name.\n"
                     ));
                 }
-                dir
             }
+        }
+        Ok(config)
+    }
 
-            None => {
-                let exe = std::fs::canonicalize(&exe).with_context(|| {
-                    format!("failed to canonicalize path of current executable: {exe:?}")
-                })?;
+    /// Corresponds to `cub ssh-proxy`, which the SSH client runs.
+    ///
+    /// Runs an SSH server in the environment that talks to the client over
+    /// standard input and output.
+    pub fn ssh_proxy(&self, name: &EnvironmentName) -> Result<()> {
+        self.shared.check_not_dry_run("connect to an environment")?;
+        self.check_ssh_enabled()?;
+        self.check_fully_exists(name)?;
+        self.check_no_instances(name, "connect to")?;
+        let key = ssh::client_key(&self.ssh_dir())?;
+        let command = ssh::server_command(&self.shared.config.ssh.sshd, &key.public)?;
+        let _session = self.shared.activity.begin_session(name)?;
+        self.forward_host_open(name);
+        self.runner
+            .run(name, &RunnerCommand::Pipe { command: &command })
+    }
 
-                let mut candidates = Vec::new();
-                let mut ancestors = exe.ancestors();
-                ancestors.next(); // skip self
-                if let Some(dir) = ancestors.next() {
-                    candidates.push(HostPath::try_from(dir.to_owned())?.join("packages"));
+    /// Starts a thread that opens the environment's requested URLs and
+    /// files on the host, if the `host_open` option is enabled. The thread
+    /// runs until this process exits.
+    fn forward_host_open(&self, name: &EnvironmentName) {
+        if !self.shared.config.host_open.enabled {
+            return;
+        }
+        if !self.runner.supports_host_open() {
+            warn(anyhow!(
+                "the {} runner doesn't support the `host_open` option",
+                self.shared.config.runner.as_str()
+            ));
+            return;
+        }
+        let program = Cubicle {
+            shared: self.shared.clone(),
+            runner: self.runner.clone(),
+        };
+        let name = name.clone();
+        std::thread::spawn(move || {
+            let dir = host_open::host_dir(&program.shared.paths, &name);
+            let result = host_open::create_pipe(&dir).and_then(|()| {
+                host_open::serve(
+                    &dir,
+                    |request| program.open_on_host(&name, &dir, request),
+                    |e| warn(e.context(format!("failed to open request from {name} on the host"))),
+                )
+            });
+            if let Err(e) = result {
+                warn(e.context(format!("stopped opening requests from {name} on the host")));
+            }
+        });
+    }
+
+    /// Opens a URL or a copy of a file from the environment on the host.
+    fn open_on_host(
+        &self,
+        name: &EnvironmentName,
+        dir: &HostPath,
+        request: host_open::Request,
+    ) -> Result<()> {
+        let command = &self.shared.config.host_open.command;
+        match request {
+            host_open::Request::Url(url) => host_open::open(command, OsStr::new(&url)),
+            host_open::Request::File(path) => {
+                // Each copy gets its own directory, so that it keeps the
+                // original file name for the program that opens it.
+                let file_name = path
+                    .file_name()
+                    .ok_or_else(|| anyhow!("cannot open {path:?} on the host: not a file path"))?;
+                let files = dir.join("files");
+                std::fs::create_dir_all(files.as_host_raw())
+                    .with_context(|| format!("failed to create directory {files}"))?;
+                if let Err(e) = host_open::remove_old_copies(&files, SystemTime::now()) {
+                    warn(e.context("failed to remove old copies of opened files"));
                 }
-                if let (Some(parent), Some(dir)) = (ancestors.next(), ancestors.next()) {
-                    if parent.ends_with("target") {
-                        candidates.push(HostPath::try_from(dir.to_owned())?.join("packages"));
+                let copy_dir = tempfile::tempdir_in(files.as_host_raw())
+                    .with_context(|| format!("failed to create directory in {files}"))?
+                    .keep();
+                let copy = copy_dir.join(file_name);
+                let mut file = std::fs::File::create(&copy)
+                    .with_context(|| format!("failed to create {copy:?}"))?;
+                match path.strip_prefix("w") {
+                    Ok(work_path) if !work_path.as_os_str().is_empty() => {
+                        self.runner.copy_out_from_work(name, work_path, &mut file)?;
                     }
+                    _ => self.runner.copy_out_from_home(name, &path, &mut file)?,
                 }
+                host_open::open(command, copy.as_os_str())
+            }
+        }
+    }
 
-                match candidates.iter().find(|dir| code_package_dir_ok(dir)) {
-                    Some(dir) => dir.clone(),
-                    None => {
-                        return Err(anyhow!(
-                            "Could not find built-in package definitions \
-                            (looked in {:?} based on executable location). \
-                            Hint: set `builtin_package_dir` in `cubicle.toml`.",
-                            candidates
-                                .iter()
-                                .map(|p| p.as_host_raw())
-                                .collect::<Vec<_>>()
-                        ))
+    fn ssh_dir(&self) -> HostPath {
+        self.shared.paths.data.join("ssh")
+    }
+
+    fn ssh_options(&self, name: &EnvironmentName, config_path: &Path) -> Result<Vec<String>> {
+        let key = ssh::client_key(&self.ssh_dir())?;
+        let exe =
+            std::env::current_exe().context("error getting the path of the current executable")?;
+        ssh::client_options(
+            &exe,
+            config_path,
+            self.shared.config.profile.as_deref(),
+            name,
+            &self.runner.username(name)?,
+            &key.path,
+        )
+    }
+
+    fn check_ssh_enabled(&self) -> Result<()> {
+        if self.shared.config.ssh.enabled {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "SSH access is disabled (set `enabled = true` in the `[ssh]` section of the configuration file)"
+            ))
+        }
+    }
+
+    /// Returns an error unless the environment fully exists.
+    fn check_fully_exists(&self, name: &EnvironmentName) -> Result<()> {
+        use EnvironmentExists::*;
+        match self.runner.exists(name)? {
+            NoEnvironment => Err(Error::with_kind(
+                ErrorKind::EnvironmentNotFound,
+                format!("Environment {name} does not exist"),
+            )),
+            PartiallyExists => Err(Error::with_kind(
+                ErrorKind::EnvironmentBroken,
+                format!(
+                    "Environment {name} in broken state (try '{} reset')",
+                    self.shared.exe_name
+                ),
+            )),
+            FullyExists => Ok(()),
+        }
+    }
+
+    /// Corresponds to `cub gc`.
+    ///
+    /// Stops environments that haven't been used for longer than the
+    /// configured `auto_stop` duration.
+    pub fn stop_idle_environments(&self) -> Result<()> {
+        self.shared.check_not_dry_run("stop idle environments")?;
+        let Some(auto_stop) = self.shared.config.auto_stop else {
+            warn(anyhow!(
+                "`auto_stop` is not configured, so no environments are considered idle"
+            ));
+            return Ok(());
+        };
+        if !self.runner.restarts_after_stop() {
+            warn(anyhow!(
+                "not stopping idle environments because this runner can't restart them \
+                automatically"
+            ));
+            return Ok(());
+        }
+        let now = SystemTime::now();
+        for name in self.get_environment_names()? {
+            if self.runner.exists(&name)? != EnvironmentExists::FullyExists {
+                continue;
+            }
+            // Holding this lock while stopping the environment keeps a new
+            // session from starting in it partway through.
+            let (activity, _idle_lock) = self.shared.activity.lock_if_idle(&name)?;
+            match activity {
+                Activity::LastUsed(time) => {
+                    if now.duration_since(time).is_ok_and(|idle| idle > auto_stop) {
+                        // Skip environments that another process is busy
+                        // creating, resetting, or purging.
+                        let Some(_lock) = self.shared.locks.try_lock_environment(&name)? else {
+                            continue;
+                        };
+                        self.shared
+                            .event(Event::StoppingIdleEnvironment(name.clone()));
+                        self.runner.stop(&name)?;
                     }
                 }
+                Activity::InUse | Activity::Unknown => {}
             }
-        };
+        }
+        Ok(())
+    }
 
-        let package_cache = xdg_cache_home.join("cubicle").join("packages");
-        let user_package_dir = xdg_data_home.join("cubicle").join("packages");
-
-        let eff_word_list_dir = xdg_cache_home.join("cubicle");
-        let random_name_gen = RandomNameGenerator::new(eff_word_list_dir);
-
-        let shared = Rc::new(CubicleShared {
-            config,
-            shell,
-            exe_name,
-            home,
-            package_cache,
-            code_package_dir,
-            user_package_dir,
-            random_name_gen,
-            env_init_script: std::include_bytes!("env-init.sh"),
-        });
+    /// Corresponds to `cub start`.
+    ///
+    /// Starts the environment, if the runner keeps environments running, so
+    /// that it's ready before it's next used.
+    pub fn start_environment(&self, name: &EnvironmentName) -> Result<()> {
+        self.shared.check_not_dry_run("start an environment")?;
+        let _lock = self.shared.locks.lock_environment(name)?;
+        self.check_fully_exists(name)?;
+        self.runner.start(name)
+    }
 
-        let runner = CheckedRunner::new(match shared.config.runner {
-            RunnerKind::Bubblewrap => {
-                #[cfg(not(target_os = "linux"))]
-                return Err(anyhow!("The Bubblewrap runner is only available on Linux"));
-                #[cfg(target_os = "linux")]
-                Box::new(Bubblewrap::new(shared.clone())?)
+    /// Corresponds to `cub stop`.
+    ///
+    /// Stops the environment and any processes running in it. It starts
+    /// again when it's next used, if the runner allows that.
+    pub fn stop_environment(&self, name: &EnvironmentName) -> Result<()> {
+        self.shared.check_not_dry_run("stop an environment")?;
+        let _lock = self.shared.locks.lock_environment(name)?;
+        self.check_fully_exists(name)?;
+        self.runner.stop(name)
+    }
+
+    /// Returns how long the slow phases of operations have taken so far.
+    pub fn timings(&self) -> Vec<Timing> {
+        self.shared.timings.get()
+    }
+
+    /// Returns true if `cub purge` and `cub reset` should ask before acting
+    /// on several environments matched by wildcards. See
+    /// [`Config::confirm_wildcards`].
+    pub fn confirm_wildcards(&self) -> bool {
+        self.shared.config.confirm_wildcards
+    }
+
+    /// Returns how `cub list` and `cub package list` should show times. See
+    /// [`Config::time_format`].
+    pub fn time_format(&self) -> config::TimeFormat {
+        self.shared.config.time_format
+    }
+
+    /// Corresponds to `cub doctor`.
+    ///
+    /// Returns how the runner in use was chosen.
+    pub fn runner_choice(&self) -> &RunnerChoice {
+        &self.shared.runner_choice
+    }
+
+    /// Corresponds to `cub doctor`.
+    ///
+    /// Checks which runners could be used on this host, as `runner = "auto"`
+    /// would when choosing one.
+    pub fn probe_runners(&self) -> Vec<RunnerProbe> {
+        auto_runner::probe(&self.shared.config)
+    }
+
+    /// Returns a list of existing environment names.
+    pub fn get_environment_names(&self) -> Result<BTreeSet<EnvironmentName>> {
+        Ok(self.runner.list()?.into_iter().collect())
+    }
+
+    /// Returns a detailed description of the current environments.
+    ///
+    /// Computing the environments' disk usage can be slow, so unless
+    /// `refresh` is set, this uses the last computed values where available.
+    /// Those older than a minute have [`EnvironmentDetails::summary_stale`]
+    /// set; callers may want to refresh them later.
+    pub fn get_environments(
+        &self,
+        refresh: Refresh,
+    ) -> Result<BTreeMap<EnvironmentName, EnvironmentDetails>> {
+        Ok(self
+            .get_environment_names()?
+            .into_iter()
+            .map(|name| {
+                let details = self.environment_details(&name, refresh);
+                (name, details)
+            })
+            .collect())
+    }
+
+    /// Returns a detailed description of a single environment.
+    ///
+    /// Unlike [`Cubicle::get_environments`], this always computes the disk
+    /// usage and also fills in [`EnvironmentDetails::networks`].
+    pub fn get_environment(&self, name: &EnvironmentName) -> Result<EnvironmentDetails> {
+        if self.runner.exists(name)? == EnvironmentExists::NoEnvironment {
+            return Err(Error::with_kind(
+                ErrorKind::EnvironmentNotFound,
+                format!("Environment {name} does not exist"),
+            ));
+        }
+        let mut details = self.environment_details(name, Refresh(true));
+        details.networks = Some(
+            self.runner
+                .networks(name)?
+                .into_iter()
+                .map(|network| {
+                    (
+                        network.name,
+                        NetworkDetails {
+                            ip_address: network.ip_address,
+                        },
+                    )
+                })
+                .collect(),
+        );
+        Ok(details)
+    }
+
+    fn environment_details(&self, name: &EnvironmentName, refresh: Refresh) -> EnvironmentDetails {
+        let (summary, summarized, summary_stale) = self.files_summary(name, refresh);
+        EnvironmentDetails {
+            home_dir: summary.home_dir_path.map(|p| p.as_host_raw().to_owned()),
+            home_dir_du_error: summary.home_dir.errors,
+            home_dir_size: summary.home_dir.total_size,
+            home_dir_mtime: nonzero_time(summary.home_dir.last_modified),
+            work_dir: summary.work_dir_path.map(|p| p.as_host_raw().to_owned()),
+            work_dir_du_error: summary.work_dir.errors,
+            work_dir_size: summary.work_dir.total_size,
+            work_dir_mtime: nonzero_time(summary.work_dir.last_modified),
+            summarized,
+            summary_stale,
+            golden: self.shared.golden.golden(name).unwrap_or_else(|e| {
+                warn(e);
+                None
+            }),
+            shared: self.runner.sharing(name).unwrap_or_else(|e| {
+                warn(e);
+                None
+            }),
+            disk_quota: self
+                .shared
+                .config
+                .disk_quota
+                .as_ref()
+                .map(|quota| quota.size),
+            running: match self.shared.activity.activity(name) {
+                Ok(activity) => activity == Activity::InUse,
+                Err(e) => {
+                    warn(e);
+                    false
+                }
+            },
+            networks: None,
+        }
+    }
+
+    /// Returns the environment's disk usage, when it was computed, and
+    /// whether it's stale.
+    ///
+    /// Unless `refresh` is set, this returns the cached summary if there is
+    /// one. Otherwise, it computes a new summary and caches it.
+    fn files_summary(
+        &self,
+        name: &EnvironmentName,
+        refresh: Refresh,
+    ) -> (EnvFilesSummary, Option<SystemTime>, bool) {
+        if !refresh.0 {
+            match self.shared.summaries.get(name) {
+                Ok(Some(cached)) => {
+                    let stale = cached.is_stale(SystemTime::now());
+                    return (cached.summary, Some(cached.computed), stale);
+                }
+                Ok(None) => {}
+                Err(e) => warn(e),
             }
-            RunnerKind::Docker => Box::new(Docker::new(shared.clone())?),
-            RunnerKind::User => Box::new(User::new(shared.clone())?),
-        });
+        }
+        let now = SystemTime::now();
+        match self
+            .shared
+            .timings
+            .time(Phase::SummarizeDiskUsage(name.clone()), || {
+                self.runner.files_summary(name)
+            }) {
+            Ok(summary) => {
+                if let Err(e) = self.shared.summaries.put(name, &summary, now) {
+                    warn(e);
+                }
+                (summary, Some(now), false)
+            }
+            Err(e) => {
+                warn(e.context(format!("failed to summarize disk usage for {name}")));
+                let summary = EnvFilesSummary {
+                    home_dir_path: None,
+                    home_dir: DirSummary::new_with_errors(),
+                    work_dir_path: None,
+                    work_dir: DirSummary::new_with_errors(),
+                };
+                (summary, None, false)
+            }
+        }
+    }
 
-        Ok(Self { shared, runner })
+    /// Corresponds to `cub new`.
+    pub fn new_environment(
+        &self,
+        name: &EnvironmentName,
+        packages: Option<BTreeSet<FullPackageName>>,
+        keep_partial: KeepPartial,
+    ) -> Result<()> {
+        self.new_environment_(name, packages, None, keep_partial, CreateMode::Default)
     }
 
-    /// Corresponds to `cub enter`.
-    pub fn enter_environment(&self, name: &EnvironmentName) -> Result<()> {
+    /// Corresponds to `cub new --encrypt`.
+    ///
+    /// Creates an environment like [`Cubicle::new_environment`], except that
+    /// the runner encrypts its files at rest and only decrypts them while
+    /// they're in use.
+    pub fn new_encrypted_environment(
+        &self,
+        name: &EnvironmentName,
+        packages: Option<BTreeSet<FullPackageName>>,
+        keep_partial: KeepPartial,
+    ) -> Result<()> {
+        if !self.runner.supports_encryption() {
+            return Err(anyhow!(
+                "The {:?} runner does not support encrypted environments",
+                self.shared.config.runner
+            ));
+        }
+        self.new_environment_(name, packages, None, keep_partial, CreateMode::Encrypted)
+    }
+
+    /// Corresponds to `cub new --group`.
+    ///
+    /// Creates an environment like [`Cubicle::new_environment`] that the
+    /// members of the given Unix group may also enter and use. The runner
+    /// records the current user as the environment's owner.
+    pub fn new_shared_environment(
+        &self,
+        name: &EnvironmentName,
+        packages: Option<BTreeSet<FullPackageName>>,
+        keep_partial: KeepPartial,
+        group: &str,
+    ) -> Result<()> {
+        if !self.runner.supports_sharing() {
+            return Err(anyhow!(
+                "The {:?} runner does not support sharing environments with a group",
+                self.shared.config.runner
+            ));
+        }
+        self.new_environment_(
+            name,
+            packages,
+            None,
+            keep_partial,
+            CreateMode::Shared { group },
+        )
+    }
+
+    /// Corresponds to `cub new --bind-mounts`.
+    ///
+    /// Creates an environment like [`Cubicle::new_environment`] whose home
+    /// and work directories are bind-mounted from the host, regardless of
+    /// the runner's default.
+    pub fn new_bind_mounted_environment(
+        &self,
+        name: &EnvironmentName,
+        packages: Option<BTreeSet<FullPackageName>>,
+        keep_partial: KeepPartial,
+    ) -> Result<()> {
+        if !self.runner.supports_bind_mounts() {
+            return Err(anyhow!(
+                "The {:?} runner does not support choosing bind mounts per environment",
+                self.shared.config.runner
+            ));
+        }
+        self.new_environment_(name, packages, None, keep_partial, CreateMode::BindMounts)
+    }
+
+    /// Creates a new environment. If `home_tar` is given, it's extracted into
+    /// the home directory before the packages, as in `cub restore`.
+    fn new_environment_(
+        &self,
+        name: &EnvironmentName,
+        packages: Option<BTreeSet<FullPackageName>>,
+        home_tar: Option<&HostPath>,
+        keep_partial: KeepPartial,
+        mode: CreateMode,
+    ) -> Result<()> {
         use EnvironmentExists::*;
+        let _lock = self.shared.locks.lock_environment(name)?;
         match self.runner.exists(name)? {
-            NoEnvironment => Err(anyhow!("Environment {name} does not exist")),
-            PartiallyExists => Err(anyhow!(
-                "Environment {name} in broken state (try '{} reset')",
+            NoEnvironment => {}
+            PartiallyExists => {
+                return Err(Error::with_kind(
+                    ErrorKind::EnvironmentBroken,
+                    format!(
+                        "environment {name} in broken state (try '{} reset')",
+                        self.shared.exe_name
+                    ),
+                ))
+            }
+            FullyExists => {
+                return Err(Error::with_kind(
+                    ErrorKind::EnvironmentExists,
+                    format!("environment {name} already exists"),
+                ))
+            }
+        }
+        self.warn_if_low_on_space();
+
+        let packages = {
+            let mut packages = packages.unwrap_or_else(|| {
+                BTreeSet::from([FullPackageName::from_str(packages::special::DEFAULT).unwrap()])
+            });
+            packages
+                .insert(FullPackageName::from_str(packages::special::AUTO_INTERACTIVE).unwrap());
+            packages
+        };
+
+        let specs = self.scan_packages()?;
+        self.check_debian_packages(&packages, &specs)?;
+        self.update_packages(
+            &packages,
+            &specs,
+            &UpdatePackagesConditions {
+                dependencies: ShouldPackageUpdate::IfStale,
+                named: ShouldPackageUpdate::IfStale,
+                excluded: BTreeSet::new(),
+            },
+        )?;
+        if self.shared.dry_run {
+            self.shared.plan(PlannedAction::CreateEnvironment {
+                name: name.clone(),
+                pieces: self.runner.inspect(name)?,
+                packages: self.seed_packages(&packages, &specs)?,
+            });
+            return self.run_hook(Hook::Create, name);
+        }
+        let packages_txt = write_package_list_tar(&packages)?;
+        let debian_packages = self.resolve_debian_packages(&packages, &specs)?;
+
+        let mut seeds = Vec::from_iter(home_tar.cloned());
+        seeds.extend(self.packages_to_seeds(&packages, &specs)?);
+        seeds.push(HostPath::try_from(packages_txt.path().to_owned())?);
+
+        let init = Init {
+            debian_packages: debian_packages
+                .iter()
+                .map(|name| name.as_str().to_owned())
+                .collect(),
+            env_vars: self.init_env_vars(),
+            seeds,
+        };
+        match mode {
+            CreateMode::Default => self.runner.create(name, &init),
+            CreateMode::Encrypted => self.runner.create_encrypted(name, &init),
+            CreateMode::Shared { group } => self.runner.create_shared(name, &init, group),
+            CreateMode::BindMounts => self.runner.create_with_bind_mounts(name, &init),
+        }
+        .or_else(|e| self.roll_back_create(name, keep_partial, e))
+        .with_context(|| format!("failed to initialize new environment {name}"))?;
+        self.shared.history.record(Operation::Create {
+            name: name.clone(),
+            packages: self.package_versions(&packages),
+            golden: None,
+        });
+        self.run_hook(Hook::Create, name)
+    }
+
+    /// Cleans up after [`Runner::create`] fails, so that a failed `cub new`
+    /// doesn't leave a broken environment behind. Returns the original error.
+    fn roll_back_create(
+        &self,
+        name: &EnvironmentName,
+        keep_partial: KeepPartial,
+        error: Error,
+    ) -> Result<()> {
+        if keep_partial.0 {
+            return Err(error.context(format!(
+                "keeping partially created environment {name} \
+                (try '{exe} reset' or '{exe} purge')",
+                exe = self.shared.exe_name
+            )));
+        }
+        self.shared
+            .event(Event::RemovingPartialEnvironment(name.clone()));
+        let purge = || -> Result<()> {
+            self.runner.purge(name)?;
+            self.shared.golden.forget(name)
+        };
+        if let Err(e) = purge() {
+            warn(e.context(format!(
+                "failed to remove partially created environment {name} (try '{} purge')",
                 self.shared.exe_name
-            )),
-            FullyExists => self
-                .runner
-                .run(name, &RunnerCommand::Interactive)
-                .or_else(|e| match e.downcast_ref::<ExitStatusError>() {
-                    Some(e) => {
-                        warn_brief(format!("exited from {name} with {}", e.status));
-                        Ok(())
-                    }
-                    None => Err(e),
-                }),
+            )));
         }
+        Err(error)
     }
 
-    /// Corresponds to `cub exec`.
-    pub fn exec_environment(&self, name: &EnvironmentName, command: &[String]) -> Result<()> {
+    /// Corresponds to `cub new --golden`.
+    ///
+    /// Creates a lightweight instance of an existing "golden" environment.
+    /// The instance's home directory is an overlay on top of the golden
+    /// environment's home directory, which is shared read-only, so the
+    /// instance only stores its own changes. The instance gets its own empty
+    /// work directory.
+    ///
+    /// The golden environment must not be in use. It's stopped, and it can't
+    /// be entered again until its instances are purged.
+    pub fn new_instance(
+        &self,
+        name: &EnvironmentName,
+        golden: &EnvironmentName,
+        keep_partial: KeepPartial,
+    ) -> Result<()> {
         use EnvironmentExists::*;
+        if !self.runner.supports_golden() {
+            return Err(anyhow!(
+                "The {:?} runner does not support golden environments",
+                self.shared.config.runner
+            ));
+        }
+        let _lock = self.shared.locks.lock_environment(name)?;
         match self.runner.exists(name)? {
-            NoEnvironment => Err(anyhow!("Environment {name} does not exist")),
-            PartiallyExists => Err(anyhow!(
-                "Environment {name} in broken state (try '{} reset')",
-                self.shared.exe_name
-            )),
-            FullyExists => self.runner.run(
-                name,
-                &RunnerCommand::Exec {
-                    command,
-                    env_vars: &[],
-                },
-            ),
+            NoEnvironment => {}
+            PartiallyExists => {
+                return Err(Error::with_kind(
+                    ErrorKind::EnvironmentBroken,
+                    format!(
+                        "environment {name} in broken state (try '{} purge')",
+                        self.shared.exe_name
+                    ),
+                ))
+            }
+            FullyExists => {
+                return Err(Error::with_kind(
+                    ErrorKind::EnvironmentExists,
+                    format!("environment {name} already exists"),
+                ))
+            }
+        }
+        if self.runner.exists(golden)? != FullyExists {
+            return Err(Error::with_kind(
+                ErrorKind::EnvironmentNotFound,
+                format!("golden environment {golden} does not exist"),
+            ));
+        }
+        if let Some(golden_golden) = self.shared.golden.golden(golden)? {
+            return Err(anyhow!(
+                "environment {golden} is itself an instance of {golden_golden} \
+                and can't be used as a golden environment"
+            ));
+        }
+        if self.runner.is_encrypted(golden)? {
+            return Err(anyhow!(
+                "environment {golden} is encrypted and can't be used as a golden environment"
+            ));
+        }
+
+        if self.shared.dry_run {
+            self.shared.plan(PlannedAction::CreateEnvironment {
+                name: name.clone(),
+                pieces: self.runner.inspect(name)?,
+                packages: Vec::new(),
+            });
+            return self.run_hook(Hook::Create, name);
+        }
+
+        // Once the instance is recorded, new sessions can't start in the
+        // golden environment, so its home directory can't change under the
+        // instance after this.
+        self.shared.golden.record(name, golden)?;
+        if self.shared.activity.activity(golden)? == Activity::InUse {
+            self.shared.golden.forget(name)?;
+            return Err(anyhow!(
+                "environment {golden} is in use and can't be used as a golden \
+                environment until its sessions exit"
+            ));
         }
+        self.runner
+            .stop(golden)
+            .or_else(|e| {
+                self.shared.golden.forget(name)?;
+                Err(e)
+            })
+            .with_context(|| format!("failed to stop golden environment {golden}"))?;
+        self.init_instance(golden, |init| self.runner.create(name, init))
+            .or_else(|e| self.roll_back_create(name, keep_partial, e))
+            .with_context(|| format!("failed to initialize new instance {name} of {golden}"))?;
+        self.shared.history.record(Operation::Create {
+            name: name.clone(),
+            packages: Vec::new(),
+            golden: Some(golden.clone()),
+        });
+        self.run_hook(Hook::Create, name)
     }
 
-    /// Returns a list of existing environment names.
-    pub fn get_environment_names(&self) -> Result<BTreeSet<EnvironmentName>> {
-        Ok(self.runner.list()?.into_iter().collect())
+    /// Corresponds to `cub new --read-only-home`.
+    ///
+    /// Creates an environment like [`Cubicle::new_environment`], except that
+    /// each session sees the home directory as it was seeded, and the
+    /// session's changes to it are discarded when the session exits. The
+    /// work directory is writable and kept as usual. `cub reset` re-seeds the
+    /// home directory and keeps it read-only.
+    pub fn new_read_only_environment(
+        &self,
+        name: &EnvironmentName,
+        packages: Option<BTreeSet<FullPackageName>>,
+        keep_partial: KeepPartial,
+    ) -> Result<()> {
+        if !self.runner.supports_read_only_home() {
+            return Err(anyhow!(
+                "The {:?} runner does not support read-only home directories",
+                self.shared.config.runner
+            ));
+        }
+        self.new_environment(name, packages, keep_partial)?;
+        if !self.shared.dry_run {
+            self.shared.read_only.record(name)?;
+        }
+        Ok(())
+    }
+
+    /// Corresponds to `cub new --shell`.
+    ///
+    /// Makes interactive sessions in the environment start `shell`, which
+    /// overrides the `shell` configuration setting. As with that setting, a
+    /// name without a slash is looked up in the environment's `PATH`.
+    pub fn set_environment_shell(&self, name: &EnvironmentName, shell: &str) -> Result<()> {
+        if self.shared.dry_run {
+            return Ok(());
+        }
+        self.shared.shells.record(name, shell)
     }
 
-    /// Returns a detailed description of the current environments.
-    pub fn get_environments(&self) -> Result<BTreeMap<EnvironmentName, EnvironmentDetails>> {
-        Ok(self
-            .get_environment_names()?
-            .into_iter()
-            .map(|name| {
-                let summary = self.runner.files_summary(&name).unwrap_or_else(|e| {
-                    warn(e.context(format!("failed to summarize disk usage for {name}")));
-                    EnvFilesSummary {
-                        home_dir_path: None,
-                        home_dir: DirSummary::new_with_errors(),
-                        work_dir_path: None,
-                        work_dir: DirSummary::new_with_errors(),
-                    }
-                });
-                (
-                    name,
-                    EnvironmentDetails {
-                        home_dir: summary.home_dir_path.map(|p| p.as_host_raw().to_owned()),
-                        home_dir_du_error: summary.home_dir.errors,
-                        home_dir_size: summary.home_dir.total_size,
-                        home_dir_mtime: nonzero_time(summary.home_dir.last_modified),
-                        work_dir: summary.work_dir_path.map(|p| p.as_host_raw().to_owned()),
-                        work_dir_du_error: summary.work_dir.errors,
-                        work_dir_size: summary.work_dir.total_size,
-                        work_dir_mtime: nonzero_time(summary.work_dir.last_modified),
-                    },
-                )
-            })
-            .collect())
+    /// Returns the shell to start for interactive sessions in the
+    /// environment, if one is set for it or in the configuration.
+    fn environment_shell(&self, name: &EnvironmentName) -> Result<Option<String>> {
+        match self.shared.shells.get(name)? {
+            Some(shell) => Ok(Some(shell)),
+            None => Ok(self.shared.config.shell.clone()),
+        }
     }
 
-    /// Corresponds to `cub list`.
-    pub fn list_environments(&self, format: ListFormat) -> Result<()> {
-        match format {
-            ListFormat::Names => {
-                for name in self.get_environment_names()? {
-                    println!("{}", name.as_str());
-                }
-            }
+    /// Corresponds to `cub worktree new`.
+    ///
+    /// Checks out `branch` of the git repository containing `repo_dir` in a
+    /// new worktree, then creates an environment named after the repository
+    /// and branch that uses the worktree as its work directory. Returns the
+    /// environment's name.
+    pub fn new_worktree_environment(
+        &self,
+        repo_dir: &Path,
+        branch: &str,
+        packages: Option<BTreeSet<FullPackageName>>,
+        keep_partial: KeepPartial,
+    ) -> Result<EnvironmentName> {
+        if !self.runner.supports_worktrees() {
+            return Err(anyhow!(
+                "The {:?} runner does not support worktree environments",
+                self.shared.config.runner
+            ));
+        }
+        let (repo, git_dir) = worktree::find_repo(repo_dir)?;
+        let name = worktree::environment_name(&repo, branch)?;
+        if self.runner.exists(&name)? != EnvironmentExists::NoEnvironment {
+            return Err(Error::with_kind(
+                ErrorKind::EnvironmentExists,
+                format!("environment {name} already exists"),
+            ));
+        }
+        if self.shared.dry_run {
+            self.new_environment(&name, packages, keep_partial)?;
+            return Ok(name);
+        }
 
-            ListFormat::Json => {
-                let envs = self.get_environments()?;
-                println!(
-                    "{}",
-                    serde_json::to_string_pretty(&envs)
-                        .context("failed to serialize JSON while listing environments")?
-                );
+        let worktree = worktree::Worktree {
+            repo,
+            git_dir,
+            path: self.shared.worktrees.checkout_path(&name),
+            branch: branch.to_owned(),
+        };
+        worktree::add(&worktree.repo, &worktree.path, branch)?;
+        self.shared.worktrees.record(&name, &worktree)?;
+        if let Err(e) = self.new_environment(&name, packages, keep_partial) {
+            if !keep_partial.0 {
+                let remove = || -> Result<()> {
+                    worktree::remove(&worktree)?;
+                    self.shared.worktrees.forget(&name)
+                };
+                if let Err(e) = remove() {
+                    warn(e.context(format!("failed to remove git worktree {}", worktree.path)));
+                }
             }
+            return Err(e);
+        }
+        Ok(name)
+    }
 
-            ListFormat::Default => {
-                let envs = self.get_environments()?;
-                let nw = envs
-                    .keys()
-                    .map(|name| name.as_str().len())
-                    .max()
-                    .unwrap_or(10);
-                let now = SystemTime::now();
-                println!(
-                    "{:<nw$} | {:^24} | {:^24}",
-                    "", "home directory", "work directory",
-                );
-                println!(
-                    "{:<nw$} | {:>10} {:>13} | {:>10} {:>13}",
-                    "name", "size", "modified", "size", "modified",
-                );
-                println!("{0:-<nw$} + {0:-<10} {0:-<13} + {0:-<10} {0:-<13}", "",);
-
-                // `Bytes` doesn't implement width/alignment, so it needs an
-                // extra `to_string()`.
-                #[allow(clippy::to_string_in_format_args)]
-                for (name, env) in envs {
-                    println!(
-                        "{:<nw$} | {:>9}{} {:>13} | {:>9}{} {:>13}",
-                        name.as_str(),
-                        Bytes(env.home_dir_size).to_string(),
-                        if env.home_dir_du_error { '+' } else { ' ' },
-                        match env.home_dir_mtime {
-                            Some(mtime) => rel_time(now.duration_since(mtime).ok()),
-                            None => String::from("N/A"),
-                        },
-                        Bytes(env.work_dir_size).to_string(),
-                        if env.work_dir_du_error { '+' } else { ' ' },
-                        match env.work_dir_mtime {
-                            Some(mtime) => rel_time(now.duration_since(mtime).ok()),
-                            None => String::from("N/A"),
-                        },
-                    );
+    /// Corresponds to `cub worktree prune`.
+    ///
+    /// Purges the environments created by `cub worktree new` whose branches
+    /// have been deleted from the remote they track (as of the last `git
+    /// fetch --prune`). Returns the names of the purged environments.
+    pub fn prune_worktree_environments(&self) -> Result<Vec<EnvironmentName>> {
+        let mut pruned = Vec::new();
+        for name in self.shared.worktrees.list()? {
+            let Some(worktree) = self.shared.worktrees.get(&name)? else {
+                continue;
+            };
+            match worktree::branch_gone(&worktree) {
+                Ok(true) => {
+                    self.purge_environment(&name, Quiet(true))?;
+                    pruned.push(name);
                 }
+                Ok(false) => {}
+                Err(e) => warn(e.context(format!(
+                    "failed to check whether branch {:?} of environment {name} still exists",
+                    worktree.branch
+                ))),
             }
         }
-        Ok(())
+        Ok(pruned)
     }
 
-    /// Corresponds to `cub new`.
-    pub fn new_environment(
+    /// Creates or resets an instance using the golden environment's package
+    /// list.
+    ///
+    /// The home directory comes from the golden environment, so this doesn't
+    /// need any package seeds, other than to record the package list.
+    fn init_instance<F>(&self, golden: &EnvironmentName, f: F) -> Result<()>
+    where
+        F: FnOnce(&Init) -> Result<()>,
+    {
+        let mut packages = self
+            .read_package_list_from_env(golden)
+            .with_context(|| format!("failed to parse `packages.txt` from {golden}"))?;
+        packages.insert(FullPackageName::from_str(packages::special::AUTO_INTERACTIVE).unwrap());
+        let specs = self.scan_packages()?;
+        let debian_packages = self.resolve_debian_packages(&packages, &specs)?;
+        let packages_txt = write_package_list_tar(&packages)?;
+        f(&Init {
+            debian_packages: debian_packages
+                .iter()
+                .map(|name| name.as_str().to_owned())
+                .collect(),
+            env_vars: Vec::new(),
+            seeds: vec![HostPath::try_from(packages_txt.path().to_owned())?],
+        })
+    }
+
+    /// Saves the given paths from the environment's home directory for
+    /// [`Cubicle::reset_environment_keeping`], returning a tar file to seed
+    /// the new home directory with, or `None` if there's nothing to keep.
+    fn save_home_paths(
         &self,
         name: &EnvironmentName,
-        packages: Option<BTreeSet<FullPackageName>>,
-    ) -> Result<()> {
-        use EnvironmentExists::*;
-        match self.runner.exists(name)? {
-            NoEnvironment => {}
-            PartiallyExists => {
+        keep: &[PathBuf],
+    ) -> Result<Option<NamedTempFile>> {
+        for path in keep {
+            if !path.components().all(|c| matches!(c, Component::Normal(_))) {
                 return Err(anyhow!(
-                    "environment {name} in broken state (try '{} reset')",
-                    self.shared.exe_name
-                ))
+                    "expected a path relative to the home directory to keep, found {path:?}"
+                ));
+            }
+            if path.starts_with("w") {
+                return Err(anyhow!(
+                    "no need to keep {path:?}: the work directory is always kept"
+                ));
             }
-            FullyExists => return Err(anyhow!("environment {name} already exists")),
         }
+        if keep.is_empty() || self.shared.dry_run {
+            return Ok(None);
+        }
+        if self.runner.exists(name)? != EnvironmentExists::FullyExists {
+            return Err(anyhow!(
+                "cannot keep files from environment {name} because it's only \
+                partially created (reset it without `--keep`)"
+            ));
+        }
+        self.shared.event(Event::SavingHomeFiles {
+            name: name.clone(),
+            paths: keep.to_vec(),
+        });
+        let mut file =
+            NamedTempFile::new().context("failed to create temporary file for files to keep")?;
+        self.runner.save_home_paths(name, keep, &mut file)?;
+        file.flush()
+            .context("failed to write temporary file for files to keep")?;
+        Ok(Some(file))
+    }
 
-        let packages = {
-            let mut packages = packages.unwrap_or_else(|| {
-                BTreeSet::from([FullPackageName::from_str(packages::special::DEFAULT).unwrap()])
-            });
-            packages
-                .insert(FullPackageName::from_str(packages::special::AUTO_INTERACTIVE).unwrap());
-            packages
-        };
-
-        let specs = self.scan_packages()?;
-        self.update_packages(
-            &packages,
-            &specs,
-            &UpdatePackagesConditions {
-                dependencies: ShouldPackageUpdate::IfStale,
-                named: ShouldPackageUpdate::IfStale,
-            },
-        )?;
-        let packages_txt = write_package_list_tar(&packages)?;
-        let debian_packages = self.resolve_debian_packages(&packages, &specs)?;
-
-        let mut seeds = self.packages_to_seeds(&packages, &specs)?;
-        seeds.push(HostPath::try_from(packages_txt.path().to_owned())?);
-
-        self.runner
-            .create(
-                name,
-                &Init {
-                    debian_packages: debian_packages
-                        .iter()
-                        .map(|name| name.as_str().to_owned())
-                        .collect(),
-                    env_vars: Vec::new(),
-                    seeds,
-                },
-            )
-            .with_context(|| format!("failed to initialize new environment {name}"))
+    /// Returns an error if `name` is the golden environment for any
+    /// instances, since changing its home directory would corrupt theirs.
+    fn check_no_instances(&self, name: &EnvironmentName, action: &str) -> Result<()> {
+        let instances = self.shared.golden.instances(name)?;
+        if instances.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "cannot {action} environment {name} because it's the golden environment \
+            for: {} (purge those first)",
+            instances
+                .iter()
+                .map(|name| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
     }
 
     /// Corresponds to `cub tmp`.
+    ///
+    /// The `name_prefix` overrides the `tmp.name_prefix` setting.
     pub fn create_enter_tmp_environment(
         &self,
         packages: Option<BTreeSet<FullPackageName>>,
+        name_prefix: Option<&str>,
     ) -> Result<()> {
+        self.shared
+            .check_not_dry_run("enter a temporary environment")?;
+        let tmp = &self.shared.config.tmp;
+        let prefix = randname::tmp_name_prefix(name_prefix.unwrap_or(&tmp.name_prefix))?;
         let name = {
             let name = self
                 .shared
                 .random_name_gen
-                .random_name(|name| {
+                .random_words(tmp.words, |name| {
                     if name.starts_with("cub") {
                         // that'd be confusing
                         return Ok(false);
                     }
-                    match EnvironmentName::from_string(format!("tmp-{name}")) {
+                    match EnvironmentName::from_string(format!("{prefix}{name}")) {
                         Ok(env) => {
                             let exists = self.runner.exists(&env)?;
                             Ok(exists == EnvironmentExists::NoEnvironment)
@@ -444,30 +1367,121 @@ impl Cubicle {
                     }
                 })
                 .context("Failed to generate random environment name")?;
-            EnvironmentName::from_string(format!("tmp-{name}")).unwrap()
+            EnvironmentName::from_string(format!("{prefix}{name}")).unwrap()
         };
-        self.new_environment(&name, packages)?;
-        self.runner
-            .run(&name, &RunnerCommand::Interactive)
-            .or_else(|e| match e.downcast_ref::<ExitStatusError>() {
-                Some(e) => {
-                    warn_brief(format!("exited from {name} with {}", e.status));
-                    Ok(())
-                }
-                None => Err(e),
-            })
+        self.new_environment(&name, packages, KeepPartial(false))?;
+        self.run_hook(Hook::Enter, &name)?;
+        let env_vars = secrets::read(&self.shared.config.secrets)?;
+        let shell = self.environment_shell(&name)?;
+        let _session = self.shared.activity.begin_session(&name)?;
+        lenient_exit(
+            &name,
+            self.runner.run(
+                &name,
+                &RunnerCommand::Interactive {
+                    env_vars: &env_vars,
+                    shell: shell.as_deref(),
+                },
+            ),
+        )
     }
 
     /// Corresponds to `cub purge`.
     pub fn purge_environment(&self, name: &EnvironmentName, quiet: Quiet) -> Result<()> {
-        if !quiet.0 && self.runner.exists(name)? == EnvironmentExists::NoEnvironment {
+        let _lock = self.shared.locks.lock_environment(name)?;
+        let existed = self.runner.exists(name)? != EnvironmentExists::NoEnvironment;
+        if !quiet.0 && !existed {
             warn(anyhow!(
                 "environment {name} does not exist (nothing to purge)"
             ));
         }
+        self.check_no_instances(name, "purge")?;
+        if self.shared.dry_run {
+            self.shared.plan(PlannedAction::PurgeEnvironment {
+                name: name.clone(),
+                pieces: self
+                    .runner
+                    .inspect(name)?
+                    .into_iter()
+                    .filter(|piece| piece.exists)
+                    .collect(),
+            });
+            if existed {
+                self.run_hook(Hook::Purge, name)?;
+            }
+            return Ok(());
+        }
+        let golden = self.shared.golden.golden(name)?;
         // Call purge regardless in case it disagrees with `exists` and finds
         // something useful to do.
         self.runner.purge(name)?;
+        self.shared.activity.forget(name)?;
+        self.shared.summaries.forget(name)?;
+        self.shared.golden.forget(name)?;
+        self.shared.read_only.forget(name)?;
+        self.shared.shells.forget(name)?;
+        if let Some(worktree) = self.shared.worktrees.get(name)? {
+            worktree::remove(&worktree)
+                .with_context(|| format!("failed to remove git worktree {}", worktree.path))?;
+            self.shared.worktrees.forget(name)?;
+        }
+        for dir in [
+            host_open::host_dir(&self.shared.paths, name),
+            x11::host_dir(&self.shared.paths, name),
+        ] {
+            if fs_util::try_exists(&dir).todo_context()? {
+                fs_util::rmtree(&dir)?;
+            }
+        }
+        if existed {
+            self.shared
+                .history
+                .record(Operation::Purge { name: name.clone() });
+            hooks::run(
+                &self.shared.config.hooks,
+                Hook::Purge,
+                self.shared.config.runner,
+                name,
+                golden.as_ref(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Corresponds to `cub purge --home-only` and `cub purge --work-only`.
+    ///
+    /// Deletes only the environment's home directory or only its work
+    /// directory. The environment then partially exists until `cub repair` or
+    /// `cub reset` recreates the missing directory.
+    pub fn purge_environment_dir(&self, name: &EnvironmentName, dir: EnvDir) -> Result<()> {
+        let _lock = self.shared.locks.lock_environment(name)?;
+        if self.runner.exists(name)? == EnvironmentExists::NoEnvironment {
+            return Err(Error::with_kind(
+                ErrorKind::EnvironmentNotFound,
+                format!("environment {name} does not exist"),
+            ));
+        }
+        match dir {
+            EnvDir::Home => self.check_no_instances(name, "purge the home directory of")?,
+            EnvDir::Work => {
+                if self.shared.worktrees.get(name)?.is_some() {
+                    return Err(anyhow!(
+                        "the work directory of environment {name} is a git worktree \
+                        (use `cub purge` without `--work-only` to remove it)"
+                    ));
+                }
+                self.check_no_instances(name, "purge the work directory of")?;
+            }
+        }
+        if self.shared.dry_run {
+            self.shared.plan(PlannedAction::PurgeEnvironmentDir {
+                name: name.clone(),
+                dir,
+            });
+            return Ok(());
+        }
+        self.runner.purge_dir(name, dir)?;
+        self.shared.summaries.forget(name)?;
         Ok(())
     }
 
@@ -477,13 +1491,73 @@ impl Cubicle {
         name: &EnvironmentName,
         packages: Option<BTreeSet<FullPackageName>>,
     ) -> Result<()> {
+        self.reset_environment_keeping(name, packages, &[])
+    }
+
+    /// Corresponds to `cub reset --keep`.
+    ///
+    /// Like [`Cubicle::reset_environment`], but also keeps the given files
+    /// and directories, whose paths are relative to the home directory. They
+    /// are saved before the reset and extracted over the new home directory
+    /// afterwards, so they replace any files from packages with the same
+    /// paths. Paths that don't exist are skipped.
+    pub fn reset_environment_keeping(
+        &self,
+        name: &EnvironmentName,
+        packages: Option<BTreeSet<FullPackageName>>,
+        keep: &[PathBuf],
+    ) -> Result<()> {
+        let _lock = self.shared.locks.lock_environment(name)?;
         if self.runner.exists(name)? == EnvironmentExists::NoEnvironment {
-            return Err(anyhow!(
-                "Environment {name} does not exist (did you mean '{} new'?)",
-                self.shared.exe_name,
+            return Err(Error::with_kind(
+                ErrorKind::EnvironmentNotFound,
+                format!(
+                    "Environment {name} does not exist (did you mean '{} new'?)",
+                    self.shared.exe_name,
+                ),
             ));
         }
 
+        self.check_no_instances(name, "reset")?;
+        self.check_disk_quota(name)?;
+        let kept = self.save_home_paths(name, keep)?;
+        let reset = |init: &Init| match &kept {
+            Some(kept) => {
+                let mut init = init.clone();
+                init.seeds.push(HostPath::try_from(kept.path().to_owned())?);
+                self.runner.reset(name, &init)
+            }
+            None => self.runner.reset(name, init),
+        };
+        if !self.shared.dry_run {
+            // The home directory is about to change completely.
+            self.shared.summaries.forget(name)?;
+        }
+
+        if let Some(golden) = self.shared.golden.golden(name)? {
+            if packages.is_some() {
+                return Err(anyhow!(
+                    "cannot change the packages of {name}, since it's an instance \
+                    of golden environment {golden}"
+                ));
+            }
+            if self.shared.dry_run {
+                self.shared.plan(PlannedAction::ResetEnvironment {
+                    name: name.clone(),
+                    packages: Vec::new(),
+                    keep: keep.to_vec(),
+                });
+            } else {
+                self.init_instance(&golden, reset)?;
+                self.shared.history.record(Operation::Reset {
+                    name: name.clone(),
+                    packages: Vec::new(),
+                    golden: Some(golden),
+                });
+            }
+            return self.run_hook(Hook::Reset, name);
+        }
+
         let packages = {
             let mut packages = match packages {
                 Some(packages) => packages,
@@ -496,33 +1570,313 @@ impl Cubicle {
             packages
         };
 
+        if self.shared.dry_run {
+            let specs = self.scan_packages()?;
+            self.check_debian_packages(&packages, &specs)?;
+            self.update_packages(
+                &packages,
+                &specs,
+                &UpdatePackagesConditions {
+                    dependencies: ShouldPackageUpdate::IfStale,
+                    named: ShouldPackageUpdate::IfStale,
+                    excluded: BTreeSet::new(),
+                },
+            )?;
+            self.shared.plan(PlannedAction::ResetEnvironment {
+                name: name.clone(),
+                packages: self.seed_packages(&packages, &specs)?,
+                keep: keep.to_vec(),
+            });
+        } else {
+            self.init_packages(&packages, reset)?;
+            self.shared.history.record(Operation::Reset {
+                name: name.clone(),
+                packages: self.package_versions(&packages),
+                golden: None,
+            });
+        }
+        self.run_hook(Hook::Reset, name)
+    }
+
+    /// Runs an external subcommand, like `cub foo` running `cub-foo` from
+    /// `$PATH`.
+    ///
+    /// The executable gets `--config <config_path>` followed by `args`. It
+    /// also gets these environment variables:
+    ///
+    /// - `CUBICLE_CONFIG`: The path to the configuration file.
+    /// - `CUBICLE_EXE`: The path to the current executable, so that the
+    ///   plugin can call back into `cub`.
+    /// - `CUBICLE_RUNNER`: The configured runner, as one of `bubblewrap`,
+    ///   `docker`, or `user`.
+    /// - `CUBICLE_BUILTIN_PACKAGE_DIR`: Where the built-in package
+    ///   definitions are.
+    /// - `CUBICLE_USER_PACKAGE_DIR`: Where the user's own package definitions
+    ///   are.
+    /// - `CUBICLE_PACKAGE_CACHE`: Where built packages are cached.
+    /// - `CUBICLE_PROFILE`: The selected configuration profile, if any. `cub`
+    ///   reads this too, so the plugin's calls back into `cub` use the same
+    ///   profile.
+    ///
+    /// Returns the executable's exit status, which may be unsuccessful. The
+    /// plugin reports its own errors, so callers should usually just exit
+    /// with the same status.
+    pub fn run_plugin(
+        &self,
+        name: &OsStr,
+        args: &[OsString],
+        config_path: &Path,
+    ) -> Result<ExitStatus> {
+        self.shared.check_not_dry_run("run a plugin")?;
+        let mut program = OsString::from("cub-");
+        program.push(name);
+        let exe =
+            std::env::current_exe().context("error getting the path of the current executable")?;
+        let mut command = Command::new(&program);
+        if let Some(profile) = &self.shared.config.profile {
+            command.env("CUBICLE_PROFILE", profile);
+        }
+        command
+            .arg("--config")
+            .arg(config_path)
+            .args(args)
+            .env("CUBICLE_CONFIG", config_path)
+            .env("CUBICLE_EXE", exe)
+            .env("CUBICLE_RUNNER", self.shared.config.runner.as_str())
+            .env(
+                "CUBICLE_BUILTIN_PACKAGE_DIR",
+                self.shared.code_package_dir.as_host_raw(),
+            )
+            .env(
+                "CUBICLE_USER_PACKAGE_DIR",
+                self.shared.user_package_dir.as_host_raw(),
+            )
+            .env(
+                "CUBICLE_PACKAGE_CACHE",
+                self.shared.paths.package_cache.as_host_raw(),
+            )
+            .status()
+            .with_context(|| {
+                format!(
+                    "unrecognized subcommand {:?} (and could not run {program:?})",
+                    name.to_string_lossy()
+                )
+            })
+    }
+
+    /// Runs the configured script for `hook` on the environment, if any.
+    fn run_hook(&self, hook: Hook, name: &EnvironmentName) -> Result<()> {
+        if self.shared.dry_run {
+            if let Some(action) = hooks::plan(&self.shared.config.hooks, hook, name) {
+                self.shared.plan(action);
+            }
+            return Ok(());
+        }
+        hooks::run(
+            &self.shared.config.hooks,
+            hook,
+            self.shared.config.runner,
+            name,
+            self.shared.golden.golden(name)?.as_ref(),
+        )
+    }
+
+    /// Returns which pieces of the environment exist, for `cub repair`.
+    pub fn inspect_environment(&self, name: &EnvironmentName) -> Result<Vec<EnvPiece>> {
+        if self.runner.exists(name)? == EnvironmentExists::NoEnvironment {
+            return Err(Error::with_kind(
+                ErrorKind::EnvironmentNotFound,
+                format!(
+                    "Environment {name} does not exist (did you mean '{} new'?)",
+                    self.shared.exe_name,
+                ),
+            ));
+        }
+        self.runner.inspect(name)
+    }
+
+    /// Corresponds to `cub repair`.
+    ///
+    /// If the environment only partially exists, this recreates the missing
+    /// pieces, keeping the existing ones where possible. Unlike
+    /// [`Cubicle::reset_environment`], this doesn't replace an existing home
+    /// directory. Returns false if the environment wasn't broken.
+    pub fn repair_environment(&self, name: &EnvironmentName) -> Result<bool> {
+        self.shared.check_not_dry_run("repair an environment")?;
+        use EnvironmentExists::*;
+        let _lock = self.shared.locks.lock_environment(name)?;
+        match self.runner.exists(name)? {
+            NoEnvironment => {
+                return Err(Error::with_kind(
+                    ErrorKind::EnvironmentNotFound,
+                    format!(
+                        "Environment {name} does not exist (did you mean '{} new'?)",
+                        self.shared.exe_name,
+                    ),
+                ));
+            }
+            PartiallyExists => {}
+            FullyExists => return Ok(false),
+        }
+
+        self.shared.event(Event::RepairingEnvironment(name.clone()));
+        if let Some(golden) = self.shared.golden.golden(name)? {
+            self.init_instance(&golden, |init| self.runner.repair(name, init))?;
+            return Ok(true);
+        }
+        let mut packages = self.read_package_list_from_env(name).unwrap_or_else(|e| {
+            warn(e.context(format!(
+                "failed to read `packages.txt` from {name} (using default packages)"
+            )));
+            BTreeSet::from([FullPackageName::from_str(packages::special::DEFAULT).unwrap()])
+        });
+        packages.insert(FullPackageName::from_str(packages::special::AUTO_INTERACTIVE).unwrap());
+        self.init_packages(&packages, |init| self.runner.repair(name, init))?;
+        Ok(true)
+    }
+
+    /// Updates the given packages as needed and passes the resulting
+    /// initialization parameters to `f`, which should create, reset, or
+    /// repair an environment.
+    fn init_packages<F>(&self, packages: &BTreeSet<FullPackageName>, f: F) -> Result<()>
+    where
+        F: FnOnce(&Init) -> Result<()>,
+    {
         let specs = self.scan_packages()?;
+        self.check_debian_packages(packages, &specs)?;
         self.update_packages(
-            &packages,
+            packages,
             &specs,
             &UpdatePackagesConditions {
                 dependencies: ShouldPackageUpdate::IfStale,
                 named: ShouldPackageUpdate::IfStale,
+                excluded: BTreeSet::new(),
             },
         )?;
-        let debian_packages = self.resolve_debian_packages(&packages, &specs)?;
-        let mut seeds = self.packages_to_seeds(&packages, &specs)?;
+        let debian_packages = self.resolve_debian_packages(packages, &specs)?;
+        let mut seeds = self.packages_to_seeds(packages, &specs)?;
 
-        let packages_txt = write_package_list_tar(&packages)?;
+        let packages_txt = write_package_list_tar(packages)?;
         seeds.push(HostPath::try_from(packages_txt.path().to_owned())?);
 
-        self.runner.reset(
-            name,
-            &Init {
-                debian_packages: debian_packages
-                    .iter()
-                    .map(|name| name.as_str().to_owned())
-                    .collect(),
-                env_vars: Vec::new(),
-                seeds,
-            },
-        )
+        f(&Init {
+            debian_packages: debian_packages
+                .iter()
+                .map(|name| name.as_str().to_owned())
+                .collect(),
+            env_vars: self.init_env_vars(),
+            seeds,
+        })
     }
+
+    /// Returns the environment variables for the initialization script.
+    fn init_env_vars(&self) -> Vec<(String, String)> {
+        let mut env_vars = self.dotfiles_env_vars();
+        env_vars.extend(self.caches_env_vars());
+        env_vars
+    }
+
+    /// Returns the environment variables that tell the initialization
+    /// script which dotfiles repository to apply, if any.
+    fn dotfiles_env_vars(&self) -> Vec<(String, String)> {
+        let Some(dotfiles) = &self.shared.config.dotfiles else {
+            return Vec::new();
+        };
+        let mut env_vars = vec![
+            ("CUBICLE_DOTFILES_REPO", dotfiles.repo.clone()),
+            (
+                "CUBICLE_DOTFILES_METHOD",
+                dotfiles.method.as_str().to_owned(),
+            ),
+        ];
+        if let Some(branch) = &dotfiles.branch {
+            env_vars.push(("CUBICLE_DOTFILES_BRANCH", branch.clone()));
+        }
+        env_vars
+            .into_iter()
+            .map(|(var, value)| (var.to_owned(), value))
+            .collect()
+    }
+
+    /// Checks that the Debian packages needed for the given packages are
+    /// available, before spending time building packages. If the runner
+    /// can't list the available Debian packages, this skips the check and
+    /// leaves any errors to be found later.
+    fn check_debian_packages(
+        &self,
+        packages: &BTreeSet<FullPackageName>,
+        specs: &PackageSpecs,
+    ) -> Result<()> {
+        let debian_packages = self.resolve_debian_packages(packages, specs)?;
+        if debian_packages.is_empty() {
+            return Ok(());
+        }
+        match self.runner.available_debian_packages() {
+            Ok(Some(available)) => {
+                apt::check_available(debian_packages.iter().map(|name| name.as_str()), &available)
+            }
+            Ok(None) => Ok(()),
+            Err(e) => {
+                warn(e.context("skipping check for missing Debian packages"));
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks whether an environment's work directory alone exceeds the
+    /// configured disk quota, before its home directory is re-seeded.
+    ///
+    /// This warns if the environment is over quota, or returns an error if
+    /// the quota is enforced.
+    fn check_disk_quota(&self, name: &EnvironmentName) -> Result<()> {
+        let Some(quota) = &self.shared.config.disk_quota else {
+            return Ok(());
+        };
+        let summary = self
+            .runner
+            .files_summary(name)
+            .with_context(|| format!("failed to summarize disk usage for {name}"))?;
+        let used = summary.work_dir.total_size;
+        if used <= quota.size {
+            return Ok(());
+        }
+        let message = format!(
+            "environment {name} work directory uses {}, over its disk quota of {}",
+            Bytes(used),
+            Bytes(quota.size),
+        );
+        if quota.enforce {
+            Err(Error::with_kind(
+                ErrorKind::DiskQuotaExceeded,
+                format!("{message} (free up space in the work directory first)"),
+            ))
+        } else {
+            warn(anyhow!("{message}"));
+            Ok(())
+        }
+    }
+}
+
+/// Treats an interactive shell exiting unsuccessfully as normal, since that
+/// often just means the last command in the shell failed or the terminal was
+/// closed. Other errors, including the shell being killed by other signals,
+/// are returned.
+fn lenient_exit(name: &EnvironmentName, result: Result<()>) -> Result<()> {
+    result.or_else(|e| match e.downcast_ref::<ExitStatusError>() {
+        Some(e)
+            if matches!(
+                e.signal().map(Signal::from_raw),
+                None | Some(Some(Signal::Hup))
+            ) =>
+        {
+            warn_brief(format!(
+                "exited from {name} with {}",
+                e.status_description()
+            ));
+            Ok(())
+        }
+        _ => Err(e),
+    })
 }
 
 #[derive(Debug)]
@@ -533,25 +1887,66 @@ struct ExitStatusError {
 
 impl ExitStatusError {
     fn new(status: ExitStatus, context: &str) -> Self {
-        assert!(matches!(status.code(), Some(c) if c != 0));
+        assert!(!status.success());
         Self {
             status,
             context: context.to_owned(),
         }
     }
+
+    /// Returns the signal that terminated the process, if it was killed by a
+    /// signal.
+    fn signal(&self) -> Option<i32> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            self.status.signal()
+        }
+        #[cfg(not(unix))]
+        None
+    }
+
+    /// Describes how the process exited, like "exit status 1" or "SIGKILL".
+    fn status_description(&self) -> String {
+        match (self.status.code(), self.signal()) {
+            (Some(code), _) => format!("exit status {code}"),
+            (None, Some(signal)) => signal_name(signal),
+            (None, None) => self.status.to_string(),
+        }
+    }
 }
 
 impl std::error::Error for ExitStatusError {}
 
 impl Display for ExitStatusError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Non-zero exit status ({}) from {}",
-            self.status.code().unwrap(),
-            self.context
-        )
+        match self.status.code() {
+            Some(code) => write!(f, "Non-zero exit status ({code}) from {}", self.context),
+            None => write!(
+                f,
+                "{} killed by {}",
+                self.context,
+                self.status_description()
+            ),
+        }
+    }
+}
+
+/// Returns a signal's name, like "SIGKILL", or "signal N" for less common
+/// signals.
+fn signal_name(signal: i32) -> String {
+    match Signal::from_raw(signal) {
+        Some(Signal::Hup) => "SIGHUP",
+        Some(Signal::Int) => "SIGINT",
+        Some(Signal::Quit) => "SIGQUIT",
+        Some(Signal::Abort) => "SIGABRT",
+        Some(Signal::Kill) => "SIGKILL",
+        Some(Signal::Segv) => "SIGSEGV",
+        Some(Signal::Pipe) => "SIGPIPE",
+        Some(Signal::Term) => "SIGTERM",
+        _ => return format!("signal {signal}"),
     }
+    .to_owned()
 }
 
 impl From<ExitStatusError> for somehow::Error {
@@ -622,6 +2017,15 @@ impl FromStr for EnvironmentName {
     }
 }
 
+impl<'de> Deserialize<'de> for EnvironmentName {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Self::from_string(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
 impl Display for EnvironmentName {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         Debug::fmt(&self.0, f)
@@ -634,18 +2038,6 @@ impl std::convert::AsRef<str> for EnvironmentName {
     }
 }
 
-/// Allowed formats for [`Cubicle::list_environments`].
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
-pub enum ListFormat {
-    /// Human-formatted table.
-    #[default]
-    Default,
-    /// Detailed JSON output for machine consumption.
-    Json,
-    /// Newline-delimited list of environment names only.
-    Names,
-}
-
 /// The type of runner to use to run isolated environments.
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
 pub enum RunnerKind {
@@ -663,36 +2055,34 @@ pub enum RunnerKind {
     #[serde(alias = "Users")]
     #[serde(alias = "users")]
     User,
+
+    /// Probe the host to pick the Bubblewrap or Docker runner the first time
+    /// Cubicle runs, then keep using that runner.
+    ///
+    /// [`CubicleBuilder::build`] resolves this to one of the other runners.
+    #[serde(alias = "auto")]
+    Auto,
 }
 
-fn time_serialize_opt<S>(time: &Option<SystemTime>, ser: S) -> std::result::Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    match time {
-        Some(time) => {
-            let time = time.duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
-            ser.serialize_some(&time)
+impl RunnerKind {
+    /// Returns the canonical name of the runner, as used in the config file.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Bubblewrap => "bubblewrap",
+            Self::Docker => "docker",
+            Self::User => "user",
+            Self::Auto => "auto",
         }
-        None => ser.serialize_none(),
     }
 }
 
-fn rel_time(duration: Option<Duration>) -> String {
-    let mut duration = match duration {
-        Some(duration) => duration.as_secs_f64(),
-        None => return String::from("N/A"),
-    };
-    duration /= 60.0;
-    if duration < 59.5 {
-        return format!("{duration:.0} minutes");
-    }
-    duration /= 60.0;
-    if duration < 23.5 {
-        return format!("{duration:.0} hours");
-    }
-    duration /= 24.0;
-    format!("{duration:.0} days")
+impl Serialize for RunnerKind {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
 }
 
 fn nonzero_time(t: SystemTime) -> Option<SystemTime> {
@@ -704,7 +2094,9 @@ fn nonzero_time(t: SystemTime) -> Option<SystemTime> {
 }
 
 /// Description of an environment as returned by [`Cubicle::get_environments`].
-#[derive(Debug, Serialize)]
+///
+/// This is part of the [`json`] output format.
+#[derive(Debug, Deserialize, Serialize)]
 #[non_exhaustive]
 pub struct EnvironmentDetails {
     /// The path on the host of the environment's home directory, if available.
@@ -716,7 +2108,10 @@ pub struct EnvironmentDetails {
     pub home_dir_size: u64,
     /// The most recent time that `home_dir` or any file or directory within
     /// it was modified.
-    #[serde(serialize_with = "time_serialize_opt")]
+    #[serde(
+        serialize_with = "json::serialize_time_opt",
+        deserialize_with = "json::deserialize_time_opt"
+    )]
     pub home_dir_mtime: Option<SystemTime>,
     /// The path on the host of the environment's work directory, if available.
     pub work_dir: Option<PathBuf>,
@@ -727,13 +2122,87 @@ pub struct EnvironmentDetails {
     pub work_dir_size: u64,
     /// The most recent time that `work_dir` or any file or directory within
     /// it was modified.
-    #[serde(serialize_with = "time_serialize_opt")]
+    #[serde(
+        serialize_with = "json::serialize_time_opt",
+        deserialize_with = "json::deserialize_time_opt"
+    )]
     pub work_dir_mtime: Option<SystemTime>,
+    /// When the `home_dir_*` and `work_dir_*` fields were computed, if
+    /// known.
+    #[serde(
+        serialize_with = "json::serialize_time_opt",
+        deserialize_with = "json::deserialize_time_opt"
+    )]
+    pub summarized: Option<SystemTime>,
+    /// If true, the `home_dir_*` and `work_dir_*` fields come from a cached
+    /// summary that may be out of date. See [`Cubicle::get_environments`].
+    pub summary_stale: bool,
+    /// The golden environment whose home directory this environment's home
+    /// directory is layered on top of, if any. See
+    /// [`Cubicle::new_instance`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub golden: Option<EnvironmentName>,
+    /// The owner and group of an environment that's shared with a group, if
+    /// any. See [`Cubicle::new_shared_environment`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared: Option<Sharing>,
+    /// The configured limit in bytes on the combined size of `home_dir` and
+    /// `work_dir`, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_quota: Option<u64>,
+    /// If true, at least one session is currently running in the
+    /// environment.
+    #[serde(default)]
+    pub running: bool,
+    /// A map from network names to details about the environment's
+    /// connection to that network.
+    ///
+    /// This is only filled in by [`Cubicle::get_environment`], since it can
+    /// be slow to compute for many environments.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub networks: Option<BTreeMap<String, NetworkDetails>>,
+}
+
+/// Description of an environment's network connection, as found in
+/// [`EnvironmentDetails::networks`].
+#[derive(Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct NetworkDetails {
+    /// The environment's IP address on the network, if available.
+    pub ip_address: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn exit_status_error() {
+        let name = EnvironmentName::from_str("foo").unwrap();
+        // These are raw wait statuses: the exit code is in the second byte
+        // and the signal is in the low bits.
+        let exited = ExitStatusError::new(ExitStatus::from_raw(3 << 8), "bwrap");
+        let killed = ExitStatusError::new(ExitStatus::from_raw(Signal::Kill as i32), "bwrap");
+        let hung_up = ExitStatusError::new(ExitStatus::from_raw(Signal::Hup as i32), "bwrap");
+        let other = ExitStatusError::new(ExitStatus::from_raw(40), "bwrap");
+
+        assert_eq!("Non-zero exit status (3) from bwrap", exited.to_string());
+        assert_eq!("bwrap killed by SIGKILL", killed.to_string());
+        assert_eq!("bwrap killed by signal 40", other.to_string());
+
+        assert!(lenient_exit(&name, Err(exited.into())).is_ok());
+        assert!(lenient_exit(&name, Err(hung_up.into())).is_ok());
+        let error = lenient_exit(&name, Err(killed.into())).unwrap_err();
+        assert_eq!("bwrap killed by SIGKILL", error.to_string());
+        assert!(lenient_exit(&name, Err(other.into())).is_err());
+    }
 }
 
 /// These things are public out of convenience but probably shouldn't be.
 #[doc(hidden)]
 pub mod hidden {
+    use std::ffi::OsStr;
     use std::path::Path;
     /// Returns the path to the home directory on the host.
     ///
@@ -743,4 +2212,29 @@ pub mod hidden {
     pub fn host_home_dir() -> &'static Path {
         super::host_home_dir().as_host_raw()
     }
+
+    /// Runs a program to completion and returns an error, including what it
+    /// printed to stderr, if it fails.
+    // Note: This is public because the `cli` mod runs `systemctl` and
+    // `launchctl` to install timers.
+    pub fn run_command(program: &str, args: &[&str]) -> crate::Result<()> {
+        let output = super::Command::new(program).args(args).output()?;
+        if !output.status.success() {
+            return Err(crate::somehow::somehow!(
+                "`{program} {}` failed with {}: {}",
+                args.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Starts a program in the background with its standard streams closed,
+    /// without waiting for it.
+    // Note: This is public because the `cli` mod runs `cub list --refresh`
+    // this way to update the disk usage of environments.
+    pub fn spawn_detached(program: &Path, args: &[&OsStr]) -> crate::Result<()> {
+        super::Command::new(program).args(args).spawn_detached()
+    }
 }
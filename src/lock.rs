@@ -0,0 +1,204 @@
+//! File-based locks that keep concurrent `cub` processes from racing on the
+//! same environment or package.
+//!
+//! Each environment and package has a lock file. A process holds an exclusive
+//! `flock` on the file while it creates, resets, or purges the environment, or
+//! while it builds the package (which also uses the package's builder
+//! environment). The kernel releases the lock if the process dies.
+
+use rustix::fs::{flock, FlockOperation};
+use std::fs::File;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::cancel;
+use super::encoding::FilenameEncoder;
+use super::{EnvironmentName, Event, EventSink, FullPackageName, HostPath};
+use crate::somehow::{Context, Error, ErrorKind, Result};
+
+/// How often to retry while waiting for a lock held by another process.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct Locks {
+    dir: HostPath,
+    /// How long to wait for another process to release a lock. `None` waits
+    /// indefinitely and zero doesn't wait at all.
+    timeout: Option<Duration>,
+    events: Arc<dyn EventSink>,
+}
+
+/// Holds a lock until dropped.
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+impl Locks {
+    pub fn new(dir: HostPath, timeout: Option<Duration>, events: Arc<dyn EventSink>) -> Self {
+        Self {
+            dir,
+            timeout,
+            events,
+        }
+    }
+
+    /// Locks the environment, waiting for other processes if needed.
+    pub fn lock_environment(&self, name: &EnvironmentName) -> Result<Lock> {
+        let file = self.open_environment(name)?;
+        self.wait(file, &format!("environment {name}"))
+    }
+
+    /// Locks the environment if no other process holds its lock.
+    pub fn try_lock_environment(&self, name: &EnvironmentName) -> Result<Option<Lock>> {
+        let file = self.open_environment(name)?;
+        let locked =
+            try_flock(&file).with_context(|| format!("failed to lock environment {name}"))?;
+        Ok(locked.then_some(Lock { _file: file }))
+    }
+
+    /// Locks the package, waiting for other processes if needed.
+    pub fn lock_package(&self, name: &FullPackageName) -> Result<Lock> {
+        let path = self.dir.join("package").join(
+            FilenameEncoder::new()
+                .push(&name.unquoted())
+                .push(".lock")
+                .encode(),
+        );
+        let file = open(&path)?;
+        self.wait(file, &format!("package {name}"))
+    }
+
+    /// Locks the Bubblewrap runner's shared seed layers, waiting for other
+    /// processes if needed.
+    pub fn lock_seed_layers(&self) -> Result<Lock> {
+        let file = open(&self.dir.join("seed-layers.lock"))?;
+        self.wait(file, "seed layers")
+    }
+
+    /// Locks the Docker runner's base image, waiting for other processes if
+    /// needed.
+    pub fn lock_base_image(&self) -> Result<Lock> {
+        let file = open(&self.dir.join("base-image.lock"))?;
+        self.wait(file, "base image")
+    }
+
+    /// Locks the mount of an encrypted environment's decrypted files,
+    /// waiting for other processes if needed. This is separate from the
+    /// environment's lock, which commands like `cub enter` don't hold.
+    pub fn lock_decrypted(&self, name: &EnvironmentName) -> Result<Lock> {
+        let file = open(&self.dir.join("decrypted").join(name.as_filename()))?;
+        self.wait(file, &format!("decrypted files of environment {name}"))
+    }
+
+    fn open_environment(&self, name: &EnvironmentName) -> Result<File> {
+        open(&self.dir.join("env").join(name.as_filename()))
+    }
+
+    fn wait(&self, file: File, what: &str) -> Result<Lock> {
+        let lock = |file: &File| try_flock(file).with_context(|| format!("failed to lock {what}"));
+        if lock(&file)? {
+            return Ok(Lock { _file: file });
+        }
+        if self.timeout == Some(Duration::ZERO) {
+            return Err(Error::with_kind(
+                ErrorKind::Locked,
+                format!("{what} is locked by another process"),
+            ));
+        }
+        self.events.event(&Event::WaitingForLock {
+            what: what.to_owned(),
+        });
+        let start = Instant::now();
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            cancel::check()?;
+            if lock(&file)? {
+                return Ok(Lock { _file: file });
+            }
+            if let Some(timeout) = self.timeout {
+                if start.elapsed() >= timeout {
+                    return Err(Error::with_kind(
+                        ErrorKind::Locked,
+                        format!(
+                            "timed out after {} seconds waiting for another process to \
+                            finish with {what}",
+                            timeout.as_secs()
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn open(path: &HostPath) -> Result<File> {
+    if let Some(dir) = path.as_host_raw().parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create directory {dir:?}"))?;
+    }
+    File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.as_host_raw())
+        .with_context(|| format!("failed to open lock file {path}"))
+}
+
+/// Returns true if the file was locked or false if another open file holds the
+/// lock.
+fn try_flock(file: &File) -> rustix::io::Result<bool> {
+    match flock(file, FlockOperation::NonBlockingLockExclusive) {
+        Ok(()) => Ok(true),
+        Err(e) if e == rustix::io::Errno::WOULDBLOCK => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    #[test]
+    fn locks() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir_path = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        let locks = Locks::new(
+            tmpdir_path.join("locks"),
+            Some(Duration::ZERO),
+            Arc::new(|_: &Event| {}),
+        );
+        let name = EnvironmentName::from_str("foo").unwrap();
+        let package = FullPackageName::from_str("bar").unwrap();
+
+        let lock = locks.lock_environment(&name).unwrap();
+        assert!(locks.try_lock_environment(&name).unwrap().is_none());
+        assert!(locks
+            .lock_environment(&name)
+            .unwrap_err()
+            .to_string()
+            .contains("is locked by another process"));
+        let _package_lock = locks.lock_package(&package).unwrap();
+        assert!(locks.lock_package(&package).is_err());
+        drop(lock);
+        assert!(locks.try_lock_environment(&name).unwrap().is_some());
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let locks = Locks::new(tmpdir_path.join("locks"), Some(Duration::from_millis(1)), {
+            let events = events.clone();
+            Arc::new(move |event: &Event| events.lock().unwrap().push(event.to_string()))
+        });
+        assert!(locks
+            .lock_package(&package)
+            .unwrap_err()
+            .to_string()
+            .contains("timed out"));
+        assert_eq!(
+            vec![String::from(
+                "Waiting for another process to finish with package \"bar\""
+            )],
+            *events.lock().unwrap()
+        );
+    }
+}
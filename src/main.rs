@@ -1,10 +1,45 @@
 mod cli;
 
-use cubicle::{config::Config, Cubicle, Result};
+use std::time::Instant;
+
+use cubicle::{config::Config, CubicleBuilder, Result};
 
 fn main() -> Result<()> {
+    let start = Instant::now();
     let args = cli::parse();
-    let config = Config::read_from_file(args.config_path())?;
-    let program = Cubicle::new(config)?;
-    cli::run(args, &program)
+    cubicle::cancel::install_handler()?;
+    if let Some(result) = cli::run_without_program(&args) {
+        return result;
+    }
+    if let Some(result) = cli::run_with_daemon(&args) {
+        return result;
+    }
+    let mut config =
+        Config::read_from_file_with_profile(args.config_path(), args.profile().as_deref())?;
+    args.override_config(&mut config);
+    let program = CubicleBuilder::new(config)
+        .events(if args.stdout_is_data() {
+            cli::eprint_event
+        } else {
+            cli::print_event
+        })
+        .dry_run(args.dry_run())
+        .build()?;
+    let timings = args.timings();
+    let long_running = args.long_running_command();
+    let daemon = cli::daemon_to_invalidate(&args);
+    let result = cli::run(args, &program);
+    if let Some(client) = daemon {
+        cli::invalidate_daemon(client);
+    }
+    if let Some(command) = long_running {
+        program.notify_finished(command, &result, start.elapsed());
+    }
+    if timings {
+        cli::print_timings(&program.timings(), start.elapsed());
+    }
+    if let Some(code) = result.as_ref().err().and_then(cli::plugin_exit_code) {
+        std::process::exit(code);
+    }
+    result
 }
@@ -0,0 +1,195 @@
+//! Renders metrics about environments and package builds in the Prometheus
+//! text format, for `cub metrics` and `cub daemon --metrics-address`.
+
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::activity::Activity;
+use super::{Cubicle, Refresh};
+use crate::somehow::Result;
+
+/// A metric with one label, as rendered by [`render`].
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    label: &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+impl Metric {
+    fn gauge(name: &'static str, label: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind: "gauge",
+            label,
+            samples: Vec::new(),
+        }
+    }
+
+    fn counter(name: &'static str, label: &'static str, help: &'static str) -> Self {
+        Self {
+            kind: "counter",
+            ..Self::gauge(name, label, help)
+        }
+    }
+}
+
+impl Cubicle {
+    /// Corresponds to `cub metrics`.
+    ///
+    /// Returns metrics in the Prometheus text exposition format: disk usage,
+    /// whether each environment is in use, when each environment was last
+    /// used, and the durations and failure counts of package builds. Disk
+    /// usage may come from a recently cached summary.
+    pub fn metrics(&self) -> Result<String> {
+        let seconds = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64()
+        };
+        let bool = |b: bool| if b { 1.0 } else { 0.0 };
+
+        let mut home_size = Metric::gauge(
+            "cubicle_environment_home_dir_bytes",
+            "environment",
+            "Size of the environment's home directory, excluding its work directory.",
+        );
+        let mut work_size = Metric::gauge(
+            "cubicle_environment_work_dir_bytes",
+            "environment",
+            "Size of the environment's work directory.",
+        );
+        let mut in_use = Metric::gauge(
+            "cubicle_environment_in_use",
+            "environment",
+            "Whether a shell or command is running in the environment.",
+        );
+        let mut last_used = Metric::gauge(
+            "cubicle_environment_last_used_timestamp_seconds",
+            "environment",
+            "When the environment was last used, if known and not in use now.",
+        );
+        for (name, env) in self.get_environments(Refresh(false))? {
+            let label = name.as_str().to_owned();
+            home_size
+                .samples
+                .push((label.clone(), env.home_dir_size as f64));
+            work_size
+                .samples
+                .push((label.clone(), env.work_dir_size as f64));
+            let activity = self.shared.activity.activity(&name)?;
+            in_use
+                .samples
+                .push((label.clone(), bool(activity == Activity::InUse)));
+            if let Activity::LastUsed(time) = activity {
+                last_used.samples.push((label, seconds(time)));
+            }
+        }
+
+        let mut builds = Metric::counter(
+            "cubicle_package_builds_total",
+            "package",
+            "Number of completed build attempts for the package.",
+        );
+        let mut failures = Metric::counter(
+            "cubicle_package_build_failures_total",
+            "package",
+            "Number of failed build attempts for the package.",
+        );
+        let mut duration = Metric::gauge(
+            "cubicle_package_last_build_duration_seconds",
+            "package",
+            "How long the package's most recent build attempt took.",
+        );
+        let mut last_failed = Metric::gauge(
+            "cubicle_package_last_build_failed",
+            "package",
+            "Whether the package's most recent build attempt failed.",
+        );
+        for name in self.get_package_names()? {
+            let stats = self.package_build_stats(&name)?;
+            if stats.builds == 0 {
+                continue;
+            }
+            let label = name.unquoted();
+            builds.samples.push((label.clone(), stats.builds as f64));
+            failures
+                .samples
+                .push((label.clone(), stats.failures as f64));
+            if let Some(d) = stats.last_duration {
+                duration.samples.push((label.clone(), d.as_secs_f64()));
+            }
+            last_failed
+                .samples
+                .push((label, bool(self.package_build_failed(&name)?)));
+        }
+
+        Ok(render(&[
+            home_size,
+            work_size,
+            in_use,
+            last_used,
+            builds,
+            failures,
+            duration,
+            last_failed,
+        ]))
+    }
+}
+
+fn render(metrics: &[Metric]) -> String {
+    let mut out = String::new();
+    for metric in metrics {
+        let Metric {
+            name,
+            help,
+            kind,
+            label,
+            samples,
+        } = metric;
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} {kind}").unwrap();
+        for (value, sample) in samples {
+            writeln!(
+                out,
+                "{name}{{{label}=\"{}\"}} {sample}",
+                escape_label(value)
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+/// Escapes a label value as the Prometheus text format requires.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+
+    #[test]
+    fn render() {
+        let mut size = Metric::gauge("cubicle_size_bytes", "environment", "Size.");
+        size.samples.push((String::from("a"), 1234.0));
+        size.samples.push((String::from(r#"b "c" \d"#), 0.5));
+        let builds = Metric::counter("cubicle_builds_total", "package", "Builds.");
+        expect![[r#"
+            # HELP cubicle_size_bytes Size.
+            # TYPE cubicle_size_bytes gauge
+            cubicle_size_bytes{environment="a"} 1234
+            cubicle_size_bytes{environment="b \"c\" \\d"} 0.5
+            # HELP cubicle_builds_total Builds.
+            # TYPE cubicle_builds_total counter
+        "#]]
+        .assert_eq(&super::render(&[size, builds]));
+    }
+}
@@ -0,0 +1,173 @@
+//! Reports when long-running commands finish, for the `notify` configuration
+//! option.
+
+use std::time::Duration;
+
+use super::command_ext::Command;
+use super::config::Notify;
+use super::Cubicle;
+use crate::somehow::{somehow as anyhow, warn, Context, Error, Result};
+
+impl Cubicle {
+    /// Used by the command-line program after `command` (like `"package
+    /// update"`) finishes with `result`, having taken `elapsed`.
+    ///
+    /// If the command took at least `notify.min_duration`, this runs the
+    /// configured command and shows a desktop notification, as configured.
+    /// Problems doing so are printed as warnings.
+    pub fn notify_finished(&self, command: &str, result: &Result<()>, elapsed: Duration) {
+        let Some(notify) = &self.shared.config.notify else {
+            return;
+        };
+        if self.shared.dry_run || elapsed < notify.min_duration {
+            return;
+        }
+        let error = result.as_ref().err();
+        if let Err(e) = run_command(notify, command, error, elapsed) {
+            warn(e);
+        }
+        if notify.desktop {
+            if let Err(e) = desktop_notification(command, error, elapsed) {
+                warn(e.context("failed to show desktop notification"));
+            }
+        }
+    }
+}
+
+fn run_command(
+    notify: &Notify,
+    command: &str,
+    error: Option<&Error>,
+    elapsed: Duration,
+) -> Result<()> {
+    let Some(path) = &notify.command else {
+        return Ok(());
+    };
+    let mut child = Command::new(path);
+    child
+        .env("CUBICLE_COMMAND", command)
+        .env(
+            "CUBICLE_STATUS",
+            if error.is_some() {
+                "failure"
+            } else {
+                "success"
+            },
+        )
+        .env("CUBICLE_DURATION", elapsed.as_secs().to_string());
+    if let Some(error) = error {
+        child.env("CUBICLE_ERROR", error.to_string());
+    }
+    let status = child
+        .status()
+        .with_context(|| format!("failed to run `notify.command` {path:?}"))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("`notify.command` {path:?} exited with {status}"))
+    }
+}
+
+fn desktop_notification(command: &str, error: Option<&Error>, elapsed: Duration) -> Result<()> {
+    let (title, body) = message(command, error, elapsed);
+    let mut child = if cfg!(target_os = "macos") {
+        let mut child = Command::new("osascript");
+        child.arg("-e").arg(format!(
+            "display notification {} with title {}",
+            applescript_string(&body),
+            applescript_string(&title)
+        ));
+        child
+    } else {
+        let mut child = Command::new("notify-send");
+        child.arg("--app-name=Cubicle").arg(title).arg(body);
+        child
+    };
+    let output = child.output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "notification command exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Returns the title and body of a desktop notification.
+fn message(command: &str, error: Option<&Error>, elapsed: Duration) -> (String, String) {
+    let elapsed = format_duration(elapsed);
+    match error {
+        None => (
+            format!("cub {command} finished"),
+            format!("Succeeded after {elapsed}"),
+        ),
+        Some(error) => (
+            format!("cub {command} failed"),
+            format!("Failed after {elapsed}: {error}"),
+        ),
+    }
+}
+
+/// Formats a duration like `1h 2m` or `3m 4s`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 60 * 60 {
+        format!("{}h {}m", secs / (60 * 60), secs / 60 % 60)
+    } else if secs >= 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{secs}s")
+    }
+}
+
+/// Quotes a string for AppleScript.
+fn applescript_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message() {
+        let error = anyhow!("package foo failed to build");
+        assert_eq!(
+            (
+                String::from("cub package update finished"),
+                String::from("Succeeded after 2m 5s"),
+            ),
+            super::message("package update", None, Duration::from_secs(125))
+        );
+        assert_eq!(
+            (
+                String::from("cub reset failed"),
+                String::from("Failed after 1h 0m: package foo failed to build"),
+            ),
+            super::message("reset", Some(&error), Duration::from_secs(3630))
+        );
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn desktop_notification() {
+        let (result, commands) = crate::command_ext::capture(|| {
+            super::desktop_notification("reset", None, Duration::from_secs(75))
+        });
+        result.unwrap();
+        assert_eq!(
+            vec!["notify-send '--app-name=Cubicle' 'cub reset finished' 'Succeeded after 1m 15s'"],
+            commands
+        );
+    }
+
+    #[test]
+    fn applescript_string() {
+        assert_eq!(
+            r#""say \"hi\" \\ bye""#,
+            super::applescript_string(r#"say "hi" \ bye"#)
+        );
+    }
+}
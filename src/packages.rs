@@ -1,5 +1,5 @@
-use clap::ValueEnum;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::borrow::Borrow;
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, BTreeSet};
@@ -7,22 +7,30 @@ use std::fmt::{self, Debug, Display};
 use std::io::{self, BufRead, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 use tempfile::NamedTempFile;
 
-use crate::somehow::{somehow as anyhow, warn, Context, Error, LowLevelResult, Result};
+use crate::somehow::{somehow as anyhow, warn, Context, Error, ErrorKind, LowLevelResult, Result};
 
+use super::cancel;
 use super::encoding::FilenameEncoder;
 use super::fs_util::{
     create_tar_from_dir, file_size, summarize_dir, try_exists, try_iterdir, try_iterdir_dirs,
     DirSummary, TarOptions,
 };
 use super::runner::{EnvironmentExists, Init, Runner, RunnerCommand};
-use super::{rel_time, time_serialize_opt, Bytes, Cubicle, EnvironmentName, HostPath, RunnerKind};
+use super::{
+    json, BuildReason, Cubicle, EnvironmentName, Event, HostPath, Operation, PackageVersion, Phase,
+    PlannedAction, RunnerKind,
+};
 
+mod build_stats;
+mod dockerfile;
 mod manifest;
-pub(crate) use manifest::Target;
-use manifest::{Dependency, Manifest};
+mod reseed;
+mod status_cache;
+mod watch;
+pub use manifest::{Capability, Dependency, Manifest, Target};
 
 pub mod special {
     pub const AUTO_BATCH: &str = "auto-batch";
@@ -58,6 +66,10 @@ pub struct UpdatePackagesConditions {
     pub dependencies: ShouldPackageUpdate,
     /// When should the given packages themselves be updated?
     pub named: ShouldPackageUpdate,
+    /// Dependencies that should only be built if they've never been built
+    /// successfully, regardless of `dependencies`. This defers rebuilding
+    /// slow dependencies. It doesn't apply to the given packages themselves.
+    pub excluded: BTreeSet<FullPackageName>,
 }
 
 /// Describes when a package should be updated.
@@ -107,23 +119,30 @@ fn transitive_depends(
                     PackageNamespace::Debian => {
                         return Ok(());
                     }
-                    PackageNamespace::Root => {
-                        self.specs.get(&p.1).ok_or_else(|| match needed_by {
-                            Some(other) => {
-                                anyhow!(
+                    PackageNamespace::Root => self.specs.get(&p.1).ok_or_else(|| {
+                        Error::with_kind(
+                            ErrorKind::PackageNotFound,
+                            match needed_by {
+                                Some(other) => format!(
                                     "could not find package definition for {p}, needed by {other}"
-                                )
-                            }
-                            None => anyhow!("could not find package definition for {p}"),
-                        })?
-                    }
+                                ),
+                                None => format!("could not find package definition for {p}"),
+                            },
+                        )
+                    })?,
                     PackageNamespace::Managed(manager) => {
-                        let spec = self.specs.get(manager).ok_or_else(|| match needed_by {
-                        Some(other) => {
-                            anyhow!("could not find package definition for package manager {}, needed by {other}", p.0)
-                        }
-                        None => anyhow!("could not find package definition for {p}"),
-                    })?;
+                        let spec = self.specs.get(manager).ok_or_else(|| {
+                            Error::with_kind(
+                                ErrorKind::PackageNotFound,
+                                match needed_by {
+                                    Some(other) => format!(
+                                        "could not find package definition for package manager {}, needed by {other}",
+                                        p.0
+                                    ),
+                                    None => format!("could not find package definition for {p}"),
+                                },
+                            )
+                        })?;
                         if !spec.manifest.package_manager {
                             return Err(anyhow!("package {} is not a package manager", p.0));
                         }
@@ -168,6 +187,7 @@ impl Cubicle {
             RunnerKind::Bubblewrap => true,
             RunnerKind::Docker => self.shared.config.docker.strict_debian_packages,
             RunnerKind::User => true,
+            RunnerKind::Auto => true,
         };
         if strict {
             strict_debian_packages(packages, specs)
@@ -196,7 +216,7 @@ impl Cubicle {
             }
             let dir = dir.join(&name.0);
 
-            let mut manifest = match Manifest::read(&dir, "package.toml").with_context(|| {
+            let mut manifest = match Manifest::read(dir.as_host_raw()).with_context(|| {
                 format!(
                     "could not read manifest for package {name}: {:?}",
                     dir.join("package.toml").as_host_raw()
@@ -213,12 +233,36 @@ impl Cubicle {
             };
 
             if let Some(targets) = &manifest.targets {
-                if !self.runner.supports_any(targets)? {
+                let mut supported = Vec::new();
+                for target in targets {
+                    if self.runner.supports_any(std::slice::from_ref(target))? {
+                        supported.push(target);
+                    }
+                }
+                if supported.is_empty() {
                     warn(anyhow!(
                         "package {name} cannot be built on the current platform"
                     ));
                     continue;
                 }
+                let capabilities = self.runner.capabilities();
+                let missing = supported
+                    .iter()
+                    .map(|target| target.missing(&capabilities))
+                    .min_by_key(|missing| missing.len())
+                    .unwrap();
+                if !missing.is_empty() {
+                    warn(anyhow!(
+                        "package {name} needs {}, which the {:?} runner does not provide",
+                        missing
+                            .iter()
+                            .map(|capability| capability.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        self.shared.config.runner,
+                    ));
+                    continue;
+                }
             }
 
             manifest
@@ -277,6 +321,12 @@ impl Cubicle {
 
     /// Returns information about available package sources.
     pub fn scan_packages(&self) -> Result<PackageSpecs> {
+        self.shared
+            .timings
+            .time(Phase::ScanPackages, || self.scan_packages_())
+    }
+
+    fn scan_packages_(&self) -> Result<PackageSpecs> {
         let mut specs = PackageSpecs::new();
 
         // Don't use try_iterdir_dirs to allow symlinks at this level.
@@ -349,11 +399,19 @@ impl Cubicle {
                     let spec = match &full_name.0 {
                         PackageNamespace::Debian => unreachable!(),
                         PackageNamespace::Root => specs.get(&full_name.1).ok_or_else(|| {
-                            anyhow!("could not find definition for package {}", full_name.1)
+                            Error::with_kind(
+                                ErrorKind::PackageNotFound,
+                                format!("could not find definition for package {}", full_name.1),
+                            )
                         })?,
                         PackageNamespace::Managed(manager) => {
                             let spec = specs.get(manager).ok_or_else(|| {
-                                anyhow!("could not find definition for package manager {manager}")
+                                Error::with_kind(
+                                    ErrorKind::PackageNotFound,
+                                    format!(
+                                        "could not find definition for package manager {manager}"
+                                    ),
+                                )
                             })?;
                             if !spec.manifest.package_manager {
                                 return Err(anyhow!("package {manager} is not a package manager"));
@@ -367,6 +425,8 @@ impl Cubicle {
 
         let now = SystemTime::now();
         let mut done: BTreeSet<FullPackageName> = BTreeSet::new();
+        // In a dry run, these packages would have been built.
+        let mut planned: BTreeSet<FullPackageName> = BTreeSet::new();
         loop {
             let start_todos = todo.len();
             if start_todos == 0 {
@@ -388,28 +448,62 @@ impl Cubicle {
                     });
 
                 if deps_ready {
-                    let needs_build = {
+                    cancel::check()?;
+                    // Another process may be building this package. Once it's
+                    // done, the package is likely no longer stale.
+                    let _lock = self.shared.locks.lock_package(&full_name)?;
+                    let mut reason = {
                         if spec.update.is_none() {
-                            false
+                            None
                         } else {
                             let when = if packages.contains(&full_name) {
                                 conditions.named
+                            } else if conditions.excluded.contains(&full_name) {
+                                ShouldPackageUpdate::IfRequired
                             } else {
                                 conditions.dependencies
                             };
                             match when {
-                                ShouldPackageUpdate::Always => true,
-                                ShouldPackageUpdate::IfStale => {
-                                    self.package_is_stale(&full_name, spec, now)?
-                                }
-                                ShouldPackageUpdate::IfRequired => {
-                                    self.last_built(&full_name).is_none()
-                                }
+                                ShouldPackageUpdate::Always => Some(BuildReason::Requested),
+                                ShouldPackageUpdate::IfStale => self
+                                    .shared
+                                    .timings
+                                    .time(Phase::CheckStaleness(full_name.clone()), || {
+                                        self.stale_reason(&full_name, spec, now, &planned)
+                                    })?,
+                                ShouldPackageUpdate::IfRequired => self
+                                    .last_built(&full_name)
+                                    .is_none()
+                                    .then_some(BuildReason::NeverBuilt),
                             }
                         }
                     };
-                    if needs_build {
-                        self.update_package(&full_name, spec, specs)?;
+                    if reason.is_none()
+                        && spec.update.is_some()
+                        && self.last_built(&full_name).is_some()
+                        && !self.package_tar_is_intact(&full_name)?
+                    {
+                        if !self.shared.dry_run {
+                            self.shared
+                                .event(Event::RebuildingCorruptPackage(full_name.clone()));
+                        }
+                        reason = Some(BuildReason::Corrupt);
+                    }
+                    if let Some(reason) = reason {
+                        if self.shared.dry_run {
+                            self.shared
+                                .event(Event::Planned(PlannedAction::BuildPackage {
+                                    name: full_name.clone(),
+                                    reason,
+                                }));
+                            planned.insert(full_name.clone());
+                        } else {
+                            self.shared
+                                .timings
+                                .time(Phase::BuildPackage(full_name.clone()), || {
+                                    self.update_package(&full_name, spec, specs)
+                                })?;
+                        }
                     }
                     done.insert(full_name);
                 } else {
@@ -432,7 +526,7 @@ impl Cubicle {
     }
 
     fn package_tar(&self, name: &FullPackageName) -> HostPath {
-        self.shared.package_cache.join(
+        self.shared.paths.package_cache.join(
             FilenameEncoder::new()
                 .push(&name.unquoted())
                 .push(".tar")
@@ -441,7 +535,7 @@ impl Cubicle {
     }
 
     fn package_names_from_tars(&self) -> Result<Vec<FullPackageName>> {
-        Ok(try_iterdir(&self.shared.package_cache)?
+        Ok(try_iterdir(&self.shared.paths.package_cache)?
             .iter()
             .filter_map(|filename| {
                 FilenameEncoder::decode(filename)
@@ -453,8 +547,44 @@ impl Cubicle {
             .collect())
     }
 
+    /// Returns the path of the file recording the SHA-256 digest of the
+    /// package's cached tarball.
+    fn package_checksum(&self, name: &FullPackageName) -> HostPath {
+        self.shared.paths.package_cache.join(
+            FilenameEncoder::new()
+                .push(&name.unquoted())
+                .push(".tar.sha256")
+                .encode(),
+        )
+    }
+
+    /// Returns true if the package's cached tarball matches its recorded
+    /// checksum. This detects tarballs that were partially written or
+    /// corrupted, which shouldn't be used to seed home directories.
+    fn package_tar_is_intact(&self, name: &FullPackageName) -> Result<bool> {
+        let checksum_path = self.package_checksum(name);
+        let expected = match std::fs::read_to_string(checksum_path.as_host_raw()) {
+            Ok(expected) => expected,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {checksum_path}")),
+        };
+        let actual = sha256_file(&self.package_tar(name))?;
+        Ok(expected.trim() == actual)
+    }
+
+    /// Returns a short identifier for the package's current build, taken from
+    /// its recorded checksum, or `None` if it hasn't been built.
+    pub(crate) fn package_version(&self, name: &FullPackageName) -> Result<Option<String>> {
+        let checksum_path = self.package_checksum(name);
+        match std::fs::read_to_string(checksum_path.as_host_raw()) {
+            Ok(checksum) => Ok(Some(checksum.trim().chars().take(12).collect())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to read {checksum_path}")),
+        }
+    }
+
     fn testing_tar(&self, name: &FullPackageName) -> HostPath {
-        self.shared.package_cache.join(
+        self.shared.paths.package_cache.join(
             FilenameEncoder::new()
                 .push(&name.unquoted())
                 .push(".testing.tar")
@@ -463,7 +593,7 @@ impl Cubicle {
     }
 
     fn failed_marker(&self, name: &FullPackageName) -> HostPath {
-        self.shared.package_cache.join(
+        self.shared.paths.package_cache.join(
             FilenameEncoder::new()
                 .push(&name.unquoted())
                 .push(".failed")
@@ -477,26 +607,31 @@ impl Cubicle {
         metadata.modified().ok()
     }
 
-    fn package_is_stale(
+    /// Returns why the package is stale, or `None` if it's fresh.
+    ///
+    /// `planned` lists the packages that a dry run would have built, which
+    /// make their dependents stale too.
+    fn stale_reason(
         &self,
         package_name: &FullPackageName,
         spec: &PackageSpec,
         now: SystemTime,
-    ) -> Result<bool> {
+        planned: &BTreeSet<FullPackageName>,
+    ) -> Result<Option<BuildReason>> {
         let built = match self.last_built(package_name) {
             Some(built) => built,
-            None => return Ok(true),
+            None => return Ok(Some(BuildReason::NeverBuilt)),
         };
         if let Some(threshold) = self.shared.config.auto_update {
             match now.duration_since(built) {
-                Ok(d) if d > threshold => return Ok(true),
-                Err(_) => return Ok(true),
+                Ok(d) if d > threshold => return Ok(Some(BuildReason::Expired)),
+                Err(_) => return Ok(Some(BuildReason::Expired)),
                 _ => {}
             }
         }
         let DirSummary { last_modified, .. } = summarize_dir(&spec.dir)?;
         if last_modified > built {
-            return Ok(true);
+            return Ok(Some(BuildReason::SourcesChanged));
         }
         for (ns, table) in spec
             .manifest
@@ -506,15 +641,17 @@ impl Cubicle {
         {
             for name in table.keys() {
                 let full_name = FullPackageName(ns.clone(), name.clone());
-                if matches!(self.last_built(&full_name), Some(b) if b > built) {
-                    return Ok(true);
+                if planned.contains(&full_name)
+                    || matches!(self.last_built(&full_name), Some(b) if b > built)
+                {
+                    return Ok(Some(BuildReason::DependencyChanged(full_name)));
                 }
             }
         }
-        Ok(false)
+        Ok(None)
     }
 
-    fn package_build_failed(&self, package_name: &FullPackageName) -> Result<bool> {
+    pub(crate) fn package_build_failed(&self, package_name: &FullPackageName) -> Result<bool> {
         let failed_marker = self.failed_marker(package_name);
         try_exists(&failed_marker)
             .with_context(|| format!("error while checking if {failed_marker:?} exists"))
@@ -528,10 +665,35 @@ impl Cubicle {
     ) -> Result<()> {
         let failed_marker = self.failed_marker(package_name);
 
-        match self
+        let start = Instant::now();
+        let result = self
             .update_package_(package_name, spec, specs)
-            .with_context(|| format!("failed to update package: {package_name}"))
-        {
+            .enough_context()
+            .map_err(|e| {
+                e.kind_context(
+                    ErrorKind::PackageBuildFailed,
+                    format!("failed to update package: {package_name}"),
+                )
+            });
+        // Interrupted builds aren't failures of the package.
+        if !cancel::is_cancelled() {
+            if let Err(e) = self.record_build(package_name, start.elapsed(), result.is_err()) {
+                warn(e);
+            }
+            let version = match result {
+                Ok(()) => self.package_version(package_name).unwrap_or_else(|e| {
+                    warn(e);
+                    None
+                }),
+                Err(_) => None,
+            };
+            self.shared.history.record(Operation::UpdatePackage {
+                package: PackageVersion::new(package_name.clone(), version),
+                failed: result.is_err(),
+            });
+        }
+
+        match result {
             Ok(_) => {
                 if let Err(e) = std::fs::remove_file(failed_marker.as_host_raw()) {
                     if e.kind() != io::ErrorKind::NotFound {
@@ -544,7 +706,17 @@ impl Cubicle {
                 Ok(())
             }
             Err(update_error) => {
-                let package_cache = &self.shared.package_cache;
+                // Don't leave behind a partially written or untested tarball.
+                let testing_tar = self.testing_tar(package_name);
+                let removed = match std::fs::remove_file(testing_tar.as_host_raw()) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    result => result,
+                };
+                if let Err(e2) = removed.with_context(|| format!("failed to remove {testing_tar}"))
+                {
+                    warn(e2);
+                }
+                let package_cache = &self.shared.paths.package_cache;
                 std::fs::create_dir_all(package_cache.as_host_raw())
                     .with_context(|| format!("failed to create directory {package_cache:?}"))?;
                 if let Err(e2) = std::fs::File::create(failed_marker.as_host_raw())
@@ -555,6 +727,7 @@ impl Cubicle {
                 let cached = self.package_tar(package_name);
                 let use_stale = match try_exists(&cached)
                     .with_context(|| format!("error while checking if {cached:?} exists"))
+                    .and_then(|exists| Ok(exists && self.package_tar_is_intact(package_name)?))
                 {
                     Ok(exists) => exists,
                     Err(e2) => {
@@ -578,12 +751,13 @@ impl Cubicle {
         spec: &PackageSpec,
         specs: &PackageSpecs,
     ) -> LowLevelResult<()> {
-        println!("Updating {package_name} package");
+        self.shared
+            .event(Event::UpdatingPackage(package_name.clone()));
         let env_name = EnvironmentName::for_builder_package(package_name);
         self.build_package(package_name, &env_name, spec, specs)
             .with_context(|| format!("error building package {package_name}"))?;
 
-        let package_cache = &self.shared.package_cache;
+        let package_cache = &self.shared.paths.package_cache;
         std::fs::create_dir_all(package_cache.as_host_raw())
             .with_context(|| format!("failed to create directory {package_cache:?}"))?;
         let package_cache_dir = cap_std::fs::Dir::open_ambient_dir(
@@ -619,6 +793,13 @@ impl Cubicle {
                 .with_context(|| format!("error testing package {package_name}"))?;
         }
 
+        // The checksum is written before the tarball is moved into place, so
+        // an interruption in between leaves a mismatch that causes a rebuild.
+        let checksum_path = self.package_checksum(package_name);
+        let checksum = sha256_file(&testing_tar_abs)?;
+        std::fs::write(checksum_path.as_host_raw(), format!("{checksum}\n"))
+            .with_context(|| format!("failed to write {checksum_path}"))?;
+
         let package_tar_abs = self.package_tar(package_name);
         let package_tar_name = package_tar_abs
             .as_host_raw()
@@ -698,12 +879,12 @@ impl Cubicle {
             let env_vars = if package_name.0 == PackageNamespace::Root {
                 vec![]
             } else {
-                vec![("PACKAGE", package_name.1.as_str().to_owned())]
+                vec![("PACKAGE".to_owned(), package_name.1.as_str().to_owned())]
             };
             self.runner.run(
                 env_name,
                 &RunnerCommand::Exec {
-                    command: &[update.clone()],
+                    command: std::slice::from_ref(update),
                     env_vars: env_vars.as_slice(),
                 },
             )?;
@@ -719,7 +900,8 @@ impl Cubicle {
         spec: &PackageSpec,
         specs: &PackageSpecs,
     ) -> Result<()> {
-        println!("Testing {package_name} package");
+        self.shared
+            .event(Event::TestingPackage(package_name.clone()));
         let test_name = EnvironmentName::from_string(format!(
             "test-{}",
             EnvironmentName::for_builder_package(package_name).as_str()
@@ -777,7 +959,7 @@ impl Cubicle {
         let env_vars = if package_name.0 == PackageNamespace::Root {
             vec![]
         } else {
-            vec![("PACKAGE", package_name.1.as_str().to_owned())]
+            vec![("PACKAGE".to_owned(), package_name.1.as_str().to_owned())]
         };
         self.runner.run(
             &test_name,
@@ -790,6 +972,131 @@ impl Cubicle {
         self.runner.purge(&test_name)
     }
 
+    /// Corresponds to `cub status`.
+    ///
+    /// Counts the packages that will be rebuilt the next time they're used.
+    /// The counts may be up to a minute old, unless a package was built since
+    /// then.
+    pub fn get_package_status(&self) -> Result<PackageStatus> {
+        let now = SystemTime::now();
+        if let Some(status) = self.cached_package_status(now) {
+            return Ok(status);
+        }
+        let status = self.compute_package_status(now)?;
+        if let Err(e) = self.cache_package_status(status, now) {
+            warn(e);
+        }
+        Ok(status)
+    }
+
+    fn compute_package_status(&self, now: SystemTime) -> Result<PackageStatus> {
+        let mut status = PackageStatus::default();
+        for (name, spec) in self.scan_packages()? {
+            let full_name = FullPackageName(PackageNamespace::Root, name);
+            if spec.update.is_none() || self.last_built(&full_name).is_none() {
+                continue;
+            }
+            match self.stale_reason(&full_name, &spec, now, &BTreeSet::new())? {
+                Some(BuildReason::Expired) => status.pending += 1,
+                Some(_) => status.stale += 1,
+                None => {}
+            }
+        }
+        Ok(status)
+    }
+
+    /// Corresponds to `cub package autoupdate`.
+    ///
+    /// Rebuilds the stale packages among those that have been built before,
+    /// so that creating or resetting environments rarely waits on builds. A
+    /// package that fails to build doesn't stop the others from updating,
+    /// but it makes this return an error at the end.
+    pub fn auto_update_packages(&self) -> Result<()> {
+        let specs = self.scan_packages()?;
+        let mut failed = Vec::new();
+        for (name, spec) in &specs {
+            let full_name = FullPackageName(PackageNamespace::Root, name.clone());
+            if spec.update.is_none() || self.last_built(&full_name).is_none() {
+                continue;
+            }
+            let result = self.update_packages(
+                &BTreeSet::from([full_name.clone()]),
+                &specs,
+                &UpdatePackagesConditions {
+                    dependencies: ShouldPackageUpdate::IfStale,
+                    named: ShouldPackageUpdate::IfStale,
+                    excluded: BTreeSet::new(),
+                },
+            );
+            if let Err(e) = result {
+                if e.kind() == Some(ErrorKind::Interrupted) {
+                    return Err(e);
+                }
+                warn(e.context(format!("failed to update package {full_name}")));
+                failed.push(full_name.to_string());
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "failed to update {} package(s): {}",
+                failed.len(),
+                failed.join(", ")
+            ))
+        }
+    }
+
+    /// Returns the packages that the environment was created or last reset
+    /// with, from the `packages.txt` file in its work directory.
+    ///
+    /// This is used for `cub new --from`.
+    pub fn get_environment_packages(
+        &self,
+        name: &EnvironmentName,
+    ) -> Result<BTreeSet<FullPackageName>> {
+        self.check_fully_exists(name)?;
+        self.read_package_list_from_env(name)
+            .with_context(|| format!("failed to parse `packages.txt` from {name}"))
+    }
+
+    /// Corresponds to `cub show --debian-packages`.
+    ///
+    /// Compares the Debian packages that the environment's packages depend
+    /// on with the ones installed for it. These differ when the Docker runner
+    /// has `strict_debian_packages = false`, which installs the Debian
+    /// dependencies of every package definition, or when package definitions
+    /// changed since the environment was created.
+    pub fn get_debian_packages(&self, name: &EnvironmentName) -> Result<DebianPackages> {
+        self.check_fully_exists(name)?;
+        let packages = self
+            .read_package_list_from_env(name)
+            .with_context(|| format!("failed to parse `packages.txt` from {name}"))?;
+        let specs = self.scan_packages()?;
+        // The runner's own packages are left out of both sets, since they're
+        // installed no matter what the environment's packages need.
+        let base = self.runner.base_debian_packages();
+        let requested: BTreeSet<String> = strict_debian_packages(&packages, &specs)?
+            .into_iter()
+            .map(|name| name.as_str().to_owned())
+            .filter(|name| !base.contains(name))
+            .collect();
+        let installed = self.runner.debian_packages(name)?;
+        let (extra, missing) = match &installed {
+            Some(installed) => (
+                installed.difference(&requested).cloned().collect(),
+                requested.difference(installed).cloned().collect(),
+            ),
+            None => (BTreeSet::new(), BTreeSet::new()),
+        };
+        Ok(DebianPackages {
+            requested,
+            installed,
+            extra,
+            missing,
+        })
+    }
+
     /// Returns details of available packages.
     pub fn get_packages(&self) -> Result<BTreeMap<FullPackageName, PackageDetails>> {
         let metadata = |name: &FullPackageName| -> (Option<SystemTime>, Option<u64>) {
@@ -874,77 +1181,6 @@ impl Cubicle {
         root_packages.chain(non_root_packages).collect()
     }
 
-    /// Corresponds to `cub package list`.
-    pub fn list_packages(&self, format: ListPackagesFormat) -> Result<()> {
-        use ListPackagesFormat::*;
-        match format {
-            Names => {
-                for name in self.get_package_names()? {
-                    println!("{}", name.unquoted());
-                }
-            }
-
-            Json => {
-                let packages = self.get_packages()?;
-                println!(
-                    "{}",
-                    serde_json::to_string_pretty(&packages)
-                        .context("failed to serialize JSON while listing packages")?
-                );
-            }
-
-            Default => {
-                let packages = self.get_packages()?;
-                let names: Vec<String> = packages
-                    .iter()
-                    .map(|(full_name, details)| {
-                        if details.package_manager {
-                            format!("{}.*", full_name.unquoted())
-                        } else {
-                            full_name.unquoted()
-                        }
-                    })
-                    .collect();
-                let nw = names.iter().map(|s| s.len()).max().unwrap_or(10);
-                let ow = packages.values().map(|p| p.origin.len()).max().unwrap_or(8);
-                let now = SystemTime::now();
-                println!(
-                    "{:<nw$}  {:<ow$}  {:>10}  {:>13}  {:>13}  {:>8}",
-                    "name", "origin", "size", "built", "edited", "status"
-                );
-                println!(
-                    "{0:-<nw$}  {0:-<ow$}  {0:-<10}  {0:-<13}  {0:-<13}  {0:-<8}",
-                    ""
-                );
-                for (name, package) in names.iter().zip(packages.values()) {
-                    println!(
-                        "{:<nw$}  {:<ow$}  {:>10}  {:>13}  {:>13}  {:>8}",
-                        name,
-                        package.origin,
-                        match package.size {
-                            Some(size) => Bytes(size).to_string(),
-                            None => String::from("N/A"),
-                        },
-                        match package.built {
-                            Some(built) => rel_time(now.duration_since(built).ok()),
-                            None => String::from("N/A"),
-                        },
-                        match package.edited {
-                            Some(edited) => rel_time(now.duration_since(edited).ok()),
-                            None => String::from("N/A"),
-                        },
-                        if package.last_build_failed {
-                            "failed"
-                        } else {
-                            "ok"
-                        },
-                    );
-                }
-            }
-        }
-        Ok(())
-    }
-
     pub(super) fn read_package_list_from_env(
         &self,
         name: &EnvironmentName,
@@ -979,6 +1215,43 @@ impl Cubicle {
         }
         Ok(seeds)
     }
+
+    /// Returns the packages that would seed a home directory in a dry run.
+    ///
+    /// Unlike [`Cubicle::packages_to_seeds`], this includes packages that
+    /// haven't been built yet but would have been.
+    pub(super) fn seed_packages(
+        &self,
+        packages: &BTreeSet<FullPackageName>,
+        specs: &PackageSpecs,
+    ) -> Result<Vec<FullPackageName>> {
+        let mut seeds = Vec::with_capacity(packages.len());
+        for name in transitive_depends(packages, specs, BuildDepends(false))? {
+            let spec = match &name.0 {
+                PackageNamespace::Debian => None,
+                PackageNamespace::Root => specs.get(&name.1),
+                PackageNamespace::Managed(manager) => specs.get(manager),
+            };
+            let buildable = spec.is_some_and(|spec| spec.update.is_some());
+            if buildable || try_exists(&self.package_tar(&name)).todo_context()? {
+                seeds.push(name);
+            }
+        }
+        Ok(seeds)
+    }
+}
+
+/// Returns the hex-encoded SHA-256 digest of the file's contents.
+fn sha256_file(path: &HostPath) -> Result<String> {
+    let mut file = std::fs::File::open(path.as_host_raw())
+        .with_context(|| format!("failed to open {path}"))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("failed to read {path}"))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
 }
 
 /// The name of a potential Cubicle package.
@@ -1115,6 +1388,15 @@ impl Serialize for FullPackageName {
     }
 }
 
+impl<'de> Deserialize<'de> for FullPackageName {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Self::from_str(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
 impl FullPackageName {
     /// Returns a string representation of the name that a human can probably
     /// decipher when standing alone.
@@ -1155,18 +1437,6 @@ impl FromStr for FullPackageName {
     }
 }
 
-/// Allowed formats for [`Cubicle::list_packages`].
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
-pub enum ListPackagesFormat {
-    /// Human-formatted table.
-    #[default]
-    Default,
-    /// Detailed JSON output for machine consumption.
-    Json,
-    /// Newline-delimited list of package names only.
-    Names,
-}
-
 pub fn write_package_list_tar(
     packages: &BTreeSet<FullPackageName>,
 ) -> Result<tempfile::NamedTempFile> {
@@ -1228,20 +1498,65 @@ fn all_debian_packages(specs: &PackageSpecs) -> BTreeSet<PackageName> {
     debian_packages
 }
 
+/// Counts of packages that are out of date, as returned by
+/// [`Cubicle::get_package_status`].
+///
+/// These only count packages that have been built before, so packages that
+/// are never used don't show up.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct PackageStatus {
+    /// The number of packages whose sources or dependencies changed since
+    /// they were last built.
+    pub stale: usize,
+    /// The number of packages due for their periodic update, as set by the
+    /// `auto_update` configuration option.
+    pub pending: usize,
+}
+
+/// The Debian packages of an environment, as returned by
+/// [`Cubicle::get_debian_packages`].
+#[derive(Debug, Deserialize, Serialize)]
+#[non_exhaustive]
+pub struct DebianPackages {
+    /// The Debian packages that the environment's packages depend on,
+    /// directly or indirectly, not counting the ones the runner always
+    /// installs.
+    pub requested: BTreeSet<String>,
+    /// The Debian packages installed for the environment, not counting the
+    /// ones the runner always installs.
+    ///
+    /// This is `None` for runners that use the host's Debian packages, and
+    /// for Docker images built by older versions of Cubicle.
+    pub installed: Option<BTreeSet<String>>,
+    /// Packages that are installed but not requested.
+    pub extra: BTreeSet<String>,
+    /// Packages that are requested but not installed.
+    pub missing: BTreeSet<String>,
+}
+
 /// Description of a package as returned by [`Cubicle::get_packages`].
-#[derive(Debug, Serialize)]
+///
+/// This is part of the [`json`](crate::json) output format.
+#[derive(Debug, Deserialize, Serialize)]
 #[non_exhaustive]
 pub struct PackageDetails {
     /// Map from package namespaces to package names for packages this package
     /// needs at build-time.
     pub build_depends: BTreeMap<String, Vec<String>>,
-    #[serde(serialize_with = "time_serialize_opt")]
+    #[serde(
+        serialize_with = "json::serialize_time_opt",
+        deserialize_with = "json::deserialize_time_opt"
+    )]
     /// The last time the package was successfully built, if available.
     pub built: Option<SystemTime>,
     /// Map from package namespaces to package names for packages this package
     /// needs at build-time and run-time.
     pub depends: BTreeMap<String, Vec<String>>,
-    #[serde(serialize_with = "time_serialize_opt")]
+    #[serde(
+        serialize_with = "json::serialize_time_opt",
+        deserialize_with = "json::deserialize_time_opt"
+    )]
     /// The last time the package sources were changed (or `UNIX_EPOCH` if
     /// unavailable).
     pub edited: Option<SystemTime>,
@@ -0,0 +1,67 @@
+//! Records how long package builds take and how often they fail, for
+//! `cub metrics`.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::Duration;
+
+use super::FullPackageName;
+use crate::encoding::FilenameEncoder;
+use crate::fs_util::write_json_atomically;
+use crate::somehow::{Context, Result};
+use crate::{Cubicle, HostPath};
+
+/// Counts of a package's build attempts, kept in the package cache.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct BuildStats {
+    /// The number of completed build attempts, including failed ones.
+    pub builds: u64,
+    /// The number of build attempts that failed.
+    pub failures: u64,
+    /// How long the most recent build attempt took.
+    pub last_duration: Option<Duration>,
+}
+
+impl Cubicle {
+    fn build_stats_path(&self, name: &FullPackageName) -> HostPath {
+        self.shared.paths.package_cache.join(
+            FilenameEncoder::new()
+                .push(&name.unquoted())
+                .push(".stats.json")
+                .encode(),
+        )
+    }
+
+    /// Returns the package's build statistics, which are all zero if it's
+    /// never been built.
+    pub(crate) fn package_build_stats(&self, name: &FullPackageName) -> Result<BuildStats> {
+        let path = self.build_stats_path(name);
+        match std::fs::read(path.as_host_raw()) {
+            Ok(buf) => {
+                serde_json::from_slice(&buf).with_context(|| format!("failed to parse {path}"))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BuildStats::default()),
+            Err(e) => Err(e).with_context(|| format!("failed to read {path}")),
+        }
+    }
+
+    /// Adds a completed build attempt to the package's statistics.
+    pub(super) fn record_build(
+        &self,
+        name: &FullPackageName,
+        duration: Duration,
+        failed: bool,
+    ) -> Result<()> {
+        let mut stats = self.package_build_stats(name)?;
+        stats.builds += 1;
+        if failed {
+            stats.failures += 1;
+        }
+        stats.last_duration = Some(duration);
+        write_json_atomically(
+            &self.shared.paths.package_cache,
+            &self.build_stats_path(name),
+            &stats,
+        )
+    }
+}
@@ -0,0 +1,442 @@
+//! Imports Dockerfiles as packages, for `cub package import-dockerfile`.
+//!
+//! The Dockerfile is built on the host with Docker, regardless of the
+//! configured runner. Then, the chosen directories are copied out of the
+//! image into an archive that places them under `~/opt/<package>`, with
+//! symlinks in `~/bin` for their executables. The generated package's
+//! `build.sh` just provides that archive.
+
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use tempfile::NamedTempFile;
+
+use super::{Cubicle, HostPath, PackageName};
+use crate::config;
+use crate::docker::docker_command;
+use crate::fs_util::{try_exists, try_iterdir};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+
+/// Identifies a `build.sh` generated by `cub package import-dockerfile`.
+const MARKER: &str = "# Generated by `cub package import-dockerfile`";
+
+/// The name of the archive within the package directory.
+const ARCHIVE: &str = "image.tar";
+
+impl Cubicle {
+    /// Corresponds to `cub package import-dockerfile`.
+    ///
+    /// Builds the Dockerfile, using its directory as the build context, and
+    /// creates (or replaces) a local package holding the given directories
+    /// from the resulting image. The package is named after the Dockerfile's
+    /// directory unless `name` is given.
+    pub fn import_dockerfile(
+        &self,
+        dockerfile: &Path,
+        name: Option<&str>,
+        image_paths: &[PathBuf],
+    ) -> Result<PackageName> {
+        self.shared.check_not_dry_run("import a Dockerfile")?;
+        let dockerfile = dockerfile
+            .canonicalize()
+            .with_context(|| format!("failed to find Dockerfile {dockerfile:?}"))?;
+        let context_dir = dockerfile.parent().unwrap();
+        let name = match name {
+            Some(name) => PackageName::strict_from_str(name)?,
+            None => {
+                let dir_name = context_dir
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or_else(|| {
+                        anyhow!("cannot name package after directory {context_dir:?}: use --name")
+                    })?;
+                PackageName::strict_from_str(dir_name)
+                    .context("cannot name package after the Dockerfile's directory: use --name")?
+            }
+        };
+        for path in image_paths {
+            if !path.is_absolute() || path.parent().is_none() {
+                return Err(anyhow!(
+                    "image paths must be absolute directories other than '/', found {path:?}"
+                ));
+            }
+        }
+
+        let dir = self.shared.user_package_dir.join(&name.0);
+        if !try_iterdir(&dir)?.is_empty() && !is_imported(&dir)? {
+            return Err(anyhow!(
+                "package directory {dir} already exists and wasn't generated by \
+                `cub package import-dockerfile`"
+            ));
+        }
+        std::fs::create_dir_all(dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {dir}"))?;
+
+        let docker = &self.shared.config.docker;
+        let image = build_image(docker, &dockerfile, context_dir)?;
+        let result = write_archive(docker, &dir, &name, &image, image_paths);
+        remove_image(docker, &image);
+        result.with_context(|| {
+            format!("failed to copy files out of image built from {dockerfile:?}")
+        })?;
+
+        let manifest = dir.join("package.toml");
+        if !try_exists(&manifest).todo_context()? {
+            std::fs::write(
+                manifest.as_host_raw(),
+                "# Imported from a Dockerfile. List any packages that the imported\n\
+                # programs need at runtime under `depends`.\n",
+            )
+            .with_context(|| format!("failed to write {manifest}"))?;
+        }
+        write_build_script(&dir, &name, &dockerfile, image_paths)?;
+        Ok(name)
+    }
+}
+
+fn is_imported(dir: &HostPath) -> Result<bool> {
+    let path = dir.join("build.sh");
+    match std::fs::read_to_string(path.as_host_raw()) {
+        Ok(script) => Ok(script.contains(MARKER)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("failed to read {path}")),
+    }
+}
+
+/// Builds the Dockerfile and returns the ID of the image.
+fn build_image(docker: &config::Docker, dockerfile: &Path, context_dir: &Path) -> Result<String> {
+    let iidfile = NamedTempFile::new().context("failed to create temporary file")?;
+    let status = docker_command(docker)
+        .arg("build")
+        .arg("--file")
+        .arg(dockerfile)
+        .arg("--iidfile")
+        .arg(iidfile.path())
+        .arg("--")
+        .arg(context_dir)
+        .status()
+        .context("failed to run docker")?;
+    if !status.success() {
+        return Err(anyhow!("`docker build` exited with {status}"));
+    }
+    let image = std::fs::read_to_string(iidfile.path())
+        .context("failed to read image ID from `docker build`")?;
+    Ok(image.trim().to_owned())
+}
+
+/// Removes the image, leaving its layers in Docker's build cache.
+fn remove_image(docker: &config::Docker, image: &str) {
+    let result = docker_command(docker)
+        .args(["image", "rm", "--", image])
+        .stdout(Stdio::null())
+        .status();
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn(anyhow!("`docker image rm {image}` exited with {status}")),
+        Err(e) => warn(anyhow!("failed to run docker: {e}")),
+    }
+}
+
+/// Copies the directories out of the image into the package's archive.
+fn write_archive(
+    docker: &config::Docker,
+    dir: &HostPath,
+    name: &PackageName,
+    image: &str,
+    image_paths: &[PathBuf],
+) -> Result<()> {
+    // The container is never started, so the command doesn't need to exist.
+    let output = docker_command(docker)
+        .args(["container", "create", "--", image, "true"])
+        .output()
+        .context("failed to run docker")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`docker container create` exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let container = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+
+    let copy = || -> Result<()> {
+        let file = NamedTempFile::new_in(dir.as_host_raw())
+            .with_context(|| format!("failed to create temporary file in {dir}"))?;
+        let mut builder = tar::Builder::new(file);
+        let opt_dir = Path::new("opt").join(&name.0);
+        let mut executables = Vec::new();
+        for image_path in image_paths {
+            let mut child = docker_command(docker)
+                .arg("container")
+                .arg("cp")
+                .arg("--")
+                .arg(format!("{container}:{}", image_path.display()))
+                .arg("-")
+                .stdout(Stdio::piped())
+                .scoped_spawn()?;
+            let found = add_image_dir(
+                child.stdout().take().unwrap(),
+                image_path,
+                image_paths,
+                &opt_dir,
+                &mut builder,
+            )
+            .with_context(|| format!("failed to archive {image_path:?}"));
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(anyhow!(
+                    "`docker container cp` of {image_path:?} exited with {status}"
+                ));
+            }
+            executables.extend(found?);
+        }
+        add_bin_symlinks(&executables, &mut builder)?;
+        let file = builder
+            .into_inner()
+            .context("failed to finish writing archive")?;
+        let path = dir.join(ARCHIVE);
+        file.persist(path.as_host_raw())
+            .with_context(|| format!("failed to write {path}"))?;
+        Ok(())
+    };
+    let result = copy();
+
+    let status = docker_command(docker)
+        .args(["container", "rm", "--", &container])
+        .stdout(Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn(anyhow!(
+            "`docker container rm {container}` exited with {status}"
+        )),
+        Err(e) => warn(anyhow!("failed to run docker: {e}")),
+    }
+    result
+}
+
+/// Copies the entries of an archive of `image_path`, as written by
+/// `docker container cp`, into `out` beneath `opt_dir`.
+///
+/// Absolute symlinks into any of the `image_paths` are made relative, so
+/// that they keep working in the home directory. Returns the home-relative
+/// paths of the executables directly in the `bin` subdirectory of
+/// `image_path`.
+fn add_image_dir<R: Read, W: io::Write>(
+    input: R,
+    image_path: &Path,
+    image_paths: &[PathBuf],
+    opt_dir: &Path,
+    out: &mut tar::Builder<W>,
+) -> Result<Vec<PathBuf>> {
+    // `docker container cp` names entries starting with the last component
+    // of `image_path`.
+    let base = opt_dir.join(relative(image_path.parent().unwrap()));
+    let bin_dir = opt_dir.join(relative(image_path)).join("bin");
+    let mut executables = Vec::new();
+    let mut archive = tar::Archive::new(input);
+    for entry in archive.entries().context("failed to read archive")? {
+        let entry = entry.context("failed to read archive")?;
+        let raw_path = entry.path().context("failed to read archive")?.into_owned();
+        let path = base.join(&raw_path);
+        let mut header = entry.header().clone();
+        let entry_type = header.entry_type();
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let target = entry
+                .link_name()
+                .context("failed to read archive")?
+                .unwrap_or_default()
+                .into_owned();
+            let target = if entry_type.is_hard_link() {
+                base.join(target)
+            } else if image_paths.iter().any(|p| target.starts_with(p)) {
+                let up = path
+                    .parent()
+                    .unwrap()
+                    .components()
+                    .map(|_| Component::ParentDir);
+                up.collect::<PathBuf>()
+                    .join(opt_dir)
+                    .join(relative(&target))
+            } else {
+                target
+            };
+            out.append_link(&mut header, &path, &target)
+                .with_context(|| format!("failed to add {raw_path:?}"))?;
+        } else if entry_type.is_file() || entry_type.is_dir() {
+            out.append_data(&mut header, &path, entry)
+                .with_context(|| format!("failed to add {raw_path:?}"))?;
+        } else {
+            warn(anyhow!(
+                "skipping {raw_path:?} from image, which has unsupported type {entry_type:?}"
+            ));
+            continue;
+        }
+        let executable = entry_type.is_symlink()
+            || entry_type.is_hard_link()
+            || (entry_type.is_file() && header.mode().unwrap_or(0) & 0o111 != 0);
+        if executable && path.parent() == Some(&bin_dir) {
+            executables.push(path);
+        }
+    }
+    Ok(executables)
+}
+
+/// Adds a symlink in `bin` for each of the executables.
+fn add_bin_symlinks<W: io::Write>(
+    executables: &[PathBuf],
+    out: &mut tar::Builder<W>,
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_mode(0o755);
+    header.set_size(0);
+    out.append_data(&mut header, "bin", io::empty())
+        .context("failed to add bin directory")?;
+    for executable in executables {
+        let file_name = executable.file_name().unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_mode(0o777);
+        header.set_size(0);
+        out.append_link(
+            &mut header,
+            Path::new("bin").join(file_name),
+            Path::new("..").join(executable),
+        )
+        .with_context(|| format!("failed to add symlink to {executable:?}"))?;
+    }
+    Ok(())
+}
+
+fn write_build_script(
+    dir: &HostPath,
+    name: &PackageName,
+    dockerfile: &Path,
+    image_paths: &[PathBuf],
+) -> Result<()> {
+    let mut command = vec![
+        String::from("cub"),
+        String::from("package"),
+        String::from("import-dockerfile"),
+        String::from("--name"),
+        name.0.clone(),
+    ];
+    for path in image_paths {
+        command.push(String::from("--path"));
+        command.push(path.to_string_lossy().into_owned());
+    }
+    command.push(dockerfile.to_string_lossy().into_owned());
+    let command =
+        shlex::try_join(command.iter().map(String::as_str)).context("invalid Dockerfile path")?;
+    let path = dir.join("build.sh");
+    std::fs::write(
+        path.as_host_raw(),
+        format!(
+            "#!/bin/sh\n\
+            {MARKER} from:\n\
+            #   {command}\n\
+            # Run that again on the host to update `{ARCHIVE}`.\n\
+            set -eu\n\
+            cp {ARCHIVE} ~/provides.tar\n"
+        ),
+    )
+    .with_context(|| format!("failed to write {path}"))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path.as_host_raw(), std::fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("failed to make {path} executable"))?;
+    }
+    Ok(())
+}
+
+/// Strips the root from an absolute path.
+fn relative(path: &Path) -> &Path {
+    path.strip_prefix("/").unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+
+    #[test]
+    fn add_image_dir() {
+        use tar::EntryType::{Directory, Link, Regular, Symlink};
+        let mut input = tar::Builder::new(Vec::new());
+        for (name, entry_type, mode, link) in [
+            ("local", Directory, 0o755, ""),
+            ("local/bin", Directory, 0o755, ""),
+            ("local/bin/tool", Regular, 0o755, ""),
+            ("local/bin/tool2", Link, 0o755, "local/bin/tool"),
+            (
+                "local/bin/tool3",
+                Symlink,
+                0o777,
+                "/usr/local/lib/tool3/run",
+            ),
+            ("local/bin/README", Regular, 0o644, ""),
+            ("local/lib/tool3/run", Regular, 0o755, ""),
+            ("local/lib/libc.so", Symlink, 0o777, "/lib/libc.so"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_mode(mode);
+            header.set_size(0);
+            if link.is_empty() {
+                input.append_data(&mut header, name, io::empty()).unwrap();
+            } else {
+                input.append_link(&mut header, name, link).unwrap();
+            }
+        }
+        let input = input.into_inner().unwrap();
+
+        let opt_dir = Path::new("opt/foo");
+        let mut out = tar::Builder::new(Vec::new());
+        let image_path = Path::new("/usr/local");
+        let executables = super::add_image_dir(
+            input.as_slice(),
+            image_path,
+            &[image_path.to_owned()],
+            opt_dir,
+            &mut out,
+        )
+        .unwrap();
+        add_bin_symlinks(&executables, &mut out).unwrap();
+        let out = out.into_inner().unwrap();
+
+        let mut archive = tar::Archive::new(out.as_slice());
+        let listing = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let mut line = format!(
+                    "{:?} {:o} {}",
+                    entry.header().entry_type(),
+                    entry.header().mode().unwrap(),
+                    entry.path().unwrap().display()
+                );
+                if let Some(link) = entry.link_name().unwrap() {
+                    line.push_str(&format!(" -> {}", link.display()));
+                }
+                line
+            })
+            .collect::<Vec<_>>();
+        expect![[r#"
+            Directory 755 opt/foo/usr/local
+            Directory 755 opt/foo/usr/local/bin
+            Regular 755 opt/foo/usr/local/bin/tool
+            Link 755 opt/foo/usr/local/bin/tool2 -> opt/foo/usr/local/bin/tool
+            Symlink 777 opt/foo/usr/local/bin/tool3 -> ../../../../../opt/foo/usr/local/lib/tool3/run
+            Regular 644 opt/foo/usr/local/bin/README
+            Regular 755 opt/foo/usr/local/lib/tool3/run
+            Symlink 777 opt/foo/usr/local/lib/libc.so -> /lib/libc.so
+            Directory 755 bin
+            Symlink 777 bin/tool -> ../opt/foo/usr/local/bin/tool
+            Symlink 777 bin/tool2 -> ../opt/foo/usr/local/bin/tool2
+            Symlink 777 bin/tool3 -> ../opt/foo/usr/local/bin/tool3"#]]
+        .assert_eq(&listing.join("\n"));
+    }
+}
@@ -1,10 +1,14 @@
+//! Parses packages' `package.toml` files.
+
 use serde::Deserialize;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::io;
+use std::path::Path;
 use std::str::FromStr;
 
-use super::{HostPath, PackageName, PackageNamespace};
-use crate::somehow::{Context, LowLevelResult, Result};
+use super::{PackageName, PackageNamespace};
+use crate::somehow::{Context, Error, Result};
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
@@ -27,50 +31,129 @@ enum DependencyOrTable {
     Table(BTreeMap<String, Dependency>),
 }
 
+/// Options for a dependency listed in a [`Manifest`].
+///
+/// There are no options yet, so dependencies are written as `name = {}`.
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
+#[non_exhaustive]
 pub struct Dependency {}
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+/// A platform pattern that a package supports, from its `package.toml`.
+///
+/// Runners check these in [`Runner::supports_any`](crate::runner::Runner::supports_any).
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
+#[non_exhaustive]
 pub struct Target {
+    /// The CPU architecture, like `"x86_64"`, or `None` to match any.
     pub arch: Option<String>,
+    /// The operating system, like `"linux"`, or `None` to match any.
     pub os: Option<String>,
+    /// What the package needs from the environments beyond the platform.
+    #[serde(default)]
+    pub needs: BTreeSet<Capability>,
+}
+
+impl Target {
+    /// Returns the capabilities this target needs that aren't in `have`.
+    pub fn missing(&self, have: &BTreeSet<Capability>) -> BTreeSet<Capability> {
+        self.needs.difference(have).copied().collect()
+    }
+}
+
+/// Something beyond the platform that a package may need from the
+/// environments it's built and used in, listed in `needs` in a [`Target`].
+///
+/// Runners report theirs in
+/// [`Runner::capabilities`](crate::runner::Runner::capabilities).
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+#[non_exhaustive]
+pub enum Capability {
+    /// Network access, such as to download files.
+    #[serde(rename = "network")]
+    Network,
+    /// Access to the host's display, for graphical programs.
+    #[serde(rename = "gui")]
+    Gui,
+    /// Access to `/dev/kvm`, for hardware-accelerated virtual machines.
+    #[serde(rename = "kvm")]
+    Kvm,
+    /// A running systemd that can manage services.
+    #[serde(rename = "systemd")]
+    Systemd,
 }
 
+impl Capability {
+    /// Every capability.
+    pub const ALL: [Self; 4] = [Self::Network, Self::Gui, Self::Kvm, Self::Systemd];
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Network => "network",
+            Self::Gui => "gui",
+            Self::Kvm => "kvm",
+            Self::Systemd => "systemd",
+        })
+    }
+}
+
+/// A package's definition from its `package.toml` file.
+///
+/// Use [`Manifest::read`] or [`str::parse`] to get one.
 #[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub struct Manifest {
+    /// If true, the package provides a way to install other packages, like
+    /// `npm` or `pypi`, and its namespace may be used in other packages'
+    /// dependencies.
     pub package_manager: bool,
+    /// The platforms the package supports, or `None` for all of them.
     pub targets: Option<Vec<Target>>,
+    /// Packages that must be installed alongside this one, by namespace.
+    /// This always has an entry for [`PackageNamespace::Root`], even if it's
+    /// empty.
     pub depends: BTreeMap<PackageNamespace, BTreeMap<PackageName, Dependency>>,
+    /// Packages that must be available to build this one, by namespace. This
+    /// always has an entry for [`PackageNamespace::Root`], even if it's
+    /// empty.
     pub build_depends: BTreeMap<PackageNamespace, BTreeMap<PackageName, Dependency>>,
 }
 
 impl Manifest {
-    pub fn read(dir_path: &HostPath, path: &str) -> LowLevelResult<Option<Self>> {
-        let dir = cap_std::fs::Dir::open_ambient_dir(
-            dir_path.as_host_raw(),
-            cap_std::ambient_authority(),
-        )
-        .with_context(|| format!("failed to open directory {:?}", dir_path.as_host_raw()))?;
-        let buf = match dir.read_to_string(path) {
+    /// Reads and parses the `package.toml` file in the package directory
+    /// `dir`, the same way Cubicle does.
+    ///
+    /// Returns `Ok(None)` if the directory has no `package.toml` file.
+    ///
+    /// # Errors
+    ///
+    /// - Opening the directory or reading the file.
+    /// - Invalid TOML, unknown keys, or invalid package names.
+    pub fn read(dir: &Path) -> Result<Option<Self>> {
+        let dir_handle = cap_std::fs::Dir::open_ambient_dir(dir, cap_std::ambient_authority())
+            .with_context(|| format!("failed to open directory {dir:?}"))?;
+        let path = dir.join("package.toml");
+        let buf = match dir_handle.read_to_string("package.toml") {
             Ok(buf) => buf,
-            Err(e) => {
-                return if e.kind() == io::ErrorKind::NotFound {
-                    Ok(None)
-                } else {
-                    Err(e)
-                        .with_context(|| {
-                            format!("failed to read {:?}", dir_path.join(path).as_host_raw())
-                        })
-                        .map_err(|e| e.into())
-                }
-            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {path:?}")),
         };
         Ok(Some(parse(&buf)?))
     }
 }
 
+impl FromStr for Manifest {
+    type Err = Error;
+
+    /// Parses the contents of a `package.toml` file.
+    fn from_str(s: &str) -> Result<Self> {
+        parse(s)
+    }
+}
+
 fn parse(buf: &str) -> Result<Manifest> {
     let manifest: TomlManifest = toml::from_str(buf).enough_context()?;
     convert(manifest)
@@ -116,6 +199,36 @@ mod tests {
     use super::*;
     use expect_test::expect;
 
+    #[test]
+    fn target_missing() {
+        let target = Target {
+            arch: None,
+            os: None,
+            needs: BTreeSet::from([Capability::Network, Capability::Kvm]),
+        };
+        assert_eq!(
+            BTreeSet::from([Capability::Kvm]),
+            target.missing(&BTreeSet::from([Capability::Network, Capability::Gui]))
+        );
+        assert!(target.missing(&BTreeSet::from(Capability::ALL)).is_empty());
+    }
+
+    #[test]
+    fn read() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(None, Manifest::read(dir.path()).unwrap());
+        std::fs::write(dir.path().join("package.toml"), "[depends]\nx = {}\n").unwrap();
+        let manifest = Manifest::read(dir.path()).unwrap().unwrap();
+        assert_eq!(
+            vec![&PackageName::strict_from_str("x").unwrap()],
+            manifest.depends[&PackageNamespace::Root]
+                .keys()
+                .collect::<Vec<_>>()
+        );
+        std::fs::write(dir.path().join("package.toml"), "color = 'blue'\n").unwrap();
+        assert!(Manifest::read(dir.path()).is_err());
+    }
+
     #[test]
     fn parse() {
         assert_eq!(
@@ -140,6 +253,10 @@ mod tests {
                             os: Some(
                                 "linux",
                             ),
+                            needs: {
+                                Network,
+                                Gui,
+                            },
                         },
                     ],
                 ),
@@ -182,6 +299,7 @@ mod tests {
                 [[targets]]
                 arch = 'x86_64'
                 os = 'linux'
+                needs = ['gui', 'network']
                 [depends]
                 x = {}
                 y = {}
@@ -0,0 +1,97 @@
+//! Applies newly built packages to existing environments, for `cub package
+//! update --reseed`.
+//!
+//! Re-seeding extracts a package's build output over an environment's home
+//! directory, the same way that creating or resetting the environment does,
+//! but without deleting anything first. Files that an older build created
+//! and the new build no longer includes are left behind until the
+//! environment is reset.
+
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use super::{
+    special, transitive_depends, try_exists, BuildDepends, EnvironmentExists, FullPackageName,
+    Runner,
+};
+use crate::somehow::{somehow as anyhow, warn, Context, Result};
+use crate::{Cubicle, EnvironmentName, Event, Operation, PlannedAction};
+
+impl Cubicle {
+    /// Corresponds to `cub package update --reseed`, after the packages are
+    /// updated.
+    ///
+    /// Extracts the build outputs of the given packages into each
+    /// environment that uses them, directly or as dependencies, and returns
+    /// the names of those environments. Instances of golden environments
+    /// are skipped, since they get their packages from the golden
+    /// environment, and so are golden environments that have instances,
+    /// since changing them would change the instances' files underneath
+    /// them.
+    pub fn reseed_environments(
+        &self,
+        packages: &BTreeSet<FullPackageName>,
+    ) -> Result<Vec<EnvironmentName>> {
+        let specs = self.scan_packages()?;
+        let mut reseeded = Vec::new();
+        for name in self.get_environment_names()? {
+            let _lock = self.shared.locks.lock_environment(&name)?;
+            if self.runner.exists(&name)? != EnvironmentExists::FullyExists
+                || self.shared.golden.golden(&name)?.is_some()
+            {
+                continue;
+            }
+
+            let mut env_packages = match self.read_package_list_from_env(&name) {
+                Ok(env_packages) => env_packages,
+                Err(e) => {
+                    warn(e.context(format!(
+                        "skipping environment {name}: failed to parse `packages.txt`"
+                    )));
+                    continue;
+                }
+            };
+            env_packages.insert(FullPackageName::from_str(special::AUTO_INTERACTIVE).unwrap());
+            let used = transitive_depends(&env_packages, &specs, BuildDepends(false))?;
+            let mut matched = Vec::new();
+            for package in packages.intersection(&used) {
+                if self.shared.dry_run || try_exists(&self.package_tar(package)).todo_context()? {
+                    matched.push(package.clone());
+                }
+            }
+            if matched.is_empty() {
+                continue;
+            }
+
+            if !self.shared.golden.instances(&name)?.is_empty() {
+                warn(anyhow!(
+                    "not re-seeding environment {name} because it's the golden \
+                    environment for other environments (reset it instead)"
+                ));
+                continue;
+            }
+
+            if self.shared.dry_run {
+                self.shared.plan(PlannedAction::ReseedEnvironment {
+                    name: name.clone(),
+                    packages: matched,
+                });
+            } else {
+                self.shared.event(Event::ReseedingEnvironment {
+                    name: name.clone(),
+                    packages: matched.clone(),
+                });
+                let seeds: Vec<_> = matched.iter().map(|p| self.package_tar(p)).collect();
+                self.runner.reseed(&name, &seeds)?;
+                // The home directory changed, so its cached size is stale.
+                self.shared.summaries.forget(&name)?;
+                self.shared.history.record(Operation::Reseed {
+                    name: name.clone(),
+                    packages: self.package_versions(&matched.into_iter().collect()),
+                });
+            }
+            reseeded.push(name);
+        }
+        Ok(reseeded)
+    }
+}
@@ -0,0 +1,58 @@
+//! Keeps the counts from [`Cubicle::get_package_status`] for a short time.
+//!
+//! Shell prompts may run `cub status` every time they're drawn, and counting
+//! the stale packages walks every package's source directory. This reuses
+//! the counts until they're older than [`FRESH_FOR`] or a package is built.
+
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+use super::PackageStatus;
+use crate::fs_util::write_json_atomically;
+use crate::somehow::Result;
+use crate::summary_cache::FRESH_FOR;
+use crate::{Cubicle, HostPath};
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    computed: SystemTime,
+    status: PackageStatus,
+}
+
+impl Cubicle {
+    fn package_status_path(&self) -> HostPath {
+        self.shared.paths.cache.join("package-status.json")
+    }
+
+    /// Returns the cached package status if it's still usable at `now`.
+    pub(super) fn cached_package_status(&self, now: SystemTime) -> Option<PackageStatus> {
+        let buf = std::fs::read(self.package_status_path().as_host_raw()).ok()?;
+        let entry: Entry = serde_json::from_slice(&buf).ok()?;
+        if !now
+            .duration_since(entry.computed)
+            .is_ok_and(|age| age <= FRESH_FOR)
+        {
+            return None;
+        }
+        // Building a package writes into the package cache, which updates
+        // the directory's modification time.
+        let package_cache = &self.shared.paths.package_cache;
+        let built = std::fs::metadata(package_cache.as_host_raw())
+            .and_then(|metadata| metadata.modified())
+            .ok()?;
+        (built < entry.computed).then_some(entry.status)
+    }
+
+    /// Saves the package status, which was computed starting at `computed`.
+    pub(super) fn cache_package_status(
+        &self,
+        status: PackageStatus,
+        computed: SystemTime,
+    ) -> Result<()> {
+        write_json_atomically(
+            &self.shared.paths.cache,
+            &self.package_status_path(),
+            &Entry { computed, status },
+        )
+    }
+}
@@ -0,0 +1,80 @@
+//! Rebuilds packages as their sources change, for `cub package update
+//! --watch`.
+//!
+//! Like `cub sync --watch`, this polls the modification times of the
+//! package directories rather than relying on a platform's file
+//! notifications.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{
+    summarize_dir, transitive_depends, BuildDepends, FullPackageName, PackageNamespace,
+    UpdatePackagesConditions,
+};
+use crate::somehow::Result;
+use crate::{cancel, Cubicle, Event, HostPath};
+
+/// How often to check the package directories for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Cubicle {
+    /// Corresponds to `cub package update --watch`.
+    ///
+    /// Updates the packages, then updates them again whenever the sources of
+    /// the packages or their dependencies change, until interrupted. Each
+    /// round's outcome is reported as an event, and failed rounds don't stop
+    /// the loop, so that the next edit can fix them.
+    pub fn watch_packages(
+        &self,
+        packages: &BTreeSet<FullPackageName>,
+        conditions: &UpdatePackagesConditions,
+    ) -> Result<()> {
+        self.shared.check_not_dry_run("watch packages")?;
+        // The directories are found once, so a dependency added later isn't
+        // watched until this restarts.
+        let dirs = self.package_dirs(packages)?;
+        self.shared
+            .event(Event::WatchingPackages(packages.iter().cloned().collect()));
+
+        let mut last_modified = None;
+        loop {
+            let modified: Vec<Option<SystemTime>> = dirs
+                .iter()
+                .map(|dir| summarize_dir(dir).ok().map(|s| s.last_modified))
+                .collect();
+            if last_modified.as_ref() != Some(&modified) {
+                last_modified = Some(modified);
+                let start = Instant::now();
+                let result = self
+                    .scan_packages()
+                    .and_then(|specs| self.update_packages(packages, &specs, conditions));
+                cancel::check()?;
+                self.shared.event(Event::WatchedPackagesUpdated {
+                    duration: start.elapsed(),
+                    error: result.err().map(|e| format!("{e:#}")),
+                });
+            }
+            std::thread::sleep(WATCH_INTERVAL);
+            cancel::check()?;
+        }
+    }
+
+    /// Returns the source directories of the packages and their
+    /// dependencies.
+    fn package_dirs(&self, packages: &BTreeSet<FullPackageName>) -> Result<Vec<HostPath>> {
+        let specs = self.scan_packages()?;
+        let mut dirs = BTreeSet::new();
+        for FullPackageName(ns, name) in transitive_depends(packages, &specs, BuildDepends(true))? {
+            let spec = match &ns {
+                PackageNamespace::Root => specs.get(&name),
+                PackageNamespace::Managed(manager) => specs.get(manager),
+                PackageNamespace::Debian => None,
+            };
+            if let Some(spec) = spec {
+                dirs.insert(spec.dir.as_host_raw().to_owned());
+            }
+        }
+        dirs.into_iter().map(HostPath::try_from).collect()
+    }
+}
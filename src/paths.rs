@@ -1,3 +1,4 @@
+use super::config::Dirs;
 use crate::somehow::{somehow as anyhow, Error, Result};
 use std::ffi::OsString;
 use std::fmt;
@@ -15,11 +16,13 @@ use std::path::{Path, PathBuf};
 /// 1. It requires paths to be absolute.
 /// 2. It does not allow joining to absolute paths.
 macro_rules! abs_path {
-    ($name:ident, $getter:ident) => {
+    ($(#[$attr:meta])* $name:ident, $getter:ident) => {
+        $(#[$attr])*
         #[derive(Debug, Clone)]
         pub struct $name(PathBuf);
 
         impl $name {
+            /// Returns the path as a standard [`Path`].
             pub fn $getter(&self) -> &Path {
                 &self.0
             }
@@ -77,13 +80,113 @@ macro_rules! abs_path {
     };
 }
 
-abs_path!(HostPath, as_host_raw);
-abs_path!(EnvPath, as_env_raw);
+abs_path!(
+    /// An absolute path in the host's filesystem namespace.
+    HostPath,
+    as_host_raw
+);
+abs_path!(
+    /// An absolute path in an environment's filesystem namespace.
+    EnvPath,
+    as_env_raw
+);
+
+/// Returns `$XDG_CACHE_HOME`, or its default within `home` if unset.
+pub fn xdg_cache_home(home: &HostPath) -> Result<HostPath> {
+    match std::env::var("XDG_CACHE_HOME") {
+        Ok(path) => HostPath::try_from(path),
+        Err(_) => Ok(home.join(".cache")),
+    }
+}
+
+/// Returns `$XDG_DATA_HOME`, or its default within `home` if unset.
+pub fn xdg_data_home(home: &HostPath) -> Result<HostPath> {
+    match std::env::var("XDG_DATA_HOME") {
+        Ok(path) => HostPath::try_from(path),
+        Err(_) => Ok(home.join(".local").join("share")),
+    }
+}
+
+/// The host directories where Cubicle keeps its files.
+///
+/// These are resolved once, from the `[dirs]` configuration and the cache and
+/// data directories, so that every module agrees on them.
+#[derive(Debug)]
+pub struct Paths {
+    /// The `cubicle` directory within the cache directory, for files that
+    /// don't have their own setting.
+    pub cache: HostPath,
+    /// The `cubicle` directory within the data directory, for files that
+    /// don't have their own setting.
+    pub data: HostPath,
+    /// Where built packages are stored.
+    pub package_cache: HostPath,
+    /// Where runners keep environments' home directories.
+    pub home_dirs: HostPath,
+    /// Where runners keep environments' work directories.
+    pub work_dirs: HostPath,
+    /// Where the word list for random environment names is kept.
+    pub word_list: HostPath,
+}
+
+impl Paths {
+    /// Resolves the paths from the base cache and data directories (such as
+    /// `$XDG_CACHE_HOME` and `$XDG_DATA_HOME`) and the configured overrides.
+    pub fn new(cache_home: &HostPath, data_home: &HostPath, dirs: &Dirs) -> Result<Self> {
+        let cache = cache_home.join("cubicle");
+        let data = data_home.join("cubicle");
+        let dir = |configured: &Option<PathBuf>, default: HostPath| match configured {
+            Some(path) => HostPath::try_from(path.clone()),
+            None => Ok(default),
+        };
+        Ok(Self {
+            package_cache: dir(&dirs.package_cache, cache.join("packages"))?,
+            home_dirs: dir(&dirs.home_dirs, cache.join("home"))?,
+            work_dirs: dir(&dirs.work_dirs, data.join("work"))?,
+            word_list: dir(&dirs.word_list, cache.clone())?,
+            cache,
+            data,
+        })
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn paths_new() {
+        let cache_home = HostPath::try_from(String::from("/c")).unwrap();
+        let data_home = HostPath::try_from(String::from("/d")).unwrap();
+        let paths = Paths::new(&cache_home, &data_home, &Dirs::default()).unwrap();
+        assert_eq!(
+            Path::new("/c/cubicle/packages"),
+            paths.package_cache.as_host_raw()
+        );
+        assert_eq!(Path::new("/c/cubicle/home"), paths.home_dirs.as_host_raw());
+        assert_eq!(Path::new("/d/cubicle/work"), paths.work_dirs.as_host_raw());
+        assert_eq!(Path::new("/c/cubicle"), paths.word_list.as_host_raw());
+
+        let dirs = Dirs {
+            work_dirs: Some(PathBuf::from("/big/work")),
+            ..Dirs::default()
+        };
+        let paths = Paths::new(&cache_home, &data_home, &dirs).unwrap();
+        assert_eq!(Path::new("/big/work"), paths.work_dirs.as_host_raw());
+        assert_eq!(Path::new("/c/cubicle/home"), paths.home_dirs.as_host_raw());
+
+        let dirs = Dirs {
+            home_dirs: Some(PathBuf::from("home")),
+            ..Dirs::default()
+        };
+        assert_eq!(
+            "HostPath must be an absolute path, got \"home\"",
+            Paths::new(&cache_home, &data_home, &dirs)
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
     #[test]
     fn path_from_str_relative() {
         assert_eq!(
@@ -94,6 +197,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn env_path_join() {
+        let home = EnvPath::try_from(String::from("/home/foo")).unwrap();
+        assert_eq!(Path::new("/home/foo/w/x"), home.join("w/x").as_env_raw());
+        let root = EnvPath::try_from(String::from("/")).unwrap();
+        assert_eq!(Path::new("/w"), root.join("w").as_env_raw());
+    }
+
     #[test]
     #[should_panic(expected = "EnvPath cannot be joined to an absolute path, got \"/bye\"")]
     fn path_join_absolute() {
@@ -1,26 +1,58 @@
 use rand::seq::SliceRandom;
 use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::time::SystemTime;
 
+use super::json::format_rfc3339;
 use super::HostPath;
 use crate::somehow::{somehow as anyhow, warn, Context, Result};
 
 pub struct RandomNameGenerator {
     cache_dir: HostPath,
+    word_list: Option<PathBuf>,
     eff_url: &'static str, // overridden for unit tests
 }
 
 impl RandomNameGenerator {
-    pub fn new(cache_dir: HostPath) -> Self {
+    pub fn new(cache_dir: HostPath, word_list: Option<PathBuf>) -> Self {
         Self {
             cache_dir,
+            word_list,
             eff_url: "https://www.eff.org/files/2016/09/08/eff_short_wordlist_1.txt",
         }
     }
 
+    /// Returns `count` random words joined with hyphens, such that `filter`
+    /// accepts the result.
+    pub fn random_words<F>(&self, count: u8, filter: F) -> Result<String>
+    where
+        F: Fn(&str) -> Result<bool>,
+    {
+        let mut prefix = String::new();
+        for _ in 1..count {
+            prefix.push_str(&self.random_name(|_| Ok(true))?);
+            prefix.push('-');
+        }
+        let last = self.random_name(|word| filter(&format!("{prefix}{word}")))?;
+        Ok(format!("{prefix}{last}"))
+    }
+
     pub fn random_name<F>(&self, filter: F) -> Result<String>
     where
         F: Fn(&str) -> Result<bool>,
     {
+        // 0. Use the configured word list, if any.
+        if let Some(path) = &self.word_list {
+            let custom = || -> Result<String> {
+                let file = std::fs::File::open(path).enough_context()?;
+                from_reader(file, &filter)
+            };
+            match custom().with_context(|| format!("failed to extract word from {path:?}")) {
+                Ok(word) => return Ok(word),
+                Err(e) => warn(e),
+            }
+        }
+
         // 1. Prefer the EFF short word list. See https://www.eff.org/dice for
         // more info.
         let eff = || -> Result<String> {
@@ -92,6 +124,26 @@ impl RandomNameGenerator {
     }
 }
 
+/// Expands `{date}` and `{project}` in the `tmp.name_prefix` setting.
+pub fn tmp_name_prefix(template: &str) -> Result<String> {
+    let date = &format_rfc3339(SystemTime::now())[..10];
+    let project = if template.contains("{project}") {
+        let dir = std::env::current_dir().context("failed to get current directory")?;
+        dir.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| anyhow!("can't use `{{project}}` from the root directory"))?
+    } else {
+        String::new()
+    };
+    Ok(expand_prefix(template, date, &project))
+}
+
+fn expand_prefix(template: &str, date: &str, project: &str) -> String {
+    template
+        .replace("{date}", date)
+        .replace("{project}", project)
+}
+
 fn from_reader<R, F>(reader: R, filter: F) -> Result<String>
 where
     R: std::io::Read,
@@ -124,11 +176,36 @@ mod tests {
     use super::HostPath;
     use expect_test::expect;
 
+    #[test]
+    fn expand_prefix() {
+        assert_eq!(
+            "tmp-",
+            super::expand_prefix("tmp-", "2024-05-06", "cubicle")
+        );
+        assert_eq!(
+            "cubicle-2024-05-06-",
+            super::expand_prefix("{project}-{date}-", "2024-05-06", "cubicle")
+        );
+    }
+
+    #[test]
+    fn random_words() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let words = tmpdir.path().join("words");
+        std::fs::write(&words, "11111 apple\n11112 apple\n").unwrap();
+        let tmpdir_path = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        let gen = super::RandomNameGenerator::new(tmpdir_path, Some(words));
+        assert_eq!(
+            "apple-apple-apple",
+            gen.random_words(3, |name| Ok(name.len() > 5)).unwrap()
+        );
+    }
+
     #[test]
     fn download_or_open_eff_list() {
         let tmpdir = tempfile::tempdir().unwrap();
         let tmpdir_path = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
-        let mut gen = super::RandomNameGenerator::new(tmpdir_path);
+        let mut gen = super::RandomNameGenerator::new(tmpdir_path, None);
         gen.eff_url = "will://not work";
         let err = gen
             .download_or_open_eff_list()
@@ -0,0 +1,77 @@
+//! Tracks which environments have read-only home directories.
+//!
+//! Sessions in such an environment see its home directory through an overlay
+//! whose upper layer is a temporary file system, so they start from the
+//! seeded state and their changes are discarded when they exit. Each of
+//! these environments has an empty marker file.
+
+use std::io;
+
+use super::fs_util::try_exists;
+use super::{EnvironmentName, HostPath};
+use crate::somehow::{Context, Result};
+
+pub struct ReadOnlyRegistry {
+    dir: HostPath,
+}
+
+impl ReadOnlyRegistry {
+    pub fn new(dir: HostPath) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, name: &EnvironmentName) -> HostPath {
+        self.dir.join(name.as_filename())
+    }
+
+    /// Returns true if `name` has a read-only home directory.
+    pub fn is_read_only(&self, name: &EnvironmentName) -> Result<bool> {
+        let path = self.path(name);
+        try_exists(&path).with_context(|| format!("failed to check if {path} exists"))
+    }
+
+    /// Records that `name` has a read-only home directory.
+    pub fn record(&self, name: &EnvironmentName) -> Result<()> {
+        std::fs::create_dir_all(self.dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {}", self.dir))?;
+        let path = self.path(name);
+        std::fs::write(path.as_host_raw(), "").with_context(|| format!("failed to write {path}"))
+    }
+
+    /// Removes the record of `name` having a read-only home directory, if
+    /// any.
+    pub fn forget(&self, name: &EnvironmentName) -> Result<()> {
+        let path = self.path(name);
+        match std::fs::remove_file(path.as_host_raw()) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("failed to remove {path}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn registry() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir_path = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        let registry = ReadOnlyRegistry::new(tmpdir_path.join("read-only"));
+        let a = EnvironmentName::from_str("a").unwrap();
+        let b = EnvironmentName::from_str("b/c").unwrap();
+
+        assert!(!registry.is_read_only(&a).unwrap());
+        registry.record(&a).unwrap();
+        registry.record(&b).unwrap();
+        assert!(registry.is_read_only(&a).unwrap());
+        assert!(registry.is_read_only(&b).unwrap());
+
+        registry.forget(&a).unwrap();
+        registry.forget(&a).unwrap();
+        assert!(!registry.is_read_only(&a).unwrap());
+        assert!(registry.is_read_only(&b).unwrap());
+    }
+}
@@ -1,13 +1,56 @@
+//! The interface between Cubicle and the mechanisms that isolate its
+//! environments.
+//!
+//! Cubicle comes with several built-in runners, which are selected with the
+//! `runner` configuration setting. Other crates may implement [`Runner`] and
+//! pass it to [`Cubicle::new_with_runner`](crate::Cubicle::new_with_runner)
+//! to use a different isolation mechanism.
+//!
+//! This interface isn't stable yet. New versions of Cubicle may add required
+//! methods or bounds to [`Runner`], so runners in other crates should expect
+//! to change when they upgrade. Methods for optional features have default
+//! implementations, so a runner only needs to implement the core operations.
+//!
+//! With the `tokio` feature, this module also offers [`AsyncRunner`], an
+//! async counterpart of [`Runner`]. [`Cubicle::async_runner`] returns the
+//! configured runner wrapped in the [`Blocking`] adapter, which lets callers
+//! run several long operations at once without managing threads themselves.
+//!
+//! [`Cubicle::async_runner`]: crate::Cubicle::async_runner
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::cancel;
+use super::file_watch::poll_host_dir;
+pub use super::file_watch::{FileKind, FileListing, FileState};
+use super::fs_util::check_tars_confined;
+pub use super::fs_util::{summarize_dir, DirSummary};
+pub use super::packages::{Capability, Target};
+pub use super::paths::HostPath;
+use super::EnvironmentName;
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+pub(crate) mod fault;
 
-use super::fs_util::DirSummary;
-pub(crate) use super::Target;
-use super::{EnvironmentName, HostPath};
-use crate::somehow::{Context, Result};
+#[cfg(feature = "tokio")]
+mod asynchronous;
+#[cfg(feature = "tokio")]
+pub use asynchronous::{AsyncRunner, Blocking, BoxFuture, OwnedRunnerCommand};
 
 /// Manages isolated operating system environments.
-pub trait Runner {
+///
+/// Cubicle wraps every runner to check its preconditions and
+/// postconditions. For example, it only calls [`Runner::create`] when the
+/// environment doesn't exist, and it panics if the environment doesn't fully
+/// exist afterwards.
+///
+/// Runners must be `Send + Sync`, since a [`Cubicle`](crate::Cubicle) may be
+/// shared across threads.
+pub trait Runner: Send + Sync {
     /// Returns a list of existing environments.
     ///
     /// The returned list includes environments that partially exist.
@@ -44,20 +87,235 @@ pub trait Runner {
         w: &mut dyn io::Write,
     ) -> Result<()>;
 
+    /// Writes a tar archive of the environment's home directory, with its
+    /// work directory at `w/`, into the given writer.
+    ///
+    /// Seeding a new environment with the archive restores the files, for
+    /// `cub backup` and `cub restore`.
+    ///
+    /// The default implementation returns an error.
+    fn backup(&self, _name: &EnvironmentName, _w: &mut dyn io::Write) -> Result<()> {
+        Err(anyhow!("this runner can't back up environments"))
+    }
+
+    /// Writes a tar archive of the given paths, which are relative to the
+    /// environment's home directory, into the given writer. Paths that don't
+    /// exist are skipped.
+    ///
+    /// Seeding the environment with the archive restores the files, for
+    /// `cub reset --keep`.
+    ///
+    /// The default implementation returns an error.
+    fn save_home_paths(
+        &self,
+        _name: &EnvironmentName,
+        _paths: &[PathBuf],
+        _w: &mut dyn io::Write,
+    ) -> Result<()> {
+        Err(anyhow!("this runner can't keep files across resets"))
+    }
+
     /// Creates a new environment with the given name.
     ///
     /// Fails if an environment already (partially or fully) exists with that
     /// name.
     fn create(&self, name: &EnvironmentName, init: &Init) -> Result<()>;
 
+    /// Creates a new environment with the given name, like
+    /// [`Runner::create`], whose files are encrypted at rest.
+    ///
+    /// The default implementation returns an error.
+    fn create_encrypted(&self, _name: &EnvironmentName, _init: &Init) -> Result<()> {
+        Err(anyhow!("this runner can't encrypt environments"))
+    }
+
+    /// Returns true if the environment's files are encrypted at rest.
+    ///
+    /// The default implementation returns false.
+    fn is_encrypted(&self, _name: &EnvironmentName) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Creates a new environment with the given name, like
+    /// [`Runner::create`], that the members of the given Unix group may
+    /// also use.
+    ///
+    /// The default implementation returns an error.
+    fn create_shared(&self, _name: &EnvironmentName, _init: &Init, _group: &str) -> Result<()> {
+        Err(anyhow!("this runner can't share environments with a group"))
+    }
+
+    /// Creates a new environment with the given name, like
+    /// [`Runner::create`], whose home and work directories are bind-mounted
+    /// from the host.
+    ///
+    /// The default implementation returns an error.
+    fn create_with_bind_mounts(&self, _name: &EnvironmentName, _init: &Init) -> Result<()> {
+        Err(anyhow!(
+            "this runner can't choose bind mounts per environment"
+        ))
+    }
+
+    /// Returns who created the environment and which group it's shared
+    /// with, or `None` if it isn't shared.
+    ///
+    /// The default implementation returns `None`.
+    fn sharing(&self, _name: &EnvironmentName) -> Result<Option<Sharing>> {
+        Ok(None)
+    }
+
     /// Returns whether the environment fully exists, partially exists (in a
     /// likely broken state), or does not exist at all.
     fn exists(&self, name: &EnvironmentName) -> Result<EnvironmentExists>;
 
+    /// Returns true if the runner can create instances of golden
+    /// environments.
+    ///
+    /// An instance's home directory overlays the home directory of its
+    /// golden environment.
+    ///
+    /// The default implementation returns false.
+    fn supports_golden(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the runner can encrypt environments' files at rest,
+    /// for `cub new --encrypt`.
+    ///
+    /// The default implementation returns false.
+    fn supports_encryption(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the runner can choose to bind-mount the home and work
+    /// directories of individual environments, for `cub new --bind-mounts`.
+    ///
+    /// The default implementation returns false.
+    fn supports_bind_mounts(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the runner can share environments with the members
+    /// of a Unix group, for `cub new --group`.
+    ///
+    /// The default implementation returns false.
+    fn supports_sharing(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the runner can give environments read-only home
+    /// directories, for `cub new --read-only-home`.
+    ///
+    /// Each session in such an environment sees the home directory through
+    /// an overlay with a temporary upper layer, so its changes are discarded
+    /// when it exits. This only applies to [`Runner::run`], not to seeding
+    /// or initializing the home directory.
+    ///
+    /// The default implementation returns false.
+    fn supports_read_only_home(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the runner can use a git worktree on the host as an
+    /// environment's work directory, for `cub worktree new`.
+    ///
+    /// The default implementation returns false.
+    fn supports_worktrees(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the runner can make the pipe for opening URLs and
+    /// files on the host available inside environments, for the
+    /// `host_open` configuration option.
+    ///
+    /// The default implementation returns false.
+    fn supports_host_open(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the runner mounts the shared caches from the
+    /// `caches` configuration option into environments.
+    ///
+    /// The default implementation returns false.
+    fn supports_caches(&self) -> bool {
+        false
+    }
+
+    /// Returns the names of the Debian packages that can be installed in
+    /// environments, or `None` if that can't be determined cheaply.
+    ///
+    /// The default implementation returns `None`.
+    fn available_debian_packages(&self) -> Result<Option<BTreeSet<String>>> {
+        Ok(None)
+    }
+
+    /// Returns the container images that environments are created from, for
+    /// `cub scan --images`.
+    ///
+    /// The default implementation returns an empty list, for runners that
+    /// use the host's files instead of images.
+    fn base_images(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Returns the Debian packages installed for the environment, not counting
+    /// the ones the runner always installs, or `None` if that isn't known.
+    ///
+    /// The default implementation returns `None`, which suits runners that
+    /// use the host's Debian packages.
+    fn debian_packages(&self, _name: &EnvironmentName) -> Result<Option<BTreeSet<String>>> {
+        Ok(None)
+    }
+
+    /// Returns the Debian packages that the runner always installs in
+    /// environments, which [`Runner::debian_packages`] leaves out.
+    ///
+    /// The default implementation returns an empty set.
+    fn base_debian_packages(&self) -> BTreeSet<String> {
+        BTreeSet::new()
+    }
+
+    /// Returns the ID of the container image that the environment runs from,
+    /// or `None` if it uses the host's system files.
+    ///
+    /// The default implementation returns `None`.
+    fn image(&self, _name: &EnvironmentName) -> Result<Option<String>> {
+        Ok(None)
+    }
+
     /// Calculates and returns information about the filesystem paths used for
     /// the environment.
     fn files_summary(&self, name: &EnvironmentName) -> Result<EnvFilesSummary>;
 
+    /// Calls `f` with a listing of the files under `path` in the
+    /// environment's work directory every `interval`, until `f` returns an
+    /// error, which this returns.
+    ///
+    /// The default implementation lists the work directory from the host,
+    /// using the path from [`Runner::files_summary`].
+    fn watch_work_dir(
+        &self,
+        name: &EnvironmentName,
+        path: &Path,
+        interval: Duration,
+        f: &mut dyn FnMut(FileListing) -> Result<()>,
+    ) -> Result<()> {
+        let work_dir = self.files_summary(name)?.work_dir_path.ok_or_else(|| {
+            anyhow!("the work directory of environment {name} isn't on the host's filesystem")
+        })?;
+        poll_host_dir(&work_dir.join(path), interval, f)
+    }
+
+    /// Returns the networks that the environment is currently connected to.
+    ///
+    /// Runners that share the host's network namespace and environments that
+    /// aren't running return an empty list.
+    ///
+    /// The default implementation returns an empty list.
+    fn networks(&self, _name: &EnvironmentName) -> Result<Vec<EnvNetwork>> {
+        Ok(Vec::new())
+    }
+
     /// Stops the environment, if running, and any processes running in it.
     ///
     /// Only returns once the environment has been stopped.
@@ -65,6 +323,24 @@ pub trait Runner {
     /// Does not remove the environment's home or work directories.
     fn stop(&self, name: &EnvironmentName) -> Result<()>;
 
+    /// Returns true if environments remain fully usable after
+    /// [`Runner::stop`], starting again when they're next used.
+    ///
+    /// The default implementation returns false.
+    fn restarts_after_stop(&self) -> bool {
+        false
+    }
+
+    /// Starts the environment's long-running processes, if the runner has
+    /// any, so that the environment is ready before it's next used.
+    ///
+    /// The environment must fully exist already.
+    ///
+    /// The default implementation does nothing.
+    fn start(&self, _name: &EnvironmentName) -> Result<()> {
+        Ok(())
+    }
+
     /// Stops the environment, if running, and any processes running in it, and
     /// deletes its home directory except for its work directory.
     ///
@@ -72,6 +348,51 @@ pub trait Runner {
     /// returns an error saying why they can't).
     fn reset(&self, name: &EnvironmentName, init: &Init) -> Result<()>;
 
+    /// Extracts the package tarballs over the environment's home directory,
+    /// leaving its other files in place.
+    ///
+    /// The default implementation returns an error.
+    fn reseed(&self, _name: &EnvironmentName, _seeds: &[HostPath]) -> Result<()> {
+        Err(anyhow!("this runner can't re-seed environments"))
+    }
+
+    /// Returns each piece of the environment (such as its home directory) and
+    /// whether it exists, to help diagnose partially existing environments.
+    ///
+    /// The default implementation returns an empty list.
+    fn inspect(&self, _name: &EnvironmentName) -> Result<Vec<EnvPiece>> {
+        Ok(Vec::new())
+    }
+
+    /// Returns where an editor should open the environment's work directory,
+    /// starting the environment if needed.
+    ///
+    /// The default implementation returns an error.
+    fn code_target(&self, _name: &EnvironmentName) -> Result<CodeTarget> {
+        Err(anyhow!("this runner can't open environments in an editor"))
+    }
+
+    /// Returns the name of the user account that runs commands in the
+    /// environment.
+    ///
+    /// The default implementation returns an error.
+    fn username(&self, _name: &EnvironmentName) -> Result<String> {
+        Err(anyhow!("this runner doesn't know which user runs commands"))
+    }
+
+    /// Recreates the missing pieces of a partially existing environment,
+    /// keeping the existing pieces where possible.
+    ///
+    /// If the home directory is missing, this initializes a new one using
+    /// `init`. Otherwise, the existing home directory is kept.
+    ///
+    /// The default implementation returns an error suggesting `cub reset`.
+    fn repair(&self, _name: &EnvironmentName, _init: &Init) -> Result<()> {
+        Err(anyhow!(
+            "this runner can't repair environments (try 'cub reset')"
+        ))
+    }
+
     /// Stops the environment, if running, and any processes running in it, and
     /// deletes the environment completely, including its home directory and
     /// work directory.
@@ -79,46 +400,247 @@ pub trait Runner {
     /// This makes partially existing environments no longer exist.
     fn purge(&self, name: &EnvironmentName) -> Result<()>;
 
+    /// Stops the environment, if running, and deletes only its home
+    /// directory or only its work directory. This leaves a partially existing
+    /// environment, which `cub repair` or `cub reset` can complete later.
+    ///
+    /// The default implementation returns an error.
+    fn purge_dir(&self, _name: &EnvironmentName, _dir: EnvDir) -> Result<()> {
+        Err(anyhow!("this runner can't purge part of an environment"))
+    }
+
     /// Runs a command or interactive shell in the environment.
     ///
     /// The environment must fully exist already.
     fn run(&self, name: &EnvironmentName, command: &RunnerCommand) -> Result<()>;
 
     /// Checks if the runner will run on any of the given platform patterns.
+    ///
+    /// This only checks their architecture and operating system. The
+    /// capabilities they need are compared with [`Runner::capabilities`].
     fn supports_any(&self, targets: &[Target]) -> Result<bool>;
+
+    /// Returns what the runner's environments can do beyond their platform,
+    /// for packages whose targets list what they need.
+    ///
+    /// The default implementation claims every capability, so no packages
+    /// are skipped for lacking one.
+    fn capabilities(&self) -> BTreeSet<Capability> {
+        BTreeSet::from(Capability::ALL)
+    }
+
+    /// Returns the directories on the host where the runner keeps
+    /// environments' files, to check how much space is free there.
+    /// Directories that can't be reached from this host are left out.
+    ///
+    /// The default implementation returns an empty list.
+    fn storage_dirs(&self) -> Result<Vec<HostPath>> {
+        Ok(Vec::new())
+    }
 }
 
+/// Whether an environment exists, as returned by [`Runner::exists`].
 #[derive(Debug, PartialEq, Eq)]
 pub enum EnvironmentExists {
+    /// No part of the environment exists.
     NoEnvironment,
+    /// Some but not all parts of the environment exist, so it's probably
+    /// broken.
     PartiallyExists,
+    /// The environment exists and is usable.
     FullyExists,
 }
 
+/// One of an environment's directories, as given to [`Runner::purge_dir`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EnvDir {
+    /// The home directory.
+    Home,
+    /// The work directory.
+    Work,
+}
+
+impl EnvDir {
+    /// Returns a description like "home directory".
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Home => "home directory",
+            Self::Work => "work directory",
+        }
+    }
+}
+
+/// Information about an environment's files, as returned by
+/// [`Runner::files_summary`].
+#[derive(Debug)]
+#[non_exhaustive]
 pub struct EnvFilesSummary {
+    /// The path on the host to the environment's home directory, if
+    /// available.
     pub home_dir_path: Option<HostPath>,
+    /// The size and modification time of the home directory. This excludes
+    /// the work directory.
     pub home_dir: DirSummary,
+    /// The path on the host to the environment's work directory, if
+    /// available.
     pub work_dir_path: Option<HostPath>,
+    /// The size and modification time of the work directory.
     pub work_dir: DirSummary,
 }
 
+impl EnvFilesSummary {
+    /// Creates a new summary.
+    pub fn new(
+        home_dir_path: Option<HostPath>,
+        home_dir: DirSummary,
+        work_dir_path: Option<HostPath>,
+        work_dir: DirSummary,
+    ) -> Self {
+        Self {
+            home_dir_path,
+            home_dir,
+            work_dir_path,
+            work_dir,
+        }
+    }
+}
+
+/// One piece of an environment, as returned by [`Runner::inspect`] and
+/// [`Cubicle::inspect_environment`](crate::Cubicle::inspect_environment).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct EnvPiece {
+    /// What kind of piece this is, like "home directory".
+    pub kind: &'static str,
+    /// Where the piece is or would be, like a path or a Docker object name.
+    pub location: String,
+    /// True if the piece is present, false if it's missing.
+    pub exists: bool,
+}
+
+impl EnvPiece {
+    /// Creates a new piece.
+    pub fn new(kind: &'static str, location: String, exists: bool) -> Self {
+        Self {
+            kind,
+            location,
+            exists,
+        }
+    }
+}
+
+/// Where an editor should open an environment, as returned by
+/// [`Runner::code_target`].
 #[derive(Debug)]
+#[non_exhaustive]
+pub enum CodeTarget {
+    /// A directory on the host.
+    HostDir(HostPath),
+    /// A directory within a running Docker container.
+    Container {
+        /// The container's name, as Docker knows it.
+        name: String,
+        /// The user that the environment's processes run as.
+        user: String,
+        /// The path of the work directory within the container.
+        work_dir: PathBuf,
+    },
+}
+
+/// A network that an environment is connected to, as returned by
+/// [`Runner::networks`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct EnvNetwork {
+    /// The name of the network.
+    pub name: String,
+    /// The environment's IP address on the network, if known.
+    pub ip_address: Option<String>,
+}
+
+impl EnvNetwork {
+    /// Creates a new network description.
+    pub fn new(name: String, ip_address: Option<String>) -> Self {
+        Self { name, ip_address }
+    }
+}
+
+/// Who an environment belongs to and who else may use it, as returned by
+/// [`Runner::sharing`].
+///
+/// This is part of the [`json`](crate::json) output format.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[non_exhaustive]
+pub struct Sharing {
+    /// The user who created the environment.
+    pub owner: String,
+    /// The Unix group whose members may also use the environment.
+    pub group: String,
+}
+
+impl Sharing {
+    /// Creates a new sharing description.
+    pub fn new(owner: String, group: String) -> Self {
+        Self { owner, group }
+    }
+}
+
+/// Parameters for initializing an environment's home directory, passed to
+/// [`Runner::create`], [`Runner::reset`], and [`Runner::repair`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct Init {
+    /// Debian packages that the environment's packages need installed.
     pub debian_packages: Vec<String>,
-    pub env_vars: Vec<(&'static str, String)>,
+    /// Environment variables to set while running the environment's
+    /// initialization script.
+    pub env_vars: Vec<(String, String)>,
+    /// Tar files on the host to extract into the home directory, in order.
+    /// These include the package build outputs and the initialization
+    /// script's inputs.
     pub seeds: Vec<HostPath>,
 }
 
+impl Init {
+    /// Checks that the seed tarballs only contain files within the home
+    /// directory, since runners extract them with `tar` in the environment.
+    fn check_seeds(&self) -> Result<()> {
+        check_tars_confined(&self.seeds).context("found unsafe package seed")
+    }
+}
+
+/// What to run in an environment, passed to [`Runner::run`].
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum RunnerCommand<'a> {
-    Interactive,
+    /// Run a login shell interactively, picked as described in
+    /// [`interactive_shell_command`], with the given additional
+    /// environment variables.
+    Interactive {
+        /// Environment variables to set for the shell.
+        env_vars: &'a [(String, String)],
+        /// The shell to start, from the environment's `--shell` or the
+        /// `shell` configuration setting, if any.
+        shell: Option<&'a str>,
+    },
+    /// Run the given command with the given additional environment
+    /// variables.
     Exec {
+        /// The program and its arguments.
+        command: &'a [String],
+        /// Environment variables to set for the command.
+        env_vars: &'a [(String, String)],
+    },
+    /// Run the given command without a terminal, with its standard input
+    /// and output connected to ours. This is for tunneling protocols like
+    /// SSH, so nothing else may write to standard output.
+    Pipe {
+        /// The program and its arguments.
         command: &'a [String],
-        env_vars: &'a [(&'static str, String)],
     },
 }
 
-pub struct CheckedRunner(Box<dyn Runner>);
+pub(crate) struct CheckedRunner(Box<dyn Runner>);
 
 impl CheckedRunner {
     pub fn new(runner: Box<dyn Runner>) -> Self {
@@ -163,12 +685,41 @@ impl Runner for CheckedRunner {
         })
     }
 
+    fn backup(&self, name: &EnvironmentName, w: &mut dyn io::Write) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before backup"
+        );
+        self.0
+            .backup(name, w)
+            .with_context(|| format!("failed to back up environment {name}"))
+    }
+
+    fn save_home_paths(
+        &self,
+        name: &EnvironmentName,
+        paths: &[PathBuf],
+        w: &mut dyn io::Write,
+    ) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before save_home_paths"
+        );
+        self.0
+            .save_home_paths(name, paths, w)
+            .with_context(|| format!("failed to save files from environment {name}"))
+    }
+
     fn create(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
         assert_eq!(
             self.exists(name)?,
             EnvironmentExists::NoEnvironment,
             "Environment {name} should not exist before create"
         );
+        cancel::check()?;
+        init.check_seeds()?;
         self.0
             .create(name, init)
             .with_context(|| format!("failed to create environment {name}"))?;
@@ -180,12 +731,147 @@ impl Runner for CheckedRunner {
         Ok(())
     }
 
+    fn create_encrypted(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::NoEnvironment,
+            "Environment {name} should not exist before create_encrypted"
+        );
+        cancel::check()?;
+        init.check_seeds()?;
+        self.0
+            .create_encrypted(name, init)
+            .with_context(|| format!("failed to create encrypted environment {name}"))?;
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist after create_encrypted"
+        );
+        Ok(())
+    }
+
+    fn is_encrypted(&self, name: &EnvironmentName) -> Result<bool> {
+        self.0
+            .is_encrypted(name)
+            .with_context(|| format!("failed to check if environment {name} is encrypted"))
+    }
+
+    fn create_shared(&self, name: &EnvironmentName, init: &Init, group: &str) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::NoEnvironment,
+            "Environment {name} should not exist before create_shared"
+        );
+        cancel::check()?;
+        init.check_seeds()?;
+        self.0
+            .create_shared(name, init, group)
+            .with_context(|| format!("failed to create environment {name} shared with {group}"))?;
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist after create_shared"
+        );
+        Ok(())
+    }
+
+    fn create_with_bind_mounts(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::NoEnvironment,
+            "Environment {name} should not exist before create_with_bind_mounts"
+        );
+        cancel::check()?;
+        init.check_seeds()?;
+        self.0
+            .create_with_bind_mounts(name, init)
+            .with_context(|| format!("failed to create environment {name} with bind mounts"))?;
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist after create_with_bind_mounts"
+        );
+        Ok(())
+    }
+
+    fn sharing(&self, name: &EnvironmentName) -> Result<Option<Sharing>> {
+        self.0
+            .sharing(name)
+            .with_context(|| format!("failed to check if environment {name} is shared"))
+    }
+
     fn exists(&self, name: &EnvironmentName) -> Result<EnvironmentExists> {
         self.0
             .exists(name)
             .with_context(|| format!("failed to check if environment {name} exists"))
     }
 
+    fn supports_golden(&self) -> bool {
+        self.0.supports_golden()
+    }
+
+    fn supports_encryption(&self) -> bool {
+        self.0.supports_encryption()
+    }
+
+    fn supports_bind_mounts(&self) -> bool {
+        self.0.supports_bind_mounts()
+    }
+
+    fn supports_sharing(&self) -> bool {
+        self.0.supports_sharing()
+    }
+
+    fn supports_read_only_home(&self) -> bool {
+        self.0.supports_read_only_home()
+    }
+
+    fn supports_worktrees(&self) -> bool {
+        self.0.supports_worktrees()
+    }
+
+    fn supports_host_open(&self) -> bool {
+        self.0.supports_host_open()
+    }
+
+    fn supports_caches(&self) -> bool {
+        self.0.supports_caches()
+    }
+
+    fn available_debian_packages(&self) -> Result<Option<BTreeSet<String>>> {
+        self.0.available_debian_packages()
+    }
+
+    fn base_images(&self) -> Result<Vec<String>> {
+        self.0.base_images()
+    }
+
+    fn debian_packages(&self, name: &EnvironmentName) -> Result<Option<BTreeSet<String>>> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before debian_packages"
+        );
+        self.0
+            .debian_packages(name)
+            .with_context(|| format!("failed to find Debian packages of environment {name}"))
+    }
+
+    fn base_debian_packages(&self) -> BTreeSet<String> {
+        self.0.base_debian_packages()
+    }
+
+    fn image(&self, name: &EnvironmentName) -> Result<Option<String>> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before image"
+        );
+        self.0
+            .image(name)
+            .with_context(|| format!("failed to find image of environment {name}"))
+    }
+
     fn files_summary(&self, name: &EnvironmentName) -> Result<EnvFilesSummary> {
         assert_ne!(
             self.exists(name)?,
@@ -197,6 +883,34 @@ impl Runner for CheckedRunner {
             .with_context(|| format!("failed to summarize filesystem usage for environment {name}"))
     }
 
+    fn watch_work_dir(
+        &self,
+        name: &EnvironmentName,
+        path: &Path,
+        interval: Duration,
+        f: &mut dyn FnMut(FileListing) -> Result<()>,
+    ) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before watch_work_dir"
+        );
+        self.0
+            .watch_work_dir(name, path, interval, f)
+            .with_context(|| format!("failed to watch work directory of environment {name}"))
+    }
+
+    fn networks(&self, name: &EnvironmentName) -> Result<Vec<EnvNetwork>> {
+        assert_ne!(
+            self.exists(name)?,
+            EnvironmentExists::NoEnvironment,
+            "Environment {name} should partially or fully exist before networks"
+        );
+        self.0
+            .networks(name)
+            .with_context(|| format!("failed to get networks for environment {name}"))
+    }
+
     fn stop(&self, name: &EnvironmentName) -> Result<()> {
         assert_ne!(
             self.exists(name)?,
@@ -214,12 +928,29 @@ impl Runner for CheckedRunner {
         Ok(())
     }
 
+    fn restarts_after_stop(&self) -> bool {
+        self.0.restarts_after_stop()
+    }
+
+    fn start(&self, name: &EnvironmentName) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before start"
+        );
+        self.0
+            .start(name)
+            .with_context(|| format!("failed to start environment {name}"))
+    }
+
     fn reset(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
         assert_ne!(
             self.exists(name)?,
             EnvironmentExists::NoEnvironment,
             "Environment {name} should partially or fully exist before reset"
         );
+        cancel::check()?;
+        init.check_seeds()?;
         self.0
             .reset(name, init)
             .with_context(|| format!("failed to reset environment {name}"))?;
@@ -231,6 +962,61 @@ impl Runner for CheckedRunner {
         Ok(())
     }
 
+    fn reseed(&self, name: &EnvironmentName, seeds: &[HostPath]) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before reseed"
+        );
+        cancel::check()?;
+        check_tars_confined(seeds).context("found unsafe package seed")?;
+        self.0
+            .reseed(name, seeds)
+            .with_context(|| format!("failed to re-seed environment {name}"))
+    }
+
+    fn inspect(&self, name: &EnvironmentName) -> Result<Vec<EnvPiece>> {
+        self.0
+            .inspect(name)
+            .with_context(|| format!("failed to inspect environment {name}"))
+    }
+
+    fn code_target(&self, name: &EnvironmentName) -> Result<CodeTarget> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist before code_target"
+        );
+        self.0
+            .code_target(name)
+            .with_context(|| format!("failed to find where to open environment {name}"))
+    }
+
+    fn username(&self, name: &EnvironmentName) -> Result<String> {
+        self.0
+            .username(name)
+            .with_context(|| format!("failed to get username for environment {name}"))
+    }
+
+    fn repair(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::PartiallyExists,
+            "Environment {name} should partially exist before repair"
+        );
+        cancel::check()?;
+        init.check_seeds()?;
+        self.0
+            .repair(name, init)
+            .with_context(|| format!("failed to repair environment {name}"))?;
+        assert_eq!(
+            self.exists(name)?,
+            EnvironmentExists::FullyExists,
+            "Environment {name} should fully exist after repair"
+        );
+        Ok(())
+    }
+
     fn purge(&self, name: &EnvironmentName) -> Result<()> {
         self.0
             .purge(name)
@@ -243,6 +1029,12 @@ impl Runner for CheckedRunner {
         Ok(())
     }
 
+    fn purge_dir(&self, name: &EnvironmentName, dir: EnvDir) -> Result<()> {
+        self.0
+            .purge_dir(name, dir)
+            .with_context(|| format!("failed to purge the {} of environment {name}", dir.as_str()))
+    }
+
     fn run(&self, name: &EnvironmentName, command: &RunnerCommand) -> Result<()> {
         assert_eq!(
             self.exists(name)?,
@@ -265,6 +1057,14 @@ impl Runner for CheckedRunner {
             .supports_any(targets)
             .context("failed to check if targets are supported")
     }
+
+    fn capabilities(&self) -> BTreeSet<Capability> {
+        self.0.capabilities()
+    }
+
+    fn storage_dirs(&self) -> Result<Vec<HostPath>> {
+        self.0.storage_dirs()
+    }
 }
 
 /// Environment variables related to locales, which should generally be passed
@@ -289,3 +1089,71 @@ pub const LOCALE_ENVIRONMENT_VARIABLES: &[&str] = &[
     "LC_TELEPHONE",
     "LC_TIME",
 ];
+
+/// A POSIX shell script that picks and starts the interactive shell. See
+/// [`interactive_shell_command`].
+const INTERACTIVE_SHELL_SCRIPT: &str = r#"shell=$1
+if [ -z "$shell" ]; then
+    shell=$(getent passwd "$(id -un)" 2>/dev/null | cut -d: -f7)
+    [ -x "$shell" ] || shell=$0
+fi
+if ! SHELL=$(command -v "$shell"); then
+    echo "shell not found: $shell" >&2
+    exit 127
+fi
+export SHELL
+exec "$SHELL" -l"#;
+
+/// Returns the program and arguments that start an interactive login shell
+/// in an environment, for [`RunnerCommand::Interactive`].
+///
+/// The shell is picked inside the environment, so that it may differ from
+/// the host's. It's `shell` if given (from `cub new --shell` or the `shell`
+/// configuration setting), looked up in the environment's `PATH` if it has no slash.
+/// Otherwise, it's the login shell from the passwd entry of the user that
+/// the command runs as, or `fallback` if that's missing or isn't
+/// executable. `$SHELL` is set to the chosen shell.
+pub fn interactive_shell_command(shell: Option<&str>, fallback: &str) -> Vec<String> {
+    vec![
+        String::from("/bin/sh"),
+        String::from("-c"),
+        String::from(INTERACTIVE_SHELL_SCRIPT),
+        String::from(fallback),
+        String::from(shell.unwrap_or_default()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command_ext::Command;
+
+    /// Runs the command from [`super::interactive_shell_command`], returning
+    /// whether it succeeded and its output.
+    fn run_shell(shell: Option<&str>, fallback: &str) -> (bool, String) {
+        let command = super::interactive_shell_command(shell, fallback);
+        let output = Command::new(&command[0])
+            .args(&command[1..])
+            .output()
+            .unwrap();
+        (
+            output.status.success(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    }
+
+    #[test]
+    fn interactive_shell_command() {
+        assert_eq!(
+            (true, String::from("-l\n")),
+            run_shell(Some("echo"), "false")
+        );
+        assert_eq!(
+            (true, String::from("-l\n")),
+            run_shell(Some("/bin/echo"), "false")
+        );
+        assert_eq!(
+            (false, String::new()),
+            run_shell(Some("cubicle-no-such-shell"), "echo")
+        );
+    }
+}
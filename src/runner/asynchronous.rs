@@ -0,0 +1,419 @@
+//! An async counterpart of [`Runner`], available with the `tokio` feature.
+
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use super::{
+    Capability, EnvDir, EnvFilesSummary, EnvNetwork, EnvPiece, EnvironmentExists, Init, Runner,
+    RunnerCommand, Target,
+};
+use crate::somehow::{somehow as anyhow, Result};
+use crate::EnvironmentName;
+
+/// A future returned by [`AsyncRunner`] methods.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// An owned version of [`RunnerCommand`], for [`AsyncRunner::run`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum OwnedRunnerCommand {
+    /// Run a login shell interactively, picked as described in
+    /// [`interactive_shell_command`](super::interactive_shell_command),
+    /// with the given additional environment variables.
+    Interactive {
+        /// Environment variables to set for the shell.
+        env_vars: Vec<(String, String)>,
+        /// The shell to start, if any.
+        shell: Option<String>,
+    },
+    /// Run the given command with the given additional environment
+    /// variables.
+    Exec {
+        /// The program and its arguments.
+        command: Vec<String>,
+        /// Environment variables to set for the command.
+        env_vars: Vec<(String, String)>,
+    },
+    /// Run the given command without a terminal, with its standard input
+    /// and output connected to ours.
+    Pipe {
+        /// The program and its arguments.
+        command: Vec<String>,
+    },
+}
+
+impl OwnedRunnerCommand {
+    /// Borrows this as a [`RunnerCommand`].
+    pub fn as_command(&self) -> RunnerCommand<'_> {
+        match self {
+            Self::Interactive { env_vars, shell } => RunnerCommand::Interactive {
+                env_vars,
+                shell: shell.as_deref(),
+            },
+            Self::Exec { command, env_vars } => RunnerCommand::Exec { command, env_vars },
+            Self::Pipe { command } => RunnerCommand::Pipe { command },
+        }
+    }
+}
+
+impl From<&RunnerCommand<'_>> for OwnedRunnerCommand {
+    fn from(command: &RunnerCommand<'_>) -> Self {
+        match command {
+            RunnerCommand::Interactive { env_vars, shell } => Self::Interactive {
+                env_vars: env_vars.to_vec(),
+                shell: shell.map(String::from),
+            },
+            RunnerCommand::Exec { command, env_vars } => Self::Exec {
+                command: command.to_vec(),
+                env_vars: env_vars.to_vec(),
+            },
+            RunnerCommand::Pipe { command } => Self::Pipe {
+                command: command.to_vec(),
+            },
+        }
+    }
+}
+
+/// Manages isolated operating system environments without blocking the
+/// calling thread.
+///
+/// Each method corresponds to the [`Runner`] method of the same name. The
+/// futures are boxed so that this can be used as a trait object, and they
+/// are `Send` so that they can be spawned onto a multithreaded runtime. This
+/// allows long operations, like creating or summarizing several environments,
+/// to run concurrently.
+///
+/// Dropping a future should cancel its operation. Implementations that run
+/// processes should spawn them with `tokio::process::Command::kill_on_drop`
+/// to get this behavior. The [`Blocking`] adapter can't stop a call once it
+/// has started; see its documentation.
+pub trait AsyncRunner: Send + Sync {
+    /// See [`Runner::list`].
+    fn list(&self) -> BoxFuture<'_, Result<Vec<EnvironmentName>>>;
+
+    /// See [`Runner::copy_out_from_home`]. This returns the file's contents
+    /// rather than writing them somewhere.
+    fn copy_out_from_home<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<u8>>>;
+
+    /// See [`Runner::copy_out_from_work`]. This returns the file's contents
+    /// rather than writing them somewhere.
+    fn copy_out_from_work<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<u8>>>;
+
+    /// See [`Runner::create`].
+    fn create<'a>(&'a self, name: &'a EnvironmentName, init: &'a Init)
+        -> BoxFuture<'a, Result<()>>;
+
+    /// See [`Runner::exists`].
+    fn exists<'a>(&'a self, name: &'a EnvironmentName) -> BoxFuture<'a, Result<EnvironmentExists>>;
+
+    /// See [`Runner::supports_golden`].
+    fn supports_golden(&self) -> bool {
+        false
+    }
+
+    /// See [`Runner::available_debian_packages`].
+    fn available_debian_packages(&self) -> BoxFuture<'_, Result<Option<BTreeSet<String>>>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// See [`Runner::files_summary`].
+    fn files_summary<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+    ) -> BoxFuture<'a, Result<EnvFilesSummary>>;
+
+    /// See [`Runner::networks`].
+    fn networks<'a>(
+        &'a self,
+        _name: &'a EnvironmentName,
+    ) -> BoxFuture<'a, Result<Vec<EnvNetwork>>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    /// See [`Runner::stop`].
+    fn stop<'a>(&'a self, name: &'a EnvironmentName) -> BoxFuture<'a, Result<()>>;
+
+    /// See [`Runner::restarts_after_stop`].
+    fn restarts_after_stop(&self) -> bool {
+        false
+    }
+
+    /// See [`Runner::reset`].
+    fn reset<'a>(&'a self, name: &'a EnvironmentName, init: &'a Init) -> BoxFuture<'a, Result<()>>;
+
+    /// See [`Runner::inspect`].
+    fn inspect<'a>(&'a self, _name: &'a EnvironmentName) -> BoxFuture<'a, Result<Vec<EnvPiece>>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    /// See [`Runner::repair`].
+    fn repair<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+        _init: &'a Init,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            Err(anyhow!(
+                "this runner doesn't know how to repair environment {name}"
+            ))
+        })
+    }
+
+    /// See [`Runner::purge`].
+    fn purge<'a>(&'a self, name: &'a EnvironmentName) -> BoxFuture<'a, Result<()>>;
+
+    /// See [`Runner::purge_dir`].
+    fn purge_dir<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+        _dir: EnvDir,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            Err(anyhow!(
+                "this runner can't purge part of environment {name}"
+            ))
+        })
+    }
+
+    /// See [`Runner::run`].
+    fn run<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+        command: &'a OwnedRunnerCommand,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// See [`Runner::supports_any`].
+    fn supports_any<'a>(&'a self, targets: &'a [Target]) -> BoxFuture<'a, Result<bool>>;
+
+    /// See [`Runner::capabilities`].
+    fn capabilities(&self) -> BTreeSet<Capability> {
+        BTreeSet::from(Capability::ALL)
+    }
+}
+
+/// Adapts a [`Runner`] to the [`AsyncRunner`] interface by running each call
+/// on Tokio's blocking thread pool.
+///
+/// This must be used from within a Tokio runtime.
+///
+/// Unlike other implementations of [`AsyncRunner`], this can't cancel an
+/// operation. Dropping one of the returned futures only stops waiting for
+/// the call. The call itself continues on its thread, and any commands it
+/// started, like `docker` or `bwrap`, keep running until they finish or
+/// until `cub` is interrupted (see [`cancel`](crate::cancel)).
+pub struct Blocking(Arc<dyn Runner>);
+
+impl Blocking {
+    /// Wraps `runner`.
+    pub fn new(runner: Arc<dyn Runner>) -> Self {
+        Self(runner)
+    }
+
+    /// Runs `f` with the runner on the blocking thread pool.
+    fn spawn<T, F>(&self, f: F) -> BoxFuture<'static, Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Runner) -> Result<T> + Send + 'static,
+    {
+        let runner = self.0.clone();
+        let handle = tokio::task::spawn_blocking(move || f(runner.as_ref()));
+        Box::pin(async move {
+            match handle.await {
+                Ok(result) => result,
+                Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                Err(e) => Err(anyhow!(e)),
+            }
+        })
+    }
+}
+
+impl AsyncRunner for Blocking {
+    fn list(&self) -> BoxFuture<'_, Result<Vec<EnvironmentName>>> {
+        self.spawn(|runner| runner.list())
+    }
+
+    fn copy_out_from_home<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<u8>>> {
+        let name = name.clone();
+        let path = PathBuf::from(path);
+        self.spawn(move |runner| {
+            let mut buf = Vec::new();
+            runner.copy_out_from_home(&name, &path, &mut buf)?;
+            Ok(buf)
+        })
+    }
+
+    fn copy_out_from_work<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<Vec<u8>>> {
+        let name = name.clone();
+        let path = PathBuf::from(path);
+        self.spawn(move |runner| {
+            let mut buf = Vec::new();
+            runner.copy_out_from_work(&name, &path, &mut buf)?;
+            Ok(buf)
+        })
+    }
+
+    fn create<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+        init: &'a Init,
+    ) -> BoxFuture<'a, Result<()>> {
+        let name = name.clone();
+        let init = init.clone();
+        self.spawn(move |runner| runner.create(&name, &init))
+    }
+
+    fn exists<'a>(&'a self, name: &'a EnvironmentName) -> BoxFuture<'a, Result<EnvironmentExists>> {
+        let name = name.clone();
+        self.spawn(move |runner| runner.exists(&name))
+    }
+
+    fn supports_golden(&self) -> bool {
+        self.0.supports_golden()
+    }
+
+    fn available_debian_packages(&self) -> BoxFuture<'_, Result<Option<BTreeSet<String>>>> {
+        self.spawn(|runner| runner.available_debian_packages())
+    }
+
+    fn files_summary<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+    ) -> BoxFuture<'a, Result<EnvFilesSummary>> {
+        let name = name.clone();
+        self.spawn(move |runner| runner.files_summary(&name))
+    }
+
+    fn networks<'a>(&'a self, name: &'a EnvironmentName) -> BoxFuture<'a, Result<Vec<EnvNetwork>>> {
+        let name = name.clone();
+        self.spawn(move |runner| runner.networks(&name))
+    }
+
+    fn stop<'a>(&'a self, name: &'a EnvironmentName) -> BoxFuture<'a, Result<()>> {
+        let name = name.clone();
+        self.spawn(move |runner| runner.stop(&name))
+    }
+
+    fn restarts_after_stop(&self) -> bool {
+        self.0.restarts_after_stop()
+    }
+
+    fn reset<'a>(&'a self, name: &'a EnvironmentName, init: &'a Init) -> BoxFuture<'a, Result<()>> {
+        let name = name.clone();
+        let init = init.clone();
+        self.spawn(move |runner| runner.reset(&name, &init))
+    }
+
+    fn inspect<'a>(&'a self, name: &'a EnvironmentName) -> BoxFuture<'a, Result<Vec<EnvPiece>>> {
+        let name = name.clone();
+        self.spawn(move |runner| runner.inspect(&name))
+    }
+
+    fn repair<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+        init: &'a Init,
+    ) -> BoxFuture<'a, Result<()>> {
+        let name = name.clone();
+        let init = init.clone();
+        self.spawn(move |runner| runner.repair(&name, &init))
+    }
+
+    fn purge<'a>(&'a self, name: &'a EnvironmentName) -> BoxFuture<'a, Result<()>> {
+        let name = name.clone();
+        self.spawn(move |runner| runner.purge(&name))
+    }
+
+    fn purge_dir<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+        dir: EnvDir,
+    ) -> BoxFuture<'a, Result<()>> {
+        let name = name.clone();
+        self.spawn(move |runner| runner.purge_dir(&name, dir))
+    }
+
+    fn run<'a>(
+        &'a self,
+        name: &'a EnvironmentName,
+        command: &'a OwnedRunnerCommand,
+    ) -> BoxFuture<'a, Result<()>> {
+        let name = name.clone();
+        let command = command.clone();
+        self.spawn(move |runner| runner.run(&name, &command.as_command()))
+    }
+
+    fn supports_any<'a>(&'a self, targets: &'a [Target]) -> BoxFuture<'a, Result<bool>> {
+        let targets = targets.to_vec();
+        self.spawn(move |runner| runner.supports_any(&targets))
+    }
+
+    fn capabilities(&self) -> BTreeSet<Capability> {
+        self.0.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockRunner;
+    use std::str::FromStr;
+
+    #[test]
+    fn blocking() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mock = MockRunner::new();
+        let runner = Blocking::new(Arc::new(mock.clone()));
+        let foo = EnvironmentName::from_str("foo").unwrap();
+        let bar = EnvironmentName::from_str("bar").unwrap();
+        let init = Init {
+            debian_packages: Vec::new(),
+            env_vars: Vec::new(),
+            seeds: Vec::new(),
+        };
+        runtime.block_on(async {
+            runner.create(&foo, &init).await.unwrap();
+            mock.set_home_file(&foo, Path::new("x"), b"home x").unwrap();
+
+            let list = runner.list();
+            let exists = runner.exists(&bar);
+            let copy = runner.copy_out_from_home(&foo, Path::new("x"));
+            assert_eq!(vec![foo.clone()], list.await.unwrap());
+            assert_eq!(EnvironmentExists::NoEnvironment, exists.await.unwrap());
+            assert_eq!(b"home x", copy.await.unwrap().as_slice());
+
+            let command = OwnedRunnerCommand::Exec {
+                command: vec![String::from("true")],
+                env_vars: Vec::new(),
+            };
+            runner.run(&foo, &command).await.unwrap();
+            mock.fail_runs_in(&foo);
+            let error = runner.run(&foo, &command).await.unwrap_err().to_string();
+            assert_eq!("command failed in environment \"foo\"", error);
+        });
+        assert_eq!(
+            vec!["create foo", "run foo: true", "run foo: true"],
+            mock.log()
+        );
+    }
+}
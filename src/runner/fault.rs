@@ -0,0 +1,396 @@
+//! Injects failures into a runner, for testing error paths like rollback and
+//! `cub repair`.
+//!
+//! This is enabled by setting `CUBICLE_FAULTS` to a comma-separated list of
+//! fault points, each optionally followed by `@` and an environment name to
+//! limit it to that environment. For example,
+//! `CUBICLE_FAULTS=init@foo,exec@package-rust` fails initializing environment
+//! `foo` and running any command in the `rust` package's builder environment.
+//! See [`Fault`] for the points.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::{
+    Capability, CodeTarget, EnvDir, EnvFilesSummary, EnvNetwork, EnvPiece, EnvironmentExists,
+    FileListing, HostPath, Init, Runner, RunnerCommand, Sharing, Target,
+};
+use crate::somehow::{somehow as anyhow, warn_brief, Context, Error, Result};
+use crate::EnvironmentName;
+
+/// The environment variable that lists faults to inject.
+const FAULTS_VAR: &str = "CUBICLE_FAULTS";
+
+/// A point where a failure can be injected.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Fault {
+    /// Fail to copy the package seeds into a new or reset environment, before
+    /// anything is created (`seed`).
+    Seed,
+    /// Fail while running the environment's initialization script, after it
+    /// has been created or reset (`init`). This leaves a broken environment
+    /// behind, like a real failure would.
+    Init,
+    /// Fail to run a command in the environment, like `docker exec` failing
+    /// (`exec`).
+    Exec,
+    /// Fail to copy a file out of the environment, like a package's
+    /// `provides.tar` (`copy-out`).
+    CopyOut,
+}
+
+impl Fault {
+    const ALL: [Self; 4] = [Self::Seed, Self::Init, Self::Exec, Self::CopyOut];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Seed => "seed",
+            Self::Init => "init",
+            Self::Exec => "exec",
+            Self::CopyOut => "copy-out",
+        }
+    }
+}
+
+/// The faults to inject, each for one environment or for all of them.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct Faults(BTreeSet<(Fault, Option<EnvironmentName>)>);
+
+impl Faults {
+    fn parse(value: &str) -> Result<Self> {
+        let mut faults = BTreeSet::new();
+        for item in value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            let (point, env) = match item.split_once('@') {
+                Some((point, env)) => (point, Some(EnvironmentName::from_str(env)?)),
+                None => (item, None),
+            };
+            let fault = Fault::ALL
+                .into_iter()
+                .find(|fault| fault.as_str() == point)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "unknown fault point {point:?} (expected one of: {})",
+                        Fault::ALL.map(Fault::as_str).join(", ")
+                    )
+                })?;
+            faults.insert((fault, env));
+        }
+        Ok(Self(faults))
+    }
+
+    fn check(&self, fault: Fault, name: &EnvironmentName) -> Result<()> {
+        if self.0.contains(&(fault, None)) || self.0.contains(&(fault, Some(name.clone()))) {
+            return Err(injected(fault, name));
+        }
+        Ok(())
+    }
+}
+
+fn injected(fault: Fault, name: &EnvironmentName) -> Error {
+    anyhow!(
+        "injected fault {:?} in environment {name} (from {FAULTS_VAR})",
+        fault.as_str()
+    )
+}
+
+/// Wraps `runner` to inject the faults listed in `CUBICLE_FAULTS`, if any.
+pub(crate) fn wrap_from_env(runner: Box<dyn Runner>) -> Result<Box<dyn Runner>> {
+    let Some(value) = std::env::var_os(FAULTS_VAR) else {
+        return Ok(runner);
+    };
+    let value = value
+        .to_str()
+        .ok_or_else(|| anyhow!("{FAULTS_VAR} is not valid UTF-8"))?;
+    let faults = Faults::parse(value).with_context(|| format!("invalid {FAULTS_VAR}"))?;
+    if faults.0.is_empty() {
+        return Ok(runner);
+    }
+    warn_brief(format!("injecting faults from {FAULTS_VAR}={value}"));
+    Ok(Box::new(FaultyRunner {
+        inner: runner,
+        faults,
+    }))
+}
+
+/// A runner that fails at chosen points and otherwise defers to another
+/// runner.
+struct FaultyRunner {
+    inner: Box<dyn Runner>,
+    faults: Faults,
+}
+
+impl FaultyRunner {
+    /// Runs `f`, which creates or resets the environment, injecting seed and
+    /// init faults around it.
+    fn initialize(&self, name: &EnvironmentName, f: impl FnOnce() -> Result<()>) -> Result<()> {
+        self.faults.check(Fault::Seed, name)?;
+        f()?;
+        self.faults.check(Fault::Init, name)
+    }
+}
+
+impl Runner for FaultyRunner {
+    fn list(&self) -> Result<Vec<EnvironmentName>> {
+        self.inner.list()
+    }
+
+    fn copy_out_from_home(
+        &self,
+        name: &EnvironmentName,
+        path: &Path,
+        w: &mut dyn io::Write,
+    ) -> Result<()> {
+        self.faults.check(Fault::CopyOut, name)?;
+        self.inner.copy_out_from_home(name, path, w)
+    }
+
+    fn copy_out_from_work(
+        &self,
+        name: &EnvironmentName,
+        path: &Path,
+        w: &mut dyn io::Write,
+    ) -> Result<()> {
+        self.faults.check(Fault::CopyOut, name)?;
+        self.inner.copy_out_from_work(name, path, w)
+    }
+
+    fn backup(&self, name: &EnvironmentName, w: &mut dyn io::Write) -> Result<()> {
+        self.inner.backup(name, w)
+    }
+
+    fn save_home_paths(
+        &self,
+        name: &EnvironmentName,
+        paths: &[PathBuf],
+        w: &mut dyn io::Write,
+    ) -> Result<()> {
+        self.faults.check(Fault::CopyOut, name)?;
+        self.inner.save_home_paths(name, paths, w)
+    }
+
+    fn create(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        self.initialize(name, || self.inner.create(name, init))
+    }
+
+    fn create_encrypted(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        self.initialize(name, || self.inner.create_encrypted(name, init))
+    }
+
+    fn is_encrypted(&self, name: &EnvironmentName) -> Result<bool> {
+        self.inner.is_encrypted(name)
+    }
+
+    fn create_shared(&self, name: &EnvironmentName, init: &Init, group: &str) -> Result<()> {
+        self.initialize(name, || self.inner.create_shared(name, init, group))
+    }
+
+    fn create_with_bind_mounts(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        self.initialize(name, || self.inner.create_with_bind_mounts(name, init))
+    }
+
+    fn sharing(&self, name: &EnvironmentName) -> Result<Option<Sharing>> {
+        self.inner.sharing(name)
+    }
+
+    fn exists(&self, name: &EnvironmentName) -> Result<EnvironmentExists> {
+        self.inner.exists(name)
+    }
+
+    fn supports_golden(&self) -> bool {
+        self.inner.supports_golden()
+    }
+
+    fn supports_encryption(&self) -> bool {
+        self.inner.supports_encryption()
+    }
+
+    fn supports_bind_mounts(&self) -> bool {
+        self.inner.supports_bind_mounts()
+    }
+
+    fn supports_sharing(&self) -> bool {
+        self.inner.supports_sharing()
+    }
+
+    fn supports_read_only_home(&self) -> bool {
+        self.inner.supports_read_only_home()
+    }
+
+    fn supports_worktrees(&self) -> bool {
+        self.inner.supports_worktrees()
+    }
+
+    fn supports_host_open(&self) -> bool {
+        self.inner.supports_host_open()
+    }
+
+    fn supports_caches(&self) -> bool {
+        self.inner.supports_caches()
+    }
+
+    fn available_debian_packages(&self) -> Result<Option<BTreeSet<String>>> {
+        self.inner.available_debian_packages()
+    }
+
+    fn base_images(&self) -> Result<Vec<String>> {
+        self.inner.base_images()
+    }
+
+    fn debian_packages(&self, name: &EnvironmentName) -> Result<Option<BTreeSet<String>>> {
+        self.inner.debian_packages(name)
+    }
+
+    fn base_debian_packages(&self) -> BTreeSet<String> {
+        self.inner.base_debian_packages()
+    }
+
+    fn image(&self, name: &EnvironmentName) -> Result<Option<String>> {
+        self.inner.image(name)
+    }
+
+    fn files_summary(&self, name: &EnvironmentName) -> Result<EnvFilesSummary> {
+        self.inner.files_summary(name)
+    }
+
+    fn watch_work_dir(
+        &self,
+        name: &EnvironmentName,
+        path: &Path,
+        interval: Duration,
+        f: &mut dyn FnMut(FileListing) -> Result<()>,
+    ) -> Result<()> {
+        self.inner.watch_work_dir(name, path, interval, f)
+    }
+
+    fn networks(&self, name: &EnvironmentName) -> Result<Vec<EnvNetwork>> {
+        self.inner.networks(name)
+    }
+
+    fn stop(&self, name: &EnvironmentName) -> Result<()> {
+        self.inner.stop(name)
+    }
+
+    fn restarts_after_stop(&self) -> bool {
+        self.inner.restarts_after_stop()
+    }
+
+    fn start(&self, name: &EnvironmentName) -> Result<()> {
+        self.inner.start(name)
+    }
+
+    fn reset(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        self.initialize(name, || self.inner.reset(name, init))
+    }
+
+    fn reseed(&self, name: &EnvironmentName, seeds: &[HostPath]) -> Result<()> {
+        self.faults.check(Fault::Seed, name)?;
+        self.inner.reseed(name, seeds)
+    }
+
+    fn inspect(&self, name: &EnvironmentName) -> Result<Vec<EnvPiece>> {
+        self.inner.inspect(name)
+    }
+
+    fn code_target(&self, name: &EnvironmentName) -> Result<CodeTarget> {
+        self.inner.code_target(name)
+    }
+
+    fn username(&self, name: &EnvironmentName) -> Result<String> {
+        self.inner.username(name)
+    }
+
+    fn repair(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        self.initialize(name, || self.inner.repair(name, init))
+    }
+
+    fn purge(&self, name: &EnvironmentName) -> Result<()> {
+        self.inner.purge(name)
+    }
+
+    fn purge_dir(&self, name: &EnvironmentName, dir: EnvDir) -> Result<()> {
+        self.inner.purge_dir(name, dir)
+    }
+
+    fn run(&self, name: &EnvironmentName, command: &RunnerCommand) -> Result<()> {
+        self.faults.check(Fault::Exec, name)?;
+        self.inner.run(name, command)
+    }
+
+    fn supports_any(&self, targets: &[Target]) -> Result<bool> {
+        self.inner.supports_any(targets)
+    }
+
+    fn capabilities(&self) -> BTreeSet<Capability> {
+        self.inner.capabilities()
+    }
+
+    fn storage_dirs(&self) -> Result<Vec<HostPath>> {
+        self.inner.storage_dirs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockRunner;
+
+    fn env(name: &str) -> EnvironmentName {
+        EnvironmentName::from_str(name).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Faults::default(), Faults::parse("").unwrap());
+        assert_eq!(
+            Faults(BTreeSet::from([
+                (Fault::Init, Some(env("foo"))),
+                (Fault::Exec, None),
+                (Fault::CopyOut, None),
+            ])),
+            Faults::parse("init@foo, exec,copy-out").unwrap()
+        );
+        let error = Faults::parse("seed,docker").unwrap_err();
+        assert_eq!(
+            "unknown fault point \"docker\" (expected one of: seed, init, exec, copy-out)",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn inject() {
+        let mock = MockRunner::new();
+        let runner = FaultyRunner {
+            inner: Box::new(mock.clone()),
+            faults: Faults::parse("seed@a,init@b,exec@b").unwrap(),
+        };
+        let init = Init {
+            debian_packages: Vec::new(),
+            env_vars: Vec::new(),
+            seeds: Vec::new(),
+        };
+
+        let error = runner.create(&env("a"), &init).unwrap_err();
+        assert_eq!(
+            "injected fault \"seed\" in environment \"a\" (from CUBICLE_FAULTS)",
+            error.to_string()
+        );
+        assert!(mock.environment(&env("a")).is_none());
+
+        runner.create(&env("b"), &init).unwrap_err();
+        assert!(mock.environment(&env("b")).is_some());
+        let command = [String::from("true")];
+        let command = RunnerCommand::Pipe { command: &command };
+        runner.run(&env("b"), &command).unwrap_err();
+
+        runner.create(&env("c"), &init).unwrap();
+        runner.run(&env("c"), &command).unwrap();
+        assert_eq!(vec!["create b", "create c", "run c: true"], mock.log());
+    }
+}
@@ -0,0 +1,355 @@
+//! Scans environments for known vulnerabilities, for `cub scan`.
+//!
+//! This runs an external scanner: Trivy if it's installed, otherwise Grype.
+//! Docker environments are scanned through their containers' images, which
+//! include the base image and the environment's Debian packages. The other
+//! runners use the host's system files, so for those, this lists the
+//! environment's Debian packages, with their versions from the host's dpkg
+//! database, in a CycloneDX software bill of materials (SBOM) and scans that.
+
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::Path;
+
+use super::command_ext::Command;
+use super::{Cubicle, EnvironmentName, Event, Runner};
+use crate::somehow::{somehow as anyhow, warn, Context, ErrorKind, Result};
+
+/// What to hand to the scanner.
+enum Target<'a> {
+    /// A Docker image name or ID.
+    Image(&'a str),
+    /// The path of a CycloneDX JSON file.
+    Sbom(&'a Path),
+}
+
+/// A Debian package installed on the host, from `dpkg-query`.
+#[derive(Debug, Eq, PartialEq)]
+struct Installed {
+    version: String,
+    arch: String,
+    source: String,
+    source_version: String,
+}
+
+impl Cubicle {
+    /// Corresponds to `cub scan --images`.
+    ///
+    /// Scans the images that the runner creates environments from.
+    pub fn scan_images(&self) -> Result<()> {
+        let images = self.runner.base_images()?;
+        if images.is_empty() {
+            return Err(anyhow!(
+                "there are no images to scan (only the Docker runner uses them, \
+                once it has created an environment)"
+            ));
+        }
+        for image in images {
+            self.shared.event(Event::ScanningImage(image.clone()));
+            run_scanner(Target::Image(&image))
+                .with_context(|| format!("failed to scan image {image:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Corresponds to `cub scan`.
+    ///
+    /// Scans the Debian packages in the environment, as described in the
+    /// module documentation.
+    pub fn scan_environment(&self, name: &EnvironmentName) -> Result<()> {
+        self.check_fully_exists(name)?;
+        self.shared.event(Event::ScanningEnvironment(name.clone()));
+        if let Some(image) = self.runner.image(name)? {
+            return run_scanner(Target::Image(&image))
+                .with_context(|| format!("failed to scan image of environment {name}"));
+        }
+
+        let packages = self
+            .read_package_list_from_env(name)
+            .with_context(|| format!("failed to parse `packages.txt` from {name}"))?;
+        let specs = self.scan_packages()?;
+        let debian_packages = self.resolve_debian_packages(&packages, &specs)?;
+        if debian_packages.is_empty() {
+            warn(anyhow!(
+                "environment {name} has no Debian packages to scan \
+                (its other system files come from the host)"
+            ));
+            return Ok(());
+        }
+
+        let installed = installed_debian_packages()?;
+        let mut found = BTreeMap::new();
+        for package in &debian_packages {
+            match installed.get(package.as_str()) {
+                Some(details) => {
+                    found.insert(package.as_str(), details);
+                }
+                None => warn(anyhow!(
+                    "Debian package {package} is not installed on the host, so it \
+                    can't be scanned"
+                )),
+            }
+        }
+        let sbom = sbom(name, &os_release()?, &found);
+        let mut file = tempfile::Builder::new()
+            .prefix("cubicle-sbom-")
+            .suffix(".json")
+            .tempfile()
+            .context("failed to create temporary file for SBOM")?;
+        serde_json::to_writer_pretty(&mut file, &sbom)
+            .map_err(std::io::Error::from)
+            .and_then(|()| file.flush())
+            .context("failed to write SBOM")?;
+        run_scanner(Target::Sbom(file.path()))
+            .with_context(|| format!("failed to scan Debian packages of environment {name}"))
+    }
+}
+
+/// Runs the first scanner that's installed, letting it print its report.
+fn run_scanner(target: Target) -> Result<()> {
+    for program in ["trivy", "grype"] {
+        let mut command = Command::new(program);
+        match (program, &target) {
+            ("trivy", Target::Image(image)) => {
+                command.args(["image", "--scanners", "vuln"]).arg(image);
+            }
+            ("trivy", Target::Sbom(path)) => {
+                command.arg("sbom").arg(path);
+            }
+            (_, Target::Image(image)) => {
+                command.arg(format!("docker:{image}"));
+            }
+            (_, Target::Sbom(path)) => {
+                let mut arg = std::ffi::OsString::from("sbom:");
+                arg.push(path);
+                command.arg(arg);
+            }
+        }
+        match command.status() {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => return Err(anyhow!("`{program}` exited with {status}")),
+            Err(e) if e.kind() == Some(ErrorKind::ProgramNotFound) => continue,
+            Err(e) => return Err(e).with_context(|| format!("failed to run `{program}`")),
+        }
+    }
+    Err(anyhow!(
+        "scanning needs Trivy (`trivy`) or Grype (`grype`) to be installed"
+    ))
+}
+
+/// Returns the Debian packages installed on the host, keyed by name.
+fn installed_debian_packages() -> Result<BTreeMap<String, Installed>> {
+    let output = match Command::new("dpkg-query")
+        .args(["--show", "--showformat"])
+        .arg("${db:Status-Abbrev}\t${Package}\t${Version}\t${Architecture}\t${source:Package}\t${source:Version}\n")
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == Some(ErrorKind::ProgramNotFound) => {
+            return Err(e.context("dpkg-query is needed to find the host's Debian packages"));
+        }
+        Err(e) => return Err(e).context("failed to run dpkg-query"),
+    };
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`dpkg-query --show` exited with {} and stderr: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(parse_dpkg_query(
+        std::str::from_utf8(&output.stdout).context("`dpkg-query` output not valid UTF-8")?,
+    ))
+}
+
+/// Parses the output of `dpkg-query` from [`installed_debian_packages`],
+/// skipping packages that aren't fully installed.
+fn parse_dpkg_query(output: &str) -> BTreeMap<String, Installed> {
+    let mut packages = BTreeMap::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [status, name, version, arch, source, source_version] = fields[..] else {
+            continue;
+        };
+        if status.trim() != "ii" {
+            continue;
+        }
+        packages.insert(
+            name.to_owned(),
+            Installed {
+                version: version.to_owned(),
+                arch: arch.to_owned(),
+                source: source.to_owned(),
+                source_version: source_version.to_owned(),
+            },
+        );
+    }
+    packages
+}
+
+/// Returns the host's distribution ID and version, like `("debian", "12")`.
+fn os_release() -> Result<(String, String)> {
+    let path = ["/etc/os-release", "/usr/lib/os-release"]
+        .into_iter()
+        .find(|path| Path::new(path).exists())
+        .ok_or_else(|| anyhow!("could not find os-release file to identify the host's OS"))?;
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {path:?}"))?;
+    Ok(parse_os_release(&contents))
+}
+
+fn parse_os_release(contents: &str) -> (String, String) {
+    let mut id = String::from("debian");
+    let mut version = String::new();
+    for line in contents.lines() {
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim().trim_matches(['"', '\'']).to_owned();
+            match key.trim() {
+                "ID" => id = value,
+                "VERSION_ID" => version = value,
+                _ => {}
+            }
+        }
+    }
+    (id, version)
+}
+
+/// Returns a CycloneDX SBOM listing the given Debian packages.
+///
+/// The package URLs include the qualifiers that Grype reads, and the
+/// properties are the ones Trivy reads, so that both can match the packages
+/// to the Debian security tracker, which is keyed by source package.
+fn sbom(
+    name: &EnvironmentName,
+    (os_id, os_version): &(String, String),
+    packages: &BTreeMap<&str, &Installed>,
+) -> serde_json::Value {
+    let distro = format!("{os_id}-{os_version}");
+    let mut refs = BTreeSet::new();
+    let mut components = vec![json!({
+        "type": "operating-system",
+        "bom-ref": "os",
+        "name": os_id,
+        "version": os_version,
+    })];
+    for (package, installed) in packages {
+        let (epoch, version) = match installed.version.split_once(':') {
+            Some((epoch, version)) => (Some(epoch), version),
+            None => (None, installed.version.as_str()),
+        };
+        let mut purl = format!(
+            "pkg:deb/{os_id}/{}@{}?arch={}",
+            purl_encode(package),
+            purl_encode(version),
+            purl_encode(&installed.arch),
+        );
+        if let Some(epoch) = epoch {
+            purl.push_str(&format!("&epoch={}", purl_encode(epoch)));
+        }
+        purl.push_str(&format!(
+            "&upstream={}&distro={}",
+            purl_encode(&format!(
+                "{}@{}",
+                installed.source, installed.source_version
+            )),
+            purl_encode(&distro)
+        ));
+        refs.insert(purl.clone());
+        components.push(json!({
+            "type": "library",
+            "bom-ref": purl,
+            "name": package,
+            "version": installed.version,
+            "purl": purl,
+            "properties": [
+                {"name": "aquasecurity:trivy:PkgType", "value": os_id},
+                {"name": "aquasecurity:trivy:SrcName", "value": installed.source},
+                {"name": "aquasecurity:trivy:SrcVersion", "value": installed.source_version},
+            ],
+        }));
+    }
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "version": 1,
+        "metadata": {
+            "component": {
+                "type": "application",
+                "bom-ref": "environment",
+                "name": name.as_str(),
+            },
+        },
+        "components": components,
+        "dependencies": [
+            {"ref": "environment", "dependsOn": ["os"]},
+            {"ref": "os", "dependsOn": refs},
+        ],
+    })
+}
+
+/// Percent-encodes a package URL component.
+fn purl_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_' | b'~') {
+            encoded.push(char::from(b));
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_dpkg_query() {
+        let packages = super::parse_dpkg_query(
+            "ii \tgit\t1:2.39.5-0+deb12u1\tamd64\tgit\t1:2.39.5-0+deb12u1\n\
+             rc \told\t1.0\tamd64\told\t1.0\n\
+             ii \tlibstdc++6\t12.2.0-14\tamd64\tgcc-12\t12.2.0-14\n",
+        );
+        assert_eq!(
+            vec!["git", "libstdc++6"],
+            packages.keys().collect::<Vec<_>>()
+        );
+        assert_eq!("gcc-12", packages["libstdc++6"].source);
+    }
+
+    #[test]
+    fn parse_os_release() {
+        assert_eq!(
+            (String::from("debian"), String::from("12")),
+            super::parse_os_release(
+                "PRETTY_NAME=\"Debian GNU/Linux 12 (bookworm)\"\nVERSION_ID=\"12\"\nID=debian\n"
+            )
+        );
+    }
+
+    #[test]
+    fn sbom() {
+        let git = Installed {
+            version: String::from("1:2.39.5-0+deb12u1"),
+            arch: String::from("amd64"),
+            source: String::from("git"),
+            source_version: String::from("1:2.39.5-0+deb12u1"),
+        };
+        let sbom = super::sbom(
+            &EnvironmentName::from_str("dev").unwrap(),
+            &(String::from("debian"), String::from("12")),
+            &BTreeMap::from([("git", &git)]),
+        );
+        expect![[r#"
+            pkg:deb/debian/git@2.39.5-0%2Bdeb12u1?arch=amd64&epoch=1&upstream=git%401%3A2.39.5-0%2Bdeb12u1&distro=debian-12"#]]
+        .assert_eq(sbom["components"][1]["purl"].as_str().unwrap());
+        assert_eq!(
+            sbom["components"][1]["purl"],
+            sbom["dependencies"][1]["dependsOn"][0]
+        );
+    }
+}
@@ -0,0 +1,385 @@
+//! Helpers to install and check the seccomp policies used by the runners.
+//!
+//! See the "Seccomp" sections in `docs/Bubblewrap.md` and `docs/Docker.md`
+//! for background.
+
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use super::os_util::host_home_dir;
+use super::paths::xdg_data_home;
+use super::{HostPath, RunnerKind};
+use crate::somehow::{somehow as anyhow, Context, LowLevelResult, Result};
+
+// TODO: Pin these to a real moby commit and the SHA-256 hashes of the
+// downloaded files. Until then, `cub seccomp install` refuses every download.
+
+/// Docker's default seccomp policy, which gets patched before installing.
+/// This is pinned to a moby commit so that its contents can't change.
+const DOCKER_POLICY_URL: &str = "https://raw.githubusercontent.com/moby/moby/\
+    0000000000000000000000000000000000000000/profiles/seccomp/default.json";
+
+/// The expected SHA-256 hash of the file at [`DOCKER_POLICY_URL`], before
+/// patching.
+const DOCKER_POLICY_SHA256: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// A compiled BPF filter extracted from a running Podman container on amd64.
+const BUBBLEWRAP_POLICY_URL: &str = "https://ongardie.net/static/podman.bpf";
+
+/// The expected SHA-256 hash of the file at [`BUBBLEWRAP_POLICY_URL`].
+const BUBBLEWRAP_POLICY_SHA256: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The size of a single BPF instruction (`struct sock_filter`).
+const BPF_INSTRUCTION_SIZE: u64 = 8;
+
+/// Corresponds to `cub seccomp install`.
+///
+/// Downloads the recommended seccomp policy for the runner named in the
+/// configuration file, saves it into the Cubicle data directory, and points
+/// the configuration file's `seccomp` setting to it. Returns the path of the
+/// installed policy.
+///
+/// This does not need a [`Cubicle`](super::Cubicle) instance, since the
+/// configuration may not be valid until a seccomp policy is set.
+pub fn install_seccomp_policy(config_path: &Path) -> Result<HostPath> {
+    let config = std::fs::read_to_string(config_path)
+        .with_context(|| format!("Failed to read config file: {config_path:?}"))?;
+    let mut doc = config
+        .parse::<toml_edit::DocumentMut>()
+        .with_context(|| format!("Failed to parse config file: {config_path:?}"))?;
+    let runner = config_runner(&doc)
+        .with_context(|| format!("Failed to parse config file: {config_path:?}"))?;
+
+    let dir = seccomp_dir()?;
+    let path = match runner {
+        RunnerKind::Bubblewrap => {
+            let path = dir.join("bubblewrap-seccomp.bpf");
+            install_bubblewrap_policy(&path)?;
+            path
+        }
+        RunnerKind::Docker => {
+            let path = dir.join("docker-seccomp.json");
+            install_docker_policy(&path)?;
+            path
+        }
+        RunnerKind::User => {
+            return Err(anyhow!(
+                "The user runner does not use a seccomp policy (nothing to install)"
+            ));
+        }
+        RunnerKind::Auto => {
+            return Err(anyhow!(
+                "Set `runner` to \"bubblewrap\" or \"docker\" in the configuration file \
+                to install the seccomp policy for that runner"
+            ));
+        }
+    };
+
+    set_config_seccomp(&mut doc, runner, path.as_host_raw())?;
+    std::fs::write(config_path, doc.to_string())
+        .with_context(|| format!("Failed to write config file: {config_path:?}"))?;
+    Ok(path)
+}
+
+fn seccomp_dir() -> Result<HostPath> {
+    Ok(xdg_data_home(host_home_dir())?
+        .join("cubicle")
+        .join("seccomp"))
+}
+
+fn config_runner(doc: &toml_edit::DocumentMut) -> LowLevelResult<RunnerKind> {
+    let runner = doc
+        .get("runner")
+        .and_then(|runner| runner.as_str())
+        .ok_or_else(|| anyhow!("missing string field `runner`"))?;
+    Ok(toml::Value::String(runner.to_owned()).try_into()?)
+}
+
+fn set_config_seccomp(
+    doc: &mut toml_edit::DocumentMut,
+    runner: RunnerKind,
+    path: &Path,
+) -> Result<()> {
+    let key = match runner {
+        RunnerKind::Bubblewrap => "bubblewrap",
+        RunnerKind::Docker => "docker",
+        RunnerKind::User | RunnerKind::Auto => {
+            unreachable!("{} runner has no seccomp setting", runner.as_str())
+        }
+    };
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow!("seccomp policy path is not valid UTF-8: {path:?}"))?;
+    let table = doc
+        .entry(key)
+        .or_insert_with(toml_edit::table)
+        .as_table_like_mut()
+        .ok_or_else(|| anyhow!("expected `{key}` to be a table in config file"))?;
+    table.insert("seccomp", toml_edit::value(path));
+    Ok(())
+}
+
+/// Downloads the policy at `url`, failing if its SHA-256 hash isn't
+/// `sha256`.
+fn download(url: &str, sha256: &str) -> Result<Vec<u8>> {
+    let body = reqwest::blocking::get(url)
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|resp| resp.bytes())
+        .with_context(|| format!("error downloading seccomp policy from {url:?}"))?;
+    check_sha256(&body, sha256)
+        .enough_context()
+        .with_context(|| format!("downloaded seccomp policy from {url:?} looks wrong"))?;
+    Ok(body.to_vec())
+}
+
+fn check_sha256(contents: &[u8], expected: &str) -> LowLevelResult<()> {
+    let actual = Sha256::digest(contents)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    if actual != expected {
+        return Err(anyhow!("expected SHA-256 hash {expected}, got {actual}").into());
+    }
+    Ok(())
+}
+
+fn write_policy(path: &HostPath, policy: &[u8]) -> Result<()> {
+    let path = path.as_host_raw();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create directory: {dir:?}"))?;
+    }
+    std::fs::write(path, policy)
+        .with_context(|| format!("failed to write seccomp policy: {path:?}"))?;
+    Ok(())
+}
+
+fn install_docker_policy(path: &HostPath) -> Result<()> {
+    let policy = download(DOCKER_POLICY_URL, DOCKER_POLICY_SHA256)?;
+    let policy =
+        String::from_utf8(policy).context("downloaded Docker seccomp policy is not valid UTF-8")?;
+    let policy = patch_docker_policy(&policy)?;
+    check_docker_policy_contents(policy.as_bytes())
+        .enough_context()
+        .context("downloaded Docker seccomp policy looks wrong")?;
+    write_policy(path, policy.as_bytes())
+}
+
+/// Allows `clone` and `unshare` unconditionally, which the Chromium sandbox
+/// needs. See `docs/Docker.md`.
+fn patch_docker_policy(policy: &str) -> Result<String> {
+    let needle = "\"getpid\",";
+    if policy.matches(needle).count() != 1 {
+        return Err(anyhow!(
+            "could not find where to allow `clone` and `unshare` in \
+            Docker seccomp policy (expected exactly one {needle:?})"
+        ));
+    }
+    Ok(policy.replacen(needle, "\"getpid\", \"clone\", \"unshare\",", 1))
+}
+
+fn install_bubblewrap_policy(path: &HostPath) -> Result<()> {
+    if !cfg!(target_arch = "x86_64") {
+        return Err(anyhow!(
+            "The recommended Bubblewrap seccomp filter is only available \
+            for amd64, but this is {}. See `docs/Bubblewrap.md` for other \
+            options.",
+            std::env::consts::ARCH
+        ));
+    }
+    let policy = download(BUBBLEWRAP_POLICY_URL, BUBBLEWRAP_POLICY_SHA256)?;
+    check_bpf_len(policy.len() as u64)
+        .enough_context()
+        .context("downloaded Bubblewrap seccomp filter looks wrong")?;
+    write_policy(path, &policy)
+}
+
+/// Checks that the Docker seccomp policy at `path` is readable and looks
+/// reasonable, to give an early error message if it's misconfigured.
+pub fn check_docker_policy(path: &Path, exe_name: &str) -> Result<()> {
+    let check = || -> LowLevelResult<()> { check_docker_policy_contents(&std::fs::read(path)?) };
+    check().enough_context().with_context(|| {
+        format!(
+            "invalid Docker seccomp policy: {path:?} \
+            (hint: run '{exe_name} seccomp install' to install the recommended policy)"
+        )
+    })
+}
+
+fn check_docker_policy_contents(contents: &[u8]) -> LowLevelResult<()> {
+    let policy: serde_json::Value = serde_json::from_slice(contents)?;
+    let Some(policy) = policy.as_object() else {
+        return Err(anyhow!("expected a JSON object").into());
+    };
+    if !policy.get("defaultAction").is_some_and(|a| a.is_string()) {
+        return Err(anyhow!("missing string field `defaultAction`").into());
+    }
+    if !policy.get("syscalls").is_some_and(|s| s.is_array()) {
+        return Err(anyhow!("missing array field `syscalls`").into());
+    }
+    Ok(())
+}
+
+/// Checks that the Bubblewrap seccomp filter at `path` is readable and looks
+/// like a compiled BPF program, to give an early error message if it's
+/// misconfigured.
+pub fn check_bubblewrap_policy(path: &Path, exe_name: &str) -> Result<()> {
+    let check = || -> LowLevelResult<()> {
+        let metadata = std::fs::metadata(path)?;
+        if !metadata.is_file() {
+            return Err(anyhow!("not a regular file").into());
+        }
+        let mut start = [0; 1];
+        let mut file = std::fs::File::open(path)?;
+        if std::io::Read::read(&mut file, &mut start)? == 1 && start[0] == b'{' {
+            return Err(
+                anyhow!("looks like JSON, but Bubblewrap needs a compiled BPF program").into(),
+            );
+        }
+        check_bpf_len(metadata.len())
+    };
+    check().enough_context().with_context(|| {
+        format!(
+            "invalid Bubblewrap seccomp filter: {path:?} \
+            (hint: run '{exe_name} seccomp install' to install the recommended filter)"
+        )
+    })
+}
+
+fn check_bpf_len(len: u64) -> LowLevelResult<()> {
+    if len == 0 {
+        return Err(anyhow!("file is empty").into());
+    }
+    if len % BPF_INSTRUCTION_SIZE != 0 {
+        return Err(anyhow!(
+            "size ({len} bytes) is not a multiple of the BPF instruction size \
+            ({BPF_INSTRUCTION_SIZE} bytes)"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+    use indoc::indoc;
+
+    #[test]
+    fn patch_docker_policy() {
+        assert_eq!(
+            r#"{"names": ["getpgrp", "getpid", "clone", "unshare", "getppid"]}"#,
+            super::patch_docker_policy(r#"{"names": ["getpgrp", "getpid", "getppid"]}"#).unwrap()
+        );
+        assert!(super::patch_docker_policy(r#"{"names": []}"#).is_err());
+    }
+
+    #[test]
+    fn check_sha256() {
+        assert!(super::check_sha256(
+            b"hi",
+            "8f434346648f6b96df89dda901c5176b10a6d83961dd3c1ac88b59b2dc327aa4"
+        )
+        .is_ok());
+        expect![[r#"
+            expected SHA-256 hash 8f434346648f6b96df89dda901c5176b10a6d83961dd3c1ac88b59b2dc327aa4, got 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"#]]
+        .assert_eq(
+            &super::check_sha256(
+                b"hello",
+                "8f434346648f6b96df89dda901c5176b10a6d83961dd3c1ac88b59b2dc327aa4",
+            )
+            .enough_context()
+            .unwrap_err()
+            .to_string(),
+        );
+    }
+
+    #[test]
+    fn check_docker_policy_contents() {
+        assert!(super::check_docker_policy_contents(
+            br#"{"defaultAction": "SCMP_ACT_ERRNO", "syscalls": []}"#
+        )
+        .is_ok());
+        expect!["expected value at line 1 column 1"].assert_eq(
+            &super::check_docker_policy_contents(b"hi")
+                .enough_context()
+                .unwrap_err()
+                .to_string(),
+        );
+        expect!["missing array field `syscalls`"].assert_eq(
+            &super::check_docker_policy_contents(br#"{"defaultAction": "SCMP_ACT_ERRNO"}"#)
+                .enough_context()
+                .unwrap_err()
+                .to_string(),
+        );
+    }
+
+    #[test]
+    fn check_bubblewrap_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("filter.bpf");
+
+        std::fs::write(&path, [0; 16]).unwrap();
+        assert!(super::check_bubblewrap_policy(&path, "cub").is_ok());
+
+        std::fs::write(&path, [0; 12]).unwrap();
+        let err = super::check_bubblewrap_policy(&path, "cub").unwrap_err();
+        assert!(err
+            .debug_without_backtrace()
+            .contains("is not a multiple of the BPF instruction size"));
+
+        std::fs::write(&path, b"{\"defaultAction\":\"\"}").unwrap();
+        let err = super::check_bubblewrap_policy(&path, "cub").unwrap_err();
+        assert!(err.debug_without_backtrace().contains("looks like JSON"));
+    }
+
+    #[test]
+    fn set_config_seccomp() {
+        let mut doc = indoc! {"
+            # my config
+            runner = 'bwrap'
+        "}
+        .parse::<toml_edit::DocumentMut>()
+        .unwrap();
+        assert_eq!(
+            RunnerKind::Bubblewrap,
+            config_runner(&doc).enough_context().unwrap()
+        );
+        super::set_config_seccomp(&mut doc, RunnerKind::Bubblewrap, Path::new("/a.bpf")).unwrap();
+        expect![[r#"
+            # my config
+            runner = 'bwrap'
+
+            [bubblewrap]
+            seccomp = "/a.bpf"
+        "#]]
+        .assert_eq(&doc.to_string());
+
+        let mut doc = indoc! {"
+            runner = 'docker'
+
+            [docker]
+            seccomp = '/old.json'
+            prefix = 'p'
+        "}
+        .parse::<toml_edit::DocumentMut>()
+        .unwrap();
+        assert_eq!(
+            RunnerKind::Docker,
+            config_runner(&doc).enough_context().unwrap()
+        );
+        super::set_config_seccomp(&mut doc, RunnerKind::Docker, Path::new("/new.json")).unwrap();
+        expect![[r#"
+            runner = 'docker'
+
+            [docker]
+            seccomp = "/new.json"
+            prefix = 'p'
+        "#]]
+        .assert_eq(&doc.to_string());
+    }
+}
@@ -0,0 +1,98 @@
+//! Reads the host-side secrets configured in [`Config::secrets`] for a
+//! session.
+//!
+//! [`Config::secrets`]: super::config::Config::secrets
+
+use std::collections::BTreeMap;
+use std::process::Stdio;
+
+use super::command_ext::Command;
+use super::config::Secret;
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// Reads every configured secret and returns them as environment variables.
+///
+/// This runs each time a session starts, so that the values are current and
+/// never need to be stored by Cubicle.
+pub fn read(secrets: &BTreeMap<String, Secret>) -> Result<Vec<(String, String)>> {
+    secrets
+        .iter()
+        .map(|(name, secret)| {
+            let value =
+                read_one(secret).with_context(|| format!("failed to read secret {name}"))?;
+            Ok((name.clone(), value))
+        })
+        .collect()
+}
+
+fn read_one(secret: &Secret) -> Result<String> {
+    let bytes = match secret {
+        Secret::Command(command) => {
+            let Some((program, args)) = command.split_first() else {
+                return Err(anyhow!("secret command must not be empty"));
+            };
+            // Leave standard error connected so that programs like `pass`
+            // can report problems.
+            let output = Command::new(program)
+                .args(args)
+                .stderr(Stdio::inherit())
+                .output()?;
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "secret command {command:?} exited with {}",
+                    output.status
+                ));
+            }
+            output.stdout
+        }
+        Secret::File(path) => {
+            std::fs::read(path).with_context(|| format!("failed to read file {path:?}"))?
+        }
+    };
+    let mut value = String::from_utf8(bytes).context("secret is not valid UTF-8")?;
+    if value.ends_with('\n') {
+        value.pop();
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn read() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("key");
+        std::fs::write(&key, "from file\n\n").unwrap();
+        let secrets = BTreeMap::from([
+            (String::from("A"), Secret::File(key)),
+            (
+                String::from("B"),
+                Secret::Command(vec![String::from("echo"), String::from("from command")]),
+            ),
+        ]);
+        assert_eq!(
+            vec![
+                (String::from("A"), String::from("from file\n")),
+                (String::from("B"), String::from("from command")),
+            ],
+            super::read(&secrets).unwrap()
+        );
+
+        let secrets = BTreeMap::from([(
+            String::from("C"),
+            Secret::File(PathBuf::from("/nonexistent/key")),
+        )]);
+        let error = super::read(&secrets).unwrap_err().to_string();
+        assert!(error.starts_with("failed to read secret C"), "{error}");
+
+        let secrets = BTreeMap::from([(
+            String::from("D"),
+            Secret::Command(vec![String::from("false")]),
+        )]);
+        let error = format!("{:#}", super::read(&secrets).unwrap_err());
+        assert!(error.contains("exited with"), "{error}");
+    }
+}
@@ -0,0 +1,85 @@
+//! Tracks which environments start a different interactive shell than the
+//! `shell` configuration setting picks.
+//!
+//! Each of these environments has a file holding the name or path of its
+//! shell, set with `cub new --shell`.
+
+use std::io;
+
+use super::{EnvironmentName, HostPath};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+pub struct ShellRegistry {
+    dir: HostPath,
+}
+
+impl ShellRegistry {
+    pub fn new(dir: HostPath) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, name: &EnvironmentName) -> HostPath {
+        self.dir.join(name.as_filename())
+    }
+
+    /// Returns the shell recorded for `name`, if any.
+    pub fn get(&self, name: &EnvironmentName) -> Result<Option<String>> {
+        let path = self.path(name);
+        match std::fs::read_to_string(path.as_host_raw()) {
+            Ok(shell) => Ok(Some(shell)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to read {path}")),
+        }
+    }
+
+    /// Records that `name` starts `shell` for interactive sessions.
+    pub fn record(&self, name: &EnvironmentName, shell: &str) -> Result<()> {
+        if shell.is_empty() {
+            return Err(anyhow!("the shell for an environment may not be empty"));
+        }
+        std::fs::create_dir_all(self.dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {}", self.dir))?;
+        let path = self.path(name);
+        std::fs::write(path.as_host_raw(), shell).with_context(|| format!("failed to write {path}"))
+    }
+
+    /// Removes the shell recorded for `name`, if any.
+    pub fn forget(&self, name: &EnvironmentName) -> Result<()> {
+        let path = self.path(name);
+        match std::fs::remove_file(path.as_host_raw()) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("failed to remove {path}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn registry() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir_path = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        let registry = ShellRegistry::new(tmpdir_path.join("shells"));
+        let a = EnvironmentName::from_str("a").unwrap();
+        let b = EnvironmentName::from_str("b/c").unwrap();
+
+        assert_eq!(None, registry.get(&a).unwrap());
+        registry.record(&a, "zsh").unwrap();
+        registry.record(&b, "/usr/bin/fish").unwrap();
+        assert!(registry.record(&a, "").is_err());
+        assert_eq!(Some(String::from("zsh")), registry.get(&a).unwrap());
+        assert_eq!(
+            Some(String::from("/usr/bin/fish")),
+            registry.get(&b).unwrap()
+        );
+
+        registry.forget(&a).unwrap();
+        registry.forget(&a).unwrap();
+        assert_eq!(None, registry.get(&a).unwrap());
+        assert!(registry.get(&b).unwrap().is_some());
+    }
+}
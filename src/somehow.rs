@@ -9,6 +9,7 @@
 //! backtraces. As of Aug 2022, [`std::error::Error::backtrace`] is not yet
 //! stabilized.
 
+use serde::{Deserialize, Serialize};
 use std::fmt::{self, Debug, Display};
 
 /// The normal return type for functions that may fail with
@@ -31,7 +32,83 @@ pub type LowLevelResult<T, E = LowLevelError> = std::result::Result<T, E>;
 /// error makes sense, consider [`LowLevelError`].
 pub struct Error(anyhow::Error);
 
+/// Broad categories of errors that callers may want to handle differently.
+///
+/// Errors carry a kind only when it's known; see [`Error::kind`]. More kinds
+/// may be added in the future.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The named environment does not exist.
+    EnvironmentNotFound,
+    /// An environment with the name already exists.
+    EnvironmentExists,
+    /// The environment only partially exists, in a likely broken state.
+    EnvironmentBroken,
+    /// A package definition could not be found.
+    PackageNotFound,
+    /// Building or testing a package failed.
+    PackageBuildFailed,
+    /// A required program, like `docker` or `bwrap`, could not be found.
+    ProgramNotFound,
+    /// Another process holds a lock on the environment or package.
+    Locked,
+    /// The user interrupted the operation (with Ctrl-C, for example).
+    Interrupted,
+    /// An environment's work directory exceeds its disk quota.
+    DiskQuotaExceeded,
+    /// The configuration file could not be read or is invalid.
+    InvalidConfig,
+}
+
+/// A message tagged with an [`ErrorKind`], used as the root error or as
+/// context.
+struct KindMessage {
+    kind: ErrorKind,
+    message: String,
+}
+
+/// Formats like a plain message, so that the kind doesn't change how errors
+/// are displayed.
+impl Debug for KindMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.message, f)
+    }
+}
+
+impl Display for KindMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for KindMessage {}
+
 impl Error {
+    /// Creates a new error of the given kind.
+    pub fn with_kind<M: Into<String>>(kind: ErrorKind, message: M) -> Self {
+        Self(anyhow::Error::new(KindMessage {
+            kind,
+            message: message.into(),
+        }))
+    }
+
+    /// Returns a new error with additional context that also sets the
+    /// error's kind.
+    pub fn kind_context<M: Into<String>>(self, kind: ErrorKind, message: M) -> Self {
+        Self(self.0.context(KindMessage {
+            kind,
+            message: message.into(),
+        }))
+    }
+
+    /// Returns the kind of error, if known.
+    ///
+    /// If the error chain has several kinds, this returns the outermost one.
+    pub fn kind(&self) -> Option<ErrorKind> {
+        self.0.downcast_ref::<KindMessage>().map(|k| k.kind)
+    }
+
     /// Returns the same output as `format!("{:?}")` but without a stack
     /// backtrace.
     ///
@@ -316,7 +393,7 @@ pub fn warn_brief(warning: String) {
 
 #[cfg(test)]
 mod tests {
-    use super::{Context, Error, Result};
+    use super::{Context, Error, ErrorKind, Result};
     use expect_test::expect;
 
     #[test]
@@ -388,4 +465,26 @@ mod tests {
                 unexpected end of file"#]]
         .assert_eq(&err);
     }
+
+    #[test]
+    fn kind() {
+        let e = somehow!("ants in pants");
+        assert_eq!(None, e.kind());
+        let e = e.kind_context(ErrorKind::Locked, "checking for ants");
+        assert_eq!(Some(ErrorKind::Locked), e.kind());
+        let e = e.context("outer");
+        assert_eq!(Some(ErrorKind::Locked), e.kind());
+        expect![[r#"
+            outer
+
+            Caused by:
+                0: checking for ants
+                1: ants in pants"#]]
+        .assert_eq(&e.debug_without_backtrace());
+
+        let e = Error::with_kind(ErrorKind::Interrupted, "interrupted");
+        assert_eq!(Some(ErrorKind::Interrupted), e.kind());
+        let e = e.kind_context(ErrorKind::PackageBuildFailed, "failed to build");
+        assert_eq!(Some(ErrorKind::PackageBuildFailed), e.kind());
+    }
 }
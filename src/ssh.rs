@@ -0,0 +1,164 @@
+//! SSH access to environments, for `cub ssh` and `cub ssh-config`.
+//!
+//! The SSH client runs `cub ssh-proxy` as its `ProxyCommand`, which starts an
+//! OpenSSH server in inetd mode (`sshd -i`) inside the environment, as the
+//! environment's user. The client can only reach the server through this
+//! local pipe, so host keys wouldn't protect against anything: the server's
+//! host key is generated inside the environment when needed, and the client
+//! doesn't check it.
+
+use std::path::Path;
+use std::process::Stdio;
+
+use super::command_ext::Command;
+use super::{EnvironmentName, HostPath};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+/// The key pair that the SSH client authenticates with.
+pub struct ClientKey {
+    /// The path to the private key on the host.
+    pub path: HostPath,
+    /// The public key, as a line for an `authorized_keys` file.
+    pub public: String,
+}
+
+/// Returns the client key pair kept in `dir`, generating it first if needed.
+pub fn client_key(dir: &HostPath) -> Result<ClientKey> {
+    let path = dir.join("id_ed25519");
+    let public_path = dir.join("id_ed25519.pub");
+    if !public_path.as_host_raw().exists() {
+        std::fs::create_dir_all(dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {dir}"))?;
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", "cubicle", "-f"])
+            .arg(path.as_host_raw())
+            .stdin(Stdio::null())
+            .status()
+            .context("failed to run ssh-keygen")?;
+        if !status.success() {
+            return Err(anyhow!("ssh-keygen exited with {status}"));
+        }
+    }
+    let public = std::fs::read_to_string(public_path.as_host_raw())
+        .with_context(|| format!("failed to read {public_path}"))?;
+    Ok(ClientKey {
+        path,
+        public: public.trim().to_owned(),
+    })
+}
+
+/// Returns the command that runs the SSH server inside an environment,
+/// accepting the given public key.
+pub fn server_command(sshd: &Path, public_key: &str) -> Result<Vec<String>> {
+    let sshd = utf8(sshd)?;
+    let quote = |s| shlex::try_quote(s).map(|s| s.into_owned());
+    let sshd = quote(sshd).context("invalid sshd path")?;
+    let public_key = quote(public_key).context("invalid public key")?;
+    // The host key and authorized key live in the environment's home
+    // directory. The server's logs go to standard error, since standard
+    // output carries the SSH protocol.
+    let script = format!(
+        r#"set -e
+dir="$HOME/.ssh/cubicle"
+mkdir -p "$dir"
+chmod 700 "$HOME/.ssh" "$dir"
+if [ ! -e "$dir/host_key" ]; then
+    ssh-keygen -q -t ed25519 -N '' -C '' -f "$dir/host_key" < /dev/null >&2
+fi
+printf '%s\n' {public_key} > "$dir/authorized_keys"
+exec {sshd} -i -e -f /dev/null -h "$dir/host_key" \
+    -o "AuthorizedKeysFile=$dir/authorized_keys" \
+    -o KbdInteractiveAuthentication=no \
+    -o LogLevel=ERROR \
+    -o PasswordAuthentication=no \
+    -o PidFile=none \
+    -o StrictModes=no
+"#
+    );
+    Ok(vec![String::from("sh"), String::from("-c"), script])
+}
+
+/// Returns the host name that `cub ssh-config` uses for the environment, or
+/// `None` if the environment's name isn't usable in a host name.
+pub fn host_alias(name: &EnvironmentName) -> Option<String> {
+    let name = name.as_str();
+    let usable = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+        && !name.starts_with(['-', '.']);
+    usable.then(|| format!("{name}.cubicle"))
+}
+
+/// Returns the SSH client options for connecting to the environment, as
+/// lines in the `ssh_config` format.
+///
+/// `exe`, `config_path`, and `profile` are used to run `cub ssh-proxy`.
+pub fn client_options(
+    exe: &Path,
+    config_path: &Path,
+    profile: Option<&str>,
+    name: &EnvironmentName,
+    user: &str,
+    key: &HostPath,
+) -> Result<Vec<String>> {
+    let mut args = vec![utf8(exe)?, "--config", utf8(config_path)?];
+    if let Some(profile) = profile {
+        args.extend(["--profile", profile]);
+    }
+    args.extend(["ssh-proxy", "--", name.as_str()]);
+    let proxy_command = shlex::try_join(args).context("invalid SSH proxy command")?;
+    // The SSH client expands '%' in these options.
+    let escape = |s: &str| s.replace('%', "%%");
+    Ok(vec![
+        format!("ProxyCommand {}", escape(&proxy_command)),
+        format!("User {user}"),
+        format!("IdentityFile \"{}\"", escape(utf8(key.as_host_raw())?)),
+        String::from("IdentitiesOnly yes"),
+        String::from("UserKnownHostsFile /dev/null"),
+        String::from("StrictHostKeyChecking no"),
+        String::from("LogLevel ERROR"),
+    ])
+}
+
+fn utf8(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| anyhow!("path not valid UTF-8: {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+    use std::str::FromStr;
+
+    #[test]
+    fn host_alias() {
+        let alias = |name| super::host_alias(&EnvironmentName::from_str(name).unwrap());
+        assert_eq!(Some(String::from("foo-1.cubicle")), alias("foo-1"));
+        assert_eq!(None, alias("foo bar"));
+        assert_eq!(None, alias("-foo"));
+    }
+
+    #[test]
+    fn client_options() {
+        let options = super::client_options(
+            Path::new("/opt/cub"),
+            Path::new("/home/me/my config.toml"),
+            None,
+            &EnvironmentName::from_str("100%").unwrap(),
+            "me",
+            &HostPath::try_from(String::from("/home/me/.local/share/cubicle/ssh/id_ed25519"))
+                .unwrap(),
+        )
+        .unwrap();
+        expect![[r#"
+            ProxyCommand /opt/cub --config '/home/me/my config.toml' ssh-proxy -- '100%%'
+            User me
+            IdentityFile "/home/me/.local/share/cubicle/ssh/id_ed25519"
+            IdentitiesOnly yes
+            UserKnownHostsFile /dev/null
+            StrictHostKeyChecking no
+            LogLevel ERROR"#]]
+        .assert_eq(&options.join("\n"));
+    }
+}
@@ -0,0 +1,188 @@
+//! Remembers the last disk usage summary of each environment.
+//!
+//! Walking large home and work directories dominates the time `cub list`
+//! takes, so it shows these cached summaries right away and refreshes them
+//! separately. Each environment has a small JSON file holding its last
+//! summary and when it was computed.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use super::fs_util::{write_json_atomically, DirSummary};
+use super::runner::EnvFilesSummary;
+use super::{EnvironmentName, HostPath};
+use crate::somehow::{Context, Result};
+
+/// Cached summaries younger than this are considered up to date.
+pub const FRESH_FOR: Duration = Duration::from_secs(60);
+
+pub struct SummaryCache {
+    dir: HostPath,
+}
+
+/// A summary read from the cache.
+pub struct Cached {
+    pub summary: EnvFilesSummary,
+    /// When the summary was computed.
+    pub computed: SystemTime,
+}
+
+impl Cached {
+    /// Returns true if the summary is older than [`FRESH_FOR`].
+    pub fn is_stale(&self, now: SystemTime) -> bool {
+        now.duration_since(self.computed)
+            .is_ok_and(|age| age > FRESH_FOR)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    computed: SystemTime,
+    home_dir_path: Option<PathBuf>,
+    home_dir: Dir,
+    work_dir_path: Option<PathBuf>,
+    work_dir: Dir,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Dir {
+    errors: bool,
+    total_size: u64,
+    last_modified: SystemTime,
+}
+
+impl From<&DirSummary> for Dir {
+    fn from(summary: &DirSummary) -> Self {
+        Self {
+            errors: summary.errors,
+            total_size: summary.total_size,
+            last_modified: summary.last_modified,
+        }
+    }
+}
+
+impl From<Dir> for DirSummary {
+    fn from(dir: Dir) -> Self {
+        Self {
+            errors: dir.errors,
+            total_size: dir.total_size,
+            last_modified: dir.last_modified,
+        }
+    }
+}
+
+impl SummaryCache {
+    pub fn new(dir: HostPath) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, name: &EnvironmentName) -> HostPath {
+        self.dir.join(name.as_filename())
+    }
+
+    /// Returns the last summary stored for the environment, if any.
+    pub fn get(&self, name: &EnvironmentName) -> Result<Option<Cached>> {
+        let path = self.path(name);
+        let buf = match std::fs::read(path.as_host_raw()) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {path}")),
+        };
+        let entry: Entry = serde_json::from_slice(&buf)
+            .with_context(|| format!("failed to parse cached disk usage from {path}"))?;
+        let host_path = |path: Option<PathBuf>| path.map(HostPath::try_from).transpose();
+        Ok(Some(Cached {
+            summary: EnvFilesSummary {
+                home_dir_path: host_path(entry.home_dir_path)?,
+                home_dir: entry.home_dir.into(),
+                work_dir_path: host_path(entry.work_dir_path)?,
+                work_dir: entry.work_dir.into(),
+            },
+            computed: entry.computed,
+        }))
+    }
+
+    /// Stores a summary of the environment, computed at the given time.
+    pub fn put(
+        &self,
+        name: &EnvironmentName,
+        summary: &EnvFilesSummary,
+        computed: SystemTime,
+    ) -> Result<()> {
+        let entry = Entry {
+            computed,
+            home_dir_path: summary
+                .home_dir_path
+                .as_ref()
+                .map(|path| path.as_host_raw().to_owned()),
+            home_dir: (&summary.home_dir).into(),
+            work_dir_path: summary
+                .work_dir_path
+                .as_ref()
+                .map(|path| path.as_host_raw().to_owned()),
+            work_dir: (&summary.work_dir).into(),
+        };
+        write_json_atomically(&self.dir, &self.path(name), &entry)
+    }
+
+    /// Removes the cached summary of the environment, if any.
+    pub fn forget(&self, name: &EnvironmentName) -> Result<()> {
+        let path = self.path(name);
+        match std::fs::remove_file(path.as_host_raw()) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("failed to remove {path}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn summary_cache() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir_path = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        let cache = SummaryCache::new(tmpdir_path.join("summaries"));
+        let name = EnvironmentName::from_str("foo").unwrap();
+        assert!(cache.get(&name).unwrap().is_none());
+
+        let computed = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let summary = EnvFilesSummary {
+            home_dir_path: Some(tmpdir_path.join("home")),
+            home_dir: DirSummary {
+                errors: false,
+                total_size: 1234,
+                last_modified: UNIX_EPOCH + Duration::from_secs(999_000),
+            },
+            work_dir_path: None,
+            work_dir: DirSummary::new_with_errors(),
+        };
+        cache.put(&name, &summary, computed).unwrap();
+
+        let cached = cache.get(&name).unwrap().unwrap();
+        assert_eq!(computed, cached.computed);
+        assert_eq!(
+            Some(tmpdir_path.join("home").as_host_raw()),
+            cached
+                .summary
+                .home_dir_path
+                .as_ref()
+                .map(|path| path.as_host_raw())
+        );
+        assert_eq!(1234, cached.summary.home_dir.total_size);
+        assert!(cached.summary.work_dir_path.is_none());
+        assert!(cached.summary.work_dir.errors);
+        assert!(!cached.is_stale(computed + FRESH_FOR));
+        assert!(cached.is_stale(computed + FRESH_FOR + Duration::from_secs(1)));
+
+        cache.forget(&name).unwrap();
+        assert!(cache.get(&name).unwrap().is_none());
+        cache.forget(&name).unwrap();
+    }
+}
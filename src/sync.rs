@@ -0,0 +1,173 @@
+//! Mirrors an environment's work directory with a remote location over
+//! rsync, for `cub sync`.
+//!
+//! This supports editing files locally while building them in a Cubicle
+//! environment on another machine (or the reverse). Watching for changes
+//! polls the work directory's modification times, since that needs nothing
+//! beyond what `cub list` already does.
+
+use std::time::Duration;
+
+use super::command_ext::Command;
+use super::fs_util::summarize_dir;
+use super::{cancel, Cubicle, EnvironmentName, Event, HostPath, Runner};
+use crate::somehow::{somehow as anyhow, Context, ErrorKind, Result};
+
+/// How often to check the work directory for changes with `--watch`.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which way [`Cubicle::sync_work_dir`] copies files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyncDirection {
+    /// Copy the work directory to the remote location.
+    Push,
+    /// Copy the remote location into the work directory.
+    Pull,
+}
+
+/// Named boolean flag for [`Cubicle::sync_work_dir`].
+///
+/// If true, the work directory is pushed again whenever it changes, until
+/// interrupted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Watch(pub bool);
+
+impl Cubicle {
+    /// Corresponds to `cub sync`.
+    ///
+    /// Makes the remote location, given in rsync's `[USER@]HOST:PATH`
+    /// syntax, a mirror of the environment's work directory, or the reverse
+    /// for [`SyncDirection::Pull`]. Files missing from the source are
+    /// deleted from the destination.
+    pub fn sync_work_dir(
+        &self,
+        name: &EnvironmentName,
+        remote: &str,
+        direction: SyncDirection,
+        watch: Watch,
+    ) -> Result<()> {
+        self.shared.check_not_dry_run("sync a work directory")?;
+        self.check_fully_exists(name)?;
+        if watch.0 && direction == SyncDirection::Pull {
+            return Err(anyhow!("watching for changes only works when pushing"));
+        }
+        check_remote(remote)?;
+        let work_dir = self.host_work_dir(name)?;
+
+        let mut last_modified = None;
+        loop {
+            let modified = summarize_dir(&work_dir)?.last_modified;
+            if last_modified != Some(modified) {
+                last_modified = Some(modified);
+                self.shared.event(Event::SyncingWorkDir {
+                    name: name.clone(),
+                    remote: remote.to_owned(),
+                });
+                rsync(&work_dir, remote, direction)?;
+            }
+            if !watch.0 {
+                return Ok(());
+            }
+            std::thread::sleep(WATCH_INTERVAL);
+            cancel::check()?;
+        }
+    }
+
+    /// Returns the path on the host of the environment's work directory.
+    fn host_work_dir(&self, name: &EnvironmentName) -> Result<HostPath> {
+        if let Some(worktree) = self.shared.worktrees.get(name)? {
+            return Ok(worktree.path);
+        }
+        self.runner
+            .files_summary(name)?
+            .work_dir_path
+            .ok_or_else(|| {
+                anyhow!(
+                    "the work directory of environment {name} isn't on the host's filesystem \
+                    (with the Docker runner, use bind mounts)"
+                )
+            })
+    }
+}
+
+/// Checks that `remote` names a remote location in rsync's syntax, rather
+/// than a local path or an option.
+///
+/// The path must name a directory other than the remote home directory or
+/// `/`, since pushing deletes everything in it that isn't in the work
+/// directory.
+fn check_remote(remote: &str) -> Result<()> {
+    let Some((host, path)) = remote
+        .split_once(':')
+        .filter(|(host, _)| !host.is_empty() && !host.starts_with('-') && !host.contains('/'))
+    else {
+        return Err(anyhow!(
+            "expected a remote location like `user@host:path`, found {remote:?}"
+        ));
+    };
+    let mut components = path
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .peekable();
+    // A leading `~` or `~user` is a home directory.
+    components.next_if(|c| c.starts_with('~'));
+    let components = components.collect::<Vec<_>>();
+    if components.is_empty() || components.contains(&"..") {
+        return Err(anyhow!(
+            "expected an explicit directory on {host:?}, like `{host}:src/project`, \
+            found {path:?}: syncing deletes everything else in the directory"
+        ));
+    }
+    Ok(())
+}
+
+/// Runs rsync to mirror one directory to the other.
+fn rsync(work_dir: &HostPath, remote: &str, direction: SyncDirection) -> Result<()> {
+    // The trailing slashes make rsync copy the directories' contents, rather
+    // than the directories themselves.
+    let local = format!("{}/", work_dir.as_host_raw().display());
+    let remote = if remote.ends_with('/') {
+        remote.to_owned()
+    } else {
+        format!("{remote}/")
+    };
+    let (source, dest) = match direction {
+        SyncDirection::Push => (local, remote),
+        SyncDirection::Pull => (remote, local),
+    };
+    let status = match Command::new("rsync")
+        .args(["--archive", "--compress", "--delete", "--"])
+        .args([source, dest])
+        .status()
+    {
+        Ok(status) => status,
+        Err(e) if e.kind() == Some(ErrorKind::ProgramNotFound) => {
+            return Err(e.context("rsync is needed to sync work directories"));
+        }
+        Err(e) => return Err(e).context("failed to run rsync"),
+    };
+    if !status.success() {
+        return Err(anyhow!("rsync exited with {status}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn check_remote() {
+        assert!(super::check_remote("me@build:src/cubicle").is_ok());
+        assert!(super::check_remote("build:/srv/cubicle/").is_ok());
+        assert!(super::check_remote("build:~/src").is_ok());
+        assert!(super::check_remote("build:").is_err());
+        assert!(super::check_remote("build:~").is_err());
+        assert!(super::check_remote("build:~me/").is_err());
+        assert!(super::check_remote("build:./").is_err());
+        assert!(super::check_remote("build:/").is_err());
+        assert!(super::check_remote("build:src/..").is_err());
+        assert!(super::check_remote("src/cubicle").is_err());
+        assert!(super::check_remote("./a:b").is_err());
+        assert!(super::check_remote(":src").is_err());
+        assert!(super::check_remote("-e sh:x").is_err());
+    }
+}
@@ -0,0 +1,499 @@
+//! A deterministic, in-memory [`Runner`] for tests.
+//!
+//! [`MockRunner`] keeps its environments in memory and never runs anything,
+//! so tests can exercise [`Cubicle`](crate::Cubicle)'s own logic, like
+//! package staleness and dependency resolution, without Docker, Bubblewrap,
+//! or `sudo`. Pass it to [`CubicleBuilder::runner`](crate::CubicleBuilder::runner)
+//! and keep a clone to inspect what Cubicle did.
+//!
+//! This is only available with the `testing` feature.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::UNIX_EPOCH;
+
+use super::runner::{
+    DirSummary, EnvFilesSummary, EnvironmentExists, HostPath, Init, Runner, RunnerCommand, Target,
+};
+use super::EnvironmentName;
+use crate::somehow::{somehow as anyhow, Context, Error, ErrorKind, Result};
+
+/// A [`Runner`] that keeps environments in memory.
+///
+/// Clones share the same state, so a test can keep one clone to inspect
+/// while Cubicle uses another.
+///
+/// Running a command in an environment only records it in [`MockRunner::log`]
+/// and succeeds, unless [`MockRunner::fail_runs_in`] says otherwise. Copying
+/// `provides.tar` out of a home directory that has no such file produces an
+/// empty tar archive, so package builds succeed with empty output.
+#[derive(Clone, Debug, Default)]
+pub struct MockRunner {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    envs: BTreeMap<EnvironmentName, MockEnvironment>,
+    base_debian_packages: BTreeSet<String>,
+    failing: BTreeSet<EnvironmentName>,
+    log: Vec<String>,
+}
+
+/// The state of an environment in a [`MockRunner`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct MockEnvironment {
+    /// The Debian packages from the environment's last create or reset.
+    pub debian_packages: Vec<String>,
+    /// The seed tarballs from the environment's last create or reset, in
+    /// order.
+    pub seeds: Vec<PathBuf>,
+    /// Files in the home directory, by relative path. These are cleared
+    /// when the environment is reset.
+    pub home_files: BTreeMap<PathBuf, Vec<u8>>,
+    /// Files in the work directory, by relative path.
+    pub work_files: BTreeMap<PathBuf, Vec<u8>>,
+    /// True if the environment has been started and not stopped since.
+    pub running: bool,
+}
+
+impl MockRunner {
+    /// Creates a runner with no environments.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // A panic in one test thread shouldn't hide the state from others.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns a copy of the environment's state, if it exists.
+    pub fn environment(&self, name: &EnvironmentName) -> Option<MockEnvironment> {
+        self.state().envs.get(name).cloned()
+    }
+
+    /// Adds or replaces a file in the environment's home directory.
+    ///
+    /// Returns an error if the environment doesn't exist.
+    pub fn set_home_file(
+        &self,
+        name: &EnvironmentName,
+        path: &Path,
+        contents: &[u8],
+    ) -> Result<()> {
+        let mut state = self.state();
+        let env = state.envs.get_mut(name).ok_or_else(|| not_found(name))?;
+        env.home_files.insert(path.to_owned(), contents.to_owned());
+        Ok(())
+    }
+
+    /// Sets the Debian packages that the runner claims to always install.
+    /// These are left out of [`Runner::debian_packages`].
+    pub fn set_base_debian_packages(&self, packages: BTreeSet<String>) {
+        self.state().base_debian_packages = packages;
+    }
+
+    /// Makes later commands run in the environment fail. This is useful to
+    /// simulate a package build failing, using the builder environment's
+    /// name from [`EnvironmentName::for_builder_package`].
+    pub fn fail_runs_in(&self, name: &EnvironmentName) {
+        self.state().failing.insert(name.clone());
+    }
+
+    /// Returns a line describing each change made and command run, in
+    /// order, like `"create foo"` or `"run foo: ./build.sh"`.
+    pub fn log(&self) -> Vec<String> {
+        self.state().log.clone()
+    }
+}
+
+fn not_found(name: &EnvironmentName) -> Error {
+    Error::with_kind(
+        ErrorKind::EnvironmentNotFound,
+        format!("environment {name} does not exist"),
+    )
+}
+
+/// Writes `contents` to `w`, or an empty tar archive for a missing
+/// `provides.tar`.
+fn copy_out(
+    files: &BTreeMap<PathBuf, Vec<u8>>,
+    name: &EnvironmentName,
+    path: &Path,
+    w: &mut dyn io::Write,
+) -> Result<()> {
+    match files.get(path) {
+        Some(contents) => w
+            .write_all(contents)
+            .with_context(|| format!("failed to copy {path:?} from {name}")),
+        None if path == Path::new("provides.tar") => tar::Builder::new(w)
+            .finish()
+            .with_context(|| format!("failed to write empty {path:?} from {name}")),
+        None => Err(anyhow!("file {path:?} not found in environment {name}")),
+    }
+}
+
+impl Runner for MockRunner {
+    fn list(&self) -> Result<Vec<EnvironmentName>> {
+        Ok(self.state().envs.keys().cloned().collect())
+    }
+
+    fn copy_out_from_home(
+        &self,
+        name: &EnvironmentName,
+        path: &Path,
+        w: &mut dyn io::Write,
+    ) -> Result<()> {
+        let state = self.state();
+        let env = state.envs.get(name).ok_or_else(|| not_found(name))?;
+        copy_out(&env.home_files, name, path, w)
+    }
+
+    fn copy_out_from_work(
+        &self,
+        name: &EnvironmentName,
+        path: &Path,
+        w: &mut dyn io::Write,
+    ) -> Result<()> {
+        let state = self.state();
+        let env = state.envs.get(name).ok_or_else(|| not_found(name))?;
+        copy_out(&env.work_files, name, path, w)
+    }
+
+    fn create(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        let mut state = self.state();
+        if state.envs.contains_key(name) {
+            return Err(Error::with_kind(
+                ErrorKind::EnvironmentExists,
+                format!("environment {name} already exists"),
+            ));
+        }
+        state.envs.insert(
+            name.clone(),
+            MockEnvironment {
+                debian_packages: init.debian_packages.clone(),
+                seeds: seed_paths(&init.seeds),
+                ..MockEnvironment::default()
+            },
+        );
+        state.log.push(format!("create {}", name.as_str()));
+        Ok(())
+    }
+
+    fn exists(&self, name: &EnvironmentName) -> Result<EnvironmentExists> {
+        Ok(if self.state().envs.contains_key(name) {
+            EnvironmentExists::FullyExists
+        } else {
+            EnvironmentExists::NoEnvironment
+        })
+    }
+
+    fn files_summary(&self, name: &EnvironmentName) -> Result<EnvFilesSummary> {
+        let state = self.state();
+        let env = state.envs.get(name).ok_or_else(|| not_found(name))?;
+        let summary = |files: &BTreeMap<PathBuf, Vec<u8>>| DirSummary {
+            errors: false,
+            total_size: files.values().map(|contents| contents.len() as u64).sum(),
+            last_modified: UNIX_EPOCH,
+        };
+        Ok(EnvFilesSummary::new(
+            None,
+            summary(&env.home_files),
+            None,
+            summary(&env.work_files),
+        ))
+    }
+
+    fn stop(&self, name: &EnvironmentName) -> Result<()> {
+        let mut state = self.state();
+        let env = state.envs.get_mut(name).ok_or_else(|| not_found(name))?;
+        env.running = false;
+        state.log.push(format!("stop {}", name.as_str()));
+        Ok(())
+    }
+
+    fn start(&self, name: &EnvironmentName) -> Result<()> {
+        let mut state = self.state();
+        let env = state.envs.get_mut(name).ok_or_else(|| not_found(name))?;
+        env.running = true;
+        state.log.push(format!("start {}", name.as_str()));
+        Ok(())
+    }
+
+    fn reset(&self, name: &EnvironmentName, init: &Init) -> Result<()> {
+        let mut state = self.state();
+        let env = state.envs.get_mut(name).ok_or_else(|| not_found(name))?;
+        env.debian_packages = init.debian_packages.clone();
+        env.seeds = seed_paths(&init.seeds);
+        env.home_files.clear();
+        state.log.push(format!("reset {}", name.as_str()));
+        Ok(())
+    }
+
+    fn purge(&self, name: &EnvironmentName) -> Result<()> {
+        let mut state = self.state();
+        if state.envs.remove(name).is_some() {
+            state.log.push(format!("purge {}", name.as_str()));
+        }
+        Ok(())
+    }
+
+    fn run(&self, name: &EnvironmentName, command: &RunnerCommand) -> Result<()> {
+        let mut state = self.state();
+        if !state.envs.contains_key(name) {
+            return Err(not_found(name));
+        }
+        let description = match command {
+            RunnerCommand::Interactive { .. } => String::from("(interactive)"),
+            RunnerCommand::Exec { command, .. } | RunnerCommand::Pipe { command } => {
+                command.join(" ")
+            }
+        };
+        state
+            .log
+            .push(format!("run {}: {description}", name.as_str()));
+        if state.failing.contains(name) {
+            return Err(anyhow!("command failed in environment {name}"));
+        }
+        Ok(())
+    }
+
+    fn debian_packages(&self, name: &EnvironmentName) -> Result<Option<BTreeSet<String>>> {
+        let state = self.state();
+        let env = state.envs.get(name).ok_or_else(|| not_found(name))?;
+        Ok(Some(
+            env.debian_packages
+                .iter()
+                .filter(|package| !state.base_debian_packages.contains(*package))
+                .cloned()
+                .collect(),
+        ))
+    }
+
+    fn base_debian_packages(&self) -> BTreeSet<String> {
+        self.state().base_debian_packages.clone()
+    }
+
+    fn supports_any(&self, _targets: &[Target]) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+fn seed_paths(seeds: &[HostPath]) -> Vec<PathBuf> {
+    seeds
+        .iter()
+        .map(|seed| seed.as_host_raw().to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::{
+        Cubicle, CubicleBuilder, EnvDir, FullPackageName, KeepPartial, ShouldPackageUpdate,
+        UpdatePackagesConditions,
+    };
+    use std::str::FromStr;
+    use std::time::UNIX_EPOCH;
+
+    fn cubicle(tmpdir: &Path, runner: &MockRunner) -> Cubicle {
+        let tmpdir_path = tmpdir.canonicalize().unwrap();
+        CubicleBuilder::new(
+            Config::from_str("runner = 'user'")
+                .enough_context()
+                .unwrap(),
+        )
+        .home_dir(tmpdir_path.join("home"))
+        .shell(String::from("/bin/sh"))
+        .cache_dir(tmpdir_path.join("cache"))
+        .data_dir(tmpdir_path.join("data"))
+        .exe_name(String::from("cub"))
+        .builtin_package_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("packages"))
+        .runner(Box::new(runner.clone()))
+        .build()
+        .unwrap()
+    }
+
+    #[test]
+    fn new_environment() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let runner = MockRunner::new();
+        let cubicle = cubicle(tmpdir.path(), &runner);
+
+        let name = EnvironmentName::from_str("foo").unwrap();
+        let configs_core = FullPackageName::from_str("configs-core").unwrap();
+        let builder = EnvironmentName::for_builder_package(&configs_core);
+        runner.fail_runs_in(&builder);
+        let error = cubicle
+            .new_environment(
+                &name,
+                Some(BTreeSet::from([configs_core.clone()])),
+                KeepPartial(false),
+            )
+            .unwrap_err();
+        assert_eq!(Some(ErrorKind::PackageBuildFailed), error.kind());
+        assert!(runner.environment(&name).is_none());
+
+        runner.state().failing.clear();
+        cubicle
+            .new_environment(
+                &name,
+                Some(BTreeSet::from([configs_core])),
+                KeepPartial(false),
+            )
+            .unwrap();
+        let env = runner.environment(&name).unwrap();
+        assert!(!env.seeds.is_empty());
+        // The package is built in its own environment before the new
+        // environment is created.
+        let log = runner.log();
+        let position = |line: String| log.iter().position(|l| *l == line).unwrap();
+        assert!(
+            position(format!("create {}", builder.as_str()))
+                < position(format!("create {}", name.as_str()))
+        );
+        assert!(cubicle.get_environment_names().unwrap().contains(&builder));
+    }
+
+    #[test]
+    fn purge_golden_dirs() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let runner = MockRunner::new();
+        let cubicle = cubicle(tmpdir.path(), &runner);
+
+        let golden = EnvironmentName::from_str("golden").unwrap();
+        let instance = EnvironmentName::from_str("instance").unwrap();
+        cubicle
+            .new_environment(&golden, Some(BTreeSet::new()), KeepPartial(false))
+            .unwrap();
+        cubicle.shared.golden.record(&instance, &golden).unwrap();
+
+        // Neither directory of a golden environment can be purged while it
+        // has instances.
+        for dir in [EnvDir::Home, EnvDir::Work] {
+            let error = cubicle.purge_environment_dir(&golden, dir).unwrap_err();
+            assert!(error.to_string().contains("golden environment for"));
+        }
+        assert!(runner.environment(&golden).is_some());
+    }
+
+    #[test]
+    fn debian_packages() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let runner = MockRunner::new();
+        runner.set_base_debian_packages(BTreeSet::from([String::from("zsh")]));
+        let cubicle = cubicle(tmpdir.path(), &runner);
+
+        let name = EnvironmentName::from_str("foo").unwrap();
+        cubicle
+            .new_environment(
+                &name,
+                Some(BTreeSet::from([FullPackageName::from_str("zsh").unwrap()])),
+                KeepPartial(false),
+            )
+            .unwrap();
+        // The mock runner doesn't extract the seeds that hold this.
+        runner
+            .state()
+            .envs
+            .get_mut(&name)
+            .unwrap()
+            .work_files
+            .insert(PathBuf::from("packages.txt"), b"zsh\n".to_vec());
+        let packages = cubicle.get_debian_packages(&name).unwrap();
+        // The runner always installs `zsh`, so it's not missing.
+        assert!(!packages.requested.contains("zsh"));
+        assert!(packages.requested.contains("zsh-autosuggestions"));
+        assert!(packages.missing.is_empty(), "{packages:?}");
+    }
+
+    #[test]
+    fn package_status() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let runner = MockRunner::new();
+        let cubicle = cubicle(tmpdir.path(), &runner);
+        let status = || {
+            let status = cubicle.get_package_status().unwrap();
+            (status.stale, status.pending)
+        };
+        let configs_core = FullPackageName::from_str("configs-core").unwrap();
+        let build = || {
+            cubicle
+                .update_packages(
+                    &BTreeSet::from([configs_core.clone()]),
+                    &cubicle.scan_packages().unwrap(),
+                    &UpdatePackagesConditions {
+                        dependencies: ShouldPackageUpdate::IfRequired,
+                        named: ShouldPackageUpdate::Always,
+                        excluded: BTreeSet::new(),
+                    },
+                )
+                .unwrap()
+        };
+
+        // Packages that were never built don't count.
+        assert_eq!((0, 0), status());
+        build();
+        assert_eq!((0, 0), status());
+
+        // Make the package look like it was built long ago, so it's due for
+        // an update. The counts are cached, so this doesn't show up at first.
+        std::fs::File::options()
+            .write(true)
+            .open(
+                tmpdir
+                    .path()
+                    .join("cache/cubicle/packages/configs-core.tar"),
+            )
+            .unwrap()
+            .set_modified(UNIX_EPOCH)
+            .unwrap();
+        assert_eq!((0, 0), status());
+        std::fs::remove_file(tmpdir.path().join("cache/cubicle/package-status.json")).unwrap();
+        assert_eq!((0, 1), status());
+
+        // Building a package makes the cached counts out of date.
+        build();
+        assert_eq!((0, 0), status());
+    }
+
+    #[test]
+    fn corrupt_package_rebuilt() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let runner = MockRunner::new();
+        let cubicle = cubicle(tmpdir.path(), &runner);
+        let configs_core = FullPackageName::from_str("configs-core").unwrap();
+        let update = || {
+            cubicle
+                .update_packages(
+                    &BTreeSet::from([configs_core.clone()]),
+                    &cubicle.scan_packages().unwrap(),
+                    &UpdatePackagesConditions {
+                        dependencies: ShouldPackageUpdate::IfRequired,
+                        named: ShouldPackageUpdate::IfRequired,
+                        excluded: BTreeSet::new(),
+                    },
+                )
+                .unwrap()
+        };
+        let tar = tmpdir
+            .path()
+            .join("cache/cubicle/packages/configs-core.tar");
+
+        update();
+        let built = std::fs::read(&tar).unwrap();
+
+        // A tarball that doesn't match its recorded checksum isn't used and
+        // gets rebuilt, even though the package isn't otherwise due.
+        let mut corrupt = built.clone();
+        corrupt.extend_from_slice(b"garbage");
+        std::fs::write(&tar, &corrupt).unwrap();
+        update();
+        assert_eq!(built, std::fs::read(&tar).unwrap());
+    }
+}
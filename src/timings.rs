@@ -0,0 +1,105 @@
+//! Records how long the slow phases of operations take.
+//!
+//! The command-line program prints these with `--timings`, to help find the
+//! slow parts of a setup. Phases may nest (building a package includes
+//! building the Docker base image) or overlap (when several environments are
+//! reset at once), so the times don't add up to the total.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{EnvironmentName, FullPackageName};
+
+/// A phase of an operation whose time is recorded.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[non_exhaustive]
+pub enum Phase {
+    /// Reading the package definitions.
+    ScanPackages,
+    /// Checking whether a package needs to be rebuilt.
+    CheckStaleness(FullPackageName),
+    /// Building and testing a package.
+    BuildPackage(FullPackageName),
+    /// Building (or finding a cached) Docker base image.
+    BuildBaseImage,
+    /// Copying the package and other seed tarballs into an environment.
+    CopySeeds(EnvironmentName),
+    /// Calculating the disk usage of an environment.
+    SummarizeDiskUsage(EnvironmentName),
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Phase::*;
+        match self {
+            ScanPackages => write!(f, "scan packages"),
+            CheckStaleness(name) => write!(f, "check if {name} package is stale"),
+            BuildPackage(name) => write!(f, "build {name} package"),
+            BuildBaseImage => write!(f, "build Docker base image"),
+            CopySeeds(name) => write!(f, "copy seeds into {name}"),
+            SummarizeDiskUsage(name) => write!(f, "summarize disk usage of {name}"),
+        }
+    }
+}
+
+/// How long a phase took, as returned by
+/// [`Cubicle::timings`](crate::Cubicle::timings).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Timing {
+    /// What was timed.
+    pub phase: Phase,
+    /// How long it took.
+    pub elapsed: Duration,
+}
+
+#[derive(Default)]
+pub(crate) struct Timings(Mutex<Vec<Timing>>);
+
+impl Timings {
+    /// Calls `f` and records how long it took, whether or not it succeeded.
+    pub(crate) fn time<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.0.lock().unwrap().push(Timing {
+            phase,
+            elapsed: start.elapsed(),
+        });
+        result
+    }
+
+    /// Returns the recorded timings, in the order the phases finished.
+    pub(crate) fn get(&self) -> Vec<Timing> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn timings() {
+        let timings = Timings::default();
+        assert_eq!(5, timings.time(Phase::ScanPackages, || 5));
+        let name = EnvironmentName::from_str("foo").unwrap();
+        timings.time(Phase::BuildBaseImage, || {
+            timings.time(Phase::SummarizeDiskUsage(name.clone()), || ())
+        });
+        let phases = timings
+            .get()
+            .into_iter()
+            .map(|timing| timing.phase.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "scan packages",
+                "summarize disk usage of \"foo\"",
+                "build Docker base image",
+            ],
+            phases
+        );
+    }
+}
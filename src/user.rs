@@ -1,23 +1,71 @@
-use std::io::{self, BufRead, Write};
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::Path;
 use std::process::Stdio;
-use std::rc::Rc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use super::command_ext::Command;
+use super::config::UserIsolation;
 use super::fs_util::{summarize_dir, DirSummary};
 use super::runner::{
-    EnvFilesSummary, EnvironmentExists, Init, Runner, RunnerCommand, Target,
-    LOCALE_ENVIRONMENT_VARIABLES,
+    interactive_shell_command, Capability, CodeTarget, EnvFilesSummary, EnvNetwork, EnvPiece,
+    EnvironmentExists, Init, Runner, RunnerCommand, Sharing, Target, LOCALE_ENVIRONMENT_VARIABLES,
 };
-use super::{apt, CubicleShared, EnvironmentName, ExitStatusError, HostPath};
+use super::{apt, CubicleShared, EnvironmentName, Event, ExitStatusError, HostPath, Phase};
 use crate::encoding::{percent_decode, percent_encode, FilenameEncoder};
 use crate::somehow::{somehow as anyhow, Context, LowLevelResult, Result};
 
+mod accounts;
+use accounts::{Account, Accounts};
+
+/// How long to wait for an environment's processes to exit after SIGTERM
+/// before sending SIGKILL.
+const STOP_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// How often to check whether the processes have exited.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The sandboxing settings for sessions with `isolation = "systemd-run"`.
+/// See systemd.exec(5) for what these do.
+const SYSTEMD_RUN_PROPERTIES: &[&str] = &[
+    // Give the session its own `/tmp` and `/var/tmp`.
+    "PrivateTmp=yes",
+    // Hide `/home`, `/root`, and `/run/user` behind empty tmpfs mounts. The
+    // environment's home directory is bound back in separately.
+    "ProtectHome=tmpfs",
+    // Hide other users' processes in `/proc`, along with most of the
+    // system information there.
+    "ProtectProc=invisible",
+    "ProcSubset=pid",
+    "ProtectSystem=full",
+    "ProtectKernelTunables=yes",
+    "ProtectKernelModules=yes",
+    "ProtectControlGroups=yes",
+    "NoNewPrivileges=yes",
+    "RestrictSUIDSGID=yes",
+    // Keep X11 working despite the private `/tmp`. The `-` ignores this if
+    // the directory doesn't exist.
+    "BindReadOnlyPaths=-/tmp/.X11-unix",
+];
+
+/// The directory of sudo configuration files, where shared environments get
+/// a rule letting their group's members switch to the environment's user.
+const SUDOERS_DIR: &str = "/etc/sudoers.d";
+
+/// The directory where the owner and group of shared environments are
+/// recorded. It and its files are owned by root, so that an environment's
+/// user can't change them (unlike the account's GECOS field, which users can
+/// change with `chfn`).
+const SHARING_DIR: &str = "/var/lib/cubicle/sharing";
+
 pub struct User {
-    pub(super) program: Rc<CubicleShared>,
+    pub(super) program: Arc<CubicleShared>,
     username_prefix: &'static str,
     work_tars: HostPath,
+    accounts: Box<dyn Accounts + Send + Sync>,
 }
 
 mod newtypes {
@@ -73,17 +121,15 @@ mod newtypes {
 use newtypes::Username;
 
 impl User {
-    pub(super) fn new(program: Rc<CubicleShared>) -> Result<Self> {
-        let xdg_data_home = match std::env::var("XDG_DATA_HOME") {
-            Ok(path) => HostPath::try_from(path)?,
-            Err(_) => program.home.join(".local").join("share"),
-        };
-        let work_tars = xdg_data_home.join("cubicle").join("work");
+    pub(super) fn new(program: Arc<CubicleShared>) -> Result<Self> {
+        let work_tars = program.paths.work_dirs.clone();
+        let accounts = accounts::new(program.config.user.accounts);
 
         Ok(Self {
             program,
             username_prefix: "cub-",
             work_tars,
+            accounts,
         })
     }
 
@@ -91,6 +137,79 @@ impl User {
         Username::new(self.username_prefix, env.as_str())
     }
 
+    /// Returns the account of the environment's user, or `None` if the user
+    /// doesn't exist.
+    fn account(&self, env: &EnvironmentName) -> Result<Option<Account>> {
+        let username = self.username_from_environment(env);
+        for account in self.accounts.list()? {
+            if account.username == username.as_str() {
+                return Ok(Some(account));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the home directory of the environment's user, or `None` if the
+    /// user doesn't exist.
+    fn home_dir(&self, env: &EnvironmentName) -> Result<Option<HostPath>> {
+        Ok(self.account(env)?.map(|account| account.home))
+    }
+
+    /// Returns the path to the sudo rule for a shared environment's group.
+    fn sudoers_path(username: &Username) -> String {
+        format!("{SUDOERS_DIR}/cubicle-{}", username.as_str())
+    }
+
+    /// Returns the path to the file recording a shared environment's owner
+    /// and group.
+    fn sharing_path(username: &Username) -> String {
+        format!("{SHARING_DIR}/{}", username.as_str())
+    }
+
+    /// Records the owner and group of a shared environment in a root-owned
+    /// file.
+    fn save_sharing(&self, username: &Username, sharing: &Sharing) -> Result<()> {
+        let file = tempfile::NamedTempFile::new().todo_context()?;
+        std::fs::write(file.path(), format_sharing(sharing)).todo_context()?;
+        sudo(&[
+            OsStr::new("install"),
+            OsStr::new("--directory"),
+            OsStr::new("--mode=0755"),
+            OsStr::new("--owner=root"),
+            OsStr::new("--group=root"),
+            OsStr::new(SHARING_DIR),
+        ])?;
+        sudo(&[
+            OsStr::new("install"),
+            OsStr::new("--mode=0644"),
+            OsStr::new("--owner=root"),
+            OsStr::new("--group=root"),
+            file.path().as_os_str(),
+            OsStr::new(&Self::sharing_path(username)),
+        ])
+        .with_context(|| format!("failed to record sharing details for user {username}"))
+    }
+
+    /// Returns the owner and group recorded by [`User::save_sharing`], or
+    /// `None` if the environment isn't shared.
+    fn read_sharing(&self, username: &Username) -> Result<Option<Sharing>> {
+        let path = Self::sharing_path(username);
+        let metadata = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {path:?}")),
+        };
+        if metadata.uid() != 0 {
+            return Err(anyhow!("{path:?} is not owned by root"));
+        }
+        let contents =
+            std::fs::read_to_string(&path).with_context(|| format!("failed to read {path:?}"))?;
+        match parse_sharing(&contents) {
+            Some(sharing) => Ok(Some(sharing)),
+            None => Err(anyhow!("invalid sharing details in {path:?}")),
+        }
+    }
+
     fn user_exists(&self, username: &Username) -> Result<bool> {
         self.user_exists_(username)
             .with_context(|| format!("failed to check if user {username} exists"))
@@ -117,26 +236,11 @@ impl User {
     }
 
     fn create_user_(&self, env_name: &EnvironmentName, username: &Username) -> LowLevelResult<()> {
-        Command::new("sudo")
-            .arg("--")
-            .arg("adduser")
-            .arg("--disabled-password")
-            .args([
-                "--gecos",
-                &percent_encode(env_name.as_str(), |_i, c| {
-                    c.is_ascii_control() || matches!(c, ',' | ':')
-                }),
-            ])
-            .args(["--shell", &self.program.shell])
-            .arg(username.as_str())
-            .status()
-            .and_then(|status| {
-                if status.success() {
-                    Ok(())
-                } else {
-                    Err(anyhow!("`sudo adduser` exited with {status}"))
-                }
-            })?;
+        self.accounts.create(
+            username.as_str(),
+            &format_gecos(env_name),
+            &self.program.shell,
+        )?;
 
         Command::new("sudo")
             // See notes about `--chdir` elsewhere.
@@ -159,19 +263,168 @@ impl User {
         Ok(())
     }
 
+    /// Creates the environment's user and home directory, shared with
+    /// `sharing`'s group if given, and initializes it.
+    fn create_(
+        &self,
+        env_name: &EnvironmentName,
+        init: &Init,
+        sharing: Option<&Sharing>,
+    ) -> Result<()> {
+        let username = self.username_from_environment(env_name);
+        self.create_user(env_name, &username)?;
+        if let Some(sharing) = sharing {
+            self.save_sharing(&username, sharing)?;
+            self.share_with_group(env_name, &username, &sharing.group)
+                .with_context(|| {
+                    format!(
+                        "failed to share environment {env_name} with group {:?}",
+                        sharing.group
+                    )
+                })?;
+        }
+        self.init(env_name, init)
+    }
+
+    /// Lets the members of `group` switch to the environment's user with
+    /// sudo, and gives the group access to the home and work directories.
+    fn share_with_group(
+        &self,
+        env_name: &EnvironmentName,
+        username: &Username,
+        group: &str,
+    ) -> Result<()> {
+        let rule = tempfile::NamedTempFile::new().todo_context()?;
+        std::fs::write(
+            rule.path(),
+            format!("%{group} ALL=({}) NOPASSWD: ALL\n", username.as_str()),
+        )
+        .todo_context()?;
+        sudo(&[
+            OsStr::new("visudo"),
+            OsStr::new("--check"),
+            OsStr::new("--quiet"),
+            OsStr::new("--file"),
+            rule.path().as_os_str(),
+        ])?;
+        sudo(&[
+            OsStr::new("install"),
+            OsStr::new("--mode=0440"),
+            OsStr::new("--owner=root"),
+            OsStr::new("--group=root"),
+            rule.path().as_os_str(),
+            OsStr::new(&Self::sudoers_path(username)),
+        ])?;
+
+        let Some(home) = self.home_dir(env_name)? else {
+            return Err(anyhow!("could not find home directory of user {username}"));
+        };
+        let work = home.join("w");
+        for dir in [&home, &work] {
+            sudo(&[
+                OsStr::new("chgrp"),
+                OsStr::new(group),
+                dir.as_host_raw().as_os_str(),
+            ])?;
+            sudo(&[
+                OsStr::new("chmod"),
+                OsStr::new("g+rwxs"),
+                dir.as_host_raw().as_os_str(),
+            ])?;
+        }
+        Ok(())
+    }
+
+    /// Stops all the user's processes. This first asks them to exit with
+    /// SIGTERM so that they can save their state, then sends SIGKILL to any
+    /// that remain after [`STOP_GRACE_PERIOD`].
     fn kill_username(&self, username: &Username) -> Result<()> {
-        // TODO: give processes a chance to handle SIGTERM first
+        let kill = || -> Result<()> {
+            if !self.signal_username(username, "TERM")? {
+                return Ok(());
+            }
+            let start = Instant::now();
+            while start.elapsed() < STOP_GRACE_PERIOD {
+                std::thread::sleep(STOP_POLL_INTERVAL);
+                if !self.has_processes(username)? {
+                    return Ok(());
+                }
+            }
+            self.program.event(Event::KillingProcesses {
+                username: username.as_str().to_owned(),
+                grace_period: STOP_GRACE_PERIOD,
+            });
+            self.signal_username(username, "KILL")?;
+            Ok(())
+        };
+        kill().with_context(|| format!("failed to kill processes for user {username}"))
+    }
+
+    /// Sends the signal to all the user's processes. Returns false if the
+    /// user had no processes.
+    fn signal_username(&self, username: &Username, signal: &str) -> Result<bool> {
         Command::new("sudo")
             .arg("--")
             .arg("pkill")
-            .args(["--signal", "KILL"])
-            .args(["--uid", username.as_str()])
+            // These are the short options, which BSD `pkill` also accepts.
+            .arg(format!("-{signal}"))
+            .args(["-u", username.as_str()])
+            .status()
+            .and_then(|status| match status.code() {
+                Some(0) => Ok(true),
+                Some(1) => Ok(false),
+                _ => Err(anyhow!("`sudo pkill -{signal}` exited with {status}")),
+            })
+    }
+
+    /// Returns true if the user has any running processes.
+    fn has_processes(&self, username: &Username) -> Result<bool> {
+        Command::new("pgrep")
+            .args(["-u", username.as_str()])
+            .stdout(Stdio::null())
             .status()
             .and_then(|status| match status.code() {
-                Some(0) | Some(1) => Ok(()),
-                _ => Err(anyhow!("`sudo pkill` exited with {status}")),
+                Some(0) => Ok(true),
+                Some(1) => Ok(false),
+                _ => Err(anyhow!("`pgrep` exited with {status}")),
             })
-            .with_context(|| format!("failed to kill processes for user {username}"))
+    }
+
+    /// Summarizes the disk usage of the user's home and work directories by
+    /// running GNU `du` as the user, who can read all of their files. The
+    /// home directory's summary leaves out the work directory.
+    fn du(&self, username: &Username) -> Result<(DirSummary, DirSummary)> {
+        self.du_(username)
+            .with_context(|| format!("failed to summarize disk usage of user {username}"))
+    }
+
+    fn du_(&self, username: &Username) -> LowLevelResult<(DirSummary, DirSummary)> {
+        let output = Command::new("sudo")
+            // See notes about `--chdir` elsewhere.
+            .arg("--login")
+            .args(["--user", username.as_str()])
+            .arg("--")
+            .arg("du")
+            .arg("--block-size=1")
+            .arg("--summarize")
+            .arg("--time")
+            .arg("--time-style=+%s")
+            // With multiple arguments, `du` counts each file only for the
+            // first argument that includes it, so listing the work directory
+            // first leaves it out of the home directory's total.
+            .args(["w", "."])
+            .env_clear()
+            .output()?;
+        // `du` exits with an error if it can't read some files, but it still
+        // prints the totals.
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Ok(parse_du(&stdout, &stderr).ok_or_else(|| {
+            anyhow!(
+                "unexpected output from `sudo ... du ...` (exited with {}): {stdout:?}",
+                output.status
+            )
+        })?)
     }
 
     fn copy_in_seeds(&self, username: &Username, seeds: &[&HostPath]) -> Result<()> {
@@ -184,7 +437,6 @@ impl User {
             return Ok(());
         }
 
-        println!("Copying/extracting seed tarball");
         let mut source = Command::new("pv")
             .args(["-i", "0.1"])
             .args(seeds.iter().map(|s| s.as_host_raw()))
@@ -301,7 +553,12 @@ impl User {
         let mut seeds: Vec<&HostPath> = seeds.iter().collect();
         let script_tar_path = HostPath::try_from(script_tar.path().to_owned())?;
         seeds.push(&script_tar_path);
-        self.copy_in_seeds(&username, &seeds)?;
+        self.program.event(Event::CopyingSeeds(env_name.clone()));
+        self.program
+            .timings
+            .time(Phase::CopySeeds(env_name.clone()), || {
+                self.copy_in_seeds(&username, &seeds)
+            })?;
         self.run_(
             env_name,
             &RunnerCommand::Exec {
@@ -314,61 +571,111 @@ impl User {
     fn run_(&self, env_name: &EnvironmentName, run_command: &RunnerCommand) -> Result<()> {
         let username = self.username_from_environment(env_name);
 
-        let mut command = Command::new("sudo");
-
-        command
-            // This used to use `--chdir ~//w`, but that was introduced
-            // relatively recently in sudo 1.9.3 (released 2020-09-21).
-            //
-            // The double-slash after `~` appeared to be necessary for sudo
-            // (1.9.5p2). It seems dubious, though.
-            .arg("--login")
-            .args(["--user", username.as_str()]);
-
-        command.env_clear();
-        command
-            .env("CUBICLE", env_name.as_str())
-            .arg("--preserve-env=CUBICLE");
-        command
-            .env("SHELL", &self.program.shell)
-            .arg("--preserve-env=SHELL");
+        let mut env_vars = vec![
+            (String::from("CUBICLE"), env_name.as_str().to_owned()),
+            (String::from("SHELL"), self.program.shell.clone()),
+        ];
         for var in ["DISPLAY", "TERM"]
             .iter()
             .chain(LOCALE_ENVIRONMENT_VARIABLES)
         {
             if let Ok(value) = std::env::var(var) {
-                command.env(var, value).arg(format!("--preserve-env={var}"));
+                env_vars.push((String::from(*var), value));
             }
         }
         match run_command {
-            RunnerCommand::Interactive => {}
-            RunnerCommand::Exec { env_vars, .. } => {
-                for (var, value) in *env_vars {
-                    command.env(var, value).arg(format!("--preserve-env={var}"));
-                }
+            RunnerCommand::Pipe { .. } => {}
+            RunnerCommand::Interactive { env_vars: vars, .. }
+            | RunnerCommand::Exec { env_vars: vars, .. } => {
+                env_vars.extend(vars.iter().cloned());
             }
         }
 
-        command.arg("--").arg(&self.program.shell);
-
-        match run_command {
-            RunnerCommand::Interactive => {
-                command.args(["-c", &format!("cd w && exec {}", self.program.shell)]);
-            }
-            RunnerCommand::Exec { command: exec, .. } => {
-                command.arg("-c");
-                command.arg(format!(
+        let script = match run_command {
+            RunnerCommand::Interactive { shell, .. } => format!(
+                "cd w && exec {}",
+                shlex::try_join(
+                    interactive_shell_command(*shell, &self.program.shell)
+                        .iter()
+                        .map(|a| a.as_str())
+                )
+                .expect("TODO")
+            ),
+            RunnerCommand::Exec { command: exec, .. } | RunnerCommand::Pipe { command: exec } => {
+                format!(
                     "cd w && {}",
                     shlex::try_join(exec.iter().map(|a| a.as_str())).expect("TODO")
-                ));
+                )
+            }
+        };
+
+        let mut command = Command::new("sudo");
+        command.env_clear();
+        // The values are passed through sudo's environment, rather than on
+        // the command line, so that other users can't see them.
+        for (var, value) in &env_vars {
+            command.env(var, value).arg(format!("--preserve-env={var}"));
+        }
+
+        match self.program.config.user.isolation {
+            UserIsolation::None => {
+                command
+                    // This used to use `--chdir ~//w`, but that was introduced
+                    // relatively recently in sudo 1.9.3 (released 2020-09-21).
+                    //
+                    // The double-slash after `~` appeared to be necessary for sudo
+                    // (1.9.5p2). It seems dubious, though.
+                    .arg("--login")
+                    .args(["--user", username.as_str()])
+                    .arg("--")
+                    .arg(&self.program.shell);
+            }
+            UserIsolation::SystemdRun => {
+                let Some(home) = self.home_dir(env_name)? else {
+                    return Err(anyhow!("could not find home directory of user {username}"));
+                };
+                let home = home.as_host_raw().display();
+                command
+                    .arg("--")
+                    .arg("systemd-run")
+                    .arg(format!("--uid={}", username.as_str()))
+                    .arg("--quiet")
+                    .arg("--wait")
+                    .arg("--collect")
+                    .arg("--service-type=exec")
+                    .arg(match run_command {
+                        RunnerCommand::Interactive { .. } => "--pty",
+                        RunnerCommand::Exec { .. } | RunnerCommand::Pipe { .. } => "--pipe",
+                    })
+                    .arg(format!("--working-directory={home}"))
+                    .args(
+                        SYSTEMD_RUN_PROPERTIES
+                            .iter()
+                            .map(|property| format!("--property={property}")),
+                    )
+                    .arg(format!("--property=BindPaths={home}"))
+                    // Without a value, `systemd-run` takes each variable's
+                    // value from its own environment.
+                    .args(env_vars.iter().map(|(var, _)| format!("--setenv={var}")))
+                    .arg("--")
+                    .arg(&self.program.shell)
+                    .arg("-l");
             }
         }
+        command.arg("-c").arg(script);
 
         let status = command.status()?;
         if status.success() {
             Ok(())
         } else {
-            Err(ExitStatusError::new(status, "sudo --user").into())
+            Err(ExitStatusError::new(
+                status,
+                match self.program.config.user.isolation {
+                    UserIsolation::None => "sudo --user",
+                    UserIsolation::SystemdRun => "sudo systemd-run",
+                },
+            )
+            .into())
         }
     }
 }
@@ -395,9 +702,25 @@ impl Runner for User {
     }
 
     fn create(&self, env_name: &EnvironmentName, init: &Init) -> Result<()> {
-        let username = self.username_from_environment(env_name);
-        self.create_user(env_name, &username)?;
-        self.init(env_name, init)
+        self.create_(env_name, init, None)
+    }
+
+    fn create_shared(&self, env_name: &EnvironmentName, init: &Init, group: &str) -> Result<()> {
+        if !is_valid_group_name(group) {
+            return Err(anyhow!("invalid group name: {group:?}"));
+        }
+        let status = Command::new("getent").args(["group", group]).status()?;
+        match status.code() {
+            Some(0) => {}
+            Some(2) => return Err(anyhow!("group {group:?} does not exist")),
+            _ => return Err(anyhow!("`getent group` exited with {status}")),
+        }
+        let owner = std::env::var("USER").context("Invalid $USER")?;
+        self.create_(env_name, init, Some(&Sharing::new(owner, group.to_owned())))
+    }
+
+    fn sharing(&self, env_name: &EnvironmentName) -> Result<Option<Sharing>> {
+        self.read_sharing(&self.username_from_environment(env_name))
     }
 
     fn exists(&self, env_name: &EnvironmentName) -> Result<EnvironmentExists> {
@@ -414,17 +737,14 @@ impl Runner for User {
 
     fn list(&self) -> Result<Vec<EnvironmentName>> {
         let mut envs = Vec::new();
-        for account in Passwd::open()? {
-            let Account {
-                username, gecos, ..
-            } = account?;
+        for Account {
+            username, gecos, ..
+        } in self.accounts.list()?
+        {
             if !username.starts_with(self.username_prefix) {
                 continue;
             }
-            let name = match gecos.split_once(',') {
-                Some((a, _)) => a,
-                None => &gecos,
-            };
+            let name = parse_gecos(&gecos);
             if let Ok(env) = percent_decode(name).and_then(EnvironmentName::from_string) {
                 if self.username_from_environment(&env).as_str() == username {
                     envs.push(env);
@@ -435,31 +755,25 @@ impl Runner for User {
     }
 
     fn files_summary(&self, env_name: &EnvironmentName) -> Result<EnvFilesSummary> {
-        let username = self.username_from_environment(env_name);
-
-        let mut home: Option<HostPath> = None;
-
-        for account in Passwd::open()? {
-            let account = account?;
-            if account.username == username.as_str() {
-                home = Some(account.home);
-                break;
-            }
-        }
-
-        match home {
+        match self.home_dir(env_name)? {
             Some(home) => {
-                // This should fail gracefully if this user can't read that
-                // user's files. We should maybe just invoke `du` as that user,
-                // but it'd need to be tolerant of different versions of `du`.
-                let summary =
-                    summarize_dir(&home).unwrap_or_else(|_| DirSummary::new_with_errors());
+                let username = self.username_from_environment(env_name);
+                let (home_dir, work_dir) = match self.du(&username) {
+                    Ok(summaries) => summaries,
+                    // Fall back to reading the files directly, which should
+                    // fail gracefully if this user can't read that user's
+                    // files.
+                    Err(_) => (
+                        summarize_dir(&home).unwrap_or_else(|_| DirSummary::new_with_errors()),
+                        DirSummary::new_with_errors(),
+                    ),
+                };
                 let work_dir_path = Some(home.join("w"));
                 Ok(EnvFilesSummary {
                     home_dir_path: Some(home),
-                    home_dir: summary,
+                    home_dir,
                     work_dir_path,
-                    work_dir: DirSummary::new_with_errors(),
+                    work_dir,
                 })
             }
             None => Ok(EnvFilesSummary {
@@ -471,13 +785,52 @@ impl Runner for User {
         }
     }
 
+    fn code_target(&self, env_name: &EnvironmentName) -> Result<CodeTarget> {
+        match self.home_dir(env_name)? {
+            Some(home) => Ok(CodeTarget::HostDir(home.join("w"))),
+            None => Err(anyhow!(
+                "could not find home directory of user {}",
+                self.username_from_environment(env_name)
+            )),
+        }
+    }
+
+    fn username(&self, env_name: &EnvironmentName) -> Result<String> {
+        Ok(self.username_from_environment(env_name).as_str().to_owned())
+    }
+
+    fn networks(&self, _env_name: &EnvironmentName) -> Result<Vec<EnvNetwork>> {
+        // shares the host's network namespace
+        Ok(Vec::new())
+    }
+
     fn stop(&self, env_name: &EnvironmentName) -> Result<()> {
         let username = self.username_from_environment(env_name);
         self.kill_username(&username)
     }
 
+    fn restarts_after_stop(&self) -> bool {
+        true
+    }
+
+    fn supports_golden(&self) -> bool {
+        // There's no way to overlay one user's home directory on another's.
+        false
+    }
+
+    fn supports_sharing(&self) -> bool {
+        true
+    }
+
+    fn available_debian_packages(&self) -> Result<Option<BTreeSet<String>>> {
+        apt::available_packages().map(Some)
+    }
+
     fn reset(&self, env_name: &EnvironmentName, init: &Init) -> Result<()> {
         let username = self.username_from_environment(env_name);
+        // Recreate the user with the same owner and group, even if another
+        // member of the group is resetting it.
+        let sharing = self.sharing(env_name)?;
         self.kill_username(&username)?;
 
         std::fs::create_dir_all(self.work_tars.as_host_raw()).todo_context()?;
@@ -497,7 +850,10 @@ impl Runner for User {
         );
 
         let save = || -> LowLevelResult<()> {
-            println!("Saving work directory to {work_tar}");
+            self.program.event(Event::SavingWorkDir {
+                name: env_name.clone(),
+                path: work_tar.as_host_raw().to_owned(),
+            });
             let mut child = Command::new("sudo")
                 // See notes about `--chdir` elsewhere.
                 .arg("--login")
@@ -534,8 +890,11 @@ impl Runner for User {
 
         let purge_and_restore = || -> Result<()> {
             self.purge(env_name)?;
-            self.create(env_name, init)?;
-            println!("Restoring work directory from {work_tar}");
+            self.create_(env_name, init, sharing.as_ref())?;
+            self.program.event(Event::RestoringWorkDir {
+                name: env_name.clone(),
+                path: work_tar.as_host_raw().to_owned(),
+            });
             self.init(
                 env_name,
                 &Init {
@@ -554,34 +913,74 @@ impl Runner for User {
                 std::fs::remove_file(work_tar.as_host_raw()).todo_context()?;
                 Ok(())
             }
-            Err(e) => {
-                println!("Encountered an error while resetting environment {env_name}.");
-                println!("A copy of its work directory is here: {work_tar}");
-                Err(e)
-            }
+            Err(e) => Err(e.context(format!(
+                "error resetting environment {env_name} (a copy of its work directory is \
+                here: {work_tar})"
+            ))),
         }
     }
 
+    fn reseed(&self, env_name: &EnvironmentName, seeds: &[HostPath]) -> Result<()> {
+        let username = self.username_from_environment(env_name);
+        self.program.event(Event::CopyingSeeds(env_name.clone()));
+        self.program
+            .timings
+            .time(Phase::CopySeeds(env_name.clone()), || {
+                self.copy_in_seeds(&username, &seeds.iter().collect::<Vec<_>>())
+            })
+    }
+
+    fn inspect(&self, env_name: &EnvironmentName) -> Result<Vec<EnvPiece>> {
+        let username = self.username_from_environment(env_name);
+        Ok(vec![
+            EnvPiece {
+                kind: "user account",
+                exists: self.list()?.contains(env_name),
+                location: username.to_string(),
+            },
+            EnvPiece {
+                kind: "sudo access to user",
+                exists: self.user_exists(&username)?,
+                location: username.to_string(),
+            },
+        ])
+    }
+
+    fn repair(&self, env_name: &EnvironmentName, _init: &Init) -> Result<()> {
+        // An environment only partially exists if its user account exists
+        // but `sudo` can't run commands as that user, which Cubicle can't fix
+        // on its own.
+        let username = self.username_from_environment(env_name);
+        Err(anyhow!(
+            "cannot repair environment {env_name} automatically: sudo can't run \
+            commands as user {username} (check the sudo configuration, or try \
+            '{exe} reset' or '{exe} purge')",
+            exe = self.program.exe_name
+        ))
+    }
+
     fn purge(&self, env_name: &EnvironmentName) -> Result<()> {
         if !self.list()?.contains(env_name) {
             return Ok(());
         }
         let username = self.username_from_environment(env_name);
+        let sharing = self.sharing(env_name)?;
         self.kill_username(&username)?;
-        Command::new("sudo")
-            .arg("--")
-            .arg("deluser")
-            .arg("--remove-home")
-            .arg(username.as_str())
-            .status()
-            .and_then(|status| {
-                if status.success() {
-                    Ok(())
-                } else {
-                    Err(anyhow!("`sudo deluser` exited with {status}"))
-                }
-            })
-            .with_context(|| format!("failed to delete user {username}"))
+        self.accounts
+            .delete(username.as_str())
+            .with_context(|| format!("failed to delete user {username}"))?;
+        if sharing.is_some() {
+            let sudoers = Self::sudoers_path(&username);
+            let sharing = Self::sharing_path(&username);
+            sudo(&[
+                OsStr::new("rm"),
+                OsStr::new("--force"),
+                OsStr::new(&sudoers),
+                OsStr::new(&sharing),
+            ])
+            .with_context(|| format!("failed to remove {sudoers:?} and {sharing:?}"))?;
+        }
+        Ok(())
     }
 
     fn run(&self, env_name: &EnvironmentName, run_command: &RunnerCommand) -> Result<()> {
@@ -589,7 +988,7 @@ impl Runner for User {
     }
 
     fn supports_any(&self, targets: &[Target]) -> Result<bool> {
-        Ok(targets.iter().any(|Target { arch, os }| {
+        Ok(targets.iter().any(|Target { arch, os, .. }| {
             (match arch {
                 None => true,
                 Some(arch) => arch == std::env::consts::ARCH,
@@ -599,70 +998,180 @@ impl Runner for User {
             })
         }))
     }
+
+    fn capabilities(&self) -> BTreeSet<Capability> {
+        // The environments are ordinary user accounts on the host.
+        let mut capabilities = BTreeSet::from([Capability::Network, Capability::Gui]);
+        // New accounts aren't in the `kvm` group, so this needs `/dev/kvm` to
+        // be usable by anyone.
+        if std::fs::metadata("/dev/kvm")
+            .is_ok_and(|metadata| metadata.permissions().mode() & 0o006 == 0o006)
+        {
+            capabilities.insert(Capability::Kvm);
+        }
+        if Path::new("/run/systemd/system").exists() {
+            capabilities.insert(Capability::Systemd);
+        }
+        capabilities
+    }
 }
 
-/// An iterator over `/etc/passwd` accounts.
-struct Passwd {
-    lines: std::iter::Enumerate<std::io::Lines<io::BufReader<std::fs::File>>>,
+/// Runs the command as root with `sudo`.
+fn sudo(args: &[&OsStr]) -> Result<()> {
+    let status = Command::new("sudo").arg("--").args(args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "`sudo {}` exited with {status}",
+            args[0].to_string_lossy()
+        ))
+    }
 }
 
-impl Passwd {
-    fn open() -> Result<Self> {
-        let file = std::fs::File::open("/etc/passwd").context("failed to open \"/etc/passwd\"")?;
-        let reader = io::BufReader::new(file);
-        Ok(Self {
-            lines: reader.lines().enumerate(),
-        })
+/// Returns the GECOS field for an environment's user account, which holds
+/// the environment's name.
+fn format_gecos(env_name: &EnvironmentName) -> String {
+    percent_encode(env_name.as_str(), |_i, c| {
+        c.is_ascii_control() || matches!(c, ',' | ':')
+    })
+}
+
+/// Parses a GECOS field written by [`format_gecos`]. Returns the
+/// environment's name, still encoded.
+///
+/// Older versions also kept a shared environment's owner and group in later
+/// fields, but those are ignored, since users can change their own GECOS
+/// fields.
+fn parse_gecos(gecos: &str) -> &str {
+    gecos.split(',').next().unwrap_or_default()
+}
+
+/// Returns the contents of the file recording a shared environment's owner
+/// and group, one per line.
+fn format_sharing(Sharing { owner, group }: &Sharing) -> String {
+    let encode = |s: &str| percent_encode(s, |_i, c| c.is_ascii_control());
+    format!("{}\n{}\n", encode(owner), encode(group))
+}
+
+/// Parses a file written by [`format_sharing`].
+fn parse_sharing(contents: &str) -> Option<Sharing> {
+    let mut lines = contents.lines();
+    let owner = percent_decode(lines.next()?).ok()?;
+    let group = percent_decode(lines.next()?).ok()?;
+    if owner.is_empty() || group.is_empty() || lines.next().is_some() {
+        return None;
+    }
+    Some(Sharing { owner, group })
+}
+
+/// Parses the output of `du --summarize --time --time-style=+%s w .` into
+/// summaries of the home and work directories.
+fn parse_du(stdout: &str, stderr: &str) -> Option<(DirSummary, DirSummary)> {
+    let mut home = None;
+    let mut work = None;
+    for line in stdout.lines() {
+        let mut fields = line.splitn(3, '\t');
+        let (Some(size), Some(mtime), Some(path)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return None;
+        };
+        // Ignore permissions errors, but note which directory had them.
+        let errors = stderr.lines().any(|line| {
+            let in_work = line.contains("'w'") || line.contains("'w/");
+            if path == "w" {
+                in_work
+            } else {
+                !in_work
+            }
+        });
+        let summary = DirSummary {
+            errors,
+            total_size: size.parse().ok()?,
+            last_modified: UNIX_EPOCH + Duration::from_secs(mtime.parse().ok()?),
+        };
+        match path {
+            "." => home = Some(summary),
+            "w" => work = Some(summary),
+            _ => return None,
+        }
     }
+    Some((home?, work?))
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
-struct Account {
-    username: String,
-    uid: u32,
-    gid: u32,
-    gecos: String,
-    home: HostPath,
-    shell: String,
+/// Returns true if `group` is safe to use in a sudo rule. This is stricter
+/// than what most systems allow for group names.
+fn is_valid_group_name(group: &str) -> bool {
+    !group.is_empty()
+        && !group.starts_with('-')
+        && group
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
 }
 
-impl Iterator for Passwd {
-    type Item = Result<Account>;
-    fn next(&mut self) -> Option<Result<Account>> {
-        self.lines.next().map(|(i, line)| {
-            line.enough_context()
-                .and_then(|line: String| -> Result<Account> {
-                    let mut fields = line.split(':');
-                    if let (
-                        Some(username),
-                        Some(_password),
-                        Some(uid),
-                        Some(gid),
-                        Some(gecos),
-                        Some(home),
-                    ) = (
-                        fields.next(),
-                        fields.next(),
-                        fields.next(),
-                        fields.next(),
-                        fields.next(),
-                        fields.next(),
-                    ) {
-                        Ok(Account {
-                            username: username.to_owned(),
-                            uid: uid.parse::<u32>().context("error parsing uid")?,
-                            gid: gid.parse::<u32>().context("error parsing gid")?,
-                            gecos: gecos.to_owned(),
-                            home: HostPath::try_from(home.to_owned())
-                                .context("error parsing home path")?,
-                            shell: fields.next().unwrap_or("/bin/sh").to_owned(),
-                        })
-                    } else {
-                        Err(anyhow!("not enough fields"))
-                    }
-                })
-                .with_context(|| format!("failed to parse line {i} of \"/etc/passwd\""))
-        })
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn gecos() {
+        let env = EnvironmentName::from_str("a,b").unwrap();
+        let gecos = format_gecos(&env);
+        assert_eq!("a%2cb", gecos);
+        assert_eq!("a%2cb", parse_gecos(&gecos));
+
+        // Accounts created by `adduser` without `--gecos` have empty fields.
+        assert_eq!("x", parse_gecos("x,,,"));
+        // Sharing details from older versions aren't trusted.
+        assert_eq!("x", parse_gecos("x,alice,lab"));
+    }
+
+    #[test]
+    fn sharing() {
+        let sharing = Sharing::new(String::from("alice\nbob"), String::from("lab"));
+        let contents = format_sharing(&sharing);
+        assert_eq!("alice%0abob\nlab\n", contents);
+        assert_eq!(Some(sharing), parse_sharing(&contents));
+
+        assert_eq!(None, parse_sharing(""));
+        assert_eq!(None, parse_sharing("alice\n"));
+        assert_eq!(None, parse_sharing("alice\n\n"));
+        assert_eq!(None, parse_sharing("alice\nlab\nextra\n"));
+    }
+
+    #[test]
+    fn parse_du() {
+        let stdout = "5678\t1700000001\tw\n1234\t1700000000\t.\n";
+        let stderr = "du: cannot read directory 'w/secret': Permission denied\n";
+        let (home, work) = super::parse_du(stdout, stderr).unwrap();
+        assert!(!home.errors);
+        assert_eq!(1234, home.total_size);
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            home.last_modified
+        );
+        assert!(work.errors);
+        assert_eq!(5678, work.total_size);
+
+        let (home, work) = super::parse_du(
+            stdout,
+            "du: cannot read directory './.cache': Permission denied\n",
+        )
+        .unwrap();
+        assert!(home.errors);
+        assert!(!work.errors);
+
+        assert!(super::parse_du("1234\t1700000000\t.\n", "").is_none());
+        assert!(super::parse_du("1234 .\n5678 w\n", "").is_none());
+    }
+
+    #[test]
+    fn is_valid_group_name() {
+        assert!(super::is_valid_group_name("lab-users.2"));
+        assert!(!super::is_valid_group_name(""));
+        assert!(!super::is_valid_group_name("-lab"));
+        assert!(!super::is_valid_group_name("lab users"));
+        assert!(!super::is_valid_group_name("ALL,%lab"));
     }
 }
@@ -0,0 +1,328 @@
+//! Creates, deletes, and lists the system user accounts for the User
+//! runner's environments, using the tools that each kind of host provides.
+
+use std::io::{self, BufRead};
+
+use crate::command_ext::Command;
+use crate::config::UserAccounts;
+use crate::somehow::{somehow as anyhow, Context, Result};
+use crate::HostPath;
+
+/// Manages user accounts on the host.
+pub trait Accounts {
+    /// Creates a user with a disabled password, a home directory, and the
+    /// given GECOS field and login shell.
+    fn create(&self, username: &str, gecos: &str, shell: &str) -> Result<()>;
+
+    /// Deletes the user and its home directory.
+    fn delete(&self, username: &str) -> Result<()>;
+
+    /// Returns all the user accounts on the host.
+    fn list(&self) -> Result<Vec<Account>> {
+        Passwd::open()?.collect()
+    }
+}
+
+/// Returns the account management tools to use for `kind`, or the ones the
+/// host provides if `None`.
+pub fn new(kind: Option<UserAccounts>) -> Box<dyn Accounts + Send + Sync> {
+    match kind.unwrap_or_else(detect) {
+        UserAccounts::Adduser => Box::new(Adduser),
+        UserAccounts::Useradd => Box::new(Useradd),
+        UserAccounts::Macos => Box::new(Macos),
+    }
+}
+
+/// Guesses which tools the host uses to manage accounts.
+///
+/// Debian's `adduser` differs from the `adduser` on other Linux
+/// distributions, which is often a link to `useradd`, so this only uses it
+/// on Debian-family hosts.
+fn detect() -> UserAccounts {
+    if cfg!(target_os = "macos") {
+        UserAccounts::Macos
+    } else if std::path::Path::new("/etc/debian_version").exists() {
+        UserAccounts::Adduser
+    } else {
+        UserAccounts::Useradd
+    }
+}
+
+/// Runs the command as root with `sudo`.
+fn sudo(args: &[&str]) -> Result<()> {
+    let status = Command::new("sudo").arg("--").args(args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("`sudo {}` exited with {status}", args[0]))
+    }
+}
+
+/// Uses `adduser` and `deluser`, found on Debian and its derivatives.
+struct Adduser;
+
+impl Accounts for Adduser {
+    fn create(&self, username: &str, gecos: &str, shell: &str) -> Result<()> {
+        sudo(&[
+            "adduser",
+            "--disabled-password",
+            "--gecos",
+            gecos,
+            "--shell",
+            shell,
+            username,
+        ])
+    }
+
+    fn delete(&self, username: &str) -> Result<()> {
+        sudo(&["deluser", "--remove-home", username])
+    }
+}
+
+/// Uses `useradd` and `userdel` from the shadow utilities, found on most
+/// Linux distributions, like Fedora, RHEL, and Arch Linux.
+struct Useradd;
+
+impl Accounts for Useradd {
+    fn create(&self, username: &str, gecos: &str, shell: &str) -> Result<()> {
+        // `useradd` leaves the password locked.
+        sudo(&[
+            "useradd",
+            "--create-home",
+            "--user-group",
+            "--comment",
+            gecos,
+            "--shell",
+            shell,
+            username,
+        ])
+    }
+
+    fn delete(&self, username: &str) -> Result<()> {
+        sudo(&["userdel", "--remove", username])
+    }
+}
+
+/// Uses `sysadminctl` and `dscl` on macOS, where `/etc/passwd` doesn't list
+/// regular users.
+struct Macos;
+
+impl Accounts for Macos {
+    fn create(&self, username: &str, gecos: &str, shell: &str) -> Result<()> {
+        let home = format!("/Users/{username}");
+        sudo(&[
+            "sysadminctl",
+            "-addUser",
+            username,
+            "-fullName",
+            gecos,
+            "-shell",
+            shell,
+            "-home",
+            &home,
+        ])?;
+        // `sysadminctl` creates the user with an empty password, so this
+        // disables it.
+        let record = format!("/Users/{username}");
+        sudo(&["dscl", ".", "-create", &record, "Password", "*"])?;
+        sudo(&["createhomedir", "-c", "-u", username])
+    }
+
+    fn delete(&self, username: &str) -> Result<()> {
+        // This also removes the home directory.
+        sudo(&["sysadminctl", "-deleteUser", username])
+    }
+
+    fn list(&self) -> Result<Vec<Account>> {
+        let output = Command::new("dscl")
+            .args([".", "-readall", "/Users"])
+            .args([
+                "RecordName",
+                "UniqueID",
+                "PrimaryGroupID",
+                "RealName",
+                "NFSHomeDirectory",
+                "UserShell",
+            ])
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "`dscl . -readall /Users` exited with {}",
+                output.status
+            ));
+        }
+        let output = String::from_utf8(output.stdout).context("`dscl` output is not UTF-8")?;
+        Ok(parse_dscl_users(&output))
+    }
+}
+
+/// Parses the output of `dscl . -readall /Users` for the attributes that
+/// make up an [`Account`], skipping records that lack any of them.
+///
+/// Each record's attributes are printed as `Name: value`, or as `Name:`
+/// followed by the value on the next line, indented by a space, if it has
+/// spaces. Records are separated by a line with just `-`.
+fn parse_dscl_users(output: &str) -> Vec<Account> {
+    let mut accounts = Vec::new();
+    let mut record: Vec<(&str, String)> = Vec::new();
+    let mut finish = |record: &mut Vec<(&str, String)>| {
+        let get = |name: &str| {
+            record
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let account = (|| {
+            Some(Account {
+                // Records may have aliases after the primary name.
+                username: get("RecordName")?.split_whitespace().next()?.to_owned(),
+                uid: get("UniqueID")?.parse().ok()?,
+                gid: get("PrimaryGroupID")?.parse().ok()?,
+                gecos: get("RealName").unwrap_or_default().to_owned(),
+                home: HostPath::try_from(get("NFSHomeDirectory")?.to_owned()).ok()?,
+                shell: get("UserShell").unwrap_or("/bin/sh").to_owned(),
+            })
+        })();
+        accounts.extend(account);
+        record.clear();
+    };
+    for line in output.lines() {
+        if line == "-" {
+            finish(&mut record);
+        } else if let Some(value) = line.strip_prefix(' ') {
+            if let Some((_, last)) = record.last_mut() {
+                if !last.is_empty() {
+                    last.push(' ');
+                }
+                last.push_str(value);
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            record.push((key, value.trim_start().to_owned()));
+        }
+    }
+    finish(&mut record);
+    accounts
+}
+
+/// An iterator over `/etc/passwd` accounts.
+struct Passwd {
+    lines: std::iter::Enumerate<std::io::Lines<io::BufReader<std::fs::File>>>,
+}
+
+impl Passwd {
+    fn open() -> Result<Self> {
+        let file = std::fs::File::open("/etc/passwd").context("failed to open \"/etc/passwd\"")?;
+        let reader = io::BufReader::new(file);
+        Ok(Self {
+            lines: reader.lines().enumerate(),
+        })
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct Account {
+    pub username: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub gecos: String,
+    pub home: HostPath,
+    pub shell: String,
+}
+
+impl Iterator for Passwd {
+    type Item = Result<Account>;
+    fn next(&mut self) -> Option<Result<Account>> {
+        self.lines.next().map(|(i, line)| {
+            line.enough_context()
+                .and_then(|line: String| -> Result<Account> {
+                    let mut fields = line.split(':');
+                    if let (
+                        Some(username),
+                        Some(_password),
+                        Some(uid),
+                        Some(gid),
+                        Some(gecos),
+                        Some(home),
+                    ) = (
+                        fields.next(),
+                        fields.next(),
+                        fields.next(),
+                        fields.next(),
+                        fields.next(),
+                        fields.next(),
+                    ) {
+                        Ok(Account {
+                            username: username.to_owned(),
+                            uid: uid.parse::<u32>().context("error parsing uid")?,
+                            gid: gid.parse::<u32>().context("error parsing gid")?,
+                            gecos: gecos.to_owned(),
+                            home: HostPath::try_from(home.to_owned())
+                                .context("error parsing home path")?,
+                            shell: fields.next().unwrap_or("/bin/sh").to_owned(),
+                        })
+                    } else {
+                        Err(anyhow!("not enough fields"))
+                    }
+                })
+                .with_context(|| format!("failed to parse line {i} of \"/etc/passwd\""))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expect_test::expect;
+
+    #[test]
+    fn parse_dscl_users() {
+        let accounts = super::parse_dscl_users(
+            "NFSHomeDirectory: /Users/cub-0123
+PrimaryGroupID: 20
+RealName:
+ my%2cenv,alice,lab
+RecordName: cub-0123
+UniqueID: 502
+UserShell: /bin/zsh
+-
+NFSHomeDirectory: /var/empty
+PrimaryGroupID: -2
+RecordName: nobody
+UniqueID: -2
+UserShell: /usr/bin/false
+-
+NFSHomeDirectory: /Users/alice
+PrimaryGroupID: 20
+RealName:
+ Alice Example
+RecordName: alice com.example.alice
+UniqueID: 501
+",
+        );
+        expect![[r#"
+            [
+                Account {
+                    username: "cub-0123",
+                    uid: 502,
+                    gid: 20,
+                    gecos: "my%2cenv,alice,lab",
+                    home: HostPath(
+                        "/Users/cub-0123",
+                    ),
+                    shell: "/bin/zsh",
+                },
+                Account {
+                    username: "alice",
+                    uid: 501,
+                    gid: 20,
+                    gecos: "Alice Example",
+                    home: HostPath(
+                        "/Users/alice",
+                    ),
+                    shell: "/bin/sh",
+                },
+            ]
+        "#]]
+        .assert_debug_eq(&accounts);
+    }
+}
@@ -0,0 +1,457 @@
+//! Tracks environments created for git branches by `cub worktree new`.
+//!
+//! Each such environment uses a git worktree of its branch, checked out on
+//! the host, as its work directory. The repository's git directory is also
+//! mounted into the environment at its host path, since the worktree's
+//! `.git` file refers to it there. Most of the git directory is mounted
+//! read-only, since git on the host uses its configuration and hooks. Each
+//! environment has a small JSON file describing its worktree, next to the
+//! worktree itself.
+
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use super::command_ext::Command;
+use super::fs_util::try_iterdir;
+use super::{EnvironmentName, HostPath};
+use crate::somehow::{somehow as anyhow, Context, Result};
+
+pub struct WorktreeRegistry {
+    dir: HostPath,
+}
+
+/// A git worktree used as an environment's work directory.
+#[derive(Debug)]
+pub struct Worktree {
+    /// The top-level directory of the repository's main worktree.
+    pub repo: HostPath,
+    /// The repository's git directory, shared by all its worktrees.
+    pub git_dir: HostPath,
+    /// Where the worktree is checked out.
+    pub path: HostPath,
+    /// The branch checked out in the worktree.
+    pub branch: String,
+}
+
+/// Part of a repository's git directory to mount into an environment, at
+/// the same path as on the host.
+#[derive(Debug)]
+pub struct GitMount {
+    pub path: HostPath,
+    pub writable: bool,
+}
+
+impl Worktree {
+    /// Returns the parts of the git directory to mount into the
+    /// environment, in the order they should be mounted.
+    ///
+    /// The git directory is mounted read-only, so that code in the
+    /// environment can't change the repository's `config` or `hooks`, which
+    /// git on the host would then run. On top of that, the directories that
+    /// git writes to when working in the worktree are writable: its objects,
+    /// refs, and reflogs, and the worktree's own administrative directory,
+    /// except for the files in it that git on the host trusts.
+    ///
+    /// This creates any of those that are missing, so they can be mounted.
+    pub fn prepare_git_mounts(&self) -> Result<Vec<GitMount>> {
+        let admin_dir = self.admin_dir()?;
+        let mut mounts = vec![GitMount {
+            path: self.git_dir.clone(),
+            writable: false,
+        }];
+        for dir in ["objects", "refs", "logs"] {
+            let path = self.git_dir.join(dir);
+            std::fs::create_dir_all(path.as_host_raw())
+                .with_context(|| format!("failed to create directory {path}"))?;
+            mounts.push(GitMount {
+                path,
+                writable: true,
+            });
+        }
+        mounts.push(GitMount {
+            path: admin_dir.clone(),
+            writable: true,
+        });
+        // `config.worktree` is only read if the repository enables
+        // `extensions.worktreeConfig`, but it's created empty here so that it
+        // can be mounted read-only either way.
+        for file in ["commondir", "gitdir", "config.worktree"] {
+            let path = admin_dir.join(file);
+            std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path.as_host_raw())
+                .with_context(|| format!("failed to create {path}"))?;
+            mounts.push(GitMount {
+                path,
+                writable: false,
+            });
+        }
+        Ok(mounts)
+    }
+
+    /// Returns the worktree's administrative directory, under the git
+    /// directory's `worktrees`.
+    ///
+    /// The worktree's `.git` file names it, but that file is writable from
+    /// the environment, so this checks that it's where git puts them.
+    fn admin_dir(&self) -> Result<HostPath> {
+        let dot_git = self.path.join(".git");
+        let contents = std::fs::read_to_string(dot_git.as_host_raw())
+            .with_context(|| format!("failed to read {dot_git}"))?;
+        let admin_dir = contents
+            .trim_end()
+            .strip_prefix("gitdir: ")
+            .map(Path::new)
+            .ok_or_else(|| anyhow!("unexpected contents in {dot_git}: {contents:?}"))?;
+        let worktrees = self.git_dir.join("worktrees");
+        let mut components = admin_dir
+            .strip_prefix(worktrees.as_host_raw())
+            .map(Path::components)
+            .ok();
+        match components.as_mut().map(|c| (c.next(), c.next())) {
+            Some((Some(Component::Normal(name)), None)) => Ok(worktrees.join(name)),
+            _ => Err(anyhow!(
+                "{dot_git} refers to {admin_dir:?}, which is not in {worktrees}"
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    repo: PathBuf,
+    git_dir: PathBuf,
+    path: PathBuf,
+    branch: String,
+}
+
+impl WorktreeRegistry {
+    pub fn new(dir: HostPath) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, name: &EnvironmentName) -> HostPath {
+        self.dir.join(format!("{}.json", name.as_filename()))
+    }
+
+    /// Returns where to check out the worktree for the environment.
+    pub fn checkout_path(&self, name: &EnvironmentName) -> HostPath {
+        self.dir.join(name.as_filename())
+    }
+
+    /// Returns the worktree that the environment uses as its work directory,
+    /// or `None` for ordinary environments.
+    pub fn get(&self, name: &EnvironmentName) -> Result<Option<Worktree>> {
+        let path = self.path(name);
+        let buf = match std::fs::read(path.as_host_raw()) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {path}")),
+        };
+        let entry: Entry =
+            serde_json::from_slice(&buf).with_context(|| format!("failed to parse {path}"))?;
+        Ok(Some(Worktree {
+            repo: HostPath::try_from(entry.repo)?,
+            git_dir: HostPath::try_from(entry.git_dir)?,
+            path: HostPath::try_from(entry.path)?,
+            branch: entry.branch,
+        }))
+    }
+
+    /// Returns the names of the environments that use worktrees.
+    pub fn list(&self) -> Result<Vec<EnvironmentName>> {
+        let mut names = Vec::new();
+        for filename in try_iterdir(&self.dir)? {
+            let Some(filename) = filename.to_str().and_then(|f| f.strip_suffix(".json")) else {
+                continue;
+            };
+            let name = EnvironmentName::from_filename(OsStr::new(filename)).with_context(|| {
+                format!(
+                    "error parsing environment name from path {}",
+                    self.dir.join(filename)
+                )
+            })?;
+            names.push(name);
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Records that the environment uses the worktree.
+    pub fn record(&self, name: &EnvironmentName, worktree: &Worktree) -> Result<()> {
+        std::fs::create_dir_all(self.dir.as_host_raw())
+            .with_context(|| format!("failed to create directory {}", self.dir))?;
+        let entry = Entry {
+            repo: worktree.repo.as_host_raw().to_owned(),
+            git_dir: worktree.git_dir.as_host_raw().to_owned(),
+            path: worktree.path.as_host_raw().to_owned(),
+            branch: worktree.branch.clone(),
+        };
+        let path = self.path(name);
+        let buf = serde_json::to_vec(&entry)
+            .with_context(|| format!("failed to serialize worktree for {path}"))?;
+        std::fs::write(path.as_host_raw(), buf).with_context(|| format!("failed to write {path}"))
+    }
+
+    /// Removes the record of the environment's worktree, if any.
+    pub fn forget(&self, name: &EnvironmentName) -> Result<()> {
+        let path = self.path(name);
+        match std::fs::remove_file(path.as_host_raw()) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("failed to remove {path}")),
+        }
+    }
+}
+
+/// Runs git in `dir` and returns its standard output.
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("failed to run git")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`git {}` in {dir:?} exited with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8(output.stdout).context("git output not valid UTF-8")
+}
+
+/// Finds the repository containing `dir`, returning its top-level directory
+/// and its git directory.
+pub fn find_repo(dir: &Path) -> Result<(HostPath, HostPath)> {
+    let out = git(
+        dir,
+        &[
+            "rev-parse",
+            "--path-format=absolute",
+            "--show-toplevel",
+            "--git-common-dir",
+        ],
+    )?;
+    let mut lines = out.lines();
+    match (lines.next(), lines.next()) {
+        (Some(top), Some(git_dir)) => {
+            // From a linked worktree, `--show-toplevel` names that worktree,
+            // but the main worktree is the parent of the git directory.
+            let git_dir = PathBuf::from(git_dir);
+            let repo = match git_dir.parent() {
+                Some(parent) if git_dir.ends_with(".git") => parent.to_owned(),
+                _ => PathBuf::from(top),
+            };
+            Ok((HostPath::try_from(repo)?, HostPath::try_from(git_dir)?))
+        }
+        _ => Err(anyhow!("unexpected output from `git rev-parse`: {out:?}")),
+    }
+}
+
+/// Returns the name for the environment of the given repository and branch.
+///
+/// This joins the repository's directory name and the branch name,
+/// replacing characters other than ASCII letters, digits, `.`, and `_` with
+/// `-`.
+pub fn environment_name(repo: &HostPath, branch: &str) -> Result<EnvironmentName> {
+    let repo_name = repo
+        .as_host_raw()
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let name = format!("{repo_name}-{branch}")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>();
+    EnvironmentName::from_str(&name)
+}
+
+/// Checks out `branch` of the repository in a new worktree at `path`.
+pub fn add(repo: &HostPath, path: &HostPath, branch: &str) -> Result<()> {
+    let path = path
+        .as_host_raw()
+        .to_str()
+        .ok_or_else(|| anyhow!("path not valid UTF-8: {path}"))?;
+    git(repo.as_host_raw(), &["worktree", "add", "--", path, branch])?;
+    Ok(())
+}
+
+/// Removes the worktree, discarding any changes in it.
+pub fn remove(worktree: &Worktree) -> Result<()> {
+    let repo = worktree.repo.as_host_raw();
+    if worktree.path.as_host_raw().exists() {
+        let path = worktree
+            .path
+            .as_host_raw()
+            .to_str()
+            .ok_or_else(|| anyhow!("path not valid UTF-8: {}", worktree.path))?;
+        git(repo, &["worktree", "remove", "--force", path])?;
+    } else {
+        git(repo, &["worktree", "prune"])?;
+    }
+    Ok(())
+}
+
+/// Returns true if the worktree's branch has been deleted from the remote
+/// that it tracks (as of the last `git fetch --prune`).
+///
+/// Branches without an upstream, or without a local branch at all, are
+/// never considered gone.
+pub fn branch_gone(worktree: &Worktree) -> Result<bool> {
+    let out = git(
+        worktree.repo.as_host_raw(),
+        &[
+            "for-each-ref",
+            "--format=%(upstream:track)",
+            &format!("refs/heads/{}", worktree.branch),
+        ],
+    )?;
+    Ok(out.lines().next() == Some("[gone]"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir_path = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        let registry = WorktreeRegistry::new(tmpdir_path.join("worktrees"));
+        let name = EnvironmentName::from_str("cubicle-fix/bug").unwrap();
+        assert!(registry.get(&name).unwrap().is_none());
+        assert!(registry.list().unwrap().is_empty());
+
+        let worktree = Worktree {
+            repo: tmpdir_path.join("cubicle"),
+            git_dir: tmpdir_path.join("cubicle").join(".git"),
+            path: registry.checkout_path(&name),
+            branch: String::from("fix/bug"),
+        };
+        registry.record(&name, &worktree).unwrap();
+        std::fs::create_dir(worktree.path.as_host_raw()).unwrap();
+        let got = registry.get(&name).unwrap().unwrap();
+        assert_eq!(worktree.repo.as_host_raw(), got.repo.as_host_raw());
+        assert_eq!(worktree.git_dir.as_host_raw(), got.git_dir.as_host_raw());
+        assert_eq!(worktree.path.as_host_raw(), got.path.as_host_raw());
+        assert_eq!("fix/bug", got.branch);
+        assert_eq!(vec![name.clone()], registry.list().unwrap());
+
+        registry.forget(&name).unwrap();
+        registry.forget(&name).unwrap();
+        assert!(registry.get(&name).unwrap().is_none());
+        assert!(registry.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn git_mounts() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir_path = HostPath::try_from(tmpdir.path().canonicalize().unwrap()).unwrap();
+        let repo = tmpdir_path.join("repo");
+        std::fs::create_dir(repo.as_host_raw()).unwrap();
+        git(repo.as_host_raw(), &["init", "--quiet"]).unwrap();
+        git(
+            repo.as_host_raw(),
+            &[
+                "-c",
+                "user.name=Test",
+                "-c",
+                "user.email=test@example.org",
+                "commit",
+                "--quiet",
+                "--allow-empty",
+                "--message=initial",
+            ],
+        )
+        .unwrap();
+        git(repo.as_host_raw(), &["branch", "feature"]).unwrap();
+        let (repo, git_dir) = find_repo(repo.as_host_raw()).unwrap();
+        let worktree = Worktree {
+            repo,
+            git_dir,
+            path: tmpdir_path.join("feature"),
+            branch: String::from("feature"),
+        };
+        add(&worktree.repo, &worktree.path, &worktree.branch).unwrap();
+
+        let mounts = worktree
+            .prepare_git_mounts()
+            .unwrap()
+            .into_iter()
+            .map(|mount| {
+                let path = mount.path.as_host_raw();
+                (
+                    path.strip_prefix(worktree.git_dir.as_host_raw())
+                        .unwrap()
+                        .to_owned(),
+                    mount.writable,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (PathBuf::new(), false),
+                (PathBuf::from("objects"), true),
+                (PathBuf::from("refs"), true),
+                (PathBuf::from("logs"), true),
+                (PathBuf::from("worktrees/feature"), true),
+                (PathBuf::from("worktrees/feature/commondir"), false),
+                (PathBuf::from("worktrees/feature/gitdir"), false),
+                (PathBuf::from("worktrees/feature/config.worktree"), false),
+            ],
+            mounts
+        );
+        // Neither a local branch without an upstream nor a missing local
+        // branch is gone.
+        assert!(!branch_gone(&worktree).unwrap());
+        assert!(!branch_gone(&Worktree {
+            branch: String::from("remote-only"),
+            ..worktree
+        })
+        .unwrap());
+
+        // The environment may rewrite the `.git` file to try to make the
+        // whole git directory writable.
+        let worktree = Worktree {
+            repo: tmpdir_path.join("repo"),
+            git_dir: tmpdir_path.join("repo/.git"),
+            path: tmpdir_path.join("feature"),
+            branch: String::from("feature"),
+        };
+        for target in ["repo/.git", "repo/.git/worktrees/feature/.."] {
+            std::fs::write(
+                worktree.path.join(".git").as_host_raw(),
+                format!(
+                    "gitdir: {}\n",
+                    tmpdir_path.join(target).as_host_raw().display()
+                ),
+            )
+            .unwrap();
+            assert!(worktree.prepare_git_mounts().is_err(), "{target}");
+        }
+    }
+
+    #[test]
+    fn environment_name() {
+        let repo = HostPath::try_from(String::from("/home/me/src/cubicle")).unwrap();
+        assert_eq!(
+            "cubicle-fix-issue_12.v2",
+            super::environment_name(&repo, "fix/issue_12.v2")
+                .unwrap()
+                .as_str()
+        );
+    }
+}
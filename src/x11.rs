@@ -0,0 +1,119 @@
+//! X11 authorization for environments.
+//!
+//! Displays that require an xauth cookie reject programs in environments,
+//! since the environments have their own home directories and hence no
+//! `~/.Xauthority`. Before running a command in an environment, the
+//! Bubblewrap and Docker runners copy the cookie for the host's current
+//! display into a file that they make available at [`ENV_XAUTHORITY`], and
+//! they point `XAUTHORITY` at it.
+//!
+//! The copied entry uses the wildcard address family, since the
+//! environment's hostname may differ from the host's, and it only includes
+//! the cookie for `$DISPLAY`, not the host's other cookies.
+
+use std::io::Write;
+use std::process::Stdio;
+
+use super::command_ext::Command;
+use super::paths::Paths;
+use super::{EnvironmentName, HostPath};
+use crate::somehow::{somehow as anyhow, Context, ErrorKind, Result};
+
+/// Where the directory holding the authorization file appears inside
+/// environments.
+pub const ENV_DIR: &str = "/run/cubicle-x11";
+
+/// The path of the authorization file inside environments.
+pub const ENV_XAUTHORITY: &str = "/run/cubicle-x11/Xauthority";
+
+/// Returns the host directory holding the environment's authorization file.
+pub fn host_dir(paths: &Paths, name: &EnvironmentName) -> HostPath {
+    paths.cache.join("x11").join(name.as_filename())
+}
+
+/// Writes the authorization for the host's display into `dir`.
+///
+/// Returns false, without writing anything, if `DISPLAY` isn't set, `xauth`
+/// isn't installed, or the display has no cookie.
+pub fn write_xauthority(dir: &HostPath) -> Result<bool> {
+    let Ok(display) = std::env::var("DISPLAY") else {
+        return Ok(false);
+    };
+    let output = match Command::new("xauth").args(["nlist", &display]).output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == Some(ErrorKind::ProgramNotFound) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if !output.status.success() {
+        return Err(anyhow!(
+            "`xauth nlist {display}` exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let entries = wildcard_entries(
+        std::str::from_utf8(&output.stdout).context("`xauth nlist` output not valid UTF-8")?,
+    );
+    if entries.is_empty() {
+        return Ok(false);
+    }
+
+    std::fs::create_dir_all(dir.as_host_raw())
+        .with_context(|| format!("failed to create directory {dir}"))?;
+    // Another `cub` process may be using the file, so this replaces it
+    // atomically.
+    let temp = tempfile::NamedTempFile::new_in(dir.as_host_raw())
+        .with_context(|| format!("failed to create temporary file in {dir}"))?;
+    let mut child = Command::new("xauth")
+        .arg("-q")
+        .arg("-f")
+        .arg(temp.path())
+        .args(["nmerge", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .scoped_spawn()?;
+    child
+        .stdin()
+        .take()
+        .unwrap()
+        .write_all(entries.as_bytes())
+        .context("failed to write to `xauth nmerge`")?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(anyhow!("`xauth nmerge` exited with {status}"));
+    }
+    let path = dir.join("Xauthority");
+    temp.persist(path.as_host_raw())
+        .with_context(|| format!("failed to write {path}"))?;
+    Ok(true)
+}
+
+/// Rewrites entries from `xauth nlist` to use the wildcard address family
+/// (`ffff`), which matches connections from any host.
+fn wildcard_entries(nlist: &str) -> String {
+    let mut entries = String::new();
+    for line in nlist.lines() {
+        if let Some(rest) = line.get(4..) {
+            entries.push_str("ffff");
+            entries.push_str(rest);
+            entries.push('\n');
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use expect_test::expect;
+
+    #[test]
+    fn wildcard_entries() {
+        expect![[r#"
+            ffff 0004 686f7374 0001 30 0012 4d49542d4d414749432d434f4f4b49452d31 0010 00112233445566778899aabbccddeeff
+        "#]]
+        .assert_eq(&super::wildcard_entries(
+            "0100 0004 686f7374 0001 30 0012 4d49542d4d414749432d434f4f4b49452d31 0010 00112233445566778899aabbccddeeff\n",
+        ));
+        assert_eq!("", super::wildcard_entries(""));
+    }
+}